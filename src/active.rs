@@ -0,0 +1,636 @@
+use crate::trace::{TraceEvent, TransferTrace};
+use anyhow::{bail, Result};
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Instant;
+use tokio::sync::{watch, Notify};
+use tokio::time::{timeout, Duration};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+use uuid::Uuid;
+
+/// How long a cancel waits for the transfer to wind down before reporting it
+/// as still stopping.
+const CANCEL_WAIT: Duration = Duration::from_secs(5);
+
+/// Speed samples kept per transfer. When full, every other one is dropped
+/// and sampling slows down to match, so a long transfer is still covered
+/// from start to end.
+const MAX_SAMPLES: usize = 600;
+/// Time between speed samples until the first downsampling.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Which way a transfer moves data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Send,
+    Receive,
+}
+
+impl Direction {
+    /// The name history records use for this direction.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Send => "sent",
+            Direction::Receive => "received",
+        }
+    }
+}
+
+impl std::str::FromStr for Direction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "send" | "sent" | "sends" => Ok(Direction::Send),
+            "receive" | "received" | "receives" => Ok(Direction::Receive),
+            _ => bail!("Unknown transfer direction {:?}; expected \"send\" or \"receive\"", s),
+        }
+    }
+}
+
+/// How a cancel request went for one transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CancelOutcome {
+    Cancelled,
+    /// Asked to stop, but still winding down when we stopped waiting.
+    Stopping,
+    /// Not running, e.g. it finished before the cancel reached it.
+    NotActive,
+}
+
+impl CancelOutcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CancelOutcome::Cancelled => "cancelled",
+            CancelOutcome::Stopping => "stopping",
+            CancelOutcome::NotActive => "not_active",
+        }
+    }
+}
+
+/// How far a transfer had got at one moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpeedSample {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub bytes_transferred: u64,
+}
+
+struct SampleBuffer {
+    samples: Vec<SpeedSample>,
+    interval: Duration,
+    last: Option<Instant>,
+    latest: u64,
+    /// When the byte count last went up, from the first report until the
+    /// transfer finishes.
+    progressed: Option<Instant>,
+}
+
+impl Default for SampleBuffer {
+    fn default() -> Self {
+        Self {
+            samples: Vec::new(),
+            interval: SAMPLE_INTERVAL,
+            last: None,
+            latest: 0,
+            progressed: None,
+        }
+    }
+}
+
+/// Speed samples of one transfer, for throughput graphs. Clones share
+/// the same samples.
+#[derive(Clone)]
+pub struct SpeedSamples {
+    buffer: Arc<Mutex<SampleBuffer>>,
+    /// Every byte count reported, not just those kept as samples.
+    moved: Arc<watch::Sender<u64>>,
+}
+
+impl Default for SpeedSamples {
+    fn default() -> Self {
+        Self {
+            buffer: Arc::default(),
+            moved: Arc::new(watch::Sender::new(0)),
+        }
+    }
+}
+
+impl SpeedSamples {
+    /// Notes progress; called as often as convenient, it keeps at most one
+    /// sample per interval.
+    pub fn record(&self, bytes_transferred: u64) {
+        self.record_at(Instant::now(), bytes_transferred, false);
+    }
+
+    /// Notes where the transfer ended, however soon after the last sample.
+    pub fn finish(&self, bytes_transferred: u64) {
+        self.record_at(Instant::now(), bytes_transferred, true);
+    }
+
+    /// Follows the byte count as it's reported, however often that is.
+    pub fn progress(&self) -> watch::Receiver<u64> {
+        self.moved.subscribe()
+    }
+
+    fn record_at(&self, now: Instant, bytes_transferred: u64, closing: bool) {
+        self.moved.send_if_modified(|moved| std::mem::replace(moved, bytes_transferred) != bytes_transferred);
+        let mut buffer = self.buffer.lock().unwrap();
+        if closing {
+            buffer.progressed = None;
+        } else if buffer.progressed.is_none() || bytes_transferred > buffer.latest {
+            buffer.progressed = Some(now);
+        }
+        buffer.latest = bytes_transferred;
+        let due = buffer.last.is_none_or(|at| now.duration_since(at) >= buffer.interval);
+        if !due && !closing {
+            return;
+        }
+        buffer.last = Some(now);
+        buffer.samples.push(SpeedSample {
+            timestamp: chrono::Utc::now(),
+            bytes_transferred,
+        });
+        if buffer.samples.len() > MAX_SAMPLES {
+            let mut index = 0;
+            buffer.samples.retain(|_| {
+                index += 1;
+                index % 2 == 1
+            });
+            buffer.interval *= 2;
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<SpeedSample> {
+        self.buffer.lock().unwrap().samples.clone()
+    }
+
+    /// How long the byte count has stood still at `now`, while data is
+    /// moving: not before the first report, nor once finished.
+    fn idle_for(&self, now: Instant) -> Option<Duration> {
+        let progressed = self.buffer.lock().unwrap().progressed?;
+        Some(now.saturating_duration_since(progressed))
+    }
+
+    /// Counts standing still from `now` on, as for a transfer held on purpose.
+    fn idle_from(&self, now: Instant) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if buffer.progressed.is_some() {
+            buffer.progressed = Some(now);
+        }
+    }
+}
+
+/// Who is holding a transfer where it is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Held {
+    /// Paused here.
+    pub here: bool,
+    /// Paused by the peer, which told us so.
+    pub by_peer: bool,
+}
+
+impl Held {
+    pub fn either(&self) -> bool {
+        self.here || self.by_peer
+    }
+}
+
+/// Whether a transfer is paused, shared by its registration and whatever
+/// moves its data.
+#[derive(Clone)]
+pub struct Pausing(Arc<watch::Sender<Held>>);
+
+impl Default for Pausing {
+    fn default() -> Self {
+        Self(Arc::new(watch::Sender::new(Held::default())))
+    }
+}
+
+impl Pausing {
+    pub fn held(&self) -> Held {
+        *self.0.borrow()
+    }
+
+    /// Pauses or resumes here; false when it already was.
+    fn set_here(&self, paused: bool) -> bool {
+        self.0.send_if_modified(|held| std::mem::replace(&mut held.here, paused) != paused)
+    }
+
+    /// Notes the peer pausing or resuming.
+    pub fn set_by_peer(&self, paused: bool) {
+        self.0.send_if_modified(|held| std::mem::replace(&mut held.by_peer, paused) != paused);
+    }
+
+    /// Follows changes from now on.
+    pub fn watch(&self) -> watch::Receiver<Held> {
+        self.0.subscribe()
+    }
+}
+
+struct Entry {
+    cancel: CancellationToken,
+    finished: CancellationToken,
+    samples: SpeedSamples,
+    trace: TransferTrace,
+    file: WritingTo,
+    stalled: Stalled,
+    pausing: Pausing,
+}
+
+/// The partial file a receive is writing, shared with its registry entry.
+type WritingTo = Arc<Mutex<Option<PathBuf>>>;
+/// How long a transfer had gone without progress when the watchdog stopped it.
+type Stalled = Arc<OnceLock<Duration>>;
+
+/// Every transfer that can still be cancelled, from the moment it is
+/// admitted until it has finished cleaning up.
+#[derive(Default)]
+pub struct ActiveTransfers {
+    entries: Mutex<HashMap<(Uuid, Direction), Entry>>,
+    /// Woken whenever a transfer finishes.
+    finished: Notify,
+}
+
+/// A registered transfer. It stays cancellable until this is dropped, so hold
+/// it until the transfer's history record is final.
+pub struct ActiveTransfer {
+    transfer_id: Uuid,
+    direction: Direction,
+    cancel: CancellationToken,
+    finished: CancellationToken,
+    samples: SpeedSamples,
+    trace: TransferTrace,
+    file: WritingTo,
+    stalled: Stalled,
+    pausing: Pausing,
+    registry: Arc<ActiveTransfers>,
+}
+
+impl ActiveTransfers {
+    pub fn register(self: &Arc<Self>, transfer_id: Uuid, direction: Direction) -> ActiveTransfer {
+        let cancel = CancellationToken::new();
+        let finished = CancellationToken::new();
+        let samples = SpeedSamples::default();
+        let trace = TransferTrace::default();
+        let file = WritingTo::default();
+        let stalled = Stalled::default();
+        let pausing = Pausing::default();
+        self.entries.lock().unwrap().insert(
+            (transfer_id, direction),
+            Entry {
+                cancel: cancel.clone(),
+                finished: finished.clone(),
+                samples: samples.clone(),
+                trace: trace.clone(),
+                file: file.clone(),
+                stalled: stalled.clone(),
+                pausing: pausing.clone(),
+            },
+        );
+        ActiveTransfer {
+            transfer_id,
+            direction,
+            cancel,
+            finished,
+            samples,
+            trace,
+            file,
+            stalled,
+            pausing,
+            registry: self.clone(),
+        }
+    }
+
+    /// Speed samples of `transfer_id` while it's running. A transfer to
+    /// ourselves gives its sending side.
+    pub fn samples_of(&self, transfer_id: &Uuid) -> Option<Vec<SpeedSample>> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&(*transfer_id, Direction::Send))
+            .or_else(|| entries.get(&(*transfer_id, Direction::Receive)))
+            .map(|entry| entry.samples.snapshot())
+    }
+
+    /// The trace of `transfer_id` while it's running, if it's traced.
+    pub fn trace_of(&self, transfer_id: &Uuid) -> Option<Vec<TraceEvent>> {
+        let entries = self.entries.lock().unwrap();
+        [Direction::Send, Direction::Receive]
+            .iter()
+            .find_map(|direction| entries.get(&(*transfer_id, *direction))?.trace.snapshot())
+    }
+
+    /// Whether a running transfer is writing to `path`.
+    pub fn is_writing(&self, path: &Path) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .values()
+            .any(|entry| entry.file.lock().unwrap().as_deref() == Some(path))
+    }
+
+    /// The transfers running right now, optionally only one direction.
+    pub fn snapshot(&self, direction: Option<Direction>) -> Vec<(Uuid, Direction)> {
+        self.entries
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(_, d)| direction.is_none_or(|wanted| *d == wanted))
+            .copied()
+            .collect()
+    }
+
+    /// Directions `transfer_id` is running in; a transfer to ourselves runs in both.
+    pub fn directions_of(&self, transfer_id: &Uuid) -> Vec<Direction> {
+        self.entries
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|(id, _)| id == transfer_id)
+            .map(|(_, direction)| *direction)
+            .collect()
+    }
+
+    /// Asks a transfer to stop and waits for it to finish cleaning up.
+    pub async fn cancel(&self, transfer_id: Uuid, direction: Direction) -> CancelOutcome {
+        let finished = {
+            let entries = self.entries.lock().unwrap();
+            let Some(entry) = entries.get(&(transfer_id, direction)) else {
+                return CancelOutcome::NotActive;
+            };
+            entry.cancel.cancel();
+            entry.finished.clone()
+        };
+        match timeout(CANCEL_WAIT, finished.cancelled()).await {
+            Ok(()) => CancelOutcome::Cancelled,
+            Err(_) => CancelOutcome::Stopping,
+        }
+    }
+
+    /// Cancels everything running now, optionally only one direction, and
+    /// waits for each to wind down. Transfers admitted meanwhile are left alone.
+    pub async fn cancel_all(&self, direction: Option<Direction>) -> Vec<(Uuid, Direction, CancelOutcome)> {
+        join_all(self.snapshot(direction).into_iter().map(|(transfer_id, direction)| async move {
+            (transfer_id, direction, self.cancel(transfer_id, direction).await)
+        }))
+        .await
+    }
+
+    /// Pauses or resumes a running transfer. Returns false when it isn't
+    /// running; pausing one already paused changes nothing.
+    pub fn set_paused(&self, transfer_id: Uuid, direction: Direction, paused: bool) -> bool {
+        let entries = self.entries.lock().unwrap();
+        let Some(entry) = entries.get(&(transfer_id, direction)) else {
+            return false;
+        };
+        entry.pausing.set_here(paused);
+        true
+    }
+
+    /// Stops every transfer whose data has stood still for `after`, as
+    /// stalled. Only data that has started moving counts, so a transfer
+    /// waiting for approval or a slot is left alone, and neither side
+    /// pausing it counts as standing still. Returns the transfers stopped
+    /// now and how long each had stood still.
+    pub fn stop_stalled(&self, after: Duration) -> Vec<(Uuid, Direction, Duration)> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+        entries
+            .iter()
+            .filter(|(_, entry)| !entry.cancel.is_cancelled())
+            .filter(|(_, entry)| {
+                let held = entry.pausing.held().either();
+                if held {
+                    entry.samples.idle_from(now);
+                }
+                !held
+            })
+            .filter_map(|(&(transfer_id, direction), entry)| {
+                let idle = entry.samples.idle_for(now).filter(|idle| *idle >= after)?;
+                let _ = entry.stalled.set(idle);
+                entry.cancel.cancel();
+                Some((transfer_id, direction, idle))
+            })
+            .collect()
+    }
+
+    /// Resolves once no transfer is registered, whatever phase it is in.
+    pub async fn wait_for_idle(&self) {
+        loop {
+            // Created before checking, so a finish in between isn't missed
+            let finished = self.finished.notified();
+            if self.entries.lock().unwrap().is_empty() {
+                return;
+            }
+            finished.await;
+        }
+    }
+}
+
+impl ActiveTransfer {
+    pub fn id(&self) -> Uuid {
+        self.transfer_id
+    }
+
+    /// Whether someone asked the transfer to stop. One the watchdog
+    /// stopped isn't cancelled; it failed.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled() && self.stalled.get().is_none()
+    }
+
+    /// Whether it has been asked to stop, by someone or by the watchdog.
+    pub fn is_stopping(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// How long the transfer had stood still, if the watchdog stopped it.
+    pub fn stalled(&self) -> Option<Duration> {
+        self.stalled.get().copied()
+    }
+
+    /// Resolves once the transfer has been asked to stop, by someone or by
+    /// the watchdog.
+    pub fn cancelled(&self) -> WaitForCancellationFuture<'_> {
+        self.cancel.cancelled()
+    }
+
+    /// Speed samples, dropped with the transfer unless copied elsewhere.
+    pub fn samples(&self) -> &SpeedSamples {
+        &self.samples
+    }
+
+    /// Debug trace, recorded only once enabled.
+    pub fn trace(&self) -> &TransferTrace {
+        &self.trace
+    }
+
+    /// Whether it's paused, here or by the peer.
+    pub fn pausing(&self) -> &Pausing {
+        &self.pausing
+    }
+
+    /// Notes the file being received into, so maintenance leaves it alone.
+    pub fn writing_to(&self, path: &Path) {
+        *self.file.lock().unwrap() = Some(path.to_path_buf());
+    }
+}
+
+impl Drop for ActiveTransfer {
+    fn drop(&mut self) {
+        self.registry
+            .entries
+            .lock()
+            .unwrap()
+            .remove(&(self.transfer_id, self.direction));
+        self.finished.cancel();
+        self.registry.finished.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn idle_waits_for_every_registered_transfer() {
+        let active = Arc::new(ActiveTransfers::default());
+        active.wait_for_idle().await;
+
+        let send = active.register(Uuid::new_v4(), Direction::Send);
+        let receive = active.register(Uuid::new_v4(), Direction::Receive);
+        let mut idle = Box::pin(active.wait_for_idle());
+        assert!(futures_util::poll!(&mut idle).is_pending());
+        drop(send);
+        assert!(futures_util::poll!(&mut idle).is_pending());
+        drop(receive);
+        timeout(Duration::from_secs(1), idle).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancel_all_reaches_every_transfer() {
+        let active = Arc::new(ActiveTransfers::default());
+        let transfers = [
+            active.register(Uuid::new_v4(), Direction::Send),
+            active.register(Uuid::new_v4(), Direction::Receive),
+        ];
+        // Each winds down as soon as it's asked to
+        let running: Vec<_> = transfers
+            .into_iter()
+            .map(|transfer| {
+                tokio::spawn(async move {
+                    transfer.cancelled().await;
+                })
+            })
+            .collect();
+
+        let outcomes = active.cancel_all(None).await;
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes.iter().all(|(_, _, outcome)| *outcome == CancelOutcome::Cancelled));
+        for task in running {
+            task.await.unwrap();
+        }
+        active.wait_for_idle().await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn cancel_all_racing_new_transfers_reports_each_one_as_it_went() {
+        let active = Arc::new(ActiveTransfers::default());
+        let release = CancellationToken::new();
+        // Keeps admitting transfers while cancel-all runs. Each winds down as
+        // soon as it's asked to, saying whether it was; every third is done
+        // before anyone could ask.
+        let registering = {
+            let (active, release) = (active.clone(), release.clone());
+            tokio::spawn(async move {
+                let mut running = Vec::new();
+                for n in 0..600 {
+                    let transfer = active.register(Uuid::new_v4(), Direction::Send);
+                    if n % 3 == 0 {
+                        continue;
+                    }
+                    let release = release.clone();
+                    running.push((
+                        transfer.id(),
+                        tokio::spawn(async move {
+                            tokio::select! {
+                                _ = transfer.cancelled() => true,
+                                _ = release.cancelled() => false,
+                            }
+                        }),
+                    ));
+                    tokio::task::yield_now().await;
+                }
+                running
+            })
+        };
+
+        let mut outcomes = HashMap::new();
+        while !registering.is_finished() {
+            for (transfer_id, _, outcome) in active.cancel_all(None).await {
+                // Waiting for a wind-down that never came would be a hang
+                assert_ne!(outcome, CancelOutcome::Stopping);
+                outcomes.insert(transfer_id, outcome);
+            }
+        }
+        let running = registering.await.unwrap();
+        release.cancel();
+        assert!(!outcomes.is_empty());
+        for (transfer_id, task) in running {
+            let was_cancelled = task.await.unwrap();
+            // Reported cancelled exactly when it was, and untouched otherwise
+            assert_eq!(outcomes.get(&transfer_id) == Some(&CancelOutcome::Cancelled), was_cancelled);
+        }
+        active.wait_for_idle().await;
+    }
+
+    #[tokio::test]
+    async fn only_transfers_whose_data_stops_moving_are_stalled() {
+        let active = Arc::new(ActiveTransfers::default());
+        let waiting = active.register(Uuid::new_v4(), Direction::Receive);
+        let moving = active.register(Uuid::new_v4(), Direction::Send);
+        let finished = active.register(Uuid::new_v4(), Direction::Send);
+        let stuck = active.register(Uuid::new_v4(), Direction::Receive);
+        let long_ago = Instant::now() - Duration::from_secs(60);
+        moving.samples().record_at(long_ago, 1000, false);
+        moving.samples().record(2000);
+        finished.samples().record_at(long_ago, 1000, false);
+        finished.samples().finish(1000);
+        stuck.samples().record_at(long_ago, 1000, false);
+        // The same count again isn't progress
+        stuck.samples().record(1000);
+
+        let stopped = active.stop_stalled(Duration::from_secs(30));
+        assert_eq!(stopped.len(), 1);
+        assert_eq!((stopped[0].0, stopped[0].1), (stuck.id(), Direction::Receive));
+        timeout(Duration::from_secs(1), stuck.cancelled()).await.unwrap();
+        assert!(!stuck.is_cancelled());
+        assert!(stuck.stalled().is_some_and(|idle| idle >= Duration::from_secs(60)));
+        for transfer in [&waiting, &moving, &finished] {
+            assert!(transfer.stalled().is_none() && !transfer.is_cancelled());
+        }
+        // Stopped once, not again on the next check
+        assert!(active.stop_stalled(Duration::from_secs(30)).is_empty());
+    }
+
+    #[test]
+    fn samples_stay_bounded_and_cover_the_whole_transfer() {
+        let samples = SpeedSamples::default();
+        let start = Instant::now();
+        for second in 0..3000u64 {
+            let now = start + Duration::from_secs(second);
+            samples.record_at(now, second * 1000, false);
+            // Several reports a second still make one sample
+            samples.record_at(now + Duration::from_millis(500), second * 1000 + 500, false);
+        }
+        samples.finish(3_000_000);
+
+        let kept = samples.snapshot();
+        assert!(kept.len() <= MAX_SAMPLES + 1);
+        assert!(kept.len() > MAX_SAMPLES / 4);
+        assert_eq!(kept[0].bytes_transferred, 0);
+        assert_eq!(kept.last().unwrap().bytes_transferred, 3_000_000);
+        assert!(kept.windows(2).all(|pair| pair[0].bytes_transferred < pair[1].bytes_transferred));
+    }
+}
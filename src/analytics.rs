@@ -0,0 +1,206 @@
+use crate::history::TransferRecord;
+use chrono::{DateTime, Datelike, Days, NaiveDate, TimeZone, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+/// Peers listed in `top_peers`.
+const TOP_PEERS: usize = 10;
+
+/// What GetHistoryAnalytics charts, over finished transfers. Benchmarks
+/// aren't transfers and are left out.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HistoryAnalytics {
+    /// Transfers counted.
+    pub transfers: u64,
+    /// The peers most bytes moved with, most first.
+    pub top_peers: Vec<PeerBytes>,
+    /// Transfers started in each hour of the day, local time, from midnight.
+    pub by_hour: Vec<u64>,
+    /// Transfers started on each day of the week, local time, from Monday.
+    pub by_weekday: Vec<u64>,
+    /// Why transfers failed, most common first.
+    pub failure_reasons: Vec<FailureCount>,
+    /// Average speed of completed transfers each week, oldest first.
+    pub weekly_speed: Vec<WeeklySpeed>,
+}
+
+/// Bytes moved with one peer, both ways.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerBytes {
+    /// The peer; unset for senders we couldn't tell.
+    pub peer_id: Option<Uuid>,
+    /// Its name in the newest of its records.
+    pub peer_hostname: String,
+    pub bytes: u64,
+    pub transfers: u64,
+}
+
+/// How often transfers failed for one reason: its error code, or the
+/// error itself for failures without one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FailureCount {
+    pub reason: String,
+    pub count: u64,
+}
+
+/// Completed transfers in the week starting on Monday `week_start`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WeeklySpeed {
+    pub week_start: NaiveDate,
+    /// Transfers whose speed is known.
+    pub transfers: u64,
+    /// Mean of their average speeds.
+    pub average_speed_bytes_per_sec: u64,
+}
+
+/// Adds up `records` in one pass, telling hours and days in `tz`.
+pub fn analyze<Tz: TimeZone>(records: &[TransferRecord], tz: &Tz) -> HistoryAnalytics {
+    let mut analytics = HistoryAnalytics {
+        by_hour: vec![0; 24],
+        by_weekday: vec![0; 7],
+        ..Default::default()
+    };
+    // Keyed by peer id, or by name for peers without one
+    let mut peers: HashMap<(Option<Uuid>, String), (PeerBytes, DateTime<Utc>)> = HashMap::new();
+    let mut failures: HashMap<String, u64> = HashMap::new();
+    let mut weeks: BTreeMap<NaiveDate, (u64, u64)> = BTreeMap::new();
+
+    // A session's own record only sums up its files' records
+    for record in records
+        .iter()
+        .filter(|record| record.direction != "benchmark" && record.session_files.is_none())
+    {
+        analytics.transfers += 1;
+        let started = record.timestamp.with_timezone(tz);
+        analytics.by_hour[started.hour() as usize] += 1;
+        analytics.by_weekday[started.weekday().num_days_from_monday() as usize] += 1;
+
+        let key = (record.peer_id, if record.peer_id.is_some() { String::new() } else { record.peer_hostname.clone() });
+        let (peer, seen) = peers.entry(key).or_insert_with(|| {
+            let peer = PeerBytes {
+                peer_id: record.peer_id,
+                peer_hostname: record.peer_hostname.clone(),
+                bytes: 0,
+                transfers: 0,
+            };
+            (peer, record.timestamp)
+        });
+        peer.bytes += record.bytes_transferred.unwrap_or(0);
+        peer.transfers += 1;
+        if record.timestamp > *seen {
+            peer.peer_hostname = record.peer_hostname.clone();
+            *seen = record.timestamp;
+        }
+
+        match record.status.as_str() {
+            "failed" => {
+                let reason = record
+                    .error_code
+                    .clone()
+                    .or_else(|| record.error.clone())
+                    .unwrap_or_else(|| "unknown".to_string());
+                *failures.entry(reason).or_default() += 1;
+            }
+            "completed" => {
+                if let Some(speed) = record.speed_bytes_per_sec {
+                    let day = started.date_naive();
+                    let week_start = day - Days::new(u64::from(day.weekday().num_days_from_monday()));
+                    let week = weeks.entry(week_start).or_default();
+                    week.0 += 1;
+                    week.1 += speed;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut top_peers: Vec<PeerBytes> = peers.into_values().map(|(peer, _)| peer).collect();
+    top_peers.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.peer_hostname.cmp(&b.peer_hostname)));
+    top_peers.truncate(TOP_PEERS);
+    analytics.top_peers = top_peers;
+
+    let mut failure_reasons: Vec<FailureCount> =
+        failures.into_iter().map(|(reason, count)| FailureCount { reason, count }).collect();
+    failure_reasons.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.reason.cmp(&b.reason)));
+    analytics.failure_reasons = failure_reasons;
+
+    analytics.weekly_speed = weeks
+        .into_iter()
+        .map(|(week_start, (transfers, total_speed))| WeeklySpeed {
+            week_start,
+            transfers,
+            average_speed_bytes_per_sec: total_speed / transfers,
+        })
+        .collect();
+    analytics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(peer: Option<Uuid>, hostname: &str, at: &str, status: &str, bytes: u64) -> TransferRecord {
+        let mut record = TransferRecord::new(
+            Uuid::new_v4(),
+            peer,
+            hostname.to_string(),
+            "file.bin".to_string(),
+            String::new(),
+            bytes,
+            "sent".to_string(),
+        );
+        record.timestamp = at.parse().unwrap();
+        record.status = status.to_string();
+        record.bytes_transferred = Some(bytes);
+        record
+    }
+
+    #[test]
+    fn synthetic_history_adds_up() {
+        let (laptop, nas) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut records = vec![
+            // Monday 2026-03-02 and the Tuesday after, then the next week
+            record(Some(laptop), "laptop", "2026-03-02T09:15:00Z", "completed", 1000),
+            record(Some(laptop), "laptop-renamed", "2026-03-03T09:45:00Z", "completed", 3000),
+            record(Some(nas), "nas", "2026-03-10T22:00:00Z", "completed", 500),
+            record(Some(nas), "nas", "2026-03-11T22:30:00Z", "failed", 200),
+            record(Some(nas), "nas", "2026-03-12T08:00:00Z", "failed", 0),
+            record(None, "10.0.0.9", "2026-03-12T08:30:00Z", "failed", 0),
+        ];
+        records[0].speed_bytes_per_sec = Some(100);
+        records[1].speed_bytes_per_sec = Some(300);
+        records[2].speed_bytes_per_sec = Some(50);
+        records[3].error_code = Some("peer_disconnected".to_string());
+        records[4].error_code = Some("peer_disconnected".to_string());
+        records[5].error = Some("Connection refused".to_string());
+        let mut benchmark = record(Some(nas), "nas", "2026-03-12T08:00:00Z", "completed", 1 << 30);
+        benchmark.direction = "benchmark".to_string();
+        records.push(benchmark);
+
+        let analytics = analyze(&records, &Utc);
+        assert_eq!(analytics.transfers, 6);
+        let top: Vec<(&str, u64, u64)> = analytics
+            .top_peers
+            .iter()
+            .map(|peer| (peer.peer_hostname.as_str(), peer.bytes, peer.transfers))
+            .collect();
+        assert_eq!(top, vec![("laptop-renamed", 4000, 2), ("nas", 700, 3), ("10.0.0.9", 0, 1)]);
+        assert_eq!((analytics.by_hour[9], analytics.by_hour[22], analytics.by_hour[8]), (2, 2, 2));
+        assert_eq!(analytics.by_hour.iter().sum::<u64>(), 6);
+        assert_eq!(analytics.by_weekday, vec![1, 2, 1, 2, 0, 0, 0]);
+        assert_eq!(
+            analytics.failure_reasons,
+            vec![
+                FailureCount { reason: "peer_disconnected".to_string(), count: 2 },
+                FailureCount { reason: "Connection refused".to_string(), count: 1 },
+            ]
+        );
+        let weeks: Vec<(String, u64, u64)> = analytics
+            .weekly_speed
+            .iter()
+            .map(|week| (week.week_start.to_string(), week.transfers, week.average_speed_bytes_per_sec))
+            .collect();
+        assert_eq!(weeks, vec![("2026-03-02".to_string(), 2, 200), ("2026-03-09".to_string(), 1, 50)]);
+    }
+}
@@ -0,0 +1,206 @@
+use crate::config::{AcceptAction, AcceptRule, AppConfig};
+use crate::peer::Peer;
+use crate::utils;
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+/// What the rules get to look at for an incoming file.
+pub struct IncomingFile<'a> {
+    pub peer: Option<&'a Peer>,
+    pub filename: &'a str,
+    pub mime_type: Option<&'a str>,
+    pub file_size: u64,
+}
+
+/// The action for an incoming file and the rule that chose it, if any.
+pub struct Decision {
+    pub action: AcceptAction,
+    pub rule: Option<String>,
+}
+
+/// Decides whether incoming files are accepted, and holds the prompts
+/// waiting on a client's answer.
+pub struct ApprovalService {
+    config: Arc<AppConfig>,
+    rules: RwLock<Vec<AcceptRule>>,
+    /// Set while the rules differ from those in the config file.
+    unsaved: AtomicBool,
+    pending: Mutex<HashMap<Uuid, oneshot::Sender<bool>>>,
+}
+
+impl AcceptRule {
+    fn matches(&self, file: &IncomingFile) -> bool {
+        let peer_matches = self.names_peer(file.peer);
+        let mime_matches = self.mime_types.is_empty()
+            || file.mime_type.is_some_and(|mime_type| utils::mime_matches(mime_type, &self.mime_types));
+        let extension_matches =
+            self.extensions.is_empty() || utils::matching_extension(file.filename, &self.extensions).is_some();
+        let size_matches = self.max_size.is_none_or(|max| file.file_size <= max);
+
+        peer_matches && mime_matches && extension_matches && size_matches
+    }
+
+    /// Whether the rule applies to files from `peer`.
+    fn names_peer(&self, peer: Option<&Peer>) -> bool {
+        self.peer.as_deref().is_none_or(|wanted| {
+            peer.is_some_and(|peer| {
+                peer.id.to_string().eq_ignore_ascii_case(wanted) || peer.hostname.eq_ignore_ascii_case(wanted)
+            })
+        })
+    }
+
+    /// Whether the rule looks at nothing but the peer.
+    fn matches_any_file(&self) -> bool {
+        self.mime_types.is_empty() && self.extensions.is_empty() && self.max_size.is_none()
+    }
+}
+
+impl ApprovalService {
+    pub fn new(config: Arc<AppConfig>) -> Self {
+        Self {
+            rules: RwLock::new(config.transfer.accept_rules.clone()),
+            unsaved: AtomicBool::new(false),
+            config,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn rules(&self) -> Vec<AcceptRule> {
+        self.rules.read().unwrap().clone()
+    }
+
+    pub fn default_action(&self) -> AcceptAction {
+        self.config.transfer.default_action
+    }
+
+    /// Replaces the rule list and saves it to the config file. The rules
+    /// apply even if saving fails; `flush` tries again.
+    pub fn set_rules(&self, rules: Vec<AcceptRule>) -> Result<()> {
+        if rules.iter().any(|rule| rule.name.trim().is_empty()) {
+            bail!("Every accept rule needs a name");
+        }
+
+        *self.rules.write().unwrap() = rules;
+        self.unsaved.store(true, Ordering::SeqCst);
+        self.flush()
+            .map_err(|e| anyhow!("Accept rules are in effect but couldn't be saved: {}", e))
+    }
+
+    /// Saves rule changes the config file doesn't have yet.
+    pub fn flush(&self) -> Result<()> {
+        if !self.unsaved.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut config = (*self.config).clone();
+        config.transfer.accept_rules = self.rules();
+        if let Err(e) = config.save() {
+            self.unsaved.store(true, Ordering::SeqCst);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Evaluates the rules in order; the first match wins.
+    pub fn decide(&self, file: &IncomingFile) -> Decision {
+        let rules = self.rules.read().unwrap();
+        match rules.iter().find(|rule| rule.matches(file)) {
+            Some(rule) => Decision {
+                action: rule.action,
+                rule: Some(rule.name.clone()),
+            },
+            None => Decision {
+                action: self.default_action(),
+                rule: None,
+            },
+        }
+    }
+
+    /// Whether the rules turn down every file `peer` could send: a rule
+    /// matching only on the peer rejects, before any that could accept.
+    pub fn rejects_everything_from(&self, peer: &Peer) -> bool {
+        let rules = self.rules.read().unwrap();
+        for rule in rules.iter().filter(|rule| rule.names_peer(Some(peer))) {
+            match (rule.action, rule.matches_any_file()) {
+                (AcceptAction::Reject, true) => return true,
+                (AcceptAction::Reject, false) => continue,
+                _ => return false,
+            }
+        }
+        self.default_action() == AcceptAction::Reject
+    }
+
+    /// Registers a prompt for `transfer_id`; the receiver resolves with the answer.
+    pub fn open_prompt(&self, transfer_id: Uuid) -> oneshot::Receiver<bool> {
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(transfer_id, tx);
+        rx
+    }
+
+    /// Drops a prompt nobody answered.
+    pub fn close_prompt(&self, transfer_id: &Uuid) {
+        self.pending.lock().unwrap().remove(transfer_id);
+    }
+
+    /// Delivers a client's answer to a waiting prompt.
+    pub fn respond(&self, transfer_id: &Uuid, accept: bool) -> Result<()> {
+        let tx = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(transfer_id)
+            .ok_or_else(|| anyhow!("No transfer {} is waiting for approval", transfer_id))?;
+        tx.send(accept)
+            .map_err(|_| anyhow!("Transfer {} is no longer waiting for approval", transfer_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(peer: Option<&str>, extensions: &[&str], action: AcceptAction) -> AcceptRule {
+        AcceptRule {
+            name: "rule".to_string(),
+            peer: peer.map(str::to_string),
+            mime_types: Vec::new(),
+            extensions: extensions.iter().map(|e| e.to_string()).collect(),
+            max_size: None,
+            action,
+        }
+    }
+
+    #[test]
+    fn a_peer_is_blocked_only_when_nothing_it_sends_could_get_through() {
+        let peer = Peer::from_discovery(Uuid::new_v4(), "192.168.1.2:7879".parse().unwrap(), "Laptop".to_string(), None);
+        // (rules, default action, blocked)
+        let cases = [
+            (vec![rule(Some("laptop"), &[], AcceptAction::Reject)], AcceptAction::Accept, true),
+            (vec![rule(Some("desktop"), &[], AcceptAction::Reject)], AcceptAction::Accept, false),
+            // Some of its files are still accepted
+            (vec![rule(Some("laptop"), &["iso"], AcceptAction::Reject)], AcceptAction::Accept, false),
+            (
+                vec![rule(None, &["pdf"], AcceptAction::Accept), rule(Some("laptop"), &[], AcceptAction::Reject)],
+                AcceptAction::Accept,
+                false,
+            ),
+            (
+                vec![rule(None, &["iso"], AcceptAction::Reject), rule(None, &[], AcceptAction::Reject)],
+                AcceptAction::Accept,
+                true,
+            ),
+            (Vec::new(), AcceptAction::Reject, true),
+            (Vec::new(), AcceptAction::Prompt, false),
+        ];
+        for (rules, default_action, blocked) in cases {
+            let mut config = AppConfig::default();
+            config.transfer.accept_rules = rules.clone();
+            config.transfer.default_action = default_action;
+            let approvals = ApprovalService::new(Arc::new(config));
+            assert_eq!(approvals.rejects_everything_from(&peer), blocked, "{:?}, default {:?}", rules, default_action);
+        }
+    }
+}
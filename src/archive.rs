@@ -0,0 +1,174 @@
+use crate::config::ArchiveCompression;
+use anyhow::{anyhow, bail, Result};
+use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+/// The type archives are offered as.
+pub const MIME_TYPE: &str = "application/zip";
+/// Archive chunks buffered ahead of the send reading them.
+const ARCHIVE_BUFFER_CHUNKS: usize = 4;
+
+/// A file going into an archive, under its own name.
+pub struct Entry {
+    pub path: PathBuf,
+    pub name: String,
+    pub size: u64,
+}
+
+/// Checks every path is a readable file and that no two share a name, as
+/// they would in the archive. Errors name the offending path.
+pub async fn entries(paths: &[PathBuf]) -> Result<Vec<Entry>> {
+    if paths.is_empty() {
+        bail!("No files to archive");
+    }
+    let mut names = HashSet::new();
+    let mut entries = Vec::with_capacity(paths.len());
+    for path in paths {
+        let metadata = tokio::fs::metadata(path)
+            .await
+            .map_err(|e| anyhow!("Couldn't read {}: {}", path.display(), e))?;
+        if !metadata.is_file() {
+            bail!("{} is not a file", path.display());
+        }
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| anyhow!("{} has no usable file name", path.display()))?
+            .to_string();
+        if !names.insert(name.clone()) {
+            bail!("{} has the same name as another file in the archive", path.display());
+        }
+        entries.push(Entry {
+            path: path.clone(),
+            name,
+            size: metadata.len(),
+        });
+    }
+    Ok(entries)
+}
+
+/// Writes a zip of `entries` on a blocking task, handing it out in chunks
+/// of `chunk_size` as it's made; nothing is kept on disk. A file that can't
+/// be read ends it with an error naming the file. Dropping the receiver
+/// stops it.
+pub fn stream(entries: Vec<Entry>, compression: ArchiveCompression, chunk_size: usize) -> mpsc::Receiver<Result<Arc<Vec<u8>>>> {
+    let (tx, rx) = mpsc::channel(ARCHIVE_BUFFER_CHUNKS);
+    tokio::task::spawn_blocking(move || {
+        let mut chunks = ChunkWriter {
+            tx: tx.clone(),
+            buffer: Vec::with_capacity(chunk_size),
+            chunk_size,
+        };
+        let written = write_zip(&mut chunks, &entries, compression).and_then(|()| Ok(chunks.flush()?));
+        if let Err(e) = written {
+            // Nobody is left to tell once the send has stopped reading
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+    rx
+}
+
+fn write_zip(out: &mut ChunkWriter, entries: &[Entry], compression: ArchiveCompression) -> Result<()> {
+    let method = match compression {
+        ArchiveCompression::Stored => CompressionMethod::Stored,
+        ArchiveCompression::Deflate => CompressionMethod::Deflated,
+    };
+    let mut zip = ZipWriter::new_stream(out);
+    let mut buffer = vec![0u8; 64 * 1024];
+    for entry in entries {
+        let options = SimpleFileOptions::default()
+            .compression_method(method)
+            .large_file(entry.size >= u64::from(u32::MAX));
+        let unreadable = |e: std::io::Error| anyhow!("Couldn't read {}: {}", entry.path.display(), e);
+        let mut file = std::fs::File::open(&entry.path).map_err(unreadable)?;
+        zip.start_file(entry.name.as_str(), options)?;
+        loop {
+            let n = file.read(&mut buffer).map_err(unreadable)?;
+            if n == 0 {
+                break;
+            }
+            zip.write_all(&buffer[..n])?;
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// Cuts what the zip writer writes into chunks for the send.
+struct ChunkWriter {
+    tx: mpsc::Sender<Result<Arc<Vec<u8>>>>,
+    buffer: Vec<u8>,
+    chunk_size: usize,
+}
+
+impl ChunkWriter {
+    fn send(&mut self) -> std::io::Result<()> {
+        let chunk = std::mem::replace(&mut self.buffer, Vec::with_capacity(self.chunk_size));
+        self.tx
+            .blocking_send(Ok(Arc::new(chunk)))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "The send stopped"))
+    }
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        let n = data.len().min(self.chunk_size - self.buffer.len());
+        self.buffer.extend_from_slice(&data[..n]);
+        if self.buffer.len() == self.chunk_size {
+            self.send()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        self.send()
+    }
+}
+
+/// The name to offer an archive as: `name`, ending in .zip.
+pub fn archive_name(name: &str) -> String {
+    if Path::new(name).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("zip")) {
+        name.to_string()
+    } else {
+        format!("{}.zip", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn a_file_gone_before_it_is_read_ends_the_archive_naming_it() {
+        let dir = std::env::temp_dir().join(format!("p2p-sharing-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("other")).unwrap();
+        let (first, second) = (dir.join("first.txt"), dir.join("second.txt"));
+        std::fs::write(&first, b"first").unwrap();
+        std::fs::write(&second, b"second").unwrap();
+        std::fs::write(dir.join("other").join("first.txt"), b"clash").unwrap();
+
+        let clash = entries(&[first.clone(), dir.join("other").join("first.txt")]).await;
+        assert!(clash.err().unwrap().to_string().contains("other"));
+
+        let listed = entries(&[first, second.clone()]).await.unwrap();
+        std::fs::remove_file(&second).unwrap();
+        let mut chunks = stream(listed, ArchiveCompression::Stored, 16);
+        let error = loop {
+            match chunks.recv().await {
+                Some(Ok(chunk)) => assert!(chunk.len() <= 16),
+                Some(Err(e)) => break e,
+                None => panic!("the archive finished without the missing file"),
+            }
+        };
+        assert!(error.to_string().contains(&second.display().to_string()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,358 @@
+use crate::config::{BandwidthConfig, BandwidthWindow};
+use chrono::{Datelike, Duration as ChronoDuration, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// How often the schedule is checked, so a transfer running across a
+/// boundary changes speed soon after it.
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Longest a transfer sleeps before looking at its limit again, so one
+/// lifted mid-wait takes effect.
+const MAX_WAIT: Duration = Duration::from_millis(250);
+
+/// Limits in force, as clients see them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthLimits {
+    /// Bytes per second for all sends together; unlimited if unset.
+    pub max_up: Option<u64>,
+    /// Bytes per second for all receives together; unlimited if unset.
+    pub max_down: Option<u64>,
+    /// Position in `bandwidth.schedule` of the window in effect; unset
+    /// outside every window.
+    pub window: Option<usize>,
+}
+
+/// A token bucket shared by every transfer in one direction. Up to a
+/// second's worth may be used in a burst. Waiters go in the order they
+/// asked, so a transfer asking a chunk at a time gets its turn between the
+/// chunks of every other, however large they are.
+pub struct RateLimiter {
+    state: Mutex<Bucket>,
+}
+
+struct Bucket {
+    limit: Option<u64>,
+    /// Bytes asked for so far.
+    requested: f64,
+    /// Bytes let through so far; ahead of `requested` by what may go now,
+    /// behind it while transfers wait their turn.
+    allowed: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.refilled = now;
+        self.allowed = match self.limit {
+            Some(limit) => (self.allowed + elapsed * limit as f64).min(self.requested + limit as f64),
+            None => self.requested,
+        };
+    }
+}
+
+impl RateLimiter {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(Bucket {
+                limit: None,
+                requested: 0.0,
+                allowed: 0.0,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Sets the limit in bytes per second; None lifts it.
+    pub fn set_limit(&self, limit: Option<u64>) {
+        let mut bucket = self.state.lock().unwrap();
+        bucket.refill(Instant::now());
+        bucket.limit = limit.filter(|limit| *limit > 0);
+    }
+
+    /// Waits until `bytes` more may go under the limit, after everything
+    /// asked for before them. The limit is looked at again while waiting,
+    /// so a change applies to transfers already held up.
+    pub async fn acquire(&self, bytes: usize) {
+        let turn = {
+            let mut bucket = self.state.lock().unwrap();
+            if bucket.limit.is_none() {
+                return;
+            }
+            bucket.refill(Instant::now());
+            bucket.requested += bytes as f64;
+            bucket.requested
+        };
+        loop {
+            let wait = {
+                let mut bucket = self.state.lock().unwrap();
+                bucket.refill(Instant::now());
+                match bucket.limit {
+                    Some(limit) if bucket.allowed < turn => Duration::from_secs_f64((turn - bucket.allowed) / limit as f64),
+                    _ => return,
+                }
+            };
+            tokio::time::sleep(wait.min(MAX_WAIT)).await;
+        }
+    }
+}
+
+/// Applies `bandwidth.max_up` and `bandwidth.schedule`: holds the limiters
+/// every transfer goes through and moves them between windows as local time
+/// passes.
+pub struct Bandwidth {
+    schedule: Vec<BandwidthWindow>,
+    /// Cap on all sends together wherever no window sets one.
+    max_up: Mutex<Option<u64>>,
+    upload: RateLimiter,
+    download: RateLimiter,
+    current: Mutex<BandwidthLimits>,
+}
+
+impl Bandwidth {
+    /// Starts with the limits of whichever window covers the time now.
+    pub fn new(config: &BandwidthConfig) -> Self {
+        let bandwidth = Self {
+            schedule: config.schedule.clone(),
+            max_up: Mutex::new(config.max_up),
+            upload: RateLimiter::new(),
+            download: RateLimiter::new(),
+            current: Mutex::new(BandwidthLimits::default()),
+        };
+        bandwidth.apply(chrono::Local::now().naive_local());
+        bandwidth
+    }
+
+    /// Limiter for what we send.
+    pub fn upload(&self) -> &RateLimiter {
+        &self.upload
+    }
+
+    /// Limiter for what we receive.
+    pub fn download(&self) -> &RateLimiter {
+        &self.download
+    }
+
+    /// Limits in force now.
+    pub fn current(&self) -> BandwidthLimits {
+        *self.current.lock().unwrap()
+    }
+
+    /// Replaces `bandwidth.max_up`, for the next chunk of every send.
+    pub fn set_max_up(&self, max_up: Option<u64>) -> BandwidthLimits {
+        *self.max_up.lock().unwrap() = max_up;
+        self.apply(chrono::Local::now().naive_local());
+        self.current()
+    }
+
+    /// Whether there is a schedule to follow at all.
+    pub fn is_scheduled(&self) -> bool {
+        !self.schedule.is_empty()
+    }
+
+    /// Re-checks the schedule every `SCHEDULE_CHECK_INTERVAL` until shutdown.
+    pub async fn follow_schedule(&self, shutdown: CancellationToken) -> anyhow::Result<()> {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(SCHEDULE_CHECK_INTERVAL) => {}
+                _ = shutdown.cancelled() => return Ok(()),
+            }
+            self.apply(chrono::Local::now().naive_local());
+        }
+    }
+
+    /// Sets the limits of the window covering local time `now`, logging
+    /// when they change.
+    fn apply(&self, now: NaiveDateTime) {
+        let mut limits = limits_at(&self.schedule, now);
+        limits.max_up = limits.max_up.or(*self.max_up.lock().unwrap());
+        let mut current = self.current.lock().unwrap();
+        if *current == limits {
+            return;
+        }
+        self.upload.set_limit(limits.max_up);
+        self.download.set_limit(limits.max_down);
+        let describe = |limit: Option<u64>| match limit {
+            Some(limit) => format!("{}/s", crate::utils::format_bytes(limit)),
+            None => "unlimited".to_string(),
+        };
+        match limits.window {
+            Some(window) => tracing::info!(
+                "Bandwidth schedule window {} in effect: up {}, down {}",
+                window,
+                describe(limits.max_up),
+                describe(limits.max_down)
+            ),
+            None => tracing::info!(
+                "Bandwidth limits outside any schedule window: up {}, down {}",
+                describe(limits.max_up),
+                describe(limits.max_down)
+            ),
+        }
+        *current = limits;
+    }
+}
+
+/// Limits at local wall-clock time `now`. Times are compared on the clock
+/// as shown, so across a DST change a window starting in a skipped hour
+/// begins once the clock passes it, and an hour that repeats is treated the
+/// same both times.
+fn limits_at(schedule: &[BandwidthWindow], now: NaiveDateTime) -> BandwidthLimits {
+    schedule
+        .iter()
+        .position(|window| covers(window, now))
+        .map(|index| BandwidthLimits {
+            max_up: schedule[index].max_up,
+            max_down: schedule[index].max_down,
+            window: Some(index),
+        })
+        .unwrap_or_default()
+}
+
+fn covers(window: &BandwidthWindow, now: NaiveDateTime) -> bool {
+    let starts_on = |date: NaiveDateTime| window.days.is_empty() || window.days.contains(&date.weekday());
+    let time = now.time();
+    if window.start == window.end {
+        return starts_on(now);
+    }
+    if window.start < window.end {
+        return starts_on(now) && window.start <= time && time < window.end;
+    }
+    // Runs past midnight: the part after the start, or the part before the
+    // end on the day after a day it starts on
+    (starts_on(now) && time >= window.start) || (starts_on(now - ChronoDuration::days(1)) && time < window.end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, NaiveTime, Weekday};
+
+    fn window(days: Vec<Weekday>, start: &str, end: &str, max_up: u64) -> BandwidthWindow {
+        BandwidthWindow {
+            days,
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+            max_up: Some(max_up),
+            max_down: None,
+        }
+    }
+
+    /// 2026-10-12 is a Monday.
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2026, 10, day)
+            .unwrap()
+            .and_time(time.parse::<NaiveTime>().unwrap())
+    }
+
+    #[test]
+    fn first_window_covering_the_time_applies() {
+        let schedule = vec![
+            window(vec![Weekday::Mon], "18:00", "23:00", 1),
+            window(Vec::new(), "22:00", "06:00", 2),
+        ];
+        assert_eq!(limits_at(&schedule, at(12, "17:59")).window, None);
+        assert_eq!(limits_at(&schedule, at(12, "18:00")).max_up, Some(1));
+        assert_eq!(limits_at(&schedule, at(12, "22:30")).max_up, Some(1));
+        assert_eq!(limits_at(&schedule, at(12, "23:00")).max_up, Some(2));
+        // Past midnight, still the window that started on Monday
+        assert_eq!(limits_at(&schedule, at(13, "05:59")).max_up, Some(2));
+        assert_eq!(limits_at(&schedule, at(13, "06:00")), BandwidthLimits::default());
+        // Tuesday evening has only the every-day window
+        assert_eq!(limits_at(&schedule, at(13, "18:30")).window, None);
+    }
+
+    #[test]
+    fn schedule_reads_from_config() {
+        let config: BandwidthConfig = toml::from_str(
+            r#"
+            [[schedule]]
+            days = ["mon", "Friday"]
+            start = "18:00"
+            end = "23:00"
+            max_up = 5242880
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.schedule, vec![window(vec![Weekday::Mon, Weekday::Fri], "18:00", "23:00", 5242880)]);
+    }
+
+    #[test]
+    fn overnight_window_follows_the_day_it_starts_on() {
+        let schedule = vec![window(vec![Weekday::Fri], "22:00", "02:00", 1)];
+        assert_eq!(limits_at(&schedule, at(16, "23:00")).window, Some(0));
+        assert_eq!(limits_at(&schedule, at(17, "01:00")).window, Some(0));
+        assert_eq!(limits_at(&schedule, at(16, "01:00")).window, None);
+        // Same start and end is all of that day
+        let all_day = vec![window(vec![Weekday::Sun], "00:00", "00:00", 1)];
+        assert_eq!(limits_at(&all_day, at(18, "13:00")).window, Some(0));
+        assert_eq!(limits_at(&all_day, at(19, "13:00")).window, None);
+    }
+
+    #[test]
+    fn a_window_setting_its_own_cap_replaces_the_standing_one() {
+        let config = BandwidthConfig {
+            max_up: Some(50),
+            schedule: vec![window(vec![Weekday::Mon], "18:00", "23:00", 1), {
+                let mut downloads_only = window(vec![Weekday::Tue], "18:00", "23:00", 0);
+                downloads_only.max_up = None;
+                downloads_only.max_down = Some(2);
+                downloads_only
+            }],
+        };
+        let bandwidth = Bandwidth::new(&config);
+        bandwidth.apply(at(12, "12:00"));
+        assert_eq!(bandwidth.current().max_up, Some(50));
+        bandwidth.apply(at(12, "19:00"));
+        assert_eq!(bandwidth.current().max_up, Some(1));
+        bandwidth.apply(at(13, "19:00"));
+        assert_eq!((bandwidth.current().max_up, bandwidth.current().max_down), (Some(50), Some(2)));
+        assert_eq!(bandwidth.set_max_up(None).max_up, None);
+    }
+
+    #[tokio::test]
+    async fn limiter_spreads_bytes_over_time_and_follows_changes() {
+        let limiter = RateLimiter::new();
+        let started = Instant::now();
+        limiter.acquire(1_000_000).await;
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        limiter.set_limit(Some(100_000));
+        limiter.acquire(30_000).await;
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(250) && elapsed < Duration::from_secs(1), "{:?}", elapsed);
+
+        // Lifting the limit frees a transfer that was held up
+        let waiting = limiter.acquire(1_000_000);
+        tokio::pin!(waiting);
+        assert!(tokio::time::timeout(Duration::from_millis(100), &mut waiting).await.is_err());
+        limiter.set_limit(None);
+        tokio::time::timeout(Duration::from_secs(1), waiting).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_large_send_cannot_starve_a_small_one() {
+        let limiter = std::sync::Arc::new(RateLimiter::new());
+        limiter.set_limit(Some(200_000));
+        let large = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move {
+                loop {
+                    limiter.acquire(20_000).await;
+                }
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // Its three chunks take turns with the large one's, at half the cap
+        let started = Instant::now();
+        for _ in 0..3 {
+            limiter.acquire(20_000).await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(400) && elapsed < Duration::from_millis(900), "{:?}", elapsed);
+        large.abort();
+    }
+}
@@ -0,0 +1,226 @@
+use crate::capability;
+use crate::protocol::{BenchmarkLeg, BenchmarkResult};
+use crate::transfer::{
+    Connection, ConnectionContext, Route, TransferError, TransferMessage, TransferService, MAX_CONTROL_MESSAGE_LEN,
+    PROTOCOL_VERSION,
+};
+use crate::utils;
+use anyhow::Result;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufRead, AsyncWrite};
+use tokio::time::{timeout, Duration, Instant};
+use uuid::Uuid;
+
+/// Longest a benchmark runs each way, whatever it asks for.
+pub const MAX_BENCHMARK_DURATION: Duration = Duration::from_secs(60);
+/// How long either side of a benchmark waits on the other.
+const BENCHMARK_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+impl TransferService {
+    /// Takes part in a peer's benchmark, never touching disk.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn serve_benchmark<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        addr: SocketAddr,
+        context: &ConnectionContext,
+        transfer_id: Uuid,
+        peer_sends: bool,
+        payload_size: u64,
+        duration: Duration,
+    ) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        if let Some(budget) = context.usage.exhausted_budget() {
+            let reject = TransferMessage::Reject {
+                transfer_id,
+                reason: Some(TransferError::DailyBudgetExhausted { budget }.to_string()),
+                protocol_version: None,
+                max_file_size: None,
+            };
+            return Self::write_message(stream, &reject).await;
+        }
+        let peer_id = match &context.relayed {
+            Some(origin) => Some(origin.peer_id),
+            None => context.peers.read().await.find_by_ip(addr.ip().to_canonical()).map(|peer| peer.id),
+        };
+        tracing::info!("Benchmarking the link with {} ({})", addr, if peer_sends { "sending" } else { "receiving" });
+        let accept = TransferMessage::Accept {
+            transfer_id,
+            public_key: None,
+            identity_key: None,
+            protocol_version: Some(PROTOCOL_VERSION),
+            window: None,
+            framed: false,
+            compression: None,
+            chunk_checksums: false,
+            parallel_streams: 0,
+        };
+        Self::write_message(stream, &accept).await?;
+        if peer_sends {
+            return Self::send_benchmark_chunks(stream, context, peer_id, transfer_id, payload_size, duration).await;
+        }
+        let leg = Self::receive_benchmark_chunks(reader, context, peer_id, transfer_id).await?;
+        Self::write_message(stream, &TransferMessage::BenchmarkReport { transfer_id, leg }).await
+    }
+
+    /// Sends generated chunks until `duration` has passed or `payload_size`
+    /// bytes have gone, then Complete.
+    async fn send_benchmark_chunks<W: AsyncWrite + Unpin>(
+        stream: &mut W,
+        context: &ConnectionContext,
+        peer_id: Option<Uuid>,
+        transfer_id: Uuid,
+        payload_size: u64,
+        duration: Duration,
+    ) -> Result<()> {
+        let chunk_size = context.config.transfer.chunk_size;
+        let pattern: Vec<u8> = (0..chunk_size).map(|i| (i % 251) as u8).collect();
+        let started = Instant::now();
+        let mut sent = 0u64;
+        let mut chunk_index = 0u64;
+        while sent < payload_size && started.elapsed() < duration {
+            let len = (payload_size - sent).min(chunk_size as u64) as usize;
+            let chunk = TransferMessage::Chunk {
+                transfer_id,
+                chunk_index,
+                data: pattern[..len].to_vec(),
+                crc: None,
+            };
+            Self::write_with_liveness(stream, &chunk, BENCHMARK_STALL_TIMEOUT).await?;
+            context.usage.record_sent(peer_id, len);
+            sent += len as u64;
+            chunk_index += 1;
+        }
+        let complete = TransferMessage::Complete {
+            transfer_id,
+            file_checksum: None,
+            seal: None,
+        };
+        Self::write_with_liveness(stream, &complete, BENCHMARK_STALL_TIMEOUT).await
+    }
+
+    /// Reads and discards benchmark chunks up to their Complete, timing how
+    /// long decoding them takes.
+    async fn receive_benchmark_chunks<R: AsyncBufRead + Unpin>(
+        reader: &mut R,
+        context: &ConnectionContext,
+        peer_id: Option<Uuid>,
+        transfer_id: Uuid,
+    ) -> Result<BenchmarkLeg> {
+        let max_message_len = Self::max_chunk_message_len(context.config.transfer.chunk_size);
+        let started = Instant::now();
+        let mut decoding = Duration::ZERO;
+        let mut leg = BenchmarkLeg::default();
+        loop {
+            let line = timeout(BENCHMARK_STALL_TIMEOUT, Self::read_raw_message(reader, max_message_len)).await??;
+            let decode_started = Instant::now();
+            let message = serde_json::from_str(&line)?;
+            decoding += decode_started.elapsed();
+            match message {
+                TransferMessage::Chunk {
+                    transfer_id: tid,
+                    chunk_index,
+                    data,
+                    ..
+                } if tid == transfer_id && chunk_index == leg.chunks => {
+                    context.usage.record_received(peer_id, data.len());
+                    leg.bytes += data.len() as u64;
+                    leg.chunks += 1;
+                }
+                TransferMessage::Complete { transfer_id: tid, .. } if tid == transfer_id => break,
+                message => {
+                    return Err(match Self::abort_of(message) {
+                        Some(aborted) => aborted.into(),
+                        None => anyhow::anyhow!("Unexpected message during the benchmark"),
+                    });
+                }
+            }
+        }
+        let elapsed = started.elapsed();
+        leg.elapsed_ms = elapsed.as_millis() as u64;
+        leg.bytes_per_sec = utils::average_speed(leg.bytes, elapsed);
+        if !elapsed.is_zero() {
+            leg.chunk_overhead_percent = decoding.as_secs_f64() / elapsed.as_secs_f64() * 100.0;
+        }
+        Ok(leg)
+    }
+
+    /// Measures the link to a known peer with up to `duration` or
+    /// `payload_size` bytes of generated data each way, sent as a transfer's
+    /// chunks are. The bytes count toward the daily budget like a transfer's.
+    pub async fn benchmark_peer(&self, peer_id: Uuid, duration: Duration, payload_size: u64) -> Result<BenchmarkResult> {
+        let peer = self.peers.read().await.get_peer(&peer_id).cloned();
+        let Some(peer) = peer else {
+            anyhow::bail!("Peer not found");
+        };
+        if !capability::supports(&peer.capabilities, capability::BENCHMARK) {
+            anyhow::bail!("{} does not support benchmarks", peer.hostname);
+        }
+        if let Some(budget) = self.usage.exhausted_budget() {
+            return Err(TransferError::DailyBudgetExhausted { budget }.into());
+        }
+        let context = self.context();
+        let duration = duration.min(MAX_BENCHMARK_DURATION);
+        let (upload, upload_rtt) = self.benchmark_leg(&context, peer_id, false, duration, payload_size).await?;
+        let (download, download_rtt) = self.benchmark_leg(&context, peer_id, true, duration, payload_size).await?;
+        Ok(BenchmarkResult {
+            peer_id,
+            rtt_ms: upload_rtt.min(download_rtt).as_secs_f64() * 1000.0,
+            chunk_size: context.config.transfer.chunk_size,
+            upload,
+            download,
+        })
+    }
+
+    /// Runs one direction of a benchmark on a connection of its own. Returns
+    /// what the receiving side measured, and the round trip of the request.
+    async fn benchmark_leg(
+        &self,
+        context: &ConnectionContext,
+        peer_id: Uuid,
+        peer_sends: bool,
+        duration: Duration,
+        payload_size: u64,
+    ) -> Result<(BenchmarkLeg, Duration)> {
+        let Connection {
+            mut reader,
+            mut writer,
+            ..
+        } = self.open(Route::Peer(peer_id)).await?;
+        let transfer_id = Uuid::new_v4();
+        let request = TransferMessage::Benchmark {
+            transfer_id,
+            peer_sends,
+            payload_size,
+            duration_secs: duration.as_secs(),
+        };
+        let asked = Instant::now();
+        Self::write_message(&mut writer, &request).await?;
+        let response = timeout(Duration::from_secs(10), Self::read_message(&mut reader, MAX_CONTROL_MESSAGE_LEN)).await??;
+        let rtt = asked.elapsed();
+        match response {
+            TransferMessage::Accept { transfer_id: tid, .. } if tid == transfer_id => {}
+            TransferMessage::Reject { reason, .. } => {
+                anyhow::bail!(
+                    "Benchmark refused by peer: {}",
+                    reason.unwrap_or_else(|| "No reason provided".to_string())
+                );
+            }
+            _ => anyhow::bail!("Unexpected response"),
+        }
+
+        let leg = if peer_sends {
+            Self::receive_benchmark_chunks(&mut reader, context, Some(peer_id), transfer_id).await?
+        } else {
+            Self::send_benchmark_chunks(&mut writer, context, Some(peer_id), transfer_id, payload_size, duration).await?;
+            match timeout(BENCHMARK_STALL_TIMEOUT, Self::read_message(&mut reader, MAX_CONTROL_MESSAGE_LEN)).await?? {
+                TransferMessage::BenchmarkReport { transfer_id: tid, leg } if tid == transfer_id => leg,
+                _ => anyhow::bail!("Unexpected response"),
+            }
+        };
+        Ok((leg, rtt))
+    }
+}
@@ -0,0 +1,265 @@
+use crate::active::ActiveTransfer;
+use crate::config::SlowPeerPolicy;
+use crate::transfer::{
+    ChunkSource, Connection, ConnectionContext, Offer, Outgoing, Route, TransferError, TransferOutcome, TransferService,
+};
+use anyhow::Result;
+use futures_util::future::{join_all, select_all};
+use futures_util::FutureExt;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+/// Chunks buffered per peer of a broadcast.
+const FAN_OUT_BUFFER: usize = 64;
+
+/// One peer of a broadcast.
+pub struct BroadcastTarget {
+    pub transfer: ActiveTransfer,
+    pub peer_id: Uuid,
+}
+
+impl TransferService {
+    /// Sends one file to several peers, reading it from disk once for all of
+    /// them. Each peer's result goes to `results` as soon as it is known,
+    /// tagged with its index in `targets`.
+    pub async fn broadcast_file(
+        &self,
+        targets: &[BroadcastTarget],
+        file_path: &Path,
+        file_checksum: Option<String>,
+        results: mpsc::UnboundedSender<(usize, Result<TransferOutcome>)>,
+    ) {
+        let context = self.context();
+
+        // Everyone answers before the shared reader starts, so peers that
+        // accept quickly don't leave the rest to catch up from the start
+        let offers = join_all(targets.iter().map(|target| {
+            self.connect_and_offer(&context, target, file_path, file_checksum.clone())
+        }))
+        .await;
+
+        let mut feeds = Vec::new();
+        let mut sends = Vec::new();
+        for (index, (target, offered)) in targets.iter().zip(offers).enumerate() {
+            let (
+                Connection {
+                    mut reader,
+                    mut writer,
+                    connectivity,
+                    ..
+                },
+                offer,
+            ) = match offered {
+                Ok(connection) => connection,
+                Err(e) => {
+                    let _ = results.send((index, Err(e)));
+                    continue;
+                }
+            };
+            if offer.identical {
+                let _ = results.send((index, Ok(Self::identical_outcome(&target.transfer, offer, connectivity))));
+                continue;
+            }
+
+            // Peers past the send limit wait for a slot and then read the file
+            // themselves, so the shared reader never waits on them
+            let permit = context.send_slots.try_acquire().ok();
+            let source = if permit.is_some() {
+                let (feed, chunks) = mpsc::channel(FAN_OUT_BUFFER);
+                feeds.push(feed);
+                ChunkSource::Shared {
+                    chunks,
+                    path: file_path.to_path_buf(),
+                }
+            } else {
+                match File::open(file_path).await {
+                    Ok(file) => ChunkSource::File(file),
+                    Err(e) => {
+                        let _ = results.send((index, Err(e.into())));
+                        continue;
+                    }
+                }
+            };
+
+            let context = ConnectionContext {
+                connectivity,
+                ..context.clone()
+            };
+            let results = &results;
+            sends.push(async move {
+                let context = &context;
+                let transfer = &target.transfer;
+                let result = async {
+                    let _permit = match permit {
+                        Some(permit) => permit,
+                        None => Self::wait_for_send_slot(context, &mut writer, transfer, offer.keepalive).await?,
+                    };
+                    Self::stream_chunks(context, &mut reader, &mut writer, transfer, offer, source).await
+                }
+                .await;
+                let _ = results.send((index, result));
+            });
+        }
+
+        let policy = self.config.transfer.broadcast_slow_peer;
+        let chunk_size = self.config.transfer.chunk_size;
+        let (read, _) = tokio::join!(Self::fan_out(file_path, chunk_size, feeds, policy), join_all(sends));
+        if let Err(e) = read {
+            tracing::warn!("Broadcast reader for {:?} failed, peers read on their own: {}", file_path, e);
+        }
+    }
+
+    /// Connects to one broadcast peer and offers it the file.
+    async fn connect_and_offer(
+        &self,
+        context: &ConnectionContext,
+        target: &BroadcastTarget,
+        file_path: &Path,
+        file_checksum: Option<String>,
+    ) -> Result<(Connection, Offer)> {
+        let transfer = &target.transfer;
+        let route = Route::Peer(target.peer_id);
+        let file_size = tokio::fs::metadata(file_path).await?.len();
+        self.check_space(route, file_size).await?;
+
+        let mut connection = tokio::select! {
+            connection = self.open(route) => connection?,
+            _ = transfer.cancelled() => return Err(TransferError::Cancelled.into()),
+        };
+        let outgoing = Outgoing::file(&context.config, file_path, file_checksum).await?;
+        let offer = Self::offer(
+            context,
+            &mut connection.reader,
+            &mut connection.writer,
+            transfer,
+            connection.address,
+            outgoing,
+        )
+        .await?;
+        Ok((connection, offer))
+    }
+
+    /// Reads `file_path` once and hands every chunk to each feed. Under
+    /// `Wait` the slowest peer sets the pace; under `CatchUp` the fastest
+    /// does, and a peer whose buffer fills is dropped to read the rest itself.
+    async fn fan_out(
+        file_path: &Path,
+        chunk_size: usize,
+        mut feeds: Vec<mpsc::Sender<Arc<Vec<u8>>>>,
+        policy: SlowPeerPolicy,
+    ) -> Result<()> {
+        let mut file = File::open(file_path).await?;
+        while !feeds.is_empty() {
+            let mut buffer = vec![0u8; chunk_size];
+            let n = file.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            buffer.truncate(n);
+            let chunk = Arc::new(buffer);
+
+            if policy == SlowPeerPolicy::CatchUp {
+                // Wait for the fastest peer to have room for a good part of a
+                // buffer, so peers keeping the same pace aren't dropped over
+                // a few chunks of jitter
+                let room = feeds.iter().map(|feed| feed.reserve_many(FAN_OUT_BUFFER / 2).boxed());
+                let _ = select_all(room).await;
+            }
+
+            let mut kept = Vec::with_capacity(feeds.len());
+            for feed in feeds {
+                let delivered = match policy {
+                    SlowPeerPolicy::Wait => feed.send(chunk.clone()).await.is_ok(),
+                    SlowPeerPolicy::CatchUp => match feed.try_send(chunk.clone()) {
+                        Ok(()) => true,
+                        Err(mpsc::error::TrySendError::Full(_)) => {
+                            tracing::debug!("Broadcast peer fell behind, it reads the rest of {:?} itself", file_path);
+                            false
+                        }
+                        Err(mpsc::error::TrySendError::Closed(_)) => false,
+                    },
+                };
+                if delivered {
+                    kept.push(feed);
+                }
+            }
+            feeds = kept;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::tests::{scratch_dir, CHUNK};
+    use std::path::PathBuf;
+    use tokio::time::{timeout, Duration};
+
+    /// A file of `chunks` chunks to broadcast, each told apart by its bytes.
+    fn broadcast_source(dir: &Path, chunks: usize) -> (PathBuf, Vec<u8>) {
+        let path = dir.join("broadcast.bin");
+        let data: Vec<u8> = (0..CHUNK * chunks).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        (path, data)
+    }
+
+    async fn drain(mut chunks: mpsc::Receiver<Arc<Vec<u8>>>) -> Vec<u8> {
+        let mut received = Vec::new();
+        while let Some(chunk) = chunks.recv().await {
+            received.extend_from_slice(&chunk);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn broadcasts_waiting_for_a_slow_peer_hold_everyone_to_its_pace() {
+        let dir = scratch_dir();
+        let (path, data) = broadcast_source(&dir, FAN_OUT_BUFFER * 4);
+        let (fast, mut fast_chunks) = mpsc::channel(FAN_OUT_BUFFER);
+        let (slow, slow_chunks) = mpsc::channel(FAN_OUT_BUFFER);
+        let reading = tokio::spawn({
+            let path = path.clone();
+            async move { TransferService::fan_out(&path, CHUNK, vec![fast, slow], SlowPeerPolicy::Wait).await }
+        });
+
+        // The fast peer gets only as far as the slow one's buffer plus the
+        // chunk the reader is stuck handing over
+        let mut held = Vec::new();
+        while let Ok(Some(chunk)) = timeout(Duration::from_millis(200), fast_chunks.recv()).await {
+            held.extend_from_slice(&chunk);
+        }
+        assert_eq!(held.len(), (FAN_OUT_BUFFER + 1) * CHUNK);
+        assert!(!reading.is_finished());
+
+        // Once the slow peer reads, both get the whole file
+        let (rest, slow_received) = tokio::join!(drain(fast_chunks), drain(slow_chunks));
+        held.extend(rest);
+        assert_eq!(held, data);
+        assert_eq!(slow_received, data);
+        reading.await.unwrap().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn broadcasts_catching_up_drop_a_slow_peer_and_keep_the_rest_going() {
+        let dir = scratch_dir();
+        let (path, data) = broadcast_source(&dir, FAN_OUT_BUFFER * 4);
+        let (fast, fast_chunks) = mpsc::channel(FAN_OUT_BUFFER);
+        let (slow, slow_chunks) = mpsc::channel(FAN_OUT_BUFFER);
+        let fast_received = tokio::spawn(drain(fast_chunks));
+
+        // The slow peer reads nothing, yet the file is read to the end
+        let reading = TransferService::fan_out(&path, CHUNK, vec![fast, slow], SlowPeerPolicy::CatchUp);
+        timeout(Duration::from_secs(5), reading).await.unwrap().unwrap();
+        assert_eq!(fast_received.await.unwrap(), data);
+        // It was fed until its buffer filled, then let go to read the rest itself
+        let slow_received = drain(slow_chunks).await;
+        assert_eq!(slow_received, data[..FAN_OUT_BUFFER * CHUNK]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::config::AppConfig;
+
+/// Encrypts transfers when the sender offers a key.
+pub(crate) const ENCRYPTION: &str = "encryption";
+/// Answers a SpaceQuery before a large file is offered.
+pub(crate) const SPACE_QUERY: &str = "space_query";
+/// Takes part in benchmarks.
+pub(crate) const BENCHMARK: &str = "benchmark";
+/// Answers a reachability Probe.
+pub(crate) const PROBE: &str = "probe";
+/// Takes transfers whose size isn't known up front, such as archives.
+pub(crate) const UNSIZED: &str = "unsized";
+/// Takes several files over one connection, listed up front.
+pub(crate) const SESSIONS: &str = "sessions";
+/// Opens offers and manifests sealed to its identity key.
+pub(crate) const SEALED: &str = "sealed";
+/// Serves a shared folder.
+pub(crate) const SHARE: &str = "share";
+/// Relays for some peers.
+pub(crate) const RELAY: &str = "relay";
+/// Has folders to sync.
+pub(crate) const SYNC: &str = "sync";
+
+/// What this node announces it supports: what every build has, and the
+/// optional services its config turns on.
+pub(crate) fn local(config: &AppConfig) -> Vec<String> {
+    let mut capabilities = vec![ENCRYPTION, SPACE_QUERY, BENCHMARK, PROBE, UNSIZED, SESSIONS, SEALED];
+    if config.share.path.is_some() {
+        capabilities.push(SHARE);
+    }
+    if !config.relay.allowed_peers.is_empty() {
+        capabilities.push(RELAY);
+    }
+    if !config.sync.pairs.is_empty() {
+        capabilities.push(SYNC);
+    }
+    capabilities.into_iter().map(String::from).collect()
+}
+
+/// Whether a peer announcing `capabilities` supports `capability`. Peers
+/// that announce none predate the list and only get the baseline, which
+/// still includes offering encryption: they ignore the key if they can't use it.
+pub(crate) fn supports(capabilities: &[String], capability: &str) -> bool {
+    if capabilities.is_empty() {
+        return capability == ENCRYPTION;
+    }
+    capabilities.iter().any(|c| c == capability)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn optional_services_are_announced_only_when_configured() {
+        let mut config = AppConfig::default();
+        assert_eq!(local(&config), vec![ENCRYPTION, SPACE_QUERY, BENCHMARK, PROBE, UNSIZED, SESSIONS, SEALED]);
+
+        config.share.path = Some("/srv/share".to_string());
+        config.relay.allowed_peers = vec!["laptop".to_string()];
+        assert_eq!(local(&config), vec![ENCRYPTION, SPACE_QUERY, BENCHMARK, PROBE, UNSIZED, SESSIONS, SEALED, SHARE, RELAY]);
+    }
+
+    #[test]
+    fn peers_without_a_list_get_the_baseline() {
+        assert!(supports(&[], ENCRYPTION));
+        assert!(!supports(&[], SPACE_QUERY));
+
+        let announced = vec![SPACE_QUERY.to_string()];
+        assert!(supports(&announced, SPACE_QUERY));
+        assert!(!supports(&announced, ENCRYPTION));
+    }
+}
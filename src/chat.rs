@@ -0,0 +1,135 @@
+use crate::config::ChatConfig;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Why a chat message was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ChatRejection {
+    /// Over `chat.max_message_len` bytes.
+    TooLong,
+    /// The client used up its allowance; it may send again after this long.
+    RateLimited(Duration),
+}
+
+impl ChatRejection {
+    /// Machine-readable cause, as sent to clients.
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            ChatRejection::TooLong => "message_too_long",
+            ChatRejection::RateLimited(_) => "rate_limited",
+        }
+    }
+}
+
+/// Applies `[chat]` limits to each client: a size cap, and a token bucket
+/// holding `rate_limit` messages that refills over `rate_window`.
+pub(crate) struct ChatLimiter {
+    max_message_len: usize,
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<HashMap<Uuid, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl ChatLimiter {
+    pub(crate) fn new(config: &ChatConfig) -> Self {
+        let capacity = config.rate_limit as f64;
+        Self {
+            max_message_len: config.max_message_len,
+            capacity,
+            refill_per_sec: capacity / config.rate_window.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes one message's worth of `client`'s allowance, if it may send `message`.
+    pub(crate) fn check(&self, client: Uuid, message: &str) -> Result<(), ChatRejection> {
+        self.check_at(client, message, Instant::now())
+    }
+
+    fn check_at(&self, client: Uuid, message: &str, now: Instant) -> Result<(), ChatRejection> {
+        if message.len() > self.max_message_len {
+            return Err(ChatRejection::TooLong);
+        }
+        if self.capacity == 0.0 {
+            return Ok(());
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.capacity,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            let wait = (1.0 - bucket.tokens) / self.refill_per_sec;
+            return Err(ChatRejection::RateLimited(Duration::from_secs_f64(wait)));
+        }
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Drops a disconnected client's allowance.
+    pub(crate) fn forget(&self, client: &Uuid) {
+        self.buckets.lock().unwrap().remove(client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rate_limit: u32) -> ChatLimiter {
+        ChatLimiter::new(&ChatConfig {
+            max_message_len: 8,
+            rate_limit,
+            rate_window: 10,
+        })
+    }
+
+    #[test]
+    fn messages_over_the_size_limit_are_rejected() {
+        let limiter = limiter(10);
+        let client = Uuid::new_v4();
+        assert_eq!(limiter.check(client, "12345678"), Ok(()));
+        assert_eq!(limiter.check(client, "123456789"), Err(ChatRejection::TooLong));
+    }
+
+    #[test]
+    fn a_burst_is_limited_until_the_allowance_refills() {
+        let limiter = limiter(10);
+        let (client, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let start = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(limiter.check_at(client, "hi", start), Ok(()));
+        }
+
+        let Err(ChatRejection::RateLimited(wait)) = limiter.check_at(client, "hi", start) else {
+            panic!("the eleventh message in a burst should be limited");
+        };
+        assert_eq!(wait, Duration::from_secs(1));
+        // Each client has its own allowance
+        assert_eq!(limiter.check_at(other, "hi", start), Ok(()));
+
+        assert_eq!(limiter.check_at(client, "hi", start + Duration::from_secs(1)), Ok(()));
+        assert!(limiter.check_at(client, "hi", start + Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn zero_rate_limit_means_unlimited() {
+        let limiter = limiter(0);
+        let client = Uuid::new_v4();
+        for _ in 0..100 {
+            assert_eq!(limiter.check(client, "hi"), Ok(()));
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+#[derive(Debug, Parser)]
+#[command(name = "p2p-sharing", version, about = "Peer-to-peer file sharing on your LAN")]
+pub struct Cli {
+    /// Run the given command against the daemon already running here, or
+    /// start one and run it there; without a command, just confirm a running
+    /// daemon responds instead of failing
+    #[arg(long)]
+    pub forward: bool,
+
+    /// Run the daemon in the background (Unix only)
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// Set on the background process started by --daemon
+    #[arg(long, hide = true)]
+    pub daemon_child: bool,
+
+    /// Run as a separate instance with its own config and state under
+    /// profiles/<name>, e.g. to try two nodes on one machine
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Commands that talk to an already-running daemon. Without a subcommand
+/// the daemon itself is started.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Send a file to a peer and wait for it to finish
+    Send {
+        /// Peer id or hostname
+        #[arg(long)]
+        to: String,
+        /// Relay through this peer (id or hostname); `--to` may then be the
+        /// id of a peer we can't see ourselves
+        #[arg(long)]
+        via: Option<String>,
+        /// File to send
+        path: PathBuf,
+    },
+    /// List the peers the daemon currently knows about
+    ListPeers,
+    /// Stop a daemon started with --daemon
+    Stop,
+    /// Show whether the daemon is running
+    Status,
+    /// Show recent transfers
+    History {
+        /// Maximum number of entries to show
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+}
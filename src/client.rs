@@ -0,0 +1,274 @@
+use crate::cli::Command;
+use p2p_sharing::AppConfig;
+use p2p_sharing::protocol::{ClientMessage, PeerInfo, ServerMessage, SystemInfo};
+use p2p_sharing::{format_bytes, format_speed};
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use std::io::Write;
+use std::path::Path;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+
+/// Thin WebSocket client used by the CLI subcommands to drive a running daemon.
+pub struct DaemonClient {
+    stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl DaemonClient {
+    pub async fn connect(config: &AppConfig) -> Result<Self> {
+        let url = format!("ws://127.0.0.1:{}/ws", config.network.web_port);
+        let (stream, _) = tokio_tungstenite::connect_async(&url)
+            .await
+            .with_context(|| format!("Could not reach the daemon at {} (is it running?)", url))?;
+        Ok(Self { stream })
+    }
+
+    pub async fn send(&mut self, message: &ClientMessage) -> Result<()> {
+        let json = serde_json::to_string(message)?;
+        self.stream.send(Message::Text(json)).await?;
+        Ok(())
+    }
+
+    /// Waits for the next server message, skipping frames that aren't JSON events.
+    pub async fn recv(&mut self) -> Result<ServerMessage> {
+        while let Some(frame) = self.stream.next().await {
+            match frame? {
+                Message::Text(text) => {
+                    if let Ok(message) = serde_json::from_str::<ServerMessage>(&text) {
+                        return Ok(message);
+                    }
+                }
+                Message::Close(_) => break,
+                _ => {}
+            }
+        }
+        Err(anyhow!("Daemon closed the connection"))
+    }
+
+    /// Sends a request and returns the first reply accepted by `pick`.
+    async fn request<T>(
+        &mut self,
+        message: &ClientMessage,
+        mut pick: impl FnMut(ServerMessage) -> Option<Result<T>>,
+    ) -> Result<T> {
+        self.send(message).await?;
+        loop {
+            if let Some(result) = pick(self.recv().await?) {
+                return result;
+            }
+        }
+    }
+
+    pub async fn peers(&mut self) -> Result<Vec<PeerInfo>> {
+        let request = ClientMessage::GetPeers {
+            sort_by: None,
+            descending: false,
+        };
+        self.request(&request, |message| match message {
+            ServerMessage::PeersList { peers } => Some(Ok(peers)),
+            ServerMessage::Error { message } => Some(Err(anyhow!(message))),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Returns the daemon's diagnostics snapshot.
+    pub async fn system_info(&mut self) -> Result<SystemInfo> {
+        self.request(&ClientMessage::GetSystemInfo, |message| match message {
+            ServerMessage::SystemInfo { info } => Some(Ok(info)),
+            ServerMessage::Error { message } => Some(Err(anyhow!(message))),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Returns the daemon's peer id and hostname.
+    pub async fn local_info(&mut self) -> Result<(Uuid, String)> {
+        self.request(&ClientMessage::GetLocalInfo, |message| match message {
+            ServerMessage::LocalInfo { peer_id, hostname, .. } => Some(Ok((peer_id, hostname))),
+            ServerMessage::Error { message } => Some(Err(anyhow!(message))),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Returns the fingerprint of the daemon's identity key.
+    pub async fn fingerprint(&mut self) -> Result<String> {
+        self.request(&ClientMessage::GetLocalInfo, |message| match message {
+            ServerMessage::LocalInfo { fingerprint, .. } => Some(Ok(fingerprint)),
+            ServerMessage::Error { message } => Some(Err(anyhow!(message))),
+            _ => None,
+        })
+        .await
+    }
+}
+
+pub async fn run(command: Command, config: &AppConfig) -> Result<()> {
+    let client = DaemonClient::connect(config).await?;
+    run_with(client, command).await
+}
+
+/// Runs `command` against the daemon starting in this process, once it
+/// accepts connections.
+pub async fn run_once_started(command: Command, config: &AppConfig) -> Result<()> {
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(10);
+    let client = loop {
+        match DaemonClient::connect(config).await {
+            Ok(client) => break client,
+            Err(e) if tokio::time::Instant::now() >= deadline => return Err(e),
+            Err(_) => tokio::time::sleep(std::time::Duration::from_millis(100)).await,
+        }
+    };
+    run_with(client, command).await
+}
+
+async fn run_with(mut client: DaemonClient, command: Command) -> Result<()> {
+    match command {
+        Command::ListPeers => list_peers(&mut client).await,
+        Command::History { limit } => history(&mut client, limit).await,
+        Command::Send { to, via, path } => send(&mut client, &to, via.as_deref(), &path).await,
+        Command::Stop | Command::Status => unreachable!("handled without a client connection"),
+    }
+}
+
+async fn list_peers(client: &mut DaemonClient) -> Result<()> {
+    let peers = client.peers().await?;
+    if peers.is_empty() {
+        println!("No peers found");
+        return Ok(());
+    }
+
+    for peer in peers {
+        let fingerprint = peer.fingerprint.as_deref().unwrap_or("-");
+        let changed = if peer.identity_changed { "  IDENTITY CHANGED" } else { "" };
+        println!("{}  {:<24} {:<22} {}{}", peer.id, peer.hostname, peer.address, fingerprint, changed);
+    }
+    Ok(())
+}
+
+async fn history(client: &mut DaemonClient, limit: usize) -> Result<()> {
+    let transfers = client
+        .request(&ClientMessage::GetTransferHistory, |message| match message {
+            ServerMessage::TransferHistory { transfers } => Some(Ok(transfers)),
+            ServerMessage::Error { message } => Some(Err(anyhow!(message))),
+            _ => None,
+        })
+        .await?;
+
+    for entry in transfers.into_iter().take(limit) {
+        println!(
+            "{}  {:<8} {:<10} {:>10}  {} ({})",
+            entry.timestamp.format("%Y-%m-%d %H:%M:%S"),
+            entry.direction,
+            entry.status,
+            format_bytes(entry.file_size),
+            entry.filename,
+            entry.peer_hostname,
+        );
+    }
+    Ok(())
+}
+
+async fn send(client: &mut DaemonClient, to: &str, via: Option<&str>, path: &Path) -> Result<()> {
+    // The daemon resolves paths relative to its own working directory
+    let path = std::fs::canonicalize(path)
+        .with_context(|| format!("Cannot read {}", path.display()))?;
+
+    let peers = client.peers().await?;
+    let relay = via.map(|via| resolve_peer(&peers, via)).transpose()?;
+    let (peer_id, hostname) = match (resolve_peer(&peers, to), Uuid::parse_str(to)) {
+        (Ok(peer), _) => (peer.id, peer.hostname.clone()),
+        // The relay may know peers we don't
+        (Err(_), Ok(id)) if relay.is_some() => (id, to.to_string()),
+        (Err(e), _) => return Err(e),
+    };
+
+    client
+        .send(&ClientMessage::SendFile {
+            peer_id,
+            file_path: path.to_string_lossy().to_string(),
+            relay_peer_id: relay.map(|relay| relay.id),
+            debug: false,
+        })
+        .await?;
+
+    let mut transfer_id: Option<Uuid> = None;
+    let mut stdout = std::io::stdout();
+
+    loop {
+        match client.recv().await? {
+            ServerMessage::FileTransferRequest { transfer_id: id, filename, file_size, .. } => {
+                transfer_id = Some(id);
+                println!("Sending {} ({}) to {}", filename, format_bytes(file_size), hostname);
+            }
+            ServerMessage::ChecksumProgress { transfer_id: id, progress, total } if Some(id) == transfer_id => {
+                print!("\rPreparing file... {:.0}%", percent(progress, total));
+                stdout.flush()?;
+            }
+            ServerMessage::TransferQueued { transfer_id: id, position } if Some(id) == transfer_id => {
+                print!("\rWaiting for a free send slot ({} in line)...", position);
+                stdout.flush()?;
+            }
+            ServerMessage::FileTransferProgress {
+                transfer_id: id,
+                progress,
+                total,
+                speed_bytes_per_sec,
+                ..
+            } if Some(id) == transfer_id => {
+                print!(
+                    "\rTransferring... {:.0}% ({})",
+                    percent(progress, total),
+                    format_speed(speed_bytes_per_sec.unwrap_or(0))
+                );
+                stdout.flush()?;
+            }
+            ServerMessage::FileTransferComplete { transfer_id: id, .. } if Some(id) == transfer_id => {
+                println!("\nTransfer complete");
+                return Ok(());
+            }
+            ServerMessage::FileTransferError { transfer_id: id, message, .. } if Some(id) == transfer_id => {
+                println!();
+                bail!("Transfer failed: {}", message);
+            }
+            ServerMessage::Error { message } if transfer_id.is_none() => {
+                bail!(message);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn percent(progress: u64, total: u64) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        progress as f64 / total as f64 * 100.0
+    }
+}
+
+/// Finds a peer by id or by case-insensitive hostname.
+fn resolve_peer<'a>(peers: &'a [PeerInfo], query: &str) -> Result<&'a PeerInfo> {
+    if let Ok(id) = Uuid::parse_str(query) {
+        return peers
+            .iter()
+            .find(|p| p.id == id)
+            .ok_or_else(|| anyhow!("No peer with id {}", id));
+    }
+
+    let matches: Vec<&PeerInfo> = peers
+        .iter()
+        .filter(|p| p.hostname.eq_ignore_ascii_case(query))
+        .collect();
+
+    match matches.as_slice() {
+        [] => bail!("No peer named {}", query),
+        [peer] => Ok(peer),
+        _ => {
+            let ids: Vec<String> = matches.iter().map(|p| format!("{} ({})", p.id, p.address)).collect();
+            bail!("{} matches several peers, use an id instead: {}", query, ids.join(", "))
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use anyhow::Result;
+use axum::extract::ws::Message;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// Messages a client may have queued before progress updates start being coalesced.
+pub const CLIENT_QUEUE_CAPACITY: usize = 256;
+/// How long a must-deliver message may wait for room before the client is dropped.
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Bounded outgoing queue for one WebSocket client.
+///
+/// Progress updates are keyed by transfer: a newer update replaces an older
+/// one still waiting in the queue, and updates are dropped outright when the
+/// queue is full. Everything else (responses, completions, errors) waits for
+/// room, and a client that can't take them within the delivery timeout is
+/// disconnected.
+pub struct ClientQueue {
+    client_id: Uuid,
+    state: Mutex<QueueState>,
+    readable: Notify,
+    writable: Notify,
+    closed: CancellationToken,
+}
+
+#[derive(Default)]
+struct QueueState {
+    items: VecDeque<Queued>,
+    coalesced: u64,
+}
+
+struct Queued {
+    message: Message,
+    progress_key: Option<Uuid>,
+}
+
+impl ClientQueue {
+    pub fn new(client_id: Uuid) -> Self {
+        Self {
+            client_id,
+            state: Mutex::new(QueueState::default()),
+            readable: Notify::new(),
+            writable: Notify::new(),
+            closed: CancellationToken::new(),
+        }
+    }
+
+    /// The client this queue sends to.
+    pub fn client_id(&self) -> Uuid {
+        self.client_id
+    }
+
+    /// Queues a message that must not be dropped.
+    pub async fn push(&self, message: Message) -> Result<()> {
+        let deadline = Instant::now() + DELIVERY_TIMEOUT;
+        let mut message = Some(message);
+
+        loop {
+            if self.closed.is_cancelled() {
+                return Err(anyhow::anyhow!("Client disconnected"));
+            }
+
+            let notified = self.writable.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.items.len() < CLIENT_QUEUE_CAPACITY {
+                    state.items.push_back(Queued {
+                        message: message.take().expect("message queued twice"),
+                        progress_key: None,
+                    });
+                    self.readable.notify_one();
+                    return Ok(());
+                }
+            }
+
+            tokio::select! {
+                _ = &mut notified => {}
+                _ = tokio::time::sleep_until(deadline) => {
+                    tracing::warn!(
+                        "WebSocket client {} stopped reading, disconnecting",
+                        self.client_id
+                    );
+                    self.close();
+                    return Err(anyhow::anyhow!("Client is not keeping up"));
+                }
+                _ = self.closed.cancelled() => {}
+            }
+        }
+    }
+
+    /// Queues a progress update for `key`, replacing any update for the same
+    /// key that hasn't been sent yet. Never blocks.
+    pub fn push_progress(&self, key: Uuid, message: Message) {
+        if self.closed.is_cancelled() {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(queued) = state.items.iter_mut().find(|q| q.progress_key == Some(key)) {
+            queued.message = message;
+        } else if state.items.len() < CLIENT_QUEUE_CAPACITY {
+            state.items.push_back(Queued {
+                message,
+                progress_key: Some(key),
+            });
+            self.readable.notify_one();
+            return;
+        }
+
+        if state.coalesced == 0 {
+            tracing::info!(
+                "WebSocket client {} is falling behind, coalescing progress updates",
+                self.client_id
+            );
+        }
+        state.coalesced += 1;
+    }
+
+    /// Waits for the next message to send; `None` once the queue is closed.
+    pub async fn pop(&self) -> Option<Message> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(queued) = state.items.pop_front() {
+                    if state.items.is_empty() && state.coalesced > 0 {
+                        tracing::info!(
+                            "WebSocket client {} caught up after {} progress updates were coalesced",
+                            self.client_id,
+                            state.coalesced
+                        );
+                        state.coalesced = 0;
+                    }
+                    self.writable.notify_waiters();
+                    return Some(queued.message);
+                }
+            }
+
+            tokio::select! {
+                _ = self.readable.notified() => {}
+                _ = self.closed.cancelled() => return None,
+            }
+        }
+    }
+
+    pub fn close(&self) {
+        self.closed.cancel();
+    }
+
+    /// Resolves once the queue has been closed.
+    pub async fn closed(&self) {
+        self.closed.cancelled().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(message: Option<Message>) -> String {
+        match message {
+            Some(Message::Text(text)) => text,
+            other => panic!("expected text, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn progress_updates_are_coalesced_once_the_queue_is_full() {
+        let queue = ClientQueue::new(Uuid::new_v4());
+        let (waiting, newcomer) = (Uuid::new_v4(), Uuid::new_v4());
+        queue.push_progress(waiting, Message::Text("10%".to_string()));
+        for n in 1..CLIENT_QUEUE_CAPACITY {
+            queue.push(Message::Text(n.to_string())).await.unwrap();
+        }
+
+        // The update already queued is brought up to date in its place, and
+        // one with nothing queued has no room
+        queue.push_progress(waiting, Message::Text("20%".to_string()));
+        queue.push_progress(newcomer, Message::Text("5%".to_string()));
+        assert_eq!(queue.state.lock().unwrap().coalesced, 2);
+        assert_eq!(text(queue.pop().await), "20%");
+        for n in 1..CLIENT_QUEUE_CAPACITY {
+            assert_eq!(text(queue.pop().await), n.to_string());
+        }
+        assert!(queue.state.lock().unwrap().items.is_empty());
+        assert_eq!(queue.state.lock().unwrap().coalesced, 0);
+    }
+
+    #[tokio::test]
+    async fn other_messages_wait_for_room_rather_than_being_dropped() {
+        let queue = ClientQueue::new(Uuid::new_v4());
+        for _ in 0..CLIENT_QUEUE_CAPACITY {
+            queue.push_progress(Uuid::new_v4(), Message::Text("progress".to_string()));
+        }
+
+        let mut complete = Box::pin(queue.push(Message::Text("complete".to_string())));
+        assert!(futures_util::poll!(&mut complete).is_pending());
+        assert_eq!(text(queue.pop().await), "progress");
+        tokio::time::timeout(Duration::from_secs(1), complete).await.unwrap().unwrap();
+        for _ in 1..CLIENT_QUEUE_CAPACITY {
+            assert_eq!(text(queue.pop().await), "progress");
+        }
+        assert_eq!(text(queue.pop().await), "complete");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_client_that_stops_reading_is_disconnected_after_the_delivery_timeout() {
+        let queue = ClientQueue::new(Uuid::new_v4());
+        for n in 0..CLIENT_QUEUE_CAPACITY {
+            queue.push(Message::Text(n.to_string())).await.unwrap();
+        }
+
+        let started = Instant::now();
+        let stalled = queue.push(Message::Text("complete".to_string())).await;
+        assert_eq!(stalled.unwrap_err().to_string(), "Client is not keeping up");
+        assert_eq!(started.elapsed(), DELIVERY_TIMEOUT);
+        tokio::time::timeout(Duration::from_secs(1), queue.closed()).await.unwrap();
+        // Nothing more is taken once it's gone
+        assert!(queue.push(Message::Text("later".to_string())).await.is_err());
+        queue.push_progress(Uuid::new_v4(), Message::Text("later".to_string()));
+        assert_eq!(queue.state.lock().unwrap().items.len(), CLIENT_QUEUE_CAPACITY);
+    }
+}
@@ -0,0 +1,64 @@
+use anyhow::{bail, Context, Result};
+
+/// What Request and Accept call zstd.
+pub const ZSTD: &str = "zstd";
+/// Fast enough to keep up with a LAN, and still shrinks text and logs
+/// several times over.
+const LEVEL: i32 = 1;
+/// First byte of a chunk once compression is agreed: the rest is as read.
+const STORED: u8 = 0;
+/// Likewise: the rest is one zstd frame.
+const COMPRESSED: u8 = 1;
+
+/// `chunk` as it goes out once compression is agreed: compressed if that
+/// makes it smaller, as it is otherwise, behind a byte telling which. Data
+/// that's compressed already goes as it is, chunk by chunk.
+pub fn pack(chunk: &[u8]) -> Vec<u8> {
+    if let Ok(compressed) = zstd::bulk::compress(chunk, LEVEL) {
+        if compressed.len() < chunk.len() {
+            let mut packed = Vec::with_capacity(compressed.len() + 1);
+            packed.push(COMPRESSED);
+            packed.extend_from_slice(&compressed);
+            return packed;
+        }
+    }
+    let mut packed = Vec::with_capacity(chunk.len() + 1);
+    packed.push(STORED);
+    packed.extend_from_slice(chunk);
+    packed
+}
+
+/// The chunk `packed` was made from, refusing one that would be longer than
+/// `max_len` so a small message can't fill memory.
+pub fn unpack(packed: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    match packed.split_first() {
+        Some((&STORED, chunk)) if chunk.len() <= max_len => Ok(chunk.to_vec()),
+        Some((&STORED, chunk)) => bail!("{} bytes, over the {} allowed", chunk.len(), max_len),
+        Some((&COMPRESSED, compressed)) => zstd::bulk::decompress(compressed, max_len).context("not valid zstd"),
+        Some((kind, _)) => bail!("unknown chunk encoding {}", kind),
+        None => bail!("empty chunk"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_chunks_that_shrink_are_compressed() {
+        let text = b"timestamp,level,message\n".repeat(400);
+        let packed = pack(&text);
+        assert!(packed.len() < text.len() / 10, "{} bytes", packed.len());
+        assert_eq!(unpack(&packed, text.len()).unwrap(), text);
+
+        // Noise doesn't compress, and goes with one byte added
+        let mut noise = vec![0u8; 8192];
+        getrandom::getrandom(&mut noise).unwrap();
+        let packed = pack(&noise);
+        assert_eq!(packed.len(), noise.len() + 1);
+        assert_eq!(unpack(&packed, noise.len()).unwrap(), noise);
+
+        assert!(unpack(&pack(&text), text.len() - 1).is_err());
+        assert!(unpack(&[7, 1, 2, 3], 100).is_err());
+    }
+}
@@ -1,12 +1,66 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
+/// Profile picked with `--profile`, if any.
+static PROFILE: OnceLock<String> = OnceLock::new();
+
+/// Settings from `config.toml` in the working directory.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppConfig {
+    /// Ports, discovery and who may connect.
     pub network: NetworkConfig,
+    /// Chunking, limits and what incoming files may be.
     pub transfer: TransferConfig,
+    /// Theme and desktop notifications.
     pub ui: UiConfig,
+    /// How failed services are restarted.
+    #[serde(default)]
+    pub supervisor: SupervisorConfig,
+    /// Logging when running in the background.
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+    /// What clients and exports are shown of paths and names.
+    #[serde(default)]
+    pub privacy: PrivacyConfig,
+    /// The folder peers may browse and download from.
+    #[serde(default)]
+    pub share: ShareConfig,
+    /// Forwarding transfers for other peers.
+    #[serde(default)]
+    pub relay: RelayConfig,
+    /// Reaching peers behind other routers through a coordinator.
+    #[serde(default)]
+    pub rendezvous: RendezvousConfig,
+    /// TLS on the transfer port.
+    #[serde(default)]
+    pub security: SecurityConfig,
+    /// Scheduled transfers.
+    #[serde(default)]
+    pub schedule: ScheduleConfig,
+    /// Folders kept in sync with peers.
+    #[serde(default)]
+    pub sync: SyncConfig,
+    /// Where transfer events are posted.
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    /// Cleanup of leftover files.
+    #[serde(default)]
+    pub maintenance: MaintenanceConfig,
+    /// Limits on chat messages from clients.
+    #[serde(default)]
+    pub chat: ChatConfig,
+    /// How this node presents itself to peers.
+    #[serde(default)]
+    pub identity: IdentityConfig,
+    /// How much transfer history is kept.
+    #[serde(default)]
+    pub history: HistoryConfig,
+    /// Limits on transfer speed.
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,25 +69,824 @@ pub struct NetworkConfig {
     pub transfer_port: u16,
     pub web_port: u16,
     pub broadcast_interval: u64,
+    /// How far each broadcast may land from `broadcast_interval`, as a
+    /// fraction of it, so identically configured nodes don't announce in step.
+    #[serde(default = "default_broadcast_jitter")]
+    pub broadcast_jitter: f64,
+    /// Network interface to advertise on (e.g. "eth0"). Auto-detected when unset.
+    #[serde(default)]
+    pub interface: Option<String>,
+    /// Ask the router (NAT-PMP or UPnP) to forward the transfer port, and
+    /// announce the outside address alongside the LAN one.
+    #[serde(default)]
+    pub upnp: bool,
+    /// Nodes to add as peers at startup ("host" or "host:port"), for those
+    /// discovery can't reach, such as ones on another subnet.
+    #[serde(default)]
+    pub peers: Vec<String>,
+    /// Discovery ports of other instances on this machine. Announcements are
+    /// also sent to these on localhost, since they can't share our port.
+    #[serde(default)]
+    pub loopback_peers: Vec<u16>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransferConfig {
     pub chunk_size: usize,
-    pub max_concurrent: usize,
+    /// Legacy setting: the limit for both directions unless overridden below.
+    #[serde(default)]
+    pub max_concurrent: Option<usize>,
+    #[serde(default)]
+    pub max_concurrent_sends: Option<usize>,
+    #[serde(default)]
+    pub max_concurrent_receives: Option<usize>,
+    /// Sends that may wait in line for a free send slot; more are refused.
+    /// 0 lets any number wait.
+    #[serde(default = "default_max_queued_sends")]
+    pub max_queued_sends: usize,
+    /// Seconds to wait for active transfers to finish when shutting down.
+    #[serde(default = "default_shutdown_grace_period")]
+    pub shutdown_grace_period: u64,
+    /// Refuse incoming transfers from addresses that aren't known peers.
+    #[serde(default = "default_require_known_peer")]
+    pub require_known_peer: bool,
+    /// Networks (CIDR) always allowed to send, even when not discovered.
+    #[serde(default)]
+    pub allowed_networks: Vec<String>,
+    /// Secret shared with the peers we take files from. Their requests
+    /// must carry a MAC made with it, and ones without a valid one are
+    /// refused before anything is written; our own requests carry one too.
+    /// Peers that both leave it unset neither send nor check one.
+    #[serde(default)]
+    pub shared_secret: Option<String>,
+    /// Refuse to send or receive without end-to-end encryption. When off,
+    /// peers that don't support it fall back to cleartext.
+    #[serde(default)]
+    pub require_encryption: bool,
+    /// Tell receivers the full local path of files we send rather than just
+    /// the filename.
+    #[serde(default)]
+    pub include_source_path: bool,
+    /// Extensions refused from anyone (e.g. "exe"), matched case-insensitively
+    /// against every extension of the name, so `invoice.pdf.exe` is caught.
+    #[serde(default)]
+    pub blocked_extensions: Vec<String>,
+    /// MIME types refused from anyone; "type/*" blocks a whole family. Checked
+    /// against the claimed type and again against the received content.
+    #[serde(default)]
+    pub blocked_mime_types: Vec<String>,
+    /// What to do with incoming files no accept rule matches.
+    #[serde(default)]
+    pub default_action: AcceptAction,
+    /// Ordered rules for incoming files; the first match decides.
+    #[serde(default)]
+    pub accept_rules: Vec<AcceptRule>,
+    /// SO_SNDBUF for transfer sockets in bytes; OS default when unset.
+    #[serde(default)]
+    pub socket_send_buffer: Option<usize>,
+    /// SO_RCVBUF for transfer sockets in bytes; OS default when unset.
+    #[serde(default)]
+    pub socket_recv_buffer: Option<usize>,
+    /// Seconds a transfer socket may sit idle before keepalive probes start.
+    #[serde(default = "default_keepalive_idle")]
+    pub keepalive_idle: u64,
+    /// Seconds between keepalive probes.
+    #[serde(default = "default_keepalive_interval")]
+    pub keepalive_interval: u64,
+    /// Unanswered probes before the connection is dropped.
+    #[serde(default = "default_keepalive_retries")]
+    pub keepalive_retries: u32,
+    /// Seconds a send may go without writing anything before the peer is
+    /// considered unresponsive.
+    #[serde(default = "default_send_stall_timeout")]
+    pub send_stall_timeout: u64,
+    /// Seconds data we've sent may go unacknowledged by the peer's machine
+    /// before the connection is dropped, so a peer that loses power or
+    /// network fails the transfer this soon rather than at the stall
+    /// timeout. Linux only; 0 leaves it to the OS.
+    #[serde(default = "default_peer_gone_timeout")]
+    pub peer_gone_timeout: u64,
+    /// Chunks a peer may send us before hearing that we've stored them;
+    /// we acknowledge every half window. 0 lets senders run ahead freely.
+    #[serde(default = "default_ack_window")]
+    pub ack_window: u32,
+    /// Put a CRC32 on each chunk we send and check the ones peers send, so
+    /// a chunk damaged on the way goes again instead of failing the whole
+    /// file at the end. Only used alongside an ack window.
+    #[serde(default = "default_chunk_checksums")]
+    pub chunk_checksums: bool,
+    /// Connections to split a file over when sending straight to a peer
+    /// that takes it that way; each carries its own part. 1 sends over one.
+    /// Only encrypted transfers of files hashed up front are split.
+    #[serde(default = "default_parallel_streams")]
+    pub parallel_streams: u32,
+    /// Most connections we take one incoming file over.
+    #[serde(default = "default_max_parallel_streams")]
+    pub max_parallel_streams: u32,
+    /// Largest file in bytes we accept from anyone; unlimited when unset.
+    #[serde(default)]
+    pub max_receive_file_size: Option<u64>,
+    /// Bytes a local calendar day may move, both ways together; once used
+    /// up, new transfers are refused until midnight. Unlimited when unset.
+    #[serde(default)]
+    pub daily_budget_bytes: Option<u64>,
+    /// Permission bits received files get once complete, e.g. "0600"; the
+    /// umask decides when unset. Unix only.
+    #[serde(default)]
+    pub received_file_mode: Option<FileMode>,
+    /// Permission bits the folder received files are saved in gets.
+    #[serde(default)]
+    pub received_dir_mode: Option<FileMode>,
+    /// Group, by name or id, received files and their folder are given to.
+    /// Needs root, or membership of the group. Unix only.
+    #[serde(default)]
+    pub received_group: Option<String>,
+    /// Files of at least this many bytes are only sent after the receiver
+    /// reports room for them.
+    #[serde(default = "default_space_check_threshold")]
+    pub space_check_threshold: u64,
+    /// Bytes to keep free on the downloads disk. Files that would eat into
+    /// them are refused, and a receive stops if other writes leave too
+    /// little room for the rest of it.
+    #[serde(default = "default_free_space_margin")]
+    pub free_space_margin: u64,
+    /// What a broadcast does with a peer that can't keep up with the others.
+    #[serde(default)]
+    pub broadcast_slow_peer: SlowPeerPolicy,
+    /// Keep each transfer's speed samples in its history record once it
+    /// has finished; otherwise they're dropped with the transfer.
+    #[serde(default)]
+    pub keep_speed_samples: bool,
+    /// Record a debug trace of every transfer, not just sends asked for
+    /// with `debug`. Failed transfers keep theirs in history.
+    #[serde(default)]
+    pub trace_transfers: bool,
+    /// Minutes a send may run with no client watching it, once the client
+    /// that started it has gone, before it is cancelled. Never when unset.
+    #[serde(default)]
+    pub orphan_cancel_minutes: Option<u64>,
+    /// Seconds a running transfer's data may stand still, though its
+    /// connection stays open, before it fails as stalled. Transfers waiting
+    /// for approval or a slot haven't started moving and aren't counted.
+    /// Never when unset.
+    #[serde(default)]
+    pub stall_timeout_secs: Option<u64>,
+    /// Store each received file once under downloads/.objects by its
+    /// SHA-256, and the file itself as a hard link to it (a copy where
+    /// links aren't supported), so the same content received twice takes
+    /// its room once. Editing a file in place changes every name sharing
+    /// its content.
+    #[serde(default)]
+    pub content_addressed: bool,
+    /// How files sent together with SendArchive are packed.
+    #[serde(default)]
+    pub archive_compression: ArchiveCompression,
+    /// Save received files in a folder under downloads for their kind,
+    /// told from their content: Images, Documents, Archives, Video or Other.
+    #[serde(default)]
+    pub organize_by_type: bool,
+    /// Folders for `organize_by_type` by MIME type or "type/*" family, e.g.
+    /// "audio/*" = "Music", ahead of the built-in ones.
+    #[serde(default)]
+    pub type_folders: BTreeMap<String, String>,
+    /// Offer to compress chunks with zstd, and take compressed chunks from
+    /// senders that offer. Chunks that don't get smaller go as they are.
+    #[serde(default = "default_compress_chunks")]
+    pub compress_chunks: bool,
+    /// What to do with a received file whose name is taken in downloads.
+    #[serde(default)]
+    pub on_existing_file: ExistingFilePolicy,
+    /// Send files in full even to receivers that already have an identical
+    /// one under the same name, rather than letting them skip it.
+    #[serde(default)]
+    pub resend_identical: bool,
+    /// Where received files go; a leading `~/` is the home directory.
+    /// `downloads` in the data directory when unset.
+    #[serde(default)]
+    pub downloads_dir: Option<String>,
+}
+
+const DEFAULT_MAX_CONCURRENT: usize = 5;
+
+/// Unix permission bits, written in octal as in chmod, e.g. "0640".
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct FileMode(pub u32);
+
+impl std::str::FromStr for FileMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let digits = s.strip_prefix("0o").unwrap_or(s);
+        match u32::from_str_radix(digits, 8) {
+            Ok(mode) if (3..=4).contains(&digits.len()) && mode <= 0o7777 => Ok(FileMode(mode)),
+            _ => anyhow::bail!("invalid file mode {:?}: use three or four octal digits, e.g. \"0640\"", s),
+        }
+    }
+}
+
+impl std::fmt::Display for FileMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04o}", self.0)
+    }
+}
+
+impl TryFrom<String> for FileMode {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<FileMode> for String {
+    fn from(mode: FileMode) -> String {
+        mode.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowPeerPolicy {
+    /// Slow every peer down to its pace.
+    Wait,
+    /// Let it fall behind and read the rest of the file on its own.
+    #[default]
+    CatchUp,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExistingFilePolicy {
+    /// Save it under the first free numbered name, e.g. "report (1).pdf".
+    #[default]
+    Rename,
+    /// Replace the file that's there.
+    Overwrite,
+    /// Discard it, and tell the sender why.
+    Reject,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveCompression {
+    /// As they are; quickest, and most files worth sending together are
+    /// compressed already.
+    #[default]
+    Stored,
+    Deflate,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AcceptAction {
+    #[default]
+    Accept,
+    /// Ask connected clients and wait for an answer.
+    Prompt,
+    Reject,
+}
+
+/// One accept rule. Every condition that is set must hold for the rule to
+/// match; unset conditions match anything.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcceptRule {
+    pub name: String,
+    /// Peer id or hostname (case-insensitive).
+    #[serde(default)]
+    pub peer: Option<String>,
+    /// MIME types, "type/*" for a family.
+    #[serde(default)]
+    pub mime_types: Vec<String>,
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// Largest file size in bytes the rule applies to.
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    pub action: AcceptAction,
+}
+
+impl NetworkConfig {
+    /// The longest a peer can wait between our announcements when jitter
+    /// stretches every one of them.
+    pub fn max_broadcast_gap(&self) -> f64 {
+        self.broadcast_interval as f64 * (1.0 + self.broadcast_jitter)
+    }
+
+    /// Checks that peers timing us out after `peer_timeout_secs` of silence
+    /// still hear from us in time, even if one announcement is lost and the
+    /// jitter stretches both gaps as far as it can, and that there is a gap
+    /// at all.
+    pub fn validate(&self, peer_timeout_secs: u64) -> anyhow::Result<()> {
+        // A zero period would announce as fast as the loop can go, flooding the LAN
+        if self.broadcast_interval == 0 {
+            anyhow::bail!("network.broadcast_interval must be at least 1 second");
+        }
+        if !(0.0..1.0).contains(&self.broadcast_jitter) {
+            anyhow::bail!("network.broadcast_jitter must be at least 0 and below 1, got {}", self.broadcast_jitter);
+        }
+        if 2.0 * self.max_broadcast_gap() >= peer_timeout_secs as f64 {
+            anyhow::bail!(
+                "network.broadcast_interval of {}s with {}% jitter would let peers expire us (they wait {}s)",
+                self.broadcast_interval,
+                self.broadcast_jitter * 100.0,
+                peer_timeout_secs
+            );
+        }
+        Ok(())
+    }
+}
+
+impl TransferConfig {
+    pub fn max_sends(&self) -> usize {
+        self.max_concurrent_sends
+            .or(self.max_concurrent)
+            .unwrap_or(DEFAULT_MAX_CONCURRENT)
+    }
+
+    pub fn max_receives(&self) -> usize {
+        self.max_concurrent_receives
+            .or(self.max_concurrent)
+            .unwrap_or(DEFAULT_MAX_CONCURRENT)
+    }
+
+    /// Where received files go for a node keeping its state in `data_dir`,
+    /// with `~` expanded.
+    pub fn downloads_dir(&self, data_dir: &Path) -> PathBuf {
+        self.downloads_dir
+            .as_deref()
+            .and_then(expand_home)
+            .unwrap_or_else(|| data_dir.join("downloads"))
+    }
+}
+
+fn default_broadcast_jitter() -> f64 {
+    0.2
+}
+
+fn default_shutdown_grace_period() -> u64 {
+    10
+}
+
+fn default_require_known_peer() -> bool {
+    true
+}
+
+fn default_compress_chunks() -> bool {
+    true
+}
+
+fn default_keepalive_idle() -> u64 {
+    30
+}
+
+fn default_keepalive_interval() -> u64 {
+    10
+}
+
+fn default_keepalive_retries() -> u32 {
+    3
+}
+
+fn default_send_stall_timeout() -> u64 {
+    60
+}
+
+fn default_peer_gone_timeout() -> u64 {
+    20
+}
+
+fn default_max_queued_sends() -> usize {
+    50
+}
+
+fn default_ack_window() -> u32 {
+    128
+}
+
+fn default_chunk_checksums() -> bool {
+    true
+}
+
+fn default_parallel_streams() -> u32 {
+    1
+}
+
+fn default_max_parallel_streams() -> u32 {
+    4
+}
+
+fn default_space_check_threshold() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_free_space_margin() -> u64 {
+    100 * 1024 * 1024
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UiConfig {
     pub theme: String,
+    /// Desktop notifications for received files and failed transfers.
+    #[serde(default)]
+    pub notifications: NotificationMode,
+    /// Command run for each notification with `notifications = "command"`.
+    /// `{hostname}`, `{filename}`, `{size}` and `{status}` are replaced
+    /// within its arguments; no shell is involved.
+    #[serde(default)]
+    pub notify_command: Option<String>,
+}
+
+/// How desktop notifications are shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationMode {
+    /// Run `ui.notify_command`.
+    Command,
+    /// The platform's own notifications, through its notification service.
+    Native,
+    #[default]
+    Off,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SupervisorConfig {
+    /// Consecutive restarts allowed before a crashed service is given up on.
+    pub max_restart_attempts: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonConfig {
+    /// Log file used with --daemon, relative to the data directory.
+    pub log_file: String,
+}
+
+/// Redaction applied to what the WebSocket API sends out, e.g. for
+/// screenshots and bug reports. Stored records are never changed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PrivacyConfig {
+    /// Show only file and folder names instead of full local paths, in
+    /// messages and in history exports.
+    #[serde(default)]
+    pub redact_paths: bool,
+    /// Replace hostnames with a short stable hash in history exports. The
+    /// live API keeps them, since clients name peers by hostname.
+    #[serde(default)]
+    pub hash_hostnames: bool,
+}
+
+/// A folder peers may browse and download from.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShareConfig {
+    /// Root of the shared folder; a leading `~/` is the home directory.
+    /// Sharing is off when unset.
+    #[serde(default)]
+    pub path: Option<String>,
+}
+
+impl ShareConfig {
+    /// The share root with `~` expanded, if sharing is on.
+    pub fn root(&self) -> Option<PathBuf> {
+        expand_home(self.path.as_deref()?)
+    }
+}
+
+/// `path` with a leading `~` taken as the home directory.
+fn expand_home(path: &str) -> Option<PathBuf> {
+    let home = || std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")).map(PathBuf::from);
+    match path.strip_prefix("~/").or(path.strip_prefix("~\\")) {
+        Some(rest) => Some(home()?.join(rest)),
+        None if path == "~" => home(),
+        None => Some(PathBuf::from(path)),
+    }
+}
+
+/// Forwarding transfers between peers that can't reach each other.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RelayConfig {
+    /// Peers (id or hostname) that may relay through us; relaying is off
+    /// when empty.
+    #[serde(default)]
+    pub allowed_peers: Vec<String>,
+    /// Cap on each relayed transfer in bytes per second; unlimited when unset.
+    #[serde(default)]
+    pub max_bytes_per_sec: Option<u64>,
+}
+
+/// Connecting to peers that neither discovery nor a direct connection can
+/// reach, with the help of a coordinator both are registered with.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RendezvousConfig {
+    /// Run as a coordinator: take registrations and put peers that ask in
+    /// touch, relaying for them when that doesn't get through.
+    #[serde(default)]
+    pub enabled: bool,
+    /// The coordinator to register with ("host" or "host:port"), and to go
+    /// through for peers that can't be reached directly.
+    #[serde(default)]
+    pub coordinator: Option<String>,
+}
+
+/// TLS on the transfer port. Off, connections are plain as they always were.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SecurityConfig {
+    /// Accept TLS, and use it with peers that announce a certificate. Peers
+    /// that don't are still served and reached in plain.
+    #[serde(default)]
+    pub tls: bool,
+    /// Refuse plain connections either way; implies `tls`.
+    #[serde(default)]
+    pub require_tls: bool,
+    /// PEM certificate chain to present; a leading `~/` is the home
+    /// directory. A self-signed pair is made in the data directory on first
+    /// run when this and `key_path` are unset.
+    #[serde(default)]
+    pub cert_path: Option<String>,
+    /// PEM private key for `cert_path`.
+    #[serde(default)]
+    pub key_path: Option<String>,
+}
+
+impl SecurityConfig {
+    /// Whether the transfer port speaks TLS at all.
+    pub fn tls_enabled(&self) -> bool {
+        self.tls || self.require_tls
+    }
+
+    /// The certificate and key files to use, with `~` expanded, or None
+    /// for a self-signed pair.
+    pub fn cert_and_key(&self) -> anyhow::Result<Option<(PathBuf, PathBuf)>> {
+        match (self.cert_path.as_deref(), self.key_path.as_deref()) {
+            (None, None) => Ok(None),
+            (Some(cert), Some(key)) => match (expand_home(cert), expand_home(key)) {
+                (Some(cert), Some(key)) => Ok(Some((cert, key))),
+                _ => anyhow::bail!("No home directory to find security.cert_path and security.key_path in"),
+            },
+            _ => anyhow::bail!("security.cert_path and security.key_path must be set together"),
+        }
+    }
+}
+
+/// How scheduled transfers are retried when a run fails, e.g. because the
+/// peer is offline at the time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleConfig {
+    /// Further attempts after a failed run before waiting for the next one.
+    #[serde(default = "default_schedule_retry_attempts")]
+    pub retry_attempts: u32,
+    /// Seconds between attempts.
+    #[serde(default = "default_schedule_retry_interval")]
+    pub retry_interval: u64,
+}
+
+fn default_schedule_retry_attempts() -> u32 {
+    3
+}
+
+fn default_schedule_retry_interval() -> u64 {
+    300
+}
+
+/// Folders kept the same on this machine and a paired peer.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncConfig {
+    #[serde(default)]
+    pub pairs: Vec<SyncPair>,
+}
+
+/// One synced folder. The peer needs a pair of the same name naming us.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncPair {
+    /// Identifies the pair on both sides.
+    pub name: String,
+    /// The local folder; a leading `~/` is the home directory.
+    pub path: String,
+    /// Peer id or hostname to sync with.
+    pub peer: String,
+    /// Delete files here when the peer deletes its copy, and the reverse.
+    #[serde(default)]
+    pub propagate_deletes: bool,
+    /// Seconds between syncs while the peer is online.
+    #[serde(default = "default_sync_interval")]
+    pub interval: u64,
+}
+
+fn default_sync_interval() -> u64 {
+    60
+}
+
+impl SyncPair {
+    /// The local folder with `~` expanded.
+    pub fn root(&self) -> Option<PathBuf> {
+        expand_home(&self.path)
+    }
+}
+
+/// Clearing out what crashed or abandoned transfers leave behind. Runs at
+/// startup and then periodically.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceConfig {
+    /// Seconds between passes after the one at startup; 0 for only that one.
+    #[serde(default = "default_maintenance_interval")]
+    pub interval: u64,
+    /// Hours since a partial download was last written before it's removed.
+    #[serde(default = "default_partial_max_age")]
+    pub partial_max_age: u64,
+    /// Days quarantined files are kept for inspection.
+    #[serde(default = "default_quarantine_retention")]
+    pub quarantine_retention: u64,
+}
+
+fn default_maintenance_interval() -> u64 {
+    3600
+}
+
+fn default_partial_max_age() -> u64 {
+    24
+}
+
+fn default_quarantine_retention() -> u64 {
+    30
+}
+
+/// Limits on transfer speed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BandwidthConfig {
+    /// Bytes per second for all sends together, however many are running;
+    /// unlimited if unset. A schedule window's own `max_up` replaces it while
+    /// the window is in effect. SetBandwidthLimit changes it at runtime.
+    #[serde(default)]
+    pub max_up: Option<u64>,
+    /// Times of the week with their own limits; the first window that
+    /// covers the current local time applies, and outside them all
+    /// transfers are unlimited.
+    #[serde(default)]
+    pub schedule: Vec<BandwidthWindow>,
+}
+
+/// A recurring stretch of local time with speed limits.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BandwidthWindow {
+    /// Days the window starts on ("mon", "tuesday", ...); every day if empty.
+    #[serde(default)]
+    pub days: Vec<chrono::Weekday>,
+    /// Local time it starts, e.g. "18:00".
+    pub start: chrono::NaiveTime,
+    /// Local time it ends; before `start` for a window running past
+    /// midnight, and equal to it for a whole day.
+    pub end: chrono::NaiveTime,
+    /// Bytes per second for all sends together; unlimited if unset.
+    #[serde(default)]
+    pub max_up: Option<u64>,
+    /// Bytes per second for all receives together; unlimited if unset.
+    #[serde(default)]
+    pub max_down: Option<u64>,
+}
+
+/// How much transfer history is kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryConfig {
+    /// Most finished transfers kept; the oldest are dropped past it.
+    #[serde(default = "default_history_max_entries")]
+    pub max_entries: usize,
+}
+
+fn default_history_max_entries() -> usize {
+    1000
+}
+
+/// How this node presents itself to peers.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IdentityConfig {
+    /// Name peers see for this node, instead of the system hostname.
+    #[serde(default)]
+    pub hostname: Option<String>,
+}
+
+/// Limits on what clients may send as chat, so one can't flood everyone
+/// else's UI.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatConfig {
+    /// Longest message accepted, in bytes.
+    #[serde(default = "default_chat_max_message_len")]
+    pub max_message_len: usize,
+    /// Messages a client may send in a burst; 0 for no limit.
+    #[serde(default = "default_chat_rate_limit")]
+    pub rate_limit: u32,
+    /// Seconds over which a client's allowance of `rate_limit` refills.
+    #[serde(default = "default_chat_rate_window")]
+    pub rate_window: u64,
+}
+
+fn default_chat_max_message_len() -> usize {
+    4096
+}
+
+fn default_chat_rate_limit() -> u32 {
+    10
+}
+
+fn default_chat_rate_window() -> u64 {
+    10
+}
+
+/// Events a webhook can be told about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    TransferCompleted,
+    TransferFailed,
+    PeerDiscovered,
+    PeerLost,
+    /// A file failed its checksum or seal, or a peer's identity key changed.
+    VerificationFailed,
+}
+
+/// An HTTP endpoint that events are POSTed to as JSON.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookConfig {
+    /// An `http://` URL.
+    pub url: String,
+    /// Events to send; all of them when empty.
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+    /// Key for the HMAC-SHA256 signature of each body, sent in the
+    /// X-P2P-Signature header; unsigned when unset.
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Seconds to wait for the endpoint to answer each attempt.
+    #[serde(default = "default_webhook_timeout")]
+    pub timeout: u64,
+}
+
+fn default_webhook_timeout() -> u64 {
+    5
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            retry_attempts: default_schedule_retry_attempts(),
+            retry_interval: default_schedule_retry_interval(),
+        }
+    }
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            interval: default_maintenance_interval(),
+            partial_max_age: default_partial_max_age(),
+            quarantine_retention: default_quarantine_retention(),
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: default_history_max_entries(),
+        }
+    }
+}
+
+impl Default for ChatConfig {
+    fn default() -> Self {
+        Self {
+            max_message_len: default_chat_max_message_len(),
+            rate_limit: default_chat_rate_limit(),
+            rate_window: default_chat_rate_window(),
+        }
+    }
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            log_file: "p2p-sharing.log".to_string(),
+        }
+    }
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            max_restart_attempts: 5,
+        }
+    }
 }
 
 impl AppConfig {
+    /// Reads `config.toml`, writing the defaults there first if it's missing.
     pub fn load() -> anyhow::Result<Self> {
         let config_path = Self::config_path();
         
         if !config_path.exists() {
-            let default_config = AppConfig::default();
+            let default_config = match PROFILE.get() {
+                Some(_) => Self::profile_default()?,
+                None => AppConfig::default(),
+            };
+            fs::create_dir_all(Self::data_dir())?;
             let toml_content = toml::to_string_pretty(&default_config)?;
             fs::write(&config_path, toml_content)?;
             return Ok(default_config);
@@ -44,10 +897,53 @@ impl AppConfig {
         Ok(config)
     }
 
+    /// Writes the config back to disk, e.g. after settings changed at runtime.
+    pub fn save(&self) -> anyhow::Result<()> {
+        crate::utils::write_atomic(&Self::config_path(), toml::to_string_pretty(self)?.as_bytes())?;
+        Ok(())
+    }
+
     fn config_path() -> PathBuf {
-        std::env::current_dir()
-            .unwrap()
-            .join("config.toml")
+        Self::data_dir().join("config.toml")
+    }
+
+    /// Directory holding the config file and other per-instance state: the
+    /// working directory, or `profiles/<name>` under it with a profile.
+    pub fn data_dir() -> PathBuf {
+        let dir = std::env::current_dir().unwrap();
+        match PROFILE.get() {
+            Some(name) => dir.join("profiles").join(name),
+            None => dir,
+        }
+    }
+
+    /// Runs this process as a separate instance named `name`, with its own
+    /// config and state, so several can run side by side on one machine.
+    /// Must be called before anything reads the data directory.
+    pub fn set_profile(name: &str) -> anyhow::Result<()> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            anyhow::bail!("Invalid profile name {:?}: use letters, digits, '-' and '_'", name);
+        }
+        PROFILE
+            .set(name.to_string())
+            .map_err(|_| anyhow::anyhow!("A profile was already chosen"))
+    }
+
+    /// Defaults for a new profile: free ports, and discovery pointed at the
+    /// instance that runs from the working directory itself.
+    fn profile_default() -> anyhow::Result<Self> {
+        let main_config = std::env::current_dir()?.join("config.toml");
+        let main = fs::read_to_string(main_config)
+            .ok()
+            .and_then(|content| toml::from_str::<AppConfig>(&content).ok())
+            .unwrap_or_default();
+
+        let mut config = AppConfig::default();
+        config.network.discovery_port = std::net::UdpSocket::bind("0.0.0.0:0")?.local_addr()?.port();
+        config.network.transfer_port = std::net::TcpListener::bind("0.0.0.0:0")?.local_addr()?.port();
+        config.network.web_port = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        config.network.loopback_peers = vec![main.network.discovery_port];
+        Ok(config)
     }
 }
 
@@ -59,14 +955,80 @@ impl Default for AppConfig {
                 transfer_port: 7879,
                 web_port: 3030,
                 broadcast_interval: 2,
+                broadcast_jitter: default_broadcast_jitter(),
+                interface: None,
+                upnp: false,
+                peers: Vec::new(),
+                loopback_peers: Vec::new(),
             },
             transfer: TransferConfig {
                 chunk_size: 65536,
-                max_concurrent: 5,
+                max_concurrent: None,
+                max_concurrent_sends: Some(DEFAULT_MAX_CONCURRENT),
+                max_concurrent_receives: Some(DEFAULT_MAX_CONCURRENT),
+                max_queued_sends: default_max_queued_sends(),
+                shutdown_grace_period: default_shutdown_grace_period(),
+                require_known_peer: default_require_known_peer(),
+                allowed_networks: Vec::new(),
+                require_encryption: false,
+                shared_secret: None,
+                include_source_path: false,
+                blocked_extensions: Vec::new(),
+                blocked_mime_types: Vec::new(),
+                default_action: AcceptAction::default(),
+                accept_rules: Vec::new(),
+                socket_send_buffer: None,
+                socket_recv_buffer: None,
+                keepalive_idle: default_keepalive_idle(),
+                keepalive_interval: default_keepalive_interval(),
+                keepalive_retries: default_keepalive_retries(),
+                send_stall_timeout: default_send_stall_timeout(),
+                peer_gone_timeout: default_peer_gone_timeout(),
+                ack_window: default_ack_window(),
+                chunk_checksums: default_chunk_checksums(),
+                parallel_streams: default_parallel_streams(),
+                max_parallel_streams: default_max_parallel_streams(),
+                max_receive_file_size: None,
+                daily_budget_bytes: None,
+                received_file_mode: None,
+                received_dir_mode: None,
+                received_group: None,
+                space_check_threshold: default_space_check_threshold(),
+                free_space_margin: default_free_space_margin(),
+                broadcast_slow_peer: SlowPeerPolicy::default(),
+                keep_speed_samples: false,
+                trace_transfers: false,
+                orphan_cancel_minutes: None,
+                stall_timeout_secs: None,
+                content_addressed: false,
+                archive_compression: ArchiveCompression::default(),
+                organize_by_type: false,
+                type_folders: BTreeMap::new(),
+                compress_chunks: default_compress_chunks(),
+                on_existing_file: ExistingFilePolicy::default(),
+                resend_identical: false,
+                downloads_dir: None,
             },
             ui: UiConfig {
                 theme: "dark".to_string(),
+                notifications: NotificationMode::default(),
+                notify_command: None,
             },
+            supervisor: SupervisorConfig::default(),
+            daemon: DaemonConfig::default(),
+            privacy: PrivacyConfig::default(),
+            share: ShareConfig::default(),
+            relay: RelayConfig::default(),
+            rendezvous: RendezvousConfig::default(),
+            security: SecurityConfig::default(),
+            schedule: ScheduleConfig::default(),
+            sync: SyncConfig::default(),
+            webhooks: Vec::new(),
+            maintenance: MaintenanceConfig::default(),
+            chat: ChatConfig::default(),
+            identity: IdentityConfig::default(),
+            history: HistoryConfig::default(),
+            bandwidth: BandwidthConfig::default(),
         }
     }
 }
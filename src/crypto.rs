@@ -0,0 +1,319 @@
+use crate::identity;
+use anyhow::{anyhow, bail, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+use x25519_dalek::{PublicKey, ReusableSecret, SharedSecret, StaticSecret};
+
+const KEY_INFO: &[u8] = b"p2p-sharing transfer v1";
+const SEALED_INFO: &[u8] = b"p2p-sharing sealed v1";
+
+/// Which end of the handshake we are; both ends must combine the identity
+/// key agreements in the same order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Sender,
+    Receiver,
+}
+
+/// One side of an ephemeral X25519 key agreement for a single transfer.
+pub struct KeyExchange {
+    secret: ReusableSecret,
+    public: PublicKey,
+}
+
+fn parse_public_key(hex_key: &str) -> Result<PublicKey> {
+    let bytes: [u8; 32] = hex::decode(hex_key)?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid public key length"))?;
+    Ok(PublicKey::from(bytes))
+}
+
+/// The key to agree keys with from an identity public key.
+fn parse_identity_key(hex_key: &str) -> Result<PublicKey> {
+    Ok(PublicKey::from(identity::agreement_key(hex_key)?))
+}
+
+fn contributory(shared: SharedSecret) -> Result<SharedSecret> {
+    if !shared.was_contributory() {
+        bail!("Peer sent a weak public key");
+    }
+    Ok(shared)
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = ReusableSecret::random();
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.public.as_bytes())
+    }
+
+    /// Completes the agreement. `transcript` is every handshake message as
+    /// sent on the wire, so tampering with any of them yields a different key
+    /// and the first chunk fails to decrypt.
+    ///
+    /// With `identities` (our identity secret, the peer's identity public key)
+    /// both identity keys are mixed in as well, so the key only matches if each
+    /// side really holds the identity it claimed.
+    pub fn finish(
+        self,
+        role: Role,
+        peer_ephemeral_hex: &str,
+        identities: Option<(&StaticSecret, &str)>,
+        transfer_id: Uuid,
+        transcript: &[&str],
+    ) -> Result<ChunkCipher> {
+        let peer_ephemeral = parse_public_key(peer_ephemeral_hex)?;
+        let mut ikm = contributory(self.secret.diffie_hellman(&peer_ephemeral))?
+            .as_bytes()
+            .to_vec();
+
+        if let Some((our_identity, peer_identity_hex)) = identities {
+            let peer_identity = parse_identity_key(peer_identity_hex)?;
+            let ours_with_theirs = contributory(self.secret.diffie_hellman(&peer_identity))?;
+            let identity_with_theirs = contributory(our_identity.diffie_hellman(&peer_ephemeral))?;
+            let (first, second) = match role {
+                Role::Sender => (ours_with_theirs, identity_with_theirs),
+                Role::Receiver => (identity_with_theirs, ours_with_theirs),
+            };
+            ikm.extend_from_slice(first.as_bytes());
+            ikm.extend_from_slice(second.as_bytes());
+        }
+
+        let mut hasher = Sha256::new();
+        for message in transcript {
+            hasher.update((message.len() as u64).to_be_bytes());
+            hasher.update(message.as_bytes());
+        }
+        let salt = hasher.finalize();
+
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(Some(&salt), &ikm)
+            .expand(KEY_INFO, &mut key)
+            .map_err(|_| anyhow!("Key derivation failed"))?;
+
+        Ok(ChunkCipher {
+            cipher: ChaCha20Poly1305::new(&Key::from(key)),
+            transfer_id,
+        })
+    }
+}
+
+/// Last nonce byte of each kind of frame, so a seal can never be taken for
+/// a chunk or the other way round.
+const CHUNK_DOMAIN: u8 = 0;
+const SEAL_DOMAIN: u8 = 1;
+const HELD_DOMAIN: u8 = 2;
+
+/// ChaCha20-Poly1305 over chunk payloads. Keys are never reused across
+/// transfers, so the chunk index is a safe nonce.
+pub struct ChunkCipher {
+    cipher: ChaCha20Poly1305,
+    transfer_id: Uuid,
+}
+
+impl ChunkCipher {
+    fn nonce(domain: u8, counter: u64) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[..8].copy_from_slice(&counter.to_le_bytes());
+        nonce[11] = domain;
+        Nonce::from(nonce)
+    }
+
+    pub fn encrypt(&self, chunk_index: u64, data: &[u8]) -> Result<Vec<u8>> {
+        let payload = Payload {
+            msg: data,
+            aad: self.transfer_id.as_bytes(),
+        };
+        self.cipher
+            .encrypt(&Self::nonce(CHUNK_DOMAIN, chunk_index), payload)
+            .map_err(|_| anyhow!("Failed to encrypt chunk {}", chunk_index))
+    }
+
+    pub fn decrypt(&self, chunk_index: u64, data: &[u8]) -> Result<Vec<u8>> {
+        let payload = Payload {
+            msg: data,
+            aad: self.transfer_id.as_bytes(),
+        };
+        self.cipher
+            .decrypt(&Self::nonce(CHUNK_DOMAIN, chunk_index), payload)
+            .map_err(|_| anyhow!("Chunk {} failed authentication", chunk_index))
+    }
+
+    /// Seals the end of a transfer: how many bytes were sent in how many
+    /// chunks, and the SHA-256 of them. Without it, whoever sits on the
+    /// connection could cut a transfer short and vouch for the remainder.
+    pub fn seal(&self, chunks: u64, size: u64, digest: &[u8]) -> Result<Vec<u8>> {
+        let payload = Payload {
+            msg: &Self::seal_contents(size, digest),
+            aad: self.transfer_id.as_bytes(),
+        };
+        self.cipher
+            .encrypt(&Self::nonce(SEAL_DOMAIN, chunks), payload)
+            .map_err(|_| anyhow!("Failed to seal the transfer"))
+    }
+
+    /// Checks a seal against what actually arrived.
+    pub fn verify_seal(&self, seal: &[u8], chunks: u64, size: u64, digest: &[u8]) -> Result<()> {
+        let payload = Payload {
+            msg: seal,
+            aad: self.transfer_id.as_bytes(),
+        };
+        let contents = self
+            .cipher
+            .decrypt(&Self::nonce(SEAL_DOMAIN, chunks), payload)
+            .map_err(|_| anyhow!("Transfer seal failed authentication"))?;
+        if contents != Self::seal_contents(size, digest) {
+            bail!("Transfer seal does not match the data received");
+        }
+        Ok(())
+    }
+
+    /// Vouches, in place of receiving the transfer, that we hold a file of
+    /// `size` bytes with SHA-256 `digest` already. Only whoever completed
+    /// the key agreement can.
+    pub fn vouch_held(&self, size: u64, digest: &[u8]) -> Result<Vec<u8>> {
+        let payload = Payload {
+            msg: &Self::seal_contents(size, digest),
+            aad: self.transfer_id.as_bytes(),
+        };
+        self.cipher
+            .encrypt(&Self::nonce(HELD_DOMAIN, 0), payload)
+            .map_err(|_| anyhow!("Failed to vouch for the file"))
+    }
+
+    /// Checks the peer's word that it holds the file we offered.
+    pub fn verify_held(&self, proof: &[u8], size: u64, digest: &[u8]) -> Result<()> {
+        let payload = Payload {
+            msg: proof,
+            aad: self.transfer_id.as_bytes(),
+        };
+        let contents = self
+            .cipher
+            .decrypt(&Self::nonce(HELD_DOMAIN, 0), payload)
+            .map_err(|_| anyhow!("Claim to hold the file failed authentication"))?;
+        if contents != Self::seal_contents(size, digest) {
+            bail!("Claim to hold the file is for another file");
+        }
+        Ok(())
+    }
+
+    fn seal_contents(size: u64, digest: &[u8]) -> Vec<u8> {
+        let mut contents = size.to_be_bytes().to_vec();
+        contents.extend_from_slice(digest);
+        contents
+    }
+}
+
+/// Key for a message sealed with `ephemeral` to `recipient`, from their
+/// agreement and both public keys.
+fn sealing_cipher(shared: SharedSecret, ephemeral: &PublicKey, recipient: &PublicKey) -> Result<ChaCha20Poly1305> {
+    let shared = contributory(shared)?;
+    let mut salt = ephemeral.as_bytes().to_vec();
+    salt.extend_from_slice(recipient.as_bytes());
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared.as_bytes())
+        .expand(SEALED_INFO, &mut key)
+        .map_err(|_| anyhow!("Key derivation failed"))?;
+    Ok(ChaCha20Poly1305::new(&Key::from(key)))
+}
+
+/// Encrypts `message` so only the holder of the identity `recipient_hex`
+/// can read it, bound to `id`. A fresh ephemeral key goes in front of the
+/// ciphertext, so every sealed message has a key of its own and the zero
+/// nonce is never reused.
+pub fn seal_for(recipient_hex: &str, id: Uuid, message: &[u8]) -> Result<Vec<u8>> {
+    let recipient = parse_identity_key(recipient_hex)?;
+    let ephemeral = ReusableSecret::random();
+    let public = PublicKey::from(&ephemeral);
+    let cipher = sealing_cipher(ephemeral.diffie_hellman(&recipient), &public, &recipient)?;
+    let payload = Payload {
+        msg: message,
+        aad: id.as_bytes(),
+    };
+    let sealed = cipher
+        .encrypt(&Nonce::default(), payload)
+        .map_err(|_| anyhow!("Failed to seal the message"))?;
+    Ok([public.as_bytes().as_slice(), &sealed].concat())
+}
+
+/// Opens what `seal_for` sealed to our identity for `id`.
+pub fn open_sealed(identity: &StaticSecret, id: Uuid, sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < 32 {
+        bail!("Sealed message is too short");
+    }
+    let (ephemeral, ciphertext) = sealed.split_at(32);
+    let ephemeral = PublicKey::from(<[u8; 32]>::try_from(ephemeral)?);
+    let cipher = sealing_cipher(identity.diffie_hellman(&ephemeral), &ephemeral, &PublicKey::from(identity))?;
+    let payload = Payload {
+        msg: ciphertext,
+        aad: id.as_bytes(),
+    };
+    cipher
+        .decrypt(&Nonce::default(), payload)
+        .map_err(|_| anyhow!("Sealed message failed authentication"))
+}
+
+/// HMAC-SHA256 under `secret` of what a Request says it offers, and the
+/// key it offers to encrypt with, so a request seen on the wire can't be
+/// sent again with other content or another key.
+fn request_hmac(
+    secret: &str,
+    transfer_id: Uuid,
+    filename: &str,
+    file_size: u64,
+    file_checksum: Option<&str>,
+    public_key: Option<&str>,
+) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(transfer_id.as_bytes());
+    mac.update(&(filename.len() as u64).to_be_bytes());
+    mac.update(filename.as_bytes());
+    mac.update(&file_size.to_be_bytes());
+    for field in [file_checksum, public_key] {
+        match field {
+            Some(field) => {
+                mac.update(&[1]);
+                mac.update(&(field.len() as u64).to_be_bytes());
+                mac.update(field.as_bytes());
+            }
+            None => mac.update(&[0]),
+        }
+    }
+    mac
+}
+
+/// The hex MAC a Request carries when `transfer.shared_secret` is set.
+pub fn request_mac(
+    secret: &str,
+    transfer_id: Uuid,
+    filename: &str,
+    file_size: u64,
+    file_checksum: Option<&str>,
+    public_key: Option<&str>,
+) -> String {
+    hex::encode(request_hmac(secret, transfer_id, filename, file_size, file_checksum, public_key).finalize().into_bytes())
+}
+
+/// Whether `mac` is the one `request_mac` gives, compared in constant time.
+pub fn verify_request_mac(
+    secret: &str,
+    mac: &str,
+    transfer_id: Uuid,
+    filename: &str,
+    file_size: u64,
+    file_checksum: Option<&str>,
+    public_key: Option<&str>,
+) -> bool {
+    let Ok(mac) = hex::decode(mac) else { return false };
+    request_hmac(secret, transfer_id, filename, file_size, file_checksum, public_key)
+        .verify_slice(&mac)
+        .is_ok()
+}
@@ -0,0 +1,164 @@
+use crate::client::DaemonClient;
+use p2p_sharing::AppConfig;
+use anyhow::{bail, Result};
+use std::path::PathBuf;
+
+const PID_FILE: &str = "p2p-sharing.pid";
+
+pub fn pid_file_path() -> PathBuf {
+    AppConfig::data_dir().join(PID_FILE)
+}
+
+pub fn write_pid_file() -> Result<()> {
+    std::fs::write(pid_file_path(), format!("{}\n", std::process::id()))?;
+    Ok(())
+}
+
+pub fn remove_pid_file() {
+    let _ = std::fs::remove_file(pid_file_path());
+}
+
+fn read_pid() -> Option<u32> {
+    std::fs::read_to_string(pid_file_path()).ok()?.trim().parse().ok()
+}
+
+/// Starts the daemon as a detached background process and returns once it
+/// is accepting connections. Its output goes to `daemon.log_file`.
+#[cfg(unix)]
+pub async fn spawn_detached(config: &AppConfig) -> Result<()> {
+    use std::os::unix::process::CommandExt;
+    use std::process::{Command, Stdio};
+    use tokio::time::{sleep, Duration, Instant};
+
+    let data_dir = AppConfig::data_dir();
+    let log_path = data_dir.join(&config.daemon.log_file);
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)?;
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .arg("--daemon-child")
+        .current_dir(&data_dir)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    // Detach from the controlling terminal so closing the shell doesn't kill us
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() == -1 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn()?;
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            bail!("Daemon exited during startup ({}), see {}", status, log_path.display());
+        }
+
+        let listening = tokio::net::TcpStream::connect(("127.0.0.1", config.network.web_port))
+            .await
+            .is_ok();
+        if listening && read_pid() == Some(child.id()) {
+            println!("Daemon started (pid {}), logging to {}", child.id(), log_path.display());
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            bail!("Daemon did not become ready within 10s, see {}", log_path.display());
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn spawn_detached(_config: &AppConfig) -> Result<()> {
+    bail!("--daemon is only supported on Unix; use a service manager instead")
+}
+
+/// Asks the daemon recorded in the PID file to shut down and waits for it to exit.
+#[cfg(unix)]
+pub async fn stop() -> Result<()> {
+    use tokio::time::{sleep, Duration, Instant};
+
+    let Some(pid) = read_pid().filter(|pid| is_alive(*pid)) else {
+        remove_pid_file();
+        bail!("Daemon is not running");
+    };
+
+    if unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while is_alive(pid) {
+        if Instant::now() >= deadline {
+            bail!("Daemon (pid {}) did not exit within 30s", pid);
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    println!("Daemon (pid {}) stopped", pid);
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn stop() -> Result<()> {
+    bail!("stop is only supported on Unix")
+}
+
+/// Reports whether the daemon is running; fails when it isn't so scripts can
+/// check the exit code.
+pub async fn status(config: &AppConfig) -> Result<()> {
+    let pid = read_pid().filter(|pid| is_alive(*pid));
+
+    match DaemonClient::connect(config).await {
+        Ok(mut client) => {
+            let (peer_id, hostname) = client.local_info().await?;
+            let pid = pid.map(|pid| format!(" (pid {})", pid)).unwrap_or_default();
+            println!("Running{} as {} [{}] on port {}", pid, hostname, peer_id, config.network.web_port);
+            println!("Fingerprint: {}", client.fingerprint().await?);
+            let info = client.system_info().await?;
+            let build = info.git_hash.map(|hash| format!(" ({})", hash)).unwrap_or_default();
+            println!(
+                "Version {}{} on {}/{}, up {}s",
+                info.version, build, info.os, info.arch, info.uptime_secs
+            );
+            let (sends, receives) = (info.config.sends, info.config.receives);
+            println!(
+                "Transfers: {}/{} sending, {}/{} receiving",
+                sends.active, sends.limit, receives.active, receives.limit
+            );
+            println!(
+                "{} peers, {} clients, {} history entries",
+                info.peers, info.websocket_clients, info.history_entries
+            );
+            for (name, health) in info.services.iter().filter(|(_, health)| health.status != "running") {
+                match &health.last_error {
+                    Some(error) => println!("Service {}: {} ({})", name, health.status, error),
+                    None => println!("Service {}: {}", name, health.status),
+                }
+            }
+            Ok(())
+        }
+        Err(_) => match pid {
+            Some(pid) => bail!("Daemon process {} exists but is not responding", pid),
+            None => bail!("Daemon is not running"),
+        },
+    }
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    unsafe { libc::kill(pid as libc::pid_t, 0) == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    true
+}
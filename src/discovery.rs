@@ -1,72 +1,341 @@
+use crate::capability;
 use crate::config::AppConfig;
-use crate::peer::{Peer, PeerManager};
+use crate::identity;
+use crate::peer::{Peer, PeerManager, PEER_TIMEOUT_SECS};
+use crate::portmap::PortMapper;
 use crate::protocol::PeerInfo;
+use crate::transfer::TransferService;
 use crate::utils;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::net::UdpSocket;
 use tokio::sync::RwLock;
-use tokio::time::{interval, Duration};
+use tokio::time::{interval, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Largest payload a UDP datagram can carry.
+const MAX_DATAGRAM_LEN: usize = 65536;
+/// Announcements above this risk IP fragmentation, which some networks drop.
+const SAFE_DATAGRAM_LEN: usize = 1400;
+/// Minimum time between warnings about malformed datagrams.
+const MALFORMED_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Prefix of everything an announcement signature covers, so it can't be
+/// passed off as a signature over anything else.
+const SIGNATURE_CONTEXT: &str = "p2p-sharing discovery v1";
+const ADDRESSES_SIGNATURE_CONTEXT: &str = "p2p-sharing discovery addresses v1";
+const CAPABILITIES_SIGNATURE_CONTEXT: &str = "p2p-sharing discovery capabilities v1";
+const TLS_SIGNATURE_CONTEXT: &str = "p2p-sharing discovery tls v1";
+
+/// Datagrams received on the discovery port that didn't parse, since startup.
+static MALFORMED_DATAGRAMS: AtomicU64 = AtomicU64::new(0);
+/// Timestamp of our latest announcement, so the next one is always newer.
+static LAST_ANNOUNCEMENT: AtomicU64 = AtomicU64::new(0);
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DiscoveryMessage {
     pub peer_id: uuid::Uuid,
     pub address: SocketAddr,
     pub hostname: String,
+    /// Set when the sender is shutting down and should be forgotten immediately.
+    #[serde(default)]
+    pub goodbye: bool,
+    /// Fingerprint of the sender's identity key. Informational only; the one
+    /// that counts is derived from `identity_key` once the signature holds.
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    /// Other addresses the sender can be reached at, such as a router port
+    /// mapping for peers outside the LAN.
+    #[serde(default)]
+    pub alt_addresses: Vec<SocketAddr>,
+    /// Every LAN address the sender listens on, in the order to try them.
+    /// `address` is the first; older nodes only know that one.
+    #[serde(default)]
+    pub addresses: Vec<SocketAddr>,
+    /// Signature by `identity_key` over `addresses`, kept apart from
+    /// `signature` so older nodes can still check that one.
+    #[serde(default)]
+    pub addresses_signature: Option<String>,
+    /// Optional features the sender supports; older nodes send none and
+    /// get only the baseline.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Signature by `identity_key` over `capabilities`, so nobody else can
+    /// make us think a peer can't encrypt.
+    #[serde(default)]
+    pub capabilities_signature: Option<String>,
+    /// SHA-256 of the TLS certificate the sender presents on its transfer
+    /// port; None when it doesn't speak TLS.
+    #[serde(default)]
+    pub tls_fingerprint: Option<String>,
+    /// Signature by `identity_key` over `tls_fingerprint`, so nobody else
+    /// can get their certificate trusted as the sender's.
+    #[serde(default)]
+    pub tls_signature: Option<String>,
+    /// The sender's identity public key, hex encoded.
+    #[serde(default)]
+    pub identity_key: Option<String>,
+    /// Signature by `identity_key` over the rest of the announcement, hex encoded.
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// Milliseconds since the epoch when sent. A signed announcement no newer
+    /// than the last one taken from the sender is a replay.
+    #[serde(default)]
+    pub timestamp: u64,
+}
+
+impl DiscoveryMessage {
+    /// Announces us at `addresses`, the first being the main one, with the
+    /// capabilities `config` gives us, signed with our identity key.
+    pub(crate) fn signed(
+        peer_manager: &PeerManager,
+        config: &AppConfig,
+        addresses: Vec<SocketAddr>,
+        goodbye: bool,
+        alt_addresses: Vec<SocketAddr>,
+    ) -> Result<Self> {
+        let Some(&address) = addresses.first() else {
+            anyhow::bail!("No address to announce");
+        };
+        let identity = peer_manager.identity();
+        let now = chrono::Utc::now().timestamp_millis().max(0) as u64;
+        let next = |last: u64| now.max(last + 1);
+        let timestamp = next(
+            LAST_ANNOUNCEMENT
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(next(last)))
+                .unwrap_or_default(),
+        );
+
+        let mut message = Self {
+            peer_id: peer_manager.local_id(),
+            address,
+            hostname: peer_manager.local_hostname().to_string(),
+            goodbye,
+            fingerprint: Some(identity.fingerprint()),
+            alt_addresses,
+            addresses,
+            addresses_signature: None,
+            capabilities: capability::local(config),
+            capabilities_signature: None,
+            tls_fingerprint: peer_manager.local_tls_fingerprint().map(str::to_string),
+            tls_signature: None,
+            identity_key: Some(identity.public_key_hex()),
+            signature: None,
+            timestamp,
+        };
+        message.signature = Some(hex::encode(identity.sign(&message.signed_bytes()?)));
+        message.addresses_signature = Some(hex::encode(identity.sign(&message.addresses_signed_bytes()?)));
+        message.capabilities_signature = Some(hex::encode(identity.sign(&message.capabilities_signed_bytes()?)));
+        if message.tls_fingerprint.is_some() {
+            message.tls_signature = Some(hex::encode(identity.sign(&message.tls_signed_bytes()?)));
+        }
+        Ok(message)
+    }
+
+    fn capabilities_signed_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&(
+            CAPABILITIES_SIGNATURE_CONTEXT,
+            self.peer_id,
+            self.timestamp,
+            &self.capabilities,
+        ))?)
+    }
+
+    /// `capabilities`, if the key that signed the rest signed them too;
+    /// otherwise none, which leaves the peer at the baseline.
+    fn verified_capabilities(&self) -> Vec<String> {
+        if self.covered_by_identity(self.capabilities_signed_bytes(), &self.capabilities_signature) {
+            self.capabilities.clone()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn tls_signed_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&(
+            TLS_SIGNATURE_CONTEXT,
+            self.peer_id,
+            self.timestamp,
+            &self.tls_fingerprint,
+        ))?)
+    }
+
+    /// `tls_fingerprint`, if the key that signed the rest signed it too.
+    /// Unsigned announcements can't name a certificate to pin.
+    pub(crate) fn verified_tls_fingerprint(&self) -> Option<String> {
+        self.identity_key.as_ref()?;
+        self.covered_by_identity(self.tls_signed_bytes(), &self.tls_signature)
+            .then(|| self.tls_fingerprint.clone())
+            .flatten()
+    }
+
+    /// Whether `signature` by `identity_key` holds over `bytes`, or there is
+    /// no key and so nothing to hold the announcement to.
+    fn covered_by_identity(&self, bytes: Result<Vec<u8>>, signature: &Option<String>) -> bool {
+        let Some(identity_key) = &self.identity_key else {
+            return true;
+        };
+        let (Ok(bytes), Some(Ok(signature))) = (bytes, signature.as_deref().map(hex::decode)) else {
+            return false;
+        };
+        identity::verify_signature(identity_key, &bytes, &signature).is_ok()
+    }
+
+    fn addresses_signed_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&(
+            ADDRESSES_SIGNATURE_CONTEXT,
+            self.peer_id,
+            self.timestamp,
+            &self.addresses,
+        ))?)
+    }
+
+    /// `addresses`, if the same key that signed the rest signed them too.
+    /// Unsigned announcements are taken as they are, like their `address`.
+    fn verified_addresses(&self) -> Vec<SocketAddr> {
+        if self.covered_by_identity(self.addresses_signed_bytes(), &self.addresses_signature) {
+            self.addresses.clone()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn signed_bytes(&self) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(&(
+            SIGNATURE_CONTEXT,
+            self.peer_id,
+            self.address,
+            &self.hostname,
+            self.goodbye,
+            &self.alt_addresses,
+            &self.identity_key,
+            self.timestamp,
+        ))?)
+    }
+
+    /// Fingerprint of the identity that signed the announcement, or None if
+    /// it isn't signed. Fails if the signature doesn't hold.
+    fn verified_fingerprint(&self) -> Result<Option<String>> {
+        let (Some(identity_key), Some(signature)) = (&self.identity_key, &self.signature) else {
+            return Ok(None);
+        };
+        identity::verify_signature(identity_key, &self.signed_bytes()?, &hex::decode(signature)?)?;
+        Ok(Some(identity::fingerprint_of_hex(identity_key)?))
+    }
+}
+
+/// How an announcement reached us.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Heard {
+    /// Broadcast on the discovery port.
+    Broadcast,
+    /// In a Hello on the transfer port. The sender is reachable at `address`
+    /// rather than wherever it says it is; `manual` marks a peer we added by
+    /// hand.
+    Hello { address: SocketAddr, manual: bool },
 }
 
+/// A change to the peer list that clients should hear about.
+pub(crate) enum PeerEvent {
+    IdentityChanged {
+        peer_id: uuid::Uuid,
+        hostname: String,
+        pinned: String,
+        fingerprint: String,
+    },
+    Removed(uuid::Uuid),
+    Discovered(PeerInfo),
+    /// A known peer moved or was renamed.
+    Updated(PeerInfo),
+}
+
+/// Finds peers on the local network by UDP broadcast and announces this
+/// node to them.
 pub struct DiscoveryService {
     config: Arc<AppConfig>,
     peers: Arc<RwLock<PeerManager>>,
-    socket: UdpSocket,
+    socket: Arc<UdpSocket>,
     websocket_service: Option<Arc<crate::websocket::WebSocketService>>,
+    transfer_service: Arc<TransferService>,
+    port_mapper: Arc<PortMapper>,
+    /// Discovery ports of other instances on this machine
+    loopback: Arc<Mutex<BTreeSet<u16>>>,
+    shutdown: CancellationToken,
 }
 
 impl DiscoveryService {
-    pub async fn new(config: Arc<AppConfig>, peers: Arc<RwLock<PeerManager>>) -> Result<Self> {
+    /// Binds the discovery port; nothing is sent until `start`. With port 0
+    /// the OS picks one, and nothing is broadcast since no other node
+    /// listens there; peers are then only found by adding them, or through
+    /// `network.loopback_peers`.
+    pub async fn new(
+        config: Arc<AppConfig>,
+        peers: Arc<RwLock<PeerManager>>,
+        transfer_service: Arc<TransferService>,
+        port_mapper: Arc<PortMapper>,
+        shutdown: CancellationToken,
+    ) -> Result<Self> {
         let bind_addr = format!("0.0.0.0:{}", config.network.discovery_port);
         let socket = UdpSocket::bind(&bind_addr).await?;
         socket.set_broadcast(true)?;
+        let loopback = config.network.loopback_peers.iter().copied().collect();
 
         Ok(Self {
             config,
             peers,
-            socket,
+            socket: Arc::new(socket),
             websocket_service: None,
+            transfer_service,
+            port_mapper,
+            loopback: Arc::new(Mutex::new(loopback)),
+            shutdown,
         })
     }
 
+    /// The address the discovery socket is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.socket.local_addr()?)
+    }
+
+    /// Tells clients about peers coming and going.
     pub fn set_websocket_service(&mut self, service: Arc<crate::websocket::WebSocketService>) {
         self.websocket_service = Some(service);
     }
 
+    /// Announces this node and listens for peers until shutdown.
     pub async fn start(&mut self) -> Result<()> {
-        let socket = Arc::new(self.socket.try_clone()?);
+        let socket = self.socket.clone();
         let config = self.config.clone();
         let peers = self.peers.clone();
         let websocket = self.websocket_service.clone();
 
-        let broadcast_task = {
+        let mut broadcast_task = {
             let socket = socket.clone();
             let config = config.clone();
             let peers = peers.clone();
+            let transfer_service = self.transfer_service.clone();
+            let port_mapper = self.port_mapper.clone();
+            let loopback = self.loopback.clone();
             tokio::spawn(async move {
-                Self::broadcast_loop(socket, config, peers).await;
+                Self::broadcast_loop(socket, config, peers, transfer_service, port_mapper, loopback).await;
             })
         };
 
-        let listen_task = {
+        let mut listen_task = {
             let socket = socket.clone();
             let peers = peers.clone();
             let websocket = websocket.clone();
+            let loopback = self.loopback.clone();
             tokio::spawn(async move {
-                Self::listen_loop(socket, peers, websocket).await;
+                Self::listen_loop(socket, peers, websocket, loopback).await;
             })
         };
 
-        let cleanup_task = {
+        let mut cleanup_task = {
             let peers = peers.clone();
             let websocket = websocket.clone();
             tokio::spawn(async move {
@@ -74,38 +343,121 @@ impl DiscoveryService {
             })
         };
 
-        tokio::select! {
-            _ = broadcast_task => {},
-            _ = listen_task => {},
-            _ = cleanup_task => {},
+        let result = tokio::select! {
+            _ = &mut broadcast_task => Err(anyhow::anyhow!("Discovery broadcast loop stopped")),
+            _ = &mut listen_task => Err(anyhow::anyhow!("Discovery listen loop stopped")),
+            _ = &mut cleanup_task => Err(anyhow::anyhow!("Discovery cleanup loop stopped")),
+            _ = self.shutdown.cancelled() => {
+                Self::send_goodbye(&socket, &config, &peers, &self.transfer_service, &self.loopback).await;
+                Ok(())
+            }
+        };
+
+        // Don't leave loops running on the old socket if we get restarted
+        broadcast_task.abort();
+        listen_task.abort();
+        cleanup_task.abort();
+
+        result
+    }
+
+    /// Tells other peers we're leaving so they drop us without waiting for the timeout.
+    async fn send_goodbye(
+        socket: &UdpSocket,
+        config: &AppConfig,
+        peers: &RwLock<PeerManager>,
+        transfer: &TransferService,
+        loopback: &Mutex<BTreeSet<u16>>,
+    ) {
+        // Never announced, so nobody needs to forget us
+        let Some(listening) = transfer.local_addr() else { return };
+        let interface = config.network.interface.as_deref();
+        let addresses = utils::advertised_addresses(interface, listening.port());
+        let broadcast_addr = SocketAddr::new(
+            IpAddr::V4(utils::get_broadcast_address(interface)),
+            config.network.discovery_port,
+        );
+
+        let message = match DiscoveryMessage::signed(&*peers.read().await, config, addresses, true, Vec::new()) {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::warn!("Failed to sign discovery goodbye: {}", e);
+                return;
+            }
+        };
+
+        if let Ok(data) = serde_json::to_vec(&message) {
+            if config.network.discovery_port != 0 {
+                if let Err(e) = socket.send_to(&data, broadcast_addr).await {
+                    tracing::warn!("Failed to send discovery goodbye: {}", e);
+                }
+            }
+            Self::send_to_loopback(socket, &data, loopback).await;
         }
+    }
 
-        Ok(())
+    /// Sends `data` to the other instances on this machine, which a
+    /// broadcast to our own discovery port never reaches.
+    async fn send_to_loopback(socket: &UdpSocket, data: &[u8], loopback: &Mutex<BTreeSet<u16>>) {
+        let ports: Vec<u16> = loopback.lock().unwrap().iter().copied().collect();
+        for port in ports {
+            if let Err(e) = socket.send_to(data, (Ipv4Addr::LOCALHOST, port)).await {
+                tracing::debug!("Failed to announce to local instance on port {}: {}", port, e);
+            }
+        }
     }
 
     async fn broadcast_loop(
         socket: Arc<UdpSocket>,
         config: Arc<AppConfig>,
         peers: Arc<RwLock<PeerManager>>,
+        transfer: Arc<TransferService>,
+        port_mapper: Arc<PortMapper>,
+        loopback: Arc<Mutex<BTreeSet<u16>>>,
     ) {
-        let mut interval = interval(Duration::from_secs(config.network.broadcast_interval));
-        let broadcast_addr = format!("{}:{}", utils::get_broadcast_address(), config.network.discovery_port);
-
-        let local_ip = utils::get_local_ip().unwrap_or(Ipv4Addr::new(127, 0, 0, 1));
-        let transfer_addr = SocketAddr::new(IpAddr::V4(local_ip), config.network.transfer_port);
+        let period = Duration::from_secs(config.network.broadcast_interval);
+        let interface = config.network.interface.as_deref();
+        let mut warned_oversized = false;
+        // So a fleet restarted together doesn't start out in step
+        let mut delay = period.mul_f64(random_fraction());
 
         loop {
-            interval.tick().await;
+            tokio::time::sleep(delay).await;
+            delay = jittered(period, config.network.broadcast_jitter);
+            // Announcing a port we don't listen on yet would only mislead
+            let Some(listening) = transfer.local_addr() else { continue };
+
+            // Re-resolve every tick so a changed network (new Wi-Fi, VPN up/down)
+            // is picked up without a restart.
+            let addresses = utils::advertised_addresses(interface, listening.port());
+            let broadcast_addr = SocketAddr::new(
+                IpAddr::V4(utils::get_broadcast_address(interface)),
+                config.network.discovery_port,
+            );
 
-            let peer_manager = peers.read().await;
-            let message = DiscoveryMessage {
-                peer_id: peer_manager.local_id(),
-                address: transfer_addr,
-                hostname: peer_manager.local_hostname().to_string(),
+            let alt_addresses = port_mapper.external_address().into_iter().collect();
+            let message = match DiscoveryMessage::signed(&*peers.read().await, &config, addresses, false, alt_addresses) {
+                Ok(message) => message,
+                Err(e) => {
+                    tracing::warn!("Failed to sign discovery announcement: {}", e);
+                    continue;
+                }
             };
 
             if let Ok(data) = serde_json::to_vec(&message) {
-                let _ = socket.send_to(&data, &broadcast_addr).await;
+                if data.len() > SAFE_DATAGRAM_LEN && !warned_oversized {
+                    tracing::warn!(
+                        "Discovery announcement is {} bytes (over {}); some networks may drop it",
+                        data.len(),
+                        SAFE_DATAGRAM_LEN
+                    );
+                    warned_oversized = true;
+                }
+                // Other nodes can't know a port the OS picked for us
+                if config.network.discovery_port != 0 {
+                    let _ = socket.send_to(&data, broadcast_addr).await;
+                }
+                Self::send_to_loopback(&socket, &data, &loopback).await;
             }
         }
     }
@@ -114,31 +466,48 @@ impl DiscoveryService {
         socket: Arc<UdpSocket>,
         peers: Arc<RwLock<PeerManager>>,
         websocket: Option<Arc<crate::websocket::WebSocketService>>,
+        loopback: Arc<Mutex<BTreeSet<u16>>>,
     ) {
-        let mut buf = [0u8; 1024];
+        let own_port = socket.local_addr().map(|addr| addr.port()).ok();
+        let mut buf = vec![0u8; MAX_DATAGRAM_LEN];
+        let mut last_malformed_warning: Option<Instant> = None;
 
         loop {
             match socket.recv_from(&mut buf).await {
                 Ok((size, addr)) => {
-                    if let Ok(message) = serde_json::from_slice::<DiscoveryMessage>(&buf[..size]) {
-                        let mut peer_manager = peers.write().await;
-                        if message.peer_id != peer_manager.local_id() {
-                            let was_new = !peer_manager.get_peer(&message.peer_id).is_some();
-                            let peer = Peer::from_discovery(message.peer_id, message.address, message.hostname.clone());
-                            peer_manager.add_or_update_peer(peer);
-                            tracing::info!("Discovered peer: {} from {}", message.hostname, addr);
-                            
-                            if was_new {
-                                if let Some(ws) = &websocket {
-                                    let peer_info = PeerInfo {
-                                        id: message.peer_id,
-                                        address: message.address,
-                                        hostname: message.hostname,
-                                    };
-                                    ws.notify_peer_discovered(peer_info).await;
-                                }
+                    let message = match serde_json::from_slice::<DiscoveryMessage>(&buf[..size]) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            let total = MALFORMED_DATAGRAMS.fetch_add(1, Ordering::Relaxed) + 1;
+                            let due = last_malformed_warning
+                                .is_none_or(|at| at.elapsed() >= MALFORMED_WARNING_INTERVAL);
+                            if due {
+                                last_malformed_warning = Some(Instant::now());
+                                let preview = String::from_utf8_lossy(&buf[..size.min(64)]);
+                                tracing::warn!(
+                                    "Ignoring malformed discovery datagram from {} ({} bytes, {} malformed so far): {} - {:?}",
+                                    addr,
+                                    size,
+                                    total,
+                                    e,
+                                    preview
+                                );
                             }
+                            continue;
                         }
+                    };
+
+                    // Another instance on this machine: announce back to it,
+                    // as it only knows our port if it was configured with it
+                    if addr.ip().is_loopback() && Some(addr.port()) != own_port {
+                        loopback.lock().unwrap().insert(addr.port());
+                    }
+
+                    // Notify only once the lock is released, so a slow client
+                    // can't hold up everyone else who needs the peer list
+                    let events = Self::apply_announcement(&mut *peers.write().await, message, addr, Heard::Broadcast);
+                    if let Some(ws) = &websocket {
+                        Self::notify(ws, events).await;
                     }
                 }
                 Err(e) => {
@@ -148,6 +517,116 @@ impl DiscoveryService {
         }
     }
 
+    pub(crate) async fn notify(websocket: &crate::websocket::WebSocketService, events: Vec<PeerEvent>) {
+        for event in events {
+            match event {
+                PeerEvent::IdentityChanged {
+                    peer_id,
+                    hostname,
+                    pinned,
+                    fingerprint,
+                } => websocket.notify_peer_identity_changed(peer_id, hostname, pinned, fingerprint).await,
+                PeerEvent::Removed(peer_id) => websocket.notify_peer_removed(peer_id).await,
+                PeerEvent::Discovered(peer) => websocket.notify_peer_discovered(peer).await,
+                PeerEvent::Updated(peer) => websocket.notify_peer_updated(peer).await,
+            }
+        }
+    }
+
+    /// Updates the peer list from an announcement received from `from`.
+    /// Announcements that don't prove the pinned identity may be an
+    /// impersonator, so they never move, add or remove that peer.
+    pub(crate) fn apply_announcement(
+        peer_manager: &mut PeerManager,
+        message: DiscoveryMessage,
+        from: SocketAddr,
+        heard: Heard,
+    ) -> Vec<PeerEvent> {
+        let mut events = Vec::new();
+        if message.peer_id == peer_manager.local_id() {
+            return events;
+        }
+
+        let fingerprint = match message.verified_fingerprint() {
+            Ok(fingerprint) => fingerprint,
+            Err(e) => {
+                tracing::debug!("Ignoring discovery announcement from {} with a bad signature: {}", from, e);
+                return events;
+            }
+        };
+
+        if let Some(fingerprint) = &fingerprint {
+            if let Some(pinned) = peer_manager.check_identity(message.peer_id, fingerprint) {
+                tracing::warn!(
+                    "IDENTITY CHANGED for peer {} ({}) from {}: pinned {}, now {}. Transfers are refused until the new fingerprint is accepted.",
+                    message.hostname,
+                    message.peer_id,
+                    from,
+                    pinned,
+                    fingerprint
+                );
+                events.push(PeerEvent::IdentityChanged {
+                    peer_id: message.peer_id,
+                    hostname: message.hostname.clone(),
+                    pinned,
+                    fingerprint: fingerprint.clone(),
+                });
+            }
+        }
+
+        let authentic = match (peer_manager.pinned_fingerprint(&message.peer_id), &fingerprint) {
+            (None, _) => true,
+            (Some(pinned), Some(fingerprint)) => pinned == fingerprint,
+            (Some(_), None) => false,
+        };
+        if !authentic {
+            return events;
+        }
+        if fingerprint.is_some() && !peer_manager.record_announcement(message.peer_id, message.timestamp) {
+            tracing::debug!("Ignoring replayed discovery announcement for {} from {}", message.peer_id, from);
+            return events;
+        }
+
+        if message.goodbye {
+            if peer_manager.get_peer(&message.peer_id).is_some() {
+                peer_manager.remove_peer(&message.peer_id);
+                tracing::info!("Peer left: {} from {}", message.hostname, from);
+                events.push(PeerEvent::Removed(message.peer_id));
+            }
+            return events;
+        }
+
+        let previous = peer_manager
+            .get_peer(&message.peer_id)
+            .map(|peer| (peer.address, peer.hostname.clone()));
+        let address = match heard {
+            Heard::Broadcast => message.address,
+            Heard::Hello { address, .. } => address,
+        };
+        let identity_key = fingerprint.as_ref().and(message.identity_key.clone());
+        let mut peer = Peer::from_discovery(message.peer_id, address, message.hostname.clone(), fingerprint);
+        peer.identity_key = identity_key;
+        peer.addresses = message.verified_addresses();
+        peer.capabilities = message.verified_capabilities();
+        peer.tls_fingerprint = message.verified_tls_fingerprint();
+        peer.alt_addresses = message.alt_addresses;
+        peer.manual = matches!(heard, Heard::Hello { manual: true, .. });
+        peer_manager.add_or_update_peer(peer);
+        tracing::info!("Discovered peer: {} from {}", message.hostname, from);
+
+        if let Some(peer) = peer_manager.get_peer(&message.peer_id) {
+            match previous {
+                None => events.push(PeerEvent::Discovered(PeerInfo::from(peer.clone()))),
+                Some((address, hostname)) if address != peer.address || hostname != peer.hostname => {
+                    tracing::info!("Peer {} is now {} at {}", hostname, peer.hostname, peer.address);
+                    events.push(PeerEvent::Updated(PeerInfo::from(peer.clone())));
+                }
+                Some(_) => {}
+            }
+        }
+        events
+    }
+
     async fn cleanup_loop(
         peers: Arc<RwLock<PeerManager>>,
         websocket: Option<Arc<crate::websocket::WebSocketService>>,
@@ -156,18 +635,21 @@ impl DiscoveryService {
 
         loop {
             interval.tick().await;
-            let mut peer_manager = peers.write().await;
-            let before_peers: std::collections::HashSet<_> = peer_manager.list_peers()
-                .iter()
-                .map(|p| p.id)
-                .collect();
-            
-            peer_manager.cleanup_stale_peers(30);
-            
-            let after_peers: std::collections::HashSet<_> = peer_manager.list_peers()
-                .iter()
-                .map(|p| p.id)
-                .collect();
+            let (before_peers, after_peers) = {
+                let mut peer_manager = peers.write().await;
+                let before_peers: std::collections::HashSet<_> = peer_manager.list_peers()
+                    .iter()
+                    .map(|p| p.id)
+                    .collect();
+
+                peer_manager.cleanup_stale_peers(PEER_TIMEOUT_SECS);
+
+                let after_peers: std::collections::HashSet<_> = peer_manager.list_peers()
+                    .iter()
+                    .map(|p| p.id)
+                    .collect();
+                (before_peers, after_peers)
+            };
 
             if let Some(ws) = &websocket {
                 for removed_id in before_peers.difference(&after_peers) {
@@ -178,3 +660,305 @@ impl DiscoveryService {
     }
 }
 
+
+/// `period` moved by a random amount of up to `jitter` of itself either way.
+fn jittered(period: Duration, jitter: f64) -> Duration {
+    period.mul_f64(1.0 + jitter * (2.0 * random_fraction() - 1.0))
+}
+
+/// A random number in `[0, 1)`, or 0.5 if the OS has no randomness to give.
+fn random_fraction() -> f64 {
+    let mut bytes = [0u8; 8];
+    match getrandom::getrandom(&mut bytes) {
+        Ok(()) => (u64::from_le_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64,
+        Err(_) => 0.5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::{Identity, TrustStore};
+
+    fn peer_manager(dir: &std::path::Path) -> PeerManager {
+        PeerManager::new(Arc::new(Identity::generate()), TrustStore::load(dir).unwrap(), "test".to_string())
+    }
+
+    fn scratch_dir() -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("p2p-sharing-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn address(last: u8) -> SocketAddr {
+        SocketAddr::from(([192, 168, 1, last], 8081))
+    }
+
+    /// An announcement from `identity`'s node, as that node would sign it.
+    fn announcement(identity: &Arc<Identity>, address: SocketAddr, goodbye: bool) -> DiscoveryMessage {
+        // Never written to, so it needn't exist
+        let unused = std::env::temp_dir().join(format!("p2p-sharing-test-{}", uuid::Uuid::new_v4()));
+        let sender = PeerManager::new(identity.clone(), TrustStore::load(&unused).unwrap(), "test".to_string());
+        DiscoveryMessage::signed(&sender, &AppConfig::default(), vec![address], goodbye, Vec::new()).unwrap()
+    }
+
+    /// An announcement claiming to be from `peer_id`, signed with some other key.
+    fn impersonation(peer_id: uuid::Uuid, address: SocketAddr, goodbye: bool) -> DiscoveryMessage {
+        let impostor = Arc::new(Identity::generate());
+        let mut message = announcement(&impostor, address, goodbye);
+        message.peer_id = peer_id;
+        message.signature = Some(hex::encode(impostor.sign(&message.signed_bytes().unwrap())));
+        message
+    }
+
+    fn apply(peers: &mut PeerManager, message: &DiscoveryMessage) -> Vec<PeerEvent> {
+        DiscoveryService::apply_announcement(peers, message.clone(), message.address, Heard::Broadcast)
+    }
+
+    #[tokio::test]
+    async fn malformed_datagrams_are_counted_and_listening_goes_on() {
+        let dir = scratch_dir();
+        let peers = Arc::new(RwLock::new(peer_manager(&dir)));
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let listening = socket.local_addr().unwrap();
+        let loopback = Arc::new(Mutex::new(BTreeSet::new()));
+        let listen = tokio::spawn(DiscoveryService::listen_loop(socket, peers.clone(), None, loopback));
+
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let before = MALFORMED_DATAGRAMS.load(Ordering::Relaxed);
+        sender.send_to(b"\xff\x00 not an announcement {", listening).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while MALFORMED_DATAGRAMS.load(Ordering::Relaxed) == before {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // A good announcement after it is still heard
+        let remote = Arc::new(Identity::generate());
+        let data = serde_json::to_vec(&announcement(&remote, address(2), false)).unwrap();
+        sender.send_to(&data, listening).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while peers.read().await.get_peer(&remote.peer_id()).is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(!listen.is_finished());
+        listen.abort();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn signed_announcement_adds_and_pins_the_peer() {
+        let dir = scratch_dir();
+        let mut peers = peer_manager(&dir);
+        let remote = Arc::new(Identity::generate());
+
+        let events = apply(&mut peers, &announcement(&remote, address(2), false));
+        assert!(matches!(events.as_slice(), [PeerEvent::Discovered(_)]));
+        let peer = peers.get_peer(&remote.peer_id()).unwrap();
+        assert_eq!(peer.address, address(2));
+        assert_eq!(peer.fingerprint.as_deref(), Some(remote.fingerprint().as_str()));
+        assert_eq!(peer.identity_key, Some(remote.public_key_hex()));
+        assert_eq!(peers.pinned_fingerprint(&remote.peer_id()), Some(remote.fingerprint().as_str()));
+
+        // A known peer that moved is reported as updated, not new
+        let events = apply(&mut peers, &announcement(&remote, address(3), false));
+        assert!(matches!(events.as_slice(), [PeerEvent::Updated(peer)] if peer.address == address(3)));
+        assert_eq!(peers.get_peer(&remote.peer_id()).unwrap().address, address(3));
+        assert!(apply(&mut peers, &announcement(&remote, address(3), false)).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn every_signed_address_of_a_peer_is_kept() {
+        let dir = scratch_dir();
+        let mut peers = peer_manager(&dir);
+        let remote = Arc::new(Identity::generate());
+        let unused = std::env::temp_dir().join(format!("p2p-sharing-test-{}", uuid::Uuid::new_v4()));
+        let sender = PeerManager::new(remote.clone(), TrustStore::load(&unused).unwrap(), "test".to_string());
+        let addresses = vec![address(2), address(3)];
+
+        let message = DiscoveryMessage::signed(&sender, &AppConfig::default(), addresses.clone(), false, Vec::new()).unwrap();
+        assert_eq!(message.address, address(2));
+        apply(&mut peers, &message);
+        assert_eq!(peers.get_peer(&remote.peer_id()).unwrap().addresses, addresses);
+
+        // Addresses someone slipped in are dropped, and the rest still counts
+        let mut tampered = DiscoveryMessage::signed(&sender, &AppConfig::default(), addresses, false, Vec::new()).unwrap();
+        tampered.addresses.push(address(66));
+        apply(&mut peers, &tampered);
+        let peer = peers.get_peer(&remote.peer_id()).unwrap();
+        assert!(peer.addresses.is_empty());
+        assert_eq!(peer.address, address(2));
+
+        // As sent by a node that predates the list
+        let mut legacy = DiscoveryMessage::signed(&sender, &AppConfig::default(), vec![address(4)], false, Vec::new()).unwrap();
+        legacy.addresses.clear();
+        legacy.addresses_signature = None;
+        apply(&mut peers, &legacy);
+        assert_eq!(peers.get_peer(&remote.peer_id()).unwrap().address, address(4));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn capabilities_are_kept_only_when_signed() {
+        let dir = scratch_dir();
+        let mut peers = peer_manager(&dir);
+        let remote = Arc::new(Identity::generate());
+        let capabilities = |peers: &PeerManager| peers.get_peer(&remote.peer_id()).unwrap().capabilities.clone();
+
+        let message = announcement(&remote, address(2), false);
+        apply(&mut peers, &message);
+        assert_eq!(capabilities(&peers), capability::local(&AppConfig::default()));
+
+        // Stripping encryption from the list would downgrade our sends
+        let mut tampered = announcement(&remote, address(2), false);
+        tampered.capabilities.retain(|c| c != capability::ENCRYPTION);
+        apply(&mut peers, &tampered);
+        assert!(capabilities(&peers).is_empty());
+
+        // As sent by a node that predates the list
+        let legacy: DiscoveryMessage = serde_json::from_value({
+            let mut json = serde_json::to_value(announcement(&remote, address(2), false)).unwrap();
+            let object = json.as_object_mut().unwrap();
+            object.remove("capabilities");
+            object.remove("capabilities_signature");
+            json
+        })
+        .unwrap();
+        apply(&mut peers, &legacy);
+        assert!(capabilities(&peers).is_empty());
+        assert!(capability::supports(&capabilities(&peers), capability::ENCRYPTION));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn tls_fingerprints_are_kept_only_when_signed() {
+        let dir = scratch_dir();
+        let mut peers = peer_manager(&dir);
+        let remote = Arc::new(Identity::generate());
+        let unused = std::env::temp_dir().join(format!("p2p-sharing-test-{}", uuid::Uuid::new_v4()));
+        let mut sender = PeerManager::new(remote.clone(), TrustStore::load(&unused).unwrap(), "test".to_string());
+        sender.set_local_tls_fingerprint("ab".repeat(32));
+        let signed = || DiscoveryMessage::signed(&sender, &AppConfig::default(), vec![address(2)], false, Vec::new()).unwrap();
+        let tls_fingerprint = |peers: &PeerManager| peers.get_peer(&remote.peer_id()).unwrap().tls_fingerprint.clone();
+
+        apply(&mut peers, &signed());
+        assert_eq!(tls_fingerprint(&peers), Some("ab".repeat(32)));
+        assert_eq!(peers.tls_fingerprint(address(2)), Some("ab".repeat(32)));
+
+        // Someone else's certificate slipped in isn't trusted as the peer's
+        let mut tampered = signed();
+        tampered.tls_fingerprint = Some("cd".repeat(32));
+        apply(&mut peers, &tampered);
+        assert_eq!(tls_fingerprint(&peers), None);
+
+        // Nor is one in an announcement nobody signed
+        let mut unsigned = signed();
+        unsigned.peer_id = uuid::Uuid::new_v4();
+        unsigned.identity_key = None;
+        unsigned.signature = None;
+        apply(&mut peers, &unsigned);
+        assert_eq!(peers.get_peer(&unsigned.peer_id).unwrap().tls_fingerprint, None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn jitter_stays_within_its_share_of_the_period() {
+        let period = Duration::from_secs(10);
+        for _ in 0..1000 {
+            let delay = jittered(period, 0.2);
+            assert!(delay >= Duration::from_secs(8) && delay <= Duration::from_secs(12), "{:?}", delay);
+        }
+        assert_eq!(jittered(period, 0.0), period);
+
+        // Peers would expire us between two announcements stretched this far
+        let mut config = AppConfig::default();
+        assert!(config.network.validate(PEER_TIMEOUT_SECS).is_ok());
+        config.network.broadcast_interval = 13;
+        assert!(config.network.validate(PEER_TIMEOUT_SECS).is_err());
+        // Or announce without pause
+        config.network.broadcast_interval = 0;
+        let error = config.network.validate(PEER_TIMEOUT_SECS).unwrap_err();
+        assert_eq!(error.to_string(), "network.broadcast_interval must be at least 1 second");
+    }
+
+    #[test]
+    fn impersonator_cannot_move_or_remove_a_pinned_peer() {
+        let dir = scratch_dir();
+        let mut peers = peer_manager(&dir);
+        let remote = Arc::new(Identity::generate());
+        apply(&mut peers, &announcement(&remote, address(2), false));
+
+        // Another key: raises the alert once, changes nothing
+        let events = apply(&mut peers, &impersonation(remote.peer_id(), address(66), false));
+        assert!(matches!(events.as_slice(), [PeerEvent::IdentityChanged { .. }]));
+        let events = apply(&mut peers, &impersonation(remote.peer_id(), address(66), true));
+        assert!(!events.iter().any(|event| matches!(event, PeerEvent::Removed(_))));
+        assert_eq!(peers.get_peer(&remote.peer_id()).unwrap().address, address(2));
+
+        // No signature, or a fingerprint that's only claimed
+        let mut unsigned = announcement(&remote, address(66), false);
+        unsigned.identity_key = None;
+        unsigned.signature = None;
+        apply(&mut peers, &unsigned);
+        unsigned.goodbye = true;
+        apply(&mut peers, &unsigned);
+
+        // The real key's signature over different contents
+        let mut tampered = announcement(&remote, address(2), false);
+        tampered.address = address(66);
+        apply(&mut peers, &tampered);
+        tampered.address = address(2);
+        tampered.goodbye = true;
+        apply(&mut peers, &tampered);
+
+        assert_eq!(peers.get_peer(&remote.peer_id()).unwrap().address, address(2));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn replayed_announcement_is_ignored() {
+        let dir = scratch_dir();
+        let mut peers = peer_manager(&dir);
+        let remote = Arc::new(Identity::generate());
+        let old = announcement(&remote, address(2), false);
+        let goodbye = announcement(&remote, address(2), true);
+        let current = announcement(&remote, address(3), false);
+
+        apply(&mut peers, &old);
+        apply(&mut peers, &current);
+        apply(&mut peers, &old);
+        assert_eq!(peers.get_peer(&remote.peer_id()).unwrap().address, address(3));
+
+        // An old goodbye can't remove the peer, and once it really left an
+        // old announcement can't bring it back
+        apply(&mut peers, &goodbye);
+        assert!(peers.get_peer(&remote.peer_id()).is_some());
+        let leaving = announcement(&remote, address(3), true);
+        assert!(matches!(apply(&mut peers, &leaving).as_slice(), [PeerEvent::Removed(_)]));
+        apply(&mut peers, &current);
+        assert!(peers.get_peer(&remote.peer_id()).is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn unsigned_announcement_is_taken_from_an_unpinned_peer() {
+        let dir = scratch_dir();
+        let mut peers = peer_manager(&dir);
+        let remote = Arc::new(Identity::generate());
+        let mut legacy = announcement(&remote, address(2), false);
+        legacy.identity_key = None;
+        legacy.signature = None;
+
+        assert!(matches!(apply(&mut peers, &legacy).as_slice(), [PeerEvent::Discovered(_)]));
+        // Its claimed fingerprint is neither shown nor pinned
+        assert_eq!(peers.get_peer(&remote.peer_id()).unwrap().fingerprint, None);
+        assert_eq!(peers.pinned_fingerprint(&remote.peer_id()), None);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,11 +1,23 @@
-use crate::protocol::TransferHistoryEntry;
+use crate::active::{SpeedSample, SpeedSamples};
+use crate::analytics::{self, HistoryAnalytics};
+use crate::protocol::{BenchmarkResult, TransferHistoryEntry};
+use crate::rendezvous::Connectivity;
+use crate::trace::{TraceEvent, TransferTrace};
+use crate::transfer::TransferOutcome;
+use crate::utils;
+use anyhow::Result;
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
+/// Where the daemon keeps finished transfers between runs, in the data directory.
+pub const HISTORY_FILE: &str = "history.json";
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransferRecord {
     pub transfer_id: Uuid,
@@ -14,8 +26,11 @@ pub struct TransferRecord {
     pub filename: String,
     pub file_path: String,
     pub file_size: u64,
-    pub direction: String, // "sent" or "received"
-    pub status: String, // "in_progress", "completed", "failed", "cancelled", "paused"
+    pub bytes_transferred: Option<u64>,
+    pub mime_type: Option<String>,
+    pub detected_mime_type: Option<String>,
+    pub direction: String, // "sent", "received" or "benchmark"
+    pub status: String, // "in_progress", "completed", "failed", "cancelled", "rejected", "paused"
     pub timestamp: chrono::DateTime<chrono::Utc>,
     pub start_time: Option<chrono::DateTime<chrono::Utc>>,
     pub end_time: Option<chrono::DateTime<chrono::Utc>>,
@@ -23,6 +38,55 @@ pub struct TransferRecord {
     pub speed_bytes_per_sec: Option<u64>,
     pub file_checksum: Option<String>,
     pub verified: bool,
+    pub encrypted: bool,
+    /// Accept rule that decided an incoming transfer, if one matched.
+    pub accept_rule: Option<String>,
+    /// Throughput over the transfer, when `transfer.keep_speed_samples` is on.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub speed_samples: Vec<SpeedSample>,
+    /// Debug trace of a traced transfer that failed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trace: Vec<TraceEvent>,
+    /// Why a failed transfer failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The failure's code, as in FileTransferError, e.g. "peer_disconnected".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    /// The broadcast a send was part of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broadcast_id: Option<Uuid>,
+    /// Peers that broadcast left out on purpose, so they don't read as
+    /// failures.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_peers: Vec<Uuid>,
+    /// The multi-file session a file went in: the transfer id of the
+    /// session's own record.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<Uuid>,
+    /// On a session's own record, how many files it had. Its bytes are its
+    /// files', so it isn't counted again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_files: Option<usize>,
+    /// Permission bits a received file was given, e.g. "0600", when
+    /// `transfer.received_file_mode` is set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_mode: Option<String>,
+    /// Where a received file was saved, relative to the downloads folder.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relative_path: Option<String>,
+    /// Room a received file took no more of because its content was
+    /// stored already, when `transfer.content_addressed` is on; for a send,
+    /// the size of a file the receiver had an identical copy of, so none went.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup_saved_bytes: Option<u64>,
+    /// How the connection to the peer got through; unset for transfers that
+    /// never finished, and those from before it was kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connectivity: Option<Connectivity>,
+    /// What a benchmark measured; only on benchmark records.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub benchmark: Option<BenchmarkResult>,
 }
 
 impl TransferRecord {
@@ -43,6 +107,9 @@ impl TransferRecord {
             filename,
             file_path,
             file_size,
+            bytes_transferred: None,
+            mime_type: None,
+            detected_mime_type: None,
             direction,
             status: "in_progress".to_string(),
             timestamp: now,
@@ -52,12 +119,28 @@ impl TransferRecord {
             speed_bytes_per_sec: None,
             file_checksum: None,
             verified: false,
+            encrypted: false,
+            accept_rule: None,
+            speed_samples: Vec::new(),
+            trace: Vec::new(),
+            error: None,
+            error_code: None,
+            broadcast_id: None,
+            excluded_peers: Vec::new(),
+            session_id: None,
+            session_files: None,
+            file_mode: None,
+            relative_path: None,
+            dedup_saved_bytes: None,
+            connectivity: None,
+            benchmark: None,
         }
     }
 
     pub fn complete(&mut self, checksum: Option<String>, verified: bool) {
         self.status = "completed".to_string();
         self.end_time = Some(Utc::now());
+        self.bytes_transferred = Some(self.file_size);
         self.file_checksum = checksum;
         self.verified = verified;
         
@@ -65,8 +148,11 @@ impl TransferRecord {
             let duration = end.signed_duration_since(start);
             self.duration_seconds = Some(duration.num_seconds() as u64);
             
-            if self.duration_seconds.unwrap_or(0) > 0 {
-                self.speed_bytes_per_sec = Some(self.file_size / self.duration_seconds.unwrap());
+            if let Ok(elapsed) = duration.to_std() {
+                let speed = utils::average_speed(self.file_size, elapsed);
+                if speed > 0 {
+                    self.speed_bytes_per_sec = Some(speed);
+                }
             }
         }
     }
@@ -91,6 +177,21 @@ impl TransferRecord {
         }
     }
 
+    /// An incoming transfer we declined before any data moved.
+    pub fn reject(&mut self) {
+        self.status = "rejected".to_string();
+        self.end_time = Some(Utc::now());
+        self.duration_seconds = Some(0);
+    }
+
+    /// Bytes per second over the whole of a finished transfer, as far as
+    /// it got.
+    pub fn average_speed(&self) -> Option<u64> {
+        let bytes = self.bytes_transferred?;
+        let elapsed = self.end_time?.signed_duration_since(self.start_time?).to_std().ok()?;
+        Some(utils::average_speed(bytes, elapsed)).filter(|speed| *speed > 0)
+    }
+
     pub fn pause(&mut self) {
         self.status = "paused".to_string();
     }
@@ -110,80 +211,425 @@ impl TransferRecord {
             peer_hostname: self.peer_hostname.clone(),
             filename: self.filename.clone(),
             file_size: self.file_size,
+            bytes_transferred: self.bytes_transferred,
+            mime_type: self.mime_type.clone(),
+            detected_mime_type: self.detected_mime_type.clone(),
             direction: self.direction.clone(),
             status: self.status.clone(),
             timestamp: self.timestamp,
             duration_seconds: self.duration_seconds,
             speed_bytes_per_sec: self.speed_bytes_per_sec,
+            encrypted: self.encrypted,
+            verified: self.verified,
+            accept_rule: self.accept_rule.clone(),
+            error_code: self.error_code.clone(),
+            broadcast_id: self.broadcast_id,
+            excluded_peers: self.excluded_peers.clone(),
+            session_id: self.session_id,
+            session_files: self.session_files,
+            file_mode: self.file_mode.clone(),
+            relative_path: self.relative_path.clone(),
+            dedup_saved_bytes: self.dedup_saved_bytes,
+            connectivity: self.connectivity,
+            benchmark: self.benchmark,
+        }
+    }
+}
+
+/// Finished records, oldest first, indexed by transfer id so that adding,
+/// evicting and looking one up don't scan or shift the rest.
+#[derive(Default)]
+struct Completed {
+    records: VecDeque<TransferRecord>,
+    /// Sequence number of `records[0]`; each record's is its position plus this.
+    first_seq: u64,
+    /// Sequence number of the newest record of each transfer id.
+    index: HashMap<Uuid, u64>,
+}
+
+impl Completed {
+    fn from_records(records: Vec<TransferRecord>) -> Self {
+        let mut completed = Self::default();
+        for record in records {
+            completed.push(record);
+        }
+        completed
+    }
+
+    fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    fn iter(&self) -> std::collections::vec_deque::Iter<'_, TransferRecord> {
+        self.records.iter()
+    }
+
+    fn push(&mut self, record: TransferRecord) {
+        let seq = self.first_seq + self.records.len() as u64;
+        self.index.insert(record.transfer_id, seq);
+        self.records.push_back(record);
+    }
+
+    /// Drops the oldest records until at most `keep` are left.
+    fn truncate_front(&mut self, keep: usize) {
+        while self.records.len() > keep {
+            let Some(record) = self.records.pop_front() else { break };
+            // A later record of the same id keeps its entry
+            if self.index.get(&record.transfer_id) == Some(&self.first_seq) {
+                self.index.remove(&record.transfer_id);
+            }
+            self.first_seq += 1;
+        }
+    }
+
+    /// Keeps only the records `keep` holds for, re-indexing the rest.
+    fn retain(&mut self, keep: impl FnMut(&TransferRecord) -> bool) {
+        let before = self.records.len();
+        self.records.retain(keep);
+        if self.records.len() != before {
+            self.index.clear();
+            for (position, record) in self.records.iter().enumerate() {
+                self.index.insert(record.transfer_id, self.first_seq + position as u64);
+            }
         }
     }
+
+    /// The newest record of `transfer_id`.
+    fn get(&self, transfer_id: &Uuid) -> Option<&TransferRecord> {
+        let seq = self.index.get(transfer_id)?;
+        self.records.get((seq - self.first_seq) as usize)
+    }
 }
 
+/// Running transfers, and the most recent finished ones.
 pub struct TransferHistory {
     transfers: Arc<RwLock<HashMap<Uuid, TransferRecord>>>,
-    completed_transfers: Arc<RwLock<Vec<TransferRecord>>>,
-    max_history: usize,
+    completed_transfers: Arc<RwLock<Completed>>,
+    /// Most finished records kept; changed at runtime by `set_max_entries`.
+    max_history: AtomicUsize,
+    /// File `save` writes to; None keeps the history in memory only.
+    path: Option<PathBuf>,
+    /// Whether records keep their transfer's speed samples.
+    keep_samples: bool,
 }
 
 impl TransferHistory {
+    /// An empty history kept in memory only.
     pub fn new(max_history: usize) -> Self {
         Self {
             transfers: Arc::new(RwLock::new(HashMap::new())),
-            completed_transfers: Arc::new(RwLock::new(Vec::new())),
-            max_history,
+            completed_transfers: Arc::new(RwLock::new(Completed::default())),
+            max_history: AtomicUsize::new(max_history),
+            path: None,
+            keep_samples: false,
+        }
+    }
+
+    /// History saved to `path`, starting from what an earlier run left there.
+    pub fn load(path: PathBuf, max_history: usize) -> Self {
+        let mut completed: Vec<TransferRecord> = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring unreadable transfer history in {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                tracing::warn!("Couldn't read transfer history from {}: {}", path.display(), e);
+                Vec::new()
+            }
+        };
+        let excess = completed.len().saturating_sub(max_history);
+        completed.drain(..excess);
+        Self {
+            transfers: Arc::new(RwLock::new(HashMap::new())),
+            completed_transfers: Arc::new(RwLock::new(Completed::from_records(completed))),
+            max_history: AtomicUsize::new(max_history),
+            path: Some(path),
+            keep_samples: false,
         }
     }
 
+    /// With `keep`, records hold on to what `keep_samples` gives them.
+    pub fn keeping_speed_samples(mut self, keep: bool) -> Self {
+        self.keep_samples = keep;
+        self
+    }
+
+    /// Writes the history to its file. Transfers still running are saved as
+    /// failed, as they can't still be running when it's next read.
+    pub async fn save(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut records: Vec<TransferRecord> = self.completed_transfers.read().await.iter().cloned().collect();
+        records.extend(self.transfers.read().await.values().cloned().map(|mut record| {
+            record.fail();
+            record
+        }));
+        let excess = records.len().saturating_sub(self.max_history.load(Ordering::Relaxed));
+        records.drain(..excess);
+        utils::write_atomic(path, &serde_json::to_vec_pretty(&records)?)?;
+        Ok(())
+    }
+
+    /// Records a transfer that has started.
     pub async fn start_transfer(&self, record: TransferRecord) {
         let mut transfers = self.transfers.write().await;
         transfers.insert(record.transfer_id, record);
     }
 
+    /// How many transfers are recorded, running and finished.
+    pub async fn entry_count(&self) -> usize {
+        let running = self.transfers.read().await.len();
+        running + self.completed_transfers.read().await.len()
+    }
+
+    /// The record of `transfer_id`, running or finished. A transfer moves
+    /// to the finished records under the running ones' lock, so it's never
+    /// missing in between.
     pub async fn get_transfer(&self, transfer_id: &Uuid) -> Option<TransferRecord> {
-        let transfers = self.transfers.read().await;
-        transfers.get(transfer_id).cloned()
+        if let Some(record) = self.transfers.read().await.get(transfer_id) {
+            return Some(record.clone());
+        }
+        self.completed_transfers.read().await.get(transfer_id).cloned()
+    }
+
+    /// Adds a finished record, dropping the oldest past the cap. Called with
+    /// the running ones' lock held, so the record is never missing in between.
+    async fn archive(&self, record: TransferRecord) {
+        let mut completed = self.completed_transfers.write().await;
+        completed.push(record);
+        completed.truncate_front(self.max_history.load(Ordering::Relaxed));
+    }
+
+    /// Changes how many finished records are kept, dropping the oldest at
+    /// once if there are now too many. Returns how many were dropped.
+    pub async fn set_max_entries(&self, max_entries: usize) -> usize {
+        self.max_history.store(max_entries, Ordering::Relaxed);
+        self.prune(Some(max_entries), None).await
     }
 
+    /// Drops finished records beyond the newest `keep_last`, and those that
+    /// finished more than `older_than` ago. Returns how many were dropped.
+    pub async fn prune(&self, keep_last: Option<usize>, older_than: Option<chrono::Duration>) -> usize {
+        let mut completed = self.completed_transfers.write().await;
+        let before = completed.len();
+        if let Some(older_than) = older_than {
+            let cutoff = Utc::now() - older_than;
+            completed.retain(|record| record.end_time.unwrap_or(record.timestamp) >= cutoff);
+        }
+        if let Some(keep_last) = keep_last {
+            completed.truncate_front(keep_last);
+        }
+        before - completed.len()
+    }
+
+    /// Moves a running transfer to the finished ones as completed.
     pub async fn complete_transfer(&self, transfer_id: &Uuid, checksum: Option<String>, verified: bool) {
         let mut transfers = self.transfers.write().await;
         if let Some(mut record) = transfers.remove(transfer_id) {
             record.complete(checksum, verified);
-            let mut completed = self.completed_transfers.write().await;
-            completed.push(record);
-            
-            // Keep only the last max_history entries
-            if completed.len() > self.max_history {
-                completed.remove(0);
+            self.archive(record).await;
+        }
+    }
+
+    /// Finishes a send's record with what the send measured, rather than
+    /// the time since it was started, which includes hashing and waiting
+    /// for the receiver.
+    pub async fn complete_send(&self, outcome: &TransferOutcome) {
+        let mut transfers = self.transfers.write().await;
+        if let Some(mut record) = transfers.remove(&outcome.transfer_id) {
+            record.complete(outcome.checksum.clone(), outcome.peer_ack);
+            record.encrypted |= outcome.encrypted;
+            record.connectivity = Some(outcome.connectivity);
+            record.bytes_transferred = Some(outcome.bytes_sent);
+            // An archive is only sized once it has all gone out
+            if record.file_size == 0 {
+                record.file_size = outcome.bytes_sent;
             }
+            record.duration_seconds = Some(outcome.duration.as_secs());
+            record.speed_bytes_per_sec = Some(outcome.average_speed).filter(|speed| *speed > 0);
+            record.dedup_saved_bytes = outcome.deduplicated.then_some(record.file_size);
+            self.archive(record).await;
         }
     }
 
-    pub async fn fail_transfer(&self, transfer_id: &Uuid) {
+    /// Adds a finished benchmark as a record of its own, so runs can be
+    /// compared over time.
+    pub async fn record_benchmark(&self, benchmark_id: Uuid, peer_hostname: String, result: BenchmarkResult) {
+        let (upload, download) = (result.upload, result.download);
+        let bytes = upload.bytes + download.bytes;
+        let elapsed = std::time::Duration::from_millis(upload.elapsed_ms + download.elapsed_ms);
+        let mut record = TransferRecord::new(
+            benchmark_id,
+            Some(result.peer_id),
+            peer_hostname,
+            String::new(),
+            String::new(),
+            bytes,
+            "benchmark".to_string(),
+        );
+        record.status = "completed".to_string();
+        record.end_time = Some(Utc::now());
+        record.bytes_transferred = Some(bytes);
+        record.duration_seconds = Some(elapsed.as_secs());
+        record.speed_bytes_per_sec = Some(utils::average_speed(bytes, elapsed)).filter(|speed| *speed > 0);
+        record.benchmark = Some(result);
+        let _running = self.transfers.write().await;
+        self.archive(record).await;
+    }
+
+    /// Moves a running transfer to the finished ones as failed with `error`.
+    pub async fn fail_transfer(&self, transfer_id: &Uuid, error: String, code: Option<String>) {
         let mut transfers = self.transfers.write().await;
         if let Some(mut record) = transfers.remove(transfer_id) {
+            record.error = Some(error);
+            record.error_code = code;
             record.fail();
-            let mut completed = self.completed_transfers.write().await;
-            completed.push(record);
-            
-            if completed.len() > self.max_history {
-                completed.remove(0);
-            }
+            self.archive(record).await;
         }
     }
 
-    pub async fn cancel_transfer(&self, transfer_id: &Uuid) {
+    /// Moves a running transfer to the finished ones as cancelled. False
+    /// when it wasn't running.
+    pub async fn cancel_transfer(&self, transfer_id: &Uuid) -> bool {
+        let mut transfers = self.transfers.write().await;
+        let Some(mut record) = transfers.remove(transfer_id) else { return false };
+        record.cancel();
+        self.archive(record).await;
+        true
+    }
+
+    /// Moves a running transfer to the finished ones as rejected.
+    pub async fn reject_transfer(&self, transfer_id: &Uuid) {
         let mut transfers = self.transfers.write().await;
         if let Some(mut record) = transfers.remove(transfer_id) {
-            record.cancel();
-            let mut completed = self.completed_transfers.write().await;
-            completed.push(record);
-            
-            if completed.len() > self.max_history {
-                completed.remove(0);
-            }
+            record.reject();
+            self.archive(record).await;
+        }
+    }
+
+    /// Copies a running transfer's speed samples onto its record, if
+    /// records keep them; call before the record is finished.
+    pub async fn keep_samples(&self, transfer_id: &Uuid, samples: &SpeedSamples) {
+        if !self.keep_samples {
+            return;
+        }
+        let mut transfers = self.transfers.write().await;
+        if let Some(record) = transfers.get_mut(transfer_id) {
+            record.speed_samples = samples.snapshot();
+        }
+    }
+
+    /// Copies a traced transfer's trace onto its record; call for failures,
+    /// before the record is finished.
+    pub async fn keep_trace(&self, transfer_id: &Uuid, trace: &TransferTrace) {
+        let Some(events) = trace.snapshot() else { return };
+        let mut transfers = self.transfers.write().await;
+        if let Some(record) = transfers.get_mut(transfer_id) {
+            record.trace = events;
+        }
+    }
+
+    /// The newest completed download for which `matches` holds.
+    pub async fn latest_received(&self, matches: impl Fn(&TransferRecord) -> bool) -> Option<TransferRecord> {
+        let completed = self.completed_transfers.read().await;
+        completed
+            .iter()
+            .rev()
+            .find(|record| record.direction == "received" && record.status == "completed" && matches(record))
+            .cloned()
+    }
+
+    /// The per-peer records of broadcast `broadcast_id`, running or finished.
+    pub async fn broadcast_children(&self, broadcast_id: &Uuid) -> Vec<TransferRecord> {
+        let of_broadcast = |record: &&TransferRecord| record.broadcast_id == Some(*broadcast_id);
+        let mut children: Vec<TransferRecord> =
+            self.transfers.read().await.values().filter(of_broadcast).cloned().collect();
+        let completed = self.completed_transfers.read().await;
+        children.extend(completed.iter().filter(of_broadcast).cloned());
+        children
+    }
+
+    /// The per-file records of session `session_id`, running or finished.
+    pub async fn session_children(&self, session_id: &Uuid) -> Vec<TransferRecord> {
+        let of_session = |record: &&TransferRecord| record.session_id == Some(*session_id);
+        let mut children: Vec<TransferRecord> = self.transfers.read().await.values().filter(of_session).cloned().collect();
+        let completed = self.completed_transfers.read().await;
+        children.extend(completed.iter().filter(of_session).cloned());
+        children
+    }
+
+    /// Finishes session `session_id`'s own record from its files' records:
+    /// completed when every file it listed was, failed otherwise. Returns
+    /// how many files made it and how many didn't.
+    pub async fn finish_session(&self, session_id: &Uuid) -> (usize, usize) {
+        let children = self.session_children(session_id).await;
+        let completed: Vec<&TransferRecord> = children.iter().filter(|child| child.status == "completed").collect();
+        let mut transfers = self.transfers.write().await;
+        let Some(mut record) = transfers.remove(session_id) else {
+            return (completed.len(), children.len() - completed.len());
+        };
+        drop(transfers);
+        // Files that never got as far as a record of their own count too
+        let total = record.session_files.unwrap_or(children.len());
+        let failed = total.saturating_sub(completed.len());
+        if failed == 0 {
+            record.complete(None, completed.iter().all(|child| child.verified));
+        } else {
+            record.fail();
+            record.error = Some(format!("{} of {} files didn't make it", failed, total));
+        }
+        record.bytes_transferred = Some(children.iter().map(|child| child.bytes_transferred.unwrap_or(0)).sum());
+        record.encrypted = !completed.is_empty() && completed.iter().all(|child| child.encrypted);
+        self.archive(record).await;
+        (completed.len(), failed)
+    }
+
+    /// Trace kept on a finished transfer's record.
+    pub async fn trace_of(&self, transfer_id: &Uuid) -> Option<Vec<TraceEvent>> {
+        let completed = self.completed_transfers.read().await;
+        completed
+            .get(transfer_id)
+            .filter(|record| !record.trace.is_empty())
+            .map(|record| record.trace.clone())
+    }
+
+    /// Speed samples kept on a finished transfer's record.
+    pub async fn samples_of(&self, transfer_id: &Uuid) -> Option<Vec<SpeedSample>> {
+        let completed = self.completed_transfers.read().await;
+        completed
+            .get(transfer_id)
+            .filter(|record| !record.speed_samples.is_empty())
+            .map(|record| record.speed_samples.clone())
+    }
+
+    /// Notes how far a running transfer has got, for when it stops short.
+    pub async fn note_progress(&self, transfer_id: &Uuid, bytes_transferred: u64) {
+        if let Some(record) = self.transfers.write().await.get_mut(transfer_id) {
+            record.bytes_transferred = Some(bytes_transferred);
+        }
+    }
+
+    /// Notes that a running transfer is encrypted.
+    pub async fn mark_encrypted(&self, transfer_id: &Uuid) {
+        let mut transfers = self.transfers.write().await;
+        if let Some(record) = transfers.get_mut(transfer_id) {
+            record.encrypted = true;
+        }
+    }
+
+    /// Fills in the name and size of a download, which aren't known until the
+    /// peer offers it.
+    pub async fn set_offered_file(&self, transfer_id: &Uuid, filename: String, file_size: u64) {
+        let mut transfers = self.transfers.write().await;
+        if let Some(record) = transfers.get_mut(transfer_id) {
+            record.filename = filename;
+            record.file_size = file_size;
         }
     }
 
+    /// Marks a running transfer paused.
     pub async fn pause_transfer(&self, transfer_id: &Uuid) {
         let mut transfers = self.transfers.write().await;
         if let Some(record) = transfers.get_mut(transfer_id) {
@@ -191,6 +637,7 @@ impl TransferHistory {
         }
     }
 
+    /// Marks a paused transfer running again.
     pub async fn resume_transfer(&self, transfer_id: &Uuid) {
         let mut transfers = self.transfers.write().await;
         if let Some(record) = transfers.get_mut(transfer_id) {
@@ -198,6 +645,7 @@ impl TransferHistory {
         }
     }
 
+    /// Sets a running transfer's current speed.
     pub async fn update_speed(&self, transfer_id: &Uuid, speed: u64) {
         let mut transfers = self.transfers.write().await;
         if let Some(record) = transfers.get_mut(transfer_id) {
@@ -205,6 +653,7 @@ impl TransferHistory {
         }
     }
 
+    /// Every transfer, running and finished, as clients list them.
     pub async fn get_all_history(&self) -> Vec<TransferHistoryEntry> {
         let active = {
             let transfers = self.transfers.read().await;
@@ -218,13 +667,239 @@ impl TransferHistory {
         
         let mut all = active;
         all.extend(completed);
-        all.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+        all.sort_by_key(|e| std::cmp::Reverse(e.timestamp));
+        all
+    }
+
+    /// Every record, newest first.
+    pub async fn export(&self) -> Vec<TransferRecord> {
+        let mut all: Vec<TransferRecord> = self.transfers.read().await.values().cloned().collect();
+        all.extend(self.completed_transfers.read().await.iter().cloned());
+        all.sort_by_key(|r| std::cmp::Reverse(r.timestamp));
         all
     }
 
+    /// Charts of finished transfers that started at `since` or later, or
+    /// of all of them. Worked out on a copy, so a long history doesn't hold
+    /// up anything waiting on the records.
+    pub async fn analytics(&self, since: Option<chrono::DateTime<Utc>>) -> HistoryAnalytics {
+        let records: Vec<TransferRecord> = self
+            .completed_transfers
+            .read()
+            .await
+            .iter()
+            .filter(|record| since.is_none_or(|since| record.timestamp >= since))
+            .cloned()
+            .collect();
+        analytics::analyze(&records, &chrono::Local)
+    }
+
+    /// Transfers still running.
     pub async fn get_active_transfers(&self) -> Vec<TransferRecord> {
         let transfers = self.transfers.read().await;
         transfers.values().cloned().collect()
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: &str) -> TransferRecord {
+        TransferRecord::new(Uuid::new_v4(), None, "host".to_string(), name.to_string(), name.to_string(), 10, "sent".to_string())
+    }
+
+    #[tokio::test]
+    async fn history_survives_a_restart() {
+        let path = std::env::temp_dir().join(format!("p2p-sharing-test-{}.json", Uuid::new_v4()));
+        let history = TransferHistory::load(path.clone(), 2);
+        for name in ["old", "kept", "newest"] {
+            let record = record(name);
+            let id = record.transfer_id;
+            history.start_transfer(record).await;
+            history.complete_transfer(&id, None, true).await;
+        }
+        history.start_transfer(record("interrupted")).await;
+        history.save().await.unwrap();
+
+        let reloaded = TransferHistory::load(path.clone(), 2).export().await;
+        let mut saved: Vec<_> = reloaded.iter().map(|r| (r.filename.as_str(), r.status.as_str())).collect();
+        saved.sort();
+        assert_eq!(saved, vec![("interrupted", "failed"), ("newest", "completed")]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn speed_samples_are_kept_only_when_asked() {
+        let samples = SpeedSamples::default();
+        samples.finish(10);
+        for keep in [false, true] {
+            let history = TransferHistory::new(10).keeping_speed_samples(keep);
+            let record = record("graph");
+            let id = record.transfer_id;
+            history.start_transfer(record).await;
+            history.keep_samples(&id, &samples).await;
+            history.complete_transfer(&id, None, true).await;
+            assert_eq!(history.samples_of(&id).await.is_some(), keep);
+        }
+    }
+
+    #[tokio::test]
+    async fn finished_transfers_are_still_found() {
+        let history = TransferHistory::new(10);
+        let record = record("report.pdf");
+        let id = record.transfer_id;
+        history.start_transfer(record).await;
+        assert_eq!(history.get_transfer(&id).await.unwrap().status, "in_progress");
+
+        history.note_progress(&id, 4).await;
+        history.fail_transfer(&id, "Connection reset by peer".to_string(), None).await;
+        let failed = history.get_transfer(&id).await.unwrap();
+        assert_eq!(failed.status, "failed");
+        assert_eq!(failed.bytes_transferred, Some(4));
+        assert_eq!(failed.error.as_deref(), Some("Connection reset by peer"));
+        assert!(history.get_transfer(&Uuid::new_v4()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn broadcast_children_are_found_running_or_finished() {
+        let history = TransferHistory::new(10);
+        let broadcast_id = Uuid::new_v4();
+        let mut ids = Vec::new();
+        for name in ["a", "b"] {
+            let mut child = record(name);
+            child.broadcast_id = Some(broadcast_id);
+            ids.push(child.transfer_id);
+            history.start_transfer(child).await;
+        }
+        history.start_transfer(record("unrelated")).await;
+        history.complete_transfer(&ids[0], None, true).await;
+
+        let mut children: Vec<_> = history.broadcast_children(&broadcast_id).await.into_iter().map(|r| r.status).collect();
+        children.sort();
+        assert_eq!(children, vec!["completed", "in_progress"]);
+        assert!(history.broadcast_children(&Uuid::new_v4()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn pruning_drops_the_oldest_finished_records() {
+        let history = TransferHistory::new(10);
+        for name in ["a", "b", "c", "d"] {
+            let record = record(name);
+            let id = record.transfer_id;
+            history.start_transfer(record).await;
+            history.complete_transfer(&id, None, true).await;
+        }
+        history.start_transfer(record("running")).await;
+
+        assert_eq!(history.prune(Some(3), None).await, 1);
+        assert_eq!(history.prune(None, Some(chrono::Duration::days(1))).await, 0);
+        assert_eq!(history.set_max_entries(2).await, 1);
+        let mut kept: Vec<_> = history.export().await.into_iter().map(|r| r.filename).collect();
+        kept.sort();
+        assert_eq!(kept, vec!["c", "d", "running"]);
+
+        // The lower cap holds for what finishes next
+        let record = record("e");
+        let id = record.transfer_id;
+        history.start_transfer(record).await;
+        history.complete_transfer(&id, None, true).await;
+        assert!(history.get_transfer(&id).await.is_some());
+        assert_eq!(history.export().await.len(), 3);
+        assert_eq!(history.prune(None, Some(chrono::Duration::zero())).await, 2);
+    }
+
+    #[tokio::test]
+    async fn many_completions_stay_capped_and_indexed() {
+        let history = TransferHistory::new(100);
+        let mut ids = Vec::new();
+        for i in 0..10_000 {
+            let record = record(&i.to_string());
+            ids.push(record.transfer_id);
+            history.start_transfer(record).await;
+            history.complete_transfer(&ids[i], None, true).await;
+        }
+        assert_eq!(history.export().await.len(), 100);
+        assert!(history.get_transfer(&ids[9_899]).await.is_none());
+        assert_eq!(history.get_transfer(&ids[9_900]).await.unwrap().filename, "9900");
+        assert_eq!(history.get_transfer(&ids[9_999]).await.unwrap().filename, "9999");
+
+        // Pruning moves the front the index counts from
+        history.prune(Some(50), None).await;
+        assert_eq!(history.get_transfer(&ids[9_950]).await.unwrap().filename, "9950");
+        assert!(history.get_transfer(&ids[9_949]).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_repeated_id_is_found_as_its_newest_record() {
+        let history = TransferHistory::new(2);
+        let first = record("first");
+        let id = first.transfer_id;
+        history.start_transfer(first).await;
+        history.fail_transfer(&id, "timed out".to_string(), None).await;
+        let mut retry = record("retry");
+        retry.transfer_id = id;
+        history.start_transfer(retry).await;
+        history.complete_transfer(&id, None, true).await;
+        assert_eq!(history.get_transfer(&id).await.unwrap().filename, "retry");
+
+        // Evicting the older one leaves the newer findable
+        let other = record("other");
+        let other_id = other.transfer_id;
+        history.start_transfer(other).await;
+        history.complete_transfer(&other_id, None, true).await;
+        assert_eq!(history.get_transfer(&id).await.unwrap().filename, "retry");
+    }
+
+    #[tokio::test]
+    async fn unreadable_history_starts_empty() {
+        let path = std::env::temp_dir().join(format!("p2p-sharing-test-{}.json", Uuid::new_v4()));
+        std::fs::write(&path, "not json").unwrap();
+        assert!(TransferHistory::load(path.clone(), 10).export().await.is_empty());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Times the ring buffer against the plain Vec it replaced, full at 10k
+    /// records: archiving past the cap, then looking records up by id. Left
+    /// out of normal runs; use `cargo test --release ring_buffer -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn ring_buffer_against_a_plain_vec() {
+        const ENTRIES: usize = 10_000;
+        let filled: Vec<_> = (0..ENTRIES).map(|i| record(&i.to_string())).collect();
+        let incoming: Vec<_> = (0..ENTRIES).map(|i| record(&i.to_string())).collect();
+        let wanted: Vec<_> = incoming.iter().rev().map(|record| record.transfer_id).collect();
+
+        // As history was kept before: drain the front, scan from the back
+        let mut plain = filled.clone();
+        let started = std::time::Instant::now();
+        for record in incoming.iter().cloned() {
+            plain.push(record);
+            let excess = plain.len().saturating_sub(ENTRIES);
+            plain.drain(..excess);
+        }
+        let plain_archive = started.elapsed();
+        let started = std::time::Instant::now();
+        for id in &wanted {
+            assert!(plain.iter().rev().any(|record| record.transfer_id == *id));
+        }
+        let plain_lookup = started.elapsed();
+
+        let mut ring = Completed::from_records(filled);
+        let started = std::time::Instant::now();
+        for record in incoming {
+            ring.push(record);
+            ring.truncate_front(ENTRIES);
+        }
+        let ring_archive = started.elapsed();
+        let started = std::time::Instant::now();
+        for id in &wanted {
+            assert!(ring.get(id).is_some());
+        }
+        let ring_lookup = started.elapsed();
+
+        println!("{} archives past the cap: vec {:?}, ring {:?}", ENTRIES, plain_archive, ring_archive);
+        println!("{} lookups by id: vec {:?}, ring {:?}", ENTRIES, plain_lookup, ring_lookup);
+    }
+}
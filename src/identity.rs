@@ -0,0 +1,415 @@
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const IDENTITY_FILE: &str = "identity.json";
+const KNOWN_PEERS_FILE: &str = "known_peers.json";
+
+/// Length of an identity public key: the X25519 key agreements are made
+/// with, then the Ed25519 key announcements are signed with.
+const IDENTITY_KEY_LEN: usize = 64;
+
+#[derive(Serialize, Deserialize)]
+struct StoredIdentity {
+    peer_id: Uuid,
+    secret_key: String,
+    /// Absent from identities made before announcements were signed with
+    /// a key of their own; one is added on the next load.
+    #[serde(default)]
+    signing_key: Option<String>,
+}
+
+/// This node's persistent peer id and identity keys: an X25519 keypair for
+/// key agreement and an Ed25519 one for signing, never one for both.
+pub struct Identity {
+    peer_id: Uuid,
+    secret: StaticSecret,
+    public: PublicKey,
+    signing: SigningKey,
+}
+
+impl Identity {
+    /// Loads the identity from the data directory, creating one on first run.
+    pub fn load_or_create(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(IDENTITY_FILE);
+        if path.exists() {
+            let stored: StoredIdentity = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+            let key = |hex_key: &str| -> Result<[u8; 32]> {
+                hex::decode(hex_key)?
+                    .try_into()
+                    .map_err(|_| anyhow!("Invalid secret key in {}", path.display()))
+            };
+            let secret = StaticSecret::from(key(&stored.secret_key)?);
+            let Some(signing_key) = &stored.signing_key else {
+                let identity = Self::from_parts(stored.peer_id, secret, random_signing_key()?);
+                identity.save(&path)?;
+                tracing::warn!(
+                    "Added a signing key to identity {}; its fingerprint is now {}, which peers will ask to accept",
+                    identity.peer_id,
+                    identity.fingerprint()
+                );
+                return Ok(identity);
+            };
+            return Ok(Self::from_parts(stored.peer_id, secret, SigningKey::from_bytes(&key(signing_key)?)));
+        }
+
+        let identity = Self::generate();
+        identity.save(&path)?;
+        tracing::info!("Created new identity {} ({})", identity.peer_id, identity.fingerprint());
+        Ok(identity)
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let stored = StoredIdentity {
+            peer_id: self.peer_id,
+            secret_key: hex::encode(self.secret.to_bytes()),
+            signing_key: Some(hex::encode(self.signing.to_bytes())),
+        };
+        write_private(path, &serde_json::to_string_pretty(&stored)?)
+    }
+
+    /// A fresh identity that isn't saved anywhere.
+    pub fn generate() -> Self {
+        let signing = random_signing_key().expect("the OS has randomness for a new identity");
+        Self::from_parts(Uuid::new_v4(), StaticSecret::random(), signing)
+    }
+
+    fn from_parts(peer_id: Uuid, secret: StaticSecret, signing: SigningKey) -> Self {
+        let public = PublicKey::from(&secret);
+        Self {
+            peer_id,
+            secret,
+            public,
+            signing,
+        }
+    }
+
+    /// This node's id.
+    pub fn peer_id(&self) -> Uuid {
+        self.peer_id
+    }
+
+    /// The private half of the key agreement key.
+    pub fn secret(&self) -> &StaticSecret {
+        &self.secret
+    }
+
+    /// Both public keys, hex encoded: the agreement key, then the signing key.
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.public_key())
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        [self.public.as_bytes().as_slice(), self.signing.verifying_key().as_bytes()].concat()
+    }
+
+    /// Short form of the public keys for people to compare.
+    pub fn fingerprint(&self) -> String {
+        fingerprint_of(&self.public_key())
+    }
+
+    /// Signs `message` with the Ed25519 half of the identity.
+    pub fn sign(&self, message: &[u8]) -> [u8; 64] {
+        self.signing.sign(message).to_bytes()
+    }
+}
+
+fn random_signing_key() -> Result<SigningKey> {
+    let mut seed = [0u8; 32];
+    getrandom::getrandom(&mut seed).map_err(|e| anyhow!("No randomness for a signing key: {}", e))?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+/// The two halves of identity public key `public_key_hex`: the X25519 key
+/// and the Ed25519 one.
+fn identity_key_halves(public_key_hex: &str) -> Result<([u8; 32], [u8; 32])> {
+    let key: [u8; IDENTITY_KEY_LEN] = hex::decode(public_key_hex)?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid identity key length"))?;
+    Ok((key[..32].try_into()?, key[32..].try_into()?))
+}
+
+/// The X25519 key of identity public key `public_key_hex`, to agree keys with.
+pub fn agreement_key(public_key_hex: &str) -> Result<[u8; 32]> {
+    Ok(identity_key_halves(public_key_hex)?.0)
+}
+
+/// Checks an Ed25519 `signature` over `message` by the identity whose
+/// public key is `public_key_hex`. Strict, so a small-order key or a
+/// signature with a non-canonical `s` never holds.
+pub fn verify_signature(public_key_hex: &str, message: &[u8], signature: &[u8]) -> Result<()> {
+    let (_, signing_key) = identity_key_halves(public_key_hex)?;
+    let signature = Signature::from_slice(signature).map_err(|_| anyhow!("Invalid signature length"))?;
+    VerifyingKey::from_bytes(&signing_key)
+        .and_then(|key| key.verify_strict(message, &signature))
+        .map_err(|_| anyhow!("Invalid signature"))
+}
+
+/// Short, human-comparable form of a public key: the first 16 bytes of its
+/// SHA-256, in colon-separated groups of four hex digits.
+pub fn fingerprint_of(public_key: &[u8]) -> String {
+    let digest = Sha256::digest(public_key);
+    digest[..16]
+        .chunks(2)
+        .map(hex::encode)
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+pub fn fingerprint_of_hex(public_key_hex: &str) -> Result<String> {
+    Ok(fingerprint_of(&hex::decode(public_key_hex)?))
+}
+
+/// Names some systems report that say nothing about which machine it is.
+const PLACEHOLDER_HOSTNAMES: &[&str] = &["localhost", "localhost.localdomain", "localhost6.localdomain6", "unknown"];
+
+/// The name peers see for this node: `configured` if set, else the system
+/// hostname, else one derived from the machine id, else one derived from
+/// `peer_id`, so that at least no two nodes look alike.
+pub fn resolve_hostname(configured: Option<&str>, peer_id: Uuid) -> String {
+    let system = hostname::get().ok().map(|name| name.to_string_lossy().into_owned());
+    let machine_id = ["/etc/machine-id", "/var/lib/dbus/machine-id"]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok());
+    let (hostname, source) = pick_hostname(configured, system, machine_id, peer_id);
+    tracing::info!("Hostname: {} (from {})", hostname, source);
+    hostname
+}
+
+fn pick_hostname(
+    configured: Option<&str>,
+    system: Option<String>,
+    machine_id: Option<String>,
+    peer_id: Uuid,
+) -> (String, &'static str) {
+    let usable = |name: &str| {
+        let name = name.trim();
+        !name.is_empty() && !PLACEHOLDER_HOSTNAMES.contains(&name.to_ascii_lowercase().as_str())
+    };
+    if let Some(name) = configured.filter(|name| usable(name)) {
+        return (name.trim().to_string(), "config");
+    }
+    if let Some(name) = system.filter(|name| usable(name)) {
+        return (name.trim().to_string(), "system hostname");
+    }
+    if let Some(id) = machine_id.filter(|id| !id.trim().is_empty()) {
+        let digest = Sha256::digest(id.trim().as_bytes());
+        return (format!("host-{}", hex::encode(&digest[..4])), "machine id");
+    }
+    (format!("peer-{}", &peer_id.simple().to_string()[..8]), "peer id")
+}
+
+#[cfg(unix)]
+pub(crate) fn write_private(path: &Path, contents: &str) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn write_private(path: &Path, contents: &str) -> Result<()> {
+    std::fs::write(path, contents)?;
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrustStatus {
+    /// First time we've seen this peer; its fingerprint is now pinned.
+    FirstContact,
+    Trusted,
+    /// The peer presented a different fingerprint from the pinned one.
+    Changed { pinned: String },
+}
+
+/// Fingerprints pinned on first contact, keyed by peer id.
+pub struct TrustStore {
+    path: PathBuf,
+    pinned: HashMap<Uuid, String>,
+}
+
+impl TrustStore {
+    /// Reads the fingerprints pinned so far from `data_dir`.
+    pub fn load(data_dir: &Path) -> Result<Self> {
+        let path = data_dir.join(KNOWN_PEERS_FILE);
+        let pinned = if path.exists() {
+            serde_json::from_str(&std::fs::read_to_string(&path)?)?
+        } else {
+            HashMap::new()
+        };
+        Ok(Self { path, pinned })
+    }
+
+    /// The fingerprint trusted for `peer_id`.
+    pub fn pinned(&self, peer_id: &Uuid) -> Option<&str> {
+        self.pinned.get(peer_id).map(String::as_str)
+    }
+
+    /// Compares `fingerprint` with the pinned one, pinning it if the peer is new.
+    pub fn check(&mut self, peer_id: Uuid, fingerprint: &str) -> TrustStatus {
+        match self.pinned.get(&peer_id) {
+            Some(pinned) if pinned == fingerprint => TrustStatus::Trusted,
+            Some(pinned) => TrustStatus::Changed {
+                pinned: pinned.clone(),
+            },
+            None => {
+                if let Err(e) = self.pin(peer_id, fingerprint.to_string()) {
+                    tracing::warn!("Failed to save fingerprint for {}: {}", peer_id, e);
+                }
+                TrustStatus::FirstContact
+            }
+        }
+    }
+
+    /// Trusts `fingerprint` for `peer_id` from now on and saves it.
+    pub fn pin(&mut self, peer_id: Uuid, fingerprint: String) -> Result<()> {
+        self.pinned.insert(peer_id, fingerprint);
+        std::fs::write(&self.path, serde_json::to_string_pretty(&self.pinned)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures_verify_under_the_signing_key_only() {
+        let identity = Identity::generate();
+        let signature = identity.sign(b"announcement");
+        assert!(verify_signature(&identity.public_key_hex(), b"announcement", &signature).is_ok());
+        assert!(verify_signature(&identity.public_key_hex(), b"announcemenT", &signature).is_err());
+        let other = Identity::generate();
+        assert!(verify_signature(&other.public_key_hex(), b"announcement", &signature).is_err());
+
+        // Someone's agreement key paired with another signing key is another
+        // identity, which their signatures don't hold under
+        let (agreement, _) = identity_key_halves(&identity.public_key_hex()).unwrap();
+        let (_, signing) = identity_key_halves(&other.public_key_hex()).unwrap();
+        let spliced = hex::encode([agreement, signing].concat());
+        assert!(verify_signature(&spliced, b"announcement", &signature).is_err());
+        assert_ne!(fingerprint_of_hex(&spliced).unwrap(), identity.fingerprint());
+    }
+
+    #[test]
+    fn signatures_match_the_ed25519_test_vectors() {
+        // RFC 8032, section 7.1, TEST 1 and TEST 2
+        let vectors = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                "",
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                "72",
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+        ];
+        for (secret, public, message, signature) in vectors {
+            let signing = SigningKey::from_bytes(&hex::decode(secret).unwrap().try_into().unwrap());
+            let identity = Identity::from_parts(Uuid::new_v4(), StaticSecret::random(), signing);
+            let message = hex::decode(message).unwrap();
+            assert_eq!(hex::encode(identity.sign(&message)), signature);
+            let (agreement, _) = identity_key_halves(&identity.public_key_hex()).unwrap();
+            let key = format!("{}{}", hex::encode(agreement), public);
+            assert_eq!(key, identity.public_key_hex());
+            assert!(verify_signature(&key, &message, &hex::decode(signature).unwrap()).is_ok());
+        }
+    }
+
+    #[test]
+    fn non_canonical_signatures_and_small_order_keys_are_rejected() {
+        let identity = Identity::generate();
+        let key = identity.public_key_hex();
+        let signature = identity.sign(b"message");
+
+        // s + L is the same scalar, written the way only a forger would
+        const L: [u8; 32] = [
+            0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14, 0, 0, 0, 0, 0,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+        ];
+        let mut stretched = signature;
+        let mut carry = 0u16;
+        for (byte, l) in stretched[32..].iter_mut().zip(L) {
+            let sum = *byte as u16 + l as u16 + carry;
+            *byte = sum as u8;
+            carry = sum >> 8;
+        }
+        assert!(verify_signature(&key, b"message", &stretched).is_err());
+
+        // The identity point signs anything with R the identity and s zero,
+        // were small-order keys taken
+        let (agreement, _) = identity_key_halves(&key).unwrap();
+        let mut neutral = [0u8; 32];
+        neutral[0] = 1;
+        let weak = hex::encode([agreement, neutral].concat());
+        let mut forged = [0u8; 64];
+        forged[0] = 1;
+        assert!(verify_signature(&weak, b"message", &forged).is_err());
+        assert!(verify_signature(&weak, b"another", &forged).is_err());
+    }
+
+    #[test]
+    fn damaged_signatures_are_rejected() {
+        let identity = Identity::generate();
+        let key = identity.public_key_hex();
+        let signature = identity.sign(b"message");
+        for byte in [0, 31, 32, 63] {
+            let mut damaged = signature;
+            damaged[byte] ^= 0x01;
+            assert!(verify_signature(&key, b"message", &damaged).is_err(), "byte {}", byte);
+        }
+        assert!(verify_signature(&key, b"message", &signature[..63]).is_err());
+        assert!(verify_signature(&key, b"message", &[0; 64]).is_err());
+        assert!(verify_signature("abcd", b"message", &signature).is_err());
+    }
+
+    #[test]
+    fn hostname_falls_back_past_placeholders() {
+        let peer_id = Uuid::new_v4();
+        let pick = |configured, system: &str, machine_id: &str| {
+            let present = |s: &str| (!s.is_empty()).then(|| s.to_string());
+            pick_hostname(configured, present(system), present(machine_id), peer_id)
+        };
+
+        assert_eq!(pick(Some("nas"), "laptop", "abc"), ("nas".to_string(), "config"));
+        assert_eq!(pick(Some(" "), "laptop", "abc"), ("laptop".to_string(), "system hostname"));
+        let (derived, source) = pick(None, "localhost.localdomain", "abc\n");
+        assert_eq!(source, "machine id");
+        assert_eq!(derived, pick(None, "", "abc").0);
+        let (fallback, source) = pick(None, "", "");
+        assert_eq!(source, "peer id");
+        assert_eq!(fallback, format!("peer-{}", &peer_id.simple().to_string()[..8]));
+    }
+
+    #[test]
+    fn identities_saved_before_signing_keys_get_one_and_keep_it() {
+        let dir = std::env::temp_dir().join(format!("p2p-sharing-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let secret = StaticSecret::random();
+        let stored = serde_json::json!({ "peer_id": Uuid::new_v4(), "secret_key": hex::encode(secret.to_bytes()) });
+        std::fs::write(dir.join(IDENTITY_FILE), stored.to_string()).unwrap();
+
+        let upgraded = Identity::load_or_create(&dir).unwrap();
+        assert_eq!(upgraded.secret().to_bytes(), secret.to_bytes());
+        let reloaded = Identity::load_or_create(&dir).unwrap();
+        assert_eq!(reloaded.public_key_hex(), upgraded.public_key_hex());
+        assert_eq!(reloaded.peer_id(), upgraded.peer_id());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
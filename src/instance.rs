@@ -0,0 +1,72 @@
+use anyhow::Result;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const LOCK_FILE: &str = "p2p-sharing.lock";
+
+/// Exclusive lock that keeps a second daemon from sharing the same data
+/// directory. The OS releases the lock when the process exits, so a lock
+/// left behind by a crash is never mistaken for a live instance.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    /// Takes the lock, or returns the running instance's details when another
+    /// process already holds it.
+    pub fn acquire(data_dir: &Path, web_url: &str) -> Result<Result<Self, RunningInstance>> {
+        let path = data_dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut contents = String::new();
+                let _ = file.read_to_string(&mut contents);
+                return Ok(Err(RunningInstance::parse(&contents)));
+            }
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+        }
+
+        // Whatever is in the file belongs to a process that no longer holds the lock
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "pid={}", std::process::id())?;
+        writeln!(file, "url={}", web_url)?;
+        file.sync_all()?;
+
+        Ok(Ok(Self { _file: file, path }))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RunningInstance {
+    pub pid: Option<u32>,
+    pub url: Option<String>,
+}
+
+impl RunningInstance {
+    fn parse(contents: &str) -> Self {
+        let mut instance = Self::default();
+        for line in contents.lines() {
+            match line.split_once('=') {
+                Some(("pid", pid)) => instance.pid = pid.trim().parse().ok(),
+                Some(("url", url)) => instance.url = Some(url.trim().to_string()),
+                _ => {}
+            }
+        }
+        instance
+    }
+}
@@ -0,0 +1,300 @@
+use crate::config::AppConfig;
+use crate::discovery::{DiscoveryMessage, DiscoveryService, Heard};
+use crate::peer::PeerManager;
+use crate::protocol::PeerInfo;
+use crate::tls;
+use crate::transfer::{ConnectionContext, TransferMessage, TransferService, MAX_CONTROL_MESSAGE_LEN};
+use crate::utils;
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncWrite, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration, Instant};
+
+/// Minimum time between warnings about refused connections.
+const REJECTION_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+/// How long an unknown address gets to introduce itself.
+const STRANGER_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often manually added peers are introduced to again, well within the
+/// time discovered peers are kept without hearing from them.
+const MANUAL_PEER_REFRESH: Duration = Duration::from_secs(10);
+
+impl TransferService {
+    /// Serves a connection from an address we don't take files from. All it
+    /// may do is ask who we are; anything else is refused.
+    pub(crate) async fn greet_stranger(
+        stream: TcpStream,
+        addr: SocketAddr,
+        context: ConnectionContext,
+        refusals: Arc<std::sync::Mutex<RefusalLog>>,
+    ) {
+        let Ok(mut stream) = tls::accept(context.tls.as_deref(), stream).await else {
+            refusals.lock().unwrap().refused(addr);
+            return;
+        };
+        let (read_half, mut writer) = stream.split();
+        let mut reader = BufReader::new(read_half);
+        let first = timeout(STRANGER_TIMEOUT, Self::read_message(&mut reader, MAX_CONTROL_MESSAGE_LEN)).await;
+        match first {
+            Ok(Ok(TransferMessage::Hello { announcement })) => {
+                if let Err(e) = Self::answer_hello(&mut writer, addr, &context, announcement, false).await {
+                    tracing::debug!("Couldn't answer the introduction from {}: {}", addr, e);
+                }
+            }
+            _ => refusals.lock().unwrap().refused(addr),
+        }
+    }
+
+    /// Answers a Hello with our own announcement. With `learn`, the sender
+    /// is also taken as a peer, reachable at the address it connected from.
+    pub(crate) async fn answer_hello<W: AsyncWrite + Unpin>(
+        stream: &mut W,
+        addr: SocketAddr,
+        context: &ConnectionContext,
+        announcement: DiscoveryMessage,
+        learn: bool,
+    ) -> Result<()> {
+        let ours = Self::announcement(&context.config, context.port, &*context.peers.read().await)?;
+        if learn {
+            let address = SocketAddr::new(addr.ip().to_canonical(), announcement.address.port());
+            let heard = Heard::Hello { address, manual: false };
+            let events = DiscoveryService::apply_announcement(&mut *context.peers.write().await, announcement, addr, heard);
+            if let Some(ws) = &context.websocket {
+                DiscoveryService::notify(ws, events).await;
+            }
+        }
+        Self::write_message(stream, &TransferMessage::Hello { announcement: ours }).await
+    }
+
+    /// Our signed announcement, as sent in a Hello, naming `port` as our
+    /// transfer port.
+    fn announcement(config: &AppConfig, port: u16, peers: &PeerManager) -> Result<DiscoveryMessage> {
+        let addresses = utils::advertised_addresses(config.network.interface.as_deref(), port);
+        DiscoveryMessage::signed(peers, config, addresses, false, Vec::new())
+    }
+
+    /// Adds the node at `address` ("host" or "host:port") as a peer and keeps
+    /// it listed until shutdown, reintroducing us every so often.
+    pub async fn add_peer(&self, address: &str) -> Result<PeerInfo> {
+        let peer = self.introduce(address).await?;
+        let mut manual_peers = self.manual_peers.lock().unwrap();
+        if !manual_peers.iter().any(|known| known == address) {
+            manual_peers.push(address.to_string());
+        }
+        Ok(peer)
+    }
+
+    /// Exchanges Hellos with the node at `address`, adding it as a manual
+    /// peer once it has proven its identity.
+    async fn introduce(&self, address: &str) -> Result<PeerInfo> {
+        let address = Self::resolve(address, self.config.network.transfer_port).await?;
+        let stream = self.reach(address).await?;
+        let pinned = self.peers.read().await.tls_fingerprint(address);
+        let mut stream = match self.tls() {
+            // Nothing is pinned for a node we're only now meeting, so what
+            // it presents is held to what it announces
+            Some(tls) if pinned.is_none() && tls.required() => tls.connect_unpinned(stream).await?,
+            tls => tls::connect(tls, stream, pinned.as_deref()).await?,
+        };
+        let presented = pinned.is_none().then(|| stream.peer_fingerprint()).flatten();
+        let (read_half, mut writer) = stream.split();
+        let mut reader = BufReader::new(read_half);
+
+        let ours = Self::announcement(&self.config, self.port(), &*self.peers.read().await)?;
+        Self::write_message(&mut writer, &TransferMessage::Hello { announcement: ours }).await?;
+        let response = timeout(Duration::from_secs(10), Self::read_message(&mut reader, MAX_CONTROL_MESSAGE_LEN)).await??;
+        let TransferMessage::Hello { announcement } = response else {
+            return Err(anyhow::anyhow!("Unexpected response"));
+        };
+        // Unlike on a LAN, there's no other way to tell who answered
+        if announcement.signature.is_none() {
+            return Err(anyhow::anyhow!("Node at {} didn't sign its introduction", address));
+        }
+        if presented.is_some() && presented != announcement.verified_tls_fingerprint() {
+            return Err(anyhow::anyhow!("Node at {} presented a TLS certificate it didn't announce", address));
+        }
+
+        let peer_id = announcement.peer_id;
+        let heard = Heard::Hello { address, manual: true };
+        let (events, peer) = {
+            let mut peers = self.peers.write().await;
+            if peer_id == peers.local_id() {
+                return Err(anyhow::anyhow!("{} is this node", address));
+            }
+            let events = DiscoveryService::apply_announcement(&mut peers, announcement, address, heard);
+            let peer = peers.get_peer(&peer_id).filter(|peer| peer.address == address).cloned();
+            (events, peer)
+        };
+        if let Some(ws) = self.websocket_service.get() {
+            DiscoveryService::notify(ws, events).await;
+        }
+        let peer = peer.ok_or_else(|| anyhow::anyhow!("Node at {} didn't prove the identity it claims", address))?;
+        Ok(PeerInfo::from(peer))
+    }
+
+    /// Introduces us to every manually added peer now and then, so each side
+    /// keeps the other listed and sees its address changes. Runs until shutdown.
+    pub async fn keep_in_touch(&self) -> Result<()> {
+        let mut refresh = tokio::time::interval(MANUAL_PEER_REFRESH);
+        loop {
+            tokio::select! {
+                _ = refresh.tick() => {}
+                _ = self.shutdown.cancelled() => return Ok(()),
+            }
+            let addresses = self.manual_peers.lock().unwrap().clone();
+            for address in addresses {
+                if let Err(e) = self.introduce(&address).await {
+                    tracing::debug!("Couldn't reach manually added peer {}: {}", address, e);
+                }
+            }
+        }
+    }
+}
+
+/// Rate limits the warning about connections refused from unknown addresses.
+#[derive(Default)]
+pub(crate) struct RefusalLog {
+    last_warning: Option<Instant>,
+    suppressed: u64,
+}
+
+impl RefusalLog {
+    pub(crate) fn refused(&mut self, addr: SocketAddr) {
+        let due = self
+            .last_warning
+            .is_none_or(|at| at.elapsed() >= REJECTION_WARNING_INTERVAL);
+        if !due {
+            self.suppressed += 1;
+            return;
+        }
+        if self.suppressed > 0 {
+            tracing::warn!(
+                "Refused transfer connection from unknown address {} ({} others refused since the last warning)",
+                addr,
+                self.suppressed
+            );
+        } else {
+            tracing::warn!("Refused transfer connection from unknown address {}", addr);
+        }
+        self.last_warning = Some(Instant::now());
+        self.suppressed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::tests::{downloaded_files, request, sample_data, scratch_dir, serve_once, service, CHUNK};
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn introduced_peer_is_added_and_kept() {
+        let (ours, theirs) = (scratch_dir(), scratch_dir());
+        let us = service(AppConfig::default(), &ours);
+        let them = service(AppConfig::default(), &theirs);
+        let their_id = them.peers.read().await.local_id();
+
+        // They don't know us, so they answer without taking us on
+        let (address, serving) = serve_once(&them, &theirs, false).await;
+        let added = us.add_peer(&address.to_string()).await.unwrap();
+        serving.await.unwrap();
+        assert_eq!((added.id, added.address, added.manual), (their_id, address, true));
+        assert!(them.peers.read().await.list_peers().is_empty());
+
+        let mut peers = us.peers.write().await;
+        peers.cleanup_stale_peers(0);
+        assert!(peers.get_peer(&their_id).is_some());
+        assert_eq!(*us.manual_peers.lock().unwrap(), vec![address.to_string()]);
+        let _ = std::fs::remove_dir_all(&ours);
+        let _ = std::fs::remove_dir_all(&theirs);
+    }
+
+    #[tokio::test]
+    async fn known_peer_is_learned_from_its_introduction() {
+        let (ours, theirs) = (scratch_dir(), scratch_dir());
+        let us = service(AppConfig::default(), &ours);
+        let them = service(AppConfig::default(), &theirs);
+        let our_id = us.peers.read().await.local_id();
+
+        let (address, serving) = serve_once(&them, &theirs, true).await;
+        us.add_peer(&address.to_string()).await.unwrap();
+        serving.await.unwrap();
+        let peers = them.peers.read().await;
+        let learned = peers.get_peer(&our_id).unwrap();
+        let port = AppConfig::default().network.transfer_port;
+        assert_eq!(learned.address, SocketAddr::from(([127, 0, 0, 1], port)));
+        assert!(!learned.manual);
+        let _ = std::fs::remove_dir_all(&ours);
+        let _ = std::fs::remove_dir_all(&theirs);
+    }
+
+    #[tokio::test]
+    async fn unproven_introduction_is_refused() {
+        let (ours, theirs) = (scratch_dir(), scratch_dir());
+        let us = service(AppConfig::default(), &ours);
+        let impostor = service(AppConfig::default(), &theirs);
+
+        for forge in [false, true] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            let mut announcement = TransferService::announcement(&AppConfig::default(), address.port(), &*impostor.peers.read().await).unwrap();
+            if forge {
+                // Signed, but over a different peer id
+                announcement.peer_id = Uuid::new_v4();
+            } else {
+                announcement.signature = None;
+            }
+            let answering = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let (read_half, mut writer) = stream.split();
+                TransferService::read_message(&mut BufReader::new(read_half), MAX_CONTROL_MESSAGE_LEN).await.unwrap();
+                TransferService::write_message(&mut writer, &TransferMessage::Hello { announcement }).await.unwrap();
+            });
+            assert!(us.add_peer(&address.to_string()).await.is_err(), "forged: {}", forge);
+            answering.await.unwrap();
+        }
+        assert!(us.peers.read().await.list_peers().is_empty());
+        assert!(us.manual_peers.lock().unwrap().is_empty());
+        let _ = std::fs::remove_dir_all(&ours);
+        let _ = std::fs::remove_dir_all(&theirs);
+    }
+
+    #[tokio::test]
+    async fn strangers_are_refused_before_anything_they_send_is_taken() {
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        config.network.transfer_port = 0;
+        let service = Arc::new(service(config, &dir));
+        let listening = service.clone();
+        tokio::spawn(async move { listening.start_listener().await });
+        let address = SocketAddr::from(([127, 0, 0, 1], service.wait_for_local_addr().await.port()));
+
+        // Nobody we know, offering a file and sending it straight after
+        let data = sample_data();
+        let transfer_id = Uuid::new_v4();
+        let (reader, mut writer) = TcpStream::connect(address).await.unwrap().into_split();
+        let mut stranger = BufReader::new(reader);
+        TransferService::write_message(&mut writer, &request(transfer_id, "intruder.bin", &data)).await.unwrap();
+        for (index, chunk) in data.chunks(CHUNK).enumerate() {
+            let chunk = TransferMessage::Chunk {
+                transfer_id,
+                chunk_index: index as u64,
+                data: chunk.to_vec(),
+                crc: None,
+            };
+            let _ = TransferService::write_message(&mut writer, &chunk).await;
+        }
+
+        // Hung up on without a word, and nothing was started or saved
+        let mut answer = Vec::new();
+        let closed = timeout(Duration::from_secs(5), stranger.read_to_end(&mut answer)).await.unwrap();
+        assert!(closed.is_err() || answer.is_empty());
+        assert!(service.refusals.lock().unwrap().last_warning.is_some());
+        assert!(service.active.snapshot(None).is_empty());
+        assert!(downloaded_files(&dir).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
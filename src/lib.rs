@@ -0,0 +1,63 @@
+//! Peer-to-peer file sharing on the local network.
+//!
+//! [`Node`] runs everything the `p2p-sharing` daemon does; the services it
+//! wires together are exported too, for embedding only some of them.
+#![deny(missing_docs)]
+
+mod active;
+mod analytics;
+mod approval;
+mod archive;
+mod bandwidth;
+mod benchmark;
+mod broadcast;
+mod capability;
+mod chat;
+mod client_queue;
+mod compress;
+mod config;
+mod crypto;
+mod discovery;
+mod history;
+mod identity;
+mod introduction;
+mod maintenance;
+mod node;
+mod notify;
+mod objects;
+mod organize;
+mod parallel;
+mod peer;
+mod portmap;
+mod privacy;
+pub mod protocol;
+mod relay;
+mod rendezvous;
+mod schedule;
+mod session;
+mod share;
+mod slots;
+mod supervisor;
+mod sync;
+mod tls;
+mod trace;
+mod transfer;
+mod usage;
+mod utils;
+mod verify;
+mod watch;
+mod webhook;
+mod websocket;
+
+pub use config::AppConfig;
+pub use discovery::DiscoveryService;
+pub use history::TransferHistory;
+pub use identity::{Identity, TrustStore};
+pub use node::{Node, NodeBuilder};
+pub use peer::PeerManager;
+pub use portmap::PortMapper;
+pub use schedule::Scheduler;
+pub use sync::SyncService;
+pub use transfer::TransferService;
+pub use utils::{format_bytes, format_speed};
+pub use websocket::WebSocketService;
@@ -1,76 +1,143 @@
-mod config;
-mod discovery;
-mod history;
-mod peer;
-mod protocol;
-mod transfer;
-mod utils;
-mod websocket;
+mod cli;
+mod client;
+mod daemon;
+mod instance;
 
 use anyhow::Result;
-use config::AppConfig;
-use discovery::DiscoveryService;
-use transfer::TransferService;
-use websocket::WebSocketService;
+use clap::Parser;
+use cli::{Cli, Command};
+use instance::{InstanceLock, RunningInstance};
+use p2p_sharing::{AppConfig, Node};
 use std::sync::Arc;
-use tokio::sync::RwLock;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
+    if cli.daemon_child {
+        // Output goes to a log file, so skip the colour codes
+        tracing_subscriber::fmt().with_ansi(false).init();
+    } else {
+        tracing_subscriber::fmt::init();
+    }
 
+    if let Some(profile) = &cli.profile {
+        AppConfig::set_profile(profile)?;
+    }
     let config = AppConfig::load()?;
     let config = Arc::new(config);
 
+    match cli.command {
+        Some(Command::Stop) => daemon::stop().await,
+        Some(Command::Status) => daemon::status(&config).await,
+        Some(command) if cli.forward => run_daemon(config, true, Some(command), cli.daemon_child).await,
+        Some(command) => client::run(command, &config).await,
+        None if cli.daemon => daemon::spawn_detached(&config).await,
+        None => run_daemon(config, cli.forward, None, cli.daemon_child).await,
+    }
+}
+
+/// Runs the daemon. With `forward`, `command` goes to whichever daemon ends
+/// up serving this directory: the one already running, or this one.
+async fn run_daemon(config: Arc<AppConfig>, forward: bool, command: Option<Command>, detached: bool) -> Result<()> {
+    let web_url = format!("http://127.0.0.1:{}", config.network.web_port);
+    let instance_lock = match InstanceLock::acquire(&AppConfig::data_dir(), &web_url)? {
+        Ok(lock) => lock,
+        Err(running) => return already_running(running, forward, command, &config).await,
+    };
+    tracing::debug!("Holding instance lock {}", instance_lock.path().display());
+
+    if detached {
+        daemon::write_pid_file()?;
+    }
+
     tracing::info!("Starting P2P File Sharing Backend");
     tracing::info!("Discovery port: {}", config.network.discovery_port);
     tracing::info!("Transfer port: {}", config.network.transfer_port);
     tracing::info!("WebSocket port: {}", config.network.web_port);
 
-    let peers = Arc::new(RwLock::new(peer::PeerManager::new()));
+    let node = Node::builder(config.clone()).build()?;
 
-    let transfer_service = Arc::new(TransferService::new(config.clone()));
-    let transfer_service_listener = transfer_service.clone();
-    
-    let transfer_task = tokio::spawn(async move {
-        tracing::info!("Transfer service started");
-        if let Err(e) = transfer_service_listener.start_listener().await {
-            tracing::error!("Transfer listener error: {}", e);
-        }
+    if let Some(command) = command {
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = client::run_once_started(command, &config).await {
+                tracing::error!("Forwarded command failed: {}", e);
+            }
+        });
+    }
+
+    let stopping = node.shutdown_token();
+    tokio::spawn(async move {
+        stopping.cancelled().await;
+        shutdown_signal().await;
+        tracing::warn!("Second shutdown signal received, exiting immediately");
+        std::process::exit(1);
     });
 
-    let websocket_service = Arc::new(WebSocketService::new(
-        config.clone(),
-        peers.clone(),
-        transfer_service.clone(),
-    ));
-
-    let mut discovery = DiscoveryService::new(
-        config.clone(),
-        peers.clone(),
-    ).await?;
-    discovery.set_websocket_service(websocket_service.clone());
-
-    let discovery_task = tokio::spawn(async move {
-        tracing::info!("Discovery service started");
-        if let Err(e) = discovery.start().await {
-            tracing::error!("Discovery service error: {}", e);
+    let result = node.run(shutdown_signal()).await;
+
+    if detached {
+        daemon::remove_pid_file();
+    }
+
+    tracing::info!("Shutdown complete");
+    result
+}
+
+async fn already_running(
+    running: RunningInstance,
+    forward: bool,
+    command: Option<Command>,
+    config: &AppConfig,
+) -> Result<()> {
+    let url = running
+        .url
+        .unwrap_or_else(|| format!("http://127.0.0.1:{}", config.network.web_port));
+    let pid = running
+        .pid
+        .map(|pid| format!(" (pid {})", pid))
+        .unwrap_or_default();
+
+    if !forward {
+        anyhow::bail!("Another instance is already running{} in this directory, open {}", pid, url);
+    }
+    if let Some(command) = command {
+        return client::run(command, config).await;
+    }
+
+    let (_, hostname) = client::DaemonClient::connect(config).await?.local_info().await?;
+    println!("Already running as {}{} at {}", hostname, pid, url);
+    Ok(())
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
         }
-    });
+    };
 
-    let websocket_task = tokio::spawn(async move {
-        tracing::info!("WebSocket service started");
-        if let Err(e) = websocket_service.start_server().await {
-            tracing::error!("WebSocket server error: {}", e);
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
         }
-    });
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
     tokio::select! {
-        _ = transfer_task => {},
-        _ = discovery_task => {},
-        _ = websocket_task => {},
+        _ = ctrl_c => {},
+        _ = terminate => {},
     }
-
-    Ok(())
 }
 
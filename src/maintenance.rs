@@ -0,0 +1,263 @@
+use crate::config::MaintenanceConfig;
+use crate::objects::{self, OBJECTS_DIR};
+use crate::sync::SYNC_DIR;
+use crate::transfer::{TransferService, QUARANTINE_DIR};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// What a leftover file was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LeftoverKind {
+    /// A download that never finished.
+    Partial,
+    /// A file fetched for a synced folder that never made it into place.
+    SyncStaging,
+    /// Blocked content kept past its retention.
+    Quarantine,
+    /// Stored content no received file links to any more.
+    Object,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Leftover {
+    pub path: String,
+    pub kind: LeftoverKind,
+    pub size: u64,
+}
+
+/// What a maintenance pass removed, or would have on a dry run.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MaintenanceReport {
+    pub dry_run: bool,
+    pub removed: Vec<Leftover>,
+    pub freed_bytes: u64,
+    /// Old enough to go, but a running transfer is writing them.
+    pub in_use: usize,
+    /// Couldn't be removed; the log says why.
+    pub failed: usize,
+}
+
+/// Where leftovers are looked for, and how old they must be to go.
+pub struct Sweep {
+    /// Partial downloads sit at its top level, stored content in its
+    /// objects folder.
+    pub downloads_dir: PathBuf,
+    pub quarantine_dir: PathBuf,
+    /// Sync staging folders; anything still in one is a leftover.
+    pub staging_dirs: Vec<PathBuf>,
+    pub partial_max_age: Duration,
+    pub quarantine_retention: Duration,
+}
+
+impl Sweep {
+    pub fn new(
+        config: &MaintenanceConfig,
+        downloads_dir: PathBuf,
+        quarantine_dir: PathBuf,
+        staging_dirs: Vec<PathBuf>,
+    ) -> Self {
+        Self {
+            downloads_dir,
+            quarantine_dir,
+            staging_dirs,
+            partial_max_age: Duration::from_secs(config.partial_max_age * 3600),
+            quarantine_retention: Duration::from_secs(config.quarantine_retention * 24 * 3600),
+        }
+    }
+
+    /// Removes every leftover last written before its cutoff, except files
+    /// `in_use` says a running transfer is writing. Blocks on the filesystem.
+    pub fn run(&self, now: SystemTime, dry_run: bool, in_use: impl Fn(&Path) -> bool) -> MaintenanceReport {
+        let partials = files_in(&self.downloads_dir)
+            .into_iter()
+            .filter(|path| path.extension().is_some_and(|ext| ext == "part"))
+            .map(|path| (path, LeftoverKind::Partial, self.partial_max_age));
+        let quarantined = files_in(&self.quarantine_dir)
+            .into_iter()
+            .map(|path| (path, LeftoverKind::Quarantine, self.quarantine_retention));
+        let staged = self
+            .staging_dirs
+            .iter()
+            .flat_map(|dir| files_in(dir))
+            .map(|path| (path, LeftoverKind::SyncStaging, self.partial_max_age));
+        // However old, an object still linked from downloads is kept
+        let stored = files_in(&self.downloads_dir.join(OBJECTS_DIR))
+            .into_iter()
+            .map(|path| (path, LeftoverKind::Object, Duration::ZERO));
+
+        let mut report = MaintenanceReport {
+            dry_run,
+            ..Default::default()
+        };
+        for (path, kind, max_age) in partials.chain(quarantined).chain(staged).chain(stored) {
+            let Ok(metadata) = std::fs::symlink_metadata(&path) else { continue };
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .unwrap_or_default();
+            if !metadata.is_file() || age < max_age {
+                continue;
+            }
+            if kind == LeftoverKind::Object && !objects::unreferenced(&metadata) {
+                continue;
+            }
+            // Checked last and per file, so a transfer that has just started is seen
+            if in_use(&path) {
+                report.in_use += 1;
+                continue;
+            }
+            if !dry_run {
+                if let Err(e) = std::fs::remove_file(&path) {
+                    tracing::warn!("Failed to remove {}: {}", path.display(), e);
+                    report.failed += 1;
+                    continue;
+                }
+            }
+            report.freed_bytes += metadata.len();
+            report.removed.push(Leftover {
+                path: path.to_string_lossy().to_string(),
+                kind,
+                size: metadata.len(),
+            });
+        }
+        report
+    }
+}
+
+/// Files directly in `dir`; none if it doesn't exist.
+fn files_in(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dir) else { return Vec::new() };
+    entries
+        .flatten()
+        .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+        .map(|entry| entry.path())
+        .collect()
+}
+
+impl TransferService {
+    /// Removes partial downloads and sync staging files past
+    /// `maintenance.partial_max_age`, and quarantined files past their
+    /// retention. Receives always start over, so no partial is kept for a
+    /// resume; those a running transfer is writing are never touched.
+    pub async fn run_maintenance(&self, dry_run: bool) -> Result<MaintenanceReport> {
+        let downloads_dir = self.downloads_dir();
+        let pairs = match self.sync_service.get() {
+            Some(sync) => sync.pairs(),
+            None => self.config.sync.pairs.clone(),
+        };
+        let sweep = Sweep::new(
+            &self.config.maintenance,
+            downloads_dir.clone(),
+            downloads_dir.join(QUARANTINE_DIR),
+            pairs.iter().filter_map(|pair| pair.root()).map(|root| root.join(SYNC_DIR)).collect(),
+        );
+        let active = self.active.clone();
+        let report = tokio::task::spawn_blocking(move || {
+            sweep.run(std::time::SystemTime::now(), dry_run, |path| active.is_writing(path))
+        })
+        .await?;
+        if !dry_run && (!report.removed.is_empty() || report.failed > 0) {
+            tracing::info!(
+                "Maintenance removed {} leftover files ({} bytes); {} in use, {} failed",
+                report.removed.len(),
+                report.freed_bytes,
+                report.in_use,
+                report.failed
+            );
+        }
+        Ok(report)
+    }
+
+    /// Runs maintenance now, then every `maintenance.interval` seconds.
+    pub async fn keep_tidy(&self) -> Result<()> {
+        let interval = self.config.maintenance.interval;
+        loop {
+            if let Err(e) = self.run_maintenance(false).await {
+                tracing::warn!("Maintenance failed: {}", e);
+            }
+            let next = async {
+                match interval {
+                    0 => std::future::pending().await,
+                    secs => tokio::time::sleep(Duration::from_secs(secs)).await,
+                }
+            };
+            tokio::select! {
+                _ = next => {}
+                _ = self.shutdown.cancelled() => return Ok(()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_leftovers_go_unless_a_transfer_is_writing_them() {
+        let dir = std::env::temp_dir().join(format!("p2p-sharing-test-{}", uuid::Uuid::new_v4()));
+        let downloads = dir.join("downloads");
+        let quarantine = downloads.join(".quarantine");
+        let staging = dir.join("synced").join(".p2p-sync");
+        for folder in [&quarantine, &staging] {
+            std::fs::create_dir_all(folder).unwrap();
+        }
+        std::fs::write(downloads.join("report.pdf"), b"done").unwrap();
+        std::fs::write(downloads.join("video.mp4.part"), b"half").unwrap();
+        std::fs::write(downloads.join("busy.iso.part"), b"half").unwrap();
+        std::fs::write(quarantine.join("blocked.exe"), b"bad").unwrap();
+        std::fs::write(staging.join("notes.txt"), b"staged").unwrap();
+
+        let config = MaintenanceConfig::default();
+        let sweep = Sweep::new(&config, downloads.clone(), quarantine.clone(), vec![staging.clone()]);
+        let busy = downloads.join("busy.iso.part");
+        let in_use = |path: &Path| path == busy;
+
+        // Within a day nothing is old enough
+        let report = sweep.run(SystemTime::now(), false, in_use);
+        assert!(report.removed.is_empty());
+
+        let in_two_days = SystemTime::now() + Duration::from_secs(48 * 3600);
+        let dry = sweep.run(in_two_days, true, in_use);
+        let kinds: Vec<LeftoverKind> = dry.removed.iter().map(|leftover| leftover.kind).collect();
+        assert_eq!(kinds, vec![LeftoverKind::Partial, LeftoverKind::SyncStaging]);
+        assert_eq!((dry.freed_bytes, dry.in_use), (10, 1));
+        assert!(downloads.join("video.mp4.part").exists());
+
+        sweep.run(in_two_days, false, in_use);
+        assert!(!downloads.join("video.mp4.part").exists() && !staging.join("notes.txt").exists());
+        assert!(busy.exists() && downloads.join("report.pdf").exists() && quarantine.join("blocked.exe").exists());
+
+        let report = sweep.run(SystemTime::now() + Duration::from_secs(31 * 24 * 3600), false, in_use);
+        assert_eq!(report.removed.len(), 1);
+        assert!(!quarantine.join("blocked.exe").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stored_content_goes_once_nothing_links_to_it() {
+        let dir = std::env::temp_dir().join(format!("p2p-sharing-test-{}", uuid::Uuid::new_v4()));
+        let downloads = dir.join("downloads");
+        let stored = downloads.join(OBJECTS_DIR);
+        std::fs::create_dir_all(&stored).unwrap();
+        std::fs::write(stored.join("aa"), b"kept").unwrap();
+        std::fs::hard_link(stored.join("aa"), downloads.join("kept.txt")).unwrap();
+        std::fs::write(stored.join("bb"), b"orphan").unwrap();
+
+        let config = MaintenanceConfig::default();
+        let sweep = Sweep::new(&config, downloads.clone(), downloads.join(".quarantine"), Vec::new());
+        let report = sweep.run(SystemTime::now(), false, |_| false);
+        assert_eq!(report.removed.len(), 1);
+        assert_eq!((report.removed[0].kind, report.freed_bytes), (LeftoverKind::Object, 6));
+        assert!(stored.join("aa").exists() && !stored.join("bb").exists());
+
+        std::fs::remove_file(downloads.join("kept.txt")).unwrap();
+        assert_eq!(sweep.run(SystemTime::now(), false, |_| false).removed.len(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,1177 @@
+use crate::config::AppConfig;
+use crate::discovery::DiscoveryService;
+use crate::identity::{self, Identity, TrustStore};
+use crate::peer::{PeerManager, PEER_TIMEOUT_SECS};
+use crate::portmap::PortMapper;
+use crate::schedule::{Scheduler, SCHEDULE_FILE};
+use crate::supervisor;
+use crate::sync::SyncService;
+use crate::tls::Tls;
+use crate::transfer::TransferService;
+use crate::utils;
+use crate::websocket::WebSocketService;
+use anyhow::Result;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+
+/// Sets up a [`Node`].
+pub struct NodeBuilder {
+    config: Arc<AppConfig>,
+    data_dir: PathBuf,
+    shutdown: CancellationToken,
+}
+
+impl NodeBuilder {
+    /// Stops the node when `shutdown` is cancelled, in addition to the
+    /// future given to [`Node::run`].
+    pub fn shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Keeps the identity, history and other state in `data_dir`, and
+    /// received files in its `downloads` folder unless
+    /// `transfer.downloads_dir` says otherwise, instead of the working
+    /// directory. Two nodes in one process need a folder each.
+    pub fn data_dir(mut self, data_dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = data_dir.into();
+        self
+    }
+
+    /// Loads the identity and trusted peers from the data directory and
+    /// wires the services to each other. Nothing runs until [`Node::run`].
+    pub fn build(self) -> Result<Node> {
+        let NodeBuilder { config, data_dir, shutdown } = self;
+        config.network.validate(PEER_TIMEOUT_SECS)?;
+        std::fs::create_dir_all(&data_dir)?;
+        let identity = Arc::new(Identity::load_or_create(&data_dir)?);
+        let trust = TrustStore::load(&data_dir)?;
+        tracing::info!("Identity fingerprint: {}", identity.fingerprint());
+        let hostname = identity::resolve_hostname(config.identity.hostname.as_deref(), identity.peer_id());
+        let tls = Tls::load(&config.security, &data_dir)?;
+        let mut peer_manager = PeerManager::new(identity, trust, hostname);
+        if let Some(tls) = &tls {
+            peer_manager.set_local_tls_fingerprint(tls.fingerprint().to_string());
+        }
+        let peers = Arc::new(RwLock::new(peer_manager));
+
+        let transfer = Arc::new(TransferService::new(
+            config.clone(),
+            peers.clone(),
+            data_dir.clone(),
+            shutdown.clone(),
+        ));
+        if let Some(tls) = tls {
+            transfer.set_tls(tls);
+        }
+        let downloads_dir = transfer.downloads_dir();
+        utils::ensure_writable_dir(&downloads_dir).map_err(|e| {
+            anyhow::anyhow!("Can't save received files in {}: {}", downloads_dir.display(), e)
+        })?;
+        tracing::info!("Saving received files in {}", downloads_dir.display());
+        let port_mapper = Arc::new(PortMapper::new(config.clone()));
+        let scheduler = Arc::new(Scheduler::load(
+            data_dir.join(SCHEDULE_FILE),
+            config.schedule.clone(),
+        ));
+        let sync = Arc::new(SyncService::new(config.clone(), peers.clone(), transfer.clone()));
+        transfer.set_sync_service(sync.clone());
+
+        let websocket = Arc::new(WebSocketService::new(
+            config.clone(),
+            peers.clone(),
+            transfer.clone(),
+            port_mapper.clone(),
+            scheduler.clone(),
+            sync.clone(),
+            shutdown.clone(),
+        ));
+        transfer.set_websocket_service(websocket.clone());
+        sync.set_websocket_service(websocket.clone());
+
+        Ok(Node {
+            config,
+            peers,
+            transfer,
+            port_mapper,
+            scheduler,
+            sync,
+            websocket,
+            shutdown,
+        })
+    }
+}
+
+/// Every service of a sharing node, wired together the way the daemon
+/// runs them.
+pub struct Node {
+    config: Arc<AppConfig>,
+    peers: Arc<RwLock<PeerManager>>,
+    transfer: Arc<TransferService>,
+    port_mapper: Arc<PortMapper>,
+    scheduler: Arc<Scheduler>,
+    sync: Arc<SyncService>,
+    websocket: Arc<WebSocketService>,
+    shutdown: CancellationToken,
+}
+
+impl Node {
+    /// Starts setting up a node with `config`.
+    pub fn builder(config: Arc<AppConfig>) -> NodeBuilder {
+        NodeBuilder {
+            config,
+            data_dir: AppConfig::data_dir(),
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// The config the node was built with.
+    pub fn config(&self) -> &Arc<AppConfig> {
+        &self.config
+    }
+
+    /// Known peers and this node's identity.
+    pub fn peers(&self) -> &Arc<RwLock<PeerManager>> {
+        &self.peers
+    }
+
+    /// Sends and receives files.
+    pub fn transfers(&self) -> &Arc<TransferService> {
+        &self.transfer
+    }
+
+    /// Serves the web UI and the client API, and owns the transfer history.
+    pub fn websocket(&self) -> &Arc<WebSocketService> {
+        &self.websocket
+    }
+
+    /// Cancelled when the node stops.
+    pub fn shutdown_token(&self) -> CancellationToken {
+        self.shutdown.clone()
+    }
+
+    /// Runs every service under supervision until `stop` resolves, the
+    /// shutdown token is cancelled or a service fails for good, then gives
+    /// running transfers the configured grace period and saves state.
+    ///
+    /// Returns the error of the service that failed, if one did.
+    pub async fn run(self, stop: impl Future<Output = ()>) -> Result<()> {
+        let mut services = self.spawn_services();
+
+        let mut failure = None;
+        tokio::select! {
+            _ = stop => {
+                tracing::info!("Shutdown signal received, stopping services");
+            }
+            _ = self.shutdown.cancelled() => {
+                tracing::info!("Shutdown requested, stopping services");
+            }
+            Some(result) = services.join_next() => failure = service_failure(result),
+        }
+
+        self.shutdown.cancel();
+
+        let grace_period = Duration::from_secs(self.config.transfer.shutdown_grace_period);
+        if tokio::time::timeout(grace_period, self.transfer.wait_for_idle()).await.is_err() {
+            tracing::warn!("Active transfers did not finish within {}s, cancelling them", grace_period.as_secs());
+            // Peers are told, so they don't wait on a connection that's about to drop
+            let cancelled = self.transfer.active_transfers().cancel_all(None).await;
+            tracing::info!("Cancelled {} transfers", cancelled.len());
+        }
+
+        // Each service winds down on its own once the token is cancelled; give
+        // them a moment to send the discovery goodbye and close client sockets.
+        let _ = tokio::time::timeout(Duration::from_secs(2), async {
+            while services.join_next().await.is_some() {}
+        }).await;
+
+        if let Err(e) = self.websocket.flush_history().await {
+            tracing::error!("Failed to save transfer history: {}", e);
+        }
+        if let Err(e) = self.transfer.usage().flush() {
+            tracing::error!("Failed to save bandwidth usage: {}", e);
+        }
+        if let Err(e) = self.transfer.approvals().flush() {
+            tracing::error!("Failed to save accept rules: {}", e);
+        }
+        if let Err(e) = self.sync.flush() {
+            tracing::error!("Failed to save synced folders: {}", e);
+        }
+
+        match failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn spawn_services(&self) -> JoinSet<Result<()>> {
+        let config = &self.config;
+        let shutdown = &self.shutdown;
+        let websocket_service = &self.websocket;
+        let mut services = JoinSet::new();
+
+        {
+            let transfer_service = self.transfer.clone();
+            services.spawn(supervisor::supervise(
+                "transfer",
+                config.supervisor.clone(),
+                shutdown.clone(),
+                websocket_service.clone(),
+                move || {
+                    let transfer_service = transfer_service.clone();
+                    async move { transfer_service.start_listener().await }
+                },
+            ));
+        }
+
+        if self.transfer.bandwidth().is_scheduled() {
+            let transfer_service = self.transfer.clone();
+            let shutdown_token = shutdown.clone();
+            services.spawn(supervisor::supervise(
+                "bandwidth schedule",
+                config.supervisor.clone(),
+                shutdown.clone(),
+                websocket_service.clone(),
+                move || {
+                    let transfer_service = transfer_service.clone();
+                    let shutdown = shutdown_token.clone();
+                    async move { transfer_service.bandwidth().follow_schedule(shutdown).await }
+                },
+            ));
+        }
+
+        {
+            let transfer_service = self.transfer.clone();
+            services.spawn(supervisor::supervise(
+                "maintenance",
+                config.supervisor.clone(),
+                shutdown.clone(),
+                websocket_service.clone(),
+                move || {
+                    let transfer_service = transfer_service.clone();
+                    async move { transfer_service.keep_tidy().await }
+                },
+            ));
+        }
+
+        {
+            let transfer_service = self.transfer.clone();
+            services.spawn(supervisor::supervise(
+                "manual peers",
+                config.supervisor.clone(),
+                shutdown.clone(),
+                websocket_service.clone(),
+                move || {
+                    let transfer_service = transfer_service.clone();
+                    async move { transfer_service.keep_in_touch().await }
+                },
+            ));
+        }
+
+        if let Some(coordinator) = config.rendezvous.coordinator.clone() {
+            let transfer_service = self.transfer.clone();
+            services.spawn(supervisor::supervise(
+                "rendezvous",
+                config.supervisor.clone(),
+                shutdown.clone(),
+                websocket_service.clone(),
+                move || {
+                    let transfer_service = transfer_service.clone();
+                    let coordinator = coordinator.clone();
+                    async move { transfer_service.keep_registered(&coordinator).await }
+                },
+            ));
+        }
+
+        if config.network.upnp {
+            let port_mapper = self.port_mapper.clone();
+            let transfer_service = self.transfer.clone();
+            let mapper_shutdown = shutdown.clone();
+            services.spawn(supervisor::supervise(
+                "port mapping",
+                config.supervisor.clone(),
+                shutdown.clone(),
+                websocket_service.clone(),
+                move || {
+                    let port_mapper = port_mapper.clone();
+                    let transfer_service = transfer_service.clone();
+                    let shutdown = mapper_shutdown.clone();
+                    async move {
+                        // The port isn't known before the listener binds when it's picked by the OS
+                        let port = tokio::select! {
+                            addr = transfer_service.wait_for_local_addr() => addr.port(),
+                            _ = shutdown.cancelled() => return Ok(()),
+                        };
+                        port_mapper.run(port, shutdown).await
+                    }
+                },
+            ));
+        }
+
+        {
+            let config = config.clone();
+            let peers = self.peers.clone();
+            let transfer_service = self.transfer.clone();
+            let port_mapper = self.port_mapper.clone();
+            let shutdown = shutdown.clone();
+            let websocket_service = websocket_service.clone();
+            services.spawn(supervisor::supervise(
+                "discovery",
+                config.supervisor.clone(),
+                shutdown.clone(),
+                websocket_service.clone(),
+                move || {
+                    let config = config.clone();
+                    let peers = peers.clone();
+                    let transfer_service = transfer_service.clone();
+                    let port_mapper = port_mapper.clone();
+                    let shutdown = shutdown.clone();
+                    let websocket_service = websocket_service.clone();
+                    async move {
+                        // Rebind on every start so a lost socket is recovered
+                        let mut discovery =
+                            DiscoveryService::new(config, peers, transfer_service, port_mapper, shutdown).await?;
+                        websocket_service.set_discovery_addr(discovery.local_addr()?);
+                        discovery.set_websocket_service(websocket_service);
+                        discovery.start().await
+                    }
+                },
+            ));
+        }
+
+        {
+            let websocket = websocket_service.clone();
+            services.spawn(supervisor::supervise(
+                "websocket",
+                config.supervisor.clone(),
+                shutdown.clone(),
+                websocket_service.clone(),
+                move || websocket.clone().start_server(),
+            ));
+        }
+
+        if config.transfer.stall_timeout_secs.is_some() {
+            let transfer_service = self.transfer.clone();
+            services.spawn(supervisor::supervise(
+                "stall watchdog",
+                config.supervisor.clone(),
+                shutdown.clone(),
+                websocket_service.clone(),
+                move || {
+                    let transfer_service = transfer_service.clone();
+                    async move { transfer_service.watch_for_stalls().await }
+                },
+            ));
+        }
+
+        if config.transfer.orphan_cancel_minutes.is_some() {
+            let websocket = websocket_service.clone();
+            services.spawn(supervisor::supervise(
+                "orphaned transfers",
+                config.supervisor.clone(),
+                shutdown.clone(),
+                websocket_service.clone(),
+                move || {
+                    let websocket = websocket.clone();
+                    async move { websocket.cancel_orphaned_transfers().await }
+                },
+            ));
+        }
+
+        {
+            let websocket = websocket_service.clone();
+            let scheduler = self.scheduler.clone();
+            let scheduler_shutdown = shutdown.clone();
+            services.spawn(supervisor::supervise(
+                "scheduler",
+                config.supervisor.clone(),
+                shutdown.clone(),
+                websocket_service.clone(),
+                move || {
+                    let websocket = websocket.clone();
+                    scheduler
+                        .clone()
+                        .run(scheduler_shutdown.clone(), move |job| websocket.clone().send_scheduled(job))
+                },
+            ));
+        }
+
+        {
+            let sync_service = self.sync.clone();
+            let sync_shutdown = shutdown.clone();
+            services.spawn(supervisor::supervise(
+                "sync",
+                config.supervisor.clone(),
+                shutdown.clone(),
+                websocket_service.clone(),
+                move || sync_service.clone().run(sync_shutdown.clone()),
+            ));
+        }
+
+        {
+            let webhooks = websocket_service.webhooks();
+            let webhooks_shutdown = shutdown.clone();
+            services.spawn(supervisor::supervise(
+                "webhooks",
+                config.supervisor.clone(),
+                shutdown.clone(),
+                websocket_service.clone(),
+                move || webhooks.clone().run(webhooks_shutdown.clone()),
+            ));
+        }
+
+        {
+            let notifier = websocket_service.notifier();
+            let notifier_shutdown = shutdown.clone();
+            services.spawn(supervisor::supervise(
+                "notifications",
+                config.supervisor.clone(),
+                shutdown.clone(),
+                websocket_service.clone(),
+                move || notifier.clone().run(notifier_shutdown.clone()),
+            ));
+        }
+
+        services
+    }
+}
+
+fn service_failure(result: Result<Result<()>, tokio::task::JoinError>) -> Option<anyhow::Error> {
+    match result {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e),
+        Err(e) => Some(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ClientMessage, ServerMessage};
+    use futures_util::{SinkExt, StreamExt};
+    use std::path::Path;
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[tokio::test]
+    async fn two_nodes_on_ephemeral_ports_share_a_file() {
+        let TwoNodes { dir, sender, receiver, running } = two_nodes().await;
+
+        let data = b"hello from an in-process node".repeat(1000);
+        let path = dir.join("greeting.txt");
+        std::fs::write(&path, &data).unwrap();
+        let url = format!("ws://127.0.0.1:{}/ws", receiver.websocket.wait_for_local_addr().await.port());
+        let (mut watching, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        assert!(send_over_client_api(&sender.websocket, receiver.id, &path).await);
+
+        let saved = dir.join("receiver").join("downloads").join("greeting.txt");
+        assert_eq!(std::fs::read(&saved).unwrap(), data);
+        // The receiving side's clients hear of it too
+        let (from_peer_id, saved_path, file_size) = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let Some(Ok(Message::Text(text))) = watching.next().await else { panic!("receiver closed") };
+                if let ServerMessage::FileReceived { from_peer_id, saved_path, file_size, .. } = serde_json::from_str(&text).unwrap() {
+                    break (from_peer_id, saved_path, file_size);
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(from_peer_id, Some(sender.id));
+        assert_eq!(std::path::PathBuf::from(saved_path), saved);
+        assert_eq!(file_size, data.len() as u64);
+
+        // And keep it in their history, as the sending side does its own
+        let history = ClientMessage::GetTransferHistory;
+        watching.send(Message::Text(serde_json::to_string(&history).unwrap())).await.unwrap();
+        let entries = loop {
+            let Some(Ok(Message::Text(text))) = watching.next().await else { panic!("receiver closed") };
+            if let ServerMessage::TransferHistory { transfers } = serde_json::from_str(&text).unwrap() {
+                break transfers;
+            }
+        };
+        let [received] = entries.as_slice() else { panic!("expected one entry, got {:?}", entries) };
+        assert_eq!((received.direction.as_str(), received.status.as_str()), ("received", "completed"));
+        assert_eq!(received.peer_id, Some(sender.id));
+        assert_eq!(received.file_size, data.len() as u64);
+        assert!(received.verified && received.duration_seconds.is_some());
+        running.finish().await;
+    }
+
+    #[tokio::test]
+    async fn benchmarks_measure_both_ways_and_are_kept_in_history() {
+        let TwoNodes { sender, receiver, running, .. } = two_nodes().await;
+
+        let url = format!("ws://127.0.0.1:{}/ws", sender.websocket.wait_for_local_addr().await.port());
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let request = ClientMessage::BenchmarkPeer {
+            peer_id: receiver.id,
+            duration_secs: Some(5),
+            payload_size: Some(256 * 1024),
+        };
+        client.send(Message::Text(serde_json::to_string(&request).unwrap())).await.unwrap();
+        let (benchmark_id, result) = tokio::time::timeout(Duration::from_secs(20), async {
+            loop {
+                let Some(Ok(Message::Text(text))) = client.next().await else { panic!("client closed") };
+                match serde_json::from_str(&text).unwrap() {
+                    ServerMessage::PeerBenchmark { benchmark_id, result } => break (benchmark_id, result),
+                    ServerMessage::Error { message } => panic!("benchmark failed: {}", message),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+        // The payload runs out long before the time does
+        assert_eq!(result.upload.bytes, 256 * 1024);
+        assert_eq!(result.download.bytes, 256 * 1024);
+        assert!(result.upload.chunks > 0 && result.download.bytes_per_sec > 0);
+
+        client.send(Message::Text(serde_json::to_string(&ClientMessage::GetTransferHistory).unwrap())).await.unwrap();
+        let entries = loop {
+            let Some(Ok(Message::Text(text))) = client.next().await else { panic!("client closed") };
+            if let ServerMessage::TransferHistory { transfers } = serde_json::from_str(&text).unwrap() {
+                break transfers;
+            }
+        };
+        let kept = entries.iter().find(|entry| entry.transfer_id == benchmark_id).unwrap();
+        assert_eq!(kept.direction, "benchmark");
+        assert_eq!(kept.benchmark, Some(result));
+        running.finish().await;
+    }
+
+    #[tokio::test]
+    async fn sends_outlive_their_client_and_can_be_watched_again() {
+        let TwoNodes { dir, sender, receiver, running } = two_nodes_with(|_, _, receiver| {
+            // Held at the receiver's prompt until the first client has gone
+            receiver.transfer.default_action = crate::config::AcceptAction::Prompt;
+        })
+        .await;
+
+        let path = dir.join("report.txt");
+        std::fs::write(&path, b"still going".repeat(1000)).unwrap();
+        let receiver_url = format!("ws://127.0.0.1:{}/ws", receiver.websocket.wait_for_local_addr().await.port());
+        let (mut approver, _) = tokio_tungstenite::connect_async(receiver_url).await.unwrap();
+        let url = format!("ws://127.0.0.1:{}/ws", sender.websocket.wait_for_local_addr().await.port());
+        let (mut starter, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let request = ClientMessage::SendFile {
+            peer_id: receiver.id,
+            file_path: path.to_string_lossy().to_string(),
+            relay_peer_id: None,
+            debug: false,
+        };
+        starter.send(Message::Text(serde_json::to_string(&request).unwrap())).await.unwrap();
+        let transfer_id = loop {
+            let Some(Ok(Message::Text(text))) = starter.next().await else { panic!("starter closed") };
+            if let ServerMessage::FileTransferRequest { transfer_id, .. } = serde_json::from_str(&text).unwrap() {
+                break transfer_id;
+            }
+        };
+        starter.close(None).await.unwrap();
+        drop(starter);
+
+        let (mut watcher, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        watcher.send(Message::Text(serde_json::to_string(&ClientMessage::GetActiveTransfers).unwrap())).await.unwrap();
+        watcher
+            .send(Message::Text(serde_json::to_string(&ClientMessage::WatchTransfer { transfer_id }).unwrap()))
+            .await
+            .unwrap();
+        let incoming = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let Some(Ok(Message::Text(text))) = approver.next().await else { panic!("approver closed") };
+                if let ServerMessage::IncomingTransferRequest { transfer_id, .. } = serde_json::from_str(&text).unwrap() {
+                    break transfer_id;
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        let (mut listed, mut snapshot) = (false, false);
+        while !(listed && snapshot) {
+            let Some(Ok(Message::Text(text))) = watcher.next().await else { panic!("watcher closed") };
+            match serde_json::from_str(&text).unwrap() {
+                ServerMessage::ActiveTransfers { transfers, .. } => {
+                    assert!(transfers.iter().any(|entry| entry.transfer_id == transfer_id));
+                    listed = true;
+                }
+                ServerMessage::TransferStats { transfer_id: id, status, .. } => {
+                    assert_eq!((id, status.as_str()), (transfer_id, "in_progress"));
+                    snapshot = true;
+                }
+                _ => {}
+            }
+        }
+
+        let accept = ClientMessage::RespondToTransfer {
+            transfer_id: incoming,
+            accept: true,
+        };
+        approver.send(Message::Text(serde_json::to_string(&accept).unwrap())).await.unwrap();
+        let verified = tokio::time::timeout(Duration::from_secs(20), async {
+            loop {
+                let Some(Ok(Message::Text(text))) = watcher.next().await else { panic!("watcher closed") };
+                match serde_json::from_str(&text).unwrap() {
+                    ServerMessage::FileTransferComplete { transfer_id: id, verified, .. } if id == transfer_id => {
+                        break verified
+                    }
+                    ServerMessage::FileTransferError { message, .. } => panic!("transfer failed: {}", message),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(verified);
+        running.finish().await;
+    }
+
+    #[tokio::test]
+    async fn a_send_reports_its_progress_to_the_client_that_started_it() {
+        let TwoNodes { dir, sender, receiver, running } = two_nodes().await;
+
+        let data: Vec<u8> = (0..4 * 1024 * 1024u32).map(|i| (i % 251) as u8).collect();
+        let path = dir.join("large.bin");
+        std::fs::write(&path, &data).unwrap();
+        let url = format!("ws://127.0.0.1:{}/ws", sender.websocket.wait_for_local_addr().await.port());
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let request = ClientMessage::SendFile {
+            peer_id: receiver.id,
+            file_path: path.to_string_lossy().to_string(),
+            relay_peer_id: None,
+            debug: false,
+        };
+        client.send(Message::Text(serde_json::to_string(&request).unwrap())).await.unwrap();
+        let reported = tokio::time::timeout(Duration::from_secs(20), async {
+            let mut reported = Vec::new();
+            loop {
+                let Some(Ok(Message::Text(text))) = client.next().await else { panic!("client closed") };
+                match serde_json::from_str(&text).unwrap() {
+                    ServerMessage::FileTransferProgress { progress, total, .. } => reported.push((progress, total)),
+                    ServerMessage::FileTransferComplete { .. } => break reported,
+                    ServerMessage::FileTransferError { message, .. } => panic!("transfer failed: {}", message),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        let size = data.len() as u64;
+        assert!(reported.iter().all(|&(_, total)| total == size));
+        assert!(reported.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(reported.last(), Some(&(size, size)));
+        running.finish().await;
+    }
+
+    #[tokio::test]
+    async fn received_files_land_in_the_configured_downloads_dir() {
+        let TwoNodes { dir, sender, receiver, running } = two_nodes_with(|dir, _, receiver| {
+            let inbox = dir.join("elsewhere").join("inbox");
+            receiver.transfer.downloads_dir = Some(inbox.to_string_lossy().to_string());
+        })
+        .await;
+        let inbox = dir.join("elsewhere").join("inbox");
+        // Made at startup, before anything arrives
+        assert!(inbox.is_dir());
+
+        let data = b"filed away from the working directory".repeat(100);
+        let path = dir.join("report.txt");
+        std::fs::write(&path, &data).unwrap();
+        assert!(send_over_client_api(&sender.websocket, receiver.id, &path).await);
+        assert_eq!(std::fs::read(inbox.join("report.txt")).unwrap(), data);
+        assert!(!dir.join("receiver").join("downloads").exists());
+
+        let url = format!("ws://127.0.0.1:{}/ws", receiver.websocket.wait_for_local_addr().await.port());
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        client.send(Message::Text(serde_json::to_string(&ClientMessage::GetLocalInfo).unwrap())).await.unwrap();
+        let downloads_dir = loop {
+            let Some(Ok(Message::Text(text))) = client.next().await else { panic!("client closed") };
+            if let Ok(ServerMessage::LocalInfo { downloads_dir, .. }) = serde_json::from_str(&text) {
+                break downloads_dir;
+            }
+        };
+        assert_eq!(std::path::PathBuf::from(downloads_dir), inbox);
+        running.finish().await;
+    }
+
+    #[test]
+    fn a_downloads_dir_that_cant_be_made_stops_startup() {
+        let dir = std::env::temp_dir().join(format!("p2p-sharing-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let blocker = dir.join("not-a-folder");
+        std::fs::write(&blocker, b"").unwrap();
+        let mut config = AppConfig::default();
+        config.transfer.downloads_dir = Some(blocker.join("downloads").to_string_lossy().to_string());
+
+        let Err(e) = Node::builder(Arc::new(config)).data_dir(dir.join("node")).build() else {
+            panic!("started without anywhere to save files");
+        };
+        assert!(e.to_string().starts_with(&format!("Can't save received files in {}", blocker.join("downloads").display())));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn files_sent_together_arrive_as_one_zip() {
+        let TwoNodes { dir, sender, receiver, running } = two_nodes_with(|_, sender, _| {
+            sender.transfer.archive_compression = crate::config::ArchiveCompression::Deflate;
+        })
+        .await;
+
+        let files = [("notes.txt", b"meeting notes ".repeat(5000)), ("data.bin", (0..200_000u32).map(|i| i as u8).collect())];
+        for (name, data) in &files {
+            std::fs::write(dir.join(name), data).unwrap();
+        }
+        let url = format!("ws://127.0.0.1:{}/ws", sender.websocket.wait_for_local_addr().await.port());
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let send_archive = |file_paths: Vec<String>| {
+            let request = ClientMessage::SendArchive {
+                peer_id: receiver.id,
+                file_paths,
+                archive_name: "bundle".to_string(),
+            };
+            Message::Text(serde_json::to_string(&request).unwrap())
+        };
+
+        // A file that isn't there is named before anything is sent
+        let missing = dir.join("missing.txt").to_string_lossy().to_string();
+        client.send(send_archive(vec![missing.clone()])).await.unwrap();
+        let Some(Ok(Message::Text(text))) = client.next().await else { panic!("client closed") };
+        let ServerMessage::Error { message } = serde_json::from_str(&text).unwrap() else { panic!("expected an error") };
+        assert!(message.contains(&missing));
+
+        let paths = files.iter().map(|(name, _)| dir.join(name).to_string_lossy().to_string()).collect();
+        client.send(send_archive(paths)).await.unwrap();
+        let (transfer_id, bytes) = tokio::time::timeout(Duration::from_secs(20), async {
+            loop {
+                let Some(Ok(Message::Text(text))) = client.next().await else { panic!("client closed") };
+                match serde_json::from_str(&text).unwrap() {
+                    ServerMessage::FileTransferComplete {
+                        transfer_id,
+                        verified,
+                        bytes_transferred,
+                        ..
+                    } => {
+                        assert!(verified);
+                        break (transfer_id, bytes_transferred.unwrap());
+                    }
+                    ServerMessage::FileTransferError { message, .. } => panic!("transfer failed: {}", message),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        let saved = dir.join("receiver").join("downloads").join("bundle.zip");
+        assert_eq!(std::fs::metadata(&saved).unwrap().len(), bytes);
+        let mut archive = zip::ZipArchive::new(std::fs::File::open(&saved).unwrap()).unwrap();
+        for (name, data) in &files {
+            let mut entry = archive.by_name(name).unwrap();
+            let mut content = Vec::new();
+            std::io::Read::read_to_end(&mut entry, &mut content).unwrap();
+            assert_eq!(&content, data);
+        }
+        client.send(Message::Text(serde_json::to_string(&ClientMessage::GetTransferHistory).unwrap())).await.unwrap();
+        let entries = loop {
+            let Some(Ok(Message::Text(text))) = client.next().await else { panic!("client closed") };
+            if let ServerMessage::TransferHistory { transfers } = serde_json::from_str(&text).unwrap() {
+                break transfers;
+            }
+        };
+        let sent = entries.iter().find(|entry| entry.transfer_id == transfer_id).unwrap();
+        assert_eq!((sent.file_size, sent.filename.as_str()), (bytes, "bundle.zip"));
+        running.finish().await;
+    }
+
+    #[tokio::test]
+    async fn files_sent_as_a_session_arrive_one_by_one_under_one_record() {
+        let TwoNodes { dir, sender, receiver, running } = two_nodes().await;
+        let websockets = [sender.websocket, receiver.websocket];
+
+        let files = [("one.txt", b"first".to_vec()), ("two.txt", b"second".to_vec()), ("three.bin", vec![3u8; 100_000])];
+        for (name, data) in &files {
+            std::fs::write(dir.join(name), data).unwrap();
+        }
+        let mut clients = Vec::new();
+        for websocket in &websockets {
+            let url = format!("ws://127.0.0.1:{}/ws", websocket.wait_for_local_addr().await.port());
+            clients.push(tokio_tungstenite::connect_async(url).await.unwrap().0);
+        }
+        let file_paths = files.iter().map(|(name, _)| dir.join(name).to_string_lossy().to_string()).collect();
+        let request = ClientMessage::SendFiles {
+            peer_id: receiver.id,
+            file_paths,
+        };
+        clients[0].send(Message::Text(serde_json::to_string(&request).unwrap())).await.unwrap();
+        let session_id = tokio::time::timeout(Duration::from_secs(20), async {
+            let mut started = None;
+            loop {
+                let Some(Ok(Message::Text(text))) = clients[0].next().await else { panic!("client closed") };
+                match serde_json::from_str(&text).unwrap() {
+                    ServerMessage::SessionTransferStart { session_id, files, .. } => {
+                        assert_eq!(files.len(), 3);
+                        started = Some(session_id);
+                    }
+                    ServerMessage::SessionTransferComplete {
+                        session_id,
+                        successful_files,
+                        failed_files,
+                    } => {
+                        assert_eq!(Some(session_id), started);
+                        assert_eq!((successful_files, failed_files), (3, 0));
+                        break session_id;
+                    }
+                    ServerMessage::FileTransferError { message, .. } => panic!("transfer failed: {}", message),
+                    ServerMessage::Error { message } => panic!("{}", message),
+                    _ => {}
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        // The receiver finishes its record of the session on its own time
+        loop {
+            let Some(Ok(Message::Text(text))) = clients[1].next().await else { panic!("client closed") };
+            if let Ok(ServerMessage::SessionTransferComplete { successful_files, .. }) = serde_json::from_str(&text) {
+                assert_eq!(successful_files, 3);
+                break;
+            }
+        }
+        for (name, data) in &files {
+            assert_eq!(&std::fs::read(dir.join("receiver").join("downloads").join(name)).unwrap(), data);
+        }
+        // Each side keeps one record of the session and one of each file
+        for client in &mut clients {
+            client.send(Message::Text(serde_json::to_string(&ClientMessage::GetTransferHistory).unwrap())).await.unwrap();
+            let entries = loop {
+                let Some(Ok(Message::Text(text))) = client.next().await else { panic!("client closed") };
+                if let Ok(ServerMessage::TransferHistory { transfers }) = serde_json::from_str(&text) {
+                    break transfers;
+                }
+            };
+            let (sessions, children): (Vec<_>, Vec<_>) = entries.iter().partition(|entry| entry.session_files.is_some());
+            let [session] = &sessions[..] else { panic!("expected one session, got {:?}", sessions) };
+            assert_eq!(session.status, "completed");
+            assert_eq!(session.file_size, files.iter().map(|(_, data)| data.len() as u64).sum::<u64>());
+            assert_eq!(children.len(), 3);
+            assert!(children.iter().all(|child| child.status == "completed"));
+            assert!(children.iter().all(|child| child.session_id == Some(session.transfer_id)));
+            if session.direction == "sent" {
+                assert_eq!(session.transfer_id, session_id);
+            }
+        }
+        running.finish().await;
+    }
+
+    #[tokio::test]
+    async fn instances_on_one_machine_discover_each_other_over_loopback() {
+        let dir = std::env::temp_dir().join(format!("p2p-sharing-test-{}", uuid::Uuid::new_v4()));
+        let mut config = ephemeral_config();
+        config.network.broadcast_interval = 1;
+        let first = Node::builder(Arc::new(config.clone())).data_dir(dir.join("first")).build().unwrap();
+        let (first_peers, first_websocket) = (first.peers().clone(), first.websocket().clone());
+        let stop = CancellationToken::new();
+        let run = |node: Node| {
+            let stop = stop.clone();
+            tokio::spawn(node.run(async move { stop.cancelled().await }))
+        };
+        let first = run(first);
+
+        // Only the second is told where the first listens; the first learns
+        // the way back from its announcements
+        let first_discovery = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                if let Some(addr) = first_websocket.discovery_addr() {
+                    return addr;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+        config.network.loopback_peers = vec![first_discovery.port()];
+        let second = Node::builder(Arc::new(config)).data_dir(dir.join("second")).build().unwrap();
+        let second_peers = second.peers().clone();
+        let second = run(second);
+
+        let first_id = first_peers.read().await.local_id();
+        let second_id = second_peers.read().await.local_id();
+        tokio::time::timeout(Duration::from_secs(20), async {
+            loop {
+                let first_knows = first_peers.read().await.get_peer(&second_id).is_some();
+                let second_knows = second_peers.read().await.get_peer(&first_id).is_some();
+                if first_knows && second_knows {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let data = b"found over loopback".repeat(1000);
+        let path = dir.join("greeting.txt");
+        std::fs::write(&path, &data).unwrap();
+        assert!(send_over_client_api(&first_websocket, second_id, &path).await);
+
+        assert_eq!(std::fs::read(dir.join("second").join("downloads").join("greeting.txt")).unwrap(), data);
+        stop.cancel();
+        for node in [first, second] {
+            node.await.unwrap().unwrap();
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn clients_are_listed_by_registered_name_until_they_leave() {
+        let dir = std::env::temp_dir().join(format!("p2p-sharing-test-{}", uuid::Uuid::new_v4()));
+        let config = ephemeral_config();
+        let node = Node::builder(Arc::new(config)).data_dir(&dir).build().unwrap();
+        let websocket = node.websocket().clone();
+        let transfers = node.transfers().clone();
+        let stop = CancellationToken::new();
+        let running = {
+            let stop = stop.clone();
+            tokio::spawn(node.run(async move { stop.cancelled().await }))
+        };
+
+        let url = format!("ws://127.0.0.1:{}/ws", websocket.wait_for_local_addr().await.port());
+        let (mut desktop, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let (mut dashboard, _) = tokio_tungstenite::connect_async(&url).await.unwrap();
+        let register = ClientMessage::RegisterClient {
+            name: "desktop".to_string(),
+            kind: Some("app".to_string()),
+        };
+        desktop.send(Message::Text(serde_json::to_string(&register).unwrap())).await.unwrap();
+        let desktop_id = loop {
+            let Some(Ok(Message::Text(text))) = desktop.next().await else { panic!("desktop closed") };
+            if let ServerMessage::ClientRegistered { client } = serde_json::from_str(&text).unwrap() {
+                assert!(client.registered);
+                break client.client_id;
+            }
+        };
+
+        dashboard.send(Message::Text(serde_json::to_string(&ClientMessage::GetClients).unwrap())).await.unwrap();
+        let clients = loop {
+            let Some(Ok(Message::Text(text))) = dashboard.next().await else { panic!("dashboard closed") };
+            if let ServerMessage::Clients { clients } = serde_json::from_str(&text).unwrap() {
+                break clients;
+            }
+        };
+        assert_eq!(clients.len(), 2);
+        let desktop_info = clients.iter().find(|client| client.client_id == desktop_id).unwrap();
+        assert_eq!(desktop_info.name, "desktop");
+        assert_eq!(desktop_info.kind.as_deref(), Some("app"));
+        assert!(clients.iter().any(|client| !client.registered && client.name.starts_with("client-")));
+
+        dashboard.send(Message::Text(serde_json::to_string(&ClientMessage::GetLocalInfo).unwrap())).await.unwrap();
+        loop {
+            let Some(Ok(Message::Text(text))) = dashboard.next().await else { panic!("dashboard closed") };
+            if let ServerMessage::LocalInfo { transfer_port, web_port, version, services, .. } = serde_json::from_str(&text).unwrap() {
+                assert_eq!(transfer_port, Some(transfers.wait_for_local_addr().await.port()));
+                assert_eq!(web_port, Some(websocket.wait_for_local_addr().await.port()));
+                assert_eq!(version, env!("CARGO_PKG_VERSION"));
+                assert_eq!(services.get("transfer").map(String::as_str), Some("running"));
+                break;
+            }
+        }
+
+        dashboard.send(Message::Text(serde_json::to_string(&ClientMessage::GetSystemInfo).unwrap())).await.unwrap();
+        loop {
+            let Some(Ok(Message::Text(text))) = dashboard.next().await else { panic!("dashboard closed") };
+            if let ServerMessage::SystemInfo { info } = serde_json::from_str(&text).unwrap() {
+                assert_eq!(info.websocket_clients, 2);
+                assert_eq!(info.os, std::env::consts::OS);
+                assert_eq!(info.config.web_port, websocket.wait_for_local_addr().await.port());
+                assert_eq!(info.services["transfer"].status, "running");
+                break;
+            }
+        }
+
+        desktop.close(None).await.unwrap();
+        let (client_id, name) = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let Some(Ok(Message::Text(text))) = dashboard.next().await else { panic!("dashboard closed") };
+                if let ServerMessage::ClientDisconnected { client_id, name } = serde_json::from_str(&text).unwrap() {
+                    break (client_id, name);
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!((client_id, name.as_str()), (desktop_id, "desktop"));
+
+        stop.cancel();
+        running.await.unwrap().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn acting_on_a_transfer_that_isnt_running_is_an_error() {
+        let dir = std::env::temp_dir().join(format!("p2p-sharing-test-{}", uuid::Uuid::new_v4()));
+        let config = ephemeral_config();
+        let node = Node::builder(Arc::new(config)).data_dir(dir.clone()).build().unwrap();
+        let websocket = node.websocket().clone();
+        let stop = CancellationToken::new();
+        let running = {
+            let stop = stop.clone();
+            tokio::spawn(node.run(async move { stop.cancelled().await }))
+        };
+
+        let url = format!("ws://127.0.0.1:{}/ws", websocket.wait_for_local_addr().await.port());
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let transfer_id = uuid::Uuid::new_v4();
+        let requests = [
+            ClientMessage::CancelTransfer { transfer_id },
+            ClientMessage::PauseTransfer { transfer_id },
+            ClientMessage::ResumeTransfer { transfer_id },
+        ];
+        for request in requests {
+            client.send(Message::Text(serde_json::to_string(&request).unwrap())).await.unwrap();
+            let message = tokio::time::timeout(Duration::from_secs(5), async {
+                loop {
+                    let Some(Ok(Message::Text(text))) = client.next().await else { panic!("client closed") };
+                    match serde_json::from_str(&text).unwrap() {
+                        ServerMessage::Error { message } => break message,
+                        ServerMessage::TransferCancelled { .. }
+                        | ServerMessage::TransferPaused { .. }
+                        | ServerMessage::TransferResumed { .. } => panic!("acted on a transfer that never ran"),
+                        _ => {}
+                    }
+                }
+            })
+            .await
+            .unwrap();
+            assert_eq!(message, format!("No transfer {}", transfer_id));
+        }
+        stop.cancel();
+        running.await.unwrap().unwrap();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Sends `path` to `peer_id` the way a client would, returning whether
+    /// the receiver verified it.
+    async fn send_over_client_api(websocket: &WebSocketService, peer_id: uuid::Uuid, path: &std::path::Path) -> bool {
+        let url = format!("ws://127.0.0.1:{}/ws", websocket.wait_for_local_addr().await.port());
+        let (mut client, _) = tokio_tungstenite::connect_async(url).await.unwrap();
+        let request = ClientMessage::SendFile {
+            peer_id,
+            file_path: path.to_string_lossy().to_string(),
+            relay_peer_id: None,
+            debug: false,
+        };
+        client.send(Message::Text(serde_json::to_string(&request).unwrap())).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(20), async {
+            while let Some(Ok(Message::Text(text))) = client.next().await {
+                match serde_json::from_str(&text).unwrap() {
+                    ServerMessage::FileTransferComplete { verified, .. } => return verified,
+                    ServerMessage::FileTransferError { message, .. } => panic!("transfer failed: {}", message),
+                    _ => {}
+                }
+            }
+            panic!("client closed before the transfer finished");
+        })
+        .await
+        .unwrap()
+    }
+
+    /// A sender and a receiver on picked ports under one scratch dir.
+    struct TwoNodes {
+        dir: PathBuf,
+        sender: Side,
+        receiver: Side,
+        running: Running,
+    }
+
+    struct Side {
+        id: uuid::Uuid,
+        transfers: Arc<TransferService>,
+        websocket: Arc<WebSocketService>,
+    }
+
+    impl Side {
+        async fn of(node: &Node) -> Self {
+            Side {
+                id: node.peers().read().await.local_id(),
+                transfers: node.transfers().clone(),
+                websocket: node.websocket().clone(),
+            }
+        }
+    }
+
+    /// Stops the nodes and clears their dir when dropped, so a failing test
+    /// doesn't leave them behind; `finish` also waits for them to shut down
+    /// cleanly.
+    struct Running {
+        stop: CancellationToken,
+        nodes: Vec<tokio::task::JoinHandle<Result<()>>>,
+        dir: PathBuf,
+    }
+
+    impl Running {
+        async fn finish(mut self) {
+            self.stop.cancel();
+            for node in std::mem::take(&mut self.nodes) {
+                node.await.unwrap().unwrap();
+            }
+        }
+    }
+
+    impl Drop for Running {
+        fn drop(&mut self) {
+            self.stop.cancel();
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn ephemeral_config() -> AppConfig {
+        let mut config = AppConfig::default();
+        config.network.discovery_port = 0;
+        config.network.transfer_port = 0;
+        config.network.web_port = 0;
+        config
+    }
+
+    async fn two_nodes() -> TwoNodes {
+        two_nodes_with(|_, _, _| {}).await
+    }
+
+    /// Like `two_nodes`, letting `configure` change the sender's and the
+    /// receiver's config before they're built.
+    async fn two_nodes_with(configure: impl FnOnce(&Path, &mut AppConfig, &mut AppConfig)) -> TwoNodes {
+        let dir = std::env::temp_dir().join(format!("p2p-sharing-test-{}", uuid::Uuid::new_v4()));
+        let mut running = Running {
+            stop: CancellationToken::new(),
+            nodes: Vec::new(),
+            dir: dir.clone(),
+        };
+        let (mut sender_config, mut receiver_config) = (ephemeral_config(), ephemeral_config());
+        configure(&dir, &mut sender_config, &mut receiver_config);
+        let sender = Node::builder(Arc::new(sender_config)).data_dir(dir.join("sender")).build().unwrap();
+        let receiver = Node::builder(Arc::new(receiver_config)).data_dir(dir.join("receiver")).build().unwrap();
+        let (sender_side, receiver_side) = (Side::of(&sender).await, Side::of(&receiver).await);
+        for node in [sender, receiver] {
+            let stop = running.stop.clone();
+            running.nodes.push(tokio::spawn(node.run(async move { stop.cancelled().await })));
+        }
+
+        // Discovery can't find nodes on picked ports, so they're introduced by hand
+        let to_sender = format!("127.0.0.1:{}", sender_side.transfers.wait_for_local_addr().await.port());
+        let to_receiver = format!("127.0.0.1:{}", receiver_side.transfers.wait_for_local_addr().await.port());
+        sender_side.transfers.add_peer(&to_receiver).await.unwrap();
+        receiver_side.transfers.add_peer(&to_sender).await.unwrap();
+        TwoNodes {
+            dir,
+            sender: sender_side,
+            receiver: receiver_side,
+            running,
+        }
+    }
+}
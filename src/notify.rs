@@ -0,0 +1,404 @@
+use crate::config::{NotificationMode, UiConfig};
+use crate::utils;
+use anyhow::{bail, Result};
+use std::collections::BTreeSet;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::time::{timeout, timeout_at, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Notifications waiting to be shown; more are dropped.
+const QUEUE_LEN: usize = 256;
+/// Notifications arriving this soon after the first are shown together.
+const COALESCE_WINDOW: Duration = Duration::from_secs(2);
+/// Least time between two popups.
+const MIN_GAP: Duration = Duration::from_secs(5);
+/// Longest a notification command may run before it's killed.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+/// Longest a placeholder value may be, in characters.
+const MAX_VALUE_LEN: usize = 200;
+/// Who native notifications are from.
+const APP_NAME: &str = "p2p-sharing";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationStatus {
+    Received,
+    Failed,
+}
+
+impl NotificationStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            NotificationStatus::Received => "received",
+            NotificationStatus::Failed => "failed",
+        }
+    }
+}
+
+/// Something worth a desktop notification.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub hostname: String,
+    pub filename: String,
+    pub size: u64,
+    pub status: NotificationStatus,
+}
+
+impl Notification {
+    /// One notification standing for all of `batch`, which share a status.
+    fn summarize(batch: &[Notification]) -> Notification {
+        if let [single] = batch {
+            return single.clone();
+        }
+        let hosts: BTreeSet<&str> = batch.iter().map(|n| n.hostname.as_str()).collect();
+        let hostname = match hosts.iter().next() {
+            Some(host) if hosts.len() == 1 => host.to_string(),
+            _ => format!("{} peers", hosts.len()),
+        };
+        Notification {
+            hostname,
+            filename: format!("{} files", batch.len()),
+            size: batch.iter().map(|n| n.size).sum(),
+            status: batch[0].status,
+        }
+    }
+
+    /// The value for `{name}`, cleaned up to be passed as (part of) an argument.
+    fn placeholder(&self, name: &str) -> Option<String> {
+        match name {
+            "hostname" => Some(sanitize(&self.hostname)),
+            "filename" => Some(sanitize(&self.filename)),
+            "size" => Some(utils::format_bytes(self.size)),
+            "status" => Some(self.status.as_str().to_string()),
+            _ => None,
+        }
+    }
+
+    fn title(&self) -> String {
+        match self.status {
+            NotificationStatus::Received => {
+                format!("{} sent you {}", sanitize(&self.hostname), sanitize(&self.filename))
+            }
+            NotificationStatus::Failed => "Transfer failed".to_string(),
+        }
+    }
+
+    fn body(&self) -> String {
+        match self.status {
+            NotificationStatus::Received => utils::format_bytes(self.size),
+            NotificationStatus::Failed => format!("{} with {}", sanitize(&self.filename), sanitize(&self.hostname)),
+        }
+    }
+}
+
+/// Drops control characters and leading dashes, so a peer-chosen name
+/// can't pass as an option or break the line it's shown on, and caps the
+/// length.
+fn sanitize(value: &str) -> String {
+    let value: String = value.chars().filter(|c| !c.is_control()).take(MAX_VALUE_LEN).collect();
+    value.trim().trim_start_matches('-').trim_start().to_string()
+}
+
+/// Splits a command template into arguments the way a shell would for
+/// plain words, single and double quotes, and backslash escapes.
+fn split_command(template: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    args.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => current.push(c),
+                        None => bail!("Unterminated ' in notify_command"),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => current.push(c),
+                            Some(c) => {
+                                current.push('\\');
+                                current.push(c);
+                            }
+                            None => bail!("Unterminated \" in notify_command"),
+                        },
+                        Some(c) => current.push(c),
+                        None => bail!("Unterminated \" in notify_command"),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                current.extend(chars.next());
+            }
+            c => {
+                in_word = true;
+                current.push(c);
+            }
+        }
+    }
+    if in_word {
+        args.push(current);
+    }
+    Ok(args)
+}
+
+/// `arg` with each known `{placeholder}` replaced. Values are put in as
+/// they are, so one can't bring in another placeholder.
+fn fill(arg: &str, notification: &Notification) -> String {
+    let mut filled = String::new();
+    let mut rest = arg;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let from_brace = &rest[start..];
+        let value = from_brace
+            .find('}')
+            .and_then(|end| Some((end, notification.placeholder(&from_brace[1..end])?)));
+        match value {
+            Some((end, value)) => {
+                filled.push_str(&value);
+                rest = &from_brace[end + 1..];
+            }
+            None => {
+                filled.push('{');
+                rest = &from_brace[1..];
+            }
+        }
+    }
+    filled.push_str(rest);
+    filled
+}
+
+enum Mode {
+    Off,
+    Command(Vec<String>),
+    Native,
+}
+
+/// Shows desktop notifications off the transfer path. Bursts are
+/// coalesced into one summary per status and popups are spaced out, so
+/// a folder of 500 files is one notification, not 500.
+pub struct Notifier {
+    mode: Mode,
+    queue: mpsc::Sender<Notification>,
+    receiver: tokio::sync::Mutex<mpsc::Receiver<Notification>>,
+    window: Duration,
+    gap: Duration,
+}
+
+impl Notifier {
+    pub fn new(ui: &UiConfig) -> Self {
+        let mode = match ui.notifications {
+            NotificationMode::Off => Mode::Off,
+            NotificationMode::Command => match ui.notify_command.as_deref().map(split_command) {
+                Some(Ok(argv)) if !argv.is_empty() => Mode::Command(argv),
+                Some(Err(e)) => {
+                    tracing::warn!("Notifications are off: {}", e);
+                    Mode::Off
+                }
+                _ => {
+                    tracing::warn!("Notifications are off: ui.notify_command isn't set");
+                    Mode::Off
+                }
+            },
+            NotificationMode::Native => Mode::Native,
+        };
+        let (queue, receiver) = mpsc::channel(QUEUE_LEN);
+        Self {
+            mode,
+            queue,
+            receiver: tokio::sync::Mutex::new(receiver),
+            window: COALESCE_WINDOW,
+            gap: MIN_GAP,
+        }
+    }
+
+    /// Queues `notification` without waiting.
+    pub fn notify(&self, notification: Notification) {
+        if matches!(self.mode, Mode::Off) {
+            return;
+        }
+        if self.queue.try_send(notification).is_err() {
+            tracing::debug!("Notification queue is full, dropping one");
+        }
+    }
+
+    /// Shows queued notifications until shutdown.
+    pub async fn run(self: Arc<Self>, shutdown: CancellationToken) -> Result<()> {
+        if matches!(self.mode, Mode::Off) {
+            return Ok(());
+        }
+        let mut queue = self.receiver.lock().await;
+        let mut next_popup = Instant::now();
+        loop {
+            let first = tokio::select! {
+                first = queue.recv() => match first {
+                    Some(first) => first,
+                    None => return Ok(()),
+                },
+                _ = shutdown.cancelled() => return Ok(()),
+            };
+            let mut batch = vec![first];
+            let until = (Instant::now() + self.window).max(next_popup);
+            loop {
+                tokio::select! {
+                    next = timeout_at(until, queue.recv()) => match next {
+                        Ok(Some(next)) => batch.push(next),
+                        _ => break,
+                    },
+                    _ = shutdown.cancelled() => return Ok(()),
+                }
+            }
+
+            let (received, failed): (Vec<_>, Vec<_>) = batch
+                .into_iter()
+                .partition(|n| n.status == NotificationStatus::Received);
+            for group in [received, failed] {
+                if !group.is_empty() {
+                    self.show(&Notification::summarize(&group));
+                }
+            }
+            next_popup = Instant::now() + self.gap;
+        }
+    }
+
+    fn show(&self, notification: &Notification) {
+        match &self.mode {
+            Mode::Off => {}
+            Mode::Command(template) => Self::run_command(template.iter().map(|arg| fill(arg, notification)).collect()),
+            Mode::Native => {
+                let (title, body) = (notification.title(), notification.body());
+                // Talking to the platform's notification service may block
+                tokio::task::spawn_blocking(move || {
+                    let shown = notify_rust::Notification::new()
+                        .appname(APP_NAME)
+                        .summary(&title)
+                        .body(&body)
+                        .show();
+                    if let Err(e) = shown {
+                        tracing::warn!("Failed to show a desktop notification: {}", e);
+                    }
+                });
+            }
+        }
+    }
+
+    fn run_command(argv: Vec<String>) {
+        let mut command = Command::new(&argv[0]);
+        command
+            .args(&argv[1..])
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true);
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                tracing::warn!("Failed to run notification command {}: {}", argv[0], e);
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            match timeout(COMMAND_TIMEOUT, child.wait()).await {
+                Ok(Ok(status)) if !status.success() => {
+                    tracing::warn!("Notification command {} exited with {}", argv[0], status)
+                }
+                Ok(Err(e)) => tracing::warn!("Notification command {} failed: {}", argv[0], e),
+                Err(_) => tracing::warn!("Notification command {} timed out, killed it", argv[0]),
+                Ok(Ok(_)) => {}
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn received(hostname: &str, filename: &str, size: u64) -> Notification {
+        Notification {
+            hostname: hostname.to_string(),
+            filename: filename.to_string(),
+            size,
+            status: NotificationStatus::Received,
+        }
+    }
+
+    #[test]
+    fn templates_split_like_a_shell_would() {
+        assert_eq!(
+            split_command(r#"notify-send 'P2P: {hostname}' "sent \"{filename}\"" a\ b"#).unwrap(),
+            vec!["notify-send", "P2P: {hostname}", "sent \"{filename}\"", "a b"]
+        );
+        assert!(split_command("say 'unfinished").is_err());
+    }
+
+    #[test]
+    fn placeholders_are_filled_with_cleaned_values() {
+        let notification = received("office-nas", "--rm {size}\nreport.pdf", 2048);
+        assert_eq!(
+            fill("{hostname} sent {filename} ({size}, {status}) {other}", &notification),
+            "office-nas sent rm {size}report.pdf (2.00 KB, received) {other}"
+        );
+    }
+
+    #[test]
+    fn bursts_are_summarized() {
+        let batch = vec![received("office-nas", "a.pdf", 100), received("office-nas", "b.pdf", 50)];
+        let summary = Notification::summarize(&batch);
+        assert_eq!((summary.hostname.as_str(), summary.filename.as_str(), summary.size), ("office-nas", "2 files", 150));
+        let mixed = vec![received("office-nas", "a.pdf", 1), received("laptop", "b.pdf", 1)];
+        assert_eq!(Notification::summarize(&mixed).hostname, "2 peers");
+        assert_eq!(Notification::summarize(&batch[..1]).filename, "a.pdf");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn a_directory_of_files_is_one_notification() {
+        let dir = std::env::temp_dir().join(format!("p2p-notify-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("shown");
+        let template = format!("sh -c 'printf \"%s\\n\" \"$0\" >> {}' '{{hostname}}: {{filename}}'", log.display());
+        let mut notifier = Notifier::new(&UiConfig {
+            theme: "dark".to_string(),
+            notifications: NotificationMode::Command,
+            notify_command: Some(template),
+        });
+        notifier.window = Duration::from_millis(200);
+        let notifier = Arc::new(notifier);
+        for i in 0..50 {
+            notifier.notify(received("office-nas", &format!("photo-{}.jpg", i), 10));
+        }
+
+        let shutdown = CancellationToken::new();
+        let running = tokio::spawn(notifier.clone().run(shutdown.clone()));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !log.exists() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        // Give a stray second popup the chance to show up
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        shutdown.cancel();
+        running.await.unwrap().unwrap();
+
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "office-nas: 50 files\n");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
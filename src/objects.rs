@@ -0,0 +1,72 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Folder in downloads holding one copy of each received file's content,
+/// named by its SHA-256, when `transfer.content_addressed` is on.
+pub const OBJECTS_DIR: &str = ".objects";
+
+/// Where the content with SHA-256 `checksum` is kept under `downloads_dir`.
+pub fn object_path(downloads_dir: &Path, checksum: &str) -> PathBuf {
+    downloads_dir.join(OBJECTS_DIR).join(checksum)
+}
+
+/// Stores the finished download at `part_path`, whose SHA-256 is
+/// `checksum`, as an object and puts `file_path` in place as a hard link to
+/// it, or as a copy where the filesystem has no hard links. Returns whether
+/// the content was stored already, in which case `part_path` is dropped
+/// and the file takes no more room.
+pub async fn store(downloads_dir: &Path, part_path: &Path, checksum: &str, file_path: &Path) -> io::Result<bool> {
+    let object = object_path(downloads_dir, checksum);
+    tokio::fs::create_dir_all(object.parent().unwrap_or(downloads_dir)).await?;
+
+    // Every step leaves the content linked from somewhere, so a sweep in
+    // between never sees the object unreferenced; one collected meanwhile
+    // is simply stored again
+    if tokio::fs::try_exists(&object).await? && place(&object, file_path).await.is_ok() {
+        tokio::fs::remove_file(part_path).await?;
+        return Ok(true);
+    }
+    place(part_path, file_path).await?;
+    tokio::fs::rename(part_path, &object).await?;
+    Ok(false)
+}
+
+/// Puts `content` at `file_path`, over whatever is there as a plain rename
+/// would. It goes under a name of its own first, so `file_path` is never
+/// free for another receive to take in between.
+async fn place(content: &Path, file_path: &Path) -> io::Result<()> {
+    let name = file_path.file_name().unwrap_or_default().to_string_lossy();
+    let staged = file_path.with_file_name(format!(".{}.{}.link", name, uuid::Uuid::new_v4().simple()));
+    match tokio::fs::hard_link(content, &staged).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Err(e),
+        Err(e) => {
+            tracing::debug!("Couldn't link {} to its content, copying: {}", file_path.display(), e);
+            if let Err(e) = tokio::fs::copy(content, &staged).await {
+                let _ = tokio::fs::remove_file(&staged).await;
+                return Err(e);
+            }
+        }
+    }
+    let placed = tokio::fs::rename(&staged, file_path).await;
+    if placed.is_err() {
+        let _ = tokio::fs::remove_file(&staged).await;
+    }
+    placed
+}
+
+/// Whether a stored object is no longer any download's content: nothing
+/// but the object itself links to it. Link counts can't be read on every
+/// platform; there objects are kept.
+pub fn unreferenced(metadata: &std::fs::Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        metadata.nlink() == 1
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        false
+    }
+}
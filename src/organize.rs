@@ -0,0 +1,98 @@
+use crate::config::TransferConfig;
+use crate::utils;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+/// Where received files of each kind go under `transfer.organize_by_type`,
+/// by MIME type or "type/*" family. Anything not listed goes in OTHER.
+const FOLDERS: &[(&str, &str)] = &[
+    ("image/*", "Images"),
+    ("video/*", "Video"),
+    ("text/*", "Documents"),
+    ("application/pdf", "Documents"),
+    ("application/rtf", "Documents"),
+    ("application/msword", "Documents"),
+    ("application/vnd.ms-excel", "Documents"),
+    ("application/vnd.ms-powerpoint", "Documents"),
+    ("application/vnd.openxmlformats-officedocument.wordprocessingml.document", "Documents"),
+    ("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet", "Documents"),
+    ("application/vnd.openxmlformats-officedocument.presentationml.presentation", "Documents"),
+    ("application/vnd.oasis.opendocument.text", "Documents"),
+    ("application/vnd.oasis.opendocument.spreadsheet", "Documents"),
+    ("application/vnd.oasis.opendocument.presentation", "Documents"),
+    ("application/epub+zip", "Documents"),
+    ("application/zip", "Archives"),
+    ("application/gzip", "Archives"),
+    ("application/x-tar", "Archives"),
+    ("application/x-bzip2", "Archives"),
+    ("application/x-xz", "Archives"),
+    ("application/zstd", "Archives"),
+    ("application/x-7z-compressed", "Archives"),
+    ("application/vnd.rar", "Archives"),
+    ("application/x-rar-compressed", "Archives"),
+    ("application/x-iso9660-image", "Archives"),
+    ("application/x-apple-diskimage", "Archives"),
+];
+const OTHER: &str = "Other";
+
+/// The folder under downloads a file of `mime_type` goes in: the first of
+/// `transfer.type_folders` that matches, exactly before by family, then the
+/// built-in ones.
+pub fn folder_for(config: &TransferConfig, mime_type: Option<&str>) -> String {
+    let Some(mime_type) = mime_type else {
+        return OTHER.to_string();
+    };
+    let configured = |exact: bool| {
+        config
+            .type_folders
+            .iter()
+            .filter(|(pattern, _)| pattern.ends_with("/*") != exact)
+            .find(|(pattern, _)| utils::mime_matches(mime_type, std::slice::from_ref(*pattern)))
+            .map(|(_, folder)| folder.clone())
+    };
+    let built_in = || {
+        FOLDERS
+            .iter()
+            .find(|(pattern, _)| utils::mime_matches(mime_type, &[pattern.to_string()]))
+            .map(|(_, folder)| folder.to_string())
+    };
+    let folder = configured(true)
+        .or_else(|| configured(false))
+        .or_else(built_in)
+        .unwrap_or_else(|| OTHER.to_string());
+    // A configured name is one folder, never a way out of downloads
+    utils::safe_filename(&folder)
+}
+
+/// The folder for what arrived at `part_path` as `filename`: told from its
+/// content, or from the name when that isn't recognised.
+pub async fn folder_for_file(config: &TransferConfig, part_path: &Path, filename: &str) -> String {
+    let mut header = Vec::with_capacity(utils::MIME_SNIFF_LEN as usize);
+    if let Ok(file) = tokio::fs::File::open(part_path).await {
+        let _ = file.take(utils::MIME_SNIFF_LEN).read_to_end(&mut header).await;
+    }
+    let mime_type = utils::sniff_mime_type(&header).or_else(|| utils::get_mime_type(Path::new(filename)));
+    folder_for(config, mime_type.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+
+    #[test]
+    fn configured_folders_come_before_the_built_in_ones() {
+        let mut config = AppConfig::default().transfer;
+        assert_eq!(folder_for(&config, Some("image/png")), "Images");
+        assert_eq!(folder_for(&config, Some("application/x-iso9660-image")), "Archives");
+        assert_eq!(folder_for(&config, Some("application/octet-stream")), "Other");
+        assert_eq!(folder_for(&config, None), "Other");
+
+        config.type_folders.insert("image/*".to_string(), "Pictures".to_string());
+        config.type_folders.insert("image/gif".to_string(), "Memes".to_string());
+        config.type_folders.insert("audio/*".to_string(), "../Music".to_string());
+        assert_eq!(folder_for(&config, Some("image/png")), "Pictures");
+        assert_eq!(folder_for(&config, Some("image/gif")), "Memes");
+        assert_eq!(folder_for(&config, Some("audio/mpeg")), "Music");
+    }
+}
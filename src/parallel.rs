@@ -0,0 +1,610 @@
+use crate::active::{ActiveTransfer, SpeedSamples};
+use crate::compress;
+use crate::crypto::ChunkCipher;
+use crate::transfer::{
+    ConnectionContext, Handshake, Offer, ReceiveOutcome, ReceiveProgress, Replies, TransferError, TransferMessage,
+    TransferOutcome, TransferService, CHECKSUM_MISMATCH, CODE_BLOCKED, CODE_CHECKSUM_MISMATCH, CODE_SIZE_MISMATCH,
+    CODE_STREAM_FAILED, CODE_UNVERIFIED, MAX_CONTROL_MESSAGE_LEN, MAX_KEEPALIVES, MIN_CHUNK_LIMIT, SENDER_TIMEOUT,
+    UNVERIFIED,
+};
+use crate::tls::{self, PeerStream};
+use crate::utils;
+use anyhow::Result;
+use futures_util::future::try_join_all;
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tokio::fs::File;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf,
+};
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Duration};
+use uuid::Uuid;
+
+/// The part of a file one of a transfer's parallel connections carries:
+/// `length` bytes from `offset`, in chunks numbered from `first_chunk`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub length: u64,
+    pub first_chunk: u64,
+}
+
+impl ByteRange {
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+/// Splits a file of `file_size` bytes into at most `streams` contiguous
+/// ranges of whole chunks, as even as chunks allow. Fewer come back when
+/// the file has fewer chunks; none for an empty file.
+pub fn split(file_size: u64, chunk_size: usize, streams: u32) -> Vec<ByteRange> {
+    let chunk_size = chunk_size.max(1) as u64;
+    let chunks = file_size.div_ceil(chunk_size);
+    let streams = u64::from(streams.max(1)).min(chunks);
+    let mut ranges = Vec::new();
+    let mut first_chunk = 0;
+    for stream in 0..streams {
+        // The first few take one more when they don't divide evenly
+        let taken = chunks / streams + u64::from(stream < chunks % streams);
+        let offset = first_chunk * chunk_size;
+        let end = ((first_chunk + taken) * chunk_size).min(file_size);
+        ranges.push(ByteRange {
+            offset,
+            length: end - offset,
+            first_chunk,
+        });
+        first_chunk += taken;
+    }
+    ranges
+}
+
+/// A further connection of a parallel transfer, past its StreamRange.
+pub(crate) struct JoinedStream {
+    pub range: ByteRange,
+    pub reader: BufReader<ReadHalf<PeerStream>>,
+    pub writer: WriteHalf<PeerStream>,
+}
+
+/// Incoming transfers split over several connections, waiting on the
+/// connections after their first. Only the address the transfer came from
+/// may join it.
+#[derive(Default)]
+pub(crate) struct StreamJoins {
+    waiting: Mutex<HashMap<Uuid, (IpAddr, mpsc::UnboundedSender<JoinedStream>)>>,
+}
+
+impl StreamJoins {
+    /// Expects connections for `transfer_id` from `ip`, until `forget`.
+    pub fn expect(&self, transfer_id: Uuid, ip: IpAddr) -> mpsc::UnboundedReceiver<JoinedStream> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.waiting.lock().unwrap().insert(transfer_id, (ip, tx));
+        rx
+    }
+
+    pub fn forget(&self, transfer_id: &Uuid) {
+        self.waiting.lock().unwrap().remove(transfer_id);
+    }
+
+    /// Hands a connection from `ip` to the transfer `transfer_id` waiting
+    /// on it, or gives it back when none from there is.
+    pub fn join(&self, transfer_id: Uuid, ip: IpAddr, joined: JoinedStream) -> Option<JoinedStream> {
+        match self.waiting.lock().unwrap().get(&transfer_id) {
+            Some((from, waiting)) if *from == ip => waiting.send(joined).err().map(|unsent| unsent.0),
+            _ => Some(joined),
+        }
+    }
+}
+
+/// What each connection of an incoming split transfer writes with.
+struct SplitReceive<'a> {
+    transfer_id: Uuid,
+    file_size: u64,
+    part_path: &'a Path,
+    cipher: Option<&'a ChunkCipher>,
+    compressed: bool,
+    context: &'a ConnectionContext,
+    peer_id: Option<Uuid>,
+    samples: SpeedSamples,
+    /// Bytes written over all its connections so far.
+    received: AtomicU64,
+}
+
+/// What each connection of a send split over several works from.
+struct SplitSend<'a> {
+    transfer: &'a ActiveTransfer,
+    context: &'a ConnectionContext,
+    file_path: &'a Path,
+    cipher: &'a ChunkCipher,
+    framed: bool,
+    compressed: bool,
+    peer_id: Option<Uuid>,
+    /// Bytes sent over all its connections so far.
+    sent: AtomicU64,
+}
+
+impl TransferService {
+    /// Receives a file split over `handshake.streams` connections: a range
+    /// on this one, and one on each further connection the sender opens,
+    /// handed over through `joining`. Each range is written where it goes in
+    /// the file, which is checked as a whole once all are in.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn receive_split<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        handshake: Handshake,
+        mut joining: mpsc::UnboundedReceiver<JoinedStream>,
+        part_path: &Path,
+        file: &mut File,
+        context: &ConnectionContext,
+        expected_checksum: Option<&str>,
+        receive_progress: &mut ReceiveProgress,
+    ) -> Result<ReceiveOutcome>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let config = &context.config.transfer;
+        let transfer_id = receive_progress.transfer_id;
+        let file_size = receive_progress.file_size;
+        let Handshake {
+            accept_line,
+            cipher,
+            sealed,
+            keepalive,
+            compressed,
+            streams,
+            ..
+        } = handshake;
+        Self::write_raw_message(stream, &accept_line).await?;
+        if let Err(e) = file.set_len(file_size).await {
+            return Err(Self::send_write_error(stream, transfer_id, e).await);
+        }
+        let split = SplitReceive {
+            transfer_id,
+            file_size,
+            part_path,
+            cipher: cipher.as_ref(),
+            compressed,
+            context,
+            peer_id: receive_progress.peer_id,
+            samples: receive_progress.samples.clone(),
+            received: AtomicU64::new(0),
+        };
+
+        let ours = async {
+            let mut keepalives = 0;
+            // The sender may wait for a slot after we accept
+            let range = loop {
+                match timeout(SENDER_TIMEOUT, Self::read_message(reader, MAX_CONTROL_MESSAGE_LEN)).await?? {
+                    TransferMessage::StreamRange { transfer_id: tid, range } if tid == transfer_id => break range,
+                    TransferMessage::KeepAlive { .. } if keepalives < MAX_KEEPALIVES => keepalives += 1,
+                    TransferMessage::Cancel { transfer_id: tid } if tid == transfer_id => return Err(TransferError::Cancelled.into()),
+                    _ => anyhow::bail!("Expected the range this connection carries"),
+                }
+            };
+            let chunks = Self::receive_range(&split, reader, range).await?;
+            // Complete comes here once every range is in; a sender that gives
+            // up on any closes this too
+            if reader.fill_buf().await?.is_empty() {
+                anyhow::bail!("Sender closed the connection with bytes {}..{} in", range.offset, range.end());
+            }
+            Ok::<_, anyhow::Error>((range, chunks))
+        };
+        let theirs = async {
+            let mut done = Vec::new();
+            let mut running = FuturesUnordered::new();
+            let mut expected = streams - 1;
+            while expected > 0 || !running.is_empty() {
+                tokio::select! {
+                    joined = timeout(SENDER_TIMEOUT, joining.recv()), if expected > 0 => {
+                        let Ok(Some(JoinedStream { range, mut reader, mut writer })) = joined else {
+                            anyhow::bail!("Sender opened {} of its {} connections", streams - expected, streams);
+                        };
+                        expected -= 1;
+                        let split = &split;
+                        running.push(async move {
+                            let chunks = Self::receive_range(split, &mut reader, range).await?;
+                            let ack = TransferMessage::Ack {
+                                transfer_id,
+                                received_chunks: chunks,
+                            };
+                            Self::write_message(&mut writer, &ack).await?;
+                            Ok::<_, anyhow::Error>((range, chunks))
+                        });
+                    }
+                    Some(finished) = running.next() => done.push(finished?),
+                }
+            }
+            Ok(done)
+        };
+        let mut ranges = match tokio::try_join!(ours, theirs) {
+            Ok((ours, mut theirs)) => {
+                theirs.push(ours);
+                theirs
+            }
+            Err(e) if matches!(e.downcast_ref(), Some(TransferError::Cancelled)) => {
+                tracing::info!("Transfer {} cancelled by sender", transfer_id);
+                return Ok(ReceiveOutcome::CancelledBySender);
+            }
+            Err(e) => return Err(Self::send_error(stream, transfer_id, CODE_STREAM_FAILED, e.to_string()).await),
+        };
+        let received_size = split.received.into_inner();
+        receive_progress.received = received_size;
+        receive_progress.samples.finish(received_size);
+
+        // Together the ranges must be the whole file, each chunk numbered once
+        ranges.sort_by_key(|(range, _)| range.offset);
+        let (mut covered, mut chunk_count) = (0, 0);
+        for (range, chunks) in &ranges {
+            if range.offset != covered || range.first_chunk != chunk_count {
+                let reason = format!("Split transfer's ranges don't meet at byte {}", covered);
+                return Err(Self::send_error(stream, transfer_id, CODE_SIZE_MISMATCH, reason).await);
+            }
+            covered = range.end();
+            chunk_count += chunks;
+        }
+        if covered != file_size {
+            let reason = format!("Transfer ended after {} of {} bytes", covered, file_size);
+            return Err(Self::send_error(stream, transfer_id, CODE_SIZE_MISMATCH, reason).await);
+        }
+
+        let seal = match timeout(SENDER_TIMEOUT, Self::read_message(reader, MAX_CONTROL_MESSAGE_LEN)).await?? {
+            TransferMessage::Complete { transfer_id: tid, seal, .. } if tid == transfer_id => seal,
+            TransferMessage::Cancel { transfer_id: tid } if tid == transfer_id => {
+                tracing::info!("Transfer {} cancelled by sender", transfer_id);
+                return Ok(ReceiveOutcome::CancelledBySender);
+            }
+            _ => anyhow::bail!("Expected the split transfer to complete"),
+        };
+        receive_progress
+            .trace
+            .record("complete_received", format!("{} chunks over {} connections, {} bytes", chunk_count, streams, covered));
+
+        if !config.blocked_mime_types.is_empty() {
+            let mut header = Vec::new();
+            File::open(part_path).await?.take(utils::MIME_SNIFF_LEN).read_to_end(&mut header).await?;
+            let detected = utils::sniff_mime_type(&header);
+            if let Some(reason) = Self::blocked_reason(config, "", [detected.as_deref()]) {
+                let error = TransferMessage::Error {
+                    transfer_id,
+                    message: reason.clone(),
+                    code: Some(CODE_BLOCKED.to_string()),
+                };
+                let _ = Self::write_message(stream, &error).await;
+                return Ok(ReceiveOutcome::Blocked(reason));
+            }
+        }
+
+        // Only the file as assembled counts, hashed again from the disk
+        let hashing = utils::calculate_file_checksum(part_path);
+        let calculated_checksum = Self::keeping_alive(stream, transfer_id, keepalive, hashing).await??;
+        let digest = hex::decode(&calculated_checksum)?;
+        if let (Some(cipher), true) = (&cipher, sealed) {
+            let verified = match &seal {
+                Some(seal) => cipher.verify_seal(seal, chunk_count, covered, &digest),
+                None => Err(anyhow::anyhow!("Transfer was not sealed")),
+            };
+            if let Err(e) = verified {
+                tracing::warn!("Discarding {}: {}", receive_progress.filename, e);
+                let reason = UNVERIFIED.to_string();
+                return Err(Self::send_error(stream, transfer_id, CODE_UNVERIFIED, reason).await);
+            }
+        }
+        if let Some(expected) = expected_checksum.filter(|expected| *expected != calculated_checksum) {
+            tracing::warn!(
+                "Checksum mismatch for {}: expected {}, got {}",
+                receive_progress.filename,
+                expected,
+                calculated_checksum
+            );
+            let reason = CHECKSUM_MISMATCH.to_string();
+            return Err(Self::send_error(stream, transfer_id, CODE_CHECKSUM_MISMATCH, reason).await);
+        }
+
+        if let Err(e) = file.sync_all().await {
+            return Err(Self::send_write_error(stream, transfer_id, e).await);
+        }
+        // The sender waits to hear the file is whole
+        let ack = TransferMessage::Ack {
+            transfer_id,
+            received_chunks: chunk_count,
+        };
+        Self::write_message(stream, &ack).await?;
+        receive_progress.checksum = Some(calculated_checksum);
+        receive_progress.verified = expected_checksum.is_some();
+        tracing::info!(
+            "File received: {} ({} bytes over {} connections) - Checksum verified: {}",
+            receive_progress.filename,
+            covered,
+            streams,
+            receive_progress.verified
+        );
+
+        Ok(ReceiveOutcome::Complete)
+    }
+
+    /// Writes the chunks of `range` arriving on one connection of a split
+    /// transfer where they go in its file, and returns how many it took.
+    async fn receive_range<R: AsyncBufRead + Unpin>(split: &SplitReceive<'_>, reader: &mut R, range: ByteRange) -> Result<u64> {
+        if range.length == 0 || range.end() > split.file_size {
+            anyhow::bail!("Bytes {}..{} aren't part of the file", range.offset, range.end());
+        }
+        let config = &split.context.config.transfer;
+        let max_message_len = Self::max_chunk_message_len(config.chunk_size);
+        let mut file = tokio::fs::OpenOptions::new().write(true).open(split.part_path).await?;
+        file.seek(SeekFrom::Start(range.offset)).await?;
+        let (mut written, mut chunks) = (0u64, 0u64);
+        let mut keepalives = 0;
+        while written < range.length {
+            match timeout(SENDER_TIMEOUT, Self::read_transfer_message(reader, max_message_len)).await?? {
+                TransferMessage::Chunk {
+                    transfer_id,
+                    chunk_index,
+                    data,
+                    ..
+                } if transfer_id == split.transfer_id && chunk_index == range.first_chunk + chunks => {
+                    keepalives = 0;
+                    split.context.bandwidth.download().acquire(data.len()).await;
+                    split.context.usage.record_received(split.peer_id, data.len());
+                    let data = match split.cipher.map(|cipher| cipher.decrypt(chunk_index, &data)) {
+                        Some(decrypted) => decrypted.map_err(|e| anyhow::anyhow!("Chunk {} could not be decrypted: {}", chunk_index, e))?,
+                        None => data,
+                    };
+                    let data = match split.compressed {
+                        true => compress::unpack(&data, config.chunk_size.max(MIN_CHUNK_LIMIT))
+                            .map_err(|e| anyhow::anyhow!("Chunk {} could not be decompressed: {}", chunk_index, e))?,
+                        false => data,
+                    };
+                    let len = data.len() as u64;
+                    if written + len > range.length {
+                        anyhow::bail!("Received more than the {} bytes from {}", range.length, range.offset);
+                    }
+                    file.write_all(&data).await?;
+                    written += len;
+                    chunks += 1;
+                    let received = split.received.fetch_add(len, Ordering::Relaxed) + len;
+                    split.samples.record(received);
+                }
+                TransferMessage::KeepAlive { .. } if keepalives < MAX_KEEPALIVES => keepalives += 1,
+                TransferMessage::Cancel { transfer_id } if transfer_id == split.transfer_id => {
+                    return Err(TransferError::Cancelled.into());
+                }
+                _ => anyhow::bail!("Unexpected message in bytes {}..{}", range.offset, range.end()),
+            }
+        }
+        file.flush().await?;
+        Ok(chunks)
+    }
+
+    /// Sends `file_path` split into `offer.streams` ranges: the first over
+    /// this connection, the others over connections of their own to the
+    /// peer's listener at `address`, then Complete here for the whole file.
+    /// The transfer fails with any of its connections.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn stream_split<R, W>(
+        context: &ConnectionContext,
+        reader: &mut R,
+        stream: &mut W,
+        transfer: &ActiveTransfer,
+        offer: Offer,
+        file_path: &Path,
+        address: SocketAddr,
+    ) -> Result<TransferOutcome>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let Offer {
+            peer_id,
+            filename,
+            file_size,
+            file_checksum,
+            cipher,
+            framed,
+            compressed,
+            streams,
+            sealed,
+            ..
+        } = offer;
+        let (Some(checksum), Some(cipher)) = (file_checksum, cipher) else {
+            anyhow::bail!("Only encrypted files hashed up front are split");
+        };
+        let transfer_id = transfer.id();
+        let stall_timeout = Duration::from_secs(context.config.transfer.send_stall_timeout);
+        let ranges = split(file_size, context.config.transfer.chunk_size, streams);
+        let Some((first, rest)) = ranges.split_first() else {
+            anyhow::bail!("Nothing to split");
+        };
+        let mut progress = utils::ProgressTracker::new(file_size);
+        let split = SplitSend {
+            transfer,
+            context,
+            file_path,
+            cipher: &cipher,
+            framed,
+            compressed,
+            peer_id,
+            sent: AtomicU64::new(0),
+        };
+        transfer
+            .trace()
+            .record("split", format!("{} connections to {}", ranges.len(), address));
+
+        let pin = context.peers.read().await.tls_fingerprint(address);
+        let theirs = rest.iter().map(|range| async {
+            let stream = Self::connect(&context.config.transfer, address).await?;
+            let (read_half, mut writer) = tls::connect(context.tls.as_deref(), stream, pin.as_deref()).await?.into_split();
+            let mut reader = BufReader::new(read_half);
+            Self::send_range(&split, &mut reader, &mut writer, *range, true).await
+        });
+        let sending = async {
+            tokio::try_join!(Self::send_range(&split, reader, stream, *first, false), try_join_all(theirs))
+        };
+        let sent = tokio::select! {
+            sent = sending => Some(sent),
+            _ = transfer.cancelled() => None,
+        };
+        let chunks = match sent {
+            Some(Ok((ours, theirs))) => ours + theirs.iter().sum::<u64>(),
+            Some(Err(e)) => return Err(Self::peer_gone(e, split.sent.load(Ordering::Relaxed))),
+            None => return Err(Self::send_cancel(stream, transfer).await),
+        };
+        transfer.samples().finish(file_size);
+
+        let digest = hex::decode(&checksum)?;
+        let complete = TransferMessage::Complete {
+            transfer_id,
+            file_checksum: (!sealed).then(|| checksum.clone()),
+            seal: Some(cipher.seal(chunks, file_size, &digest)?),
+        };
+        Self::write_with_liveness(stream, &complete, stall_timeout)
+            .await
+            .map_err(|e| Self::peer_gone(e, file_size))?;
+        transfer
+            .trace()
+            .record("complete_sent", format!("{} chunks, {} bytes", chunks, file_size));
+        // The receiver checks the file as assembled before it says so
+        let mut replies = Replies::default();
+        Self::wait_for_ack(reader, stream, transfer, &mut replies, chunks, 1, stall_timeout)
+            .await
+            .map_err(|e| Self::peer_gone(e, file_size))?;
+        if let Some(aborted) = Self::abort_reason(reader).await {
+            return Err(aborted.into());
+        }
+
+        progress.update(file_size);
+        tracing::info!(
+            "File sent: {} ({} bytes over {} connections) in {:.2}s - {}",
+            filename,
+            file_size,
+            ranges.len(),
+            progress.elapsed().as_secs_f64(),
+            utils::format_speed(progress.average_speed())
+        );
+        Ok(TransferOutcome {
+            transfer_id,
+            bytes_sent: file_size,
+            duration: progress.elapsed(),
+            average_speed: progress.average_speed(),
+            checksum: Some(checksum),
+            peer_ack: true,
+            encrypted: true,
+            connectivity: context.connectivity,
+            deduplicated: false,
+        })
+    }
+
+    /// Sends `range` of a split send's file on one of its connections,
+    /// after the StreamRange saying which it is. On a connection of its
+    /// own, waits for the receiver to have written it. Returns how many
+    /// chunks it took.
+    async fn send_range<R, W>(split: &SplitSend<'_>, reader: &mut R, stream: &mut W, range: ByteRange, own_connection: bool) -> Result<u64>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let transfer_id = split.transfer.id();
+        let config = &split.context.config.transfer;
+        let stall_timeout = Duration::from_secs(config.send_stall_timeout);
+        Self::write_message(stream, &TransferMessage::StreamRange { transfer_id, range }).await?;
+        let mut file = File::open(split.file_path).await?;
+        file.seek(SeekFrom::Start(range.offset)).await?;
+        let mut replies = Replies::default();
+        let (mut sent, mut chunks) = (0u64, 0u64);
+        while sent < range.length {
+            let mut plain = vec![0u8; (range.length - sent).min(config.chunk_size as u64) as usize];
+            file.read_exact(&mut plain).await?;
+            let packed = split.compressed.then(|| compress::pack(&plain));
+            let chunk_index = range.first_chunk + chunks;
+            let data = split.cipher.encrypt(chunk_index, packed.as_deref().unwrap_or(&plain))?;
+            let wire_bytes = data.len();
+            split.context.bandwidth.upload().acquire(wire_bytes).await;
+            let chunk = Self::encode_chunk(transfer_id, chunk_index, data, None, split.framed)?;
+            Self::write_chunk(reader, stream, &chunk, &mut replies, stall_timeout).await?;
+
+            split.context.usage.record_sent(split.peer_id, wire_bytes);
+            sent += plain.len() as u64;
+            chunks += 1;
+            let total = split.sent.fetch_add(plain.len() as u64, Ordering::Relaxed) + plain.len() as u64;
+            split.transfer.samples().record(total);
+        }
+        if own_connection {
+            Self::wait_for_ack(reader, stream, split.transfer, &mut replies, chunks, 1, stall_timeout).await?;
+        }
+        Ok(chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::tests::{context, limited_config, scratch_dir, service, CHUNK};
+    use crate::transfer::Route;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn ranges_cover_the_file_in_whole_chunks() {
+        let ranges = split(10_500, 1000, 4);
+        let lengths: Vec<u64> = ranges.iter().map(|range| range.length).collect();
+        assert_eq!(lengths, [3000, 3000, 3000, 1500]);
+        assert_eq!(ranges.iter().map(|range| range.first_chunk).collect::<Vec<_>>(), [0, 3, 6, 9]);
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].end(), pair[1].offset);
+        }
+        assert_eq!(ranges.last().unwrap().end(), 10_500);
+
+        // No range is left without a chunk
+        assert_eq!(split(2500, 1000, 8).len(), 3);
+        assert!(split(0, 1000, 4).is_empty());
+        assert_eq!(split(999, 1000, 0), [ByteRange { offset: 0, length: 999, first_chunk: 0 }]);
+    }
+
+    #[tokio::test]
+    async fn a_large_send_is_split_over_several_connections() {
+        let dir = scratch_dir();
+        let mut config = limited_config(1);
+        config.transfer.parallel_streams = 4;
+        let sender = service(config, &dir);
+        let receiver = service(limited_config(1), &dir);
+        let data: Vec<u8> = (0..37 * CHUNK + CHUNK / 2).map(|i| (i % 241) as u8).collect();
+        let path = dir.join("large.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let accepting = {
+            let context = context(&receiver, &dir);
+            let connections = connections.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, from) = listener.accept().await.unwrap();
+                    connections.fetch_add(1, Ordering::SeqCst);
+                    let context = context.clone();
+                    tokio::spawn(async move { TransferService::handle_connection(stream.into(), from, context, &mut None).await });
+                }
+            })
+        };
+        let transfer = sender.track_send(Uuid::new_v4());
+        let outcome = sender
+            .send_file(&transfer, Route::Direct(address), path, |_, _| {})
+            .await
+            .unwrap();
+        accepting.abort();
+
+        assert_eq!(outcome.bytes_sent, data.len() as u64);
+        assert!(outcome.peer_ack && outcome.encrypted);
+        assert_eq!(connections.load(Ordering::SeqCst), 4);
+        assert_eq!(std::fs::read(dir.join("downloads").join("large.bin")).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
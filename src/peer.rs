@@ -1,35 +1,96 @@
+use crate::identity::{Identity, TrustStatus, TrustStore};
+use crate::rendezvous::Connectivity;
+use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use tokio::sync::RwLock;
 use uuid::Uuid;
 
+/// Seconds a discovered peer stays listed, and counts as online, after it
+/// was last heard from.
+pub const PEER_TIMEOUT_SECS: u64 = 30;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Peer {
     pub id: Uuid,
     pub address: SocketAddr,
     pub hostname: String,
     pub last_seen: std::time::SystemTime,
+    /// Identity fingerprint the peer announced, if it has one.
+    pub fingerprint: Option<String>,
+    /// Identity key (hex) behind `fingerprint`, once an announcement
+    /// signed with it was taken.
+    #[serde(default)]
+    pub identity_key: Option<String>,
+    /// Set while the peer presents a fingerprint other than the pinned one.
+    pub identity_changed: bool,
+    /// Where else the peer said it can be reached, tried when `address`
+    /// doesn't answer.
+    pub alt_addresses: Vec<SocketAddr>,
+    /// Every LAN address the peer announced, in the order to try them.
+    #[serde(default)]
+    pub addresses: Vec<SocketAddr>,
+    /// Where a connection to it last got through, tried first next time.
+    #[serde(default)]
+    pub reached_at: Option<SocketAddr>,
+    /// How long the last connection to it took to open.
+    #[serde(default)]
+    pub latency: Option<std::time::Duration>,
+    /// Optional features it announced; empty for peers that predate them.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Added by hand rather than discovered, so it stays listed while quiet.
+    #[serde(default)]
+    pub manual: bool,
+    /// How the last connection to it got through.
+    #[serde(default)]
+    pub connectivity: Option<Connectivity>,
+    /// SHA-256 of the TLS certificate it announced, the only one trusted
+    /// on connections to it; None for peers without TLS.
+    #[serde(default)]
+    pub tls_fingerprint: Option<String>,
 }
 
 impl Peer {
-    pub fn new(address: SocketAddr, hostname: String) -> Self {
+    pub fn from_discovery(id: Uuid, address: SocketAddr, hostname: String, fingerprint: Option<String>) -> Self {
         Self {
-            id: Uuid::new_v4(),
+            id,
             address,
             hostname,
             last_seen: std::time::SystemTime::now(),
+            fingerprint,
+            identity_key: None,
+            identity_changed: false,
+            alt_addresses: Vec::new(),
+            addresses: Vec::new(),
+            reached_at: None,
+            latency: None,
+            capabilities: Vec::new(),
+            manual: false,
+            connectivity: None,
+            tls_fingerprint: None,
         }
     }
 
-    pub fn from_discovery(id: Uuid, address: SocketAddr, hostname: String) -> Self {
-        Self {
-            id,
-            address,
-            hostname,
-            last_seen: std::time::SystemTime::now(),
+    /// Whether it was heard from within `PEER_TIMEOUT_SECS`.
+    pub fn is_online(&self) -> bool {
+        self.last_seen
+            .elapsed()
+            .is_ok_and(|elapsed| elapsed.as_secs() < PEER_TIMEOUT_SECS)
+    }
+
+    /// Where to try reaching it: wherever it last answered, then `address`,
+    /// its other LAN addresses and its outside ones.
+    pub fn addresses_to_try(&self) -> Vec<SocketAddr> {
+        let mut candidates: Vec<SocketAddr> = Vec::new();
+        let all = self.reached_at.iter().chain([&self.address]).chain(&self.addresses).chain(&self.alt_addresses);
+        for address in all {
+            if !candidates.contains(address) {
+                candidates.push(*address);
+            }
         }
+        candidates
     }
 
     pub fn update_seen(&mut self) {
@@ -37,64 +98,210 @@ impl Peer {
     }
 }
 
+/// Peers this node knows of, checked against their pinned identities.
 pub struct PeerManager {
     peers: HashMap<Uuid, Peer>,
     local_id: Uuid,
     local_hostname: String,
+    identity: Arc<Identity>,
+    trust: TrustStore,
+    /// Peers whose fingerprint changed, with the new fingerprint awaiting acceptance.
+    identity_alerts: HashMap<Uuid, String>,
+    /// Timestamp of the latest signed announcement taken from each peer. Kept
+    /// after the peer is gone, so an old announcement can't bring it back.
+    announced_at: HashMap<Uuid, u64>,
+    /// SHA-256 of the TLS certificate we announce, when we use TLS.
+    local_tls_fingerprint: Option<String>,
 }
 
 impl PeerManager {
-    pub fn new() -> Self {
-        let hostname = hostname::get()
-            .unwrap_or_else(|_| "unknown".into())
-            .to_string_lossy()
-            .to_string();
-
+    /// Starts with no peers; `trust` decides whose identity is accepted, and
+    /// peers see us as `hostname`.
+    pub fn new(identity: Arc<Identity>, trust: TrustStore, hostname: String) -> Self {
         Self {
             peers: HashMap::new(),
-            local_id: Uuid::new_v4(),
+            local_id: identity.peer_id(),
             local_hostname: hostname,
+            identity,
+            trust,
+            identity_alerts: HashMap::new(),
+            announced_at: HashMap::new(),
+            local_tls_fingerprint: None,
         }
     }
 
+    /// This node's identity.
+    pub fn identity(&self) -> Arc<Identity> {
+        self.identity.clone()
+    }
+
+    /// This node's id.
     pub fn local_id(&self) -> Uuid {
         self.local_id
     }
 
+    /// The name peers see for this node.
     pub fn local_hostname(&self) -> &str {
         &self.local_hostname
     }
 
-    pub fn add_or_update_peer(&mut self, peer: Peer) {
+    /// Fingerprint of the TLS certificate peers are told to expect from us.
+    pub fn local_tls_fingerprint(&self) -> Option<&str> {
+        self.local_tls_fingerprint.as_deref()
+    }
+
+    /// Announces `fingerprint` as our TLS certificate from now on.
+    pub fn set_local_tls_fingerprint(&mut self, fingerprint: String) {
+        self.local_tls_fingerprint = Some(fingerprint);
+    }
+
+    /// Adds a peer or refreshes what's known of it; this node itself is ignored.
+    pub fn add_or_update_peer(&mut self, mut peer: Peer) {
         if peer.id != self.local_id {
+            let identity_changed = self.identity_alerts.contains_key(&peer.id);
             if let Some(existing) = self.peers.get_mut(&peer.id) {
                 existing.address = peer.address;
+                existing.alt_addresses = peer.alt_addresses;
+                existing.addresses = peer.addresses;
+                existing.capabilities = peer.capabilities;
+                existing.tls_fingerprint = peer.tls_fingerprint;
+                // Only worth trying first while the peer still claims it
+                let reached_at = existing.reached_at.take();
+                existing.reached_at = reached_at.filter(|reached| existing.addresses_to_try().contains(reached));
                 existing.hostname = peer.hostname;
+                if peer.fingerprint.is_some() {
+                    existing.fingerprint = peer.fingerprint;
+                    existing.identity_key = peer.identity_key;
+                }
+                existing.identity_changed = identity_changed;
+                existing.manual |= peer.manual;
                 existing.update_seen();
             } else {
+                peer.identity_changed = identity_changed;
                 self.peers.insert(peer.id, peer);
             }
         }
     }
 
+    /// Checks a fingerprint presented by `peer_id` against the one pinned on
+    /// first contact. Returns the pinned fingerprint the first time a given
+    /// mismatch is seen, so the caller raises the alert only once.
+    pub fn check_identity(&mut self, peer_id: Uuid, fingerprint: &str) -> Option<String> {
+        match self.trust.check(peer_id, fingerprint) {
+            TrustStatus::FirstContact => {
+                tracing::info!("Pinned fingerprint {} for peer {}", fingerprint, peer_id);
+                None
+            }
+            TrustStatus::Trusted => None,
+            TrustStatus::Changed { pinned } => {
+                let previous = self.identity_alerts.insert(peer_id, fingerprint.to_string());
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.identity_changed = true;
+                }
+                (previous.as_deref() != Some(fingerprint)).then_some(pinned)
+            }
+        }
+    }
+
+    /// Records a signed announcement's timestamp, unless it's no newer than
+    /// one already taken from the peer, in which case it's a replay.
+    pub fn record_announcement(&mut self, peer_id: Uuid, timestamp: u64) -> bool {
+        match self.announced_at.get(&peer_id) {
+            Some(latest) if *latest >= timestamp => false,
+            _ => {
+                self.announced_at.insert(peer_id, timestamp);
+                true
+            }
+        }
+    }
+
+    /// The fingerprint trusted for `peer_id`.
+    pub fn pinned_fingerprint(&self, peer_id: &Uuid) -> Option<&str> {
+        self.trust.pinned(peer_id)
+    }
+
+    /// Trusts the fingerprint that raised an identity alert for `peer_id`.
+    pub fn accept_identity(&mut self, peer_id: &Uuid) -> Result<String> {
+        let fingerprint = self
+            .identity_alerts
+            .remove(peer_id)
+            .ok_or_else(|| anyhow!("Peer {} has no pending identity change", peer_id))?;
+        self.trust.pin(*peer_id, fingerprint.clone())?;
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.identity_changed = false;
+        }
+        Ok(fingerprint)
+    }
+
+    /// Forgets a peer.
     pub fn remove_peer(&mut self, peer_id: &Uuid) {
         self.peers.remove(peer_id);
     }
 
+    /// A peer by id.
     pub fn get_peer(&self, peer_id: &Uuid) -> Option<&Peer> {
         self.peers.get(peer_id)
     }
 
+    /// The peer announced from `ip`.
+    pub fn find_by_ip(&self, ip: IpAddr) -> Option<&Peer> {
+        self.peers
+            .values()
+            .find(|p| p.address.ip() == ip)
+            .or_else(|| self.peers.values().find(|p| p.addresses.iter().any(|address| address.ip() == ip)))
+    }
+
+    /// Where to try reaching the peer announced at `address`, in order; just
+    /// `address` for a node we don't know.
+    pub fn addresses_to_try(&self, address: SocketAddr) -> Vec<SocketAddr> {
+        self.peers
+            .values()
+            .find(|p| p.address == address)
+            .map(Peer::addresses_to_try)
+            .unwrap_or_else(|| vec![address])
+    }
+
+    /// The TLS certificate fingerprint announced by the peer reachable at
+    /// `address`, if one is and did.
+    pub fn tls_fingerprint(&self, address: SocketAddr) -> Option<String> {
+        self.peers
+            .values()
+            .find(|p| p.address == address)
+            .or_else(|| self.peers.values().find(|p| p.addresses_to_try().contains(&address)))
+            .and_then(|p| p.tls_fingerprint.clone())
+    }
+
+    /// Remembers that the peer announced at `address` answered at
+    /// `reached`, taking `latency` to connect.
+    pub fn record_reached(&mut self, address: SocketAddr, reached: SocketAddr, latency: std::time::Duration) {
+        if let Some(peer) = self.peers.values_mut().find(|p| p.address == address) {
+            peer.reached_at = Some(reached);
+            peer.latency = Some(latency);
+        }
+    }
+
+    /// Notes how a connection to `peer_id` just got through.
+    pub fn record_connectivity(&mut self, peer_id: &Uuid, connectivity: Connectivity) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.connectivity = Some(connectivity);
+        }
+    }
+
+    /// Every known peer.
     pub fn list_peers(&self) -> Vec<Peer> {
         self.peers.values().cloned().collect()
     }
 
+    /// Forgets discovered peers not heard from within `timeout_secs`.
+    /// Manually added ones are kept.
     pub fn cleanup_stale_peers(&mut self, timeout_secs: u64) {
         let now = std::time::SystemTime::now();
         let timeout = std::time::Duration::from_secs(timeout_secs);
 
         self.peers.retain(|_, peer| {
-            if let Ok(elapsed) = now.duration_since(peer.last_seen) {
+            if peer.manual {
+                true
+            } else if let Ok(elapsed) = now.duration_since(peer.last_seen) {
                 elapsed < timeout
             } else {
                 false
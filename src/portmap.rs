@@ -0,0 +1,335 @@
+use crate::config::AppConfig;
+use crate::protocol::PortMappingStatus;
+use crate::utils;
+use anyhow::{anyhow, bail, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use tokio::net::UdpSocket;
+use tokio::time::{sleep, timeout, Duration};
+use tokio_util::sync::CancellationToken;
+
+/// Lease asked for; mappings are renewed at half of what the router grants.
+const LEASE: Duration = Duration::from_secs(3600);
+/// Shortest wait between renewals, however short a lease the router grants.
+const MIN_RENEWAL: Duration = Duration::from_secs(60);
+/// Wait before trying again after the router couldn't give us a mapping.
+const RETRY_AFTER_FAILURE: Duration = Duration::from_secs(300);
+const NAT_PMP_PORT: u16 = 5351;
+const MAPPING_DESCRIPTION: &str = "p2p-sharing";
+/// How long to wait for a UPnP gateway to answer the search.
+const UPNP_SEARCH_TIMEOUT: Duration = Duration::from_secs(3);
+/// How long removing the mapping may take on shutdown, so the service still
+/// stops within the daemon's shutdown grace.
+const UNMAP_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// How a mapping was made, so it can be renewed and removed the same way.
+#[derive(Debug, Clone)]
+enum Gateway {
+    NatPmp(SocketAddr),
+    Upnp(Box<upnp::Device>),
+}
+
+impl Gateway {
+    fn method(&self) -> &'static str {
+        match self {
+            Gateway::NatPmp(_) => "nat-pmp",
+            Gateway::Upnp(_) => "upnp",
+        }
+    }
+}
+
+struct Mapping {
+    gateway: Gateway,
+    external: SocketAddr,
+    lease: Duration,
+}
+
+/// Keeps a router port mapping for the transfer port so peers outside the
+/// LAN can reach us, when `network.upnp` is on.
+pub struct PortMapper {
+    config: Arc<AppConfig>,
+    status: RwLock<PortMappingStatus>,
+}
+
+impl PortMapper {
+    /// Nothing is mapped until `run`.
+    pub fn new(config: Arc<AppConfig>) -> Self {
+        let state = if config.network.upnp { "pending" } else { "disabled" };
+        Self {
+            config,
+            status: RwLock::new(PortMappingStatus {
+                state: state.to_string(),
+                method: None,
+                external_address: None,
+                message: None,
+            }),
+        }
+    }
+
+    /// How the mapping is doing.
+    pub fn status(&self) -> PortMappingStatus {
+        self.status.read().unwrap().clone()
+    }
+
+    /// The mapped address peers outside the LAN can use, while a mapping is up.
+    pub fn external_address(&self) -> Option<SocketAddr> {
+        let status = self.status.read().unwrap();
+        (status.state == "active").then_some(status.external_address).flatten()
+    }
+
+    /// Maps `port`, the bound transfer port, and keeps renewing it until
+    /// shutdown, then removes it. Failures are logged and retried later;
+    /// they never stop the daemon.
+    pub async fn run(self: Arc<Self>, port: u16, shutdown: CancellationToken) -> Result<()> {
+        let mut current: Option<Gateway> = None;
+        loop {
+            let wait = match self.map(port, current.as_ref()).await {
+                Ok(mapping) => {
+                    if current.is_none() {
+                        tracing::info!(
+                            "Router port mapping via {}: peers outside the LAN can reach us at {}",
+                            mapping.gateway.method(),
+                            mapping.external
+                        );
+                    }
+                    self.set_status("active", Some(mapping.gateway.method()), Some(mapping.external), None);
+                    current = Some(mapping.gateway);
+                    (mapping.lease / 2).max(MIN_RENEWAL)
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "Router port mapping unavailable, peers outside the LAN won't reach us: {}",
+                        e
+                    );
+                    self.set_status("failed", None, None, Some(e.to_string()));
+                    current = None;
+                    RETRY_AFTER_FAILURE
+                }
+            };
+
+            tokio::select! {
+                _ = sleep(wait) => {}
+                _ = shutdown.cancelled() => break,
+            }
+        }
+
+        if let Some(gateway) = current {
+            match timeout(UNMAP_TIMEOUT, self.unmap(port, &gateway)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => tracing::debug!("Couldn't remove the router port mapping: {}", e),
+                Err(_) => tracing::debug!("Router didn't confirm removing the port mapping in time"),
+            }
+        }
+        Ok(())
+    }
+
+    fn set_status(&self, state: &str, method: Option<&str>, external_address: Option<SocketAddr>, message: Option<String>) {
+        *self.status.write().unwrap() = PortMappingStatus {
+            state: state.to_string(),
+            method: method.map(str::to_string),
+            external_address,
+            message,
+        };
+    }
+
+    /// Creates or renews the mapping, reusing the gateway that worked last time.
+    async fn map(&self, port: u16, known: Option<&Gateway>) -> Result<Mapping> {
+        let local_ip = utils::get_local_ip(self.config.network.interface.as_deref())
+            .ok_or_else(|| anyhow!("no local IPv4 address"))?;
+
+        let mapping = match known {
+            Some(Gateway::NatPmp(gateway)) => nat_pmp::map(*gateway, port, LEASE).await?,
+            Some(Gateway::Upnp(device)) => upnp::map(device.clone(), local_ip, port, LEASE).await?,
+            None => {
+                let nat_pmp = match default_gateway() {
+                    Some(gateway) => nat_pmp::map(SocketAddr::new(IpAddr::V4(gateway), NAT_PMP_PORT), port, LEASE).await,
+                    None => Err(anyhow!("no default gateway found")),
+                };
+                match nat_pmp {
+                    Ok(mapping) => mapping,
+                    Err(nat_pmp_error) => {
+                        let device = upnp::discover()
+                            .await
+                            .map_err(|e| anyhow!("no NAT-PMP ({}) or UPnP gateway ({})", nat_pmp_error, e))?;
+                        upnp::map(device, local_ip, port, LEASE).await?
+                    }
+                }
+            }
+        };
+
+        // A router whose own outside address is private sits behind another
+        // NAT we can't map through
+        if let IpAddr::V4(ip) = mapping.external.ip() {
+            if ip.is_private() || is_shared_address(ip) {
+                let _ = self.unmap(port, &mapping.gateway).await;
+                bail!(
+                    "double NAT: the router's external address {} is itself behind another NAT",
+                    ip
+                );
+            }
+        }
+        Ok(mapping)
+    }
+
+    async fn unmap(&self, port: u16, gateway: &Gateway) -> Result<()> {
+        match gateway {
+            Gateway::NatPmp(gateway) => nat_pmp::unmap(*gateway, port).await,
+            Gateway::Upnp(device) => upnp::unmap(device, port).await,
+        }
+    }
+}
+
+/// 100.64.0.0/10, used by carrier-grade NAT.
+fn is_shared_address(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    a == 100 && (64..128).contains(&b)
+}
+
+/// The IPv4 default gateway, read from the routing table on Linux and
+/// guessed as the `.1` of our subnet elsewhere.
+fn default_gateway() -> Option<Ipv4Addr> {
+    #[cfg(target_os = "linux")]
+    {
+        let routes = std::fs::read_to_string("/proc/net/route").ok()?;
+        routes.lines().skip(1).find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.get(1) != Some(&"00000000") {
+                return None;
+            }
+            let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+            Some(Ipv4Addr::from(gateway.to_le_bytes()))
+        })
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let [a, b, c, _] = utils::get_local_ip(None)?.octets();
+        Some(Ipv4Addr::new(a, b, c, 1))
+    }
+}
+
+/// NAT-PMP (RFC 6886).
+mod nat_pmp {
+    use super::*;
+
+    const OP_EXTERNAL_ADDRESS: u8 = 0;
+    const OP_MAP_TCP: u8 = 2;
+
+    /// Maps `port` for `lease`.
+    pub(super) async fn map(gateway: SocketAddr, port: u16, lease: Duration) -> Result<Mapping> {
+        let socket = connect(gateway).await?;
+
+        let response = request(&socket, &[0, OP_EXTERNAL_ADDRESS], 12).await?;
+        let external_ip = Ipv4Addr::new(response[8], response[9], response[10], response[11]);
+
+        let response = request(&socket, &map_request(port, port, lease), 16).await?;
+        let external_port = u16::from_be_bytes([response[10], response[11]]);
+        let granted = u32::from_be_bytes([response[12], response[13], response[14], response[15]]);
+
+        Ok(Mapping {
+            gateway: Gateway::NatPmp(gateway),
+            external: SocketAddr::new(IpAddr::V4(external_ip), external_port),
+            lease: Duration::from_secs(granted.into()),
+        })
+    }
+
+    /// Removes the mapping of `port`: the RFC wants both the suggested
+    /// external port and the lifetime zeroed for a delete.
+    pub(super) async fn unmap(gateway: SocketAddr, port: u16) -> Result<()> {
+        let socket = connect(gateway).await?;
+        request(&socket, &map_request(port, 0, Duration::ZERO), 16).await.map(|_| ())
+    }
+
+    async fn connect(gateway: SocketAddr) -> Result<UdpSocket> {
+        let socket = UdpSocket::bind("0.0.0.0:0").await?;
+        socket.connect(gateway).await?;
+        Ok(socket)
+    }
+
+    fn map_request(internal_port: u16, external_port: u16, lease: Duration) -> Vec<u8> {
+        let mut message = vec![0, OP_MAP_TCP, 0, 0];
+        message.extend_from_slice(&internal_port.to_be_bytes());
+        message.extend_from_slice(&external_port.to_be_bytes());
+        message.extend_from_slice(&(lease.as_secs() as u32).to_be_bytes());
+        message
+    }
+
+    /// Sends `message` with the RFC's backoff, returning a successful
+    /// response of at least `len` bytes.
+    async fn request(socket: &UdpSocket, message: &[u8], len: usize) -> Result<Vec<u8>> {
+        let mut wait = Duration::from_millis(250);
+        let mut buffer = [0u8; 64];
+        for _ in 0..4 {
+            socket.send(message).await?;
+            if let Ok(received) = timeout(wait, socket.recv(&mut buffer)).await {
+                let n = received?;
+                if n < len || buffer[1] != message[1] | 0x80 {
+                    bail!("malformed NAT-PMP response");
+                }
+                let result = u16::from_be_bytes([buffer[2], buffer[3]]);
+                if result != 0 {
+                    bail!("gateway refused the NAT-PMP request (result {})", result);
+                }
+                return Ok(buffer[..n].to_vec());
+            }
+            wait *= 2;
+        }
+        bail!("gateway doesn't answer NAT-PMP")
+    }
+}
+
+/// UPnP Internet Gateway Device, through igd-next.
+mod upnp {
+    use super::*;
+    use igd_next::aio::tokio::{search_gateway, Tokio};
+    use igd_next::{AddPortError, PortMappingProtocol, SearchOptions};
+
+    pub(super) type Device = igd_next::aio::Gateway<Tokio>;
+
+    /// Finds the gateway with SSDP.
+    pub(super) async fn discover() -> Result<Box<Device>> {
+        let options = SearchOptions {
+            timeout: Some(UPNP_SEARCH_TIMEOUT),
+            ..SearchOptions::default()
+        };
+        Ok(Box::new(search_gateway(options).await?))
+    }
+
+    pub(super) async fn map(device: Box<Device>, local_ip: Ipv4Addr, port: u16, lease: Duration) -> Result<Mapping> {
+        let external_ip = device.get_external_ip().await?;
+        let local = SocketAddr::new(IpAddr::V4(local_ip), port);
+        let add = |lease: u32| device.add_port(PortMappingProtocol::TCP, port, local, lease, MAPPING_DESCRIPTION);
+        // Some gateways only take permanent mappings; those are removed on
+        // shutdown instead of expiring
+        let lease = match add(lease.as_secs() as u32).await {
+            Ok(()) => lease,
+            Err(AddPortError::OnlyPermanentLeasesSupported) => {
+                add(0).await?;
+                LEASE
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Mapping {
+            gateway: Gateway::Upnp(device),
+            external: SocketAddr::new(external_ip, port),
+            lease,
+        })
+    }
+
+    pub(super) async fn unmap(device: &Device, port: u16) -> Result<()> {
+        Ok(device.remove_port(PortMappingProtocol::TCP, port).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn carrier_grade_nat_addresses_are_told_apart() {
+        assert!(is_shared_address(Ipv4Addr::new(100, 64, 0, 1)));
+        assert!(is_shared_address(Ipv4Addr::new(100, 127, 255, 254)));
+        assert!(!is_shared_address(Ipv4Addr::new(100, 128, 0, 1)));
+        assert!(!is_shared_address(Ipv4Addr::new(203, 0, 113, 7)));
+    }
+}
@@ -0,0 +1,493 @@
+use crate::config::{AppConfig, PrivacyConfig};
+use crate::history::TransferRecord;
+use crate::protocol::ServerMessage;
+use crate::schedule::ScheduledTransfer;
+use sha2::{Digest, Sha256};
+
+/// Applies the configured path redaction to a message about to be sent to
+/// a client. Works on an owned copy, so the records it came from are
+/// untouched. Hostnames are left alone here: clients address peers by them,
+/// so hashing is only for exports.
+pub fn redact(message: ServerMessage, privacy: &PrivacyConfig) -> ServerMessage {
+    if !privacy.redact_paths {
+        return message;
+    }
+
+    let path = |p: String| redact_path(&p);
+    let text = |t: String| redact_paths_in(&t);
+    let schedule = |mut s: ScheduledTransfer| {
+        s.file_path = path(s.file_path);
+        s.last_error = s.last_error.map(text);
+        s
+    };
+
+    match message {
+        ServerMessage::LocalInfo {
+            peer_id,
+            hostname,
+            fingerprint,
+            downloads_dir,
+            downloads_free_bytes,
+            downloads_total_bytes,
+            port_mapping,
+            discovery_port,
+            transfer_port,
+            web_port,
+            advertised_addresses,
+            version,
+            uptime_secs,
+            services,
+            bandwidth,
+        } => ServerMessage::LocalInfo {
+            peer_id,
+            hostname,
+            fingerprint,
+            downloads_dir: path(downloads_dir),
+            downloads_free_bytes,
+            downloads_total_bytes,
+            port_mapping,
+            discovery_port,
+            transfer_port,
+            web_port,
+            advertised_addresses,
+            version,
+            uptime_secs,
+            services,
+            bandwidth,
+        },
+        ServerMessage::FileTransferRequest {
+            transfer_id,
+            peer_id,
+            filename,
+            file_path,
+            file_size,
+            file_checksum,
+            mime_type,
+            detected_mime_type,
+            origin,
+        } => ServerMessage::FileTransferRequest {
+            transfer_id,
+            peer_id,
+            filename,
+            file_path: path(file_path),
+            file_size,
+            file_checksum,
+            mime_type,
+            detected_mime_type,
+            origin,
+        },
+        ServerMessage::BroadcastTransferStart {
+            transfer_id,
+            filename,
+            file_path,
+            file_size,
+            total_peers,
+            file_checksum,
+            mime_type,
+            detected_mime_type,
+            excluded_peers,
+            origin,
+        } => ServerMessage::BroadcastTransferStart {
+            transfer_id,
+            filename,
+            file_path: path(file_path),
+            file_size,
+            total_peers,
+            file_checksum,
+            mime_type,
+            detected_mime_type,
+            excluded_peers,
+            origin,
+        },
+        ServerMessage::BroadcastDryRunResult {
+            file_path,
+            file_size,
+            peers,
+            excluded_peers,
+        } => ServerMessage::BroadcastDryRunResult {
+            file_path: path(file_path),
+            file_size,
+            peers,
+            excluded_peers,
+        },
+        ServerMessage::FileReceived {
+            transfer_id,
+            from_peer_id,
+            from_hostname,
+            filename,
+            saved_path,
+            relative_path,
+            file_size,
+            mime_type,
+            verified,
+        } => ServerMessage::FileReceived {
+            transfer_id,
+            from_peer_id,
+            from_hostname,
+            filename,
+            saved_path: path(saved_path),
+            // Inside downloads, so it's no more than the name and its folder
+            relative_path,
+            file_size,
+            mime_type,
+            verified,
+        },
+        ServerMessage::FileTransferError {
+            transfer_id,
+            peer_id,
+            message,
+            code,
+            peer_space,
+        } => ServerMessage::FileTransferError {
+            transfer_id,
+            peer_id,
+            message: text(message),
+            code,
+            peer_space,
+        },
+        ServerMessage::TransferScheduled { schedule: s } => ServerMessage::TransferScheduled { schedule: schedule(s) },
+        ServerMessage::ScheduledTransfers { schedules } => ServerMessage::ScheduledTransfers {
+            schedules: schedules.into_iter().map(schedule).collect(),
+        },
+        ServerMessage::SyncStatus { pairs } => ServerMessage::SyncStatus {
+            pairs: pairs
+                .into_iter()
+                .map(|mut pair| {
+                    pair.path = path(pair.path);
+                    pair.last_error = pair.last_error.map(text);
+                    pair
+                })
+                .collect(),
+        },
+        ServerMessage::TransferTrace {
+            transfer_id,
+            active,
+            events,
+        } => ServerMessage::TransferTrace {
+            transfer_id,
+            active,
+            events: events
+                .into_iter()
+                .map(|mut event| {
+                    event.detail = event.detail.map(text);
+                    event
+                })
+                .collect(),
+        },
+        ServerMessage::MaintenanceReport { mut report } => {
+            for leftover in &mut report.removed {
+                leftover.path = path(std::mem::take(&mut leftover.path));
+            }
+            ServerMessage::MaintenanceReport { report }
+        }
+        ServerMessage::VerificationResult {
+            transfer_id,
+            relative_path,
+            matches,
+            expected,
+            actual,
+        } => ServerMessage::VerificationResult {
+            transfer_id,
+            relative_path: path(relative_path),
+            matches,
+            expected,
+            actual,
+        },
+        ServerMessage::VerificationError {
+            transfer_id,
+            relative_path,
+            code,
+            message,
+        } => ServerMessage::VerificationError {
+            transfer_id,
+            relative_path: relative_path.map(path),
+            code,
+            message: text(message),
+        },
+        ServerMessage::TransferStats {
+            transfer_id,
+            status,
+            progress,
+            total,
+            speed_bytes_per_sec,
+            eta_seconds,
+            start_time,
+            end_time,
+            duration_seconds,
+            average_speed_bytes_per_sec,
+            verified,
+            error,
+        } => ServerMessage::TransferStats {
+            transfer_id,
+            status,
+            progress,
+            total,
+            speed_bytes_per_sec,
+            eta_seconds,
+            start_time,
+            end_time,
+            duration_seconds,
+            average_speed_bytes_per_sec,
+            verified,
+            error: error.map(text),
+        },
+        ServerMessage::Error { message } => ServerMessage::Error { message: text(message) },
+        other => other,
+    }
+}
+
+/// What an export leaves out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Redaction {
+    pub paths: bool,
+    pub hostnames: bool,
+}
+
+impl Redaction {
+    /// Everything, when the export asks for redaction; nothing when it asks
+    /// for the raw records; otherwise what the config says.
+    pub fn for_export(privacy: &PrivacyConfig, requested: Option<bool>) -> Self {
+        match requested {
+            Some(redact) => Self {
+                paths: redact,
+                hostnames: redact,
+            },
+            None => Self {
+                paths: privacy.redact_paths,
+                hostnames: privacy.hash_hostnames,
+            },
+        }
+    }
+}
+
+/// Redacts a copy of a history record for export. `hostnames` are the names
+/// to hash wherever they appear in free text, such as accept rule names.
+pub fn redact_record(record: TransferRecord, redaction: Redaction, hostnames: &[String]) -> TransferRecord {
+    let text = |t: String| {
+        let t = if redaction.paths { redact_paths_in(&t) } else { t };
+        if redaction.hostnames { hash_hostnames_in(&t, hostnames) } else { t }
+    };
+    TransferRecord {
+        peer_hostname: if redaction.hostnames {
+            hash_hostname(&record.peer_hostname)
+        } else {
+            record.peer_hostname
+        },
+        file_path: if redaction.paths {
+            redact_path(&record.file_path)
+        } else {
+            record.file_path
+        },
+        accept_rule: record.accept_rule.map(text),
+        error: record.error.map(text),
+        trace: record
+            .trace
+            .into_iter()
+            .map(|mut event| {
+                event.detail = event.detail.map(text);
+                event
+            })
+            .collect(),
+        ..record
+    }
+}
+
+/// Field names whose values are never shown by GetConfig. Webhook URLs
+/// often carry a token in their path, so they're masked too.
+const SECRET_MARKERS: &[&str] = &["token", "secret", "password", "private_key", "url"];
+const MASK: &str = "********";
+
+/// The config as GetConfig shows it, with any secrets masked.
+pub fn masked_config(config: &AppConfig) -> serde_json::Value {
+    let mut value = serde_json::to_value(config).unwrap_or_default();
+    mask_secrets(&mut value);
+    value
+}
+
+fn mask_secrets(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                let name = name.to_lowercase();
+                if SECRET_MARKERS.iter().any(|marker| name.contains(marker)) {
+                    if !field.is_null() {
+                        *field = MASK.into();
+                    }
+                } else {
+                    mask_secrets(field);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(mask_secrets),
+        _ => {}
+    }
+}
+
+/// Keeps only the last component of a path.
+fn redact_path(path: &str) -> String {
+    path.trim_end_matches(['/', '\\'])
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Cuts every absolute path in free text, such as an error message, down
+/// to its last component.
+fn redact_paths_in(text: &str) -> String {
+    text.split(' ')
+        .map(|word| {
+            let path = word
+                .trim_start_matches(['"', '\'', '('])
+                .trim_end_matches(['"', '\'', ')', ',', ';', ':', '.']);
+            if looks_like_path(path) {
+                word.replacen(path, &redact_path(path), 1)
+            } else {
+                word.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn looks_like_path(word: &str) -> bool {
+    let bytes = word.as_bytes();
+    let windows = bytes.len() > 3 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' && matches!(bytes[2], b'\\' | b'/');
+    (word.len() > 1 && (word.starts_with('/') || word.starts_with("~/"))) || windows
+}
+
+fn hash_hostnames_in(text: &str, hostnames: &[String]) -> String {
+    hostnames
+        .iter()
+        .filter(|hostname| !hostname.is_empty())
+        .fold(text.to_string(), |text, hostname| text.replace(hostname.as_str(), &hash_hostname(hostname)))
+}
+
+/// Stable short stand-in for a hostname, so peers stay distinguishable.
+fn hash_hostname(hostname: &str) -> String {
+    let digest = Sha256::digest(hostname.as_bytes());
+    format!("host-{}", hex::encode(&digest[..4]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PeerInfo;
+    use uuid::Uuid;
+
+    fn redacting_everything() -> PrivacyConfig {
+        PrivacyConfig {
+            redact_paths: true,
+            hash_hostnames: true,
+        }
+    }
+
+    #[test]
+    fn live_messages_keep_hostnames() {
+        let peer = crate::peer::Peer::from_discovery(Uuid::new_v4(), "10.0.0.2:8081".parse().unwrap(), "alice-laptop".to_string(), None);
+        let message = redact(
+            ServerMessage::PeersList {
+                peers: vec![PeerInfo::from(peer)],
+            },
+            &redacting_everything(),
+        );
+        let ServerMessage::PeersList { peers } = message else {
+            panic!("changed type");
+        };
+        assert_eq!(peers[0].hostname, "alice-laptop");
+    }
+
+    #[test]
+    fn paths_in_error_text_are_cut_to_the_name() {
+        let cases = [
+            ("Failed to open /home/alice/secret/plan.pdf: No such file", "Failed to open plan.pdf: No such file"),
+            ("File \"/srv/share/a.txt\" changed", "File \"a.txt\" changed"),
+            ("Cannot read C:\\Users\\alice\\notes.txt.", "Cannot read notes.txt."),
+            ("Missing ~/Documents/report.docx", "Missing report.docx"),
+            ("Directory /home/alice/photos/ is empty", "Directory photos is empty"),
+            ("Transfer ended after 10 of 20 bytes", "Transfer ended after 10 of 20 bytes"),
+            ("Files of type application/x-msdownload are not accepted", "Files of type application/x-msdownload are not accepted"),
+            ("ratio 1/2 reached", "ratio 1/2 reached"),
+        ];
+        for (message, expected) in cases {
+            let redacted = redact(ServerMessage::Error { message: message.to_string() }, &redacting_everything());
+            let ServerMessage::Error { message: redacted } = redacted else {
+                panic!("changed type");
+            };
+            assert_eq!(redacted, expected);
+        }
+
+        let untouched = redact(
+            ServerMessage::Error {
+                message: "Failed to open /home/alice/plan.pdf".to_string(),
+            },
+            &PrivacyConfig::default(),
+        );
+        assert!(matches!(untouched, ServerMessage::Error { message } if message.contains("/home/alice")));
+    }
+
+    fn record() -> TransferRecord {
+        let mut record = TransferRecord::new(
+            Uuid::new_v4(),
+            None,
+            "alice-laptop".to_string(),
+            "plan.pdf".to_string(),
+            "/home/me/work/plan.pdf".to_string(),
+            10,
+            "sent".to_string(),
+        );
+        record.accept_rule = Some("anything from alice-laptop".to_string());
+        record
+    }
+
+    #[test]
+    fn exports_are_redacted_as_asked() {
+        let hostnames = vec!["alice-laptop".to_string()];
+        let hash = hash_hostname("alice-laptop");
+
+        let redaction = Redaction::for_export(&PrivacyConfig::default(), Some(true));
+        let redacted = redact_record(record(), redaction, &hostnames);
+        assert_eq!(redacted.file_path, "plan.pdf");
+        assert_eq!(redacted.peer_hostname, hash);
+        assert_eq!(redacted.accept_rule, Some(format!("anything from {}", hash)));
+
+        // Asking for the raw records overrides the config
+        let redaction = Redaction::for_export(&redacting_everything(), Some(false));
+        let raw = redact_record(record(), redaction, &hostnames);
+        assert_eq!(raw.file_path, "/home/me/work/plan.pdf");
+        assert_eq!(raw.peer_hostname, "alice-laptop");
+
+        // Otherwise the config decides, one flag at a time
+        let paths_only = PrivacyConfig {
+            redact_paths: true,
+            hash_hostnames: false,
+        };
+        let partial = redact_record(record(), Redaction::for_export(&paths_only, None), &hostnames);
+        assert_eq!(partial.file_path, "plan.pdf");
+        assert_eq!(partial.peer_hostname, "alice-laptop");
+        assert!(hash.starts_with("host-") && hash != hash_hostname("bob-desktop"));
+    }
+
+    #[test]
+    fn secrets_are_masked_in_the_config_view() {
+        let mut value = serde_json::json!({
+            "network": {"auth_token": "abc123", "web_port": 8080},
+            "relay": {"shared_secret": "hunter2", "allowed_peers": ["laptop"]},
+            "peers": [{"password": "pw", "name": "x"}],
+            "unset": {"api_token": null},
+            "webhooks": [{"url": "http://hooks.local/T0/abc", "events": []}]
+        });
+        mask_secrets(&mut value);
+        assert_eq!(value["network"]["auth_token"], MASK);
+        assert_eq!(value["network"]["web_port"], 8080);
+        assert_eq!(value["relay"]["shared_secret"], MASK);
+        assert_eq!(value["relay"]["allowed_peers"][0], "laptop");
+        assert_eq!(value["peers"][0]["password"], MASK);
+        assert_eq!(value["peers"][0]["name"], "x");
+        assert!(value["unset"]["api_token"].is_null());
+        assert_eq!(value["webhooks"][0]["url"], MASK);
+
+        let config = masked_config(&AppConfig::default());
+        assert_eq!(config["network"]["web_port"], AppConfig::default().network.web_port);
+    }
+}
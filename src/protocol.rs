@@ -1,172 +1,1485 @@
+//! Messages exchanged with clients over the WebSocket API, as JSON tagged
+//! by `type`.
+
+use crate::active::SpeedSample;
+use crate::analytics::HistoryAnalytics;
+use crate::bandwidth::BandwidthLimits;
+use crate::config::{AcceptAction, AcceptRule, SyncPair, WebhookEvent};
+use crate::history::TransferRecord;
+use crate::maintenance::MaintenanceReport;
 use crate::peer::Peer;
+use crate::rendezvous::Connectivity;
+use crate::schedule::{MissedPolicy, ScheduledTransfer};
+use crate::trace::TraceEvent;
+use crate::usage::{UsageGrouping, UsageRow};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use uuid::Uuid;
-use chrono;
 
+/// A request from a client.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ClientMessage {
-    GetPeers,
+    /// Lists known peers, by hostname unless sorted otherwise.
+    GetPeers {
+        /// What to order them by; ties go by hostname, then id.
+        #[serde(default)]
+        sort_by: Option<PeerSort>,
+        /// Largest first instead.
+        #[serde(default)]
+        descending: bool,
+    },
+    /// Adds the node at `address` ("host" or "host:port") as a peer, for
+    /// nodes discovery can't see, such as ones on another subnet.
+    AddPeer {
+        /// Where the node listens.
+        address: String,
+    },
+    /// Sends a file to a peer.
     SendFile {
+        /// Who gets it.
         peer_id: Uuid,
+        /// Local path of the file.
         file_path: String,
+        /// Send through this peer when we can't reach `peer_id` ourselves.
+        #[serde(default)]
+        relay_peer_id: Option<Uuid>,
+        /// Record a debug trace of this transfer (see GetTransferTrace).
+        #[serde(default)]
+        debug: bool,
+    },
+    /// Sends several files to a peer as one zip archive, made as it goes
+    /// so nothing is written to disk first. Answered like SendFile, with a
+    /// `file_size` of 0: the size is only known once it has all been sent.
+    SendArchive {
+        /// Who gets it.
+        peer_id: Uuid,
+        /// Local paths of the files; each goes in under its own name.
+        file_paths: Vec<String>,
+        /// What to call the archive; ".zip" is added if missing.
+        archive_name: String,
+    },
+    /// Sends several files to a peer one after another over one connection,
+    /// each kept as a file of its own. Answered with SessionTransferStart;
+    /// each file then reports as a send of its own would.
+    SendFiles {
+        /// Who gets them.
+        peer_id: Uuid,
+        /// Local paths of the files, in the order they go.
+        file_paths: Vec<String>,
     },
+    /// Sends a file at `at_unix` (seconds since the epoch), repeating as
+    /// `recurring` says ("daily", "6h", ...) when set.
+    ScheduleTransfer {
+        /// Who gets it.
+        peer_id: Uuid,
+        /// Local path of the file.
+        file_path: String,
+        /// First run, in seconds since the Unix epoch.
+        at_unix: u64,
+        /// How often to repeat; once when unset.
+        #[serde(default)]
+        recurring: Option<String>,
+        /// Whether a run missed while the daemon was off goes out on startup
+        /// ("run_now") or is dropped ("skip").
+        #[serde(default)]
+        missed: MissedPolicy,
+    },
+    /// Lists pending scheduled transfers.
+    GetScheduledTransfers,
+    /// Drops a scheduled transfer.
+    CancelScheduledTransfer {
+        /// The schedule to drop.
+        schedule_id: Uuid,
+    },
+    /// Sends every file under a folder to a peer.
     SendDirectory {
+        /// Who gets it.
         peer_id: Uuid,
+        /// Local path of the folder.
         dir_path: String,
     },
+    /// Sends to every peer but those in `exclude_peer_ids`.
     BroadcastFile {
+        /// Local path of the file.
         file_path: String,
+        /// Peers to leave out.
+        #[serde(default)]
+        exclude_peer_ids: Option<Vec<Uuid>>,
+        /// Only check which peers would take a connection, answered with
+        /// BroadcastDryRunResult; nothing is sent.
+        #[serde(default)]
+        dry_run: bool,
     },
+    /// Sends a folder to every peer but those in `exclude_peer_ids`.
     BroadcastDirectory {
+        /// Local path of the folder.
         dir_path: String,
+        /// Peers to leave out.
+        #[serde(default)]
+        exclude_peer_ids: Option<Vec<Uuid>>,
     },
+    /// Asks who this node is.
     GetLocalInfo,
+    /// Everything worth attaching to a bug report, in one reply.
+    GetSystemInfo,
+    /// Sends a chat message to one peer, or to all when `peer_id` is unset.
     SendChat {
+        /// Who gets it; everyone when unset.
         peer_id: Option<Uuid>,
+        /// The text.
         message: String,
     },
+    /// Lists finished transfers.
     GetTransferHistory,
+    /// Throughput samples of a running transfer, or of a finished one when
+    /// `transfer.keep_speed_samples` is on.
+    GetTransferSamples {
+        /// The transfer asked about.
+        transfer_id: Uuid,
+    },
+    /// Re-hashes a finished download and compares it with the checksum it
+    /// arrived with. Give the transfer, or the file's path relative to the
+    /// downloads folder (for a file that was moved there).
+    VerifyDownload {
+        /// The transfer that received the file.
+        #[serde(default)]
+        transfer_id: Option<Uuid>,
+        /// The file, relative to the downloads folder.
+        #[serde(default)]
+        relative_path: Option<String>,
+    },
+    /// Removes leftover partial downloads, sync staging files and expired
+    /// quarantine now; with `dry_run`, only reports what would go.
+    RunMaintenance {
+        /// Report without removing anything.
+        #[serde(default)]
+        dry_run: bool,
+    },
+    /// Debug trace of a traced transfer, running or failed.
+    GetTransferTrace {
+        /// The transfer asked about.
+        transfer_id: Uuid,
+    },
+    /// Full history records for sharing, redacted as `redact` says: all of
+    /// it, none of it, or as configured when unset.
+    ExportHistory {
+        /// Whether to redact; as configured when unset.
+        #[serde(default)]
+        redact: Option<bool>,
+    },
+    /// Drops finished transfers from history: all but the newest
+    /// `keep_last`, and those that finished over `older_than_days` ago.
+    PruneHistory {
+        /// How many of the newest to keep.
+        #[serde(default)]
+        keep_last: Option<usize>,
+        /// Age in days past which they go.
+        #[serde(default)]
+        older_than_days: Option<u32>,
+    },
+    /// Changes how many finished transfers history keeps, pruning at once if
+    /// lowered; saved to the config file.
+    SetHistoryMaxEntries {
+        /// The new cap.
+        max_entries: usize,
+    },
+    /// The running configuration, with secrets masked.
+    GetConfig,
+    /// Lists running and queued transfers.
+    GetActiveTransfers,
+    /// Bytes moved per local day or per peer, counted as transfers go.
+    GetBandwidthUsage {
+        /// First day counted; all kept when unset.
+        #[serde(default)]
+        since: Option<chrono::NaiveDate>,
+        /// "day" or "peer".
+        #[serde(default)]
+        group_by: UsageGrouping,
+    },
+    /// Charts of finished transfers: bytes by peer, when transfers happen,
+    /// why they fail and how fast they've been each week.
+    GetHistoryAnalytics {
+        /// Only transfers started then or later, in seconds since the Unix
+        /// epoch; all of them when unset.
+        #[serde(default)]
+        since_unix: Option<i64>,
+    },
+    /// Changes how many transfers may run at once in each direction, from
+    /// 1 to 256; an unset one is left as it is. A lowered limit takes effect
+    /// as running transfers finish rather than stopping any.
+    SetTransferLimits {
+        /// The new send limit.
+        #[serde(default)]
+        max_concurrent_sends: Option<usize>,
+        /// The new receive limit.
+        #[serde(default)]
+        max_concurrent_receives: Option<usize>,
+        /// Whether to save them to the config file too.
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Caps all sends together at `max_up` bytes per second, or lifts the
+    /// cap when unset. Running sends take it up from their next chunk; a
+    /// schedule window that sets its own still overrides it.
+    SetBandwidthLimit {
+        /// The new cap.
+        #[serde(default)]
+        max_up: Option<u64>,
+        /// Whether to save it to the config file too.
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Progress of a running transfer, or how a finished one went. Given a
+    /// broadcast's id, answered with BroadcastStats over all its peers.
     GetTransferStats {
+        /// The transfer asked about.
         transfer_id: Uuid,
     },
+    /// Follows a send or broadcast started from here, e.g. after
+    /// reconnecting: answered like GetTransferStats, after the latest
+    /// progress update, and followed by its live updates and outcome. A
+    /// finished transfer only gets the stats.
+    WatchTransfer {
+        /// The transfer to follow.
+        transfer_id: Uuid,
+    },
+    /// Stops a running or queued transfer.
     CancelTransfer {
+        /// The transfer to stop.
         transfer_id: Uuid,
     },
+    /// Cancels every running and queued transfer, or only those in
+    /// `direction` ("send" or "receive").
+    CancelAllTransfers {
+        /// Which direction to cancel; both when unset.
+        direction: Option<String>,
+    },
+    /// Holds a running transfer where it is.
     PauseTransfer {
+        /// The transfer to hold.
         transfer_id: Uuid,
     },
+    /// Lets a paused transfer carry on.
     ResumeTransfer {
+        /// The transfer to resume.
+        transfer_id: Uuid,
+    },
+    /// Trusts a peer's new identity fingerprint after a PeerIdentityChanged alert.
+    AcceptPeerIdentity {
+        /// The peer whose new identity is trusted.
+        peer_id: Uuid,
+    },
+    /// Lists the rules deciding which incoming files are taken.
+    GetAcceptRules,
+    /// Replaces the accept rules; they are saved to the config file.
+    SetAcceptRules {
+        /// The new rules, first match wins.
+        rules: Vec<AcceptRule>,
+    },
+    /// How each synced folder is doing.
+    GetSyncStatus,
+    /// How each webhook's deliveries are going.
+    GetWebhookStatus,
+    /// Adds a synced folder, replacing any of the same name; saved to the
+    /// config file.
+    AddSyncPair {
+        /// The folder and the peer it syncs with.
+        pair: SyncPair,
+    },
+    /// Stops syncing a folder; saved to the config file.
+    RemoveSyncPair {
+        /// Name of the synced folder.
+        name: String,
+    },
+    /// Lists a folder of a peer's share; "" is the root.
+    BrowsePeerShare {
+        /// Whose share.
+        peer_id: Uuid,
+        /// Folder within the share.
+        #[serde(default)]
+        path: String,
+    },
+    /// Checks that a peer's transfer port takes a connection, without
+    /// sending anything.
+    CheckPeer {
+        /// The peer checked.
+        peer_id: Uuid,
+    },
+    /// Asks a peer how much it can take.
+    QueryPeerSpace {
+        /// The peer asked.
+        peer_id: Uuid,
+    },
+    /// Measures the link to a peer with generated data, one way and then
+    /// the other, without either side touching disk. The result is kept in
+    /// history.
+    BenchmarkPeer {
+        /// The peer measured.
+        peer_id: Uuid,
+        /// Seconds each way, up to 60; 5 if unset.
+        #[serde(default)]
+        duration_secs: Option<u64>,
+        /// Most bytes sent each way; 1 GiB if unset.
+        #[serde(default)]
+        payload_size: Option<u64>,
+    },
+    /// Downloads a file from a peer's share into our downloads folder.
+    DownloadFromPeer {
+        /// Whose share.
+        peer_id: Uuid,
+        /// The file, relative to the share's root.
+        relative_path: String,
+    },
+    /// Answers an IncomingTransferRequest.
+    RespondToTransfer {
+        /// The transfer waiting for an answer.
         transfer_id: Uuid,
+        /// Whether to take the file.
+        accept: bool,
+    },
+    /// Names this connection in logs, client listings and the transfers it
+    /// starts; until then it's known by a generated label.
+    RegisterClient {
+        /// What to call it, e.g. "desktop".
+        name: String,
+        /// What sort of client it is, e.g. "browser" or "script".
+        #[serde(default)]
+        kind: Option<String>,
     },
+    /// Lists the connected clients.
+    GetClients,
+    /// Checks the connection; answered with Pong.
     Ping,
 }
 
+/// A reply or event sent to clients.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
+    /// Answers GetPeers.
     PeersList {
+        /// Every known peer.
         peers: Vec<PeerInfo>,
     },
+    /// Answers GetLocalInfo.
     LocalInfo {
+        /// This node's id.
         peer_id: Uuid,
+        /// The name peers see.
         hostname: String,
+        /// Fingerprint of this node's identity key.
+        fingerprint: String,
+        /// Where received files go.
+        downloads_dir: String,
+        /// Free space on the downloads folder's disk, if it could tell.
+        downloads_free_bytes: Option<u64>,
+        /// Size of the downloads folder's disk, if it could tell.
+        downloads_total_bytes: Option<u64>,
+        /// How reachable this node is from outside the network.
+        port_mapping: PortMappingStatus,
+        /// The bound discovery port, which is the one picked when
+        /// `network.discovery_port` is 0.
+        #[serde(default)]
+        discovery_port: Option<u16>,
+        /// The bound transfer port, likewise.
+        #[serde(default)]
+        transfer_port: Option<u16>,
+        /// The bound web port, likewise.
+        #[serde(default)]
+        web_port: Option<u16>,
+        /// Transfer addresses announced to peers: the LAN one, and the
+        /// router's while a port mapping is up.
+        #[serde(default)]
+        advertised_addresses: Vec<SocketAddr>,
+        /// This build's version.
+        #[serde(default)]
+        version: String,
+        /// Seconds since the node started.
+        #[serde(default)]
+        uptime_secs: u64,
+        /// Each background service's state, as in ServiceStatusChanged.
+        #[serde(default)]
+        services: BTreeMap<String, String>,
+        /// Speed limits in force under `bandwidth.schedule`.
+        #[serde(default)]
+        bandwidth: BandwidthLimits,
+    },
+    /// Answers GetSystemInfo.
+    SystemInfo {
+        /// The snapshot.
+        info: SystemInfo,
     },
+    /// Answers AddPeer once the node has introduced itself.
+    PeerAdded {
+        /// The node added.
+        peer: PeerInfo,
+    },
+    /// A peer appeared.
     PeerDiscovered {
+        /// The peer as now known.
+        peer: PeerInfo,
+    },
+    /// A known peer's address or name changed.
+    PeerUpdated {
+        /// The peer as now known.
         peer: PeerInfo,
     },
+    /// A peer went away.
     PeerRemoved {
+        /// The peer that left.
+        peer_id: Uuid,
+    },
+    /// A known peer presented a different identity key; transfers with it are
+    /// refused until the new fingerprint is accepted.
+    PeerIdentityChanged {
+        /// The peer.
+        peer_id: Uuid,
+        /// Its name.
+        hostname: String,
+        /// The fingerprint trusted so far.
+        previous_fingerprint: String,
+        /// The fingerprint it now presents.
+        fingerprint: String,
+    },
+    /// A peer's new identity is trusted.
+    PeerIdentityAccepted {
+        /// The peer.
+        peer_id: Uuid,
+        /// The fingerprint now trusted.
+        fingerprint: String,
+    },
+    /// Answers GetAcceptRules and SetAcceptRules.
+    AcceptRules {
+        /// The rules, first match wins.
+        rules: Vec<AcceptRule>,
+        /// What happens to files no rule matches.
+        default_action: AcceptAction,
+    },
+    /// Answers GetWebhookStatus.
+    WebhookStatus {
+        /// One per configured webhook.
+        webhooks: Vec<WebhookStatus>,
+    },
+    /// Sent on request and whenever a synced folder's progress changes.
+    SyncStatus {
+        /// One per synced folder.
+        pairs: Vec<SyncPairStatus>,
+    },
+    /// An incoming file waits for a RespondToTransfer; `rule` is the accept
+    /// rule that asked for the prompt.
+    IncomingTransferRequest {
+        /// The transfer waiting.
+        transfer_id: Uuid,
+        /// The sender, if known.
+        peer_id: Option<Uuid>,
+        /// The sender's name.
+        peer_hostname: String,
+        /// Name of the file offered.
+        filename: String,
+        /// Its size in bytes.
+        file_size: u64,
+        /// Set when the sender can't tell the size up front, as for an
+        /// archive it makes while sending; `file_size` is 0.
+        #[serde(default)]
+        size_unknown: bool,
+        /// Its type as the sender gave it.
+        mime_type: Option<String>,
+        /// The accept rule that asked for the prompt.
+        rule: Option<String>,
+    },
+    /// A prompt was answered or timed out; clients should dismiss it.
+    IncomingTransferResolved {
+        /// The transfer that was waiting.
+        transfer_id: Uuid,
+        /// Whether the file is being taken.
+        accepted: bool,
+    },
+    /// Answers BrowsePeerShare.
+    PeerShareListing {
+        /// Whose share.
+        peer_id: Uuid,
+        /// The folder listed.
+        path: String,
+        /// What's in it.
+        entries: Vec<ShareEntry>,
+    },
+    /// Answers CheckPeer.
+    PeerReachability {
+        /// What the check found.
+        result: PeerReachability,
+    },
+    /// Answers BroadcastFile with `dry_run`: which peers a broadcast would
+    /// reach.
+    BroadcastDryRunResult {
+        /// The file that would go.
+        file_path: String,
+        /// Its size in bytes.
+        file_size: u64,
+        /// One per peer it would go to.
+        peers: Vec<PeerReachability>,
+        /// Peers left out as asked.
+        excluded_peers: Vec<Uuid>,
+    },
+    /// Answers QueryPeerSpace.
+    PeerSpace {
+        /// The peer asked.
         peer_id: Uuid,
+        /// What it reported.
+        space: PeerSpace,
     },
+    /// Answers BenchmarkPeer.
+    PeerBenchmark {
+        /// The run, as kept in history.
+        benchmark_id: Uuid,
+        /// What it measured.
+        result: BenchmarkResult,
+    },
+    /// A DownloadFromPeer was sent; FileTransferComplete or FileTransferError follows.
+    ShareDownloadStarted {
+        /// The transfer fetching the file.
+        transfer_id: Uuid,
+        /// Whose share.
+        peer_id: Uuid,
+        /// The file, relative to the share's root.
+        relative_path: String,
+    },
+    /// A send started.
     FileTransferRequest {
+        /// The new transfer.
         transfer_id: Uuid,
+        /// Who gets it.
         peer_id: Uuid,
+        /// Name of the file.
         filename: String,
+        /// Local path of the file being sent; never shown to the receiver.
+        #[serde(default)]
         file_path: String,
+        /// Its size in bytes.
         file_size: u64,
+        /// SHA-256 of the file, once hashed.
         file_checksum: Option<String>,
+        /// Its type guessed from the name.
         mime_type: Option<String>,
+        /// Its type guessed from the content.
+        detected_mime_type: Option<String>,
+        /// Name of the client that started it; unset for scheduled sends.
+        #[serde(default)]
+        origin: Option<String>,
+    },
+    /// How far hashing a file has got.
+    ChecksumProgress {
+        /// The transfer or verification hashing.
+        transfer_id: Uuid,
+        /// Bytes hashed.
+        progress: u64,
+        /// Bytes to hash.
+        total: u64,
+    },
+    /// A send waits for a free send slot, `position` in line (1 is next).
+    /// Sent again as it moves up; progress follows once it starts.
+    TransferQueued {
+        /// The send.
+        transfer_id: Uuid,
+        /// Its place in line.
+        position: usize,
     },
+    /// How far a transfer has got.
     FileTransferProgress {
+        /// The transfer.
         transfer_id: Uuid,
+        /// Bytes moved.
         progress: u64,
+        /// Bytes to move.
         total: u64,
+        /// Recent throughput.
         speed_bytes_per_sec: Option<u64>,
+        /// Seconds left at that speed.
         eta_seconds: Option<u64>,
     },
+    /// A transfer finished.
     FileTransferComplete {
+        /// The transfer.
         transfer_id: Uuid,
+        /// The other side, if known.
         peer_id: Option<Uuid>,
+        /// SHA-256 of what was moved.
         file_checksum: Option<String>,
+        /// Whether the receiving end matched the checksum.
+        verified: bool,
+        /// Bytes moved.
+        #[serde(default)]
+        bytes_transferred: Option<u64>,
+        /// How long the data took to move, for sends.
+        #[serde(default)]
+        duration_ms: Option<u64>,
+        /// Average over `duration_ms`.
+        #[serde(default)]
+        speed_bytes_per_sec: Option<u64>,
+    },
+    /// A peer sent us a file and it was saved. Failed receives are told
+    /// as FileTransferError.
+    FileReceived {
+        /// The transfer.
+        transfer_id: Uuid,
+        /// Who sent it, if known.
+        from_peer_id: Option<Uuid>,
+        /// Their name, or their address when unknown.
+        from_hostname: String,
+        /// The name it arrived under.
+        filename: String,
+        /// Where it was saved.
+        saved_path: String,
+        /// That, relative to the downloads folder.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        relative_path: Option<String>,
+        /// Its size in bytes.
+        file_size: u64,
+        /// Its type, if one could be told.
+        mime_type: Option<String>,
+        /// Whether it matched the checksum the sender gave.
         verified: bool,
     },
+    /// A transfer failed.
     FileTransferError {
+        /// The transfer.
         transfer_id: Uuid,
+        /// The other side, if known.
         peer_id: Option<Uuid>,
+        /// What went wrong.
         message: String,
+        /// Machine-readable cause for errors the UI handles specially,
+        /// e.g. `incompatible_protocol_version`.
+        code: Option<String>,
+        /// What the receiver reported, for `insufficient_space` and
+        /// `file_too_large` errors.
+        #[serde(default)]
+        peer_space: Option<PeerSpace>,
     },
+    /// A broadcast started.
     BroadcastTransferStart {
+        /// The broadcast; each peer's send has its own transfer.
         transfer_id: Uuid,
+        /// Name of the file.
         filename: String,
+        /// Local path of the file.
         file_path: String,
+        /// Its size in bytes.
         file_size: u64,
+        /// Peers it's going to, after exclusions.
         total_peers: usize,
+        /// SHA-256 of the file.
         file_checksum: Option<String>,
+        /// Its type guessed from the name.
         mime_type: Option<String>,
+        /// Its type guessed from the content.
+        detected_mime_type: Option<String>,
+        /// Peers left out on purpose.
+        #[serde(default)]
+        excluded_peers: Vec<Uuid>,
+        /// Name of the client that started it.
+        #[serde(default)]
+        origin: Option<String>,
     },
+    /// Another peer of a broadcast is done.
     BroadcastTransferProgress {
+        /// The broadcast.
         transfer_id: Uuid,
+        /// Peers done, whether or not they succeeded.
         completed_peers: usize,
+        /// Peers it's going to.
         total_peers: usize,
     },
+    /// Every peer of a broadcast is done.
     BroadcastTransferComplete {
+        /// The broadcast.
         transfer_id: Uuid,
+        /// Peers that got the file.
         successful_peers: usize,
+        /// Peers that didn't.
         failed_peers: usize,
     },
+    /// Several files started going to or coming from a peer over one
+    /// connection.
+    SessionTransferStart {
+        /// The session; each file has a transfer of its own.
+        session_id: Uuid,
+        /// The other side, if known.
+        peer_id: Option<Uuid>,
+        /// "sent" or "received".
+        direction: String,
+        /// Its files, in the order they go.
+        files: Vec<ManifestEntry>,
+        /// Their sizes together.
+        total_size: u64,
+    },
+    /// Another file of a session is done.
+    SessionTransferProgress {
+        /// The session.
+        session_id: Uuid,
+        /// Files that made it.
+        completed_files: usize,
+        /// Files that didn't.
+        failed_files: usize,
+        /// Files in the session.
+        total_files: usize,
+        /// Bytes moved over all its files so far.
+        bytes_transferred: u64,
+        /// Their sizes together.
+        total_size: u64,
+    },
+    /// Every file of a session is done, or the session ended early; files
+    /// it never got to count as failed.
+    SessionTransferComplete {
+        /// The session.
+        session_id: Uuid,
+        /// Files that made it.
+        successful_files: usize,
+        /// Files that didn't.
+        failed_files: usize,
+    },
+    /// A chat message, sent or received.
     ChatMessage {
+        /// The sender.
         from_peer_id: Uuid,
+        /// The sender's name.
         from_hostname: String,
+        /// Who it was for; everyone when unset.
         to_peer_id: Option<Uuid>,
+        /// The text.
         message: String,
+        /// When it was sent, in seconds since the Unix epoch.
         timestamp: u64,
     },
+    /// A SendChat was turned away and nobody got it.
+    ChatRejected {
+        /// `message_too_long` or `rate_limited`.
+        code: String,
+        /// What went wrong.
+        message: String,
+        /// For `rate_limited`, how long until another message is accepted.
+        retry_after_ms: Option<u64>,
+    },
+    /// Answers GetTransferHistory.
     TransferHistory {
+        /// Finished transfers, oldest first.
         transfers: Vec<TransferHistoryEntry>,
     },
+    /// Answers ExportHistory.
+    HistoryExport {
+        /// Full records, redacted as asked.
+        transfers: Vec<TransferRecord>,
+    },
+    /// Answers PruneHistory and SetHistoryMaxEntries.
+    HistoryPruned {
+        /// Finished transfers dropped.
+        removed: usize,
+    },
+    /// Answers GetTransferSamples.
+    TransferSamples {
+        /// The transfer.
+        transfer_id: Uuid,
+        /// Whether the transfer is still running, so more samples will follow.
+        active: bool,
+        /// Throughput over time.
+        samples: Vec<SpeedSample>,
+    },
+    /// Answers GetTransferTrace.
+    TransferTrace {
+        /// The transfer.
+        transfer_id: Uuid,
+        /// Whether the transfer is still running.
+        active: bool,
+        /// What happened, in order.
+        events: Vec<TraceEvent>,
+    },
+    /// Answers RunMaintenance.
+    MaintenanceReport {
+        /// What was removed.
+        report: MaintenanceReport,
+    },
+    /// Preceded by ChecksumProgress for the transfer while hashing.
+    VerificationResult {
+        /// The transfer that received the file.
+        transfer_id: Uuid,
+        /// The file, relative to the downloads folder.
+        relative_path: String,
+        /// Whether the file still matches.
+        matches: bool,
+        /// The checksum it arrived with.
+        expected: String,
+        /// The checksum it has now.
+        actual: String,
+    },
+    /// A download that couldn't be checked. `code` is one of
+    /// `unknown_download`, `no_checksum`, `file_deleted`, `file_moved` or
+    /// `invalid_path`.
+    VerificationError {
+        /// The transfer asked about, if given.
+        transfer_id: Option<Uuid>,
+        /// The file asked about, if given.
+        relative_path: Option<String>,
+        /// Why it couldn't be checked.
+        code: String,
+        /// The same, for people.
+        message: String,
+    },
+    /// Answers GetConfig.
+    Config {
+        /// The configuration, with secrets masked.
+        config: serde_json::Value,
+    },
+    /// Answers GetActiveTransfers.
+    ActiveTransfers {
+        /// Running and queued transfers.
+        transfers: Vec<TransferHistoryEntry>,
+        /// Send slots taken.
+        sends: SlotUsage,
+        /// Receive slots taken.
+        receives: SlotUsage,
+    },
+    /// Answers GetBandwidthUsage.
+    BandwidthUsage {
+        /// Totals, oldest day or lowest peer id first.
+        rows: Vec<UsageRow>,
+        /// Bytes moved today, both ways.
+        today_bytes: u64,
+        /// `transfer.daily_budget_bytes`, if set.
+        daily_budget_bytes: Option<u64>,
+    },
+    /// Answers GetHistoryAnalytics.
+    HistoryAnalytics {
+        /// What the transfers add up to.
+        analytics: HistoryAnalytics,
+    },
+    /// Answers SetBandwidthLimit.
+    BandwidthLimit {
+        /// Speed limits now in force.
+        bandwidth: BandwidthLimits,
+    },
+    /// Answers SetTransferLimits.
+    TransferLimits {
+        /// Send slots taken, and the limit now in force.
+        sends: SlotUsage,
+        /// Receive slots taken, and the limit now in force.
+        receives: SlotUsage,
+    },
+    /// Answers GetTransferStats.
     TransferStats {
+        /// The transfer.
         transfer_id: Uuid,
+        /// As in TransferHistoryEntry, plus "in_progress" and "paused".
         status: String,
+        /// Bytes moved.
         progress: u64,
+        /// Bytes to move.
         total: u64,
+        /// Recent throughput, while running.
         speed_bytes_per_sec: Option<u64>,
+        /// Seconds left at that speed.
         eta_seconds: Option<u64>,
+        /// When it started.
         start_time: Option<chrono::DateTime<chrono::Utc>>,
+        /// The rest are set once the transfer has finished; `progress` is
+        /// then as far as it got.
+        end_time: Option<chrono::DateTime<chrono::Utc>>,
+        /// How long it ran.
+        duration_seconds: Option<u64>,
+        /// Average throughput over the whole transfer.
+        average_speed_bytes_per_sec: Option<u64>,
+        /// Whether a completed transfer matched its checksum.
+        verified: Option<bool>,
+        /// Why a failed transfer failed.
+        error: Option<String>,
     },
+    /// Answers GetTransferStats for a broadcast, taking its peers together.
+    BroadcastStats {
+        /// The broadcast.
+        transfer_id: Uuid,
+        /// Peers it's going to.
+        total_peers: usize,
+        /// Peers that got the file.
+        completed_peers: usize,
+        /// Peers that failed, were cancelled or declined.
+        failed_peers: usize,
+        /// Peers still being sent to, including paused ones.
+        in_progress_peers: usize,
+        /// Bytes moved, over every peer.
+        progress: u64,
+        /// Bytes to move, over every peer.
+        total: u64,
+        /// Recent throughput of the peers still running, added up.
+        speed_bytes_per_sec: Option<u64>,
+        /// Seconds until the slowest running peer is done.
+        eta_seconds: Option<u64>,
+    },
+    /// A transfer was cancelled.
     TransferCancelled {
+        /// The transfer.
         transfer_id: Uuid,
     },
+    /// Answers ScheduleTransfer.
+    TransferScheduled {
+        /// The new schedule.
+        schedule: ScheduledTransfer,
+    },
+    /// Answers GetScheduledTransfers.
+    ScheduledTransfers {
+        /// Pending schedules.
+        schedules: Vec<ScheduledTransfer>,
+    },
+    /// Answers CancelScheduledTransfer.
+    ScheduledTransferCancelled {
+        /// The schedule dropped.
+        schedule_id: Uuid,
+    },
+    /// Answers CancelAllTransfers.
+    TransfersCancelled {
+        /// What happened to each transfer.
+        results: Vec<CancelResult>,
+    },
+    /// A transfer was paused.
     TransferPaused {
+        /// The transfer.
         transfer_id: Uuid,
     },
+    /// A paused transfer carries on.
     TransferResumed {
+        /// The transfer.
         transfer_id: Uuid,
     },
+    /// A background service started, stopped or gave up.
+    ServiceStatusChanged {
+        /// Which service.
+        service: String,
+        /// "running", "restarting" or "failed".
+        status: String,
+        /// Why it stopped.
+        message: Option<String>,
+    },
+    /// Answers RegisterClient.
+    ClientRegistered {
+        /// The connection as now named.
+        client: ClientInfo,
+    },
+    /// Answers GetClients.
+    Clients {
+        /// Every connected client, including the one asking.
+        clients: Vec<ClientInfo>,
+    },
+    /// A client went away.
+    ClientDisconnected {
+        /// The connection.
+        client_id: Uuid,
+        /// What it was called.
+        name: String,
+    },
+    /// Answers Ping.
     Pong,
+    /// A request failed.
     Error {
+        /// What went wrong.
         message: String,
     },
 }
 
+/// A transfer as listed in history and among active transfers.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransferHistoryEntry {
+    /// The transfer.
     pub transfer_id: Uuid,
+    /// The other side, if known.
     pub peer_id: Option<Uuid>,
+    /// The other side's name.
     pub peer_hostname: String,
+    /// Name of the file.
     pub filename: String,
+    /// Its size in bytes.
     pub file_size: u64,
-    pub direction: String, // "sent" or "received"
-    pub status: String, // "completed", "failed", "cancelled"
+    /// How far it got.
+    pub bytes_transferred: Option<u64>,
+    /// Its type guessed from the name.
+    pub mime_type: Option<String>,
+    /// Its type guessed from the content.
+    pub detected_mime_type: Option<String>,
+    /// "sent", "received" or "benchmark".
+    pub direction: String,
+    /// "completed", "failed", "cancelled" or "rejected".
+    pub status: String,
+    /// When it started.
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// How long it ran.
     pub duration_seconds: Option<u64>,
+    /// Average throughput.
     pub speed_bytes_per_sec: Option<u64>,
+    /// Whether the data was encrypted on the wire.
+    pub encrypted: bool,
+    /// Whether what arrived matched the checksum it was sent with.
+    #[serde(default)]
+    pub verified: bool,
+    /// The accept rule that decided on an incoming file.
+    pub accept_rule: Option<String>,
+    /// Why a failed transfer failed, as a code like FileTransferError's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    /// The broadcast this send was part of.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub broadcast_id: Option<Uuid>,
+    /// Peers that broadcast left out on purpose.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub excluded_peers: Vec<Uuid>,
+    /// The multi-file session this file went in: the `transfer_id` of the
+    /// session's own entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<Uuid>,
+    /// On a session's own entry, how many files it had.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_files: Option<usize>,
+    /// Permission bits a received file was given.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_mode: Option<String>,
+    /// Where a received file was saved, relative to the downloads folder.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relative_path: Option<String>,
+    /// Bytes saved because a received file's content was stored already,
+    /// or a sent one was skipped as the receiver had it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dedup_saved_bytes: Option<u64>,
+    /// How the connection got through: direct, punched or relayed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connectivity: Option<Connectivity>,
+    /// What a benchmark measured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub benchmark: Option<BenchmarkResult>,
+}
+
+/// One entry of a peer's shared folder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShareEntry {
+    /// File or folder name.
+    pub name: String,
+    /// Size in bytes; 0 for folders.
+    pub size: u64,
+    /// Last modification, in seconds since the Unix epoch.
+    pub mtime: Option<u64>,
+    /// Whether it's a folder.
+    pub is_dir: bool,
+    /// SHA-256 of small files, so a client can tell whether it already has one.
+    pub checksum: Option<String>,
+}
+
+/// How one synced folder is doing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncPairStatus {
+    /// Name of the synced folder.
+    pub name: String,
+    /// The peer it syncs with.
+    pub peer: String,
+    /// The local folder.
+    pub path: String,
+    /// Whether the peer is reachable.
+    pub online: bool,
+    /// Whether a sync is running.
+    pub syncing: bool,
+    /// Files still to fetch or delete in the current sync.
+    pub pending: usize,
+    /// Files fetched since startup.
+    pub transferred: u64,
+    /// Local files set aside as conflicted copies since startup.
+    pub conflicts: u64,
+    /// Files that failed to sync since startup.
+    pub failed: u64,
+    /// When the last sync finished.
+    pub last_sync: Option<chrono::DateTime<chrono::Utc>>,
+    /// Why the last sync failed.
+    pub last_error: Option<String>,
+}
+
+/// A connected client.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientInfo {
+    /// The connection.
+    pub client_id: Uuid,
+    /// The registered name, or a generated label.
+    pub name: String,
+    /// What sort of client it said it is.
+    pub kind: Option<String>,
+    /// Whether it sent RegisterClient.
+    pub registered: bool,
+    /// When it connected, in seconds since the Unix epoch.
+    pub connected_since: u64,
+}
+
+/// Delivery record of one webhook since startup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookStatus {
+    /// Scheme and host only, since the rest often carries a token.
+    pub url: String,
+    /// Events it's sent.
+    pub events: Vec<WebhookEvent>,
+    /// "closed" while delivering, "open" while paused after repeated
+    /// failures, "half_open" while trying again, "invalid" for a URL that
+    /// can't be used.
+    pub circuit: String,
+    /// Events delivered.
+    pub delivered: u64,
+    /// Events that failed to deliver.
+    pub failed: u64,
+    /// Events left out because the queue was full or the circuit open.
+    pub dropped: u64,
+    /// Failures since the last delivery.
+    pub consecutive_failures: u32,
+    /// Why the last delivery failed.
+    pub last_error: Option<String>,
+    /// When a delivery was last tried.
+    pub last_attempt: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// One file of a multi-file session, as listed before any of them goes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// The file's own transfer.
+    pub transfer_id: Uuid,
+    /// Name of the file.
+    pub filename: String,
+    /// Its size in bytes.
+    pub file_size: u64,
+    /// SHA-256 of the file, when it could be hashed.
+    pub file_checksum: Option<String>,
 }
 
+/// Room a peer reported for incoming files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerSpace {
+    /// Free bytes in its downloads folder, if it could tell.
+    pub free_bytes: Option<u64>,
+    /// Largest file it accepts, if it has a limit.
+    pub max_file_size: Option<u64>,
+}
+
+impl PeerSpace {
+    /// Whether a file of `file_size` bytes fits.
+    pub fn fits(&self, file_size: u64) -> bool {
+        self.free_bytes.is_none_or(|free| file_size <= free)
+            && self.max_file_size.is_none_or(|max| file_size <= max)
+    }
+}
+
+/// The router port mapping requested with `network.upnp`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PortMappingStatus {
+    /// "disabled", "pending", "active" or "failed".
+    pub state: String,
+    /// "nat-pmp" or "upnp", while a mapping is up.
+    pub method: Option<String>,
+    /// Where peers outside the network can reach us.
+    pub external_address: Option<SocketAddr>,
+    /// Why the last attempt failed.
+    pub message: Option<String>,
+}
+
+/// What a CancelAllTransfers did to one transfer.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelResult {
+    /// The transfer.
+    pub transfer_id: Uuid,
+    /// "sent" or "received".
+    pub direction: String,
+    /// "cancelled", "stopping" (still winding down) or "not_active".
+    pub status: String,
+}
+
+/// A node's state for diagnostics, as GetSystemInfo and `/health` give it.
+/// Holds no secrets.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SystemInfo {
+    /// This build's version.
+    pub version: String,
+    /// Commit the build was made from, when built from a git checkout.
+    pub git_hash: Option<String>,
+    /// Operating system, as Rust names it.
+    pub os: String,
+    /// CPU architecture, likewise.
+    pub arch: String,
+    /// Seconds since the node started.
+    pub uptime_secs: u64,
+    /// The settings that most often explain a report.
+    pub config: ConfigSummary,
+    /// Peers known now.
+    pub peers: usize,
+    /// Transfers running or queued.
+    pub active_transfers: usize,
+    /// Clients connected to the API.
+    pub websocket_clients: usize,
+    /// Transfers in history, running and finished.
+    pub history_entries: usize,
+    /// Free space on the downloads folder's disk, if it could tell.
+    pub downloads_free_bytes: Option<u64>,
+    /// Each background service's state and last error.
+    pub services: BTreeMap<String, ServiceHealth>,
+}
+
+/// The parts of the configuration GetSystemInfo reports.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigSummary {
+    /// Bound discovery port, or the configured one before binding.
+    pub discovery_port: u16,
+    /// Bound transfer port, likewise.
+    pub transfer_port: u16,
+    /// Bound web port, likewise.
+    pub web_port: u16,
+    /// Bytes per chunk sent.
+    pub chunk_size: usize,
+    /// Send slots in use and their limit.
+    pub sends: SlotUsage,
+    /// Receive slots in use and their limit.
+    pub receives: SlotUsage,
+    /// Speed limits in force.
+    pub bandwidth: BandwidthLimits,
+}
+
+/// A background service's state.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServiceHealth {
+    /// As in ServiceStatusChanged.
+    pub status: String,
+    /// Why it last failed, if it ever has.
+    pub last_error: Option<String>,
+}
+
+/// Whether a peer's transfer port took a connection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PeerReachability {
+    /// The peer checked.
+    pub peer_id: Uuid,
+    /// Its name.
+    pub hostname: String,
+    /// Where it was reached, which may be another of its addresses.
+    pub address: Option<String>,
+    /// Whether it took the connection and, if it answers probes, answered.
+    pub reachable: bool,
+    /// Milliseconds to connect.
+    pub connect_ms: Option<f64>,
+    /// Milliseconds for a probe and its answer, from peers that answer them.
+    pub rtt_ms: Option<f64>,
+    /// Why it isn't reachable.
+    pub error: Option<String>,
+}
+
+/// What BenchmarkPeer measured.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BenchmarkResult {
+    /// The peer measured.
+    pub peer_id: Uuid,
+    /// Shortest round trip of a request and its answer, in milliseconds.
+    pub rtt_ms: f64,
+    /// Bytes per chunk we sent.
+    pub chunk_size: usize,
+    /// From us to the peer, as the peer received it.
+    pub upload: BenchmarkLeg,
+    /// From the peer to us.
+    pub download: BenchmarkLeg,
+}
+
+/// One direction of a benchmark, as the receiving side measured it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct BenchmarkLeg {
+    /// Payload bytes that arrived.
+    pub bytes: u64,
+    /// Chunks they came in.
+    pub chunks: u64,
+    /// From the handshake to the last chunk.
+    pub elapsed_ms: u64,
+    /// Payload bytes per second.
+    pub bytes_per_sec: u64,
+    /// Share of the time spent decoding chunk messages rather than waiting
+    /// on the network, in percent; high means this tool is the bottleneck.
+    pub chunk_overhead_percent: f64,
+}
+
+/// How many of a direction's concurrent transfer slots are taken.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SlotUsage {
+    /// Slots taken.
+    pub active: usize,
+    /// Slots there are.
+    pub limit: usize,
+}
+
+/// A peer as clients see it.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerInfo {
+    /// The peer's id.
     pub id: Uuid,
+    /// Where it's reached.
     pub address: SocketAddr,
+    /// Its name.
     pub hostname: String,
+    /// Fingerprint of its identity key, once known.
+    pub fingerprint: Option<String>,
+    /// Whether it presented a key other than the trusted one.
+    pub identity_changed: bool,
+    /// Other addresses the peer announced, e.g. a router port mapping.
+    #[serde(default)]
+    pub alt_addresses: Vec<SocketAddr>,
+    /// Every LAN address it announced, in the order they're tried.
+    #[serde(default)]
+    pub addresses: Vec<SocketAddr>,
+    /// Where it last answered, tried first.
+    #[serde(default)]
+    pub reached_at: Option<SocketAddr>,
+    /// Added by hand with AddPeer or `network.peers`.
+    #[serde(default)]
+    pub manual: bool,
+    /// When it was last heard from, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub last_seen: u64,
+    /// Milliseconds the last connection to it took to open, once there's
+    /// been one.
+    #[serde(default)]
+    pub latency_ms: Option<u64>,
+    /// Heard from recently; a quiet manual peer stays listed but isn't.
+    #[serde(default)]
+    pub online: bool,
+    /// Its identity key was pinned on first contact.
+    #[serde(default)]
+    pub pinned: bool,
+    /// Pinned, and still presenting that key.
+    #[serde(default)]
+    pub trusted: bool,
+    /// Every file it sends is rejected by the accept rules. Only filled in
+    /// PeersList.
+    #[serde(default)]
+    pub blocked: bool,
+    /// Optional features it announced, e.g. "share" or "relay"; empty for
+    /// peers that predate them.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// How the last connection to it got through; unset until there's
+    /// been one.
+    #[serde(default)]
+    pub connectivity: Option<Connectivity>,
+}
+
+/// What GetPeers orders peers by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerSort {
+    /// Name, case-insensitively.
+    Hostname,
+    /// When each was last heard from.
+    LastSeen,
+    /// How quickly each answered; those never connected to come last.
+    Latency,
+}
+
+impl PeerSort {
+    /// Sorts `peers`, breaking ties by hostname and then id so the order is
+    /// the same on every call.
+    pub fn sort(sort_by: Option<PeerSort>, descending: bool, peers: &mut [PeerInfo]) {
+        let by_name = |a: &PeerInfo, b: &PeerInfo| {
+            a.hostname
+                .to_lowercase()
+                .cmp(&b.hostname.to_lowercase())
+                .then(a.id.cmp(&b.id))
+        };
+        peers.sort_by(|a, b| {
+            let order = match sort_by.unwrap_or(PeerSort::Hostname) {
+                PeerSort::Hostname => std::cmp::Ordering::Equal,
+                PeerSort::LastSeen => a.last_seen.cmp(&b.last_seen),
+                PeerSort::Latency => match (a.latency_ms, b.latency_ms) {
+                    (Some(a), Some(b)) => a.cmp(&b),
+                    // Unknown stays last either way round
+                    (Some(_), None) => return std::cmp::Ordering::Less,
+                    (None, Some(_)) => return std::cmp::Ordering::Greater,
+                    (None, None) => std::cmp::Ordering::Equal,
+                },
+            }
+            .then_with(|| by_name(a, b));
+            if descending {
+                order.reverse()
+            } else {
+                order
+            }
+        });
+    }
 }
 
 impl From<Peer> for PeerInfo {
     fn from(peer: Peer) -> Self {
+        let pinned = peer.fingerprint.is_some();
         Self {
+            last_seen: peer
+                .last_seen
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            latency_ms: peer.latency.map(|latency| latency.as_millis() as u64),
+            online: peer.is_online(),
+            pinned,
+            trusted: pinned && !peer.identity_changed,
+            blocked: false,
             id: peer.id,
             address: peer.address,
+            alt_addresses: peer.alt_addresses,
+            addresses: peer.addresses,
+            reached_at: peer.reached_at,
+            capabilities: peer.capabilities,
             hostname: peer.hostname,
+            fingerprint: peer.fingerprint,
+            identity_changed: peer.identity_changed,
+            manual: peer.manual,
+            connectivity: peer.connectivity,
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(hostname: &str, last_seen: u64, latency_ms: Option<u64>) -> PeerInfo {
+        let mut peer = PeerInfo::from(Peer::from_discovery(
+            Uuid::new_v4(),
+            SocketAddr::from(([192, 168, 1, 2], 7879)),
+            hostname.to_string(),
+            None,
+        ));
+        peer.last_seen = last_seen;
+        peer.latency_ms = latency_ms;
+        peer
+    }
+
+    fn sorted(sort_by: Option<PeerSort>, descending: bool, peers: &[PeerInfo]) -> Vec<String> {
+        let mut peers = peers.to_vec();
+        PeerSort::sort(sort_by, descending, &mut peers);
+        peers.into_iter().map(|peer| peer.hostname).collect()
+    }
+
+    #[test]
+    fn peers_sort_the_same_way_every_time() {
+        let peers = [peer("nas", 30, None), peer("Laptop", 10, Some(40)), peer("desktop", 20, Some(5))];
+
+        assert_eq!(sorted(None, false, &peers), ["desktop", "Laptop", "nas"]);
+        assert_eq!(sorted(Some(PeerSort::Hostname), true, &peers), ["nas", "Laptop", "desktop"]);
+        assert_eq!(sorted(Some(PeerSort::LastSeen), true, &peers), ["nas", "desktop", "Laptop"]);
+        // Never connected to comes last whichever way round
+        assert_eq!(sorted(Some(PeerSort::Latency), false, &peers), ["desktop", "Laptop", "nas"]);
+        assert_eq!(sorted(Some(PeerSort::Latency), true, &peers), ["Laptop", "desktop", "nas"]);
+
+        // Same names and times fall back to the id
+        let twins = [peer("twin", 0, None), peer("twin", 0, None)];
+        let mut once = twins.to_vec();
+        let mut again = [twins[1].clone(), twins[0].clone()];
+        PeerSort::sort(None, false, &mut once);
+        PeerSort::sort(None, false, &mut again);
+        assert_eq!(once.iter().map(|p| p.id).collect::<Vec<_>>(), again.iter().map(|p| p.id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn get_peers_without_options_still_parses() {
+        let message: ClientMessage = serde_json::from_str(r#"{"type":"GetPeers"}"#).unwrap();
+        assert!(matches!(message, ClientMessage::GetPeers { sort_by: None, descending: false }));
+    }
+}
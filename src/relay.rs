@@ -0,0 +1,286 @@
+use crate::rendezvous::Connectivity;
+use crate::tls;
+use crate::transfer::{Connection, ConnectionContext, RelayedPeer, TransferMessage, TransferService, MAX_CONTROL_MESSAGE_LEN};
+use crate::utils;
+use anyhow::Result;
+use std::net::SocketAddr;
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::time::{timeout, Duration, Instant};
+use uuid::Uuid;
+
+/// Bytes a relay holds in each direction at a time.
+const RELAY_BUFFER: usize = 64 * 1024;
+
+impl TransferService {
+    /// Answers a RelayRequest: connects the peer at `addr` on to
+    /// `destination` and passes bytes both ways until both sides are done.
+    /// Nothing is stored; at most a buffer's worth is held here at a time.
+    pub(crate) async fn relay<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        addr: SocketAddr,
+        context: &ConnectionContext,
+        destination: Uuid,
+        origin: Option<Uuid>,
+    ) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        if let Some(coordinator) = context.coordinator.as_deref() {
+            if let Some(target) = coordinator.registration(&destination) {
+                return Self::relay_registered(reader, stream, addr, context, coordinator, origin, target).await;
+            }
+        }
+
+        let (origin, target) = {
+            let peers = context.peers.read().await;
+            (
+                peers.find_by_ip(addr.ip().to_canonical()).cloned(),
+                peers.get_peer(&destination).cloned(),
+            )
+        };
+        // Only a peer we know and were told to trust, and never a connection
+        // that is itself relayed
+        let allowed = &context.config.relay.allowed_peers;
+        let origin = origin.filter(|origin| {
+            context.relayed.is_none()
+                && allowed.iter().any(|allowed| {
+                    origin.id.to_string().eq_ignore_ascii_case(allowed) || origin.hostname.eq_ignore_ascii_case(allowed)
+                })
+        });
+        let Some(origin) = origin else {
+            tracing::warn!("Refused to relay for {}", addr);
+            let refusal = TransferMessage::RelayError {
+                message: "Not allowed to relay through this peer".to_string(),
+            };
+            return Self::write_message(stream, &refusal).await;
+        };
+        let Some(target) = target else {
+            let refusal = TransferMessage::RelayError {
+                message: "Destination is not a peer of the relay".to_string(),
+            };
+            return Self::write_message(stream, &refusal).await;
+        };
+
+        let connecting = async {
+            let onward = Self::connect(&context.config.transfer, target.address).await?;
+            tls::connect(context.tls.as_deref(), onward, target.tls_fingerprint.as_deref()).await
+        };
+        let onward = match timeout(Duration::from_secs(10), connecting).await {
+            Ok(Ok(onward)) => onward,
+            Ok(Err(e)) => {
+                let refusal = TransferMessage::RelayError {
+                    message: format!("Couldn't reach {}: {}", target.hostname, e),
+                };
+                return Self::write_message(stream, &refusal).await;
+            }
+            Err(_) => {
+                let refusal = TransferMessage::RelayError {
+                    message: format!("Couldn't reach {}: timed out", target.hostname),
+                };
+                return Self::write_message(stream, &refusal).await;
+            }
+        };
+        let (onward_reader, onward_writer) = onward.into_split();
+        let origin = RelayedPeer {
+            peer_id: origin.id,
+            hostname: origin.hostname,
+        };
+        let target = RelayedPeer {
+            peer_id: target.id,
+            hostname: target.hostname,
+        };
+        Self::splice(reader, stream, context, origin, target, onward_reader, onward_writer).await
+    }
+
+    /// Introduces `origin` on the onward connection to `target`, tells the
+    /// origin it's through, then passes bytes both ways until both sides
+    /// are done.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn splice<R, W, OR, OW>(
+        reader: &mut R,
+        stream: &mut W,
+        context: &ConnectionContext,
+        origin: RelayedPeer,
+        target: RelayedPeer,
+        mut onward_reader: OR,
+        mut onward_writer: OW,
+    ) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+        OR: AsyncRead + Unpin,
+        OW: AsyncWrite + Unpin,
+    {
+        let (origin_hostname, target_hostname) = (origin.hostname.clone(), target.hostname.clone());
+        Self::write_message(&mut onward_writer, &TransferMessage::Relayed { origin }).await?;
+        Self::write_message(stream, &TransferMessage::RelayReady { destination: target }).await?;
+        tracing::info!("Relaying from {} to {}", origin_hostname, target_hostname);
+
+        // Each side is closed once the other has nothing more to say, so both
+        // copies end however the transfer does
+        let limit = context.config.relay.max_bytes_per_sec;
+        let upstream = async {
+            let copied = Self::pipe(reader, &mut onward_writer, limit).await;
+            let _ = onward_writer.shutdown().await;
+            copied
+        };
+        let downstream = async {
+            let copied = Self::pipe(&mut onward_reader, stream, None).await;
+            let _ = stream.shutdown().await;
+            copied
+        };
+        let (upstream, downstream) = tokio::join!(upstream, downstream);
+        tracing::info!(
+            "Relayed {} from {} to {}",
+            utils::format_bytes(upstream.as_ref().copied().unwrap_or(0)),
+            origin_hostname,
+            target_hostname
+        );
+        upstream.and(downstream).map(|_| ())
+    }
+
+    /// Copies until `from` ends, at no more than `limit` bytes per second.
+    async fn pipe<R, W>(from: &mut R, to: &mut W, limit: Option<u64>) -> Result<u64>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let started = Instant::now();
+        let mut buffer = vec![0u8; RELAY_BUFFER];
+        let mut copied = 0u64;
+        loop {
+            let n = from.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            to.write_all(&buffer[..n]).await?;
+            copied += n as u64;
+            if let Some(limit) = limit.filter(|limit| *limit > 0) {
+                tokio::time::sleep_until(started + Duration::from_secs_f64(copied as f64 / limit as f64)).await;
+            }
+        }
+        to.flush().await?;
+        Ok(copied)
+    }
+
+    /// Connects to `destination` through the relay at `relay`, returning
+    /// once the relay has reached it.
+    pub(crate) async fn open_relayed(&self, relay: SocketAddr, destination: Uuid) -> Result<Connection> {
+        let stream = self.connect_peer(relay).await?;
+        let (read_half, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(read_half);
+
+        let origin = Some(self.peers.read().await.local_id());
+        Self::write_message(&mut writer, &TransferMessage::RelayRequest { destination, origin }).await?;
+        // The relay connects onward before it answers
+        let response = timeout(Duration::from_secs(20), Self::read_message(&mut reader, MAX_CONTROL_MESSAGE_LEN)).await??;
+        match response {
+            TransferMessage::RelayReady { destination } => Ok(Connection {
+                reader,
+                writer,
+                address: relay,
+                relayed: Some(destination),
+                connectivity: Connectivity::Relayed,
+            }),
+            TransferMessage::RelayError { message } => Err(anyhow::anyhow!("Relay refused: {}", message)),
+            _ => Err(anyhow::anyhow!("Unexpected response")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::peer::Peer;
+    use crate::transfer::tests::{connection, context, scratch_dir, service};
+    use std::path::Path;
+    use tokio::net::TcpListener;
+
+    /// A relay's service in `dir` knowing an origin, at a loopback address of
+    /// its own, and a destination listening on `onward`.
+    async fn relay_between(allowed_peers: Vec<String>, dir: &Path, onward: &TcpListener) -> (TransferService, Peer, Peer) {
+        let mut config = AppConfig::default();
+        config.relay.allowed_peers = allowed_peers;
+        let service = service(config, dir);
+        let origin = Peer::from_discovery(Uuid::new_v4(), SocketAddr::from(([127, 0, 0, 2], 40000)), "origin".to_string(), None);
+        let target = Peer::from_discovery(Uuid::new_v4(), onward.local_addr().unwrap(), "target".to_string(), None);
+        let mut peers = service.peers.write().await;
+        peers.add_or_update_peer(origin.clone());
+        peers.add_or_update_peer(target.clone());
+        drop(peers);
+        (service, origin, target)
+    }
+
+    #[tokio::test]
+    async fn relays_refuse_peers_that_arent_allowed_before_going_on() {
+        let dir = scratch_dir();
+        let onward = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (service, origin, target) = relay_between(vec!["someone-else".to_string()], &dir, &onward).await;
+        let context = context(&service, &dir);
+
+        let (mut reader, mut writer, mut peer) = connection();
+        TransferService::relay(&mut reader, &mut writer, origin.address, &context, target.id, Some(origin.id))
+            .await
+            .unwrap();
+        let TransferMessage::RelayError { message } = peer.recv().await else { panic!("expected a refusal") };
+        assert_eq!(message, "Not allowed to relay through this peer");
+        // The destination was never contacted
+        assert!(timeout(Duration::from_millis(100), onward.accept()).await.is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn relayed_data_passes_through_without_touching_disk() {
+        let dir = scratch_dir();
+        let onward = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (service, origin, target) = relay_between(vec!["origin".to_string()], &dir, &onward).await;
+        let context = context(&service, &dir);
+        let payload: Vec<u8> = (0..1024 * 1024u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+
+        let destination = tokio::spawn(async move {
+            let (stream, _) = onward.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            let introduced = TransferService::read_transfer_message(&mut reader, MAX_CONTROL_MESSAGE_LEN).await.unwrap();
+            let mut received = Vec::new();
+            reader.read_to_end(&mut received).await.unwrap();
+            writer.write_all(b"got it").await.unwrap();
+            (introduced, received)
+        });
+        let (mut reader, mut writer, mut peer) = connection();
+        let relaying = TransferService::relay(&mut reader, &mut writer, origin.address, &context, target.id, Some(origin.id));
+        let sending = async {
+            assert!(matches!(peer.recv().await, TransferMessage::RelayReady { .. }));
+            peer.writer.write_all(&payload).await.unwrap();
+            peer.writer.shutdown().await.unwrap();
+            let mut reply = Vec::new();
+            peer.reader.read_to_end(&mut reply).await.unwrap();
+            reply
+        };
+        let (relayed, reply) = tokio::join!(relaying, sending);
+        relayed.unwrap();
+        assert_eq!(reply, b"got it");
+        let (introduced, received) = destination.await.unwrap();
+        let TransferMessage::Relayed { origin: introduced } = introduced else { panic!("origin wasn't introduced") };
+        assert_eq!(introduced.peer_id, origin.id);
+        assert_eq!(received, payload);
+
+        // Nothing the relay keeps holds any of it
+        let mut unvisited = vec![dir.clone()];
+        while let Some(visiting) = unvisited.pop() {
+            for entry in std::fs::read_dir(visiting).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    unvisited.push(path);
+                } else {
+                    let kept = std::fs::read(&path).unwrap();
+                    assert!(!kept.windows(4096).any(|window| window == &payload[..4096]), "{} holds relayed data", path.display());
+                }
+            }
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
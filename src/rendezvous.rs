@@ -0,0 +1,534 @@
+use crate::transfer::{
+    Connection, ConnectionContext, RelayedPeer, TransferMessage, TransferService, CONNECT_ATTEMPT_TIMEOUT,
+    MAX_CONTROL_MESSAGE_LEN,
+};
+use crate::tls::{self, PeerStream};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufRead, AsyncReadExt, AsyncWrite, BufReader, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{timeout, Duration};
+use uuid::Uuid;
+
+/// How long punching through to a peer is tried before relaying instead.
+const PUNCH_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long each punch attempt waits on its connection.
+const PUNCH_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Pause between punch attempts.
+const PUNCH_RETRY: Duration = Duration::from_millis(250);
+/// How long after losing its registration we register with the rendezvous
+/// coordinator again.
+const REGISTRATION_RETRY: Duration = Duration::from_secs(15);
+/// Answer to rendezvous requests when we aren't a coordinator.
+const NOT_A_COORDINATOR: &str = "Not a rendezvous coordinator";
+
+/// How a connection to a peer got through.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Connectivity {
+    /// Straight to one of its addresses.
+    #[default]
+    Direct,
+    /// Through both sides' routers, opened at once from each end as a
+    /// coordinator arranged.
+    Punched,
+    /// Passed on by a relay or coordinator.
+    Relayed,
+}
+
+/// A connection to the coordinator, handed over to pass a relayed transfer on.
+pub(crate) type Joined = (BufReader<ReadHalf<PeerStream>>, WriteHalf<PeerStream>);
+
+/// A peer registered with us as coordinator.
+#[derive(Clone)]
+pub(crate) struct Registration {
+    pub peer: RelayedPeer,
+    /// Where its registration came from: its router's mapping of its
+    /// transfer port, as others must connect to it.
+    pub address: SocketAddr,
+    /// Messages for it, written on its registration connection.
+    pushes: mpsc::UnboundedSender<TransferMessage>,
+    /// Tells this registration from a newer one by the same peer.
+    generation: Uuid,
+}
+
+impl Registration {
+    /// Passes `message` on to the peer; false once it has gone.
+    pub fn push(&self, message: TransferMessage) -> bool {
+        self.pushes.send(message).is_ok()
+    }
+}
+
+/// What a node running as rendezvous coordinator knows: who is registered,
+/// and the connections it waits on for relays.
+#[derive(Default)]
+pub(crate) struct Coordinator {
+    registrations: Mutex<HashMap<Uuid, Registration>>,
+    joins: Mutex<HashMap<Uuid, oneshot::Sender<Joined>>>,
+}
+
+impl Coordinator {
+    /// Registers `peer`, seen at `address`, in place of any earlier
+    /// registration of it. Returns the generation to unregister with and
+    /// where its messages arrive.
+    pub fn register(&self, peer: RelayedPeer, address: SocketAddr) -> (Uuid, mpsc::UnboundedReceiver<TransferMessage>) {
+        let (pushes, received) = mpsc::unbounded_channel();
+        let generation = Uuid::new_v4();
+        let registration = Registration {
+            peer,
+            address,
+            pushes,
+            generation,
+        };
+        self.registrations.lock().unwrap().insert(registration.peer.peer_id, registration);
+        (generation, received)
+    }
+
+    /// Forgets `peer_id`'s registration, unless a newer one replaced it.
+    pub fn unregister(&self, peer_id: Uuid, generation: Uuid) {
+        let mut registrations = self.registrations.lock().unwrap();
+        if registrations.get(&peer_id).is_some_and(|registration| registration.generation == generation) {
+            registrations.remove(&peer_id);
+        }
+    }
+
+    pub fn registration(&self, peer_id: &Uuid) -> Option<Registration> {
+        self.registrations.lock().unwrap().get(peer_id).cloned()
+    }
+
+    /// The registration of `peer_id`, if it was made from `ip`; anyone else
+    /// claiming the id is refused.
+    pub fn registration_from(&self, peer_id: &Uuid, ip: IpAddr) -> Option<Registration> {
+        self.registration(peer_id)
+            .filter(|registration| registration.address.ip().to_canonical() == ip.to_canonical())
+    }
+
+    /// Waits for the connection that joins under `token`.
+    pub fn expect_join(&self, token: Uuid) -> oneshot::Receiver<Joined> {
+        let (tx, rx) = oneshot::channel();
+        self.joins.lock().unwrap().insert(token, tx);
+        rx
+    }
+
+    pub fn forget_join(&self, token: &Uuid) {
+        self.joins.lock().unwrap().remove(token);
+    }
+
+    /// Hands a connection joining under `token` to the relay waiting on it.
+    /// False when nothing was.
+    pub fn join(&self, token: &Uuid, joined: Joined) -> bool {
+        match self.joins.lock().unwrap().remove(token) {
+            Some(waiting) => waiting.send(joined).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Peers being punched through to, by the address the coordinator saw them
+/// at. Either side's attempt may land on the other's listener instead of
+/// meeting its attempt, so connections accepted from these addresses go to
+/// the punch rather than being served as usual.
+#[derive(Default)]
+pub(crate) struct Punches {
+    expected: Mutex<HashMap<SocketAddr, oneshot::Sender<TcpStream>>>,
+}
+
+impl Punches {
+    /// Expects a connection from `address`, until `forget`.
+    pub fn expect(&self, address: SocketAddr) -> oneshot::Receiver<TcpStream> {
+        let (tx, rx) = oneshot::channel();
+        self.expected.lock().unwrap().insert(address, tx);
+        rx
+    }
+
+    pub fn forget(&self, address: &SocketAddr) {
+        self.expected.lock().unwrap().remove(address);
+    }
+
+    /// Hands over a connection accepted from `address` if a punch expects
+    /// it, or gives it back.
+    pub fn claim(&self, address: SocketAddr, stream: TcpStream) -> Option<TcpStream> {
+        match self.expected.lock().unwrap().remove(&address) {
+            Some(waiting) => waiting.send(stream).err(),
+            None => Some(stream),
+        }
+    }
+}
+
+impl TransferService {
+    /// Relays for a peer registered with us as coordinator to `target`,
+    /// another one. The target is behind a router we can't connect through,
+    /// so it's asked to connect back, and that connection is the way on.
+    pub(crate) async fn relay_registered<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        addr: SocketAddr,
+        context: &ConnectionContext,
+        coordinator: &Coordinator,
+        origin: Option<Uuid>,
+        target: Registration,
+    ) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let origin = origin
+            .filter(|_| context.relayed.is_none())
+            .and_then(|origin| coordinator.registration_from(&origin, addr.ip()));
+        let Some(origin) = origin else {
+            tracing::warn!("Refused to relay for {}, which isn't registered", addr);
+            let refusal = TransferMessage::RelayError {
+                message: "Not registered with this coordinator".to_string(),
+            };
+            return Self::write_message(stream, &refusal).await;
+        };
+
+        let token = Uuid::new_v4();
+        let joining = coordinator.expect_join(token);
+        let joined = match target.push(TransferMessage::RendezvousRelay { token }) {
+            true => timeout(Duration::from_secs(10), joining).await.ok().and_then(Result::ok),
+            false => None,
+        };
+        coordinator.forget_join(&token);
+        let Some((onward_reader, onward_writer)) = joined else {
+            let refusal = TransferMessage::RelayError {
+                message: format!("Couldn't reach {}: it didn't connect back", target.peer.hostname),
+            };
+            return Self::write_message(stream, &refusal).await;
+        };
+        Self::splice(reader, stream, context, origin.peer, target.peer, onward_reader, onward_writer).await
+    }
+
+    /// Keeps `peer`, at `addr`, registered with us as coordinator while its
+    /// connection stays open, passing on to it what other peers ask of it.
+    pub(crate) async fn serve_registration<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        addr: SocketAddr,
+        context: &ConnectionContext,
+        peer: RelayedPeer,
+    ) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let Some(coordinator) = context.coordinator.as_deref() else {
+            let refusal = TransferMessage::RendezvousError {
+                message: NOT_A_COORDINATOR.to_string(),
+            };
+            return Self::write_message(stream, &refusal).await;
+        };
+        let (peer_id, hostname) = (peer.peer_id, peer.hostname.clone());
+        let (generation, mut pushes) = coordinator.register(peer, addr);
+        tracing::info!("{} registered for rendezvous from {}", hostname, addr);
+
+        let served = async {
+            Self::write_message(stream, &TransferMessage::RendezvousRegistered { observed: addr }).await?;
+            // Nothing more is expected from the peer; reading only tells
+            // when it hangs up
+            let mut ignored = [0u8; 256];
+            loop {
+                tokio::select! {
+                    push = pushes.recv() => match push {
+                        Some(message) => Self::write_message(stream, &message).await?,
+                        // It registered again since
+                        None => return Ok(()),
+                    },
+                    read = reader.read(&mut ignored) => {
+                        if read? == 0 {
+                            return Ok(());
+                        }
+                    }
+                }
+            }
+        };
+        let result = served.await;
+        coordinator.unregister(peer_id, generation);
+        tracing::info!("{} is no longer registered for rendezvous", hostname);
+        result
+    }
+
+    /// Answers a RendezvousConnect: tells `origin` where `destination` is
+    /// seen, and `destination` to start connecting to `origin`. Both must
+    /// be registered with us, `origin` from the address asking.
+    pub(crate) async fn broker<W: AsyncWrite + Unpin>(
+        stream: &mut W,
+        addr: SocketAddr,
+        context: &ConnectionContext,
+        origin: RelayedPeer,
+        destination: Uuid,
+    ) -> Result<()> {
+        let refusal = |message: &str| TransferMessage::RendezvousError {
+            message: message.to_string(),
+        };
+        let Some(coordinator) = context.coordinator.as_deref() else {
+            return Self::write_message(stream, &refusal(NOT_A_COORDINATOR)).await;
+        };
+        let Some(from) = coordinator.registration_from(&origin.peer_id, addr.ip()) else {
+            tracing::warn!("Refused to broker for {}, which isn't registered", addr);
+            return Self::write_message(stream, &refusal("Not registered with this coordinator")).await;
+        };
+        let to = coordinator.registration(&destination).filter(|to| {
+            to.push(TransferMessage::RendezvousPunch {
+                peer: from.peer.clone(),
+                address: from.address,
+            })
+        });
+        let Some(to) = to else {
+            return Self::write_message(stream, &refusal("Destination is not registered with this coordinator")).await;
+        };
+        tracing::info!(
+            "Putting {} at {} in touch with {} at {}",
+            from.peer.hostname,
+            from.address,
+            to.peer.hostname,
+            to.address
+        );
+        let found = TransferMessage::RendezvousPeer {
+            peer: to.peer,
+            address: to.address,
+        };
+        Self::write_message(stream, &found).await
+    }
+
+    /// Reaches `destination` through the rendezvous `coordinator`, which
+    /// both are registered with: each connects to where the coordinator
+    /// sees the other at once, which gets through most routers, and failing
+    /// that the coordinator relays.
+    pub(crate) async fn open_through(&self, coordinator: &str, destination: Uuid) -> Result<Connection> {
+        let coordinator = Self::resolve(coordinator, self.config.network.transfer_port).await?;
+        let stream = timeout(CONNECT_ATTEMPT_TIMEOUT, Self::connect(&self.config.transfer, coordinator)).await??;
+        let mut stream = self.secure(stream, coordinator).await?;
+        let (read_half, mut writer) = stream.split();
+        let mut reader = BufReader::new(read_half);
+        let origin = self.local_peer().await;
+        Self::write_message(&mut writer, &TransferMessage::RendezvousConnect { origin, destination }).await?;
+        let response = timeout(Duration::from_secs(10), Self::read_message(&mut reader, MAX_CONTROL_MESSAGE_LEN)).await??;
+        let (peer, address) = match response {
+            TransferMessage::RendezvousPeer { peer, address } => (peer, address),
+            TransferMessage::RendezvousError { message } => {
+                return Err(anyhow::anyhow!("Rendezvous coordinator refused: {}", message));
+            }
+            _ => return Err(anyhow::anyhow!("Unexpected response")),
+        };
+        drop(stream);
+
+        match self.punch(address).await {
+            Ok(stream) => {
+                tracing::info!("Punched through to {} at {}", peer.hostname, address);
+                let pin = self.peers.read().await.get_peer(&destination).and_then(|peer| peer.tls_fingerprint.clone());
+                let stream = tls::connect(self.tls(), stream, pin.as_deref()).await?;
+                return Ok(Self::connection(stream, address, Connectivity::Punched));
+            }
+            Err(e) => tracing::info!(
+                "Couldn't punch through to {} at {} ({}), relaying through the coordinator",
+                peer.hostname,
+                address,
+                e
+            ),
+        }
+        self.open_relayed(coordinator, destination).await
+    }
+
+    /// Connects to `address` from our transfer port while the peer there
+    /// does the same towards us, so each router has seen a connection go out
+    /// before the other side's comes in. Early attempts tend to be turned
+    /// away until both have, so they're repeated for up to PUNCH_TIMEOUT.
+    /// The peer's attempt may reach our listener instead, and then that
+    /// connection is the one.
+    async fn punch(&self, address: SocketAddr) -> Result<TcpStream> {
+        let mut accepted = self.punches.expect(address);
+        let attempts = async {
+            loop {
+                tokio::select! {
+                    connected = timeout(PUNCH_ATTEMPT_TIMEOUT, Self::connect_from(&self.config.transfer, self.port(), address)) => {
+                        match connected {
+                            Ok(Ok(stream)) => return stream,
+                            Ok(Err(e)) => tracing::debug!("Punch to {} failed: {}", address, e),
+                            Err(_) => tracing::debug!("Punch to {} timed out", address),
+                        }
+                    }
+                    Ok(stream) = &mut accepted => return stream,
+                }
+                tokio::time::sleep(PUNCH_RETRY).await;
+            }
+        };
+        let punched = timeout(PUNCH_TIMEOUT, attempts).await;
+        self.punches.forget(&address);
+        punched.map_err(|_| anyhow::anyhow!("no connection within {} seconds", PUNCH_TIMEOUT.as_secs()))
+    }
+
+    /// Us, as named to a coordinator.
+    async fn local_peer(&self) -> RelayedPeer {
+        let peers = self.peers.read().await;
+        RelayedPeer {
+            peer_id: peers.local_id(),
+            hostname: peers.local_hostname().to_string(),
+        }
+    }
+
+    /// Stays registered with the rendezvous `coordinator` until shutdown,
+    /// registering again whenever the connection drops, and does what it
+    /// passes on: punching through to peers that want to reach us, and
+    /// connecting back for transfers it relays to us.
+    pub async fn keep_registered(self: &Arc<Self>, coordinator: &str) -> Result<()> {
+        // Registrations come from the transfer port, which isn't known before
+        // the listener binds when it's picked by the OS
+        tokio::select! {
+            _ = self.wait_for_local_addr() => {}
+            _ = self.shutdown.cancelled() => return Ok(()),
+        }
+        loop {
+            tokio::select! {
+                registered = self.register(coordinator) => match registered {
+                    Ok(()) => tracing::info!("Rendezvous coordinator {} closed our registration", coordinator),
+                    Err(e) => tracing::warn!("Couldn't stay registered with rendezvous coordinator {}: {}", coordinator, e),
+                },
+                _ = self.shutdown.cancelled() => return Ok(()),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(REGISTRATION_RETRY) => {}
+                _ = self.shutdown.cancelled() => return Ok(()),
+            }
+        }
+    }
+
+    /// Registers with the coordinator at `coordinator` and serves the
+    /// registration until the coordinator hangs up.
+    async fn register(self: &Arc<Self>, coordinator: &str) -> Result<()> {
+        let coordinator = Self::resolve(coordinator, self.config.network.transfer_port).await?;
+        let stream = timeout(CONNECT_ATTEMPT_TIMEOUT, Self::connect_from(&self.config.transfer, self.port(), coordinator)).await??;
+        let (read_half, mut writer) = self.secure(stream, coordinator).await?.into_split();
+        let mut reader = BufReader::new(read_half);
+        let peer = self.local_peer().await;
+        Self::write_message(&mut writer, &TransferMessage::RendezvousRegister { peer }).await?;
+        match timeout(Duration::from_secs(10), Self::read_message(&mut reader, MAX_CONTROL_MESSAGE_LEN)).await?? {
+            TransferMessage::RendezvousRegistered { observed } => {
+                tracing::info!("Registered with rendezvous coordinator {}, which sees us at {}", coordinator, observed);
+            }
+            TransferMessage::RendezvousError { message } => return Err(anyhow::anyhow!("Refused: {}", message)),
+            _ => return Err(anyhow::anyhow!("Unexpected response")),
+        }
+
+        loop {
+            match Self::read_message(&mut reader, MAX_CONTROL_MESSAGE_LEN).await? {
+                TransferMessage::RendezvousPunch { peer, address } => {
+                    tracing::info!("Punching through to {} at {} for the rendezvous coordinator", peer.hostname, address);
+                    let service = self.clone();
+                    tokio::spawn(async move {
+                        match service.punch(address).await {
+                            Ok(stream) => {
+                                let context = ConnectionContext {
+                                    connectivity: Connectivity::Punched,
+                                    ..service.context()
+                                };
+                                Self::serve(stream, address, context).await;
+                            }
+                            Err(e) => tracing::info!("Couldn't punch through to {} at {}: {}", peer.hostname, address, e),
+                        }
+                    });
+                }
+                TransferMessage::RendezvousRelay { token } => {
+                    tokio::spawn(Self::join_relay(coordinator, token, self.context()));
+                }
+                other => tracing::debug!("Ignoring {:?} from rendezvous coordinator {}", other, coordinator),
+            }
+        }
+    }
+
+    /// Connects back to the coordinator at `coordinator` for a transfer it
+    /// relays to us, and serves it.
+    async fn join_relay(coordinator: SocketAddr, token: Uuid, context: ConnectionContext) {
+        let mut stream = match timeout(CONNECT_ATTEMPT_TIMEOUT, Self::connect(&context.config.transfer, coordinator)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
+                tracing::warn!("Couldn't connect back to rendezvous coordinator {}: {}", coordinator, e);
+                return;
+            }
+            Err(_) => {
+                tracing::warn!("Couldn't connect back to rendezvous coordinator {}: timed out", coordinator);
+                return;
+            }
+        };
+        if let Err(e) = Self::write_message(&mut stream, &TransferMessage::RendezvousJoin { token }).await {
+            tracing::warn!("Couldn't connect back to rendezvous coordinator {}: {}", coordinator, e);
+            return;
+        }
+        Self::serve(stream, coordinator, context).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{AppConfig, RendezvousConfig};
+    use crate::peer::Peer;
+    use crate::transfer::tests::{sample_data, scratch_dir, service};
+    use crate::transfer::Route;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn an_unreachable_peer_is_punched_through_to_via_the_coordinator() {
+        let dir = scratch_dir();
+        let on_any_port = |rendezvous: RendezvousConfig| {
+            let mut config = AppConfig::default();
+            config.network.transfer_port = 0;
+            config.transfer.allowed_networks = vec!["127.0.0.0/8".to_string()];
+            config.rendezvous = rendezvous;
+            config
+        };
+        let coordinator = Arc::new(service(
+            on_any_port(RendezvousConfig {
+                enabled: true,
+                coordinator: None,
+            }),
+            &dir.join("coordinator"),
+        ));
+        let listening = coordinator.clone();
+        tokio::spawn(async move { listening.start_listener().await });
+        let at = format!("127.0.0.1:{}", coordinator.wait_for_local_addr().await.port());
+
+        let [sender, receiver] = ["sender", "receiver"].map(|name| {
+            let rendezvous = RendezvousConfig {
+                enabled: false,
+                coordinator: Some(at.clone()),
+            };
+            Arc::new(service(on_any_port(rendezvous), &dir.join(name)))
+        });
+        for node in [&sender, &receiver] {
+            let (listening, registering, at) = (node.clone(), node.clone(), at.clone());
+            tokio::spawn(async move { listening.start_listener().await });
+            tokio::spawn(async move { registering.keep_registered(&at).await });
+        }
+        let (sender_id, receiver_id) = (sender.peers.read().await.local_id(), receiver.peers.read().await.local_id());
+        let registered = coordinator.coordinator.clone().unwrap();
+        timeout(Duration::from_secs(5), async {
+            while registered.registration(&sender_id).is_none() || registered.registration(&receiver_id).is_none() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // All the sender has for the receiver is an address nothing answers at
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let peer = Peer::from_discovery(receiver_id, dead, "receiver".to_string(), None);
+        sender.peers.write().await.add_or_update_peer(peer);
+        let data = sample_data();
+        let path = dir.join("punched.bin");
+        std::fs::write(&path, &data).unwrap();
+        let transfer = sender.track_send(Uuid::new_v4());
+        let outcome = sender.send_file(&transfer, Route::Peer(receiver_id), path, |_, _| {}).await.unwrap();
+
+        assert_eq!(outcome.connectivity, Connectivity::Punched);
+        assert!(outcome.peer_ack);
+        let peers = sender.peers.read().await;
+        assert_eq!(peers.get_peer(&receiver_id).unwrap().connectivity, Some(Connectivity::Punched));
+        assert_eq!(std::fs::read(dir.join("receiver").join("downloads").join("punched.bin")).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,412 @@
+use crate::config::ScheduleConfig;
+use crate::utils;
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio::time::{sleep, Duration};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// Where scheduled transfers are kept between runs, in the data directory.
+pub const SCHEDULE_FILE: &str = "schedule.json";
+/// A run this late is still on time, e.g. across a quick restart.
+const MISSED_GRACE: u64 = 60;
+/// Longest the scheduler sleeps before looking at the clock again, so a
+/// clock change or a suspended machine doesn't push runs back.
+const MAX_SLEEP: u64 = 60;
+/// Shortest interval a recurring transfer may repeat at.
+const MIN_RECURRENCE: u64 = 60;
+
+/// What to do with a run whose time passed while the daemon was off.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissedPolicy {
+    /// Run it as soon as the daemon starts.
+    #[default]
+    RunNow,
+    /// Leave it out; a recurring transfer waits for its next run.
+    Skip,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduledTransfer {
+    pub id: Uuid,
+    pub peer_id: Uuid,
+    pub file_path: String,
+    /// Next run, in seconds since the epoch.
+    pub at_unix: u64,
+    /// "hourly", "daily", "weekly" or an interval such as "30m", "6h" or "2d";
+    /// a one-off transfer when unset.
+    pub recurring: Option<String>,
+    #[serde(default)]
+    pub missed: MissedPolicy,
+    /// When the last run finished, in seconds since the epoch.
+    #[serde(default)]
+    pub last_run: Option<u64>,
+    /// Why the last run failed, once its retries were used up.
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// Set while a one-off transfer is running or being retried.
+    #[serde(skip)]
+    running: bool,
+}
+
+/// Seconds between runs of a recurring transfer.
+pub fn recurrence_interval(spec: &str) -> Result<u64> {
+    let spec = spec.trim().to_ascii_lowercase();
+    let seconds = match spec.as_str() {
+        "hourly" => 3600,
+        "daily" => 24 * 3600,
+        "weekly" => 7 * 24 * 3600,
+        _ => {
+            let split = spec.len().saturating_sub(1);
+            let (count, unit) = spec.split_at(split);
+            let unit = match unit {
+                "m" => 60,
+                "h" => 3600,
+                "d" => 24 * 3600,
+                _ => bail!("Unknown recurrence {:?}; use hourly, daily, weekly or e.g. 30m, 6h, 2d", spec),
+            };
+            let count: u64 = count
+                .parse()
+                .map_err(|_| anyhow!("Unknown recurrence {:?}; use hourly, daily, weekly or e.g. 30m, 6h, 2d", spec))?;
+            count.saturating_mul(unit)
+        }
+    };
+    if seconds < MIN_RECURRENCE {
+        bail!("Scheduled transfers can't repeat more than once a minute");
+    }
+    Ok(seconds)
+}
+
+/// The first run after `now` of one starting at `at` and repeating every `interval`.
+fn next_after(at: u64, interval: u64, now: u64) -> u64 {
+    if at > now {
+        return at;
+    }
+    at + interval * ((now - at) / interval + 1)
+}
+
+fn now_unix() -> u64 {
+    chrono::Utc::now().timestamp().max(0) as u64
+}
+
+/// Transfers set to go out later, kept on disk and fired when due.
+pub struct Scheduler {
+    path: PathBuf,
+    config: ScheduleConfig,
+    jobs: Mutex<Vec<ScheduledTransfer>>,
+    changed: Notify,
+}
+
+impl Scheduler {
+    /// The schedule saved in `path`, empty if there is none yet.
+    pub fn load(path: PathBuf, config: ScheduleConfig) -> Self {
+        let jobs = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring unreadable schedule in {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                tracing::warn!("Couldn't read the schedule from {}: {}", path.display(), e);
+                Vec::new()
+            }
+        };
+        Self {
+            path,
+            config,
+            jobs: Mutex::new(jobs),
+            changed: Notify::new(),
+        }
+    }
+
+    /// Schedules a file to go to `peer_id` at `at_unix`, saving the schedule.
+    pub fn add(
+        &self,
+        peer_id: Uuid,
+        file_path: String,
+        at_unix: u64,
+        recurring: Option<String>,
+        missed: MissedPolicy,
+    ) -> Result<ScheduledTransfer> {
+        if let Some(spec) = &recurring {
+            recurrence_interval(spec)?;
+        }
+        let job = ScheduledTransfer {
+            id: Uuid::new_v4(),
+            peer_id,
+            file_path,
+            at_unix,
+            recurring,
+            missed,
+            last_run: None,
+            last_error: None,
+            running: false,
+        };
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.push(job.clone());
+        self.save(&jobs)?;
+        drop(jobs);
+        self.changed.notify_one();
+        Ok(job)
+    }
+
+    /// Every scheduled transfer, soonest first.
+    pub fn list(&self) -> Vec<ScheduledTransfer> {
+        let mut jobs = self.jobs.lock().unwrap().clone();
+        jobs.sort_by_key(|job| job.at_unix);
+        jobs
+    }
+
+    /// Removes a scheduled transfer; one being retried stops retrying.
+    pub fn cancel(&self, id: &Uuid) -> Result<()> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let before = jobs.len();
+        jobs.retain(|job| job.id != *id);
+        if jobs.len() == before {
+            bail!("No scheduled transfer {}", id);
+        }
+        self.save(&jobs)
+    }
+
+    fn save(&self, jobs: &[ScheduledTransfer]) -> Result<()> {
+        utils::write_atomic(&self.path, &serde_json::to_vec_pretty(jobs)?)?;
+        Ok(())
+    }
+
+    fn save_or_log(&self, jobs: &[ScheduledTransfer]) {
+        if let Err(e) = self.save(jobs) {
+            tracing::error!("Failed to save the schedule to {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Fires due transfers through `send` until shutdown. `send` resolves
+    /// when the transfer is over; failed ones are retried as configured.
+    pub async fn run<F, Fut>(self: Arc<Self>, shutdown: CancellationToken, send: F) -> Result<()>
+    where
+        F: Fn(ScheduledTransfer) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let send = Arc::new(send);
+        self.catch_up(now_unix());
+        loop {
+            for job in self.take_due(now_unix()) {
+                tokio::spawn(self.clone().attempt(job, send.clone()));
+            }
+
+            let wait = self.next_run().map_or(MAX_SLEEP, |at| at.saturating_sub(now_unix()).clamp(1, MAX_SLEEP));
+            tokio::select! {
+                _ = sleep(Duration::from_secs(wait)) => {}
+                _ = self.changed.notified() => {}
+                _ = shutdown.cancelled() => return Ok(()),
+            }
+        }
+    }
+
+    /// Applies each job's missed-run policy to runs that fell due while the
+    /// daemon was off.
+    fn catch_up(&self, now: u64) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let before = jobs.len();
+        let mut changed = false;
+        jobs.retain_mut(|job| {
+            if job.missed != MissedPolicy::Skip || job.at_unix + MISSED_GRACE >= now {
+                return true;
+            }
+            changed = true;
+            match job.recurring.as_deref().map(recurrence_interval) {
+                Some(Ok(interval)) => {
+                    job.at_unix = next_after(job.at_unix, interval, now);
+                    tracing::info!("Skipped missed runs of scheduled transfer {} of {}", job.id, job.file_path);
+                    true
+                }
+                _ => {
+                    tracing::info!("Skipped missed scheduled transfer {} of {}", job.id, job.file_path);
+                    false
+                }
+            }
+        });
+        if changed || jobs.len() != before {
+            self.save_or_log(&jobs);
+        }
+    }
+
+    /// Takes the runs due at `now`: recurring jobs move on to their next
+    /// run, one-off jobs stay listed until their attempts are over.
+    fn take_due(&self, now: u64) -> Vec<ScheduledTransfer> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let mut due = Vec::new();
+        for job in jobs.iter_mut().filter(|job| !job.running && job.at_unix <= now) {
+            due.push(job.clone());
+            match job.recurring.as_deref().map(recurrence_interval) {
+                Some(Ok(interval)) => job.at_unix = next_after(job.at_unix, interval, now),
+                _ => job.running = true,
+            }
+        }
+        if !due.is_empty() {
+            self.save_or_log(&jobs);
+        }
+        due
+    }
+
+    fn next_run(&self) -> Option<u64> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|job| !job.running)
+            .map(|job| job.at_unix)
+            .min()
+    }
+
+    fn is_scheduled(&self, id: &Uuid) -> bool {
+        self.jobs.lock().unwrap().iter().any(|job| job.id == *id)
+    }
+
+    async fn attempt<F, Fut>(self: Arc<Self>, job: ScheduledTransfer, send: Arc<F>)
+    where
+        F: Fn(ScheduledTransfer) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let mut attempt = 0;
+        let result = loop {
+            tracing::info!("Running scheduled transfer {} of {}", job.id, job.file_path);
+            match send(job.clone()).await {
+                Ok(()) => break Ok(()),
+                Err(e) if attempt >= self.config.retry_attempts => break Err(e),
+                Err(e) => {
+                    attempt += 1;
+                    tracing::warn!(
+                        "Scheduled transfer {} failed, retrying in {}s ({}/{}): {}",
+                        job.id,
+                        self.config.retry_interval,
+                        attempt,
+                        self.config.retry_attempts,
+                        e
+                    );
+                    sleep(Duration::from_secs(self.config.retry_interval)).await;
+                    if !self.is_scheduled(&job.id) {
+                        return;
+                    }
+                }
+            }
+        };
+        if let Err(e) = &result {
+            tracing::error!("Scheduled transfer {} of {} failed: {}", job.id, job.file_path, e);
+        }
+        self.finish(&job, result.err().map(|e| e.to_string()), now_unix());
+    }
+
+    /// Records how a run went. A one-off transfer is done with either way.
+    fn finish(&self, run: &ScheduledTransfer, error: Option<String>, now: u64) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if run.recurring.is_none() {
+            jobs.retain(|job| job.id != run.id);
+        } else if let Some(job) = jobs.iter_mut().find(|job| job.id == run.id) {
+            job.last_run = Some(now);
+            job.last_error = error;
+        }
+        self.save_or_log(&jobs);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn scheduler(config: ScheduleConfig) -> (Arc<Scheduler>, PathBuf) {
+        let path = std::env::temp_dir().join(format!("p2p-sharing-test-{}.json", Uuid::new_v4()));
+        (Arc::new(Scheduler::load(path.clone(), config)), path)
+    }
+
+    #[test]
+    fn recurrences_parse_to_intervals() {
+        assert_eq!(recurrence_interval("daily").unwrap(), 86400);
+        assert_eq!(recurrence_interval("Weekly").unwrap(), 604800);
+        assert_eq!(recurrence_interval("30m").unwrap(), 1800);
+        assert_eq!(recurrence_interval("6h").unwrap(), 21600);
+        assert!(recurrence_interval("30s").is_err());
+        assert!(recurrence_interval("0h").is_err());
+        assert!(recurrence_interval("fortnightly").is_err());
+        assert!(recurrence_interval("").is_err());
+    }
+
+    #[test]
+    fn next_run_skips_every_missed_one() {
+        assert_eq!(next_after(1000, 100, 500), 1000);
+        assert_eq!(next_after(1000, 100, 1000), 1100);
+        assert_eq!(next_after(1000, 100, 1350), 1400);
+    }
+
+    #[test]
+    fn due_runs_are_taken_once_and_recurring_ones_move_on() {
+        let (scheduler, path) = scheduler(ScheduleConfig::default());
+        let once = scheduler.add(Uuid::new_v4(), "a".into(), 1000, None, MissedPolicy::RunNow).unwrap();
+        let daily = scheduler.add(Uuid::new_v4(), "b".into(), 1000, Some("daily".into()), MissedPolicy::RunNow).unwrap();
+        scheduler.add(Uuid::new_v4(), "c".into(), 5000, None, MissedPolicy::RunNow).unwrap();
+
+        let due: Vec<_> = scheduler.take_due(1500).into_iter().map(|job| job.id).collect();
+        assert_eq!(due, vec![once.id, daily.id]);
+        assert!(scheduler.take_due(1500).is_empty());
+        let jobs = scheduler.list();
+        assert_eq!(jobs.iter().find(|job| job.id == daily.id).unwrap().at_unix, 1000 + 86400);
+
+        // A one-off run is only dropped once it's over
+        assert!(scheduler.is_scheduled(&once.id));
+        scheduler.finish(&once, None, 1600);
+        assert!(!scheduler.is_scheduled(&once.id));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn missed_runs_follow_their_policy() {
+        let (scheduler, path) = scheduler(ScheduleConfig::default());
+        let run_now = scheduler.add(Uuid::new_v4(), "a".into(), 1000, None, MissedPolicy::RunNow).unwrap();
+        let skip_once = scheduler.add(Uuid::new_v4(), "b".into(), 1000, None, MissedPolicy::Skip).unwrap();
+        let skip_hourly = scheduler.add(Uuid::new_v4(), "c".into(), 1000, Some("hourly".into()), MissedPolicy::Skip).unwrap();
+        let just_late = scheduler.add(Uuid::new_v4(), "d".into(), 9990, None, MissedPolicy::Skip).unwrap();
+
+        scheduler.catch_up(10000);
+        let jobs = scheduler.list();
+        let at = |id: Uuid| jobs.iter().find(|job| job.id == id).map(|job| job.at_unix);
+        assert_eq!(at(run_now.id), Some(1000));
+        assert_eq!(at(skip_once.id), None);
+        assert_eq!(at(skip_hourly.id), Some(1000 + 3 * 3600));
+        assert_eq!(at(just_late.id), Some(9990));
+
+        // What's left is what a restart finds
+        let reloaded = Scheduler::load(path.clone(), ScheduleConfig::default()).list();
+        assert_eq!(reloaded.len(), 3);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn failed_runs_are_retried_then_recorded() {
+        let config = ScheduleConfig {
+            retry_attempts: 2,
+            retry_interval: 0,
+        };
+        let (scheduler, path) = scheduler(config);
+        let job = scheduler.add(Uuid::new_v4(), "a".into(), 0, Some("daily".into()), MissedPolicy::RunNow).unwrap();
+        let tries = Arc::new(AtomicU32::new(0));
+        let send = {
+            let tries = tries.clone();
+            Arc::new(move |_| {
+                tries.fetch_add(1, Ordering::Relaxed);
+                async { Err(anyhow!("Peer not found")) }
+            })
+        };
+
+        scheduler.clone().attempt(job.clone(), send).await;
+        assert_eq!(tries.load(Ordering::Relaxed), 3);
+        let recorded = scheduler.list().into_iter().find(|j| j.id == job.id).unwrap();
+        assert_eq!(recorded.last_error.as_deref(), Some("Peer not found"));
+        assert!(recorded.last_run.is_some());
+        let _ = std::fs::remove_file(&path);
+    }
+}
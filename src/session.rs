@@ -0,0 +1,569 @@
+use crate::active::ActiveTransfer;
+use crate::capability;
+use crate::crypto;
+use crate::peer::PeerManager;
+use crate::protocol::ManifestEntry;
+use crate::transfer::{
+    ChunkSource, ConnectionContext, Outgoing, ReceiveOutcome, ReceiveProgress, RelayedPeer, Route, TransferError,
+    TransferMessage, TransferOutcome, TransferService, MAX_CONTROL_MESSAGE_LEN,
+};
+use crate::utils;
+use anyhow::Result;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use tokio::fs::File;
+use tokio::io::{AsyncBufRead, AsyncWrite};
+use tokio::sync::{mpsc, RwLock};
+use tokio::time::{timeout, Duration};
+use uuid::Uuid;
+
+/// Most files one session may list.
+const MAX_SESSION_FILES: usize = 10_000;
+/// Room for a session's files in each ManifestRequest, well within
+/// MAX_CONTROL_MESSAGE_LEN.
+const MANIFEST_LINE_BUDGET: usize = 32 * 1024;
+
+/// An incoming multi-file session, as far as it has got.
+pub struct ReceiveSession {
+    pub session_id: Uuid,
+    /// Who really sent it, when it came through a relay.
+    pub relayed_from: Option<RelayedPeer>,
+    /// Its files as listed up front.
+    pub files: Vec<ManifestEntry>,
+    /// Files saved so far.
+    pub completed: usize,
+    /// Files turned down or lost so far.
+    pub failed: usize,
+    /// Bytes received over all its files.
+    pub bytes_received: u64,
+}
+
+/// One file of a multi-file session.
+pub struct SessionFile {
+    pub transfer: ActiveTransfer,
+    pub path: PathBuf,
+}
+
+impl TransferService {
+    /// Receives the files of session `session_id` one after another over
+    /// this connection, once the rest of its manifest is in when `more`. A
+    /// file turned down here or cancelled by its sender is passed over; the
+    /// session ends at any other failure, keeping the files saved before it.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn receive_session<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        addr: SocketAddr,
+        context: &ConnectionContext,
+        session_id: Uuid,
+        files: Vec<ManifestEntry>,
+        sealed: Option<Vec<u8>>,
+        mut more: bool,
+        progress: &mut Option<ReceiveProgress>,
+    ) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        // A part we can't open still reads the rest, to turn the session down
+        let mut unopened = None;
+        let mut files = match Self::listed_files(&context.peers, session_id, files, sealed).await {
+            Ok(files) => files,
+            Err(e) => {
+                unopened = Some(e);
+                Vec::new()
+            }
+        };
+        while more && files.len() <= MAX_SESSION_FILES {
+            let line = timeout(Duration::from_secs(30), Self::read_raw_message(reader, MAX_CONTROL_MESSAGE_LEN)).await??;
+            match serde_json::from_str(&line)? {
+                TransferMessage::ManifestRequest {
+                    session_id: id,
+                    files: listed,
+                    more: continued,
+                    sealed,
+                } if id == session_id => {
+                    match Self::listed_files(&context.peers, session_id, listed, sealed).await {
+                        Ok(listed) => files.extend(listed),
+                        Err(e) => unopened = Some(e),
+                    }
+                    more = continued;
+                }
+                _ => return Err(anyhow::anyhow!("Session {} from {} broke off its manifest", session_id, addr)),
+            }
+        }
+        let mut pending: std::collections::HashSet<Uuid> = files.iter().map(|file| file.transfer_id).collect();
+        let refusal = if let Some(e) = unopened {
+            Some(format!("Could not open the sealed manifest: {}", e))
+        } else if files.is_empty() {
+            Some("No files listed".to_string())
+        } else if files.len() > MAX_SESSION_FILES {
+            Some(format!("Sessions take at most {} files", MAX_SESSION_FILES))
+        } else if pending.len() != files.len() {
+            Some("A file is listed twice".to_string())
+        } else {
+            None
+        };
+        if let Some(reason) = refusal {
+            tracing::warn!("Refusing session {} from {}: {}", session_id, addr, reason);
+            let reject = TransferMessage::Reject {
+                transfer_id: session_id,
+                reason: Some(reason),
+                protocol_version: None,
+                max_file_size: None,
+            };
+            return Self::write_message(stream, &reject).await;
+        }
+        Self::write_message(stream, &TransferMessage::ManifestAccept { session_id }).await?;
+        tracing::info!("Receiving {} files from {} in session {}", files.len(), addr, session_id);
+
+        let websocket = context.websocket.as_deref();
+        let mut session = ReceiveSession {
+            session_id,
+            relayed_from: context.relayed.clone(),
+            files,
+            completed: 0,
+            failed: 0,
+            bytes_received: 0,
+        };
+        if let Some(ws) = websocket {
+            ws.notify_session_started(addr, &session).await;
+        }
+        let context = ConnectionContext {
+            session_id: Some(session_id),
+            ..context.clone()
+        };
+        let result = loop {
+            if pending.is_empty() {
+                break Ok(());
+            }
+            // A sender ends a session early by hanging up
+            let Ok(Ok(line)) = timeout(Duration::from_secs(30), Self::read_raw_message(reader, MAX_CONTROL_MESSAGE_LEN)).await
+            else {
+                break Ok(());
+            };
+            let transfer_id = match serde_json::from_str(&line) {
+                Ok(TransferMessage::Request { transfer_id, .. }) if pending.remove(&transfer_id) => transfer_id,
+                _ => break Err(anyhow::anyhow!("Session {} from {} sent what isn't one of its files", session_id, addr)),
+            };
+            let mut file_progress = None;
+            let outcome = Self::receive_file(reader, stream, addr, &context, &line, None, &mut file_progress).await;
+            session.bytes_received += file_progress.as_ref().map_or(0, |file| file.received);
+            match outcome {
+                Ok(ReceiveOutcome::Complete) => {
+                    session.completed += 1;
+                    if let Err(e) = Self::write_message(stream, &TransferMessage::Saved { transfer_id }).await {
+                        break Err(e);
+                    }
+                }
+                // Nothing came, so there's no Saved to wait for
+                Ok(ReceiveOutcome::Identical) => session.completed += 1,
+                Ok(ReceiveOutcome::Refused(_) | ReceiveOutcome::CancelledBySender) => session.failed += 1,
+                // We cancelled it mid-stream, so what's still on its way isn't a Request
+                Ok(_) => {
+                    session.failed += 1;
+                    break Ok(());
+                }
+                Err(e) => {
+                    session.failed += 1;
+                    *progress = file_progress;
+                    break Err(e);
+                }
+            }
+            if let Some(ws) = websocket {
+                ws.notify_session_progress(&session).await;
+            }
+        };
+        tracing::info!(
+            "Session {} from {} ended: {} of {} files saved",
+            session_id,
+            addr,
+            session.completed,
+            session.files.len()
+        );
+        if let Some(ws) = websocket {
+            ws.notify_session_finished(addr, &session).await;
+        }
+        result
+    }
+
+    /// The files a ManifestRequest lists, opened first when sealed.
+    async fn listed_files(
+        peers: &RwLock<PeerManager>,
+        session_id: Uuid,
+        files: Vec<ManifestEntry>,
+        sealed: Option<Vec<u8>>,
+    ) -> Result<Vec<ManifestEntry>> {
+        match sealed {
+            Some(sealed) => Self::open_sealed(peers, session_id, &sealed).await,
+            None => Ok(files),
+        }
+    }
+
+    /// Sends `files` along `route` one after another over one connection, as
+    /// session `session_id`, each kept as a file of its own. Each file's
+    /// result goes to `results` as soon as it is known, tagged with its index
+    /// in `files`. Peers that don't take sessions get each file over a
+    /// connection of its own.
+    pub async fn send_files(
+        &self,
+        session_id: Uuid,
+        route: Route,
+        files: &[SessionFile],
+        results: mpsc::UnboundedSender<(usize, Result<TransferOutcome>)>,
+    ) {
+        // The manifest lists every checksum, so all are hashed first
+        let mut manifest = Vec::new();
+        for (index, file) in files.iter().enumerate() {
+            let entry = tokio::select! {
+                entry = Self::manifest_entry(file) => entry,
+                _ = file.transfer.cancelled() => Err(TransferError::Cancelled.into()),
+            };
+            match entry {
+                Ok(entry) => manifest.push((index, entry)),
+                Err(e) => {
+                    let _ = results.send((index, Err(e)));
+                }
+            }
+        }
+        let fail_all = |manifest: Vec<(usize, ManifestEntry)>, error: anyhow::Error| {
+            for (index, _) in manifest {
+                let _ = results.send((index, Err(TransferError::again(&error))));
+            }
+        };
+        let Some(&(first, _)) = manifest.first() else {
+            return;
+        };
+        let total_size = manifest.iter().map(|(_, entry)| entry.file_size).sum();
+        if let Err(e) = self.check_space(route, total_size).await {
+            return fail_all(manifest, e);
+        }
+
+        let takes_sessions = {
+            let peers = self.peers.read().await;
+            let known = match route {
+                Route::Peer(peer_id) | Route::Relay { destination: peer_id, .. } => peers.get_peer(&peer_id),
+                Route::Direct(address) => peers.find_by_ip(address.ip().to_canonical()),
+            };
+            known.is_none_or(|peer| capability::supports(&peer.capabilities, capability::SESSIONS))
+        };
+        if !takes_sessions {
+            for (index, entry) in manifest {
+                let transfer = &files[index].transfer;
+                let result = async {
+                    let (mut connection, context) = self.open_for(transfer, route).await?;
+                    let (reader, writer) = (&mut connection.reader, &mut connection.writer);
+                    Self::send_over(&context, reader, writer, transfer, connection.address, &files[index].path, entry.file_checksum)
+                        .await
+                };
+                let _ = results.send((index, result.await));
+            }
+            return;
+        }
+
+        // Connecting waits on the first file, and is cancelled with it
+        let (mut connection, context) = match self.open_for(&files[first].transfer, route).await {
+            Ok(opened) => opened,
+            Err(e) => return fail_all(manifest, e),
+        };
+        let (reader, writer) = (&mut connection.reader, &mut connection.writer);
+        Self::send_session(&context, reader, writer, connection.address, session_id, files, manifest, &results).await;
+    }
+
+    /// How `file` is listed in its session's manifest.
+    async fn manifest_entry(file: &SessionFile) -> Result<ManifestEntry> {
+        let file_size = tokio::fs::metadata(&file.path).await?.len();
+        let file_checksum = utils::calculate_file_checksum(&file.path).await.ok();
+        file.transfer.trace().record("checksummed", format!("{} bytes", file_size));
+        Ok(ManifestEntry {
+            transfer_id: file.transfer.id(),
+            filename: file
+                .path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("unknown")
+                .to_string(),
+            file_size,
+            file_checksum,
+        })
+    }
+
+    /// Sends the files `manifest` lists, out of `files`, over one connection:
+    /// the manifest, then each file as a connection of its own would carry
+    /// it. A file the receiver turns down or that we cancel is passed over;
+    /// the session ends at any other failure, and the files it didn't get
+    /// to fail with it.
+    #[allow(clippy::too_many_arguments)]
+    async fn send_session<R, W>(
+        context: &ConnectionContext,
+        reader: &mut R,
+        stream: &mut W,
+        peer_address: SocketAddr,
+        session_id: Uuid,
+        files: &[SessionFile],
+        manifest: Vec<(usize, ManifestEntry)>,
+        results: &mpsc::UnboundedSender<(usize, Result<TransferOutcome>)>,
+    ) where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let seal_to = {
+            let peers = context.peers.read().await;
+            let known = match &context.relayed {
+                Some(relayed) => peers.get_peer(&relayed.peer_id),
+                None => peers.find_by_ip(peer_address.ip().to_canonical()),
+            };
+            known
+                .filter(|peer| capability::supports(&peer.capabilities, capability::SEALED))
+                .and_then(|peer| peer.identity_key.clone())
+        };
+        // Sealed, a line's bytes go as numbers of up to four characters each
+        let line_budget = if seal_to.is_some() { MANIFEST_LINE_BUDGET / 4 } else { MANIFEST_LINE_BUDGET };
+        let listed = async {
+            let entries: Vec<ManifestEntry> = manifest.iter().map(|(_, entry)| entry.clone()).collect();
+            let mut lines = Vec::new();
+            let (mut line, mut line_len) = (Vec::new(), 0);
+            for entry in entries {
+                let entry_len = serde_json::to_string(&entry)?.len() + 1;
+                if !line.is_empty() && line_len + entry_len > line_budget {
+                    lines.push(std::mem::take(&mut line));
+                    line_len = 0;
+                }
+                line.push(entry);
+                line_len += entry_len;
+            }
+            lines.push(line);
+            let last = lines.len() - 1;
+            for (n, files) in lines.into_iter().enumerate() {
+                let request = match &seal_to {
+                    Some(recipient) => TransferMessage::ManifestRequest {
+                        session_id,
+                        files: Vec::new(),
+                        more: n < last,
+                        sealed: Some(crypto::seal_for(recipient, session_id, &serde_json::to_vec(&files)?)?),
+                    },
+                    None => TransferMessage::ManifestRequest {
+                        session_id,
+                        files,
+                        more: n < last,
+                        sealed: None,
+                    },
+                };
+                Self::write_message(stream, &request).await?;
+            }
+            let answer = timeout(Duration::from_secs(30), Self::read_message(reader, MAX_CONTROL_MESSAGE_LEN))
+                .await?
+                .map_err(|e| anyhow::anyhow!("Peer didn't take the session: {}", e))?;
+            match answer {
+                TransferMessage::ManifestAccept { session_id: id } if id == session_id => Ok(()),
+                TransferMessage::Reject { reason, .. } => Err(anyhow::anyhow!(
+                    "Session rejected by peer: {}",
+                    reason.unwrap_or_else(|| "No reason provided".to_string())
+                )),
+                _ => Err(anyhow::anyhow!("Unexpected response")),
+            }
+        };
+        if let Err(e) = listed.await {
+            for (index, _) in manifest {
+                let _ = results.send((index, Err(TransferError::again(&e))));
+            }
+            return;
+        }
+
+        let mut remaining = manifest.into_iter();
+        for (index, entry) in remaining.by_ref() {
+            let SessionFile { transfer, path } = &files[index];
+            // Nothing has gone for a file until the receiver takes it, so one
+            // it doesn't take leaves the connection as it was
+            let offered = match Outgoing::file(&context.config, path, entry.file_checksum).await {
+                Ok(outgoing) => Self::offer(context, reader, stream, transfer, peer_address, outgoing).await,
+                Err(e) => Err(e),
+            };
+            let offer = match offered {
+                Ok(offer) => offer,
+                Err(e) => {
+                    let _ = results.send((index, Err(e)));
+                    continue;
+                }
+            };
+            let sent = async {
+                if offer.identical {
+                    return Ok(Self::identical_outcome(transfer, offer, context.connectivity));
+                }
+                let _permit = Self::wait_for_send_slot(context, stream, transfer, offer.keepalive).await?;
+                transfer.trace().record("send_slot", "");
+                let source = ChunkSource::File(File::open(path).await?);
+                Self::stream_chunks(context, reader, stream, transfer, offer, source).await
+            }
+            .await;
+            // A cancelled file's Cancel leaves the connection ready for the next
+            let carry_on = sent.is_ok() || transfer.is_cancelled();
+            let _ = results.send((index, sent));
+            if !carry_on {
+                break;
+            }
+        }
+        for (index, _) in remaining {
+            let _ = results.send((index, Err(anyhow::anyhow!("Session ended before this file was sent"))));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::transfer::tests::{context, downloaded_files, peer_address, scratch_dir, sealing_peer, service};
+    use sha2::{Digest, Sha256};
+    use std::path::Path;
+    use tokio::io::BufReader;
+
+    /// Sends `files` as session `session_id` over one connection to a
+    /// receiver on `service`, listed as `manifest` says. Returns each file's
+    /// result in order and how the receiver came out, with the first line
+    /// of the manifest as sent when it saw the session through.
+    async fn session_through_pipe(
+        service: &TransferService,
+        dir: &Path,
+        session_id: Uuid,
+        files: Vec<SessionFile>,
+        manifest: Vec<(usize, ManifestEntry)>,
+    ) -> (Vec<Result<TransferOutcome>>, Result<String>) {
+        let (ours, theirs) = tokio::io::duplex(1024 * 1024);
+        let ((our_reader, mut our_writer), (their_reader, mut their_writer)) = (tokio::io::split(ours), tokio::io::split(theirs));
+        let (mut our_reader, mut their_reader) = (BufReader::new(our_reader), BufReader::new(their_reader));
+        let sender_context = context(service, dir);
+        let receiver_context = context(service, dir);
+        let (results_tx, mut results) = mpsc::unbounded_channel();
+        let sending = TransferService::send_session(
+            &sender_context,
+            &mut our_reader,
+            &mut our_writer,
+            peer_address(),
+            session_id,
+            &files,
+            manifest,
+            &results_tx,
+        );
+        let receiving = async {
+            let line = TransferService::read_raw_message(&mut their_reader, MAX_CONTROL_MESSAGE_LEN).await?;
+            let TransferMessage::ManifestRequest { session_id, files, more, sealed } = serde_json::from_str(&line)? else {
+                anyhow::bail!("expected a manifest");
+            };
+            let mut progress = None;
+            TransferService::receive_session(
+                &mut their_reader,
+                &mut their_writer,
+                peer_address(),
+                &receiver_context,
+                session_id,
+                files,
+                sealed,
+                more,
+                &mut progress,
+            )
+            .await?;
+            Ok(line)
+        };
+        let (_, received) = timeout(Duration::from_secs(30), async { tokio::join!(sending, receiving) }).await.unwrap();
+        drop(results_tx);
+        let mut sent = Vec::new();
+        while let Some((index, result)) = results.recv().await {
+            assert_eq!(index, sent.len());
+            sent.push(result);
+        }
+        (sent, received)
+    }
+
+    #[tokio::test]
+    async fn a_failure_late_in_a_session_keeps_the_files_before_it() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        std::fs::create_dir_all(dir.join("outgoing")).unwrap();
+        let mut files = Vec::new();
+        let mut manifest = Vec::new();
+        for n in 0..300 {
+            let path = dir.join("outgoing").join(format!("file-{:03}.txt", n));
+            std::fs::write(&path, format!("contents of file {}", n)).unwrap();
+            let file = SessionFile {
+                transfer: service.track_send(Uuid::new_v4()),
+                path,
+            };
+            manifest.push((n, TransferService::manifest_entry(&file).await.unwrap()));
+            files.push(file);
+        }
+        // The last file changed since it was listed
+        manifest[299].1.file_checksum = Some(hex::encode(Sha256::digest(b"what it was")));
+
+        let (sent, received) = session_through_pipe(&service, &dir, Uuid::new_v4(), files, manifest).await;
+        assert_eq!(sent.len(), 300);
+        assert!(sent[..299].iter().all(Result::is_ok));
+        assert!(sent[299].is_err());
+        assert!(received.is_err());
+        let mut saved = downloaded_files(&dir);
+        saved.sort();
+        let expected: Vec<String> = (0..299).map(|n| format!("file-{:03}.txt", n)).collect();
+        assert_eq!(saved, expected);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("downloads").join("file-298.txt")).unwrap(),
+            "contents of file 298"
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_manifest_listing_a_file_twice_is_refused() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let path = dir.join("notes.txt");
+        std::fs::write(&path, b"notes").unwrap();
+        let files: Vec<SessionFile> = (0..2)
+            .map(|_| SessionFile {
+                transfer: service.track_send(Uuid::new_v4()),
+                path: path.clone(),
+            })
+            .collect();
+        let entry = TransferService::manifest_entry(&files[0]).await.unwrap();
+        let manifest = vec![(0, entry.clone()), (1, entry)];
+
+        let (sent, received) = session_through_pipe(&service, &dir, Uuid::new_v4(), files, manifest).await;
+        assert!(received.is_ok());
+        assert!(matches!(&sent[..], [Err(a), Err(b)]
+            if a.to_string() == "Session rejected by peer: A file is listed twice" && a.to_string() == b.to_string()));
+        assert!(downloaded_files(&dir).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_session_to_a_peer_that_can_open_it_is_listed_sealed() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let identity = service.peers.read().await.identity();
+        sealing_peer(&service, &identity).await;
+        std::fs::create_dir_all(dir.join("outgoing")).unwrap();
+        let mut files = Vec::new();
+        let mut manifest = Vec::new();
+        for n in 0..100 {
+            let path = dir.join("outgoing").join(format!("minutes-{:03}.txt", n));
+            std::fs::write(&path, format!("minutes of meeting {}", n)).unwrap();
+            let file = SessionFile {
+                transfer: service.track_send(Uuid::new_v4()),
+                path,
+            };
+            manifest.push((n, TransferService::manifest_entry(&file).await.unwrap()));
+            files.push(file);
+        }
+
+        let (sent, received) = session_through_pipe(&service, &dir, Uuid::new_v4(), files, manifest).await;
+        assert!(sent.iter().all(Result::is_ok));
+        assert_eq!(downloaded_files(&dir).len(), 100);
+        // Over several lines, none naming a file
+        let first_line = received.unwrap();
+        assert!(!first_line.contains("minutes-"));
+        assert!(matches!(
+            serde_json::from_str(&first_line).unwrap(),
+            TransferMessage::ManifestRequest { files, more: true, sealed: Some(_), .. } if files.is_empty()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::active::{ActiveTransfer, Direction};
+use crate::config::AppConfig;
+use crate::protocol::ShareEntry;
+use crate::transfer::{
+    ConnectionContext, ReceiveOutcome, ReceiveProgress, TransferError, TransferMessage, TransferService,
+    MAX_CONTROL_MESSAGE_LEN,
+};
+use crate::utils;
+use anyhow::{anyhow, bail, Result};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::io::{AsyncBufRead, AsyncWrite, BufReader};
+use tokio::time::{timeout, Duration};
+use uuid::Uuid;
+
+/// Files up to this size get a checksum in listings.
+const CHECKSUM_LIMIT: u64 = 1024 * 1024;
+/// Answer to share requests when no share is configured.
+const SHARING_DISABLED: &str = "Sharing is disabled";
+/// Longest share listing accepted.
+pub const MAX_LISTING_LEN: usize = 16 * 1024 * 1024;
+/// How long a FetchShared waits for the file's Request; the peer hashes the
+/// file first.
+pub const FETCH_RESPONSE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Resolves `relative` inside the share `root`, refusing anything that would
+/// leave it, whether through `..`, an absolute path or a symlink.
+pub async fn resolve(root: &Path, relative: &str) -> Result<PathBuf> {
+    let relative = Path::new(relative);
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        bail!("Path is outside the shared folder");
+    }
+
+    let root = tokio::fs::canonicalize(root)
+        .await
+        .map_err(|e| anyhow!("Shared folder is unavailable: {}", e))?;
+    let path = tokio::fs::canonicalize(root.join(relative))
+        .await
+        .map_err(|_| anyhow!("No such file or folder in the share"))?;
+    if !path.starts_with(&root) {
+        bail!("Path is outside the shared folder");
+    }
+    Ok(path)
+}
+
+/// Lists a folder of the share, folders first. Entries that lead outside
+/// the share are left out.
+pub async fn list(root: &Path, relative: &str) -> Result<Vec<ShareEntry>> {
+    let dir = resolve(root, relative).await?;
+    if !tokio::fs::metadata(&dir).await?.is_dir() {
+        bail!("Not a folder");
+    }
+    let root = tokio::fs::canonicalize(root).await?;
+
+    let mut entries = Vec::new();
+    let mut read_dir = tokio::fs::read_dir(&dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let Ok(target) = tokio::fs::canonicalize(entry.path()).await else {
+            continue;
+        };
+        if !target.starts_with(&root) {
+            continue;
+        }
+        let Ok(metadata) = tokio::fs::metadata(&target).await else {
+            continue;
+        };
+
+        let checksum = if metadata.is_file() && metadata.len() <= CHECKSUM_LIMIT {
+            utils::calculate_file_checksum(&target).await.ok()
+        } else {
+            None
+        };
+        entries.push(ShareEntry {
+            name: entry.file_name().to_string_lossy().to_string(),
+            size: if metadata.is_file() { metadata.len() } else { 0 },
+            mtime: metadata
+                .modified()
+                .ok()
+                .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                .map(|since| since.as_secs()),
+            is_dir: metadata.is_dir(),
+            checksum,
+        });
+    }
+
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(entries)
+}
+
+impl TransferService {
+    /// Answers a ListShare.
+    pub(crate) async fn serve_listing<W: AsyncWrite + Unpin>(stream: &mut W, config: &AppConfig, path: String) -> Result<()> {
+        let response = match config.share.root() {
+            None => TransferMessage::ShareError {
+                message: SHARING_DISABLED.to_string(),
+            },
+            Some(root) => match list(&root, &path).await {
+                Ok(entries) => TransferMessage::ShareListing { path, entries },
+                Err(e) => TransferMessage::ShareError { message: e.to_string() },
+            },
+        };
+        Self::write_message(stream, &response).await
+    }
+
+    /// Answers a FetchShared by sending the file over the same connection.
+    pub(crate) async fn serve_fetch<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        addr: SocketAddr,
+        context: &ConnectionContext,
+        transfer_id: Uuid,
+        relative_path: &str,
+    ) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let file_path = match context.config.share.root() {
+            None => Err(anyhow::anyhow!(SHARING_DISABLED)),
+            Some(root) => match resolve(&root, relative_path).await {
+                Ok(path) if path.is_file() => Ok(path),
+                Ok(_) => Err(anyhow::anyhow!("Not a file")),
+                Err(e) => Err(e),
+            },
+        };
+        let file_path = match file_path {
+            Ok(path) => path,
+            Err(e) => {
+                tracing::warn!("Refused to serve {:?} from the share to {}: {}", relative_path, addr, e);
+                let error = TransferMessage::ShareError { message: e.to_string() };
+                return Self::write_message(stream, &error).await;
+            }
+        };
+
+        tracing::info!("Serving {:?} from the share to {}", relative_path, addr);
+        Self::serve_requested(reader, stream, addr, context, transfer_id, &file_path).await
+    }
+
+    /// Sends a file the peer asked for as `transfer_id` over the same connection.
+    pub(crate) async fn serve_requested<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        addr: SocketAddr,
+        context: &ConnectionContext,
+        transfer_id: Uuid,
+        file_path: &Path,
+    ) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let transfer = context.active.register(transfer_id, Direction::Send);
+        let file_checksum = tokio::select! {
+            checksum = utils::calculate_file_checksum(file_path) => checksum.ok(),
+            _ = transfer.cancelled() => return Ok(()),
+        };
+        Self::send_over(context, reader, stream, &transfer, addr, file_path, file_checksum)
+            .await
+            .map(|_| ())
+    }
+
+    /// Lists a folder of a peer's share; "" is the root.
+    pub async fn browse_share(&self, peer_address: SocketAddr, path: &str) -> Result<Vec<ShareEntry>> {
+        let mut stream = self.connect_peer(peer_address).await?;
+        let (read_half, mut stream) = stream.split();
+        let mut reader = BufReader::new(read_half);
+
+        let request = TransferMessage::ListShare { path: path.to_string() };
+        Self::write_message(&mut stream, &request).await?;
+        let response = timeout(Duration::from_secs(30), Self::read_message(&mut reader, MAX_LISTING_LEN)).await??;
+        match response {
+            TransferMessage::ShareListing { entries, .. } => Ok(entries),
+            TransferMessage::ShareError { message } => Err(anyhow::anyhow!(message)),
+            _ => Err(anyhow::anyhow!("Unexpected response")),
+        }
+    }
+
+    /// Downloads a file from a peer's share into downloads, as the transfer
+    /// registered with `track_receive`. Returns what was received.
+    pub async fn fetch_shared(
+        &self,
+        transfer: &ActiveTransfer,
+        peer_address: SocketAddr,
+        relative_path: &str,
+    ) -> Result<ReceiveProgress> {
+        let fetch = TransferMessage::FetchShared {
+            transfer_id: transfer.id(),
+            relative_path: relative_path.to_string(),
+        };
+        self.fetch(transfer, peer_address, &fetch, self.context()).await
+    }
+
+    /// Sends `request` and receives the file the peer answers it with.
+    pub(crate) async fn fetch(
+        &self,
+        transfer: &ActiveTransfer,
+        peer_address: SocketAddr,
+        request: &TransferMessage,
+        context: ConnectionContext,
+    ) -> Result<ReceiveProgress> {
+        let mut stream = tokio::select! {
+            stream = self.connect_peer(peer_address) => stream?,
+            _ = transfer.cancelled() => return Err(TransferError::Cancelled.into()),
+        };
+        let (read_half, mut stream) = stream.split();
+        let mut reader = BufReader::new(read_half);
+
+        Self::write_message(&mut stream, request).await?;
+        let request_line = tokio::select! {
+            line = timeout(FETCH_RESPONSE_TIMEOUT, Self::read_raw_message(&mut reader, MAX_CONTROL_MESSAGE_LEN)) => line??,
+            _ = transfer.cancelled() => return Err(TransferError::Cancelled.into()),
+        };
+        if let Ok(TransferMessage::ShareError { message } | TransferMessage::SyncError { message }) =
+            serde_json::from_str(&request_line)
+        {
+            return Err(anyhow::anyhow!(message));
+        }
+
+        let mut progress = None;
+        let outcome = Self::receive_file(
+            &mut reader,
+            &mut stream,
+            peer_address,
+            &context,
+            &request_line,
+            Some(transfer),
+            &mut progress,
+        )
+        .await?;
+        match outcome {
+            ReceiveOutcome::Complete => progress.ok_or_else(|| anyhow::anyhow!("Transfer did not start")),
+            ReceiveOutcome::CancelledLocally => Err(TransferError::Cancelled.into()),
+            ReceiveOutcome::CancelledBySender => Err(anyhow::anyhow!("Cancelled by the peer")),
+            ReceiveOutcome::Blocked(reason) | ReceiveOutcome::Refused(reason) => Err(anyhow::anyhow!(reason)),
+            // Never for a file we asked for
+            ReceiveOutcome::Identical => Err(anyhow::anyhow!("Transfer did not start")),
+        }
+    }
+}
@@ -0,0 +1,265 @@
+use crate::protocol::SlotUsage;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{watch, AcquireError, OwnedSemaphorePermit, Semaphore, TryAcquireError};
+use uuid::Uuid;
+
+/// Most transfers one direction may be allowed to run at once.
+pub const MAX_SLOTS: usize = 256;
+
+/// Concurrent transfer slots for one direction, resizable while transfers
+/// hold them. Growing frees slots at once; shrinking takes back free slots
+/// and then each one a running transfer gives up, so nothing is cut short.
+pub struct Slots {
+    semaphore: Arc<Semaphore>,
+    state: Mutex<SlotsState>,
+    /// Ticks whenever someone joins or leaves the line.
+    line_moved: watch::Sender<()>,
+}
+
+struct SlotsState {
+    limit: usize,
+    /// Slots still held by transfers that go away instead of being handed on
+    /// when they're given up, after a shrink.
+    owed: usize,
+    /// Transfers waiting in turn for a slot, next first.
+    line: VecDeque<Uuid>,
+}
+
+/// Why a transfer couldn't wait its turn for a slot.
+#[derive(Debug, thiserror::Error)]
+pub enum QueueError {
+    #[error("{0} transfers are already waiting for a slot")]
+    Full(usize),
+    #[error(transparent)]
+    Closed(#[from] AcquireError),
+}
+
+/// A place in line, given up when dropped.
+struct InLine<'a> {
+    slots: &'a Slots,
+    id: Uuid,
+}
+
+impl Drop for InLine<'_> {
+    fn drop(&mut self) {
+        self.slots.state.lock().unwrap().line.retain(|id| *id != self.id);
+        self.slots.line_moved.send_replace(());
+    }
+}
+
+/// A slot, or several, given back when dropped.
+pub struct SlotPermit {
+    permit: Option<OwnedSemaphorePermit>,
+    slots: Arc<Slots>,
+}
+
+impl Slots {
+    /// `limit` slots, all free.
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            state: Mutex::new(SlotsState {
+                limit,
+                owed: 0,
+                line: VecDeque::new(),
+            }),
+            line_moved: watch::Sender::new(()),
+        })
+    }
+
+    /// Waits for a free slot.
+    pub async fn acquire(self: &Arc<Self>) -> Result<SlotPermit, AcquireError> {
+        self.acquire_many(1).await
+    }
+
+    /// Waits until `count` slots are free and takes them all.
+    pub async fn acquire_many(self: &Arc<Self>, count: usize) -> Result<SlotPermit, AcquireError> {
+        let permit = self.semaphore.clone().acquire_many_owned(count as u32).await?;
+        Ok(self.wrap(permit))
+    }
+
+    /// Takes a slot if one is free.
+    pub fn try_acquire(self: &Arc<Self>) -> Result<SlotPermit, TryAcquireError> {
+        let permit = self.semaphore.clone().try_acquire_owned()?;
+        Ok(self.wrap(permit))
+    }
+
+    /// Waits for a slot as `id`, behind whoever already waits this way.
+    /// `on_position` hears its place in line (1 is next) whenever that
+    /// changes; a free slot nobody waits for is taken without queueing.
+    /// Refused when `max_waiting` (0 for any number) already wait. Dropping
+    /// the future leaves the line without taking a slot.
+    pub async fn acquire_in_turn<F>(
+        self: &Arc<Self>,
+        id: Uuid,
+        max_waiting: usize,
+        mut on_position: F,
+    ) -> Result<SlotPermit, QueueError>
+    where
+        F: FnMut(usize),
+    {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.line.is_empty() {
+                if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+                    return Ok(self.wrap(permit));
+                }
+            }
+            if max_waiting > 0 && state.line.len() >= max_waiting {
+                return Err(QueueError::Full(state.line.len()));
+            }
+            state.line.push_back(id);
+        }
+        let _in_line = InLine { slots: self, id };
+        let mut line_moved = self.line_moved.subscribe();
+        self.line_moved.send_replace(());
+
+        let acquiring = self.semaphore.clone().acquire_owned();
+        tokio::pin!(acquiring);
+        let mut told = 0;
+        loop {
+            tokio::select! {
+                permit = &mut acquiring => return Ok(self.wrap(permit?)),
+                _ = line_moved.changed() => {
+                    let position = self.state.lock().unwrap().line.iter().position(|waiting| *waiting == id);
+                    match position.map(|index| index + 1) {
+                        Some(position) if position != told => {
+                            told = position;
+                            on_position(position);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// How many transfers wait in turn for a slot.
+    pub fn waiting(&self) -> usize {
+        self.state.lock().unwrap().line.len()
+    }
+
+    fn wrap(self: &Arc<Self>, permit: OwnedSemaphorePermit) -> SlotPermit {
+        SlotPermit {
+            permit: Some(permit),
+            slots: self.clone(),
+        }
+    }
+
+    /// How many slots there are.
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    /// Changes how many slots there are. Slots over a lowered limit that
+    /// transfers hold stay theirs until they finish.
+    pub fn set_limit(&self, limit: usize) {
+        let mut state = self.state.lock().unwrap();
+        if limit > state.limit {
+            // Slots not yet taken back are simply kept
+            let grow = limit - state.limit;
+            let forgiven = grow.min(state.owed);
+            state.owed -= forgiven;
+            self.semaphore.add_permits(grow - forgiven);
+        } else {
+            let shrink = state.limit - limit;
+            let taken = self.semaphore.forget_permits(shrink);
+            state.owed += shrink - taken;
+        }
+        state.limit = limit;
+    }
+
+    /// Slots taken, which runs over the limit while a lowered one catches up.
+    pub fn usage(&self) -> SlotUsage {
+        let state = self.state.lock().unwrap();
+        SlotUsage {
+            active: (state.limit + state.owed).saturating_sub(self.semaphore.available_permits()),
+            limit: state.limit,
+        }
+    }
+}
+
+impl Drop for SlotPermit {
+    fn drop(&mut self) {
+        let Some(mut permit) = self.permit.take() else {
+            return;
+        };
+        let mut state = self.slots.state.lock().unwrap();
+        let repaid = state.owed.min(permit.num_permits());
+        if repaid == 0 {
+            return;
+        }
+        state.owed -= repaid;
+        if let Some(owed) = permit.split(repaid) {
+            owed.forget();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn lowered_limit_waits_for_running_transfers() {
+        let slots = Slots::new(3);
+        let first = slots.acquire().await.unwrap();
+        let second = slots.acquire().await.unwrap();
+
+        // One free slot goes at once; the other waits for a transfer to end
+        slots.set_limit(1);
+        assert_eq!(slots.usage().active, 2);
+        assert_eq!(slots.usage().limit, 1);
+        assert!(slots.try_acquire().is_err());
+        drop(first);
+        assert!(slots.try_acquire().is_err());
+        drop(second);
+        assert_eq!(slots.usage().active, 0);
+        let only = slots.try_acquire().unwrap();
+        assert!(slots.try_acquire().is_err());
+        drop(only);
+
+        // Raising it again while owed forgives the debt first
+        let held = slots.acquire().await.unwrap();
+        slots.set_limit(0);
+        slots.set_limit(2);
+        assert_eq!(slots.usage().active, 1);
+        let _next = slots.try_acquire().unwrap();
+        assert!(slots.try_acquire().is_err());
+        drop(held);
+        assert_eq!(slots.usage().active, 1);
+        assert!(slots.try_acquire().is_ok());
+    }
+
+    #[tokio::test]
+    async fn transfers_wait_in_line_and_may_leave_it() {
+        let slots = Slots::new(1);
+        let running = slots.acquire_in_turn(Uuid::new_v4(), 2, |_| panic!("a free slot has no line")).await.unwrap();
+
+        let (told, mut positions) = tokio::sync::mpsc::unbounded_channel();
+        let waiter = |told: tokio::sync::mpsc::UnboundedSender<(usize, usize)>, n| {
+            let slots = slots.clone();
+            tokio::spawn(async move {
+                slots
+                    .acquire_in_turn(Uuid::new_v4(), 2, move |position| told.send((n, position)).unwrap())
+                    .await
+            })
+        };
+        let first = waiter(told.clone(), 1);
+        assert_eq!(positions.recv().await, Some((1, 1)));
+        let second = waiter(told.clone(), 2);
+        assert_eq!(positions.recv().await, Some((2, 2)));
+        assert!(matches!(slots.acquire_in_turn(Uuid::new_v4(), 2, |_| {}).await, Err(QueueError::Full(2))));
+
+        // Leaving the line moves those behind up, and takes no slot
+        first.abort();
+        assert_eq!(positions.recv().await, Some((2, 1)));
+        assert_eq!(slots.waiting(), 1);
+        drop(running);
+        let next = second.await.unwrap().unwrap();
+        assert_eq!((slots.waiting(), slots.usage().active), (0, 1));
+        drop(next);
+        assert_eq!(slots.usage().active, 0);
+    }
+}
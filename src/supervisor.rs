@@ -0,0 +1,112 @@
+use crate::config::SupervisorConfig;
+use crate::websocket::WebSocketService;
+use anyhow::Result;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Delay before the first restart; doubles on each consecutive failure.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// A service that stays up this long is considered healthy again.
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// Runs a service and restarts it with exponential backoff whenever it
+/// errors, panics or returns before shutdown.
+///
+/// Returns `Ok` once shutdown is requested, or an error when the service
+/// failed more than `max_restart_attempts` times in a row.
+pub async fn supervise<F, Fut>(
+    name: &'static str,
+    config: SupervisorConfig,
+    shutdown: CancellationToken,
+    websocket: Arc<WebSocketService>,
+    mut run: F,
+) -> Result<()>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let mut failures = 0u32;
+
+    loop {
+        tracing::info!("{} service started", name);
+        websocket.notify_service_status(name, "running", None).await;
+
+        let started = Instant::now();
+        let outcome = tokio::spawn(run()).await;
+
+        if shutdown.is_cancelled() {
+            return Ok(());
+        }
+
+        let reason = match outcome {
+            Ok(Ok(())) => "exited unexpectedly".to_string(),
+            Ok(Err(e)) => e.to_string(),
+            Err(e) if e.is_panic() => "panicked".to_string(),
+            Err(e) => e.to_string(),
+        };
+
+        if started.elapsed() >= STABLE_RUN {
+            failures = 0;
+        }
+        failures += 1;
+
+        if failures > config.max_restart_attempts {
+            tracing::error!("{} service failed permanently: {}", name, reason);
+            websocket.notify_service_status(name, "failed", Some(reason.clone())).await;
+            return Err(anyhow::anyhow!("{} service failed: {}", name, reason));
+        }
+
+        let backoff = (INITIAL_BACKOFF * 2u32.saturating_pow(failures - 1)).min(MAX_BACKOFF);
+        tracing::warn!(
+            "{} service stopped ({}), restarting in {}s (attempt {}/{})",
+            name,
+            reason,
+            backoff.as_secs(),
+            failures,
+            config.max_restart_attempts
+        );
+        websocket.notify_service_status(name, "restarting", Some(reason)).await;
+
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown.cancelled() => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::node::Node;
+    use std::sync::Mutex;
+
+    #[tokio::test(start_paused = true)]
+    async fn a_failing_service_is_restarted_ever_more_slowly_then_given_up_on() {
+        let dir = std::env::temp_dir().join(format!("p2p-sharing-test-{}", uuid::Uuid::new_v4()));
+        let node = Node::builder(Arc::new(AppConfig::default())).data_dir(&dir).build().unwrap();
+        let config = SupervisorConfig {
+            max_restart_attempts: 7,
+        };
+        let started = Arc::new(Mutex::new(Vec::new()));
+        let run = || {
+            let started = started.clone();
+            async move {
+                started.lock().unwrap().push(Instant::now());
+                Err(anyhow::anyhow!("boom"))
+            }
+        };
+
+        let result = supervise("test", config, CancellationToken::new(), node.websocket().clone(), run).await;
+        assert_eq!(result.unwrap_err().to_string(), "test service failed: boom");
+        // Started once and restarted as often as allowed, each delay double
+        // the last until the cap
+        let started = started.lock().unwrap();
+        let delays: Vec<_> = started.windows(2).map(|pair| (pair[1] - pair[0]).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,987 @@
+use crate::active::ActiveTransfer;
+use crate::config::{AppConfig, ExistingFilePolicy, SyncPair};
+use crate::peer::{Peer, PeerManager};
+use crate::protocol::{ServerMessage, SyncPairStatus};
+use crate::share::{self, FETCH_RESPONSE_TIMEOUT, MAX_LISTING_LEN};
+use crate::transfer::{ConnectionContext, ReceiveProgress, TransferMessage, TransferService};
+use crate::utils;
+use crate::websocket::WebSocketService;
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufRead, AsyncWrite, BufReader};
+use tokio::sync::RwLock;
+use tokio::time::{sleep, timeout, Instant};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+/// Folder inside each synced folder where incoming files are received
+/// before they're moved into place; never synced itself.
+pub const SYNC_DIR: &str = ".p2p-sync";
+/// Directory under the data directory holding what each pair last agreed on.
+const STATE_DIR: &str = "sync";
+/// How often pairs are checked for a peer coming online or a sync falling due.
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a deletion is remembered, for a peer that was away meanwhile.
+const TOMBSTONE_TTL: u64 = 30 * 24 * 3600;
+
+/// One file of a synced folder.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SyncEntry {
+    /// Relative to the folder, `/`-separated.
+    pub path: String,
+    pub size: u64,
+    /// Last modification, in seconds since the Unix epoch.
+    pub mtime: u64,
+    pub checksum: String,
+}
+
+/// A synced file that was deleted, and the version that was.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub path: String,
+    pub checksum: String,
+    pub deleted_at: u64,
+}
+
+/// What a synced folder holds, as exchanged with the paired peer.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub entries: Vec<SyncEntry>,
+    /// Only sent when deletions propagate.
+    #[serde(default)]
+    pub tombstones: Vec<Tombstone>,
+}
+
+/// A pair's view of its folder, kept between runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PairState {
+    /// The folder as of the last scan.
+    files: BTreeMap<String, SyncEntry>,
+    /// Checksum of each file as both sides last had it.
+    synced: BTreeMap<String, String>,
+    tombstones: BTreeMap<String, Tombstone>,
+}
+
+/// A change a sync makes to the local folder.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Pull(SyncEntry),
+    Delete(Tombstone),
+}
+
+/// Progress of a pair since startup.
+#[derive(Debug, Clone, Default)]
+struct Progress {
+    online: bool,
+    syncing: bool,
+    pending: usize,
+    transferred: u64,
+    conflicts: u64,
+    failed: u64,
+    last_sync: Option<chrono::DateTime<chrono::Utc>>,
+    last_error: Option<String>,
+}
+
+impl PairState {
+    fn manifest(&self, propagate_deletes: bool) -> Manifest {
+        Manifest {
+            entries: self.files.values().cloned().collect(),
+            tombstones: if propagate_deletes {
+                self.tombstones.values().cloned().collect()
+            } else {
+                Vec::new()
+            },
+        }
+    }
+
+    /// Records the files both sides already have the same, and returns the
+    /// changes that bring ours up to date with `remote`. Changes made only
+    /// here are left for the peer to fetch; when both sides changed a file
+    /// the newest wins.
+    fn plan(&mut self, remote: &Manifest, propagate_deletes: bool) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for theirs in &remote.entries {
+            let pull = match self.files.get(&theirs.path) {
+                Some(ours) if ours.checksum == theirs.checksum => {
+                    self.synced.insert(theirs.path.clone(), theirs.checksum.clone());
+                    false
+                }
+                Some(ours) => match self.synced.get(&theirs.path) {
+                    Some(base) if *base == ours.checksum => true,
+                    Some(base) if *base == theirs.checksum => false,
+                    // Ties go to the larger checksum, so both sides pick the same
+                    _ => (theirs.mtime, &theirs.checksum) > (ours.mtime, &ours.checksum),
+                },
+                // Still the version we deleted; the peer will delete it too
+                None => !self
+                    .tombstones
+                    .get(&theirs.path)
+                    .is_some_and(|deleted| propagate_deletes && deleted.checksum == theirs.checksum),
+            };
+            if pull {
+                actions.push(Action::Pull(theirs.clone()));
+            }
+        }
+
+        if propagate_deletes {
+            let theirs: HashMap<&str, &SyncEntry> = remote.entries.iter().map(|e| (e.path.as_str(), e)).collect();
+            for deleted in &remote.tombstones {
+                let unchanged = self
+                    .files
+                    .get(&deleted.path)
+                    .is_some_and(|ours| ours.checksum == deleted.checksum);
+                if unchanged && !theirs.contains_key(deleted.path.as_str()) {
+                    actions.push(Action::Delete(deleted.clone()));
+                }
+            }
+        }
+        actions
+    }
+}
+
+/// Keeps folders the same here and on a paired peer. Each side fetches what
+/// the other has newer, so a pair syncs both ways once both have run.
+pub struct SyncService {
+    config: Arc<AppConfig>,
+    pairs: std::sync::RwLock<Vec<SyncPair>>,
+    /// Set while the pairs differ from those in the config file.
+    unsaved: AtomicBool,
+    peers: Arc<RwLock<PeerManager>>,
+    transfer_service: Arc<TransferService>,
+    websocket_service: OnceLock<Arc<WebSocketService>>,
+    state_dir: PathBuf,
+    states: Mutex<HashMap<String, Arc<tokio::sync::Mutex<PairState>>>>,
+    progress: Mutex<HashMap<String, Progress>>,
+}
+
+impl SyncService {
+    /// Syncs the folders in `sync.pairs` once `run` is called.
+    pub fn new(config: Arc<AppConfig>, peers: Arc<RwLock<PeerManager>>, transfer_service: Arc<TransferService>) -> Self {
+        Self {
+            pairs: std::sync::RwLock::new(config.sync.pairs.clone()),
+            unsaved: AtomicBool::new(false),
+            config,
+            peers,
+            state_dir: transfer_service.data_dir().join(STATE_DIR),
+            transfer_service,
+            websocket_service: OnceLock::new(),
+            states: Mutex::new(HashMap::new()),
+            progress: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Tells clients how syncing goes.
+    pub fn set_websocket_service(&self, service: Arc<WebSocketService>) {
+        let _ = self.websocket_service.set(service);
+    }
+
+    /// The synced folders.
+    pub fn pairs(&self) -> Vec<SyncPair> {
+        self.pairs.read().unwrap().clone()
+    }
+
+    /// Adds a pair, replacing any of the same name, and saves it to the
+    /// config file. The pair syncs even if saving fails; `flush` tries again.
+    pub fn add_pair(&self, pair: SyncPair) -> Result<()> {
+        if pair.name.trim().is_empty() || pair.peer.trim().is_empty() {
+            bail!("A synced folder needs a name and a peer");
+        }
+        if pair.root().is_none() {
+            bail!("Can't find the home directory for {:?}", pair.path);
+        }
+        if pair.interval == 0 {
+            bail!("Sync interval must be at least a second");
+        }
+        {
+            let mut pairs = self.pairs.write().unwrap();
+            pairs.retain(|existing| existing.name != pair.name);
+            pairs.push(pair);
+        }
+        self.unsaved.store(true, Ordering::SeqCst);
+        self.flush()
+            .map_err(|e| anyhow!("Synced folder is in effect but couldn't be saved: {}", e))
+    }
+
+    /// Stops syncing a pair and forgets what it had synced. Files stay.
+    pub fn remove_pair(&self, name: &str) -> Result<()> {
+        {
+            let mut pairs = self.pairs.write().unwrap();
+            let before = pairs.len();
+            pairs.retain(|pair| pair.name != name);
+            if pairs.len() == before {
+                bail!("No synced folder named {:?}", name);
+            }
+        }
+        self.states.lock().unwrap().remove(name);
+        self.progress.lock().unwrap().remove(name);
+        let _ = std::fs::remove_file(self.state_path(name));
+        self.unsaved.store(true, Ordering::SeqCst);
+        self.flush()
+            .map_err(|e| anyhow!("Synced folder is removed but the change couldn't be saved: {}", e))
+    }
+
+    /// Saves pair changes the config file doesn't have yet.
+    pub fn flush(&self) -> Result<()> {
+        if !self.unsaved.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let mut config = (*self.config).clone();
+        config.sync.pairs = self.pairs();
+        if let Err(e) = config.save() {
+            self.unsaved.store(true, Ordering::SeqCst);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// How each synced folder is doing.
+    pub fn status(&self) -> Vec<SyncPairStatus> {
+        let progress = self.progress.lock().unwrap();
+        self.pairs()
+            .into_iter()
+            .map(|pair| {
+                let progress = progress.get(&pair.name).cloned().unwrap_or_default();
+                SyncPairStatus {
+                    name: pair.name,
+                    peer: pair.peer,
+                    path: pair.path,
+                    online: progress.online,
+                    syncing: progress.syncing,
+                    pending: progress.pending,
+                    transferred: progress.transferred,
+                    conflicts: progress.conflicts,
+                    failed: progress.failed,
+                    last_sync: progress.last_sync,
+                    last_error: progress.last_error,
+                }
+            })
+            .collect()
+    }
+
+    /// The manifest of `folder` for `peer`, freshly scanned.
+    pub async fn manifest_for(&self, folder: &str, peer: &Peer) -> Result<Manifest> {
+        let pair = self.pair_for(folder, peer)?;
+        let root = Self::root_of(&pair).await?;
+        let state = self.state(&pair.name);
+        let mut state = state.lock().await;
+        scan(&root, &mut state, pair.propagate_deletes, now_unix()).await?;
+        self.save_state(&pair.name, &state);
+        Ok(state.manifest(pair.propagate_deletes))
+    }
+
+    /// The local file at `relative_path` of `folder`, for `peer` to fetch.
+    pub async fn resolve_for(&self, folder: &str, peer: &Peer, relative_path: &str) -> Result<PathBuf> {
+        let pair = self.pair_for(folder, peer)?;
+        let root = Self::root_of(&pair).await?;
+        local_path(&root, relative_path)?;
+        let path = share::resolve(&root, relative_path).await?;
+        if !path.is_file() {
+            bail!("Not a file");
+        }
+        Ok(path)
+    }
+
+    /// Syncs each pair when its peer comes online and every `interval` while
+    /// it stays online, until shutdown.
+    pub async fn run(self: Arc<Self>, shutdown: CancellationToken) -> Result<()> {
+        let mut last_round: HashMap<String, Instant> = HashMap::new();
+        loop {
+            for pair in self.pairs() {
+                let peer = self.find_peer(&pair).await;
+                let was_online = self.update(&pair.name, |progress| {
+                    std::mem::replace(&mut progress.online, peer.is_some())
+                });
+                if was_online != peer.is_some() {
+                    self.notify().await;
+                }
+                let Some(peer) = peer else { continue };
+
+                let due = !was_online
+                    || last_round
+                        .get(&pair.name)
+                        .is_none_or(|at| at.elapsed() >= Duration::from_secs(pair.interval));
+                if !due || self.update(&pair.name, |progress| progress.syncing) {
+                    continue;
+                }
+                self.update(&pair.name, |progress| progress.syncing = true);
+                last_round.insert(pair.name.clone(), Instant::now());
+                tokio::spawn(self.clone().sync_round(pair, peer));
+            }
+
+            tokio::select! {
+                _ = sleep(CHECK_INTERVAL) => {}
+                _ = shutdown.cancelled() => return Ok(()),
+            }
+        }
+    }
+
+    async fn sync_round(self: Arc<Self>, pair: SyncPair, peer: Peer) {
+        self.notify().await;
+        let result = self.sync(&pair, &peer).await;
+        if let Err(e) = &result {
+            tracing::warn!("Syncing {:?} with {} failed: {}", pair.name, peer.hostname, e);
+        }
+        self.update(&pair.name, |progress| {
+            progress.syncing = false;
+            progress.pending = 0;
+            match result {
+                Ok(()) => {
+                    progress.last_sync = Some(chrono::Utc::now());
+                    progress.last_error = None;
+                }
+                Err(e) => progress.last_error = Some(e.to_string()),
+            }
+        });
+        self.notify().await;
+    }
+
+    /// One sync of `pair`: fetches what the peer has newer and applies its
+    /// deletions.
+    async fn sync(&self, pair: &SyncPair, peer: &Peer) -> Result<()> {
+        let root = Self::root_of(pair).await?;
+        let state = self.state(&pair.name);
+        {
+            let mut state = state.lock().await;
+            scan(&root, &mut state, pair.propagate_deletes, now_unix()).await?;
+            self.save_state(&pair.name, &state);
+        }
+        let remote = self.transfer_service.fetch_manifest(peer.address, &pair.name).await?;
+        let actions = {
+            let mut state = state.lock().await;
+            let actions = state.plan(&remote, pair.propagate_deletes);
+            self.save_state(&pair.name, &state);
+            actions
+        };
+        if actions.is_empty() {
+            return Ok(());
+        }
+        tracing::info!("Syncing {} changes to {:?} from {}", actions.len(), pair.name, peer.hostname);
+        self.update(&pair.name, |progress| progress.pending = actions.len());
+        self.notify().await;
+
+        let mut last_error = None;
+        for action in actions {
+            let result = match &action {
+                Action::Pull(entry) => self.pull(pair, peer, &root, &state, entry).await,
+                Action::Delete(deleted) => self.delete(pair, &root, &state, deleted).await.map(|_| false),
+            };
+            self.update(&pair.name, |progress| {
+                progress.pending = progress.pending.saturating_sub(1);
+                match (&action, &result) {
+                    (Action::Pull(_), Ok(conflict)) => {
+                        progress.transferred += 1;
+                        progress.conflicts += u64::from(*conflict);
+                    }
+                    (_, Ok(_)) => {}
+                    (_, Err(_)) => progress.failed += 1,
+                }
+            });
+            if let Err(e) = result {
+                let path = match &action {
+                    Action::Pull(entry) => &entry.path,
+                    Action::Delete(deleted) => &deleted.path,
+                };
+                tracing::warn!("Couldn't sync {:?} in {:?}: {}", path, pair.name, e);
+                last_error = Some(anyhow!("{}: {}", path, e));
+            }
+            self.notify().await;
+        }
+        match last_error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Fetches the peer's version of a file and moves it into place. A local
+    /// version changed since the last sync is kept as a conflicted copy;
+    /// returns whether there was one.
+    async fn pull(
+        &self,
+        pair: &SyncPair,
+        peer: &Peer,
+        root: &Path,
+        state: &tokio::sync::Mutex<PairState>,
+        entry: &SyncEntry,
+    ) -> Result<bool> {
+        let target = local_path(root, &entry.path)?;
+        let staging_dir = root.join(SYNC_DIR);
+        tokio::fs::create_dir_all(&staging_dir).await?;
+
+        let transfer = self.transfer_service.track_receive(Uuid::new_v4());
+        let received = self
+            .transfer_service
+            .fetch_synced(&transfer, peer.address, &pair.name, &entry.path, &staging_dir)
+            .await?;
+        let staged = staging_dir.join(&received.filename);
+        let checksum = received.checksum.clone().unwrap_or_else(|| entry.checksum.clone());
+
+        let mut state = state.lock().await;
+        let current = match tokio::fs::metadata(&target).await {
+            Ok(metadata) if metadata.is_file() => Some(utils::calculate_file_checksum(&target).await?),
+            Ok(_) => bail!("A folder is in the way"),
+            Err(_) => None,
+        };
+        let conflict = current
+            .as_ref()
+            .is_some_and(|current| *current != checksum && state.synced.get(&entry.path) != Some(current));
+        if conflict {
+            let hostname = self.peers.read().await.local_hostname().to_string();
+            let copy = conflicted_copy(&target, &hostname, &chrono::Local::now().format("%Y-%m-%d").to_string());
+            tokio::fs::rename(&target, &copy).await?;
+            tracing::warn!("Sync conflict on {:?} in {:?}; kept ours as {}", entry.path, pair.name, copy.display());
+        }
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(&staged, &target).await?;
+        // Keep the peer's time, or the copy would look like a newer edit
+        let mtime = UNIX_EPOCH + Duration::from_secs(entry.mtime);
+        std::fs::OpenOptions::new().write(true).open(&target)?.set_modified(mtime)?;
+
+        state.files.insert(
+            entry.path.clone(),
+            SyncEntry {
+                path: entry.path.clone(),
+                size: received.file_size,
+                mtime: entry.mtime,
+                checksum: checksum.clone(),
+            },
+        );
+        state.synced.insert(entry.path.clone(), checksum);
+        state.tombstones.remove(&entry.path);
+        self.save_state(&pair.name, &state);
+        Ok(conflict)
+    }
+
+    /// Deletes a file the peer deleted, unless it has changed here since.
+    async fn delete(
+        &self,
+        pair: &SyncPair,
+        root: &Path,
+        state: &tokio::sync::Mutex<PairState>,
+        deleted: &Tombstone,
+    ) -> Result<()> {
+        let target = local_path(root, &deleted.path)?;
+        let mut state = state.lock().await;
+        match utils::calculate_file_checksum(&target).await {
+            Ok(current) if current == deleted.checksum => tokio::fs::remove_file(&target).await?,
+            // Changed since; the peer will fetch it back
+            Ok(_) => return Ok(()),
+            Err(_) => {}
+        }
+        tracing::info!("Deleted {:?} from {:?} as the peer did", deleted.path, pair.name);
+        state.files.remove(&deleted.path);
+        state.synced.remove(&deleted.path);
+        state.tombstones.insert(deleted.path.clone(), deleted.clone());
+        self.save_state(&pair.name, &state);
+        Ok(())
+    }
+
+    fn pair_for(&self, folder: &str, peer: &Peer) -> Result<SyncPair> {
+        self.pairs
+            .read()
+            .unwrap()
+            .iter()
+            .find(|pair| pair.name == folder && is_pair_peer(pair, peer))
+            .cloned()
+            .ok_or_else(|| anyhow!("No synced folder {:?} is paired with this peer", folder))
+    }
+
+    async fn find_peer(&self, pair: &SyncPair) -> Option<Peer> {
+        self.peers
+            .read()
+            .await
+            .list_peers()
+            .into_iter()
+            .find(|peer| !peer.identity_changed && is_pair_peer(pair, peer))
+    }
+
+    async fn root_of(pair: &SyncPair) -> Result<PathBuf> {
+        let root = pair
+            .root()
+            .ok_or_else(|| anyhow!("Can't find the home directory for {:?}", pair.path))?;
+        tokio::fs::create_dir_all(&root).await?;
+        Ok(root)
+    }
+
+    fn state(&self, name: &str) -> Arc<tokio::sync::Mutex<PairState>> {
+        self.states
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_insert_with(|| {
+                let path = self.state_path(name);
+                let state = std::fs::read_to_string(&path)
+                    .ok()
+                    .and_then(|content| serde_json::from_str(&content).ok())
+                    .unwrap_or_default();
+                Arc::new(tokio::sync::Mutex::new(state))
+            })
+            .clone()
+    }
+
+    fn state_path(&self, name: &str) -> PathBuf {
+        self.state_dir.join(format!("{}.json", utils::safe_filename(name)))
+    }
+
+    fn save_state(&self, name: &str, state: &PairState) {
+        let save = || -> Result<()> {
+            std::fs::create_dir_all(&self.state_dir)?;
+            utils::write_atomic(&self.state_path(name), &serde_json::to_vec(state)?)?;
+            Ok(())
+        };
+        if let Err(e) = save() {
+            tracing::error!("Failed to save the sync state of {:?}: {}", name, e);
+        }
+    }
+
+    fn update<T>(&self, name: &str, change: impl FnOnce(&mut Progress) -> T) -> T {
+        change(self.progress.lock().unwrap().entry(name.to_string()).or_default())
+    }
+
+    async fn notify(&self) {
+        if let Some(ws) = self.websocket_service.get() {
+            ws.broadcast(ServerMessage::SyncStatus { pairs: self.status() }).await;
+        }
+    }
+}
+
+fn is_pair_peer(pair: &SyncPair, peer: &Peer) -> bool {
+    peer.id.to_string().eq_ignore_ascii_case(&pair.peer) || peer.hostname.eq_ignore_ascii_case(&pair.peer)
+}
+
+fn now_unix() -> u64 {
+    chrono::Utc::now().timestamp().max(0) as u64
+}
+
+/// Where `relative` goes under `root`, refusing anything outside it or in
+/// the staging folder.
+fn local_path(root: &Path, relative: &str) -> Result<PathBuf> {
+    let relative = Path::new(relative);
+    let mut components = relative.components();
+    let valid = components.clone().all(|component| matches!(component, Component::Normal(_)));
+    let first = components.next();
+    if !valid || first.is_none() || first == Some(Component::Normal(SYNC_DIR.as_ref())) {
+        bail!("Path is outside the synced folder");
+    }
+    Ok(root.join(relative))
+}
+
+/// A free name beside `path` for the local side of a conflict.
+fn conflicted_copy(path: &Path, hostname: &str, date: &str) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+    let extension = path.extension().map(|e| format!(".{}", e.to_string_lossy())).unwrap_or_default();
+    (1..)
+        .map(|n| {
+            let counter = if n == 1 { String::new() } else { format!(" {}", n) };
+            path.with_file_name(format!("{} (conflicted copy from {} {}{}){}", stem, hostname, date, counter, extension))
+        })
+        .find(|candidate| !candidate.exists())
+        .expect("some counter is free")
+}
+
+/// Brings `state` up to date with the folder. Checksums are reused for files
+/// whose size and time haven't changed; synced files that are gone become
+/// tombstones when deletions propagate.
+async fn scan(root: &Path, state: &mut PairState, propagate_deletes: bool, now: u64) -> Result<()> {
+    let mut found = BTreeMap::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(relative) = dirs.pop() {
+        let mut read_dir = match tokio::fs::read_dir(root.join(&relative)).await {
+            Ok(read_dir) => read_dir,
+            Err(e) if relative.as_os_str().is_empty() => return Err(e.into()),
+            Err(_) => continue,
+        };
+        while let Some(entry) = read_dir.next_entry().await? {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if (relative.as_os_str().is_empty() && name == SYNC_DIR) || name.ends_with(".part") {
+                continue;
+            }
+            // Symlinks are left alone, so nothing outside the folder is synced
+            let Ok(file_type) = entry.file_type().await else {
+                continue;
+            };
+            let path = relative.join(&name);
+            if file_type.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+            if !file_type.is_file() {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            let key = path
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let size = metadata.len();
+            let mtime = metadata
+                .modified()
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs());
+            let checksum = match state.files.get(&key) {
+                Some(known) if known.size == size && known.mtime == mtime => known.checksum.clone(),
+                _ => match utils::calculate_file_checksum(&root.join(&path)).await {
+                    Ok(checksum) => checksum,
+                    // Gone or unreadable meanwhile; the next scan will tell
+                    Err(_) => continue,
+                },
+            };
+            found.insert(
+                key.clone(),
+                SyncEntry {
+                    path: key,
+                    size,
+                    mtime,
+                    checksum,
+                },
+            );
+        }
+    }
+
+    for (path, entry) in &state.files {
+        if found.contains_key(path) || state.synced.remove(path).is_none() || !propagate_deletes {
+            continue;
+        }
+        state.tombstones.insert(
+            path.clone(),
+            Tombstone {
+                path: path.clone(),
+                checksum: entry.checksum.clone(),
+                deleted_at: now,
+            },
+        );
+    }
+    state.tombstones.retain(|path, deleted| !found.contains_key(path) && deleted.deleted_at + TOMBSTONE_TTL > now);
+    state.files = found;
+    Ok(())
+}
+
+impl TransferService {
+    /// The peer at `addr`, for sync requests, which are never relayed.
+    async fn sync_peer(addr: SocketAddr, context: &ConnectionContext) -> Result<(Arc<SyncService>, Peer)> {
+        let Some(sync) = context.sync.clone() else {
+            return Err(anyhow::anyhow!("Sync is not available"));
+        };
+        if context.relayed.is_some() {
+            return Err(anyhow::anyhow!("Sync requests can't be relayed"));
+        }
+        let peer = context.peers.read().await.find_by_ip(addr.ip().to_canonical()).cloned();
+        let peer = peer.ok_or_else(|| anyhow::anyhow!("Unknown peer"))?;
+        Ok((sync, peer))
+    }
+
+    /// Answers a SyncManifestRequest from a peer paired on `folder`.
+    pub(crate) async fn serve_manifest<W: AsyncWrite + Unpin>(
+        stream: &mut W,
+        addr: SocketAddr,
+        context: &ConnectionContext,
+        folder: String,
+    ) -> Result<()> {
+        let manifest = match Self::sync_peer(addr, context).await {
+            Ok((sync, peer)) => sync.manifest_for(&folder, &peer).await,
+            Err(e) => Err(e),
+        };
+        let response = match manifest {
+            Ok(manifest) => TransferMessage::SyncManifest { folder, manifest },
+            Err(e) => {
+                tracing::warn!("Refused the manifest of synced folder {:?} to {}: {}", folder, addr, e);
+                TransferMessage::SyncError { message: e.to_string() }
+            }
+        };
+        Self::write_message(stream, &response).await
+    }
+
+    /// Answers a SyncFetch by sending the file over the same connection.
+    pub(crate) async fn serve_sync_fetch<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        addr: SocketAddr,
+        context: &ConnectionContext,
+        transfer_id: Uuid,
+        folder: &str,
+        relative_path: &str,
+    ) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let file_path = match Self::sync_peer(addr, context).await {
+            Ok((sync, peer)) => sync.resolve_for(folder, &peer, relative_path).await,
+            Err(e) => Err(e),
+        };
+        let file_path = match file_path {
+            Ok(path) => path,
+            Err(e) => {
+                tracing::warn!("Refused to serve {:?} from synced folder {:?} to {}: {}", relative_path, folder, addr, e);
+                let error = TransferMessage::SyncError { message: e.to_string() };
+                return Self::write_message(stream, &error).await;
+            }
+        };
+
+        tracing::info!("Serving {:?} from synced folder {:?} to {}", relative_path, folder, addr);
+        Self::serve_requested(reader, stream, addr, context, transfer_id, &file_path).await
+    }
+
+    /// Asks a peer for the manifest of the folder it syncs with us as `folder`.
+    pub async fn fetch_manifest(&self, peer_address: SocketAddr, folder: &str) -> Result<Manifest> {
+        let mut stream = self.connect_peer(peer_address).await?;
+        let (read_half, mut stream) = stream.split();
+        let mut reader = BufReader::new(read_half);
+
+        let request = TransferMessage::SyncManifestRequest {
+            folder: folder.to_string(),
+        };
+        Self::write_message(&mut stream, &request).await?;
+        // The peer rescans its folder first
+        let response = timeout(FETCH_RESPONSE_TIMEOUT, Self::read_message(&mut reader, MAX_LISTING_LEN)).await??;
+        match response {
+            TransferMessage::SyncManifest { manifest, .. } => Ok(manifest),
+            TransferMessage::SyncError { message } => Err(anyhow::anyhow!(message)),
+            _ => Err(anyhow::anyhow!("Unexpected response")),
+        }
+    }
+
+    /// Downloads a file from a synced folder of a peer into `staging_dir`, as
+    /// the transfer registered with `track_receive`. Returns what was received.
+    pub async fn fetch_synced(
+        &self,
+        transfer: &ActiveTransfer,
+        peer_address: SocketAddr,
+        folder: &str,
+        relative_path: &str,
+        staging_dir: &Path,
+    ) -> Result<ReceiveProgress> {
+        let fetch = TransferMessage::SyncFetch {
+            transfer_id: transfer.id(),
+            folder: folder.to_string(),
+            relative_path: relative_path.to_string(),
+        };
+        let context = ConnectionContext {
+            downloads_dir: staging_dir.to_path_buf(),
+            content_addressed: false,
+            organize_by_type: false,
+            on_existing_file: ExistingFilePolicy::Overwrite,
+            ..self.context()
+        };
+        self.fetch(transfer, peer_address, &fetch, context).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfer::tests::{serve_once, service};
+
+    fn entry(path: &str, mtime: u64, checksum: &str) -> SyncEntry {
+        SyncEntry {
+            path: path.to_string(),
+            size: 1,
+            mtime,
+            checksum: checksum.to_string(),
+        }
+    }
+
+    fn tombstone(path: &str, checksum: &str) -> Tombstone {
+        Tombstone {
+            path: path.to_string(),
+            checksum: checksum.to_string(),
+            deleted_at: 0,
+        }
+    }
+
+    fn state(files: Vec<SyncEntry>, synced: &[(&str, &str)]) -> PairState {
+        PairState {
+            files: files.into_iter().map(|e| (e.path.clone(), e)).collect(),
+            synced: synced.iter().map(|(p, c)| (p.to_string(), c.to_string())).collect(),
+            tombstones: BTreeMap::new(),
+        }
+    }
+
+    fn remote(entries: Vec<SyncEntry>, tombstones: &[Tombstone]) -> Manifest {
+        Manifest {
+            entries,
+            tombstones: tombstones.to_vec(),
+        }
+    }
+
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("p2p-sharing-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn only_files_changed_on_the_peer_are_fetched() {
+        let mut ours = state(
+            vec![entry("same", 1, "a"), entry("theirs-changed", 1, "b"), entry("ours-changed", 5, "c2")],
+            &[("theirs-changed", "b"), ("ours-changed", "c")],
+        );
+        let theirs = [
+            entry("same", 1, "a"),
+            entry("theirs-changed", 5, "b2"),
+            entry("ours-changed", 1, "c"),
+            entry("new", 1, "d"),
+        ];
+
+        let actions = ours.plan(&remote(theirs.to_vec(), &[]), false);
+        assert_eq!(actions, vec![Action::Pull(theirs[1].clone()), Action::Pull(theirs[3].clone())]);
+        assert_eq!(ours.synced.get("same").map(String::as_str), Some("a"));
+    }
+
+    #[test]
+    fn conflicts_go_to_the_newest_on_both_sides() {
+        let here = entry("doc", 10, "ours");
+        let there = entry("doc", 20, "theirs");
+        let mut ours = state(vec![here.clone()], &[("doc", "base")]);
+        let mut theirs = state(vec![there.clone()], &[("doc", "base")]);
+
+        assert_eq!(ours.plan(&remote(vec![there.clone()], &[]), false), vec![Action::Pull(there)]);
+        assert!(theirs.plan(&remote(vec![here], &[]), false).is_empty());
+
+        // A tie still has exactly one winner
+        let (a, b) = (entry("doc", 10, "a"), entry("doc", 10, "b"));
+        let pulls_a = state(vec![b.clone()], &[]).plan(&remote(vec![a.clone()], &[]), false);
+        let pulls_b = state(vec![a], &[]).plan(&remote(vec![b], &[]), false);
+        assert_eq!(pulls_a.len() + pulls_b.len(), 1);
+    }
+
+    #[test]
+    fn deletions_only_propagate_when_asked() {
+        let kept = entry("kept", 1, "a");
+        let edited = entry("edited", 1, "new");
+        let deleted_remote = [tombstone("kept", "a"), tombstone("edited", "old")];
+
+        let mut ours = state(vec![kept.clone(), edited.clone()], &[("kept", "a"), ("edited", "old")]);
+        assert!(ours.plan(&remote(vec![], &deleted_remote), false).is_empty());
+        // Only the file that's still what the peer deleted goes
+        assert_eq!(
+            ours.plan(&remote(vec![], &deleted_remote), true),
+            vec![Action::Delete(deleted_remote[0].clone())]
+        );
+
+        // What we deleted isn't fetched back unless the peer changed it since
+        let mut ours = state(vec![], &[]);
+        ours.tombstones.insert("gone".to_string(), tombstone("gone", "a"));
+        let unchanged = entry("gone", 1, "a");
+        assert!(ours.plan(&remote(vec![unchanged.clone()], &[]), true).is_empty());
+        assert_eq!(ours.plan(&remote(vec![unchanged.clone()], &[]), false), vec![Action::Pull(unchanged)]);
+        let changed = entry("gone", 2, "b");
+        assert_eq!(ours.plan(&remote(vec![changed.clone()], &[]), true), vec![Action::Pull(changed)]);
+    }
+
+    #[tokio::test]
+    async fn scan_skips_staging_and_tombstones_synced_files() {
+        let root = scratch_dir();
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(root.join(SYNC_DIR)).unwrap();
+        std::fs::write(root.join("a.txt"), b"a").unwrap();
+        std::fs::write(root.join("sub/b.txt"), b"b").unwrap();
+        std::fs::write(root.join("c.txt.part"), b"c").unwrap();
+        std::fs::write(root.join(SYNC_DIR).join("d.txt"), b"d").unwrap();
+
+        let mut state = PairState::default();
+        scan(&root, &mut state, true, 100).await.unwrap();
+        assert_eq!(state.files.keys().collect::<Vec<_>>(), vec!["a.txt", "sub/b.txt"]);
+
+        state.synced.insert("a.txt".to_string(), state.files["a.txt"].checksum.clone());
+        std::fs::remove_file(root.join("a.txt")).unwrap();
+        std::fs::remove_file(root.join("sub/b.txt")).unwrap();
+        scan(&root, &mut state, true, 200).await.unwrap();
+        assert!(state.files.is_empty());
+        // Only what had been synced is a deletion to pass on
+        assert_eq!(state.tombstones.keys().collect::<Vec<_>>(), vec!["a.txt"]);
+        assert_eq!(state.tombstones["a.txt"].deleted_at, 200);
+
+        // A file that comes back is no longer deleted
+        std::fs::write(root.join("a.txt"), b"again").unwrap();
+        scan(&root, &mut state, true, 300).await.unwrap();
+        assert!(state.tombstones.is_empty());
+        let _ = std::fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn peer_paths_stay_inside_the_folder() {
+        let root = Path::new("/sync");
+        assert_eq!(local_path(root, "a/b.txt").unwrap(), root.join("a/b.txt"));
+        assert!(local_path(root, "../b.txt").is_err());
+        assert!(local_path(root, "/etc/passwd").is_err());
+        assert!(local_path(root, "").is_err());
+        assert!(local_path(root, &format!("{}/x", SYNC_DIR)).is_err());
+    }
+
+    #[test]
+    fn conflicted_copies_get_a_free_name() {
+        let dir = scratch_dir();
+        let path = dir.join("notes.txt");
+        let first = conflicted_copy(&path, "laptop", "2026-10-16");
+        assert_eq!(first, dir.join("notes (conflicted copy from laptop 2026-10-16).txt"));
+        std::fs::write(&first, b"x").unwrap();
+        assert_eq!(
+            conflicted_copy(&path, "laptop", "2026-10-16"),
+            dir.join("notes (conflicted copy from laptop 2026-10-16 2).txt")
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn synced_files_are_served_only_to_the_paired_peer() {
+        let (ours, theirs) = (scratch_dir(), scratch_dir());
+        let us = service(AppConfig::default(), &ours);
+        let our_id = us.peers.read().await.local_id();
+        let folder = theirs.join("docs");
+        std::fs::create_dir_all(folder.join("sub")).unwrap();
+        std::fs::write(folder.join("sub").join("notes.txt"), b"synced").unwrap();
+
+        let mut config = AppConfig::default();
+        config.sync.pairs.push(SyncPair {
+            name: "docs".to_string(),
+            path: folder.to_string_lossy().to_string(),
+            peer: our_id.to_string(),
+            propagate_deletes: false,
+            interval: 60,
+        });
+        let them = Arc::new(service(config.clone(), &theirs));
+        let loopback = SocketAddr::from(([127, 0, 0, 1], 7879));
+        them.peers
+            .write()
+            .await
+            .add_or_update_peer(Peer::from_discovery(our_id, loopback, "us".to_string(), None));
+        them.set_sync_service(Arc::new(SyncService::new(Arc::new(config), them.peers.clone(), them.clone())));
+
+        let staging = ours.join("staging");
+        // (folder, path, served)
+        let cases = [
+            ("docs", "sub/notes.txt", true),
+            ("photos", "sub/notes.txt", false),
+            ("docs", "../docs/sub/notes.txt", false),
+        ];
+        for (name, path, served) in cases {
+            let (address, serving) = serve_once(&them, &theirs, true).await;
+            let transfer = us.track_receive(Uuid::new_v4());
+            let result = us.fetch_synced(&transfer, address, name, path, &staging).await;
+            serving.await.unwrap();
+            match result {
+                Ok(received) if served => {
+                    assert_eq!(std::fs::read(staging.join(&received.filename)).unwrap(), b"synced");
+                }
+                Err(_) if !served => {}
+                other => panic!("{} {}: unexpected {:?}", name, path, other.map(|r| r.filename)),
+            }
+        }
+        let _ = std::fs::remove_dir_all(&ours);
+        let _ = std::fs::remove_dir_all(&theirs);
+    }
+}
@@ -0,0 +1,485 @@
+use crate::config::SecurityConfig;
+use crate::identity;
+use anyhow::{anyhow, bail, Result};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{self, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{CertificateError, ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+/// Self-signed certificate made on first run, in the data directory.
+const CERT_FILE: &str = "tls_cert.pem";
+const KEY_FILE: &str = "tls_key.pem";
+/// First byte of a TLS handshake record. Plain connections open with a JSON
+/// message instead.
+const HANDSHAKE_RECORD: u8 = 0x16;
+/// How long a connection gets to show which it is and finish its handshake;
+/// the same a plain one gets for its first message.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Name asked for in every handshake. Certificates are pinned by
+/// fingerprint, so no name is ever checked.
+const SERVER_NAME: &str = "p2p-sharing";
+
+/// A connection on the transfer port, in TLS or plain.
+pub(crate) enum PeerStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl PeerStream {
+    /// Read and write halves that can be used at once, borrowing the stream.
+    pub(crate) fn split(&mut self) -> (ReadHalf<&mut Self>, WriteHalf<&mut Self>) {
+        tokio::io::split(self)
+    }
+
+    /// Read and write halves that can be used at once.
+    pub(crate) fn into_split(self) -> (ReadHalf<Self>, WriteHalf<Self>) {
+        tokio::io::split(self)
+    }
+
+    /// Address of the other end.
+    pub(crate) fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Plain(stream) => stream.peer_addr(),
+            Self::Tls(stream) => stream.get_ref().0.peer_addr(),
+        }
+    }
+
+    /// Fingerprint of the certificate the other end presented; None in plain.
+    pub(crate) fn peer_fingerprint(&self) -> Option<String> {
+        let Self::Tls(stream) = self else {
+            return None;
+        };
+        stream.get_ref().1.peer_certificates()?.first().map(fingerprint)
+    }
+}
+
+impl From<TcpStream> for PeerStream {
+    fn from(stream: TcpStream) -> Self {
+        Self::Plain(stream)
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Self::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Plain(stream) => stream.is_write_vectored(),
+            Self::Tls(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Our certificate on the transfer port, and whether plain connections are
+/// still taken and made.
+pub(crate) struct Tls {
+    acceptor: TlsAcceptor,
+    provider: Arc<CryptoProvider>,
+    fingerprint: String,
+    required: bool,
+}
+
+impl Tls {
+    /// TLS as `config` sets it up, presenting the certificate it names or a
+    /// self-signed one kept in `data_dir`; None when it's off.
+    pub(crate) fn load(config: &SecurityConfig, data_dir: &Path) -> Result<Option<Arc<Self>>> {
+        if !config.tls_enabled() {
+            return Ok(None);
+        }
+        let (cert_path, key_path) = match config.cert_and_key()? {
+            Some(paths) => paths,
+            None => {
+                let (cert_path, key_path) = (data_dir.join(CERT_FILE), data_dir.join(KEY_FILE));
+                if !cert_path.exists() || !key_path.exists() {
+                    generate(&cert_path, &key_path)?;
+                }
+                (cert_path, key_path)
+            }
+        };
+        let chain = CertificateDer::pem_file_iter(&cert_path)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| anyhow!("Can't read TLS certificate {}: {}", cert_path.display(), e))?;
+        let Some(leaf) = chain.first() else {
+            bail!("No certificate in {}", cert_path.display());
+        };
+        let fingerprint = fingerprint(leaf);
+        let key = PrivateKeyDer::from_pem_file(&key_path)
+            .map_err(|e| anyhow!("Can't read TLS key {}: {}", key_path.display(), e))?;
+
+        let provider = Arc::new(crypto::ring::default_provider());
+        let server = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .with_no_client_auth()
+            .with_single_cert(chain, key)?;
+        tracing::info!("TLS certificate fingerprint: {}", fingerprint);
+        Ok(Some(Arc::new(Self {
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+            provider,
+            fingerprint,
+            required: config.require_tls,
+        })))
+    }
+
+    /// SHA-256 of our certificate, as announced to peers.
+    pub(crate) fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// Whether plain connections are refused.
+    pub(crate) fn required(&self) -> bool {
+        self.required
+    }
+
+    /// Takes the TLS handshake on a connection we accepted, if it opens with
+    /// one. Plain ones are passed on as they are unless TLS is required.
+    pub(crate) async fn accept(&self, stream: TcpStream) -> Result<PeerStream> {
+        let handshake = async {
+            let mut first = [0u8; 1];
+            stream.peek(&mut first).await?;
+            if first[0] != HANDSHAKE_RECORD {
+                if self.required {
+                    bail!("Plain connection refused; TLS is required");
+                }
+                return Ok(PeerStream::Plain(stream));
+            }
+            let stream = self.acceptor.accept(stream).await?;
+            Ok(PeerStream::Tls(Box::new(stream.into())))
+        };
+        timeout(HANDSHAKE_TIMEOUT, handshake).await?
+    }
+
+    /// Opens TLS on a connection we made, trusting only the certificate
+    /// whose fingerprint is `pin`. Without one the peer never announced a
+    /// certificate, so the connection stays plain unless TLS is required.
+    pub(crate) async fn connect(&self, stream: TcpStream, pin: Option<&str>) -> Result<PeerStream> {
+        match pin {
+            Some(pin) => self.handshake(stream, Some(pin.to_string())).await,
+            None if self.required => bail!("Peer announced no TLS certificate, and TLS is required"),
+            None => Ok(PeerStream::Plain(stream)),
+        }
+    }
+
+    /// Opens TLS on a connection we made to a node whose certificate we
+    /// don't know yet, trusting whatever it presents. Only for exchanging
+    /// introductions, whose signed announcement then tells what it should
+    /// have presented.
+    pub(crate) async fn connect_unpinned(&self, stream: TcpStream) -> Result<PeerStream> {
+        self.handshake(stream, None).await
+    }
+
+    async fn handshake(&self, stream: TcpStream, pin: Option<String>) -> Result<PeerStream> {
+        let verifier = PinnedCertificate {
+            pin,
+            provider: self.provider.clone(),
+        };
+        let config = ClientConfig::builder_with_provider(self.provider.clone())
+            .with_protocol_versions(&[&rustls::version::TLS13])?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(config));
+        let stream = timeout(HANDSHAKE_TIMEOUT, connector.connect(ServerName::try_from(SERVER_NAME)?, stream)).await??;
+        Ok(PeerStream::Tls(Box::new(stream.into())))
+    }
+}
+
+/// `stream`, a connection we accepted, with TLS taken from it when `tls` is on.
+pub(crate) async fn accept(tls: Option<&Tls>, stream: TcpStream) -> Result<PeerStream> {
+    match tls {
+        Some(tls) => tls.accept(stream).await,
+        None => Ok(PeerStream::Plain(stream)),
+    }
+}
+
+/// `stream`, a connection we made, in TLS pinned to `pin` when `tls` is on.
+pub(crate) async fn connect(tls: Option<&Tls>, stream: TcpStream, pin: Option<&str>) -> Result<PeerStream> {
+    match tls {
+        Some(tls) => tls.connect(stream, pin).await,
+        None => Ok(PeerStream::Plain(stream)),
+    }
+}
+
+/// Hex SHA-256 of a DER certificate.
+pub(crate) fn fingerprint(certificate: &CertificateDer<'_>) -> String {
+    hex::encode(Sha256::digest(certificate.as_ref()))
+}
+
+/// Makes a self-signed certificate and its key, readable only by us.
+fn generate(cert_path: &Path, key_path: &Path) -> Result<()> {
+    let certified = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+    identity::write_private(key_path, &certified.key_pair.serialize_pem())?;
+    std::fs::write(cert_path, certified.cert.pem())?;
+    tracing::info!("Created a self-signed TLS certificate in {}", cert_path.display());
+    Ok(())
+}
+
+/// Trusts the one certificate whose fingerprint is `pin`, or, without a
+/// pin, any. Names and expiry don't come into it: a peer's certificate is
+/// only ever the one its signed announcement names.
+#[derive(Debug)]
+struct PinnedCertificate {
+    pin: Option<String>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        match &self.pin {
+            Some(pin) if *pin != fingerprint(end_entity) => {
+                Err(rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure))
+            }
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(message, certificate, signature, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(message, certificate, signature, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+    use crate::peer::Peer;
+    use crate::transfer::tests::{downloaded_files, sample_data, scratch_dir, service};
+    use crate::transfer::{Route, TransferService};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use uuid::Uuid;
+
+    fn tls(required: bool) -> SecurityConfig {
+        SecurityConfig {
+            tls: true,
+            require_tls: required,
+            ..SecurityConfig::default()
+        }
+    }
+
+    /// A node in `dir` speaking TLS as `security` sets up, listening on a
+    /// loopback port of its own and taking files from loopback.
+    async fn listening(security: SecurityConfig, dir: &Path) -> (Arc<TransferService>, SocketAddr) {
+        let mut config = AppConfig::default();
+        config.network.transfer_port = 0;
+        config.transfer.allowed_networks = vec!["127.0.0.0/8".to_string()];
+        config.security = security;
+        let service = Arc::new(secured(config, dir).await);
+        let listening = service.clone();
+        tokio::spawn(async move { listening.start_listener().await });
+        let address = SocketAddr::from(([127, 0, 0, 1], service.wait_for_local_addr().await.port()));
+        (service, address)
+    }
+
+    /// A service in `dir` speaking TLS as `config` sets up, and announcing
+    /// its certificate when it does.
+    async fn secured(config: AppConfig, dir: &Path) -> TransferService {
+        std::fs::create_dir_all(dir).unwrap();
+        let tls = Tls::load(&config.security, dir).unwrap();
+        let service = service(config, dir);
+        if let Some(tls) = tls {
+            service.peers.write().await.set_local_tls_fingerprint(tls.fingerprint().to_string());
+            service.set_tls(tls);
+        }
+        service
+    }
+
+    /// Lists a peer at `address` that announced `tls_fingerprint`.
+    async fn knows(service: &TransferService, address: SocketAddr, tls_fingerprint: Option<String>) {
+        let mut peer = Peer::from_discovery(Uuid::new_v4(), address, "receiver".to_string(), None);
+        peer.tls_fingerprint = tls_fingerprint;
+        service.peers.write().await.add_or_update_peer(peer);
+    }
+
+    async fn send(sender: &TransferService, address: SocketAddr, path: &Path) -> Result<()> {
+        let transfer = sender.track_send(Uuid::new_v4());
+        sender.send_file(&transfer, Route::Direct(address), path.to_path_buf(), |_, _| {}).await.map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn a_transfer_to_a_pinned_certificate_goes_encrypted() {
+        let dir = scratch_dir();
+        let (receiver, address) = listening(tls(true), &dir.join("receiver")).await;
+        let sender = secured(AppConfig { security: tls(false), ..AppConfig::default() }, &dir.join("sender")).await;
+
+        // Everything the sender says passes through here on its way
+        let tap = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tapped = tap.local_addr().unwrap();
+        let recording = tokio::spawn(async move {
+            let (mut from_sender, _) = tap.accept().await.unwrap();
+            let mut to_receiver = TcpStream::connect(address).await.unwrap();
+            let (mut receiver_reader, mut receiver_writer) = to_receiver.split();
+            let (mut sender_reader, mut sender_writer) = from_sender.split();
+            let mut seen = Vec::new();
+            let forward = async {
+                let mut buffer = vec![0u8; 64 * 1024];
+                loop {
+                    let n = sender_reader.read(&mut buffer).await.unwrap_or(0);
+                    if n == 0 {
+                        let _ = receiver_writer.shutdown().await;
+                        return;
+                    }
+                    seen.extend_from_slice(&buffer[..n]);
+                    if receiver_writer.write_all(&buffer[..n]).await.is_err() {
+                        return;
+                    }
+                }
+            };
+            let backward = tokio::io::copy(&mut receiver_reader, &mut sender_writer);
+            let _ = tokio::join!(forward, backward);
+            seen
+        });
+        knows(&sender, tapped, Some(receiver.tls().unwrap().fingerprint().to_string())).await;
+        let data = sample_data();
+        let path = dir.join("payroll-march.csv");
+        std::fs::write(&path, &data).unwrap();
+
+        send(&sender, tapped, &path).await.unwrap();
+        let received = dir.join("receiver").join("downloads").join("payroll-march.csv");
+        assert_eq!(std::fs::read(received).unwrap(), data);
+        let seen = recording.await.unwrap();
+        assert_eq!(seen.first(), Some(&HANDSHAKE_RECORD));
+        // Not even what's offered shows
+        assert!(!seen.windows(13).any(|window| window == b"payroll-march"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_certificate_other_than_the_pinned_one_is_refused() {
+        let dir = scratch_dir();
+        let (_receiver, address) = listening(tls(false), &dir.join("receiver")).await;
+        let sender = secured(AppConfig { security: tls(false), ..AppConfig::default() }, &dir.join("sender")).await;
+        // What we learned for the receiver is someone else's certificate
+        std::fs::create_dir_all(dir.join("other")).unwrap();
+        let other = Tls::load(&tls(false), &dir.join("other")).unwrap().unwrap();
+        knows(&sender, address, Some(other.fingerprint().to_string())).await;
+        let path = dir.join("secret.txt");
+        std::fs::write(&path, b"for the pinned certificate only").unwrap();
+
+        let error = send(&sender, address, &path).await.expect_err("the send is refused").to_string();
+        assert!(error.contains("certificate"), "{}", error);
+        assert!(downloaded_files(&dir.join("receiver")).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn plain_peers_are_served_unless_tls_is_required() {
+        let dir = scratch_dir();
+        let plain = secured(AppConfig::default(), &dir.join("plain")).await;
+        let path = dir.join("notes.txt");
+        std::fs::write(&path, b"nothing secret").unwrap();
+
+        let (_, optional) = listening(tls(false), &dir.join("optional")).await;
+        send(&plain, optional, &path).await.unwrap();
+        assert_eq!(downloaded_files(&dir.join("optional")), vec!["notes.txt"]);
+
+        let (_, required) = listening(tls(true), &dir.join("required")).await;
+        assert!(send(&plain, required, &path).await.is_err());
+        assert!(downloaded_files(&dir.join("required")).is_empty());
+
+        // Nor do we send in plain to a peer that announced no certificate
+        let strict = secured(AppConfig { security: tls(true), ..AppConfig::default() }, &dir.join("strict")).await;
+        knows(&strict, optional, None).await;
+        let error = send(&strict, optional, &path).await.expect_err("the send is refused").to_string();
+        assert!(error.contains("TLS is required"), "{}", error);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_new_peers_certificate_is_learned_from_its_introduction() {
+        let dir = scratch_dir();
+        let (them, address) = listening(tls(true), &dir.join("them")).await;
+        let us = secured(AppConfig { security: tls(true), ..AppConfig::default() }, &dir.join("us")).await;
+
+        us.add_peer(&address.to_string()).await.unwrap();
+        let learned = us.peers.read().await.tls_fingerprint(address);
+        assert_eq!(learned.as_deref(), Some(them.tls().unwrap().fingerprint()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn the_self_signed_certificate_is_kept_across_restarts() {
+        let dir = scratch_dir();
+        let first = Tls::load(&tls(false), &dir).unwrap().unwrap();
+        let second = Tls::load(&tls(false), &dir).unwrap().unwrap();
+        assert_eq!(first.fingerprint(), second.fingerprint());
+        assert!(Tls::load(&SecurityConfig::default(), &dir).unwrap().is_none());
+        let half = SecurityConfig {
+            cert_path: Some(dir.join(CERT_FILE).to_string_lossy().to_string()),
+            ..tls(false)
+        };
+        assert!(Tls::load(&half, &dir).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,146 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Events kept per trace. The first `HEAD` are always kept; past that only
+/// the latest, so both how a transfer started and how it ended survive.
+const MAX_EVENTS: usize = 256;
+const HEAD: usize = 64;
+/// Longest detail kept on an event, in characters.
+const MAX_DETAIL_LEN: usize = 512;
+/// Chunk counters are noted each time this share of the file has moved.
+const CHECKPOINTS: u64 = 10;
+
+/// One step of a traced transfer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceEvent {
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Milliseconds since the trace started.
+    pub elapsed_ms: u64,
+    pub event: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+struct TraceBuffer {
+    started: Instant,
+    events: Vec<TraceEvent>,
+    /// Events dropped from between the head and the latest.
+    dropped: usize,
+    next_checkpoint: u64,
+}
+
+/// A structured record of what happened to one transfer, for debugging a
+/// failure without raising the log level. Does nothing until enabled;
+/// clones share the same trace.
+#[derive(Clone, Default)]
+pub struct TransferTrace(Arc<Mutex<Option<TraceBuffer>>>);
+
+impl TransferTrace {
+    pub fn enable(&self) {
+        let mut buffer = self.0.lock().unwrap();
+        if buffer.is_none() {
+            *buffer = Some(TraceBuffer {
+                started: Instant::now(),
+                events: Vec::new(),
+                dropped: 0,
+                next_checkpoint: 0,
+            });
+        }
+    }
+
+    /// Notes `event`, with `detail` unless it's empty.
+    pub fn record(&self, event: &str, detail: impl Into<String>) {
+        let mut buffer = self.0.lock().unwrap();
+        let Some(buffer) = buffer.as_mut() else { return };
+        let detail: String = detail.into();
+        let event = TraceEvent {
+            timestamp: chrono::Utc::now(),
+            elapsed_ms: buffer.started.elapsed().as_millis() as u64,
+            event: event.to_string(),
+            detail: (!detail.is_empty()).then(|| detail.chars().take(MAX_DETAIL_LEN).collect()),
+        };
+        if buffer.events.len() == MAX_EVENTS {
+            buffer.events.remove(HEAD);
+            buffer.dropped += 1;
+        }
+        buffer.events.push(event);
+    }
+
+    /// Notes the chunk counters each time another tenth of `total` has moved.
+    pub fn progress(&self, chunks: u64, bytes: u64, total: u64) {
+        let due = {
+            let mut buffer = self.0.lock().unwrap();
+            let Some(buffer) = buffer.as_mut() else { return };
+            if bytes < buffer.next_checkpoint {
+                return;
+            }
+            let step = (total / CHECKPOINTS).max(1);
+            buffer.next_checkpoint = (bytes / step + 1) * step;
+            true
+        };
+        if due {
+            self.record("progress", format!("{} chunks, {} of {} bytes", chunks, bytes, total));
+        }
+    }
+
+    /// The events so far, with a marker where any were dropped.
+    pub fn snapshot(&self) -> Option<Vec<TraceEvent>> {
+        let buffer = self.0.lock().unwrap();
+        let buffer = buffer.as_ref()?;
+        let mut events = buffer.events.clone();
+        if buffer.dropped > 0 {
+            let at = &events[HEAD];
+            let marker = TraceEvent {
+                timestamp: at.timestamp,
+                elapsed_ms: at.elapsed_ms,
+                event: "dropped".to_string(),
+                detail: Some(format!("{} events left out", buffer.dropped)),
+            };
+            events.insert(HEAD, marker);
+        }
+        Some(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_disabled_trace_keeps_nothing() {
+        let trace = TransferTrace::default();
+        trace.record("request_sent", "report.pdf");
+        assert!(trace.snapshot().is_none());
+    }
+
+    #[test]
+    fn a_long_trace_keeps_its_start_and_end() {
+        let trace = TransferTrace::default();
+        trace.enable();
+        trace.record("request_sent", "");
+        for i in 0..1000 {
+            trace.record("step", i.to_string());
+        }
+        trace.record("failed", "Connection reset by peer");
+
+        let events = trace.snapshot().unwrap();
+        assert_eq!(events.len(), MAX_EVENTS + 1);
+        assert_eq!((events[0].event.as_str(), events[0].detail.as_deref()), ("request_sent", None));
+        assert_eq!(events[HEAD].event, "dropped");
+        assert_eq!(events.last().unwrap().detail.as_deref(), Some("Connection reset by peer"));
+    }
+
+    #[test]
+    fn progress_is_noted_at_checkpoints() {
+        let trace = TransferTrace::default();
+        trace.enable();
+        for chunk in 1..=100 {
+            trace.progress(chunk, chunk * 10, 1000);
+        }
+        let events = trace.snapshot().unwrap();
+        assert_eq!(events.len(), 11);
+        assert_eq!(events[0].detail.as_deref(), Some("1 chunks, 10 of 1000 bytes"));
+        assert_eq!(events[10].detail.as_deref(), Some("100 chunks, 1000 of 1000 bytes"));
+    }
+}
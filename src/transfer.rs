@@ -1,46 +1,380 @@
-use crate::config::AppConfig;
+use crate::active::{ActiveTransfer, ActiveTransfers, Direction, Pausing, SpeedSamples};
+use crate::approval::{ApprovalService, Decision, IncomingFile};
+use crate::archive;
+use crate::bandwidth::Bandwidth;
+use crate::benchmark::MAX_BENCHMARK_DURATION;
+use crate::capability;
+use crate::compress;
+use crate::config::{AcceptAction, AppConfig, ExistingFilePolicy, FileMode, TransferConfig};
+use crate::crypto::{self, ChunkCipher, KeyExchange, Role};
+use crate::discovery::DiscoveryMessage;
+use crate::identity::{self, Identity};
+use crate::introduction::RefusalLog;
+use crate::peer::{Peer, PeerManager};
+use crate::protocol::{
+    BenchmarkLeg, ManifestEntry, PeerReachability, PeerSpace, ServerMessage, ShareEntry, SlotUsage,
+};
+use crate::rendezvous::{Connectivity, Coordinator, Punches};
+use crate::slots::{QueueError, SlotPermit, Slots, MAX_SLOTS};
+use crate::objects;
+use crate::organize;
+use crate::parallel::{self, ByteRange, JoinedStream, StreamJoins};
+use crate::sync::{Manifest, SyncService};
+use crate::tls::{self, PeerStream, Tls};
+use crate::trace::TransferTrace;
+use crate::usage::{UsageLog, USAGE_FILE};
 use crate::utils;
+use crate::websocket::WebSocketService;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
 use tokio::fs::File;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Semaphore;
-use tokio::time::{timeout, Duration};
+use std::io::SeekFrom;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf, WriteHalf,
+};
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::sync::{mpsc, watch, RwLock};
+use tokio::time::{timeout, Duration, Instant};
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 use sha2::{Digest, Sha256};
 
+/// Transfer protocol version this build speaks.
+pub const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion { major: 1, minor: 10 };
+
+/// `major.minor` version of the transfer protocol. Minor bumps only add
+/// optional fields or new messages; anything that changes the meaning of an existing message
+/// bumps the major version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ProtocolVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl ProtocolVersion {
+    /// Peers that predate versioning send no version at all.
+    const LEGACY: ProtocolVersion = ProtocolVersion { major: 1, minor: 0 };
+
+    /// First version whose encrypted transfers end with a sealed Complete.
+    const SEALED: ProtocolVersion = ProtocolVersion { major: 1, minor: 4 };
+
+    /// First version that understands KeepAlive and says its version in Accept.
+    const KEEPALIVE: ProtocolVersion = ProtocolVersion { major: 1, minor: 7 };
+
+    /// First version that sends Acks when asked to in Accept.
+    const WINDOWED: ProtocolVersion = ProtocolVersion { major: 1, minor: 8 };
+
+    /// First version that sends chunks as binary frames when Accept says
+    /// they're taken.
+    const FRAMED: ProtocolVersion = ProtocolVersion { major: 1, minor: 9 };
+
+    /// First version that holds a transfer between Pause and Resume, from
+    /// either side, rather than timing out.
+    const PAUSABLE: ProtocolVersion = ProtocolVersion { major: 1, minor: 10 };
+
+    /// The compatibility policy: any version with the same major is accepted.
+    pub fn accepts(&self, other: &ProtocolVersion) -> bool {
+        self.major == other.major
+    }
+}
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        Self::LEGACY
+    }
+}
+
+impl std::fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl std::str::FromStr for ProtocolVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (major, minor) = s
+            .split_once('.')
+            .ok_or_else(|| anyhow::anyhow!("Invalid protocol version: {}", s))?;
+        Ok(Self {
+            major: major.parse()?,
+            minor: minor.parse()?,
+        })
+    }
+}
+
+impl TryFrom<String> for ProtocolVersion {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<ProtocolVersion> for String {
+    fn from(version: ProtocolVersion) -> Self {
+        version.to_string()
+    }
+}
+
+/// Transfer failures the UI reports with a specific error code.
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum TransferError {
+    #[error("Peer speaks transfer protocol {theirs}, which is incompatible with ours ({ours})")]
+    IncompatibleProtocol {
+        theirs: ProtocolVersion,
+        ours: ProtocolVersion,
+    },
+    #[error("Transfer cancelled")]
+    Cancelled,
+    #[error("{}", insufficient_space_message(*.file_size, .space))]
+    InsufficientSpace {
+        file_size: u64,
+        space: PeerSpace,
+    },
+    /// `transfer.daily_budget_bytes` is used up for today.
+    #[error("Daily transfer budget of {} used up; new transfers resume at midnight", utils::format_bytes(*.budget))]
+    DailyBudgetExhausted { budget: u64 },
+    /// The receiver gave up part way and said why.
+    #[error("Transfer aborted by peer: {reason}")]
+    AbortedByPeer {
+        reason: String,
+        /// The receiver's code for what went wrong, when it sent one.
+        code: Option<String>,
+    },
+    /// The receiver went away part way: the connection broke or closed, or
+    /// it stopped taking data.
+    #[error("Receiver disconnected after {} was sent: {reason}", utils::format_bytes(*.bytes_sent))]
+    PeerDisconnected { bytes_sent: u64, reason: String },
+    /// No data moved for `transfer.stall_timeout_secs` though the
+    /// connection stayed open, so the watchdog stopped it.
+    #[error("Transfer stalled: no data moved for {idle_secs}s")]
+    Stalled { idle_secs: u64 },
+    /// The receiver turned the file down for want of disk space.
+    #[error("Transfer rejected by peer: {reason}")]
+    PeerOutOfSpace { reason: String },
+    /// The receiver takes no files this large.
+    #[error("Peer accepts files up to {}, this one is {}", utils::format_bytes(*.max_file_size), utils::format_bytes(*.file_size))]
+    FileTooLarge { file_size: u64, max_file_size: u64 },
+    /// `transfer.max_queued_sends` sends already wait for a free slot.
+    #[error("Send queue is full: {waiting} sends are already waiting for a free slot")]
+    SendQueueFull { waiting: usize },
+}
+
+/// The peer closed the connection or stopped answering; becomes
+/// `TransferError::PeerDisconnected` mid-send.
+#[derive(Debug, thiserror::Error)]
+enum PeerGone {
+    #[error("Receiver closed the connection")]
+    Closed,
+    #[error("Peer unresponsive: {0}")]
+    Unresponsive(String),
+}
+
+impl PeerGone {
+    fn reason(&self) -> String {
+        match self {
+            PeerGone::Closed => "it closed the connection".to_string(),
+            PeerGone::Unresponsive(what) => what.clone(),
+        }
+    }
+}
+
+fn insufficient_space_message(file_size: u64, space: &PeerSpace) -> String {
+    let size = utils::format_bytes(file_size);
+    match space.free_bytes {
+        Some(free) => format!("Peer has {} free, this file needs {}", utils::format_bytes(free), size),
+        None => format!("Peer has no room for {}", size),
+    }
+}
+
+/// Why a file is refused under `transfer.max_receive_file_size`.
+fn too_large(max_file_size: u64) -> String {
+    format!("Files over {} are not accepted", utils::format_bytes(max_file_size))
+}
+
+/// Why a file is refused under `transfer.on_existing_file = "reject"`.
+fn file_exists(filename: &str) -> String {
+    format!("{} already exists", filename)
+}
+
+impl TransferError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            TransferError::IncompatibleProtocol { .. } => "incompatible_protocol_version",
+            TransferError::Cancelled => "cancelled",
+            TransferError::InsufficientSpace { .. } => "insufficient_space",
+            TransferError::DailyBudgetExhausted { .. } => "daily_budget_exhausted",
+            TransferError::AbortedByPeer { .. } => "aborted_by_peer",
+            TransferError::PeerDisconnected { .. } => "peer_disconnected",
+            TransferError::Stalled { .. } => "stalled",
+            TransferError::PeerOutOfSpace { .. } => "insufficient_space",
+            TransferError::FileTooLarge { .. } => "file_too_large",
+            TransferError::SendQueueFull { .. } => "send_queue_full",
+        }
+    }
+
+    /// What the receiver reported, if `error` is an insufficient space error.
+    pub fn peer_space_of(error: &anyhow::Error) -> Option<PeerSpace> {
+        match error.downcast_ref::<TransferError>() {
+            Some(TransferError::InsufficientSpace { space, .. }) => Some(*space),
+            Some(TransferError::FileTooLarge { max_file_size, .. }) => Some(PeerSpace {
+                free_bytes: None,
+                max_file_size: Some(*max_file_size),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Error code for `error`, if it is one the UI distinguishes.
+    pub fn code_of(error: &anyhow::Error) -> Option<String> {
+        match error.downcast_ref::<TransferError>()? {
+            TransferError::AbortedByPeer { code: Some(code), .. } => Some(code.clone()),
+            e => Some(e.code().to_string()),
+        }
+    }
+
+    /// `error` again, for each of several transfers it ends, keeping its
+    /// code.
+    pub(crate) fn again(error: &anyhow::Error) -> anyhow::Error {
+        match error.downcast_ref::<TransferError>() {
+            Some(e) => e.clone().into(),
+            None => anyhow::anyhow!("{}", error),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TransferMessage {
     Request {
+        #[serde(default)]
+        protocol_version: ProtocolVersion,
         transfer_id: Uuid,
         filename: String,
+        /// Only the filename unless the sender opted into sharing its full
+        /// source path; kept on the wire because older receivers require it.
+        #[serde(default)]
         file_path: String,
         file_size: u64,
         file_checksum: Option<String>,
         mime_type: Option<String>,
+        #[serde(default)]
+        detected_mime_type: Option<String>,
+        /// Sender's ephemeral X25519 key (hex); absent from peers without encryption.
+        #[serde(default)]
+        public_key: Option<String>,
+        /// Sender's long-term identity key (hex), proven by the key agreement.
+        #[serde(default)]
+        identity_key: Option<String>,
+        /// Set for an archive made as it's sent: `file_size` is 0, and the
+        /// checksum comes in Complete. Only offered to peers announcing
+        /// the `unsized` capability.
+        #[serde(default)]
+        size_unknown: bool,
+        /// How the sender can compress chunks, e.g. "zstd"; never when unset.
+        #[serde(default)]
+        compression: Option<String>,
+        /// Hex HMAC-SHA256 of the offer under `transfer.shared_secret`, from
+        /// senders that have one.
+        #[serde(default)]
+        mac: Option<String>,
+        /// Whether the sender can put a CRC32 on each chunk and send a chunk
+        /// again when asked with ChunkNack.
+        #[serde(default)]
+        chunk_checksums: bool,
+        /// Connections the sender would split the file over; one when unset.
+        #[serde(default)]
+        parallel_streams: u32,
+        /// Whether the sender takes AlreadyExists for an answer, when the
+        /// receiver holds a file of this name and checksum already. Only
+        /// asked with keys, which the answer is proven under.
+        #[serde(default)]
+        skip_identical: bool,
+        /// The offer's details, sealed to the receiver's identity key, for
+        /// receivers announcing the `sealed` capability. The name, path,
+        /// size, checksum and types in the clear are then left empty.
+        #[serde(default)]
+        sealed: Option<Vec<u8>>,
     },
     Accept {
         transfer_id: Uuid,
+        /// Receiver's ephemeral key, present when the transfer will be encrypted.
+        #[serde(default)]
+        public_key: Option<String>,
+        #[serde(default)]
+        identity_key: Option<String>,
+        /// The receiver's version; absent from receivers before 1.7.
+        #[serde(default)]
+        protocol_version: Option<ProtocolVersion>,
+        /// Chunks the sender may have unacknowledged; no limit when unset.
+        /// The receiver acknowledges every half window.
+        #[serde(default)]
+        window: Option<u32>,
+        /// Whether chunks may come as binary frames instead of JSON lines.
+        #[serde(default)]
+        framed: bool,
+        /// The request's compression, when chunks are to come compressed.
+        /// Checksums and sizes stay those of the file itself.
+        #[serde(default)]
+        compression: Option<String>,
+        /// Whether chunks are to carry a CRC32. Only asked for along with a
+        /// window, which bounds what we hold while a damaged chunk comes again.
+        #[serde(default)]
+        chunk_checksums: bool,
+        /// Connections the file is to come over, at most what the request
+        /// asked for; one when unset. Split transfers go without a window.
+        #[serde(default)]
+        parallel_streams: u32,
     },
     Reject {
         transfer_id: Uuid,
         reason: Option<String>,
+        /// The receiver's version, set when the request's version was refused.
+        #[serde(default)]
+        protocol_version: Option<ProtocolVersion>,
+        /// Largest file the receiver takes, set when the file was refused
+        /// for being larger.
+        #[serde(default)]
+        max_file_size: Option<u64>,
+    },
+    /// Answers a Request with `skip_identical` in place of Accept: the
+    /// receiver has the very file already, so none of it need go. `proof`
+    /// vouches for its size and checksum under the key the request and
+    /// these keys agree, so only the receiver the identity names can say so.
+    AlreadyExists {
+        transfer_id: Uuid,
+        public_key: String,
+        identity_key: String,
+        proof: Vec<u8>,
     },
     Chunk {
         transfer_id: Uuid,
         chunk_index: u64,
         data: Vec<u8>,
+        /// CRC32 of `data`, once chunk checksums are agreed.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        crc: Option<u32>,
     },
     Complete {
         transfer_id: Uuid,
         file_checksum: Option<String>,
+        /// Length and checksum of what was sent, under the transfer key.
+        #[serde(default)]
+        seal: Option<Vec<u8>>,
     },
     Error {
         transfer_id: Uuid,
         message: String,
+        /// What went wrong, for the sender's UI, e.g. "disk_full".
+        #[serde(default)]
+        code: Option<String>,
     },
     Pause {
         transfer_id: Uuid,
@@ -51,303 +385,6140 @@ pub enum TransferMessage {
     Cancel {
         transfer_id: Uuid,
     },
+    /// The receiver has stored the first `received_chunks` chunks.
+    Ack {
+        transfer_id: Uuid,
+        received_chunks: u64,
+    },
+    /// Chunk `chunk_index` arrived not matching its CRC32; the receiver
+    /// holds the chunks after it until it comes again.
+    ChunkNack {
+        transfer_id: Uuid,
+        chunk_index: u64,
+    },
+    /// Starts the chunks for `range` of a transfer split over several
+    /// connections: first on the transfer's own connection, and first of all
+    /// on each further one, which the receiver answers with an Ack once its
+    /// range is written. Complete follows on the transfer's own connection
+    /// once every range is in.
+    StreamRange {
+        transfer_id: Uuid,
+        range: ByteRange,
+    },
+    /// Sent by either side when it has had nothing to say for a while,
+    /// so the other doesn't give up on it; changes nothing else.
+    KeepAlive {
+        transfer_id: Uuid,
+    },
+    /// Asks for a folder of the peer's share; "" is the root.
+    ListShare {
+        path: String,
+    },
+    ShareListing {
+        path: String,
+        entries: Vec<ShareEntry>,
+    },
+    /// Asks the peer to send a file from its share. It answers with a
+    /// Request for `transfer_id`, and the transfer goes on as usual.
+    FetchShared {
+        transfer_id: Uuid,
+        relative_path: String,
+    },
+    /// A ListShare or FetchShared the peer won't serve.
+    ShareError {
+        message: String,
+    },
+    /// Asks the peer to answer with ProbeAck, to show it would take a
+    /// transfer; nothing else happens on the connection.
+    Probe,
+    ProbeAck,
+    /// Asks how much the peer can receive, before offering a large file.
+    SpaceQuery,
+    SpaceReport {
+        free_bytes: Option<u64>,
+        max_file_size: Option<u64>,
+    },
+    /// Asks the peer to take part in a benchmark: with `peer_sends` it sends
+    /// generated chunks, otherwise it discards ours, for up to
+    /// `duration_secs` or `payload_size` bytes. Answered with Accept or
+    /// Reject; the chunks follow as in a transfer, ending with Complete.
+    Benchmark {
+        transfer_id: Uuid,
+        peer_sends: bool,
+        payload_size: u64,
+        duration_secs: u64,
+    },
+    /// What the peer measured of the benchmark chunks we sent it.
+    BenchmarkReport {
+        transfer_id: Uuid,
+        leg: BenchmarkLeg,
+    },
+    /// Asks the peer to connect us to `destination` and pass everything
+    /// through; sent first on the connection.
+    RelayRequest {
+        destination: Uuid,
+        /// Who asks, for a coordinator to tell apart peers behind one
+        /// router; absent from peers before rendezvous.
+        #[serde(default)]
+        origin: Option<Uuid>,
+    },
+    /// From the relay once the destination is connected; from here on the
+    /// connection behaves as if made to the destination directly.
+    RelayReady {
+        destination: RelayedPeer,
+    },
+    RelayError {
+        message: String,
+    },
+    /// Sent by a relay ahead of the origin's own first message.
+    Relayed {
+        origin: RelayedPeer,
+    },
+    /// Opens a session of several files over one connection, listing them
+    /// before any goes. A long list takes several of these, all but the last
+    /// with `more` set. Answered with ManifestAccept or a Reject for
+    /// `session_id`; each file then follows as its own Request, and the
+    /// receiver answers the Complete of each file it keeps with Saved.
+    ManifestRequest {
+        session_id: Uuid,
+        files: Vec<ManifestEntry>,
+        #[serde(default)]
+        more: bool,
+        /// `files`, sealed to the receiver's identity key as for a Request,
+        /// with none left in the clear.
+        #[serde(default)]
+        sealed: Option<Vec<u8>>,
+    },
+    ManifestAccept {
+        session_id: Uuid,
+    },
+    /// A file of a session is saved; its sender can go on to the next.
+    Saved {
+        transfer_id: Uuid,
+    },
+    /// Introduces the sender with the signed announcement it would otherwise
+    /// broadcast, for peers discovery can't reach. Answered with our own.
+    Hello {
+        announcement: DiscoveryMessage,
+    },
+    /// Asks for the manifest of a folder both sides sync as `folder`.
+    SyncManifestRequest {
+        folder: String,
+    },
+    SyncManifest {
+        folder: String,
+        manifest: Manifest,
+    },
+    /// Asks the peer to send a file from a synced folder; answered like
+    /// FetchShared.
+    SyncFetch {
+        transfer_id: Uuid,
+        folder: String,
+        relative_path: String,
+    },
+    /// A SyncManifestRequest or SyncFetch the peer won't serve.
+    SyncError {
+        message: String,
+    },
+    /// Registers `peer` with a coordinator, from our transfer port. The
+    /// connection stays open for the coordinator's RendezvousPunch and
+    /// RendezvousRelay.
+    RendezvousRegister {
+        peer: RelayedPeer,
+    },
+    /// The coordinator took the registration; `observed` is where it sees us.
+    RendezvousRegistered {
+        observed: SocketAddr,
+    },
+    /// Asks a coordinator, from our transfer port, to put us in touch with
+    /// `destination`. Answered with RendezvousPeer, or RendezvousError.
+    RendezvousConnect {
+        origin: RelayedPeer,
+        destination: Uuid,
+    },
+    /// Where the destination of a RendezvousConnect is seen; the asking side
+    /// connects there while the destination connects back.
+    RendezvousPeer {
+        peer: RelayedPeer,
+        address: SocketAddr,
+    },
+    /// From a coordinator to a registered peer: connect to `peer` at
+    /// `address` now, as it is connecting to us.
+    RendezvousPunch {
+        peer: RelayedPeer,
+        address: SocketAddr,
+    },
+    /// From a coordinator to a registered peer: connect back and send
+    /// RendezvousJoin with `token`, to be relayed a transfer.
+    RendezvousRelay {
+        token: Uuid,
+    },
+    /// Sent first on a connection back to a coordinator; the relayed
+    /// transfer follows as on any incoming connection.
+    RendezvousJoin {
+        token: Uuid,
+    },
+    RendezvousError {
+        message: String,
+    },
+}
+
+/// The peer on the far side of a relay.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayedPeer {
+    pub peer_id: Uuid,
+    pub hostname: String,
+}
+
+/// Where a send goes: straight to a peer, or through a relay that can reach it.
+#[derive(Debug, Clone, Copy)]
+pub enum Route {
+    /// A known peer, at whatever address it has when we connect, so one
+    /// that moved while the send waited its turn is still reached.
+    Peer(Uuid),
+    Direct(SocketAddr),
+    Relay { relay: SocketAddr, destination: Uuid },
 }
 
+/// Longest request/response message accepted; these never carry file data.
+pub(crate) const MAX_CONTROL_MESSAGE_LEN: usize = 64 * 1024;
+/// Starts a chunk framed in binary; JSON lines never start with it.
+const FRAME_MARKER: u8 = 0;
+/// First byte of a binary chunk frame that carries a CRC32 of its data
+/// after the length.
+const CHECKED_FRAME_MARKER: u8 = 1;
+/// The marker, transfer id, chunk index and data length before a framed
+/// chunk's data.
+const FRAME_HEADER_LEN: usize = 1 + 16 + 8 + 4;
+/// Chunks up to this size are taken whatever our own chunk size, since a
+/// peer's may be larger.
+pub(crate) const MIN_CHUNK_LIMIT: usize = 1024 * 1024;
+/// How long a receiver waits for the sender's next message, unless the
+/// transfer is paused.
+pub(crate) const SENDER_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often the watchdog looks for stalled transfers.
+const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long each of a peer's addresses gets to accept a connection before
+/// the next is tried.
+pub(crate) const CONNECT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a peer has to answer a reachability probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Chunks a send reads ahead of what the socket has taken.
+const READ_AHEAD_CHUNKS: usize = 4;
+/// Directory under downloads where blocked files are moved instead of kept.
+pub(crate) const QUARANTINE_DIR: &str = ".quarantine";
+/// How long an incoming file waits for a client to approve it; shorter than
+/// the wait for an answer of a sender too old for KeepAlive.
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(25);
+/// How long an incoming file waits for approval when the sender is kept
+/// waiting with KeepAlives.
+const KEPT_ALIVE_APPROVAL_TIMEOUT: Duration = Duration::from_secs(120);
+/// How long either side goes quiet before sending a KeepAlive, well within
+/// the other's timeouts.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+/// KeepAlives in a row after which the other side is given up on, so a
+/// connection that never gets anywhere doesn't live forever.
+pub(crate) const MAX_KEEPALIVES: u32 = 30;
+/// How long a sender waits for the receiver to explain why it stopped.
+const ABORT_REASON_TIMEOUT: Duration = Duration::from_secs(1);
+/// Reason given to a sender whose transfer we cancelled before accepting it.
+const CANCELLED_BY_RECIPIENT: &str = "Cancelled by the recipient";
+/// Reasons a received file is discarded because it isn't what was sent.
+pub const CHECKSUM_MISMATCH: &str = "Checksum mismatch";
+pub const UNVERIFIED: &str = "Transfer could not be verified";
+/// Codes a receiver gives with the Error it sends when it gives up.
+pub(crate) const CODE_BLOCKED: &str = "blocked";
+pub(crate) const CODE_CHECKSUM_MISMATCH: &str = "checksum_mismatch";
+pub(crate) const CODE_UNVERIFIED: &str = "unverified";
+pub(crate) const CODE_SIZE_MISMATCH: &str = "size_mismatch";
+const CODE_DECRYPT_FAILED: &str = "decrypt_failed";
+const CODE_DECOMPRESS_FAILED: &str = "decompress_failed";
+const CODE_DISK_FULL: &str = "disk_full";
+const CODE_CHUNK_DAMAGED: &str = "chunk_damaged";
+pub(crate) const CODE_STREAM_FAILED: &str = "stream_failed";
+const CODE_WRITE_FAILED: &str = "write_failed";
+const CODE_FILE_EXISTS: &str = "file_exists";
+const CODE_FILE_TOO_LARGE: &str = "file_too_large";
+/// Reason to refuse a peer that names an identity key but offers no key
+/// exchange, which is the only thing that proves it holds that key.
+const UNPROVEN_IDENTITY: &str = "Identity key presented without a key exchange";
+/// Reason to refuse a request without a valid MAC under our shared secret.
+const BAD_SHARED_SECRET: &str = "Shared secret missing or wrong";
+/// How a refusal for want of disk space starts, so the sender can tell it.
+const INSUFFICIENT_DISK_SPACE: &str = "Insufficient disk space";
+/// Bytes received between checks that the disk still has room for the rest.
+const SPACE_RECHECK_BYTES: u64 = 64 * 1024 * 1024;
+/// Times one chunk may be sent again after arriving damaged before the
+/// transfer is given up on.
+const MAX_CHUNK_RESENDS: u32 = 3;
+/// Sends files to peers and receives theirs.
 pub struct TransferService {
-    config: Arc<AppConfig>,
-    semaphore: Arc<Semaphore>,
+    pub(crate) config: Arc<AppConfig>,
+    pub(crate) peers: Arc<RwLock<PeerManager>>,
+    allowed_networks: Vec<utils::IpNetwork>,
+    send_slots: Arc<Slots>,
+    receive_slots: Arc<Slots>,
+    pub(crate) shutdown: CancellationToken,
+    pub(crate) websocket_service: OnceLock<Arc<WebSocketService>>,
+    pub(crate) sync_service: OnceLock<Arc<SyncService>>,
+    /// Set when the transfer port speaks TLS.
+    tls: OnceLock<Arc<Tls>>,
+    approvals: Arc<ApprovalService>,
+    pub(crate) active: Arc<ActiveTransfers>,
+    bandwidth: Arc<Bandwidth>,
+    pub(crate) usage: Arc<UsageLog>,
+    /// Addresses added as peers by hand or in `network.peers`, as given, so
+    /// names are looked up again on every refresh.
+    pub(crate) manual_peers: std::sync::Mutex<Vec<String>>,
+    pub(crate) refusals: Arc<std::sync::Mutex<RefusalLog>>,
+    /// Where state is kept; downloads go in its `downloads` folder.
+    data_dir: PathBuf,
+    /// Where the listener is bound, once it is.
+    local_addr: watch::Sender<Option<SocketAddr>>,
+    /// Set when running as a rendezvous coordinator.
+    pub(crate) coordinator: Option<Arc<Coordinator>>,
+    pub(crate) punches: Punches,
+    stream_joins: Arc<StreamJoins>,
 }
 
-impl TransferService {
-    pub fn new(config: Arc<AppConfig>) -> Self {
-        let max_concurrent = config.transfer.max_concurrent;
-        Self {
-            config,
-            semaphore: Arc::new(Semaphore::new(max_concurrent)),
-        }
-    }
+/// Shared state a connection's handler works with, whichever side opened it.
+#[derive(Clone)]
+pub(crate) struct ConnectionContext {
+    pub(crate) config: Arc<AppConfig>,
+    pub(crate) peers: Arc<RwLock<PeerManager>>,
+    pub(crate) send_slots: Arc<Slots>,
+    pub(crate) receive_slots: Arc<Slots>,
+    pub(crate) approvals: Arc<ApprovalService>,
+    pub(crate) active: Arc<ActiveTransfers>,
+    pub(crate) bandwidth: Arc<Bandwidth>,
+    pub(crate) usage: Arc<UsageLog>,
+    pub(crate) websocket: Option<Arc<WebSocketService>>,
+    pub(crate) sync: Option<Arc<SyncService>>,
+    /// Set when the transfer port speaks TLS.
+    pub(crate) tls: Option<Arc<Tls>>,
+    /// The peer beyond the relay, when the connection is relayed.
+    pub(crate) relayed: Option<RelayedPeer>,
+    /// How the connection got through.
+    pub(crate) connectivity: Connectivity,
+    /// Set when running as a rendezvous coordinator.
+    pub(crate) coordinator: Option<Arc<Coordinator>>,
+    pub(crate) downloads_dir: PathBuf,
+    /// Whether finished files are stored once by content under
+    /// `downloads_dir`; never for sync staging.
+    pub(crate) content_addressed: bool,
+    /// Whether finished files go in a folder for their kind under
+    /// `downloads_dir`; never for sync staging.
+    pub(crate) organize_by_type: bool,
+    /// What happens to a finished file whose name is taken; sync staging
+    /// always overwrites.
+    pub(crate) on_existing_file: ExistingFilePolicy,
+    /// The multi-file session the connection carries, once its manifest is
+    /// taken.
+    pub(crate) session_id: Option<Uuid>,
+    /// The transfer port we announce.
+    pub(crate) port: u16,
+    /// Incoming transfers split over several connections, waiting on them.
+    pub(crate) stream_joins: Arc<StreamJoins>,
+    /// The peer's listener we sent to, when more connections to it may
+    /// carry parts of the file.
+    pub(crate) parallel_to: Option<SocketAddr>,
+}
 
-    pub async fn start_listener(&self) -> Result<()> {
-        let bind_addr = format!("0.0.0.0:{}", self.config.network.transfer_port);
-        let listener = TcpListener::bind(&bind_addr).await?;
-        tracing::info!("Transfer listener started on {}", bind_addr);
+/// Where an incoming transfer got to before it stopped.
+#[derive(Clone)]
+pub struct ReceiveProgress {
+    pub transfer_id: Uuid,
+    /// The sender, when we know them.
+    pub peer_id: Option<Uuid>,
+    pub filename: String,
+    /// 0 until complete when the sender didn't know it up front.
+    pub file_size: u64,
+    /// Whether the sender didn't know the size, as for archives.
+    pub size_unknown: bool,
+    pub received: u64,
+    pub encrypted: bool,
+    /// Accept rule that let the transfer in, if one matched.
+    pub accept_rule: Option<String>,
+    /// Who really sent it, when it came through a relay.
+    pub relayed_from: Option<RelayedPeer>,
+    /// How the sender's connection got through.
+    pub connectivity: Connectivity,
+    /// SHA-256 of what arrived, once all of it has.
+    pub checksum: Option<String>,
+    /// Whether that matched the checksum the sender gave.
+    pub verified: bool,
+    /// The type it was let in as, if one could be told.
+    pub mime_type: Option<String>,
+    /// Where it was saved, once complete.
+    pub saved_path: Option<PathBuf>,
+    /// That, relative to the downloads folder.
+    pub relative_path: Option<String>,
+    /// Permission bits it was given once saved, if configured.
+    pub file_mode: Option<FileMode>,
+    /// Whether its content was stored already, so it took no more room.
+    pub deduplicated: bool,
+    /// How fast it arrived, shared with the transfer's registration.
+    pub samples: SpeedSamples,
+    /// Debug trace, shared with the transfer's registration.
+    pub trace: TransferTrace,
+    /// Whether it's paused, shared with the transfer's registration.
+    pub pausing: Pausing,
+    /// The multi-file session it came in.
+    pub session_id: Option<Uuid>,
+}
 
-        loop {
-            let (stream, addr) = listener.accept().await?;
-            let config = self.config.clone();
-            let semaphore = self.semaphore.clone();
+/// The receiver's side of the handshake: the Accept to send and, when the
+/// sender offered a key, the cipher for its chunks.
+pub(crate) struct Handshake {
+    pub(crate) accept_line: String,
+    pub(crate) cipher: Option<ChunkCipher>,
+    /// Whether the sender is new enough to seal its Complete.
+    pub(crate) sealed: bool,
+    /// Whether the sender understands KeepAlive.
+    pub(crate) keepalive: bool,
+    /// Chunks the sender may run ahead of our Acks, if we asked for them.
+    pub(crate) window: Option<u32>,
+    /// Whether the sender holds on Pause, and may pause us.
+    pub(crate) pausable: bool,
+    /// Whether chunks come compressed.
+    pub(crate) compressed: bool,
+    /// Whether chunks carry a CRC32 we check, asking again for damaged ones.
+    pub(crate) chunk_checksums: bool,
+    /// Connections the file comes over; more than one splits it.
+    pub(crate) streams: u32,
+}
 
-            tokio::spawn(async move {
-                if let Err(e) = Self::handle_receiver(stream, config, semaphore).await {
-                    tracing::error!("Transfer receiver error from {}: {}", addr, e);
-                }
-            });
-        }
-    }
+/// How an incoming transfer ended, short of an I/O or protocol error.
+pub(crate) enum ReceiveOutcome {
+    Complete,
+    CancelledBySender,
+    /// We cancelled it; the sender has been told.
+    CancelledLocally,
+    /// The received content is a blocked type; carries the reason.
+    Blocked(String),
+    /// We refused the transfer before any data moved; carries the reason.
+    Refused(String),
+    /// We have an identical file already; the sender has been told.
+    Identical,
+}
 
-    async fn read_message(reader: &mut BufReader<&mut TcpStream>) -> Result<TransferMessage> {
-        let mut line = String::new();
-        reader.read_line(&mut line).await?;
-        let message: TransferMessage = serde_json::from_str(line.trim())?;
-        Ok(message)
-    }
+/// What a successful send did.
+pub struct TransferOutcome {
+    pub transfer_id: Uuid,
+    pub bytes_sent: u64,
+    /// From the receiver accepting to its last word on the file.
+    pub duration: Duration,
+    /// Bytes per second over `duration`.
+    pub average_speed: u64,
+    /// SHA-256 of what was sent.
+    pub checksum: Option<String>,
+    /// Whether the receiver checked the file against our checksum. It keeps
+    /// a file only once that matches, so finishing cleanly with one means
+    /// it did.
+    pub peer_ack: bool,
+    pub encrypted: bool,
+    /// How the connection to the receiver got through.
+    pub connectivity: Connectivity,
+    /// Whether the receiver had an identical file already, so none was sent.
+    pub deduplicated: bool,
+}
 
-    async fn write_message(stream: &mut TcpStream, message: &TransferMessage) -> Result<()> {
-        let data = serde_json::to_string(message)?;
-        stream.write_all(data.as_bytes()).await?;
-        stream.write_all(b"\n").await?;
-        Ok(())
+impl TransferOutcome {
+    /// The event telling clients about it, under `transfer_id` (a
+    /// broadcast's id, for one of its peers).
+    pub fn complete_message(&self, transfer_id: Uuid, peer_id: Option<Uuid>) -> ServerMessage {
+        ServerMessage::FileTransferComplete {
+            transfer_id,
+            peer_id,
+            file_checksum: self.checksum.clone(),
+            verified: self.peer_ack,
+            bytes_transferred: Some(self.bytes_sent),
+            duration_ms: Some(self.duration.as_millis() as u64),
+            speed_bytes_per_sec: Some(self.average_speed),
+        }
     }
+}
 
-    async fn handle_receiver(
-        mut stream: TcpStream,
-        config: Arc<AppConfig>,
-        semaphore: Arc<Semaphore>,
-    ) -> Result<()> {
-        let _permit = semaphore.acquire().await?;
-        let mut reader = BufReader::new(&mut stream);
-        
-        let message = timeout(Duration::from_secs(30), Self::read_message(&mut reader)).await??;
+/// An offer the receiver accepted, ready to stream.
+pub(crate) struct Offer {
+    /// Who it goes to, when we know them.
+    pub(crate) peer_id: Option<Uuid>,
+    pub(crate) filename: String,
+    /// 0 when the size isn't known up front.
+    pub(crate) file_size: u64,
+    pub(crate) size_unknown: bool,
+    pub(crate) file_checksum: Option<String>,
+    pub(crate) cipher: Option<ChunkCipher>,
+    /// Whether the receiver understands KeepAlive.
+    pub(crate) keepalive: bool,
+    /// Chunks we may send ahead of the receiver's Acks; no limit when unset.
+    pub(crate) window: Option<u32>,
+    /// Whether the receiver takes chunks as binary frames.
+    pub(crate) framed: bool,
+    /// Whether the receiver holds on Pause, and may pause us.
+    pub(crate) pausable: bool,
+    /// Whether the receiver takes chunks compressed.
+    pub(crate) compressed: bool,
+    /// Whether chunks carry a CRC32 and go again on ChunkNack.
+    pub(crate) chunk_checksums: bool,
+    /// Connections the file goes over; more than one splits it.
+    pub(crate) streams: u32,
+    /// Whether the receiver has the file already, so there's nothing to send.
+    pub(crate) identical: bool,
+    /// Whether the request was sealed, so Complete mustn't give away the
+    /// checksum it kept out of sight.
+    pub(crate) sealed: bool,
+}
 
-        match message {
-            TransferMessage::Request {
-                transfer_id,
-                filename,
-                file_path: _,
-                file_size,
-                file_checksum: expected_checksum,
-                mime_type: _,
-            } => {
-                let downloads_dir = std::env::current_dir()?.join("downloads");
-                std::fs::create_dir_all(&downloads_dir)?;
-                
-                let file_path = downloads_dir.join(&filename);
-                let mut file = File::create(&file_path).await?;
-
-                let accept_msg = TransferMessage::Accept { transfer_id };
-                Self::write_message(&mut stream, &accept_msg).await?;
-
-                let mut received_size = 0u64;
-                let mut chunk_index = 0u64;
-                let mut hasher = Sha256::new();
-                let start_time = std::time::Instant::now();
-
-                while received_size < file_size {
-                    let chunk_msg = timeout(
-                        Duration::from_secs(60),
-                        Self::read_message(&mut reader)
-                    ).await??;
-                    
-                    match chunk_msg {
-                        TransferMessage::Chunk {
-                            transfer_id: tid,
-                            chunk_index: idx,
-                            data,
-                        } => {
-                            if tid == transfer_id && idx == chunk_index {
-                                file.write_all(&data).await?;
-                                hasher.update(&data);
-                                received_size += data.len() as u64;
-                                chunk_index += 1;
-                                
-                                // Log progress every 10MB
-                                if received_size % (10 * 1024 * 1024) == 0 {
-                                    let elapsed = start_time.elapsed().as_secs_f64();
-                                    let speed = if elapsed > 0.0 {
-                                        (received_size as f64 / elapsed) as u64
-                                    } else {
-                                        0
-                                    };
-                                    tracing::debug!(
-                                        "Receiving {}: {}/{} ({:.1}%) - {}",
-                                        filename,
-                                        utils::format_bytes(received_size),
-                                        utils::format_bytes(file_size),
-                                        (received_size as f64 / file_size as f64) * 100.0,
-                                        utils::format_speed(speed)
-                                    );
-                                }
-                            }
-                        }
-                        TransferMessage::Complete { 
-                            transfer_id: tid,
-                            file_checksum: received_checksum,
-                        } => {
-                            if tid == transfer_id {
-                                break;
-                            }
-                        }
-                        TransferMessage::Cancel { transfer_id: tid } => {
-                            if tid == transfer_id {
-                                tracing::info!("Transfer {} cancelled by sender", transfer_id);
-                                return Ok(());
-                            }
-                        }
-                        _ => {}
-                    }
-                }
+/// What a sender has heard back from its receiver mid-transfer.
+#[derive(Default)]
+pub(crate) struct Replies {
+    /// Chunks the receiver has stored.
+    acked: u64,
+    /// Whether it asked us to hold.
+    paused: bool,
+    /// Chunks it found damaged and wants again.
+    damaged: Vec<u64>,
+}
 
-                file.sync_all().await?;
-                
-                // Verify checksum if provided
-                let calculated_checksum = hex::encode(hasher.finalize());
-                let verified = if let Some(expected) = expected_checksum {
-                    calculated_checksum == expected
-                } else {
-                    true // No checksum to verify
-                };
-                
-                if !verified {
-                    tracing::warn!(
-                        "Checksum mismatch for {}: expected {:?}, got {}",
-                        filename,
-                        expected_checksum,
-                        calculated_checksum
-                    );
-                } else {
-                    tracing::info!(
-                        "File received: {} ({} bytes) - Checksum verified: {}",
-                        filename,
-                        received_size,
-                        verified
-                    );
-                }
-            }
-            _ => {}
-        }
 
-        Ok(())
-    }
+/// What a Request offers, sealed in it when the receiver can open it.
+#[derive(Default, Serialize, Deserialize)]
+struct OfferDetails {
+    filename: String,
+    file_path: String,
+    file_size: u64,
+    file_checksum: Option<String>,
+    mime_type: Option<String>,
+    detected_mime_type: Option<String>,
+}
 
-    pub async fn send_file(
-        &self,
-        peer_address: std::net::SocketAddr,
-        file_path: PathBuf,
-    ) -> Result<Uuid> {
-        let transfer_id = Uuid::new_v4();
-        let _permit = self.semaphore.acquire().await?;
-
-        // Calculate checksum and get metadata
-        let file_checksum = utils::calculate_file_checksum(&file_path).await.ok();
-        let mime_type = utils::get_mime_type(&file_path);
-        
-        let mut file = File::open(&file_path).await?;
-        let metadata = file.metadata().await?;
-        let file_size = metadata.len();
+/// What a Request offers: a file, or an archive made as it's sent.
+pub(crate) struct Outgoing {
+    filename: String,
+    /// The path the Request gives, which is only the name unless
+    /// `transfer.include_source_path` is on.
+    shared_path: String,
+    /// Unknown for archives until all of one has been sent.
+    file_size: Option<u64>,
+    file_checksum: Option<String>,
+    mime_type: Option<String>,
+    detected_mime_type: Option<String>,
+}
+
+impl Outgoing {
+    /// The file at `file_path`, whose SHA-256 is `file_checksum` if it
+    /// could be hashed.
+    pub(crate) async fn file(config: &AppConfig, file_path: &Path, file_checksum: Option<String>) -> Result<Self> {
         let filename = file_path
             .file_name()
             .and_then(|n| n.to_str())
             .unwrap_or("unknown")
             .to_string();
-        let file_path_str = file_path.to_string_lossy().to_string();
+        let shared_path = if config.transfer.include_source_path {
+            file_path.to_string_lossy().to_string()
+        } else {
+            filename.clone()
+        };
+        Ok(Self {
+            filename,
+            shared_path,
+            file_size: Some(tokio::fs::metadata(file_path).await?.len()),
+            file_checksum,
+            mime_type: utils::get_mime_type(file_path),
+            detected_mime_type: utils::detect_mime_type(file_path).await,
+        })
+    }
 
-        // Connect with timeout
-        let stream = timeout(
-            Duration::from_secs(10),
-            TcpStream::connect(peer_address)
-        ).await??;
-        let mut stream = stream;
-        let mut reader = BufReader::new(&mut stream);
+    /// A zip archive offered as `filename`.
+    fn archive(filename: String) -> Self {
+        Self {
+            shared_path: filename.clone(),
+            filename,
+            file_size: None,
+            file_checksum: None,
+            mime_type: Some(archive::MIME_TYPE.to_string()),
+            detected_mime_type: Some(archive::MIME_TYPE.to_string()),
+        }
+    }
+}
 
-        let request = TransferMessage::Request {
-            transfer_id,
-            filename: filename.clone(),
-            file_path: file_path_str.clone(),
-            file_size,
-            file_checksum: file_checksum.clone(),
-            mime_type,
-        };
-        Self::write_message(&mut stream, &request).await?;
+/// An open connection to a peer, possibly through a relay.
+pub(crate) struct Connection {
+    pub(crate) reader: BufReader<ReadHalf<PeerStream>>,
+    pub(crate) writer: WriteHalf<PeerStream>,
+    /// Where we connected: the peer, or the relay.
+    pub(crate) address: SocketAddr,
+    /// The destination, when relayed.
+    pub(crate) relayed: Option<RelayedPeer>,
+    pub(crate) connectivity: Connectivity,
+}
 
-        let response = timeout(
-            Duration::from_secs(30),
-            Self::read_message(&mut reader)
-        ).await??;
+/// Where a send's chunks come from: the file itself, or a broadcast's shared
+/// reader until that stops feeding us and we read the rest ourselves.
+pub(crate) enum ChunkSource {
+    File(File),
+    Shared {
+        chunks: mpsc::Receiver<Arc<Vec<u8>>>,
+        path: PathBuf,
+    },
+    /// An archive being written as it's sent.
+    Archive(mpsc::Receiver<ReadChunk>),
+}
 
-        match response {
-            TransferMessage::Accept { transfer_id: tid } => {
-                if tid != transfer_id {
-                    return Err(anyhow::anyhow!("Transfer ID mismatch"));
-                }
-            }
-            TransferMessage::Reject { reason } => {
-                return Err(anyhow::anyhow!(
-                    "Transfer rejected by peer: {}",
-                    reason.unwrap_or_else(|| "No reason provided".to_string())
-                ));
-            }
-            _ => {
-                return Err(anyhow::anyhow!("Unexpected response"));
+/// A chunk read ahead of the socket, or why reading stopped.
+type ReadChunk = Result<Arc<Vec<u8>>>;
+
+impl ChunkSource {
+    /// The next chunk, or None at the end of the file. `sent` is how much went
+    /// out already, which is where a peer dropped by the shared reader resumes.
+    async fn next_chunk(&mut self, chunk_size: usize, sent: u64) -> Result<Option<Arc<Vec<u8>>>> {
+        if let ChunkSource::Archive(chunks) = self {
+            return chunks.recv().await.transpose();
+        }
+        if let ChunkSource::Shared { chunks, path } = self {
+            if let Some(chunk) = chunks.recv().await {
+                return Ok(Some(chunk));
             }
+            let mut file = File::open(&path).await?;
+            file.seek(SeekFrom::Start(sent)).await?;
+            *self = ChunkSource::File(file);
         }
+        let ChunkSource::File(file) = self else {
+            unreachable!("other sources return or switch to the file above");
+        };
 
-        let chunk_size = self.config.transfer.chunk_size;
         let mut buffer = vec![0u8; chunk_size];
-        let mut chunk_index = 0u64;
-        let mut sent_size = 0u64;
-        let start_time = std::time::Instant::now();
-
-        // Reset file to beginning
-        file = File::open(&file_path).await?;
+        let n = file.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buffer.truncate(n);
+        Ok(Some(Arc::new(buffer)))
+    }
+}
 
-        loop {
-            let n = file.read(&mut buffer).await?;
-            if n == 0 {
-                break;
-            }
+impl TransferService {
+    /// Nothing is received until `start_listener`; running transfers stop
+    /// when `shutdown` is cancelled. Received files go in `data_dir`'s
+    /// `downloads` folder.
+    pub fn new(
+        config: Arc<AppConfig>,
+        peers: Arc<RwLock<PeerManager>>,
+        data_dir: PathBuf,
+        shutdown: CancellationToken,
+    ) -> Self {
+        let allowed_networks = config
+            .transfer
+            .allowed_networks
+            .iter()
+            .filter_map(|network| match utils::IpNetwork::parse(network) {
+                Ok(network) => Some(network),
+                Err(e) => {
+                    tracing::warn!("Ignoring invalid allowed network {:?}: {}", network, e);
+                    None
+                }
+            })
+            .collect();
+        Self {
+            peers,
+            allowed_networks,
+            send_slots: Slots::new(config.transfer.max_sends()),
+            receive_slots: Slots::new(config.transfer.max_receives()),
+            shutdown,
+            websocket_service: OnceLock::new(),
+            sync_service: OnceLock::new(),
+            tls: OnceLock::new(),
+            approvals: Arc::new(ApprovalService::new(config.clone())),
+            active: Arc::new(ActiveTransfers::default()),
+            bandwidth: Arc::new(Bandwidth::new(&config.bandwidth)),
+            usage: Arc::new(UsageLog::load(data_dir.join(USAGE_FILE), config.transfer.daily_budget_bytes)),
+            manual_peers: std::sync::Mutex::new(config.network.peers.clone()),
+            refusals: Arc::default(),
+            data_dir,
+            local_addr: watch::Sender::new(None),
+            coordinator: config.rendezvous.enabled.then(Arc::default),
+            punches: Punches::default(),
+            stream_joins: Arc::default(),
+            config,
+        }
+    }
 
-            let chunk = TransferMessage::Chunk {
-                transfer_id,
-                chunk_index,
-                data: buffer[..n].to_vec(),
-            };
+    /// Decides which incoming files are taken.
+    pub fn approvals(&self) -> &ApprovalService {
+        &self.approvals
+    }
 
-            Self::write_message(&mut stream, &chunk).await?;
+    /// Running and queued transfers.
+    pub fn active_transfers(&self) -> &ActiveTransfers {
+        &self.active
+    }
 
-            sent_size += n as u64;
-            chunk_index += 1;
-            
-            // Log progress every 10MB
-            if sent_size % (10 * 1024 * 1024) == 0 {
-                let elapsed = start_time.elapsed().as_secs_f64();
-                let speed = if elapsed > 0.0 {
-                    (sent_size as f64 / elapsed) as u64
-                } else {
-                    0
-                };
-                tracing::debug!(
-                    "Sending {}: {}/{} ({:.1}%) - {}",
-                    filename,
-                    utils::format_bytes(sent_size),
-                    utils::format_bytes(file_size),
-                    (sent_size as f64 / file_size as f64) * 100.0,
-                    utils::format_speed(speed)
+    /// Fails transfers whose data stops moving for
+    /// `transfer.stall_timeout_secs` though their connection stays open,
+    /// as when a peer wedges but its keepalives still get through.
+    pub async fn watch_for_stalls(&self) -> Result<()> {
+        let Some(secs) = self.config.transfer.stall_timeout_secs else { return Ok(()) };
+        let after = Duration::from_secs(secs);
+        loop {
+            tokio::time::sleep(STALL_CHECK_INTERVAL).await;
+            for (transfer_id, direction, idle) in self.active.stop_stalled(after) {
+                tracing::warn!(
+                    "Stopping transfer {} ({}): no data moved for {}s",
+                    transfer_id,
+                    direction.as_str(),
+                    idle.as_secs()
                 );
             }
         }
+    }
 
-        let complete = TransferMessage::Complete {
-            transfer_id,
-            file_checksum,
-        };
-        Self::write_message(&mut stream, &complete).await?;
+    /// Speed limits every transfer goes through.
+    pub fn bandwidth(&self) -> &Bandwidth {
+        &self.bandwidth
+    }
 
-        let elapsed = start_time.elapsed().as_secs_f64();
-        let speed = if elapsed > 0.0 {
-            (sent_size as f64 / elapsed) as u64
-        } else {
-            0
-        };
-        
-        tracing::info!(
-            "File sent: {} ({} bytes) in {:.2}s - {}",
-            filename,
-            sent_size,
-            elapsed,
-            utils::format_speed(speed)
-        );
+    /// Bytes moved per day and peer, and the daily budget.
+    pub fn usage(&self) -> &UsageLog {
+        &self.usage
+    }
 
-        Ok(transfer_id)
+    /// Registers an outgoing transfer so it can be cancelled while it is
+    /// queued or running. Pass the result to `send_file`.
+    pub fn track_send(&self, transfer_id: Uuid) -> ActiveTransfer {
+        self.active.register(transfer_id, Direction::Send)
     }
-}
 
+    /// Registers a download from a peer's share. Pass the result to `fetch_shared`.
+    pub fn track_receive(&self, transfer_id: Uuid) -> ActiveTransfer {
+        self.active.register(transfer_id, Direction::Receive)
+    }
+
+    /// Tells clients how transfers go.
+    pub fn set_websocket_service(&self, service: Arc<WebSocketService>) {
+        let _ = self.websocket_service.set(service);
+    }
+
+    /// Lets peers' sync requests be answered.
+    pub fn set_sync_service(&self, service: Arc<SyncService>) {
+        let _ = self.sync_service.set(service);
+    }
+
+    /// Speaks TLS on the transfer port from now on, both ways.
+    pub(crate) fn set_tls(&self, tls: Arc<Tls>) {
+        let _ = self.tls.set(tls);
+    }
+
+    /// Resolves once no transfer is running, from admission through hashing,
+    /// the handshake and any prompt, and no connection holds a slot.
+    pub async fn wait_for_idle(&self) {
+        self.active.wait_for_idle().await;
+        let _ = self.send_slots.acquire_many(self.send_slots.limit()).await;
+        let _ = self.receive_slots.acquire_many(self.receive_slots.limit()).await;
+    }
+
+    /// How many concurrent sends are running.
+    pub fn send_slot_usage(&self) -> SlotUsage {
+        self.send_slots.usage()
+    }
+
+    /// How many concurrent receives are running.
+    pub fn receive_slot_usage(&self) -> SlotUsage {
+        self.receive_slots.usage()
+    }
+
+    /// Changes how many sends and receives may run at once; those left
+    /// unset keep their limit. A lowered limit takes effect as running
+    /// transfers finish. Returns the limits now in force.
+    pub fn set_slot_limits(&self, sends: Option<usize>, receives: Option<usize>) -> Result<(SlotUsage, SlotUsage)> {
+        for limit in [sends, receives].into_iter().flatten() {
+            if !(1..=MAX_SLOTS).contains(&limit) {
+                anyhow::bail!("Concurrency limits must be between 1 and {}, not {}", MAX_SLOTS, limit);
+            }
+        }
+        if let Some(sends) = sends {
+            self.send_slots.set_limit(sends);
+        }
+        if let Some(receives) = receives {
+            self.receive_slots.set_limit(receives);
+        }
+        Ok((self.send_slot_usage(), self.receive_slot_usage()))
+    }
+
+    /// Where state such as the transfer history is kept.
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// Where received files go.
+    pub fn downloads_dir(&self) -> PathBuf {
+        self.config.transfer.downloads_dir(&self.data_dir)
+    }
+
+    /// The address the listener is bound to, once it is. With
+    /// `network.transfer_port` 0 this is where to find the port picked.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *self.local_addr.borrow()
+    }
+
+    /// Resolves with the listener's address once it is bound.
+    pub async fn wait_for_local_addr(&self) -> SocketAddr {
+        let mut bound = self.local_addr.subscribe();
+        loop {
+            if let Some(addr) = *bound.borrow_and_update() {
+                return addr;
+            }
+            // Never closed, since we hold the sender
+            let _ = bound.changed().await;
+        }
+    }
+
+    /// The transfer port peers should use: the bound one, or the
+    /// configured one before the listener is up.
+    pub(crate) fn port(&self) -> u16 {
+        self.local_addr()
+            .map_or(self.config.network.transfer_port, |addr| addr.port())
+    }
+
+    /// Accepts incoming transfers until shutdown. A port picked by the OS
+    /// is kept across restarts, so peers can still reach us.
+    pub async fn start_listener(&self) -> Result<()> {
+        let bind_addr = SocketAddr::from(([0, 0, 0, 0], self.port()));
+        let listener = self.bind_listener(bind_addr)?;
+        let bind_addr = listener.local_addr()?;
+        self.local_addr.send_replace(Some(bind_addr));
+        tracing::info!("Transfer listener started on {}", bind_addr);
+
+        loop {
+            let (stream, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = self.shutdown.cancelled() => {
+                    tracing::info!("Transfer listener stopped");
+                    return Ok(());
+                }
+            };
+
+            let known = self.is_allowed_source(addr.ip()).await;
+
+            if let Err(e) = Self::tune_socket(&self.config.transfer, SockRef::from(&stream)) {
+                tracing::warn!("Failed to tune transfer socket from {}: {}", addr, e);
+            }
+            // A peer we're punching through to may get here before our own
+            // attempt reaches it
+            let Some(stream) = self.punches.claim(addr, stream) else {
+                continue;
+            };
+
+            let context = self.context();
+            if !known {
+                let refusals = self.refusals.clone();
+                tokio::spawn(Self::greet_stranger(stream, addr, context, refusals));
+                continue;
+            }
+
+            tokio::spawn(Self::serve(stream, addr, context));
+        }
+    }
+
+    /// Serves an incoming connection, reporting a receive it fails.
+    pub(crate) async fn serve(stream: TcpStream, addr: SocketAddr, context: ConnectionContext) {
+        let stream = match tls::accept(context.tls.as_deref(), stream).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                tracing::warn!("Dropped transfer connection from {}: {}", addr, e);
+                return;
+            }
+        };
+        let mut progress = None;
+        let websocket = context.websocket.clone();
+        let result = Self::handle_connection(stream, addr, context, &mut progress).await;
+        if let Err(e) = result {
+            tracing::error!("Transfer receiver error from {}: {}", addr, e);
+            if let (Some(progress), Some(ws)) = (progress, websocket) {
+                ws.notify_receive_failed(addr, progress, &e).await;
+            }
+        }
+    }
+
+    pub(crate) fn context(&self) -> ConnectionContext {
+        ConnectionContext {
+            config: self.config.clone(),
+            peers: self.peers.clone(),
+            send_slots: self.send_slots.clone(),
+            receive_slots: self.receive_slots.clone(),
+            approvals: self.approvals.clone(),
+            active: self.active.clone(),
+            bandwidth: self.bandwidth.clone(),
+            usage: self.usage.clone(),
+            websocket: self.websocket_service.get().cloned(),
+            sync: self.sync_service.get().cloned(),
+            tls: self.tls.get().cloned(),
+            relayed: None,
+            connectivity: Connectivity::Direct,
+            coordinator: self.coordinator.clone(),
+            downloads_dir: self.downloads_dir(),
+            content_addressed: self.config.transfer.content_addressed,
+            organize_by_type: self.config.transfer.organize_by_type,
+            on_existing_file: self.config.transfer.on_existing_file,
+            session_id: None,
+            port: self.port(),
+            stream_joins: self.stream_joins.clone(),
+            parallel_to: None,
+        }
+    }
+
+    /// Applies TCP_NODELAY, keepalive, the peer-gone timeout and the
+    /// configured buffer sizes.
+    fn tune_socket(transfer: &TransferConfig, socket: SockRef<'_>) -> std::io::Result<()> {
+        socket.set_tcp_nodelay(true)?;
+        socket.set_tcp_keepalive(&Self::keepalive(transfer))?;
+        #[cfg(any(target_os = "linux", target_os = "android", target_os = "fuchsia"))]
+        if transfer.peer_gone_timeout > 0 {
+            socket.set_tcp_user_timeout(Some(Duration::from_secs(transfer.peer_gone_timeout)))?;
+        }
+        if let Some(size) = transfer.socket_send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = transfer.socket_recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// Keepalive probing so a silently dead peer is noticed even when neither
+    /// side is writing.
+    fn keepalive(transfer: &TransferConfig) -> TcpKeepalive {
+        let keepalive = TcpKeepalive::new().with_time(Duration::from_secs(transfer.keepalive_idle));
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            target_os = "windows",
+        ))]
+        let keepalive = keepalive
+            .with_interval(Duration::from_secs(transfer.keepalive_interval))
+            .with_retries(transfer.keepalive_retries);
+        keepalive
+    }
+
+    /// Writes a message, failing if the peer hasn't let us make progress
+    /// within `stall_timeout`.
+    pub(crate) async fn write_with_liveness<W: AsyncWrite + Unpin>(
+        stream: &mut W,
+        message: &TransferMessage,
+        stall_timeout: Duration,
+    ) -> Result<()> {
+        match timeout(stall_timeout, Self::write_message(stream, message)).await {
+            Ok(result) => result,
+            Err(_) => Err(PeerGone::Unresponsive(format!("no data accepted for {}s", stall_timeout.as_secs())).into()),
+        }
+    }
+
+    /// Waits for a send slot, keeping a receiver that has accepted waiting
+    /// with KeepAlives meanwhile.
+    pub(crate) async fn wait_for_send_slot<W: AsyncWrite + Unpin>(
+        context: &ConnectionContext,
+        stream: &mut W,
+        transfer: &ActiveTransfer,
+        keepalive: bool,
+    ) -> Result<SlotPermit> {
+        let transfer_id = transfer.id();
+        let on_position = |position| {
+            transfer.trace().record("queued", format!("position {}", position));
+            if let Some(ws) = &context.websocket {
+                ws.notify_send_queued(transfer_id, position);
+            }
+        };
+        let max_waiting = context.config.transfer.max_queued_sends;
+        let waiting = async {
+            tokio::select! {
+                permit = context.send_slots.acquire_in_turn(transfer_id, max_waiting, on_position) => Some(permit),
+                _ = transfer.cancelled() => None,
+            }
+        };
+        match Self::keeping_alive(stream, transfer_id, keepalive, waiting).await? {
+            Some(Ok(permit)) => Ok(permit),
+            Some(Err(QueueError::Full(waiting))) => {
+                let _ = Self::write_message(stream, &TransferMessage::Cancel { transfer_id }).await;
+                Err(TransferError::SendQueueFull { waiting }.into())
+            }
+            Some(Err(QueueError::Closed(e))) => Err(e.into()),
+            None => Err(Self::send_cancel(stream, transfer).await),
+        }
+    }
+
+    /// Refuses up front when `transfer.max_queued_sends` sends already wait
+    /// for a slot, rather than after hashing and asking the peer.
+    fn check_send_queue(&self) -> Result<()> {
+        let (waiting, max) = (self.send_slots.waiting(), self.config.transfer.max_queued_sends);
+        if max > 0 && waiting >= max {
+            return Err(TransferError::SendQueueFull { waiting }.into());
+        }
+        Ok(())
+    }
+
+    /// Writes a chunk while reading: mid-transfer the receiver only speaks to
+    /// give up, which stops it reading and would otherwise leave the write
+    /// stuck, to send KeepAlives while its disk is slow, which give the
+    /// write more time, or to pause and resume.
+    pub(crate) async fn write_chunk<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        chunk: &[u8],
+        replies: &mut Replies,
+        stall_timeout: Duration,
+    ) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let write = async { Ok::<_, anyhow::Error>(stream.write_all(chunk).await?) };
+        tokio::pin!(write);
+        let mut deadline = Instant::now() + stall_timeout;
+        let mut keepalives = 0;
+        let written = loop {
+            tokio::select! {
+                biased;
+                heard = reader.fill_buf() => {
+                    if !heard.is_ok_and(|buffered| !buffered.is_empty()) {
+                        break Err(PeerGone::Closed.into());
+                    }
+                    let message = timeout(ABORT_REASON_TIMEOUT, Self::read_message(reader, MAX_CONTROL_MESSAGE_LEN)).await;
+                    match message {
+                        Ok(Ok(TransferMessage::Ack { received_chunks, .. })) => {
+                            replies.acked = received_chunks.max(replies.acked);
+                            keepalives = 0;
+                            deadline = Instant::now() + stall_timeout;
+                        }
+                        Ok(Ok(message @ (TransferMessage::Pause { .. } | TransferMessage::Resume { .. }))) => {
+                            replies.paused = matches!(message, TransferMessage::Pause { .. });
+                            deadline = Instant::now() + stall_timeout;
+                        }
+                        Ok(Ok(TransferMessage::KeepAlive { .. })) if keepalives < MAX_KEEPALIVES => {
+                            keepalives += 1;
+                            deadline = Instant::now() + stall_timeout;
+                        }
+                        Ok(Ok(TransferMessage::ChunkNack { chunk_index, .. })) => replies.damaged.push(chunk_index),
+                        Ok(Ok(message)) => match Self::abort_of(message) {
+                            Some(aborted) => return Err(aborted.into()),
+                            None => break Err(PeerGone::Closed.into()),
+                        },
+                        _ => break Err(PeerGone::Closed.into()),
+                    }
+                }
+                written = &mut write => break written,
+                _ = tokio::time::sleep_until(deadline) => {
+                    break Err(PeerGone::Unresponsive(format!("no data accepted for {}s", stall_timeout.as_secs())).into());
+                }
+            }
+        };
+        match written {
+            Ok(()) => Ok(()),
+            Err(e) => Err(match Self::abort_reason(reader).await {
+                Some(aborted) => aborted.into(),
+                None => e,
+            }),
+        }
+    }
+
+    /// Makes a failure on a send's connection that means the receiver has
+    /// gone into `PeerDisconnected`, noting how much had been sent; any
+    /// other failure is passed on as it is.
+    pub(crate) fn peer_gone(error: anyhow::Error, bytes_sent: u64) -> anyhow::Error {
+        let reason = if let Some(gone) = error.downcast_ref::<PeerGone>() {
+            gone.reason()
+        } else {
+            let Some(io) = error.downcast_ref::<std::io::Error>() else { return error };
+            match io.kind() {
+                std::io::ErrorKind::BrokenPipe
+                | std::io::ErrorKind::ConnectionReset
+                | std::io::ErrorKind::ConnectionAborted
+                | std::io::ErrorKind::NotConnected => "the connection was reset".to_string(),
+                std::io::ErrorKind::UnexpectedEof => "it closed the connection".to_string(),
+                // TCP_USER_TIMEOUT or keepalive gave up on it
+                std::io::ErrorKind::TimedOut => "its machine stopped answering".to_string(),
+                _ => return error,
+            }
+        };
+        TransferError::PeerDisconnected { bytes_sent, reason }.into()
+    }
+
+    /// Waits until the receiver has acknowledged all but fewer than `window`
+    /// of the `sent` chunks, pauses, or asks for a chunk again. Its
+    /// KeepAlives stand in for Acks meanwhile, as it may be stuck on a slow
+    /// disk.
+    pub(crate) async fn wait_for_ack<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        transfer: &ActiveTransfer,
+        replies: &mut Replies,
+        sent: u64,
+        window: u32,
+        stall_timeout: Duration,
+    ) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut keepalives = 0;
+        // A damaged chunk holds the receiver's Acks back until it goes again
+        while sent - replies.acked >= u64::from(window) && !replies.paused && replies.damaged.is_empty() {
+            let message = tokio::select! {
+                message = timeout(stall_timeout, Self::read_message(reader, MAX_CONTROL_MESSAGE_LEN)) => message,
+                _ = transfer.cancelled() => return Err(Self::send_cancel(stream, transfer).await),
+            };
+            match message {
+                Ok(Ok(TransferMessage::Ack { received_chunks, .. })) => {
+                    replies.acked = received_chunks.min(sent).max(replies.acked);
+                    keepalives = 0;
+                }
+                Ok(Ok(TransferMessage::Pause { .. })) => replies.paused = true,
+                Ok(Ok(TransferMessage::Resume { .. })) => replies.paused = false,
+                Ok(Ok(TransferMessage::ChunkNack { chunk_index, .. })) => replies.damaged.push(chunk_index),
+                Ok(Ok(TransferMessage::KeepAlive { .. })) if keepalives < MAX_KEEPALIVES => keepalives += 1,
+                Ok(Ok(TransferMessage::KeepAlive { .. })) => {
+                    anyhow::bail!("Receiver kept the transfer waiting without acknowledging chunks");
+                }
+                Ok(Ok(message)) => {
+                    return Err(match Self::abort_of(message) {
+                        Some(aborted) => aborted.into(),
+                        None => anyhow::anyhow!("Unexpected message while waiting for an acknowledgement"),
+                    });
+                }
+                Ok(Err(_)) => return Err(PeerGone::Closed.into()),
+                Err(_) => {
+                    return Err(PeerGone::Unresponsive(format!("no chunks acknowledged for {}s", stall_timeout.as_secs())).into());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends again the chunks the receiver found damaged, as they were first
+    /// sent, returning how many bytes that took. A chunk that keeps arriving
+    /// damaged is given up on.
+    async fn resend_damaged<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        unacked: &VecDeque<(u64, Vec<u8>)>,
+        replies: &mut Replies,
+        resends: &mut HashMap<u64, u32>,
+        stall_timeout: Duration,
+    ) -> Result<usize>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut resent = 0;
+        // Writing may hear of more
+        while !replies.damaged.is_empty() {
+            let index = replies.damaged.remove(0);
+            let times = resends.entry(index).or_insert(0);
+            *times += 1;
+            if *times > MAX_CHUNK_RESENDS {
+                anyhow::bail!("Chunk {} arrived damaged {} times", index, times);
+            }
+            let Some((_, chunk)) = unacked.iter().find(|(unacked, _)| *unacked == index) else {
+                anyhow::bail!("Receiver asked again for chunk {}, which it had or was never sent", index);
+            };
+            Self::write_chunk(reader, stream, chunk, replies, stall_timeout).await?;
+            resent += chunk.len();
+        }
+        Ok(resent)
+    }
+
+    /// Runs `work`, sending KeepAlives on `stream` while it takes a while
+    /// if the peer understands them.
+    pub(crate) async fn keeping_alive<W, F>(stream: &mut W, transfer_id: Uuid, keepalive: bool, work: F) -> Result<F::Output>
+    where
+        W: AsyncWrite + Unpin,
+        F: std::future::Future,
+    {
+        tokio::pin!(work);
+        if !keepalive {
+            return Ok(work.await);
+        }
+        loop {
+            tokio::select! {
+                output = &mut work => return Ok(output),
+                _ = tokio::time::sleep(KEEPALIVE_INTERVAL) => {
+                    Self::write_message(stream, &TransferMessage::KeepAlive { transfer_id }).await?;
+                }
+            }
+        }
+    }
+
+    /// Holds a send between chunks while either side has it paused, taking
+    /// what the receiver says meanwhile. A receiver new enough is told we
+    /// paused and resumed; an older one is kept waiting with KeepAlives.
+    async fn hold_while_paused<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        transfer: &ActiveTransfer,
+        replies: &mut Replies,
+        pausable: bool,
+        keepalive: bool,
+    ) -> Result<()>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let pausing = transfer.pausing();
+        let mut changes = pausing.watch();
+        let mut told = false;
+        loop {
+            pausing.set_by_peer(replies.paused);
+            let here = changes.borrow_and_update().here;
+            if pausable && here != told {
+                let transfer_id = transfer.id();
+                let message = if here { TransferMessage::Pause { transfer_id } } else { TransferMessage::Resume { transfer_id } };
+                Self::write_message(stream, &message).await?;
+                told = here;
+            }
+            if transfer.is_stopping() || !(here || replies.paused) {
+                return Ok(());
+            }
+            // Only reads once something has arrived, so a change here never
+            // cuts a message short
+            let waiting = async {
+                tokio::select! {
+                    _ = changes.changed() => Ok(false),
+                    _ = transfer.cancelled() => Ok(false),
+                    buffered = reader.fill_buf(), if pausable => match buffered {
+                        Ok(buffered) if !buffered.is_empty() => Ok(true),
+                        _ => Err(PeerGone::Closed),
+                    },
+                }
+            };
+            if !Self::keeping_alive(stream, transfer.id(), keepalive && !pausable, waiting).await?? {
+                continue;
+            }
+            match Self::read_message(reader, MAX_CONTROL_MESSAGE_LEN).await {
+                Ok(TransferMessage::Ack { received_chunks, .. }) => replies.acked = received_chunks.max(replies.acked),
+                Ok(TransferMessage::Pause { .. }) => replies.paused = true,
+                Ok(TransferMessage::Resume { .. }) => replies.paused = false,
+                Ok(TransferMessage::ChunkNack { chunk_index, .. }) => replies.damaged.push(chunk_index),
+                Ok(TransferMessage::KeepAlive { .. }) => {}
+                Ok(message) => {
+                    return Err(match Self::abort_of(message) {
+                        Some(aborted) => aborted.into(),
+                        None => PeerGone::Closed.into(),
+                    });
+                }
+                Err(_) => return Err(PeerGone::Closed.into()),
+            }
+        }
+    }
+
+    /// Binds the transfer listener. Buffer sizes are set before listening so
+    /// accepted sockets inherit them and negotiate a matching TCP window.
+    fn bind_listener(&self, addr: SocketAddr) -> Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        // Same as std's bind, so a restart doesn't trip over TIME_WAIT connections
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        // So registrations and punches can go out from the port we listen on
+        if self.config.rendezvous.coordinator.is_some() {
+            Self::share_port(&socket)?;
+        }
+        Self::tune_socket(&self.config.transfer, SockRef::from(&socket))?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+        socket.set_nonblocking(true)?;
+        Ok(TcpListener::from_std(socket.into())?)
+    }
+
+    pub(crate) async fn connect(transfer: &TransferConfig, addr: SocketAddr) -> Result<TcpStream> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        Self::tune_socket(transfer, SockRef::from(&socket))?;
+        socket.set_nonblocking(true)?;
+        let socket = TcpSocket::from_std_stream(socket.into());
+        Ok(socket.connect(addr).await?)
+    }
+
+    /// Like `connect`, but from our transfer port `port`, so that routers
+    /// on the way map it to the same public port as our other rendezvous
+    /// connections.
+    pub(crate) async fn connect_from(transfer: &TransferConfig, port: u16, addr: SocketAddr) -> Result<TcpStream> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        Self::tune_socket(transfer, SockRef::from(&socket))?;
+        socket.set_reuse_address(true)?;
+        Self::share_port(&socket)?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, port).into(),
+            SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, port).into(),
+        };
+        socket.bind(&local.into())?;
+        socket.set_nonblocking(true)?;
+        let socket = TcpSocket::from_std_stream(socket.into());
+        Ok(socket.connect(addr).await?)
+    }
+
+    /// Lets `socket` bind the port the listener is on.
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin"))))]
+    fn share_port(socket: &Socket) -> std::io::Result<()> {
+        socket.set_reuse_port(true)
+    }
+
+    /// Lets `socket` bind the port the listener is on; without port reuse,
+    /// address reuse has to do.
+    #[cfg(not(all(unix, not(any(target_os = "solaris", target_os = "illumos", target_os = "cygwin")))))]
+    fn share_port(socket: &Socket) -> std::io::Result<()> {
+        socket.set_reuse_address(true)
+    }
+
+    /// Connects to the peer at `address`, falling back to the other
+    /// addresses it announced, such as a router port mapping, in TLS when
+    /// we speak it and the peer announced a certificate.
+    pub(crate) async fn connect_peer(&self, address: SocketAddr) -> Result<PeerStream> {
+        let stream = self.reach(address).await?;
+        self.secure(stream, address).await
+    }
+
+    /// Opens TLS on `stream`, which we connected to the peer at `address`,
+    /// trusting only the certificate it announced. Plain when we don't use
+    /// TLS or, unless it's required, the peer doesn't.
+    pub(crate) async fn secure(&self, stream: TcpStream, address: SocketAddr) -> Result<PeerStream> {
+        let pin = self.peers.read().await.tls_fingerprint(address);
+        tls::connect(self.tls(), stream, pin.as_deref()).await
+    }
+
+    /// The TLS the transfer port speaks, if it does.
+    pub(crate) fn tls(&self) -> Option<&Tls> {
+        self.tls.get().map(Arc::as_ref)
+    }
+
+    /// Opens a TCP connection to the peer at `address` or, failing that,
+    /// one of the other addresses it announced.
+    pub(crate) async fn reach(&self, address: SocketAddr) -> Result<TcpStream> {
+        let candidates = self.peers.read().await.addresses_to_try(address);
+        let mut first_error = None;
+        for candidate in candidates {
+            let started = Instant::now();
+            let error = match timeout(CONNECT_ATTEMPT_TIMEOUT, Self::connect(&self.config.transfer, candidate)).await {
+                Ok(Ok(stream)) => {
+                    if candidate != address {
+                        tracing::info!("Reached {} at its other address {}", address, candidate);
+                    }
+                    self.peers.write().await.record_reached(address, candidate, started.elapsed());
+                    return Ok(stream);
+                }
+                Ok(Err(e)) => e,
+                Err(e) => e.into(),
+            };
+            tracing::debug!("Couldn't reach {} at {}: {}", address, candidate, error);
+            first_error.get_or_insert(error);
+        }
+        Err(first_error.unwrap_or_else(|| anyhow::anyhow!("No address to reach {}", address)))
+    }
+
+    /// Whether `ip` may send us files: a discovered peer or an allowed network.
+    async fn is_allowed_source(&self, ip: std::net::IpAddr) -> bool {
+        if !self.config.transfer.require_known_peer {
+            return true;
+        }
+        if self.allowed_networks.iter().any(|network| network.contains(ip)) {
+            return true;
+        }
+        self.peers.read().await.find_by_ip(ip.to_canonical()).is_some()
+    }
+
+    /// Longest chunk message accepted from a peer. Chunk data is a JSON array of
+    /// numbers (up to 4 bytes per byte), and peers may use a larger chunk size
+    /// than we do, so allow at least 1 MiB of payload.
+    pub(crate) fn max_chunk_message_len(chunk_size: usize) -> usize {
+        chunk_size.max(MIN_CHUNK_LIMIT) * 4 + 1024
+    }
+
+    /// Reads one newline-terminated message, failing without buffering further
+    /// once it exceeds `max_len` bytes.
+    pub(crate) async fn read_message<R: AsyncBufRead + Unpin>(reader: &mut R, max_len: usize) -> Result<TransferMessage> {
+        let line = Self::read_raw_message(reader, max_len).await?;
+        Ok(serde_json::from_str(&line)?)
+    }
+
+    /// Like `read_message`, but returns the line as received so it can be
+    /// included in the handshake transcript.
+    pub(crate) async fn read_raw_message<R: AsyncBufRead + Unpin>(reader: &mut R, max_len: usize) -> Result<String> {
+        let mut line = String::new();
+        let mut limited = (&mut *reader).take(max_len as u64 + 1);
+        if limited.read_line(&mut line).await? == 0 {
+            return Err(anyhow::anyhow!("Connection closed by peer"));
+        }
+        if line.len() > max_len {
+            return Err(anyhow::anyhow!(
+                "Protocol error: message exceeds {} bytes",
+                max_len
+            ));
+        }
+        Ok(line.trim().to_string())
+    }
+
+    pub(crate) async fn write_message<W: AsyncWrite + Unpin>(stream: &mut W, message: &TransferMessage) -> Result<()> {
+        let data = serde_json::to_string(message)?;
+        Self::write_raw_message(stream, &data).await
+    }
+
+    pub(crate) async fn write_raw_message<W: AsyncWrite + Unpin>(stream: &mut W, data: &str) -> Result<()> {
+        stream.write_all(data.as_bytes()).await?;
+        stream.write_all(b"\n").await?;
+        Ok(())
+    }
+
+    /// A chunk as it goes on the wire: a binary frame when the receiver
+    /// takes them, a JSON line otherwise, with `crc` when there is one.
+    pub(crate) fn encode_chunk(transfer_id: Uuid, chunk_index: u64, data: Vec<u8>, crc: Option<u32>, framed: bool) -> Result<Vec<u8>> {
+        if !framed {
+            let mut line = serde_json::to_vec(&TransferMessage::Chunk {
+                transfer_id,
+                chunk_index,
+                data,
+                crc,
+            })?;
+            line.push(b'\n');
+            return Ok(line);
+        }
+        let length = u32::try_from(data.len())?;
+        let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + 4 + data.len());
+        frame.push(if crc.is_some() { CHECKED_FRAME_MARKER } else { FRAME_MARKER });
+        frame.extend_from_slice(transfer_id.as_bytes());
+        frame.extend_from_slice(&chunk_index.to_be_bytes());
+        frame.extend_from_slice(&length.to_be_bytes());
+        if let Some(crc) = crc {
+            frame.extend_from_slice(&crc.to_be_bytes());
+        }
+        frame.extend_from_slice(&data);
+        Ok(frame)
+    }
+
+    /// Reads the next message of a transfer under way: a chunk framed in
+    /// binary, or any message as a JSON line. Frames longer than `max_len`
+    /// are refused before their data is read.
+    pub(crate) async fn read_transfer_message<R: AsyncBufRead + Unpin>(reader: &mut R, max_len: usize) -> Result<TransferMessage> {
+        let checked = match reader.fill_buf().await?.first() {
+            Some(&FRAME_MARKER) => false,
+            Some(&CHECKED_FRAME_MARKER) => true,
+            _ => return Self::read_message(reader, max_len).await,
+        };
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        reader.read_exact(&mut header).await?;
+        let transfer_id = Uuid::from_bytes(header[1..17].try_into()?);
+        let chunk_index = u64::from_be_bytes(header[17..25].try_into()?);
+        let length = u32::from_be_bytes(header[25..29].try_into()?) as usize;
+        if length > max_len {
+            return Err(anyhow::anyhow!("Protocol error: chunk exceeds {} bytes", max_len));
+        }
+        let crc = if checked {
+            let mut crc = [0u8; 4];
+            reader.read_exact(&mut crc).await?;
+            Some(u32::from_be_bytes(crc))
+        } else {
+            None
+        };
+        let mut data = vec![0u8; length];
+        reader.read_exact(&mut data).await?;
+        Ok(TransferMessage::Chunk {
+            transfer_id,
+            chunk_index,
+            data,
+            crc,
+        })
+    }
+
+    /// Gives a just-saved file, and the folder it's in, the configured group
+    /// and permission bits. Returns the mode the file got, if one was set;
+    /// what can't be applied is logged and left as it was.
+    fn apply_received_permissions(config: &TransferConfig, path: &Path) -> Option<FileMode> {
+        let dir = path.parent();
+        // Before the modes, as changing the group may clear setgid bits
+        if let Some(group) = &config.received_group {
+            for target in [Some(path), dir].into_iter().flatten() {
+                match utils::set_group(target, group) {
+                    Ok(true) => {}
+                    Ok(false) => tracing::debug!("Files have no group here, not giving {} to {}", target.display(), group),
+                    Err(e) => tracing::warn!("Couldn't give {} to group {}: {}", target.display(), group, e),
+                }
+            }
+        }
+        if let (Some(mode), Some(dir)) = (config.received_dir_mode, dir) {
+            match utils::set_mode(dir, mode.0) {
+                Ok(true) => {}
+                Ok(false) => tracing::debug!("Folders have no mode here, leaving {} as it is", dir.display()),
+                Err(e) => tracing::warn!("Couldn't set mode {} on {}: {}", mode, dir.display(), e),
+            }
+        }
+        let mode = config.received_file_mode?;
+        match utils::set_mode(path, mode.0) {
+            Ok(true) => Some(mode),
+            Ok(false) => {
+                tracing::debug!("Files have no mode here, leaving {} as created", path.display());
+                None
+            }
+            Err(e) => {
+                tracing::warn!("Couldn't set mode {} on {}: {}", mode, path.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Partial data is written next to the final path under this suffix and
+    /// only renamed into place once every byte has arrived.
+    fn partial_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".part");
+        PathBuf::from(name)
+    }
+
+    /// Creates the file `filename` is received into in `dir`: its name with
+    /// ".part", numbered while another receive of the same name has that.
+    async fn create_partial(dir: &Path, filename: &str) -> Result<(PathBuf, File)> {
+        let mut n = 0;
+        loop {
+            let part_path = Self::partial_path(&dir.join(utils::numbered_filename(filename, n)));
+            match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&part_path).await {
+                Ok(file) => return Ok((part_path, file)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Whether `path` is a file of `file_size` bytes hashing to `checksum`.
+    async fn holds_identical(path: &Path, file_size: u64, checksum: &str) -> bool {
+        match tokio::fs::metadata(path).await {
+            Ok(metadata) if metadata.is_file() && metadata.len() == file_size => utils::calculate_file_checksum(path)
+                .await
+                .is_ok_and(|found| found.eq_ignore_ascii_case(checksum)),
+            _ => false,
+        }
+    }
+
+    /// Where a file finished as `filename` is saved in `dir`: under that name,
+    /// or the first free numbered one when it's taken and we rename. None
+    /// when it's taken and we reject. Unless we overwrite, the name is held
+    /// with an empty file until the download is put over it, so a receive
+    /// of the same name finishing alongside can't take it too.
+    async fn claim_path(dir: &Path, filename: &str, policy: ExistingFilePolicy) -> Result<Option<PathBuf>> {
+        let mut n = 0;
+        loop {
+            let path = dir.join(utils::numbered_filename(filename, n));
+            if policy == ExistingFilePolicy::Overwrite {
+                return Ok(Some(path));
+            }
+            match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&path).await {
+                Ok(_) => return Ok(Some(path)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && policy == ExistingFilePolicy::Reject => {
+                    return Ok(None);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => n += 1,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Serves one incoming connection: a file offered to us, or a request
+    /// for our share. `progress` is filled in once a file's request has been
+    /// read so the caller can report how far a failed transfer got.
+    pub(crate) async fn handle_connection(
+        stream: PeerStream,
+        addr: SocketAddr,
+        mut context: ConnectionContext,
+        progress: &mut Option<ReceiveProgress>,
+    ) -> Result<()> {
+        let (read_half, mut stream) = stream.into_split();
+        let mut reader = BufReader::new(read_half);
+
+        let mut first_line = timeout(
+            Duration::from_secs(30),
+            Self::read_raw_message(&mut reader, MAX_CONTROL_MESSAGE_LEN),
+        )
+        .await??;
+        // A relay names the origin, then passes on whatever the origin sends
+        if let Ok(TransferMessage::Relayed { origin }) = serde_json::from_str(&first_line) {
+            tracing::info!("Connection from {} relayed by {}", origin.hostname, addr);
+            context.relayed = Some(origin);
+            context.connectivity = Connectivity::Relayed;
+            first_line = timeout(
+                Duration::from_secs(30),
+                Self::read_raw_message(&mut reader, MAX_CONTROL_MESSAGE_LEN),
+            )
+            .await??;
+        }
+
+        match serde_json::from_str::<TransferMessage>(&first_line) {
+            Ok(TransferMessage::ListShare { path }) => Self::serve_listing(&mut stream, &context.config, path).await,
+            Ok(TransferMessage::SpaceQuery) => Self::report_space(&mut stream, &context).await,
+            Ok(TransferMessage::Probe) => Self::write_message(&mut stream, &TransferMessage::ProbeAck).await,
+            Ok(TransferMessage::Benchmark {
+                transfer_id,
+                peer_sends,
+                payload_size,
+                duration_secs,
+            }) => {
+                let duration = Duration::from_secs(duration_secs).min(MAX_BENCHMARK_DURATION);
+                Self::serve_benchmark(&mut reader, &mut stream, addr, &context, transfer_id, peer_sends, payload_size, duration).await
+            }
+            Ok(TransferMessage::FetchShared {
+                transfer_id,
+                relative_path,
+            }) => Self::serve_fetch(&mut reader, &mut stream, addr, &context, transfer_id, &relative_path).await,
+            Ok(TransferMessage::SyncManifestRequest { folder }) => {
+                Self::serve_manifest(&mut stream, addr, &context, folder).await
+            }
+            Ok(TransferMessage::SyncFetch {
+                transfer_id,
+                folder,
+                relative_path,
+            }) => Self::serve_sync_fetch(&mut reader, &mut stream, addr, &context, transfer_id, &folder, &relative_path).await,
+            Ok(TransferMessage::RelayRequest { destination, origin }) => {
+                Self::relay(&mut reader, &mut stream, addr, &context, destination, origin).await
+            }
+            Ok(TransferMessage::RendezvousRegister { peer }) => {
+                Self::serve_registration(&mut reader, &mut stream, addr, &context, peer).await
+            }
+            Ok(TransferMessage::RendezvousConnect { origin, destination }) => {
+                Self::broker(&mut stream, addr, &context, origin, destination).await
+            }
+            Ok(TransferMessage::RendezvousJoin { token }) => {
+                let joined = context
+                    .coordinator
+                    .as_ref()
+                    .is_some_and(|coordinator| coordinator.join(&token, (reader, stream)));
+                if !joined {
+                    return Err(anyhow::anyhow!("Connection back for a relay nobody is waiting on"));
+                }
+                Ok(())
+            }
+            Ok(TransferMessage::StreamRange { transfer_id, range }) => {
+                if context.relayed.is_some() {
+                    return Err(anyhow::anyhow!("Refused a relayed part of a split transfer"));
+                }
+                let joined = JoinedStream {
+                    range,
+                    reader,
+                    writer: stream,
+                };
+                match context.stream_joins.join(transfer_id, addr.ip().to_canonical(), joined) {
+                    None => Ok(()),
+                    Some(_) => Err(anyhow::anyhow!("Connection for a transfer that isn't split over it")),
+                }
+            }
+            Ok(TransferMessage::ManifestRequest { session_id, files, more, sealed }) => {
+                Self::receive_session(&mut reader, &mut stream, addr, &context, session_id, files, sealed, more, progress).await
+            }
+            Ok(TransferMessage::Hello { announcement }) => {
+                if context.relayed.is_some() {
+                    return Err(anyhow::anyhow!("Refused a relayed introduction"));
+                }
+                Self::answer_hello(&mut stream, addr, &context, announcement, true).await
+            }
+            // Anything else should be a file offered to us, possibly in a
+            // request too new to parse
+            _ => Self::receive_file(&mut reader, &mut stream, addr, &context, &first_line, None, progress)
+                .await
+                .map(|_| ()),
+        }
+    }
+
+    /// Opens what a peer sealed to our identity for `id`.
+    pub(crate) async fn open_sealed<T: serde::de::DeserializeOwned>(peers: &RwLock<PeerManager>, id: Uuid, sealed: &[u8]) -> Result<T> {
+        let identity = peers.read().await.identity();
+        let opened = crypto::open_sealed(identity.secret(), id, sealed)?;
+        Ok(serde_json::from_slice(&opened)?)
+    }
+
+    /// Receives the file `request_line` offers. With `requested`, this is a
+    /// file we asked for, so it must be that transfer and isn't put to the
+    /// accept rules.
+    pub(crate) async fn receive_file<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        addr: SocketAddr,
+        context: &ConnectionContext,
+        request_line: &str,
+        requested: Option<&ActiveTransfer>,
+        progress: &mut Option<ReceiveProgress>,
+    ) -> Result<ReceiveOutcome>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let ConnectionContext {
+            config,
+            peers,
+            receive_slots,
+            approvals,
+            active,
+            websocket,
+            relayed,
+            downloads_dir,
+            content_addressed,
+            organize_by_type,
+            on_existing_file,
+            ..
+        } = context;
+
+        let raw: serde_json::Value = serde_json::from_str(request_line)?;
+        if let Some(version) = Self::unsupported_version(&raw) {
+            let reason = format!("unsupported protocol version {}, I speak {}", version, PROTOCOL_VERSION);
+            tracing::warn!("Refusing transfer from {}: {}", addr, reason);
+            if let Some(transfer_id) = raw["Request"]["transfer_id"].as_str().and_then(|id| id.parse().ok()) {
+                let reject = TransferMessage::Reject {
+                    transfer_id,
+                    reason: Some(reason.clone()),
+                    protocol_version: Some(PROTOCOL_VERSION),
+                    max_file_size: None,
+                };
+                Self::write_message(stream, &reject).await?;
+            }
+            return Ok(ReceiveOutcome::Refused(reason));
+        }
+        let message: TransferMessage = serde_json::from_value(raw)?;
+
+        let TransferMessage::Request {
+            protocol_version,
+            transfer_id,
+            filename,
+            file_path,
+            file_size,
+            file_checksum,
+            mime_type,
+            detected_mime_type,
+            public_key: sender_key,
+            identity_key: sender_identity,
+            size_unknown,
+            compression,
+            mac,
+            chunk_checksums,
+            parallel_streams,
+            skip_identical,
+            sealed,
+        } = message else {
+            return Ok(ReceiveOutcome::Refused("Expected a transfer request".to_string()));
+        };
+        let details = match sealed {
+            None => OfferDetails {
+                filename,
+                file_path,
+                file_size,
+                file_checksum,
+                mime_type,
+                detected_mime_type,
+            },
+            Some(sealed) => match Self::open_sealed(peers, transfer_id, &sealed).await {
+                Ok(details) => details,
+                Err(e) => {
+                    let reason = format!("Could not open the sealed request: {}", e);
+                    tracing::warn!("Refusing transfer from {}: {}", addr, reason);
+                    let reject = TransferMessage::Reject {
+                        transfer_id,
+                        reason: Some(reason.clone()),
+                        protocol_version: None,
+                        max_file_size: None,
+                    };
+                    Self::write_message(stream, &reject).await?;
+                    return Ok(ReceiveOutcome::Refused(reason));
+                }
+            },
+        };
+        let OfferDetails {
+            filename,
+            file_size,
+            file_checksum: expected_checksum,
+            mime_type,
+            detected_mime_type,
+            ..
+        } = details;
+        // Over the name as sent, before it's made safe
+        let authenticated = config.transfer.shared_secret.as_deref().is_none_or(|secret| {
+            mac.as_deref().is_some_and(|mac| {
+                crypto::verify_request_mac(
+                    secret,
+                    mac,
+                    transfer_id,
+                    &filename,
+                    file_size,
+                    expected_checksum.as_deref(),
+                    sender_key.as_deref(),
+                )
+            })
+        });
+        let unsafe_name = utils::filename_refusal(&filename).map(|why| format!("Unsafe filename {:?}: {}", filename, why));
+        // Everything below, the blocklist included, sees the name we'd save under
+        let filename = utils::safe_filename(&filename);
+
+        // A file we may have already is compared, once admitted, before its
+        // name counts as taken; we vouch for having it under the sender's keys
+        let identical_to = match (expected_checksum.as_deref(), sender_key.as_deref(), sender_identity.as_deref()) {
+            (Some(checksum), Some(key), Some(identity_key)) if skip_identical && requested.is_none() && !size_unknown => {
+                Some((checksum, key, identity_key))
+            }
+            _ => None,
+        };
+        // Told to the sender along with the refusal, when that's why
+        let mut max_file_size = None;
+        let refusal = if requested.is_some_and(|transfer| transfer.id() != transfer_id) {
+            Some("Not the transfer that was asked for".to_string())
+        } else if !authenticated {
+            Some(BAD_SHARED_SECRET.to_string())
+        } else if let Some(reason) = unsafe_name {
+            Some(reason)
+        } else if sender_identity.is_some() && sender_key.is_none() {
+            Some(UNPROVEN_IDENTITY.to_string())
+        } else if let Err(reason) =
+            Self::verify_peer_identity(peers, addr, relayed.as_ref(), sender_identity.as_deref(), websocket.as_deref()).await
+        {
+            Some(reason)
+        } else if sender_key.is_none() && config.transfer.require_encryption {
+            Some("Encryption required".to_string())
+        } else if let Some(budget) = context.usage.exhausted_budget() {
+            Some(TransferError::DailyBudgetExhausted { budget }.to_string())
+        } else if *on_existing_file == ExistingFilePolicy::Reject
+            && !*organize_by_type
+            && identical_to.is_none()
+            && downloads_dir.join(&filename).exists()
+        {
+            // Under organize_by_type the folder isn't known until it's all here
+            Some(file_exists(&filename))
+        } else if let Some(max) = config.transfer.max_receive_file_size.filter(|max| file_size > *max) {
+            max_file_size = Some(max);
+            Some(too_large(max))
+        } else {
+            Self::lacks_room(downloads_dir, file_size, config.transfer.free_space_margin)
+        };
+        if let Some(reason) = refusal {
+            tracing::warn!("Refusing {} from {}: {}", filename, addr, reason);
+            let reject = TransferMessage::Reject {
+                transfer_id,
+                reason: Some(reason.clone()),
+                protocol_version: None,
+                max_file_size,
+            };
+            Self::write_message(stream, &reject).await?;
+            return Ok(ReceiveOutcome::Refused(reason));
+        }
+
+        let extension_type = utils::get_mime_type(Path::new(&filename));
+        let claimed_types = [mime_type.as_deref(), detected_mime_type.as_deref(), extension_type.as_deref()];
+        if let Some(reason) = Self::blocked_reason(&config.transfer, &filename, claimed_types) {
+            tracing::warn!("Refusing {} from {}: {}", filename, addr, reason);
+            let reject = TransferMessage::Reject {
+                transfer_id,
+                reason: Some(reason.clone()),
+                protocol_version: None,
+                max_file_size: None,
+            };
+            Self::write_message(stream, &reject).await?;
+            return Ok(ReceiveOutcome::Refused(reason));
+        }
+
+        if utils::is_mime_mismatch(mime_type.as_deref(), detected_mime_type.as_deref()) {
+            tracing::warn!(
+                "Incoming file {} claims {} but content looks like {}",
+                filename,
+                mime_type.as_deref().unwrap_or_default(),
+                detected_mime_type.as_deref().unwrap_or_default()
+            );
+        }
+
+        // From here on the transfer is queued, and cancelling it is up to us.
+        // A requested file was registered when it was asked for.
+        let registered;
+        let transfer = match requested {
+            Some(transfer) => transfer,
+            None => {
+                registered = active.register(transfer_id, Direction::Receive);
+                &registered
+            }
+        };
+        if config.transfer.trace_transfers {
+            transfer.trace().enable();
+        }
+        transfer.trace().record(
+            "request_received",
+            format!("{} ({} bytes) from {}, protocol {}", filename, file_size, addr, protocol_version),
+        );
+
+        let peer = match relayed {
+            // A relayed origin we haven't discovered ourselves still goes to
+            // the rules by its id and hostname
+            Some(origin) => Some(peers.read().await.get_peer(&origin.peer_id).cloned().unwrap_or_else(|| {
+                Peer::from_discovery(origin.peer_id, addr, origin.hostname.clone(), None)
+            })),
+            None => peers.read().await.find_by_ip(addr.ip().to_canonical()).cloned(),
+        };
+        let content_type = detected_mime_type.as_deref().or(mime_type.as_deref()).or(extension_type.as_deref());
+        let decision = approvals.decide(&IncomingFile {
+            peer: peer.as_ref(),
+            filename: &filename,
+            mime_type: content_type,
+            // Could be any size, so no rule capped by size covers it
+            file_size: if size_unknown { u64::MAX } else { file_size },
+        });
+        let keepalive = protocol_version >= ProtocolVersion::KEEPALIVE;
+        // Asking for a file is consent enough, short of a rule rejecting it
+        let decision = match (requested, decision.action, &decision.rule) {
+            (Some(_), AcceptAction::Reject, Some(_)) | (None, _, _) => decision,
+            (Some(_), _, _) => Decision {
+                action: AcceptAction::Accept,
+                ..decision
+            },
+        };
+        let verdict = match decision.action {
+            AcceptAction::Accept => Ok(()),
+            AcceptAction::Reject => Err(match &decision.rule {
+                Some(rule) => format!("Declined by rule \"{}\"", rule),
+                None => "Declined by the recipient".to_string(),
+            }),
+            AcceptAction::Prompt => {
+                let prompt = ServerMessage::IncomingTransferRequest {
+                    transfer_id,
+                    peer_id: peer.as_ref().map(|p| p.id),
+                    peer_hostname: peer.as_ref().map_or_else(|| addr.ip().to_string(), |p| p.hostname.clone()),
+                    filename: filename.clone(),
+                    file_size,
+                    size_unknown,
+                    mime_type: content_type.map(str::to_string),
+                    rule: decision.rule.clone(),
+                };
+                // A sender kept waiting with KeepAlives can wait for a person
+                let approval_timeout = if keepalive { KEPT_ALIVE_APPROVAL_TIMEOUT } else { APPROVAL_TIMEOUT };
+                let asking = Self::ask_to_accept(approvals, websocket.as_deref(), transfer, prompt, approval_timeout);
+                Self::keeping_alive(stream, transfer_id, keepalive, asking).await?
+            }
+        };
+        // Only a transfer we're about to accept takes a slot
+        let permit = match verdict {
+            Ok(()) => {
+                let waiting = async {
+                    tokio::select! {
+                        permit = receive_slots.acquire() => Ok(permit),
+                        _ = transfer.cancelled() => Err(CANCELLED_BY_RECIPIENT.to_string()),
+                    }
+                };
+                match Self::keeping_alive(stream, transfer_id, keepalive, waiting).await? {
+                    Ok(permit) => Ok(permit?),
+                    Err(reason) => Err(reason),
+                }
+            }
+            Err(reason) => Err(reason),
+        };
+        let receive_progress = ReceiveProgress {
+            transfer_id,
+            peer_id: peer.as_ref().map(|p| p.id),
+            filename,
+            file_size,
+            size_unknown,
+            received: 0,
+            encrypted: sender_key.is_some(),
+            accept_rule: decision.rule,
+            relayed_from: relayed.clone(),
+            connectivity: context.connectivity,
+            checksum: None,
+            verified: false,
+            mime_type: content_type.map(str::to_string),
+            saved_path: None,
+            relative_path: None,
+            file_mode: None,
+            deduplicated: false,
+            samples: transfer.samples().clone(),
+            trace: transfer.trace().clone(),
+            pausing: transfer.pausing().clone(),
+            session_id: context.session_id,
+        };
+        let _permit = match permit {
+            Ok(permit) => permit,
+            Err(reason) => {
+                tracing::info!("Declined {} from {}: {}", receive_progress.filename, addr, reason);
+                let reject = TransferMessage::Reject {
+                    transfer_id,
+                    reason: Some(reason.clone()),
+                    protocol_version: None,
+                    max_file_size: None,
+                };
+                Self::write_message(stream, &reject).await?;
+                if transfer.is_cancelled() {
+                    if let Some(ws) = websocket {
+                        ws.notify_receive_cancelled(addr, receive_progress).await;
+                    }
+                    return Ok(ReceiveOutcome::CancelledLocally);
+                }
+                if let Some(ws) = websocket {
+                    ws.notify_receive_declined(addr, receive_progress).await;
+                }
+                return Ok(ReceiveOutcome::Refused(reason));
+            }
+        };
+        let admitted = match &receive_progress.accept_rule {
+            Some(rule) => format!("by rule {:?}", rule),
+            None => String::new(),
+        };
+        transfer.trace().record("admitted", admitted);
+
+        if let Some((checksum, sender_key, sender_identity)) = identical_to {
+            // Under organize_by_type, in the folder its type would have put it in
+            let existing = if *organize_by_type {
+                let sniffed_type = detected_mime_type.as_deref().or(extension_type.as_deref());
+                downloads_dir.join(organize::folder_for(&config.transfer, sniffed_type)).join(&receive_progress.filename)
+            } else {
+                downloads_dir.join(&receive_progress.filename)
+            };
+            let comparing = Self::holds_identical(&existing, file_size, checksum);
+            if Self::keeping_alive(stream, transfer_id, keepalive, comparing).await? {
+                let identity = peers.read().await.identity();
+                let held = Self::already_exists(transfer_id, request_line, file_size, checksum, sender_key, sender_identity, &identity)?;
+                tracing::info!(
+                    "Skipping {} from {}: an identical file is in downloads already",
+                    receive_progress.filename,
+                    addr
+                );
+                transfer.trace().record("already_exists", "");
+                Self::write_message(stream, &held).await?;
+                return Ok(ReceiveOutcome::Identical);
+            }
+            if *on_existing_file == ExistingFilePolicy::Reject
+                && !*organize_by_type
+                && downloads_dir.join(&receive_progress.filename).exists()
+            {
+                let reason = file_exists(&receive_progress.filename);
+                tracing::warn!("Refusing {} from {}: {}", receive_progress.filename, addr, reason);
+                let reject = TransferMessage::Reject {
+                    transfer_id,
+                    reason: Some(reason.clone()),
+                    protocol_version: None,
+                    max_file_size: None,
+                };
+                Self::write_message(stream, &reject).await?;
+                if let Some(ws) = websocket {
+                    ws.notify_receive_declined(addr, receive_progress).await;
+                }
+                return Ok(ReceiveOutcome::Refused(reason));
+            }
+        }
+        if let (Some(ws), None) = (websocket, requested) {
+            ws.notify_receive_started(addr, &receive_progress).await;
+        }
+
+        tokio::fs::create_dir_all(downloads_dir).await?;
+
+        let (part_path, mut file) = Self::create_partial(downloads_dir, &receive_progress.filename).await?;
+        transfer.writing_to(&part_path);
+
+        let progress = progress.insert(receive_progress);
+
+        let identity = peers.read().await.identity();
+        // Split only over connections straight from the sender, for a file
+        // it hashed up front and encrypts
+        let streams = if relayed.is_none() && sender_key.is_some() && !size_unknown && expected_checksum.is_some() {
+            parallel_streams.min(config.transfer.max_parallel_streams).max(1)
+        } else {
+            1
+        };
+        let window = Some(config.transfer.ack_window)
+            .filter(|window| *window > 0 && streams == 1 && protocol_version >= ProtocolVersion::WINDOWED);
+        let compressed = config.transfer.compress_chunks && compression.as_deref() == Some(compress::ZSTD);
+        // Only with a window, which bounds what's held while a chunk comes again
+        let chunk_checksums = config.transfer.chunk_checksums && chunk_checksums && window.is_some();
+        let handshake = Self::accept_handshake(
+            transfer_id,
+            protocol_version,
+            request_line,
+            window,
+            compressed,
+            chunk_checksums,
+            streams,
+            sender_key.as_deref(),
+            sender_identity.as_deref(),
+            &identity,
+        )?;
+        transfer
+            .trace()
+            .record("accepted", if handshake.cipher.is_some() { "encrypted" } else { "cleartext" });
+        // Waited on before the sender hears it may open them
+        let joining = (streams > 1).then(|| context.stream_joins.expect(transfer_id, addr.ip().to_canonical()));
+        let receiving = async {
+            match joining {
+                Some(joining) => {
+                    let expected_checksum = expected_checksum.as_deref();
+                    Self::receive_split(reader, stream, handshake, joining, &part_path, &mut file, context, expected_checksum, progress)
+                        .await
+                }
+                None => Self::receive_chunks(reader, stream, handshake, &mut file, context, expected_checksum.as_deref(), progress).await,
+            }
+        };
+        let result = tokio::select! {
+            result = receiving => result,
+            _ = transfer.cancelled() => Ok(ReceiveOutcome::CancelledLocally),
+        };
+        context.stream_joins.forget(&transfer_id);
+        drop(file);
+
+        match result {
+            Ok(ReceiveOutcome::Complete) => {
+                // Only once it's all here and checked is its content worth telling
+                let dir = if *organize_by_type {
+                    let folder = organize::folder_for_file(&config.transfer, &part_path, &progress.filename).await;
+                    tokio::fs::create_dir_all(downloads_dir.join(&folder)).await?;
+                    downloads_dir.join(folder)
+                } else {
+                    downloads_dir.clone()
+                };
+                let Some(file_path) = Self::claim_path(&dir, &progress.filename, *on_existing_file).await? else {
+                    let _ = tokio::fs::remove_file(&part_path).await;
+                    tracing::warn!("Discarding {} from {}: the name is taken", progress.filename, addr);
+                    let reason = file_exists(&progress.filename);
+                    return Err(Self::send_error(stream, transfer_id, CODE_FILE_EXISTS, reason).await);
+                };
+                let saved = match progress.checksum.clone().filter(|_| *content_addressed) {
+                    Some(checksum) => objects::store(downloads_dir, &part_path, &checksum, &file_path).await,
+                    None => tokio::fs::rename(&part_path, &file_path).await.map(|()| false),
+                };
+                progress.deduplicated = match saved {
+                    Ok(deduplicated) => deduplicated,
+                    Err(e) => {
+                        // Give back the name we held
+                        if *on_existing_file != ExistingFilePolicy::Overwrite {
+                            let _ = tokio::fs::remove_file(&file_path).await;
+                        }
+                        return Err(e.into());
+                    }
+                };
+                progress.file_mode = Self::apply_received_permissions(&config.transfer, &file_path);
+                progress.relative_path = file_path
+                    .strip_prefix(downloads_dir)
+                    .ok()
+                    .map(|path| path.to_string_lossy().to_string());
+                progress.saved_path = Some(file_path);
+                // Files we fetched ourselves aren't news
+                if let (Some(ws), None) = (websocket, requested) {
+                    ws.notify_received(addr, progress).await;
+                }
+                Ok(ReceiveOutcome::Complete)
+            }
+            Ok(ReceiveOutcome::CancelledLocally) if transfer.stalled().is_some() => {
+                let _ = tokio::fs::remove_file(&part_path).await;
+                let stalled = TransferError::Stalled {
+                    idle_secs: transfer.stalled().unwrap_or_default().as_secs(),
+                };
+                tracing::warn!("Gave up on {} from {}: {}", progress.filename, addr, stalled);
+                let error = TransferMessage::Error {
+                    transfer_id,
+                    message: stalled.to_string(),
+                    code: Some(stalled.code().to_string()),
+                };
+                let _ = Self::write_message(stream, &error).await;
+                Err(stalled.into())
+            }
+            Ok(ReceiveOutcome::CancelledLocally) => {
+                let cancel = TransferMessage::Cancel { transfer_id };
+                let _ = Self::write_message(stream, &cancel).await;
+                let _ = tokio::fs::remove_file(&part_path).await;
+                tracing::info!("Cancelled {} from {}", progress.filename, addr);
+                if let Some(ws) = websocket {
+                    ws.notify_receive_cancelled(addr, progress.clone()).await;
+                }
+                Ok(ReceiveOutcome::CancelledLocally)
+            }
+            Ok(ReceiveOutcome::Blocked(reason)) => {
+                let quarantined = Self::quarantine(&part_path, downloads_dir, transfer_id, &progress.filename).await?;
+                tracing::warn!(
+                    "Aborted {} from {}: {}; partial data quarantined at {}",
+                    progress.filename,
+                    addr,
+                    reason,
+                    quarantined.display()
+                );
+                Err(anyhow::anyhow!(reason))
+            }
+            Ok(outcome) => {
+                let _ = tokio::fs::remove_file(&part_path).await;
+                Ok(outcome)
+            }
+            Err(e) => {
+                if let Err(remove_err) = tokio::fs::remove_file(&part_path).await {
+                    tracing::warn!("Failed to remove partial file {}: {}", part_path.display(), remove_err);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Resolves "host" or "host:port", using `default_port` when none is given.
+    pub(crate) async fn resolve(address: &str, default_port: u16) -> Result<SocketAddr> {
+        let has_port = match address.rsplit_once(':') {
+            // A bare IPv6 address has colons but no port
+            Some((host, port)) => port.parse::<u16>().is_ok() && (!host.contains(':') || host.ends_with(']')),
+            None => false,
+        };
+        let resolved: Vec<SocketAddr> = if has_port {
+            tokio::net::lookup_host(address).await?.collect()
+        } else {
+            let host = address.trim_start_matches('[').trim_end_matches(']');
+            tokio::net::lookup_host((host, default_port)).await?.collect()
+        };
+        resolved
+            .into_iter()
+            .next()
+            .map(|address| SocketAddr::new(address.ip().to_canonical(), address.port()))
+            .ok_or_else(|| anyhow::anyhow!("{} didn't resolve to any address", address))
+    }
+
+    /// Why `needed` more bytes won't fit in `downloads_dir` while keeping
+    /// `margin` free, if they won't. A disk that can't be asked is given the
+    /// benefit of the doubt.
+    fn lacks_room(downloads_dir: &Path, needed: u64, margin: u64) -> Option<String> {
+        let free = utils::available_space(downloads_dir).ok()?.available;
+        (free < needed.saturating_add(margin)).then(|| {
+            format!(
+                "{}: {} needed, {} free",
+                INSUFFICIENT_DISK_SPACE,
+                utils::format_bytes(needed),
+                utils::format_bytes(free.saturating_sub(margin))
+            )
+        })
+    }
+
+    /// Answers a SpaceQuery, leaving out the space we keep free.
+    async fn report_space<W: AsyncWrite + Unpin>(stream: &mut W, context: &ConnectionContext) -> Result<()> {
+        let free_bytes = utils::available_space(&context.downloads_dir)
+            .map(|space| space.available.saturating_sub(context.config.transfer.free_space_margin))
+            .ok();
+        let report = TransferMessage::SpaceReport {
+            free_bytes,
+            max_file_size: context.config.transfer.max_receive_file_size,
+        };
+        Self::write_message(stream, &report).await
+    }
+
+    /// Shows an incoming file to connected clients and waits for one of them
+    /// to answer. Returns why the file wasn't accepted otherwise.
+    async fn ask_to_accept(
+        approvals: &ApprovalService,
+        websocket: Option<&WebSocketService>,
+        transfer: &ActiveTransfer,
+        prompt: ServerMessage,
+        approval_timeout: Duration,
+    ) -> std::result::Result<(), String> {
+        let transfer_id = transfer.id();
+        let Some(ws) = websocket else {
+            return Err("Nobody is available to approve the transfer".to_string());
+        };
+        if !ws.has_clients().await {
+            return Err("Nobody is available to approve the transfer".to_string());
+        }
+
+        let answer = approvals.open_prompt(transfer_id);
+        ws.broadcast(prompt).await;
+        let accepted = tokio::select! {
+            answer = timeout(approval_timeout, answer) => answer.ok().and_then(Result::ok),
+            _ = transfer.cancelled() => None,
+        };
+        if accepted.is_none() {
+            approvals.close_prompt(&transfer_id);
+        }
+        ws.broadcast(ServerMessage::IncomingTransferResolved {
+            transfer_id,
+            accepted: accepted == Some(true),
+        })
+        .await;
+
+        match accepted {
+            Some(true) => Ok(()),
+            Some(false) => Err("Declined by the recipient".to_string()),
+            None if transfer.is_cancelled() => Err(CANCELLED_BY_RECIPIENT.to_string()),
+            None => Err("Not approved in time".to_string()),
+        }
+    }
+
+    /// Why an incoming file is refused by the configured blocklists, if it is.
+    pub(crate) fn blocked_reason<'a>(
+        config: &TransferConfig,
+        filename: &str,
+        mime_types: impl IntoIterator<Item = Option<&'a str>>,
+    ) -> Option<String> {
+        if let Some(extension) = utils::matching_extension(filename, &config.blocked_extensions) {
+            return Some(format!("Files of type .{} are not accepted", extension));
+        }
+        mime_types
+            .into_iter()
+            .flatten()
+            .find(|mime_type| utils::mime_matches(mime_type, &config.blocked_mime_types))
+            .map(|mime_type| format!("Files of type {} are not accepted", mime_type))
+    }
+
+    /// Moves the partial data of a blocked file aside for inspection instead
+    /// of leaving it in downloads.
+    async fn quarantine(part_path: &Path, downloads_dir: &Path, transfer_id: Uuid, filename: &str) -> Result<PathBuf> {
+        let dir = downloads_dir.join(QUARANTINE_DIR);
+        tokio::fs::create_dir_all(&dir).await?;
+        let path = dir.join(format!("{}-{}", transfer_id, filename));
+        tokio::fs::rename(part_path, &path).await?;
+        Ok(path)
+    }
+
+    /// Reads the Error or Cancel a receiver sends when it gives up on a
+    /// transfer, if one arrives shortly.
+    pub(crate) async fn abort_reason<R: AsyncBufRead + Unpin>(reader: &mut R) -> Option<TransferError> {
+        let reading = async {
+            loop {
+                match Self::read_message(reader, MAX_CONTROL_MESSAGE_LEN).await.ok()? {
+                    TransferMessage::KeepAlive { .. } | TransferMessage::Ack { .. } => continue,
+                    message => return Self::abort_of(message),
+                }
+            }
+        };
+        timeout(ABORT_REASON_TIMEOUT, reading).await.ok()?
+    }
+
+    /// The error a receiver's `message` gives up with, if it is one.
+    pub(crate) fn abort_of(message: TransferMessage) -> Option<TransferError> {
+        match message {
+            TransferMessage::Error { message, code, .. } => Some(TransferError::AbortedByPeer { reason: message, code }),
+            TransferMessage::Cancel { .. } => Some(TransferError::AbortedByPeer {
+                reason: CANCELLED_BY_RECIPIENT.to_string(),
+                code: None,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the version a request was made with if we can't speak it. The
+    /// version is checked before the request is parsed, since a request from an
+    /// incompatible peer may not parse at all.
+    fn unsupported_version(raw: &serde_json::Value) -> Option<String> {
+        let request = raw.get("Request")?;
+        let version = match request.get("protocol_version") {
+            None | Some(serde_json::Value::Null) => ProtocolVersion::default(),
+            Some(value) => match value.as_str().map(str::parse::<ProtocolVersion>) {
+                Some(Ok(version)) => version,
+                _ => return Some(value.to_string()),
+            },
+        };
+        (!PROTOCOL_VERSION.accepts(&version)).then(|| version.to_string())
+    }
+
+    /// Checks the identity key a peer presented against the fingerprint pinned
+    /// for the peer at that address, or for the peer beyond the relay when
+    /// `relayed`. Returns the reason to refuse on mismatch.
+    async fn verify_peer_identity(
+        peers: &RwLock<PeerManager>,
+        addr: SocketAddr,
+        relayed: Option<&RelayedPeer>,
+        identity_key: Option<&str>,
+        websocket: Option<&WebSocketService>,
+    ) -> std::result::Result<(), String> {
+        let (result, changed) = {
+            let mut peer_manager = peers.write().await;
+            let peer = match relayed {
+                Some(relayed) => relayed.clone(),
+                None => match peer_manager.find_by_ip(addr.ip().to_canonical()) {
+                    Some(peer) => RelayedPeer {
+                        peer_id: peer.id,
+                        hostname: peer.hostname.clone(),
+                    },
+                    None => return Ok(()),
+                },
+            };
+
+            let Some(identity_key) = identity_key else {
+                return match peer_manager.pinned_fingerprint(&peer.peer_id) {
+                    Some(_) => Err(format!("{} did not present its identity key", peer.hostname)),
+                    None => Ok(()),
+                };
+            };
+
+            let fingerprint = identity::fingerprint_of_hex(identity_key).map_err(|e| e.to_string())?;
+            let changed = peer_manager
+                .check_identity(peer.peer_id, &fingerprint)
+                .map(|pinned| (peer.peer_id, peer.hostname.clone(), pinned, fingerprint.clone()));
+
+            let result = if peer_manager.pinned_fingerprint(&peer.peer_id) != Some(fingerprint.as_str()) {
+                Err(format!(
+                    "identity of {} changed to {}; accept the new fingerprint first",
+                    peer.hostname, fingerprint
+                ))
+            } else {
+                Ok(())
+            };
+            (result, changed)
+        };
+
+        // Not under the lock, which a slow client would otherwise hold up
+        if let (Some(ws), Some((peer_id, hostname, pinned, fingerprint))) = (websocket, changed) {
+            ws.notify_peer_identity_changed(peer_id, hostname, pinned, fingerprint).await;
+        }
+        result
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn accept_handshake(
+        transfer_id: Uuid,
+        sender_version: ProtocolVersion,
+        request_line: &str,
+        window: Option<u32>,
+        compressed: bool,
+        chunk_checksums: bool,
+        streams: u32,
+        sender_key: Option<&str>,
+        sender_identity: Option<&str>,
+        identity: &Identity,
+    ) -> Result<Handshake> {
+        let Some(sender_key) = sender_key else {
+            if sender_identity.is_some() {
+                anyhow::bail!(UNPROVEN_IDENTITY);
+            }
+            let accept = TransferMessage::Accept {
+                transfer_id,
+                public_key: None,
+                identity_key: None,
+                protocol_version: Some(PROTOCOL_VERSION),
+                window,
+                framed: sender_version >= ProtocolVersion::FRAMED,
+                compression: compressed.then(|| compress::ZSTD.to_string()),
+                chunk_checksums,
+                parallel_streams: streams,
+            };
+            return Ok(Handshake {
+                accept_line: serde_json::to_string(&accept)?,
+                cipher: None,
+                sealed: false,
+                keepalive: sender_version >= ProtocolVersion::KEEPALIVE,
+                window,
+                pausable: sender_version >= ProtocolVersion::PAUSABLE,
+                compressed,
+                chunk_checksums,
+                streams,
+            });
+        };
+
+        let exchange = KeyExchange::new();
+        let accept = TransferMessage::Accept {
+            transfer_id,
+            public_key: Some(exchange.public_key_hex()),
+            identity_key: sender_identity.map(|_| identity.public_key_hex()),
+            protocol_version: Some(PROTOCOL_VERSION),
+            window,
+            framed: sender_version >= ProtocolVersion::FRAMED,
+            compression: compressed.then(|| compress::ZSTD.to_string()),
+            chunk_checksums,
+            parallel_streams: streams,
+        };
+        let accept_line = serde_json::to_string(&accept)?;
+        let cipher = exchange.finish(
+            Role::Receiver,
+            sender_key,
+            sender_identity.map(|key| (identity.secret(), key)),
+            transfer_id,
+            &[request_line, &accept_line],
+        )?;
+        // The version is part of the transcript, so it can't be lowered to
+        // get out of the seal
+        Ok(Handshake {
+            accept_line,
+            cipher: Some(cipher),
+            sealed: sender_version >= ProtocolVersion::SEALED,
+            keepalive: sender_version >= ProtocolVersion::KEEPALIVE,
+            window,
+            pausable: sender_version >= ProtocolVersion::PAUSABLE,
+            compressed,
+            chunk_checksums,
+            streams,
+        })
+    }
+
+    /// AlreadyExists for `request_line`, vouching for the file of `file_size`
+    /// bytes and `checksum` we hold under the key it agrees with the sender.
+    #[allow(clippy::too_many_arguments)]
+    fn already_exists(
+        transfer_id: Uuid,
+        request_line: &str,
+        file_size: u64,
+        checksum: &str,
+        sender_key: &str,
+        sender_identity: &str,
+        identity: &Identity,
+    ) -> Result<TransferMessage> {
+        let exchange = KeyExchange::new();
+        let public_key = exchange.public_key_hex();
+        let cipher = exchange.finish(
+            Role::Receiver,
+            sender_key,
+            Some((identity.secret(), sender_identity)),
+            transfer_id,
+            &[request_line],
+        )?;
+        Ok(TransferMessage::AlreadyExists {
+            transfer_id,
+            public_key,
+            identity_key: identity.public_key_hex(),
+            proof: cipher.vouch_held(file_size, &hex::decode(checksum)?)?,
+        })
+    }
+
+    /// Streams chunks into `file` and reports how the transfer ended.
+    async fn receive_chunks<R, W>(
+        reader: &mut R,
+        stream: &mut W,
+        handshake: Handshake,
+        file: &mut File,
+        context: &ConnectionContext,
+        expected_checksum: Option<&str>,
+        receive_progress: &mut ReceiveProgress,
+    ) -> Result<ReceiveOutcome>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let config = &context.config.transfer;
+        let transfer_id = receive_progress.transfer_id;
+        let file_size = receive_progress.file_size;
+        let size_unknown = receive_progress.size_unknown;
+        // What may arrive: what was offered, or as much as we take at all
+        let size_limit = if size_unknown { config.max_receive_file_size } else { Some(file_size) };
+        let filename = receive_progress.filename.clone();
+        let Handshake {
+            accept_line,
+            cipher,
+            sealed,
+            keepalive,
+            window,
+            pausable,
+            compressed,
+            chunk_checksums,
+            streams: _,
+        } = handshake;
+        Self::write_raw_message(stream, &accept_line).await?;
+        // Acknowledging every half window keeps a sender that waits on us busy
+        let ack_every = window.map(|window| u64::from(window / 2).max(1));
+
+        let mut received_size = 0u64;
+        let mut chunk_index = 0u64;
+        let mut hasher = Sha256::new();
+        let mut progress = utils::ProgressTracker::new(file_size);
+        let max_message_len = Self::max_chunk_message_len(config.chunk_size);
+        // Leading bytes kept to check the real content type against the blocklist
+        let mut header = Vec::new();
+        let mut sniffed = config.blocked_mime_types.is_empty();
+        let mut keepalives = 0;
+        let pausing = receive_progress.pausing.clone();
+        let mut changes = pausing.watch();
+        let mut told = false;
+        let mut deadline = Instant::now() + SENDER_TIMEOUT;
+        let mut next_space_check = SPACE_RECHECK_BYTES;
+        // Chunks that came after a damaged one, until it has come again
+        let mut ahead = BTreeMap::new();
+        // How often each chunk still owed to us has arrived damaged
+        let mut damaged: HashMap<u64, u32> = HashMap::new();
+
+        let (seal, completed_checksum) = loop {
+            // A sender that can be told holds while we're paused, and
+            // sends nothing on purpose while it is
+            let held = *changes.borrow_and_update();
+            if pausable && held.here != told {
+                let message = if held.here {
+                    TransferMessage::Pause { transfer_id }
+                } else {
+                    TransferMessage::Resume { transfer_id }
+                };
+                Self::write_message(stream, &message).await?;
+                told = held.here;
+                deadline = Instant::now() + SENDER_TIMEOUT;
+            }
+            let idle = told || held.by_peer;
+            let chunk_msg = if let Some(data) = ahead.remove(&chunk_index) {
+                TransferMessage::Chunk {
+                    transfer_id,
+                    chunk_index,
+                    data,
+                    crc: None,
+                }
+            } else {
+                // Only reads once something has arrived, so a change here
+                // never cuts a message short
+                let arrived = tokio::select! {
+                    _ = reader.fill_buf() => true,
+                    _ = changes.changed() => false,
+                    _ = tokio::time::sleep_until(deadline), if !idle => {
+                        anyhow::bail!("Sender sent nothing for {}s", SENDER_TIMEOUT.as_secs());
+                    }
+                };
+                if !arrived {
+                    continue;
+                }
+                let chunk_msg = timeout(SENDER_TIMEOUT, Self::read_transfer_message(reader, max_message_len)).await??;
+                deadline = Instant::now() + SENDER_TIMEOUT;
+                chunk_msg
+            };
+
+            match chunk_msg {
+                TransferMessage::Chunk {
+                    transfer_id: tid,
+                    chunk_index: idx,
+                    data,
+                    crc: Some(crc),
+                } if tid == transfer_id && chunk_checksums && crc32fast::hash(&data) != crc => {
+                    keepalives = 0;
+                    // One we've already stored can only be a stray copy
+                    if idx >= chunk_index {
+                        let times = damaged.entry(idx).or_insert(0);
+                        *times += 1;
+                        if *times > MAX_CHUNK_RESENDS {
+                            let reason = format!("Chunk {} arrived damaged {} times", idx, times);
+                            return Err(Self::send_error(stream, transfer_id, CODE_CHUNK_DAMAGED, reason).await);
+                        }
+                        receive_progress.trace.record("chunk_damaged", format!("chunk {}, {} times", idx, times));
+                        let nack = TransferMessage::ChunkNack {
+                            transfer_id,
+                            chunk_index: idx,
+                        };
+                        Self::write_message(stream, &nack).await?;
+                    }
+                }
+                TransferMessage::Chunk {
+                    transfer_id: tid,
+                    chunk_index: idx,
+                    data,
+                    ..
+                } if tid == transfer_id && chunk_checksums && idx > chunk_index => {
+                    keepalives = 0;
+                    // The sender keeps within the window while a chunk is owed
+                    if ahead.len() >= window.map_or(0, |window| window as usize) {
+                        anyhow::bail!("Sender ran more than its window ahead of chunk {}", chunk_index);
+                    }
+                    ahead.insert(idx, data);
+                }
+                TransferMessage::Chunk {
+                    transfer_id: tid,
+                    chunk_index: idx,
+                    data,
+                    ..
+                } if tid == transfer_id && idx == chunk_index => {
+                    keepalives = 0;
+                    damaged.remove(&idx);
+                    // Not reading holds the sender back once the socket buffers fill
+                    context.bandwidth.download().acquire(data.len()).await;
+                    context.usage.record_received(receive_progress.peer_id, data.len());
+                    let data = match cipher.as_ref().map(|cipher| cipher.decrypt(idx, &data)) {
+                        Some(Ok(data)) => data,
+                        Some(Err(e)) => {
+                            let reason = format!("Chunk {} could not be decrypted: {}", idx, e);
+                            return Err(Self::send_error(stream, transfer_id, CODE_DECRYPT_FAILED, reason).await);
+                        }
+                        None => data,
+                    };
+                    let data = match compressed.then(|| compress::unpack(&data, config.chunk_size.max(MIN_CHUNK_LIMIT))) {
+                        Some(Ok(data)) => data,
+                        Some(Err(e)) => {
+                            let reason = format!("Chunk {} could not be decompressed: {}", idx, e);
+                            return Err(Self::send_error(stream, transfer_id, CODE_DECOMPRESS_FAILED, reason).await);
+                        }
+                        None => data,
+                    };
+                    if let Some(limit) = size_limit.filter(|limit| received_size + data.len() as u64 > *limit) {
+                        if size_unknown {
+                            return Err(Self::send_error(stream, transfer_id, CODE_FILE_TOO_LARGE, too_large(limit)).await);
+                        }
+                        let reason = format!("Received more than the {} bytes offered", limit);
+                        return Err(Self::send_error(stream, transfer_id, CODE_SIZE_MISMATCH, reason).await);
+                    }
+                    // A disk that stalls mustn't look to the sender like we've gone
+                    if let Err(e) = Self::keeping_alive(stream, transfer_id, keepalive, file.write_all(&data)).await? {
+                        return Err(Self::send_write_error(stream, transfer_id, e).await);
+                    }
+                    hasher.update(&data);
+                    received_size += data.len() as u64;
+                    chunk_index += 1;
+                    receive_progress.received = received_size;
+                    receive_progress.samples.record(received_size);
+                    receive_progress.trace.progress(chunk_index, received_size, file_size);
+                    progress.update(received_size);
+                    // Other writes to the disk may have taken the room this was
+                    // accepted with; one of unknown size must leave the margin
+                    if received_size >= next_space_check {
+                        next_space_check = received_size + SPACE_RECHECK_BYTES;
+                        let rest = file_size.saturating_sub(received_size);
+                        if let Some(reason) = Self::lacks_room(&context.downloads_dir, rest, config.free_space_margin) {
+                            return Err(Self::send_error(stream, transfer_id, CODE_DISK_FULL, reason).await);
+                        }
+                    }
+                    if ack_every.is_some_and(|every| chunk_index.is_multiple_of(every)) {
+                        let ack = TransferMessage::Ack {
+                            transfer_id,
+                            received_chunks: chunk_index,
+                        };
+                        Self::write_message(stream, &ack).await?;
+                    }
+
+                    if !sniffed {
+                        let wanted = utils::MIME_SNIFF_LEN as usize - header.len();
+                        header.extend_from_slice(&data[..data.len().min(wanted)]);
+                        if header.len() as u64 >= utils::MIME_SNIFF_LEN || received_size >= file_size {
+                            sniffed = true;
+                            let detected = utils::sniff_mime_type(&header);
+                            if let Some(reason) = Self::blocked_reason(config, "", [detected.as_deref()]) {
+                                let error = TransferMessage::Error {
+                                    transfer_id,
+                                    message: reason.clone(),
+                                    code: Some(CODE_BLOCKED.to_string()),
+                                };
+                                let _ = Self::write_message(stream, &error).await;
+                                return Ok(ReceiveOutcome::Blocked(reason));
+                            }
+                        }
+                    }
+                    
+                    // Log progress every 10MB
+                    if received_size.is_multiple_of(10 * 1024 * 1024) {
+                        tracing::debug!(
+                            "Receiving {}: {}/{} ({:.1}%) - {}, ETA {}s",
+                            filename,
+                            utils::format_bytes(received_size),
+                            utils::format_bytes(file_size),
+                            progress.percentage(),
+                            utils::format_speed(progress.smoothed_speed()),
+                            progress.eta_seconds().unwrap_or(0)
+                        );
+                    }
+                }
+                TransferMessage::Complete {
+                    transfer_id: tid,
+                    file_checksum,
+                    seal,
+                } if tid == transfer_id && damaged.is_empty() => {
+                    // A sender checking chunks waits to hear none is owed
+                    if chunk_checksums {
+                        let ack = TransferMessage::Ack {
+                            transfer_id,
+                            received_chunks: chunk_index,
+                        };
+                        Self::write_message(stream, &ack).await?;
+                    }
+                    break (seal, file_checksum);
+                }
+                TransferMessage::Cancel { transfer_id: tid } if tid == transfer_id => {
+                    tracing::info!("Transfer {} cancelled by sender", transfer_id);
+                    return Ok(ReceiveOutcome::CancelledBySender);
+                }
+                TransferMessage::KeepAlive { transfer_id: tid } if tid == transfer_id => {
+                    keepalives += 1;
+                    if keepalives > MAX_KEEPALIVES {
+                        anyhow::bail!("Sender sent nothing but keepalives for {} messages", MAX_KEEPALIVES);
+                    }
+                }
+                TransferMessage::Pause { transfer_id: tid } if tid == transfer_id => pausing.set_by_peer(true),
+                TransferMessage::Resume { transfer_id: tid } if tid == transfer_id => pausing.set_by_peer(false),
+                _ => {}
+            }
+        };
+        receive_progress.samples.finish(received_size);
+        receive_progress
+            .trace
+            .record("complete_received", format!("{} chunks, {} bytes", chunk_index, received_size));
+
+        // A sender that stops early still says Complete; only the whole file
+        // with the checksum it promised counts
+        if size_unknown {
+            receive_progress.file_size = received_size;
+        } else if received_size != file_size {
+            let reason = format!(
+                "Transfer ended after {} of {} bytes",
+                received_size, file_size
+            );
+            return Err(Self::send_error(stream, transfer_id, CODE_SIZE_MISMATCH, reason).await);
+        }
+        // Only a sender that couldn't hash up front gives the checksum at the end
+        let expected_checksum = expected_checksum.or(completed_checksum.as_deref().filter(|_| size_unknown));
+
+        let digest = hasher.finalize();
+        if let (Some(cipher), true) = (&cipher, sealed) {
+            let verified = match &seal {
+                Some(seal) => cipher.verify_seal(seal, chunk_index, received_size, &digest),
+                None => Err(anyhow::anyhow!("Transfer was not sealed")),
+            };
+            if let Err(e) = verified {
+                tracing::warn!("Discarding {}: {}", filename, e);
+                let reason = UNVERIFIED.to_string();
+                return Err(Self::send_error(stream, transfer_id, CODE_UNVERIFIED, reason).await);
+            }
+        }
+
+        let calculated_checksum = hex::encode(digest);
+        if let Some(expected) = expected_checksum.filter(|expected| *expected != calculated_checksum) {
+            tracing::warn!(
+                "Checksum mismatch for {}: expected {}, got {}",
+                filename,
+                expected,
+                calculated_checksum
+            );
+            let reason = CHECKSUM_MISMATCH.to_string();
+            return Err(Self::send_error(stream, transfer_id, CODE_CHECKSUM_MISMATCH, reason).await);
+        }
+
+        if let Err(e) = file.sync_all().await {
+            return Err(Self::send_write_error(stream, transfer_id, e).await);
+        }
+        receive_progress.checksum = Some(calculated_checksum);
+        receive_progress.verified = expected_checksum.is_some();
+        tracing::info!(
+            "File received: {} ({} bytes) - Checksum verified: {}",
+            filename,
+            received_size,
+            receive_progress.verified
+        );
+
+        Ok(ReceiveOutcome::Complete)
+    }
+
+    /// Tells the sender why we are discarding its transfer, and returns that
+    /// as the error to fail the transfer with.
+    pub(crate) async fn send_error<W: AsyncWrite + Unpin>(
+        stream: &mut W,
+        transfer_id: Uuid,
+        code: &str,
+        message: String,
+    ) -> anyhow::Error {
+        let error = TransferMessage::Error {
+            transfer_id,
+            message: message.clone(),
+            code: Some(code.to_string()),
+        };
+        let _ = Self::write_message(stream, &error).await;
+        anyhow::anyhow!(message)
+    }
+
+    /// Tells the sender we couldn't store what it sent, e.g. with the disk full.
+    pub(crate) async fn send_write_error<W: AsyncWrite + Unpin>(stream: &mut W, transfer_id: Uuid, error: std::io::Error) -> anyhow::Error {
+        let code = match error.kind() {
+            std::io::ErrorKind::StorageFull => CODE_DISK_FULL,
+            _ => CODE_WRITE_FAILED,
+        };
+        Self::send_error(stream, transfer_id, code, format!("Receiver could not write the file: {}", error)).await
+    }
+
+    /// Tells the receiver we are giving up on the transfer, and returns why:
+    /// it was cancelled, or the watchdog found it stalled.
+    pub(crate) async fn send_cancel<W: AsyncWrite + Unpin>(stream: &mut W, transfer: &ActiveTransfer) -> anyhow::Error {
+        let cancel = TransferMessage::Cancel {
+            transfer_id: transfer.id(),
+        };
+        let _ = Self::write_message(stream, &cancel).await;
+        match transfer.stalled() {
+            Some(idle) => TransferError::Stalled {
+                idle_secs: idle.as_secs(),
+            }
+            .into(),
+            None => TransferError::Cancelled.into(),
+        }
+    }
+
+    /// Sends a file to a peer as the transfer registered with `track_send`.
+    /// `on_checksum_progress` receives (bytes hashed, total) while the file is
+    /// checksummed before the transfer starts.
+    pub async fn send_file<F>(
+        &self,
+        transfer: &ActiveTransfer,
+        route: Route,
+        file_path: PathBuf,
+        on_checksum_progress: F,
+    ) -> Result<TransferOutcome>
+    where
+        F: FnMut(u64, u64),
+    {
+        // Checked before hashing, which alone can take minutes for a file
+        // this size
+        let file_size = tokio::fs::metadata(&file_path).await?.len();
+        self.check_send_queue()?;
+        self.check_space(route, file_size).await?;
+
+        let file_checksum = tokio::select! {
+            checksum = utils::calculate_file_checksum_with_progress(&file_path, on_checksum_progress) => checksum.ok(),
+            _ = transfer.cancelled() => return Err(TransferError::Cancelled.into()),
+        };
+        transfer.trace().record("checksummed", format!("{} bytes", file_size));
+
+        let (mut connection, context) = self.open_for(transfer, route).await?;
+        let context = ConnectionContext {
+            parallel_to: (context.relayed.is_none() && context.connectivity == Connectivity::Direct).then_some(connection.address),
+            ..context
+        };
+        Self::send_over(
+            &context,
+            &mut connection.reader,
+            &mut connection.writer,
+            transfer,
+            connection.address,
+            &file_path,
+            file_checksum,
+        )
+        .await
+    }
+
+    /// Sends `entries` along `route` as one zip archive offered as
+    /// `archive_name`. The archive is written as it goes out, so its size
+    /// and checksum are only known once it has all been sent.
+    pub async fn send_archive(
+        &self,
+        transfer: &ActiveTransfer,
+        route: Route,
+        entries: Vec<archive::Entry>,
+        archive_name: String,
+    ) -> Result<TransferOutcome> {
+        // The archive comes to about the size of what goes in it
+        let input_size = entries.iter().map(|entry| entry.size).sum();
+        self.check_send_queue()?;
+        self.check_space(route, input_size).await?;
+
+        let (mut connection, context) = self.open_for(transfer, route).await?;
+        let (reader, writer) = (&mut connection.reader, &mut connection.writer);
+        let outgoing = Outgoing::archive(archive_name);
+        let offer = Self::offer(&context, reader, writer, transfer, connection.address, outgoing).await?;
+        let _permit = Self::wait_for_send_slot(&context, writer, transfer, offer.keepalive).await?;
+        transfer.trace().record("send_slot", "");
+
+        let transfer_config = &context.config.transfer;
+        let chunks = archive::stream(entries, transfer_config.archive_compression, transfer_config.chunk_size);
+        Self::stream_chunks(&context, reader, writer, transfer, offer, ChunkSource::Archive(chunks)).await
+    }
+
+    /// Connects along `route` for `transfer`, unless it is cancelled first,
+    /// with the context to send over the connection in.
+    pub(crate) async fn open_for(&self, transfer: &ActiveTransfer, route: Route) -> Result<(Connection, ConnectionContext)> {
+        let connection = tokio::select! {
+            connection = self.open(route) => connection?,
+            _ = transfer.cancelled() => return Err(TransferError::Cancelled.into()),
+        };
+        transfer.trace().record(
+            "connected",
+            match &connection.relayed {
+                Some(destination) => format!("{} relaying to {}", connection.address, destination.hostname),
+                None if connection.connectivity == Connectivity::Punched => format!("{} punched through", connection.address),
+                None => connection.address.to_string(),
+            },
+        );
+        let context = ConnectionContext {
+            relayed: connection.relayed.clone(),
+            connectivity: connection.connectivity,
+            ..self.context()
+        };
+        Ok((connection, context))
+    }
+
+    /// Connects along `route`. Through a relay, this returns once the relay
+    /// has reached the destination. A peer that can't be reached directly is
+    /// tried through the rendezvous coordinator, when there is one.
+    pub(crate) async fn open(&self, route: Route) -> Result<Connection> {
+        let connection = match route {
+            Route::Peer(peer_id) => {
+                let address = match self.peers.read().await.get_peer(&peer_id) {
+                    Some(peer) => peer.address,
+                    None => return Err(anyhow::anyhow!("Peer not found")),
+                };
+                match (self.connect_peer(address).await, &self.config.rendezvous.coordinator) {
+                    (Ok(stream), _) => Self::connection(stream, address, Connectivity::Direct),
+                    (Err(e), Some(coordinator)) => {
+                        tracing::info!("Couldn't reach {} directly ({}), asking the rendezvous coordinator", address, e);
+                        self.open_through(coordinator, peer_id).await?
+                    }
+                    (Err(e), None) => return Err(e),
+                }
+            }
+            Route::Direct(address) => Self::connection(self.connect_peer(address).await?, address, Connectivity::Direct),
+            Route::Relay { relay, destination } => self.open_relayed(relay, destination).await?,
+        };
+        if let Route::Peer(peer_id) | Route::Relay { destination: peer_id, .. } = route {
+            self.peers.write().await.record_connectivity(&peer_id, connection.connectivity);
+        }
+        Ok(connection)
+    }
+
+    pub(crate) fn connection(stream: PeerStream, address: SocketAddr, connectivity: Connectivity) -> Connection {
+        let (read_half, writer) = stream.into_split();
+        Connection {
+            reader: BufReader::new(read_half),
+            writer,
+            address,
+            relayed: None,
+            connectivity,
+        }
+    }
+
+    /// Refuses up front when a peer has said it has no room for `file_size`
+    /// bytes; only asked for files past the space check threshold.
+    pub(crate) async fn check_space(&self, route: Route, file_size: u64) -> Result<()> {
+        if file_size < self.config.transfer.space_check_threshold {
+            return Ok(());
+        }
+        let peers = self.peers.read().await;
+        let known = match route {
+            Route::Peer(peer_id) | Route::Relay { destination: peer_id, .. } => peers.get_peer(&peer_id),
+            Route::Direct(address) => peers.find_by_ip(address.ip().to_canonical()),
+        };
+        // A peer we know of that doesn't list the query would only hang up on it
+        if known.is_some_and(|peer| !capability::supports(&peer.capabilities, capability::SPACE_QUERY)) {
+            return Ok(());
+        }
+        drop(peers);
+        match self.query_space(route).await {
+            Ok(PeerSpace {
+                max_file_size: Some(max_file_size),
+                ..
+            }) if file_size > max_file_size => {
+                return Err(TransferError::FileTooLarge { file_size, max_file_size }.into());
+            }
+            Ok(space) if !space.fits(file_size) => {
+                return Err(TransferError::InsufficientSpace { file_size, space }.into());
+            }
+            Ok(_) => {}
+            // Peers from before the query existed just hang up
+            Err(e) => tracing::debug!("Couldn't ask {:?} for its free space: {}", route, e),
+        }
+        Ok(())
+    }
+
+    /// Asks a peer how much it can receive.
+    pub async fn query_space(&self, route: Route) -> Result<PeerSpace> {
+        let Connection {
+            mut reader,
+            mut writer,
+            ..
+        } = self.open(route).await?;
+
+        Self::write_message(&mut writer, &TransferMessage::SpaceQuery).await?;
+        let response = timeout(Duration::from_secs(10), Self::read_message(&mut reader, MAX_CONTROL_MESSAGE_LEN)).await??;
+        match response {
+            TransferMessage::SpaceReport {
+                free_bytes,
+                max_file_size,
+            } => Ok(PeerSpace {
+                free_bytes,
+                max_file_size,
+            }),
+            _ => Err(anyhow::anyhow!("Unexpected response")),
+        }
+    }
+
+    /// Whether a known peer's transfer port takes a connection, and how
+    /// quickly. Peers that answer probes must answer one too; no data is
+    /// sent either way.
+    pub async fn check_peer(&self, peer_id: Uuid) -> PeerReachability {
+        let peer = self.peers.read().await.get_peer(&peer_id).cloned();
+        let mut result = PeerReachability {
+            peer_id,
+            hostname: peer.as_ref().map_or_else(|| peer_id.to_string(), |peer| peer.hostname.clone()),
+            address: None,
+            reachable: false,
+            connect_ms: None,
+            rtt_ms: None,
+            error: None,
+        };
+        let Some(peer) = peer else {
+            result.error = Some("Peer not found".to_string());
+            return result;
+        };
+
+        let started = Instant::now();
+        let stream = match self.connect_peer(peer.address).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                result.error = Some(e.to_string());
+                return result;
+            }
+        };
+        result.connect_ms = Some(started.elapsed().as_secs_f64() * 1000.0);
+        result.address = stream.peer_addr().ok().map(|addr| addr.to_string());
+        if !capability::supports(&peer.capabilities, capability::PROBE) {
+            result.reachable = true;
+            return result;
+        }
+
+        let (read_half, mut writer) = stream.into_split();
+        let mut reader = BufReader::new(read_half);
+        let asked = Instant::now();
+        let answer = async {
+            Self::write_message(&mut writer, &TransferMessage::Probe).await?;
+            Self::read_message(&mut reader, MAX_CONTROL_MESSAGE_LEN).await
+        };
+        match timeout(PROBE_TIMEOUT, answer).await {
+            Ok(Ok(TransferMessage::ProbeAck)) => {
+                result.rtt_ms = Some(asked.elapsed().as_secs_f64() * 1000.0);
+                result.reachable = true;
+            }
+            Ok(Ok(_)) => result.error = Some("Unexpected answer to a probe".to_string()),
+            // A peer that doesn't know us hangs up without a word
+            Ok(Err(_)) => result.error = Some("Connected, but the peer hung up; it may not accept us".to_string()),
+            Err(_) => result.error = Some(format!("Connected, but no answer within {}s", PROBE_TIMEOUT.as_secs())),
+        }
+        result
+    }
+
+    /// Offers `file_path` on an open connection and streams it if accepted;
+    /// used both for files we push and for files a peer fetches from our share.
+    pub(crate) async fn send_over<R, W>(
+        context: &ConnectionContext,
+        reader: &mut R,
+        stream: &mut W,
+        transfer: &ActiveTransfer,
+        peer_address: SocketAddr,
+        file_path: &Path,
+        file_checksum: Option<String>,
+    ) -> Result<TransferOutcome>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let outgoing = Outgoing::file(&context.config, file_path, file_checksum).await?;
+        let offer = Self::offer(context, reader, stream, transfer, peer_address, outgoing).await?;
+        if offer.identical {
+            return Ok(Self::identical_outcome(transfer, offer, context.connectivity));
+        }
+
+        // Hashing and the handshake above don't count against the limit, only
+        // moving data does
+        let _permit = Self::wait_for_send_slot(context, stream, transfer, offer.keepalive).await?;
+        transfer.trace().record("send_slot", "");
+
+        if let (true, Some(address)) = (offer.streams > 1, context.parallel_to) {
+            return Self::stream_split(context, reader, stream, transfer, offer, file_path, address).await;
+        }
+        let source = ChunkSource::File(File::open(file_path).await?);
+        Self::stream_chunks(context, reader, stream, transfer, offer, source).await
+    }
+
+    /// Sends the Request for `outgoing` and waits for the receiver to take it.
+    pub(crate) async fn offer<R, W>(
+        context: &ConnectionContext,
+        reader: &mut R,
+        stream: &mut W,
+        transfer: &ActiveTransfer,
+        peer_address: SocketAddr,
+        outgoing: Outgoing,
+    ) -> Result<Offer>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let transfer_id = transfer.id();
+        let Outgoing {
+            filename,
+            shared_path,
+            file_size,
+            file_checksum,
+            mime_type,
+            detected_mime_type,
+        } = outgoing;
+        let size_unknown = file_size.is_none();
+        let file_size = file_size.unwrap_or(0);
+
+        if let Some(budget) = context.usage.exhausted_budget() {
+            return Err(TransferError::DailyBudgetExhausted { budget }.into());
+        }
+        let (identity, encrypt, takes_unsized, peer_id, seal_to) = {
+            let peers = context.peers.read().await;
+            let known = match &context.relayed {
+                Some(relayed) => peers.get_peer(&relayed.peer_id),
+                None => peers.find_by_ip(peer_address.ip().to_canonical()),
+            };
+            // Peers we know nothing of are offered a key; they ignore it if they can't use it
+            let encrypt = known.is_none_or(|peer| capability::supports(&peer.capabilities, capability::ENCRYPTION));
+            let takes_unsized = known.is_none_or(|peer| capability::supports(&peer.capabilities, capability::UNSIZED));
+            let peer_id = context.relayed.as_ref().map(|relayed| relayed.peer_id).or(known.map(|peer| peer.id));
+            let seal_to = known
+                .filter(|peer| capability::supports(&peer.capabilities, capability::SEALED))
+                .and_then(|peer| peer.identity_key.clone());
+            (peers.identity(), encrypt, takes_unsized, peer_id, seal_to)
+        };
+        if !encrypt && context.config.transfer.require_encryption {
+            return Err(anyhow::anyhow!("Peer does not support encryption"));
+        }
+        if size_unknown && !takes_unsized {
+            return Err(anyhow::anyhow!("Peer is too old to receive archives"));
+        }
+        let exchange = encrypt.then(KeyExchange::new);
+        let public_key = exchange.as_ref().map(KeyExchange::public_key_hex);
+        // Split only what can be sealed as a whole, over connections we open
+        // straight to the peer
+        let streams = match (context.parallel_to, &file_checksum) {
+            (Some(_), Some(_)) if encrypt && !size_unknown => {
+                let transfer_config = &context.config.transfer;
+                parallel::split(file_size, transfer_config.chunk_size, transfer_config.parallel_streams).len() as u32
+            }
+            _ => 1,
+        };
+        let mac = context.config.transfer.shared_secret.as_deref().map(|secret| {
+            crypto::request_mac(secret, transfer_id, &filename, file_size, file_checksum.as_deref(), public_key.as_deref())
+        });
+        // A receiver's word that it has the file is only worth anything from
+        // a peer we know, under keys
+        let skip_identical = exchange.is_some()
+            && peer_id.is_some()
+            && file_checksum.is_some()
+            && !size_unknown
+            && !context.config.transfer.resend_identical;
+        let details = OfferDetails {
+            filename: filename.clone(),
+            file_path: shared_path.clone(),
+            file_size,
+            file_checksum: file_checksum.clone(),
+            mime_type,
+            detected_mime_type,
+        };
+        // Sealed only along with keys, which the receiver proves its
+        // identity under before anything is sent
+        let sealed = match seal_to.filter(|_| exchange.is_some()) {
+            Some(recipient) => Some(crypto::seal_for(&recipient, transfer_id, &serde_json::to_vec(&details)?)?),
+            None => None,
+        };
+        let details = if sealed.is_some() { OfferDetails::default() } else { details };
+        let request = TransferMessage::Request {
+            protocol_version: PROTOCOL_VERSION,
+            transfer_id,
+            filename: details.filename,
+            file_path: details.file_path,
+            file_size: details.file_size,
+            file_checksum: details.file_checksum,
+            mime_type: details.mime_type,
+            detected_mime_type: details.detected_mime_type,
+            public_key,
+            identity_key: Some(identity.public_key_hex()),
+            size_unknown,
+            compression: context.config.transfer.compress_chunks.then(|| compress::ZSTD.to_string()),
+            mac,
+            chunk_checksums: context.config.transfer.chunk_checksums,
+            parallel_streams: streams,
+            skip_identical,
+            sealed: sealed.clone(),
+        };
+        let request_line = serde_json::to_string(&request)?;
+        Self::write_raw_message(stream, &request_line).await?;
+        let size = if size_unknown { "size unknown".to_string() } else { format!("{} bytes", file_size) };
+        transfer
+            .trace()
+            .record("request_sent", format!("{} ({}), protocol {}", shared_path, size, PROTOCOL_VERSION));
+
+        // A receiver waiting on a person or a slot keeps us waiting with
+        // KeepAlives, which aren't part of the handshake
+        let mut keepalives = 0;
+        let (response_line, response) = loop {
+            let line = tokio::select! {
+                line = timeout(Duration::from_secs(30), Self::read_raw_message(reader, MAX_CONTROL_MESSAGE_LEN)) => line??,
+                _ = transfer.cancelled() => return Err(Self::send_cancel(stream, transfer).await),
+            };
+            match serde_json::from_str(&line)? {
+                TransferMessage::KeepAlive { .. } if keepalives < MAX_KEEPALIVES => keepalives += 1,
+                TransferMessage::KeepAlive { .. } => {
+                    let _ = Self::send_cancel(stream, transfer).await;
+                    anyhow::bail!("Receiver kept the transfer waiting without answering");
+                }
+                // The session's file before, saved after we stopped waiting to hear
+                TransferMessage::Saved { .. } => {}
+                response => break (line, response),
+            }
+        };
+        let keepalive;
+        let window;
+        let framed;
+        let pausable;
+        let compressed;
+        let chunk_checksums;
+        let accepted_streams;
+
+        let cipher: Option<ChunkCipher> = match response {
+            TransferMessage::Accept {
+                transfer_id: tid,
+                public_key,
+                identity_key,
+                protocol_version,
+                window: accepted_window,
+                framed: accepted_framed,
+                compression,
+                chunk_checksums: accepted_checksums,
+                parallel_streams,
+            } => {
+                if tid != transfer_id {
+                    return Err(anyhow::anyhow!("Transfer ID mismatch"));
+                }
+                keepalive = protocol_version.is_some_and(|version| version >= ProtocolVersion::KEEPALIVE);
+                pausable = protocol_version.is_some_and(|version| version >= ProtocolVersion::PAUSABLE);
+                window = accepted_window.filter(|window| *window > 0);
+                framed = accepted_framed;
+                // Only what we offered
+                compressed = context.config.transfer.compress_chunks && compression.as_deref() == Some(compress::ZSTD);
+                chunk_checksums = context.config.transfer.chunk_checksums && accepted_checksums && window.is_some();
+                accepted_streams = parallel_streams.clamp(1, streams);
+                let websocket = context.websocket.as_deref();
+                let verified = if identity_key.is_some() && public_key.is_none() {
+                    Err(UNPROVEN_IDENTITY.to_string())
+                } else {
+                    Self::verify_peer_identity(&context.peers, peer_address, context.relayed.as_ref(), identity_key.as_deref(), websocket)
+                        .await
+                };
+                if let Err(reason) = verified {
+                    let cancel = TransferMessage::Cancel { transfer_id };
+                    let _ = Self::write_message(stream, &cancel).await;
+                    return Err(anyhow::anyhow!("Refusing to send: {}", reason));
+                }
+                match public_key.zip(exchange) {
+                    Some((key, exchange)) => Some(exchange.finish(
+                        Role::Sender,
+                        &key,
+                        identity_key.as_deref().map(|peer_identity| (identity.secret(), peer_identity)),
+                        transfer_id,
+                        &[&request_line, &response_line],
+                    )?),
+                    None if context.config.transfer.require_encryption => {
+                        let cancel = TransferMessage::Cancel { transfer_id };
+                        let _ = Self::write_message(stream, &cancel).await;
+                        return Err(anyhow::anyhow!("Peer does not support encryption"));
+                    }
+                    None => {
+                        tracing::warn!("Peer {} does not support encryption, sending {} in cleartext", peer_address, filename);
+                        None
+                    }
+                }
+            }
+            TransferMessage::AlreadyExists {
+                transfer_id: tid,
+                public_key,
+                identity_key,
+                proof,
+            } if tid == transfer_id && skip_identical => {
+                // Only the receiver whose identity we pinned can vouch for
+                // having the file, under the key it agreed with us
+                let websocket = context.websocket.as_deref();
+                let pinned = match peer_id {
+                    Some(peer_id) => {
+                        let verified =
+                            Self::verify_peer_identity(&context.peers, peer_address, context.relayed.as_ref(), Some(&identity_key), websocket)
+                                .await;
+                        let fingerprint = identity::fingerprint_of_hex(&identity_key).ok();
+                        verified.is_ok() && context.peers.read().await.pinned_fingerprint(&peer_id) == fingerprint.as_deref()
+                    }
+                    None => false,
+                };
+                let vouched = match (pinned, exchange, file_checksum.as_deref()) {
+                    (true, Some(exchange), Some(checksum)) => exchange
+                        .finish(Role::Sender, &public_key, Some((identity.secret(), &identity_key)), transfer_id, &[&request_line])
+                        .and_then(|cipher| cipher.verify_held(&proof, file_size, &hex::decode(checksum)?)),
+                    _ => Err(anyhow::anyhow!("not from a peer whose identity is pinned")),
+                };
+                if let Err(e) = vouched {
+                    let cancel = TransferMessage::Cancel { transfer_id };
+                    let _ = Self::write_message(stream, &cancel).await;
+                    return Err(anyhow::anyhow!("Receiver says it has {} already, unproven: {}", filename, e));
+                }
+                transfer.trace().record("already_exists", "");
+                return Ok(Offer {
+                    peer_id,
+                    filename,
+                    file_size,
+                    size_unknown,
+                    file_checksum,
+                    cipher: None,
+                    keepalive: false,
+                    window: None,
+                    framed: false,
+                    pausable: false,
+                    compressed: false,
+                    chunk_checksums: false,
+                    streams: 1,
+                    identical: true,
+                    sealed: sealed.is_some(),
+                });
+            }
+            TransferMessage::Reject {
+                protocol_version: Some(theirs),
+                ..
+            } if !PROTOCOL_VERSION.accepts(&theirs) => {
+                return Err(TransferError::IncompatibleProtocol {
+                    theirs,
+                    ours: PROTOCOL_VERSION,
+                }
+                .into());
+            }
+            TransferMessage::Reject {
+                max_file_size: Some(max_file_size),
+                ..
+            } => {
+                return Err(TransferError::FileTooLarge { file_size, max_file_size }.into());
+            }
+            TransferMessage::Reject {
+                reason: Some(reason), ..
+            } if reason.starts_with(INSUFFICIENT_DISK_SPACE) => {
+                return Err(TransferError::PeerOutOfSpace { reason }.into());
+            }
+            TransferMessage::Reject { reason, .. } => {
+                return Err(anyhow::anyhow!(
+                    "Transfer rejected by peer: {}",
+                    reason.unwrap_or_else(|| "No reason provided".to_string())
+                ));
+            }
+            _ => {
+                return Err(anyhow::anyhow!("Unexpected response"));
+            }
+        };
+
+        transfer
+            .trace()
+            .record("accepted", if cipher.is_some() { "encrypted" } else { "cleartext" });
+        Ok(Offer {
+            peer_id,
+            filename,
+            file_size,
+            size_unknown,
+            file_checksum,
+            keepalive,
+            window,
+            framed,
+            pausable,
+            compressed,
+            chunk_checksums,
+            // Never in cleartext: the cipher is what ties the ranges together
+            streams: if cipher.is_some() { accepted_streams } else { 1 },
+            cipher,
+            identical: false,
+            sealed: sealed.is_some(),
+        })
+    }
+
+    /// What a send the receiver had no need of did: nothing went, and the
+    /// receiver matched our checksum against its own copy.
+    pub(crate) fn identical_outcome(transfer: &ActiveTransfer, offer: Offer, connectivity: Connectivity) -> TransferOutcome {
+        tracing::info!("Not sending {}: the peer has an identical file already", offer.filename);
+        TransferOutcome {
+            transfer_id: transfer.id(),
+            bytes_sent: 0,
+            duration: Duration::ZERO,
+            average_speed: 0,
+            checksum: offer.file_checksum,
+            peer_ack: true,
+            encrypted: false,
+            connectivity,
+            deduplicated: true,
+        }
+    }
+
+    /// Reads `source` on a task of its own, up to `READ_AHEAD_CHUNKS` ahead of
+    /// the socket, so the disk and the network are busy at once. The task
+    /// hashes what it reads and returns the SHA-256; it stops after a read
+    /// error, which is passed on, or once the chunks stop being taken.
+    fn read_ahead(mut source: ChunkSource, chunk_size: usize) -> (mpsc::Receiver<ReadChunk>, tokio::task::JoinHandle<Vec<u8>>) {
+        let (tx, rx) = mpsc::channel(READ_AHEAD_CHUNKS);
+        let task = tokio::spawn(async move {
+            let mut hasher = Sha256::new();
+            let mut read = 0u64;
+            loop {
+                match source.next_chunk(chunk_size, read).await {
+                    Ok(Some(chunk)) => {
+                        hasher.update(chunk.as_slice());
+                        read += chunk.len() as u64;
+                        if tx.send(Ok(chunk)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = tx.send(Err(e)).await;
+                        break;
+                    }
+                }
+            }
+            hasher.finalize().to_vec()
+        });
+        (rx, task)
+    }
+
+    /// Streams an accepted offer's chunks from `source`, then completes it.
+    pub(crate) async fn stream_chunks<R, W>(
+        context: &ConnectionContext,
+        reader: &mut R,
+        stream: &mut W,
+        transfer: &ActiveTransfer,
+        offer: Offer,
+        source: ChunkSource,
+    ) -> Result<TransferOutcome>
+    where
+        R: AsyncBufRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let Offer {
+            peer_id,
+            filename,
+            file_size,
+            size_unknown,
+            file_checksum,
+            cipher,
+            keepalive,
+            window,
+            framed,
+            pausable,
+            compressed,
+            chunk_checksums,
+            streams: _,
+            identical: _,
+            sealed,
+        } = offer;
+        let transfer_id = transfer.id();
+        let chunk_size = context.config.transfer.chunk_size;
+        let stall_timeout = Duration::from_secs(context.config.transfer.send_stall_timeout);
+        let mut chunk_index = 0u64;
+        let mut sent_size = 0u64;
+        let mut replies = Replies::default();
+        // Chunks as sent that the receiver may yet find damaged; the window
+        // keeps them few
+        let mut unacked = VecDeque::new();
+        let mut resends = HashMap::new();
+        let mut progress = utils::ProgressTracker::new(file_size);
+        let (mut chunks, read_ahead) = Self::read_ahead(source, chunk_size);
+
+        let streamed: Result<()> = async {
+            loop {
+                // Only between chunks, so the receiver never sees half a message
+                Self::hold_while_paused(reader, stream, transfer, &mut replies, pausable, keepalive)
+                    .await
+                    .map_err(|e| Self::peer_gone(e, sent_size))?;
+                if transfer.is_stopping() {
+                    return Err(Self::send_cancel(stream, transfer).await);
+                }
+                if !replies.damaged.is_empty() {
+                    let resent = Self::resend_damaged(reader, stream, &unacked, &mut replies, &mut resends, stall_timeout)
+                        .await
+                        .map_err(|e| Self::peer_gone(e, sent_size))?;
+                    context.usage.record_sent(peer_id, resent);
+                    continue;
+                }
+                if let Some(window) = window {
+                    Self::wait_for_ack(reader, stream, transfer, &mut replies, chunk_index, window, stall_timeout)
+                        .await
+                        .map_err(|e| Self::peer_gone(e, sent_size))?;
+                    // It paused before taking what we'd sent, or wants a
+                    // chunk again
+                    if replies.paused || !replies.damaged.is_empty() {
+                        continue;
+                    }
+                }
+                // A slow disk or a tight speed limit mustn't look to the
+                // receiver like we've gone
+                let next = async {
+                    let plain = match chunks.recv().await? {
+                        Ok(plain) => plain,
+                        Err(e) => return Some(Err(e)),
+                    };
+                    let packed = compressed.then(|| compress::pack(&plain));
+                    let len = packed.as_ref().map_or(plain.len(), Vec::len);
+                    context.bandwidth.upload().acquire(len).await;
+                    Some(Ok((plain, packed)))
+                };
+                let kept_alive = Self::keeping_alive(stream, transfer_id, keepalive, next).await;
+                let (plain, packed) = match kept_alive.map_err(|e| Self::peer_gone(e, sent_size))? {
+                    Some(chunk) => chunk?,
+                    None => break,
+                };
+                let n = plain.len();
+
+                let data = match (&cipher, packed) {
+                    (Some(cipher), packed) => cipher.encrypt(chunk_index, packed.as_deref().unwrap_or(&plain))?,
+                    (None, Some(packed)) => packed,
+                    (None, None) => plain.to_vec(),
+                };
+                let wire_bytes = data.len();
+                let crc = chunk_checksums.then(|| crc32fast::hash(&data));
+                let chunk = Self::encode_chunk(transfer_id, chunk_index, data, crc, framed)?;
+
+                Self::write_chunk(reader, stream, &chunk, &mut replies, stall_timeout)
+                    .await
+                    .map_err(|e| Self::peer_gone(e, sent_size))?;
+                if chunk_checksums {
+                    unacked.push_back((chunk_index, chunk));
+                    while unacked.front().is_some_and(|(index, _)| *index < replies.acked) {
+                        unacked.pop_front();
+                    }
+                }
+
+                context.usage.record_sent(peer_id, wire_bytes);
+                sent_size += n as u64;
+                chunk_index += 1;
+                progress.update(sent_size);
+                transfer.samples().record(sent_size);
+                transfer.trace().progress(chunk_index, sent_size, file_size);
+            
+                // Log progress every 10MB
+                if sent_size.is_multiple_of(10 * 1024 * 1024) {
+                    tracing::debug!(
+                        "Sending {}: {}/{} ({:.1}%) - {}, ETA {}s",
+                        filename,
+                        utils::format_bytes(sent_size),
+                        utils::format_bytes(file_size),
+                        progress.percentage(),
+                        utils::format_speed(progress.smoothed_speed()),
+                        progress.eta_seconds().unwrap_or(0)
+                    );
+                }
+            }
+            Ok(())
+        }
+        .await;
+        if let Err(e) = streamed {
+            read_ahead.abort();
+            return Err(e);
+        }
+
+        transfer.samples().finish(sent_size);
+        // The reader has finished: it closed the channel
+        let digest = read_ahead.await?;
+        let checksum = hex::encode(&digest);
+        // Without a size up front, the receiver checks what it got against
+        // the checksum we send now
+        let file_checksum = file_checksum.or_else(|| size_unknown.then(|| checksum.clone()));
+        let peer_ack = file_checksum.as_deref() == Some(checksum.as_str());
+        // A sealed request told the receiver the checksum already; an
+        // archive's goes in the clear, as nothing else carries it
+        let file_checksum = file_checksum.filter(|_| size_unknown || !sealed);
+        let seal = match &cipher {
+            Some(cipher) => Some(cipher.seal(chunk_index, sent_size, &digest)?),
+            None => None,
+        };
+        let complete = TransferMessage::Complete {
+            transfer_id,
+            file_checksum,
+            seal,
+        };
+        loop {
+            if !replies.damaged.is_empty() {
+                let resent = Self::resend_damaged(reader, stream, &unacked, &mut replies, &mut resends, stall_timeout)
+                    .await
+                    .map_err(|e| Self::peer_gone(e, sent_size))?;
+                context.usage.record_sent(peer_id, resent);
+            }
+            Self::write_with_liveness(stream, &complete, stall_timeout)
+                .await
+                .map_err(|e| Self::peer_gone(e, sent_size))?;
+            transfer
+                .trace()
+                .record("complete_sent", format!("{} chunks, {} bytes", chunk_index, sent_size));
+            if !chunk_checksums {
+                break;
+            }
+            // The last chunks may yet be found damaged: a receiver checking
+            // them acknowledges the lot once it has them all, and ignores a
+            // Complete while one is owed
+            while replies.acked < chunk_index && replies.damaged.is_empty() {
+                replies.paused = false;
+                Self::wait_for_ack(reader, stream, transfer, &mut replies, chunk_index, 1, stall_timeout)
+                    .await
+                    .map_err(|e| Self::peer_gone(e, sent_size))?;
+            }
+            if replies.damaged.is_empty() {
+                break;
+            }
+        }
+
+        // A receiver that discards the file says so before closing; one that
+        // keeps it just closes
+        if let Some(aborted) = Self::abort_reason(reader).await {
+            return Err(aborted.into());
+        }
+
+        tracing::info!(
+            "File sent: {} ({} bytes) in {:.2}s - {}",
+            filename,
+            sent_size,
+            progress.elapsed().as_secs_f64(),
+            utils::format_speed(progress.average_speed())
+        );
+
+        Ok(TransferOutcome {
+            transfer_id,
+            bytes_sent: sent_size,
+            duration: progress.elapsed(),
+            average_speed: progress.average_speed(),
+            checksum: Some(checksum),
+            peer_ack,
+            encrypted: cipher.is_some(),
+            connectivity: context.connectivity,
+            deduplicated: false,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::config::AcceptRule;
+    use futures_util::future::join_all;
+    use crate::identity::TrustStore;
+    use tokio::io::{AsyncRead, DuplexStream, ReadHalf, WriteHalf};
+    use tokio::task::JoinHandle;
+
+    pub(crate) const CHUNK: usize = 1000;
+
+    pub(crate) fn peer_address() -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], 40000))
+    }
+
+    /// An empty directory of its own for one test.
+    pub(crate) fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("p2p-sharing-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    pub(crate) fn service(config: AppConfig, dir: &Path) -> TransferService {
+        let peers = PeerManager::new(Arc::new(Identity::generate()), TrustStore::load(dir).unwrap(), "test".to_string());
+        TransferService::new(Arc::new(config), Arc::new(RwLock::new(peers)), dir.to_path_buf(), CancellationToken::new())
+    }
+
+    pub(crate) fn context(service: &TransferService, dir: &Path) -> ConnectionContext {
+        ConnectionContext {
+            downloads_dir: dir.join("downloads"),
+            ..service.context()
+        }
+    }
+
+    pub(crate) fn downloaded_files(dir: &Path) -> Vec<String> {
+        std::fs::read_dir(dir.join("downloads"))
+            .map(|entries| {
+                entries
+                    .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The other end of a connection, speaking the protocol by hand.
+    pub(crate) struct FakePeer {
+        pub(crate) reader: BufReader<ReadHalf<DuplexStream>>,
+        pub(crate) writer: WriteHalf<DuplexStream>,
+    }
+
+    impl FakePeer {
+        async fn send(&mut self, message: &TransferMessage) {
+            TransferService::write_message(&mut self.writer, message).await.unwrap();
+        }
+
+        pub(crate) async fn recv(&mut self) -> TransferMessage {
+            TransferService::read_transfer_message(&mut self.reader, MAX_CONTROL_MESSAGE_LEN).await.unwrap()
+        }
+
+        async fn send_chunks(&mut self, transfer_id: Uuid, data: &[u8]) {
+            for (index, chunk) in data.chunks(CHUNK).enumerate() {
+                let chunk = TransferMessage::Chunk {
+                    transfer_id,
+                    chunk_index: index as u64,
+                    data: chunk.to_vec(),
+                    crc: None,
+                };
+                self.send(&chunk).await;
+            }
+        }
+
+        /// Sends `data` encrypted and returns how many chunks that took.
+        async fn send_encrypted_chunks(&mut self, transfer_id: Uuid, data: &[u8], cipher: &ChunkCipher) -> u64 {
+            let mut chunks = 0;
+            for (index, chunk) in data.chunks(CHUNK).enumerate() {
+                let chunk = TransferMessage::Chunk {
+                    transfer_id,
+                    chunk_index: index as u64,
+                    data: cipher.encrypt(index as u64, chunk).unwrap(),
+                    crc: None,
+                };
+                self.send(&chunk).await;
+                chunks += 1;
+            }
+            chunks
+        }
+
+        async fn complete(&mut self, transfer_id: Uuid, seal: Option<Vec<u8>>) {
+            let complete = TransferMessage::Complete {
+                transfer_id,
+                file_checksum: None,
+                seal,
+            };
+            self.send(&complete).await;
+        }
+    }
+
+    /// Our end of a connection as a (reader, writer) pair, and the peer at the other end.
+    pub(crate) fn connection() -> (BufReader<ReadHalf<DuplexStream>>, WriteHalf<DuplexStream>, FakePeer) {
+        let (ours, theirs) = tokio::io::duplex(1024 * 1024);
+        let (our_reader, our_writer) = tokio::io::split(ours);
+        let (their_reader, their_writer) = tokio::io::split(theirs);
+        let peer = FakePeer {
+            reader: BufReader::new(their_reader),
+            writer: their_writer,
+        };
+        (BufReader::new(our_reader), our_writer, peer)
+    }
+
+    pub(crate) fn request(transfer_id: Uuid, filename: &str, data: &[u8]) -> TransferMessage {
+        TransferMessage::Request {
+            protocol_version: PROTOCOL_VERSION,
+            transfer_id,
+            filename: filename.to_string(),
+            file_path: filename.to_string(),
+            file_size: data.len() as u64,
+            file_checksum: Some(hex::encode(Sha256::digest(data))),
+            mime_type: None,
+            detected_mime_type: None,
+            public_key: None,
+            identity_key: None,
+            size_unknown: false,
+            compression: None,
+            chunk_checksums: false,
+            parallel_streams: 0,
+            skip_identical: false,
+            mac: None,
+            sealed: None,
+        }
+    }
+
+    /// Starts receiving the file `request` offers, as if it had just arrived
+    /// on a new connection.
+    fn receive(context: ConnectionContext, request: &TransferMessage) -> (JoinHandle<Result<ReceiveOutcome>>, FakePeer) {
+        receive_line(context, serde_json::to_string(request).unwrap())
+    }
+
+    fn receive_line(context: ConnectionContext, request_line: String) -> (JoinHandle<Result<ReceiveOutcome>>, FakePeer) {
+        let (mut reader, mut writer, peer) = connection();
+        let receiving = tokio::spawn(async move {
+            let mut progress = None;
+            TransferService::receive_file(&mut reader, &mut writer, peer_address(), &context, &request_line, None, &mut progress)
+                .await
+        });
+        (receiving, peer)
+    }
+
+    pub(crate) fn sample_data() -> Vec<u8> {
+        (0..4 * CHUNK).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Yields `x` forever without a newline, counting what it hands out.
+    struct Endless {
+        served: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl AsyncRead for Endless {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            let n = buf.remaining();
+            buf.put_slice(&vec![b'x'; n]);
+            self.served.fetch_add(n, std::sync::atomic::Ordering::Relaxed);
+            std::task::Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn endless_line_is_cut_off_at_the_limit() {
+        let served = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut reader = BufReader::new(Endless { served: served.clone() });
+
+        let result = timeout(
+            Duration::from_secs(5),
+            TransferService::read_message(&mut reader, MAX_CONTROL_MESSAGE_LEN),
+        )
+        .await
+        .expect("gave up promptly");
+
+        let error = result.unwrap_err().to_string();
+        assert_eq!(error, format!("Protocol error: message exceeds {} bytes", MAX_CONTROL_MESSAGE_LEN));
+        // No more than the limit plus one buffer's worth was ever read
+        let served = served.load(std::sync::atomic::Ordering::Relaxed);
+        assert!(served <= MAX_CONTROL_MESSAGE_LEN + 1 + 8192, "read {} bytes", served);
+    }
+
+    #[tokio::test]
+    async fn line_at_the_limit_is_read() {
+        let message = serde_json::to_string(&TransferMessage::ShareError {
+            message: "x".repeat(100),
+        })
+        .unwrap();
+        let input = format!("{}\n", message);
+        let mut reader = BufReader::new(input.as_bytes());
+        assert!(TransferService::read_message(&mut reader, message.len() + 1).await.is_ok());
+
+        let mut reader = BufReader::new(input.as_bytes());
+        assert!(TransferService::read_message(&mut reader, message.len() - 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn closed_connection_is_an_error() {
+        let mut reader = BufReader::new(&b""[..]);
+        let error = TransferService::read_message(&mut reader, MAX_CONTROL_MESSAGE_LEN).await.unwrap_err();
+        assert_eq!(error.to_string(), "Connection closed by peer");
+    }
+
+    /// Sends `path` over a fresh connection, returning the task and the
+    /// receiving peer.
+    fn send(context: ConnectionContext, transfer: ActiveTransfer, path: PathBuf) -> (JoinHandle<Result<TransferOutcome>>, FakePeer) {
+        let (mut reader, mut writer, peer) = connection();
+        let sending = tokio::spawn(async move {
+            let checksum = utils::calculate_file_checksum(&path).await.ok();
+            TransferService::send_over(&context, &mut reader, &mut writer, &transfer, peer_address(), &path, checksum).await
+        });
+        (sending, peer)
+    }
+
+    /// Plays a receiver that accepts in cleartext and takes everything.
+    /// Returns the bytes received.
+    async fn accept_all(peer: &mut FakePeer) -> Vec<u8> {
+        let TransferMessage::Request { transfer_id, .. } = peer.recv().await else {
+            panic!("expected a request");
+        };
+        peer.send(&TransferMessage::Accept {
+            transfer_id,
+            public_key: None,
+            identity_key: None,
+            protocol_version: None,
+            window: None,
+            framed: false,
+            compression: None,
+            chunk_checksums: false,
+            parallel_streams: 0,
+        })
+        .await;
+        let mut received = Vec::new();
+        loop {
+            match peer.recv().await {
+                TransferMessage::Chunk { data, .. } => received.extend(data),
+                TransferMessage::Complete { .. } => return received,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn keepalives_from_the_receiver_keep_a_send_going() {
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        config.transfer.chunk_size = CHUNK;
+        let service = service(config, &dir);
+        let data = sample_data();
+        let path = dir.join("outgoing.bin");
+        std::fs::write(&path, &data).unwrap();
+        let (sending, mut receiver) = send(context(&service, &dir), service.track_send(Uuid::new_v4()), path);
+
+        // Waiting on someone to approve it, then on a slow disk
+        let TransferMessage::Request { transfer_id, .. } = receiver.recv().await else {
+            panic!("expected a request");
+        };
+        for _ in 0..3 {
+            receiver.send(&TransferMessage::KeepAlive { transfer_id }).await;
+        }
+        receiver
+            .send(&TransferMessage::Accept {
+                transfer_id,
+                public_key: None,
+                identity_key: None,
+                protocol_version: Some(PROTOCOL_VERSION),
+                window: None,
+                framed: false,
+                compression: None,
+                chunk_checksums: false,
+                parallel_streams: 0,
+            })
+            .await;
+        let mut received = Vec::new();
+        loop {
+            match receiver.recv().await {
+                TransferMessage::Chunk { data, .. } => {
+                    if received.is_empty() {
+                        receiver.send(&TransferMessage::KeepAlive { transfer_id }).await;
+                    }
+                    received.extend(data);
+                }
+                TransferMessage::Complete { .. } => break,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(received, data);
+        drop(receiver);
+        assert!(sending.await.unwrap().is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn sender_waits_for_acks_once_a_window_is_out() {
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        config.transfer.chunk_size = CHUNK;
+        let service = service(config, &dir);
+        let data = sample_data();
+        let path = dir.join("outgoing.bin");
+        std::fs::write(&path, &data).unwrap();
+        let (sending, mut receiver) = send(context(&service, &dir), service.track_send(Uuid::new_v4()), path);
+
+        let TransferMessage::Request { transfer_id, .. } = receiver.recv().await else {
+            panic!("expected a request");
+        };
+        receiver
+            .send(&TransferMessage::Accept {
+                transfer_id,
+                public_key: None,
+                identity_key: None,
+                protocol_version: Some(PROTOCOL_VERSION),
+                window: Some(2),
+                framed: false,
+                compression: None,
+                chunk_checksums: false,
+                parallel_streams: 0,
+            })
+            .await;
+        let mut received = Vec::new();
+        for _ in 0..2 {
+            let TransferMessage::Chunk { data, .. } = receiver.recv().await else {
+                panic!("expected a chunk");
+            };
+            received.extend(data);
+        }
+        assert!(timeout(Duration::from_millis(200), receiver.recv()).await.is_err(), "sent past the window");
+
+        // Each Ack lets one more chunk out
+        let mut acked = 1;
+        loop {
+            receiver
+                .send(&TransferMessage::Ack {
+                    transfer_id,
+                    received_chunks: acked,
+                })
+                .await;
+            acked += 1;
+            match receiver.recv().await {
+                TransferMessage::Chunk { data, .. } => received.extend(data),
+                TransferMessage::Complete { .. } => break,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(received, data);
+        drop(receiver);
+        assert!(sending.await.unwrap().is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    pub(crate) fn limited_config(limit: usize) -> AppConfig {
+        let mut config = AppConfig::default();
+        config.transfer.chunk_size = CHUNK;
+        config.transfer.max_concurrent_sends = Some(limit);
+        config.transfer.max_concurrent_receives = Some(limit);
+        config
+    }
+
+    #[tokio::test]
+    async fn full_receive_slots_do_not_hold_up_sends() {
+        let dir = scratch_dir();
+        let service = service(limited_config(2), &dir);
+        let _receiving = service.receive_slots.acquire_many(2).await.unwrap();
+        assert_eq!(service.receive_slot_usage().active, 2);
+
+        let data = sample_data();
+        let path = dir.join("outgoing.bin");
+        std::fs::write(&path, &data).unwrap();
+        let (sending, mut receiver) = send(context(&service, &dir), service.track_send(Uuid::new_v4()), path);
+
+        let received = timeout(Duration::from_secs(5), accept_all(&mut receiver)).await.expect("send went ahead");
+        assert_eq!(received, data);
+        drop(receiver);
+        assert!(sending.await.unwrap().is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn full_send_slots_do_not_hold_up_receives() {
+        let dir = scratch_dir();
+        let service = service(limited_config(2), &dir);
+        let _sending = service.send_slots.acquire_many(2).await.unwrap();
+        assert_eq!(service.send_slot_usage().active, 2);
+
+        let data = sample_data();
+        let transfer_id = Uuid::new_v4();
+        let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, "incoming.bin", &data));
+        let accepted = timeout(Duration::from_secs(5), sender.recv()).await.expect("receive went ahead");
+        assert!(matches!(accepted, TransferMessage::Accept { .. }));
+        sender.send_chunks(transfer_id, &data).await;
+        sender
+            .send(&TransferMessage::Complete {
+                transfer_id,
+                file_checksum: None,
+                seal: None,
+            })
+            .await;
+
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Complete)));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn files_over_the_receive_limit_are_refused_with_the_limit() {
+        let dir = scratch_dir();
+        let sender = service(limited_config(1), &dir);
+        let mut config = limited_config(1);
+        config.transfer.max_receive_file_size = Some(CHUNK as u64);
+        let receiver = service(config, &dir);
+        let path = dir.join("outgoing.bin");
+        std::fs::write(&path, sample_data()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let receiving = {
+            let context = context(&receiver, &dir);
+            tokio::spawn(async move {
+                let (stream, from) = listener.accept().await.unwrap();
+                TransferService::handle_connection(stream.into(), from, context, &mut None).await
+            })
+        };
+        let transfer = sender.track_send(Uuid::new_v4());
+        let error = sender.send_file(&transfer, Route::Direct(address), path, |_, _| {}).await.err().unwrap();
+        let _ = receiving.await;
+
+        assert_eq!(TransferError::code_of(&error).as_deref(), Some("file_too_large"));
+        let space = TransferError::peer_space_of(&error).unwrap();
+        assert_eq!(space.max_file_size, Some(CHUNK as u64));
+        assert!(downloaded_files(&dir).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn files_of_unknown_size_are_cut_off_at_the_receive_limit() {
+        let dir = scratch_dir();
+        let mut config = limited_config(1);
+        config.transfer.max_receive_file_size = Some(CHUNK as u64 + CHUNK as u64 / 2);
+        let service = service(config, &dir);
+        let transfer_id = Uuid::new_v4();
+        // Claims nothing, then keeps sending
+        let mut offer = request(transfer_id, "endless.zip", b"");
+        if let TransferMessage::Request { size_unknown, file_checksum, .. } = &mut offer {
+            *size_unknown = true;
+            *file_checksum = None;
+        }
+        let (receiving, mut sender) = receive(context(&service, &dir), &offer);
+        assert!(matches!(sender.recv().await, TransferMessage::Accept { .. }));
+        sender.send_chunks(transfer_id, &sample_data()).await;
+
+        let TransferMessage::Error { code, message, .. } = sender.recv().await else {
+            panic!("expected an error");
+        };
+        assert_eq!(code.as_deref(), Some(CODE_FILE_TOO_LARGE));
+        assert_eq!(message, too_large(CHUNK as u64 + CHUNK as u64 / 2));
+        assert!(receiving.await.unwrap().is_err());
+        assert!(downloaded_files(&dir).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn full_send_slots_queue_further_sends() {
+        let dir = scratch_dir();
+        let service = service(limited_config(1), &dir);
+        let held = service.send_slots.acquire().await.unwrap();
+
+        let path = dir.join("outgoing.bin");
+        std::fs::write(&path, sample_data()).unwrap();
+        let (sending, mut receiver) = send(context(&service, &dir), service.track_send(Uuid::new_v4()), path);
+        let TransferMessage::Request { transfer_id, .. } = receiver.recv().await else {
+            panic!("expected a request");
+        };
+        receiver
+            .send(&TransferMessage::Accept {
+                transfer_id,
+                public_key: None,
+                identity_key: None,
+                protocol_version: None,
+                window: None,
+                framed: false,
+                compression: None,
+                chunk_checksums: false,
+                parallel_streams: 0,
+            })
+            .await;
+
+        // Accepted, but no data moves until a slot frees up
+        let waiting = timeout(Duration::from_millis(200), receiver.recv()).await;
+        assert!(waiting.is_err(), "sent without a slot");
+        drop(held);
+        assert!(matches!(receiver.recv().await, TransferMessage::Chunk { .. }));
+        drop(receiver);
+        let _ = sending.await;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn sends_past_a_full_queue_are_refused_up_front() {
+        let dir = scratch_dir();
+        let mut config = limited_config(1);
+        config.transfer.max_queued_sends = 1;
+        let service = service(config, &dir);
+        let _held = service.send_slots.acquire().await.unwrap();
+        let in_line = {
+            let slots = service.send_slots.clone();
+            tokio::spawn(async move { slots.acquire_in_turn(Uuid::new_v4(), 1, |_| {}).await })
+        };
+        while service.send_slots.waiting() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let path = dir.join("outgoing.bin");
+        std::fs::write(&path, sample_data()).unwrap();
+        // Nothing listens there: it's refused before anything is dialled
+        let nowhere = Route::Direct("127.0.0.1:9".parse().unwrap());
+        let transfer = service.track_send(Uuid::new_v4());
+        let error = service.send_file(&transfer, nowhere, path, |_, _| {}).await.err().unwrap();
+        assert_eq!(TransferError::code_of(&error).as_deref(), Some("send_queue_full"));
+        in_line.abort();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// A request as a peer speaking `version` would send it; None leaves the
+    /// field out, as peers from before versioning did.
+    fn request_line_as(version: Option<&str>, transfer_id: Uuid, data: &[u8]) -> String {
+        let mut request = serde_json::to_value(request(transfer_id, "notes.txt", data)).unwrap();
+        let fields = request["Request"].as_object_mut().unwrap();
+        match version {
+            Some(version) => fields.insert("protocol_version".to_string(), version.into()),
+            None => fields.remove("protocol_version"),
+        };
+        request.to_string()
+    }
+
+    #[test]
+    fn version_policy_accepts_the_same_major() {
+        let version = |s: &str| s.parse::<ProtocolVersion>().unwrap();
+        assert!(PROTOCOL_VERSION.accepts(&version("1.0")));
+        assert!(PROTOCOL_VERSION.accepts(&version("1.99")));
+        assert!(!PROTOCOL_VERSION.accepts(&version("2.0")));
+        assert!(!PROTOCOL_VERSION.accepts(&version("0.9")));
+
+        let unsupported = |raw: &str| TransferService::unsupported_version(&serde_json::from_str(raw).unwrap());
+        assert_eq!(unsupported(r#"{"Request":{}}"#), None);
+        assert_eq!(unsupported(r#"{"Request":{"protocol_version":null}}"#), None);
+        assert_eq!(unsupported(r#"{"Request":{"protocol_version":"1.7"}}"#), None);
+        assert_eq!(unsupported(r#"{"Request":{"protocol_version":"2.0"}}"#), Some("2.0".to_string()));
+        assert_eq!(unsupported(r#"{"Request":{"protocol_version":"two"}}"#), Some("\"two\"".to_string()));
+        // Not a request at all: left to the request parser
+        assert_eq!(unsupported(r#"{"SpaceQuery":null}"#), None);
+    }
+
+    #[tokio::test]
+    async fn newer_major_version_is_rejected_with_ours() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let transfer_id = Uuid::new_v4();
+
+        let line = request_line_as(Some("2.0"), transfer_id, b"hello");
+        let (receiving, mut sender) = receive_line(context(&service, &dir), line);
+        match timeout(Duration::from_secs(5), sender.recv()).await.expect("answered instead of timing out") {
+            TransferMessage::Reject {
+                transfer_id: tid,
+                reason,
+                protocol_version,
+                ..
+            } => {
+                assert_eq!(tid, transfer_id);
+                assert_eq!(reason.unwrap(), format!("unsupported protocol version 2.0, I speak {}", PROTOCOL_VERSION));
+                assert_eq!(protocol_version, Some(PROTOCOL_VERSION));
+            }
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Refused(_))));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn request_without_a_version_is_accepted() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let transfer_id = Uuid::new_v4();
+
+        let line = request_line_as(None, transfer_id, b"hello");
+        let (receiving, mut sender) = receive_line(context(&service, &dir), line);
+        assert!(matches!(sender.recv().await, TransferMessage::Accept { .. }));
+        sender.send_chunks(transfer_id, b"hello").await;
+        sender
+            .send(&TransferMessage::Complete {
+                transfer_id,
+                file_checksum: None,
+                seal: None,
+            })
+            .await;
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Complete)));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn sender_reports_an_incompatible_receiver() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let path = dir.join("outgoing.bin");
+        std::fs::write(&path, b"hello").unwrap();
+
+        let (sending, mut receiver) = send(context(&service, &dir), service.track_send(Uuid::new_v4()), path);
+        let TransferMessage::Request { transfer_id, .. } = receiver.recv().await else {
+            panic!("expected a request");
+        };
+        let newer = ProtocolVersion { major: 2, minor: 1 };
+        receiver
+            .send(&TransferMessage::Reject {
+                transfer_id,
+                reason: Some(format!("unsupported protocol version {}, I speak {}", PROTOCOL_VERSION, newer)),
+                protocol_version: Some(newer),
+                max_file_size: None,
+            })
+            .await;
+
+        let error = sending.await.unwrap().err().expect("send failed");
+        assert_eq!(TransferError::code_of(&error).as_deref(), Some("incompatible_protocol_version"));
+        assert_eq!(
+            error.to_string(),
+            format!("Peer speaks transfer protocol 2.1, which is incompatible with ours ({})", PROTOCOL_VERSION)
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn sender_passes_on_a_plain_rejection() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let path = dir.join("outgoing.bin");
+        std::fs::write(&path, b"hello").unwrap();
+
+        let (sending, mut receiver) = send(context(&service, &dir), service.track_send(Uuid::new_v4()), path);
+        let TransferMessage::Request { transfer_id, .. } = receiver.recv().await else {
+            panic!("expected a request");
+        };
+        // An older receiver names no version when it refuses
+        receiver
+            .send(&TransferMessage::Reject {
+                transfer_id,
+                reason: Some("Busy".to_string()),
+                protocol_version: None,
+                max_file_size: None,
+            })
+            .await;
+
+        let error = sending.await.unwrap().err().expect("send failed");
+        assert_eq!(TransferError::code_of(&error), None);
+        assert_eq!(error.to_string(), "Transfer rejected by peer: Busy");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn blocking(extensions: &[&str], mime_types: &[&str]) -> TransferConfig {
+        let mut config = AppConfig::default().transfer;
+        config.blocked_extensions = extensions.iter().map(|e| e.to_string()).collect();
+        config.blocked_mime_types = mime_types.iter().map(|m| m.to_string()).collect();
+        config
+    }
+
+    #[test]
+    fn blocked_extensions_ignore_case_and_catch_double_extensions() {
+        let config = blocking(&["exe", ".BAT", "tar.gz"], &[]);
+        let cases = [
+            ("setup.exe", Some("exe")),
+            ("SETUP.EXE", Some("exe")),
+            ("Setup.Exe", Some("exe")),
+            ("invoice.pdf.exe", Some("exe")),
+            ("invoice.exe.pdf", Some("exe")),
+            ("run.bat", Some("bat")),
+            ("backup.TAR.GZ", Some("tar.gz")),
+            ("invoice.pdf", None),
+            ("exe", None),
+            ("README", None),
+            ("Makefile", None),
+            // A leading dot marks a hidden file, not an extension
+            (".exe", None),
+            (".hidden.exe", Some("exe")),
+            ("archive.gz", None),
+        ];
+        for (filename, blocked) in cases {
+            let expected = blocked.map(|extension| format!("Files of type .{} are not accepted", extension));
+            assert_eq!(TransferService::blocked_reason(&config, filename, []), expected, "{}", filename);
+        }
+    }
+
+    #[test]
+    fn blocked_mime_types_match_exactly_or_by_family() {
+        let config = blocking(&[], &["application/x-msdownload", "VIDEO/*"]);
+        let cases = [
+            (Some("application/x-msdownload"), true),
+            (Some("Application/X-MSDownload"), true),
+            (Some("video/mp4"), true),
+            (Some("application/pdf"), false),
+            (Some("videos/mp4"), false),
+            (None, false),
+        ];
+        for (mime_type, blocked) in cases {
+            assert_eq!(
+                TransferService::blocked_reason(&config, "file", [mime_type]).is_some(),
+                blocked,
+                "{:?}",
+                mime_type
+            );
+        }
+        // Any one of the claimed types is enough
+        let reason = TransferService::blocked_reason(&config, "file", [Some("text/plain"), None, Some("video/webm")]);
+        assert_eq!(reason.as_deref(), Some("Files of type video/webm are not accepted"));
+    }
+
+    #[tokio::test]
+    async fn blocked_extension_is_rejected_before_any_data() {
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        config.transfer.blocked_extensions = vec!["exe".to_string()];
+        let service = service(config, &dir);
+
+        for filename in ["TOOL.EXE", "invoice.pdf.exe"] {
+            let transfer_id = Uuid::new_v4();
+            let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, filename, b"MZ"));
+            match sender.recv().await {
+                TransferMessage::Reject { reason, .. } => {
+                    assert_eq!(reason.as_deref(), Some("Files of type .exe are not accepted"), "{}", filename)
+                }
+                other => panic!("expected a rejection of {}, got {:?}", filename, other),
+            }
+            assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Refused(_))));
+        }
+        assert!(downloaded_files(&dir).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn blocked_content_is_quarantined() {
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        config.transfer.blocked_mime_types = vec!["application/x-executable".to_string()];
+        let service = service(config, &dir);
+
+        // An ELF binary dressed up as text
+        let mut data = b"\x7fELF\x02\x01\x01\x00".to_vec();
+        data.resize(3 * CHUNK, 0);
+        let transfer_id = Uuid::new_v4();
+        let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, "notes.txt", &data));
+        assert!(matches!(sender.recv().await, TransferMessage::Accept { .. }));
+        sender.send_chunks(transfer_id, &data).await;
+
+        match sender.recv().await {
+            TransferMessage::Error { message, .. } => {
+                assert_eq!(message, "Files of type application/x-executable are not accepted")
+            }
+            other => panic!("expected an error, got {:?}", other),
+        }
+        assert!(receiving.await.unwrap().is_err());
+        assert_eq!(downloaded_files(&dir), vec![QUARANTINE_DIR.to_string()]);
+        let quarantined = std::fs::read_dir(dir.join("downloads").join(QUARANTINE_DIR)).unwrap().count();
+        assert_eq!(quarantined, 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn sender_vanishing_mid_stream_leaves_no_file() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let data = sample_data();
+        let transfer_id = Uuid::new_v4();
+
+        let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, "report.bin", &data));
+        assert!(matches!(sender.recv().await, TransferMessage::Accept { .. }));
+        sender.send_chunks(transfer_id, &data[..2 * CHUNK]).await;
+        drop(sender);
+
+        let result = timeout(Duration::from_secs(5), receiving).await.unwrap().unwrap();
+        assert!(result.is_err());
+        assert!(downloaded_files(&dir).is_empty(), "left behind: {:?}", downloaded_files(&dir));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn complete_before_all_data_is_refused() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let data = sample_data();
+        let transfer_id = Uuid::new_v4();
+
+        let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, "report.bin", &data));
+        sender.recv().await;
+        sender.send_chunks(transfer_id, &data[..2 * CHUNK]).await;
+        sender
+            .send(&TransferMessage::Complete {
+                transfer_id,
+                file_checksum: None,
+                seal: None,
+            })
+            .await;
+
+        match sender.recv().await {
+            TransferMessage::Error { message, .. } => assert_eq!(message, "Transfer ended after 2000 of 4000 bytes"),
+            other => panic!("expected an error, got {:?}", other),
+        }
+        assert!(receiving.await.unwrap().is_err());
+        assert!(downloaded_files(&dir).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn corrupted_data_is_refused() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let data = sample_data();
+        let transfer_id = Uuid::new_v4();
+
+        let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, "report.bin", &data));
+        sender.recv().await;
+        let mut corrupted = data.clone();
+        corrupted[CHUNK + 7] ^= 0xff;
+        sender.send_chunks(transfer_id, &corrupted).await;
+        sender
+            .send(&TransferMessage::Complete {
+                transfer_id,
+                file_checksum: None,
+                seal: None,
+            })
+            .await;
+
+        assert!(matches!(sender.recv().await, TransferMessage::Error { message, .. } if message == "Checksum mismatch"));
+        assert!(receiving.await.unwrap().is_err());
+        assert!(downloaded_files(&dir).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn complete_file_is_kept() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let data = sample_data();
+        let transfer_id = Uuid::new_v4();
+
+        let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, "report.bin", &data));
+        sender.recv().await;
+        sender.send_chunks(transfer_id, &data).await;
+        sender
+            .send(&TransferMessage::Complete {
+                transfer_id,
+                file_checksum: None,
+                seal: None,
+            })
+            .await;
+
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Complete)));
+        assert_eq!(downloaded_files(&dir), vec!["report.bin".to_string()]);
+        assert_eq!(std::fs::read(dir.join("downloads/report.bin")).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Offers `data` as an encrypted transfer from a sender speaking
+    /// `version`. Returns the receiving task, the sender, and the cipher the
+    /// receiver's Accept settled on.
+    async fn encrypted_offer(
+        context: ConnectionContext,
+        transfer_id: Uuid,
+        data: &[u8],
+        version: &str,
+    ) -> (JoinHandle<Result<ReceiveOutcome>>, FakePeer, ChunkCipher) {
+        let exchange = KeyExchange::new();
+        let mut request = serde_json::to_value(request(transfer_id, "secret.bin", data)).unwrap();
+        let fields = request["Request"].as_object_mut().unwrap();
+        fields.insert("protocol_version".to_string(), version.into());
+        fields.insert("public_key".to_string(), exchange.public_key_hex().into());
+        let request_line = request.to_string();
+
+        let (receiving, mut sender) = receive_line(context, request_line.clone());
+        let accept_line = TransferService::read_raw_message(&mut sender.reader, MAX_CONTROL_MESSAGE_LEN).await.unwrap();
+        let TransferMessage::Accept {
+            public_key: Some(key), ..
+        } = serde_json::from_str(&accept_line).unwrap()
+        else {
+            panic!("expected an encrypted accept, got {}", accept_line);
+        };
+        let cipher = exchange
+            .finish(Role::Sender, &key, None, transfer_id, &[&request_line, &accept_line])
+            .unwrap();
+        (receiving, sender, cipher)
+    }
+
+    #[tokio::test]
+    async fn sealed_transfer_is_kept() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let data = sample_data();
+        let transfer_id = Uuid::new_v4();
+
+        let (receiving, mut sender, cipher) =
+            encrypted_offer(context(&service, &dir), transfer_id, &data, &PROTOCOL_VERSION.to_string()).await;
+        let chunks = sender.send_encrypted_chunks(transfer_id, &data, &cipher).await;
+        let seal = cipher.seal(chunks, data.len() as u64, &Sha256::digest(&data)).unwrap();
+        sender.complete(transfer_id, Some(seal)).await;
+
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Complete)));
+        assert_eq!(std::fs::read(dir.join("downloads/secret.bin")).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn missing_or_forged_seal_is_refused() {
+        let data = sample_data();
+        let digest = Sha256::digest(&data);
+        let forged = |cipher: &ChunkCipher, chunks: u64| -> Vec<Option<Vec<u8>>> {
+            let mut flipped = cipher.seal(chunks, data.len() as u64, &digest).unwrap();
+            flipped[0] ^= 1;
+            vec![
+                None,
+                Some(flipped),
+                // Vouches for fewer chunks, or a different length or checksum
+                Some(cipher.seal(chunks - 1, data.len() as u64, &digest).unwrap()),
+                Some(cipher.seal(chunks, data.len() as u64 - 1, &digest).unwrap()),
+                Some(cipher.seal(chunks, data.len() as u64, &Sha256::digest(b"other")).unwrap()),
+                // A chunk's ciphertext passed off as the seal
+                Some(cipher.encrypt(chunks, &[0; 40]).unwrap()),
+            ]
+        };
+
+        for case in 0..6 {
+            let dir = scratch_dir();
+            let service = service(AppConfig::default(), &dir);
+            let transfer_id = Uuid::new_v4();
+            let (receiving, mut sender, cipher) =
+                encrypted_offer(context(&service, &dir), transfer_id, &data, &PROTOCOL_VERSION.to_string()).await;
+            let chunks = sender.send_encrypted_chunks(transfer_id, &data, &cipher).await;
+            let seal = forged(&cipher, chunks).swap_remove(case);
+            sender.complete(transfer_id, seal).await;
+
+            match sender.recv().await {
+                TransferMessage::Error { message, .. } => assert_eq!(message, "Transfer could not be verified", "case {}", case),
+                other => panic!("case {}: expected an error, got {:?}", case, other),
+            }
+            assert!(receiving.await.unwrap().is_err());
+            assert!(downloaded_files(&dir).is_empty(), "case {}", case);
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn sender_from_before_seals_is_not_asked_for_one() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let data = sample_data();
+        let transfer_id = Uuid::new_v4();
+
+        let (receiving, mut sender, cipher) = encrypted_offer(context(&service, &dir), transfer_id, &data, "1.3").await;
+        sender.send_encrypted_chunks(transfer_id, &data, &cipher).await;
+        sender.complete(transfer_id, None).await;
+
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Complete)));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn sender_seals_what_it_sent() {
+        let dir = scratch_dir();
+        let service = service(limited_config(1), &dir);
+        let data = sample_data();
+        let path = dir.join("outgoing.bin");
+        std::fs::write(&path, &data).unwrap();
+        let (sending, mut receiver) = send(context(&service, &dir), service.track_send(Uuid::new_v4()), path);
+
+        let request_line = TransferService::read_raw_message(&mut receiver.reader, MAX_CONTROL_MESSAGE_LEN).await.unwrap();
+        let TransferMessage::Request {
+            transfer_id,
+            public_key: Some(sender_key),
+            ..
+        } = serde_json::from_str(&request_line).unwrap()
+        else {
+            panic!("expected an encrypted request");
+        };
+        let identity = Identity::generate();
+        let handshake =
+            TransferService::accept_handshake(transfer_id, PROTOCOL_VERSION, &request_line, None, false, false, 1, Some(&sender_key), None, &identity)
+                .unwrap();
+        TransferService::write_raw_message(&mut receiver.writer, &handshake.accept_line).await.unwrap();
+        let cipher = handshake.cipher.unwrap();
+
+        let mut received = Vec::new();
+        let mut chunks = 0;
+        let seal = loop {
+            match receiver.recv().await {
+                TransferMessage::Chunk { chunk_index, data, .. } => {
+                    received.extend(cipher.decrypt(chunk_index, &data).unwrap());
+                    chunks += 1;
+                }
+                TransferMessage::Complete { seal, .. } => break seal.expect("sealed"),
+                other => panic!("unexpected {:?}", other),
+            }
+        };
+        assert_eq!(received, data);
+        cipher.verify_seal(&seal, chunks, data.len() as u64, &Sha256::digest(&data)).unwrap();
+        drop(receiver);
+        assert!(sending.await.unwrap().unwrap().encrypted);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn sender_reports_what_the_receiver_verified() {
+        let dir = scratch_dir();
+        let service = service(limited_config(1), &dir);
+        let data = sample_data();
+        let path = dir.join("outgoing.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        let (sending, mut receiver) = send(context(&service, &dir), service.track_send(Uuid::new_v4()), path.clone());
+        assert_eq!(accept_all(&mut receiver).await, data);
+        drop(receiver);
+        let sent = sending.await.unwrap().unwrap();
+        assert!(sent.peer_ack);
+        assert_eq!(sent.checksum, Some(hex::encode(Sha256::digest(&data))));
+        assert_eq!(sent.bytes_sent, data.len() as u64);
+
+        // A receiver whose copy didn't match says so instead of keeping it
+        let (sending, mut receiver) = send(context(&service, &dir), service.track_send(Uuid::new_v4()), path.clone());
+        let TransferMessage::Request { transfer_id, .. } = receiver.recv().await else {
+            panic!("expected a request");
+        };
+        receiver
+            .send(&TransferMessage::Accept {
+                transfer_id,
+                public_key: None,
+                identity_key: None,
+                protocol_version: None,
+                window: None,
+                framed: false,
+                compression: None,
+                chunk_checksums: false,
+                parallel_streams: 0,
+            })
+            .await;
+        while !matches!(receiver.recv().await, TransferMessage::Complete { .. }) {}
+        receiver
+            .send(&TransferMessage::Error {
+                transfer_id,
+                message: "Checksum mismatch".to_string(),
+                code: Some(CODE_CHECKSUM_MISMATCH.to_string()),
+            })
+            .await;
+        let error = sending.await.unwrap().err().expect("a refused file isn't sent");
+        assert!(error.to_string().contains("Checksum mismatch"));
+
+        // Without a checksum to offer, nothing was checked
+        let (mut reader, mut writer, mut receiver) = connection();
+        let context = context(&service, &dir);
+        let transfer = service.track_send(Uuid::new_v4());
+        let sending = tokio::spawn(async move {
+            TransferService::send_over(&context, &mut reader, &mut writer, &transfer, peer_address(), &path, None).await
+        });
+        accept_all(&mut receiver).await;
+        drop(receiver);
+        assert!(!sending.await.unwrap().unwrap().peer_ack);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn receiver_error_mid_transfer_reaches_a_blocked_sender() {
+        let dir = scratch_dir();
+        let service = service(limited_config(1), &dir);
+        // Far more than the connection buffers, so the sender ends up stuck writing
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let path = dir.join("large.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        let (sending, mut receiver) = send(context(&service, &dir), service.track_send(Uuid::new_v4()), path);
+        let TransferMessage::Request { transfer_id, .. } = receiver.recv().await else {
+            panic!("expected a request");
+        };
+        receiver
+            .send(&TransferMessage::Accept {
+                transfer_id,
+                public_key: None,
+                identity_key: None,
+                protocol_version: None,
+                window: None,
+                framed: false,
+                compression: None,
+                chunk_checksums: false,
+                parallel_streams: 0,
+            })
+            .await;
+        assert!(matches!(receiver.recv().await, TransferMessage::Chunk { .. }));
+        receiver
+            .send(&TransferMessage::Error {
+                transfer_id,
+                message: "Receiver could not write the file: No space left on device".to_string(),
+                code: Some(CODE_DISK_FULL.to_string()),
+            })
+            .await;
+
+        // Well before the stall timeout, with the receiver's reason and code
+        let error = timeout(Duration::from_secs(10), sending).await.unwrap().unwrap().err().expect("the send fails");
+        assert_eq!(TransferError::code_of(&error).as_deref(), Some(CODE_DISK_FULL));
+        assert!(error.to_string().contains("No space left on device"));
+        drop(receiver);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn receiver_vanishing_mid_transfer_is_a_peer_disconnect() {
+        let dir = scratch_dir();
+        let service = service(limited_config(1), &dir);
+        let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        let path = dir.join("large.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        let (sending, mut receiver) = send(context(&service, &dir), service.track_send(Uuid::new_v4()), path);
+        let TransferMessage::Request { transfer_id, .. } = receiver.recv().await else {
+            panic!("expected a request");
+        };
+        receiver
+            .send(&TransferMessage::Accept {
+                transfer_id,
+                public_key: None,
+                identity_key: None,
+                protocol_version: None,
+                window: None,
+                framed: false,
+                compression: None,
+                chunk_checksums: false,
+                parallel_streams: 0,
+            })
+            .await;
+        assert!(matches!(receiver.recv().await, TransferMessage::Chunk { .. }));
+        drop(receiver);
+
+        let error = timeout(Duration::from_secs(10), sending).await.unwrap().unwrap().err().expect("the send fails");
+        assert_eq!(TransferError::code_of(&error).as_deref(), Some("peer_disconnected"));
+        let Some(TransferError::PeerDisconnected { bytes_sent, .. }) = error.downcast_ref::<TransferError>() else {
+            panic!("expected a disconnect, got {}", error);
+        };
+        assert!(*bytes_sent >= CHUNK as u64 && *bytes_sent < data.len() as u64);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn queued_send_reaches_a_peer_that_moved_before_it_ran() {
+        let dir = scratch_dir();
+        let sender = service(limited_config(1), &dir);
+        let receiver = service(limited_config(1), &dir);
+        let data = sample_data();
+        let path = dir.join("outgoing.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        // Queued while the peer was somewhere nothing listens any more...
+        let peer_id = Uuid::new_v4();
+        let old_address = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let moved = |address| Peer::from_discovery(peer_id, address, "receiver".to_string(), None);
+        sender.peers.write().await.add_or_update_peer(moved(old_address));
+        let route = Route::Peer(peer_id);
+
+        // ...and moved before the send got going
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        sender.peers.write().await.add_or_update_peer(moved(listener.local_addr().unwrap()));
+        let receiving = {
+            let context = context(&receiver, &dir);
+            tokio::spawn(async move {
+                let (stream, from) = listener.accept().await.unwrap();
+                TransferService::handle_connection(stream.into(), from, context, &mut None).await
+            })
+        };
+        let transfer = sender.track_send(Uuid::new_v4());
+        let outcome = sender.send_file(&transfer, route, path, |_, _| {}).await.unwrap();
+        receiving.await.unwrap().unwrap();
+
+        assert_eq!(outcome.bytes_sent, data.len() as u64);
+        assert_eq!(std::fs::read(dir.join("downloads").join("outgoing.bin")).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn send_falls_back_to_another_address_and_remembers_it() {
+        let dir = scratch_dir();
+        let sender = service(limited_config(1), &dir);
+        let receiver = service(limited_config(1), &dir);
+        let data = sample_data();
+        let path = dir.join("outgoing.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        // Announced first at an address that no longer answers
+        let peer_id = Uuid::new_v4();
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let working = listener.local_addr().unwrap();
+        let mut peer = Peer::from_discovery(peer_id, dead, "receiver".to_string(), None);
+        peer.addresses = vec![dead, working];
+        sender.peers.write().await.add_or_update_peer(peer);
+
+        let receiving = {
+            let context = context(&receiver, &dir);
+            tokio::spawn(async move {
+                let (stream, from) = listener.accept().await.unwrap();
+                TransferService::handle_connection(stream.into(), from, context, &mut None).await
+            })
+        };
+        let transfer = sender.track_send(Uuid::new_v4());
+        sender.send_file(&transfer, Route::Peer(peer_id), path, |_, _| {}).await.unwrap();
+        receiving.await.unwrap().unwrap();
+
+        let peers = sender.peers.read().await;
+        assert_eq!(peers.get_peer(&peer_id).unwrap().reached_at, Some(working));
+        assert_eq!(peers.addresses_to_try(dead), [working, dead]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn peer_check_probes_without_sending_anything() {
+        let dir = scratch_dir();
+        let checker = service(limited_config(1), &dir);
+        let checked = service(limited_config(1), &dir);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let (up, down) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut peer = Peer::from_discovery(up, listener.local_addr().unwrap(), "up".to_string(), None);
+        peer.capabilities = vec![capability::PROBE.to_string()];
+        checker.peers.write().await.add_or_update_peer(peer);
+        let dead = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        checker
+            .peers
+            .write()
+            .await
+            .add_or_update_peer(Peer::from_discovery(down, dead, "down".to_string(), None));
+
+        let answering = {
+            let context = context(&checked, &dir);
+            tokio::spawn(async move {
+                let (stream, from) = listener.accept().await.unwrap();
+                TransferService::handle_connection(stream.into(), from, context, &mut None).await
+            })
+        };
+        let result = checker.check_peer(up).await;
+        answering.await.unwrap().unwrap();
+        assert!(result.reachable, "{:?}", result.error);
+        assert!(result.connect_ms.is_some() && result.rtt_ms.is_some());
+        assert!(downloaded_files(&dir).is_empty());
+
+        let result = checker.check_peer(down).await;
+        assert!(!result.reachable);
+        assert_eq!((result.hostname.as_str(), result.address), ("down", None));
+        assert!(result.error.is_some());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn send_file_reports_its_outcome() {
+        let dir = scratch_dir();
+        let sender = service(limited_config(1), &dir);
+        let receiver = service(limited_config(1), &dir);
+        let data = sample_data();
+        let path = dir.join("outgoing.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let receiving = {
+            let context = context(&receiver, &dir);
+            tokio::spawn(async move {
+                let (stream, from) = listener.accept().await.unwrap();
+                TransferService::handle_connection(stream.into(), from, context, &mut None).await
+            })
+        };
+        let transfer = sender.track_send(Uuid::new_v4());
+        let outcome = sender
+            .send_file(&transfer, Route::Direct(address), path, |_, _| {})
+            .await
+            .unwrap();
+        receiving.await.unwrap().unwrap();
+
+        assert_eq!(outcome.transfer_id, transfer.id());
+        assert_eq!(outcome.bytes_sent, data.len() as u64);
+        assert_eq!(outcome.checksum, Some(hex::encode(Sha256::digest(&data))));
+        assert!(outcome.peer_ack && outcome.encrypted);
+        assert_eq!(std::fs::read(dir.join("downloads").join("outgoing.bin")).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Adds a peer at the test address whose identity is already pinned.
+    async fn pin_peer(service: &TransferService, identity: &Identity) {
+        let mut peers = service.peers.write().await;
+        let peer = Peer::from_discovery(identity.peer_id(), peer_address(), "pinned".to_string(), Some(identity.fingerprint()));
+        peers.add_or_update_peer(peer);
+        peers.check_identity(identity.peer_id(), &identity.fingerprint());
+        assert_eq!(peers.pinned_fingerprint(&identity.peer_id()), Some(identity.fingerprint().as_str()));
+    }
+
+    #[tokio::test]
+    async fn identity_without_key_exchange_is_refused() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let pinned = Identity::generate();
+        pin_peer(&service, &pinned).await;
+
+        // Claiming the pinned key without the exchange that would prove it,
+        // or not presenting it at all
+        let cases = [
+            (Some(pinned.public_key_hex()), UNPROVEN_IDENTITY.to_string()),
+            (None, "pinned did not present its identity key".to_string()),
+        ];
+        for (identity_key, expected) in cases {
+            let transfer_id = Uuid::new_v4();
+            let mut offer = request(transfer_id, "notes.txt", b"hello");
+            if let TransferMessage::Request { identity_key: key, .. } = &mut offer {
+                *key = identity_key;
+            }
+            let (receiving, mut sender) = receive(context(&service, &dir), &offer);
+            match sender.recv().await {
+                TransferMessage::Reject { reason, .. } => assert_eq!(reason.unwrap(), expected),
+                other => panic!("expected a rejection, got {:?}", other),
+            }
+            assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Refused(_))));
+        }
+
+        let identity = Identity::generate();
+        let error = TransferService::accept_handshake(
+            Uuid::new_v4(),
+            PROTOCOL_VERSION,
+            "{}",
+            None,
+            false,
+            false,
+            1,
+            None,
+            Some(&pinned.public_key_hex()),
+            &identity,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(error.to_string(), UNPROVEN_IDENTITY);
+        assert!(downloaded_files(&dir).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn sender_refuses_identity_without_key_exchange() {
+        let dir = scratch_dir();
+        let pinned = Identity::generate();
+        for pin in [false, true] {
+            let service = service(AppConfig::default(), &dir);
+            if pin {
+                pin_peer(&service, &pinned).await;
+            }
+            let path = dir.join("outgoing.bin");
+            std::fs::write(&path, sample_data()).unwrap();
+            let (sending, mut receiver) = send(context(&service, &dir), service.track_send(Uuid::new_v4()), path);
+            let TransferMessage::Request { transfer_id, .. } = receiver.recv().await else {
+                panic!("expected a request");
+            };
+            receiver
+                .send(&TransferMessage::Accept {
+                    transfer_id,
+                    public_key: None,
+                    identity_key: Some(pinned.public_key_hex()),
+                    protocol_version: None,
+                    window: None,
+                    framed: false,
+                    compression: None,
+                    chunk_checksums: false,
+                    parallel_streams: 0,
+                })
+                .await;
+
+            assert!(matches!(receiver.recv().await, TransferMessage::Cancel { .. }));
+            let error = sending.await.unwrap().err().unwrap();
+            assert_eq!(error.to_string(), format!("Refusing to send: {}", UNPROVEN_IDENTITY));
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn offered_filename_cannot_leave_downloads() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let data = sample_data();
+
+        // A backslash is only a separator on Windows; elsewhere it's tidied away
+        let backslashed = "..\\outside.bin";
+        let hostile = ["../../outside.bin", "/tmp/outside.bin", "..", "\u{0}\u{1}"].into_iter().chain(cfg!(windows).then_some(backslashed));
+        for hostile in hostile {
+            let transfer_id = Uuid::new_v4();
+            let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, hostile, &data));
+            let TransferMessage::Reject { reason: Some(reason), .. } = sender.recv().await else {
+                panic!("{:?} was not refused", hostile);
+            };
+            assert!(reason.starts_with(&format!("Unsafe filename {:?}", hostile)), "{}", reason);
+            assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Refused(refused)) if refused == reason));
+        }
+
+        // Names that only need tidying are saved under the tidied name
+        let mut tidied = vec![("nul.txt. ", "_nul.txt")];
+        if !cfg!(windows) {
+            tidied.push((backslashed, "_outside.bin"));
+        }
+        for (name, _) in &tidied {
+            let transfer_id = Uuid::new_v4();
+            let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, name, &data));
+            assert!(matches!(sender.recv().await, TransferMessage::Accept { .. }));
+            sender.send_chunks(transfer_id, &data).await;
+            sender.complete(transfer_id, None).await;
+            assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Complete)));
+        }
+        let mut saved = downloaded_files(&dir);
+        saved.sort();
+        let mut expected: Vec<String> = tidied.iter().map(|(_, saved)| saved.to_string()).collect();
+        expected.sort();
+        assert_eq!(saved, expected);
+        assert!(!dir.join("outside.bin").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Receives `offer` as the answer to a download we asked for.
+    fn receive_requested(
+        service: &TransferService,
+        dir: &Path,
+        offer: &TransferMessage,
+    ) -> (JoinHandle<Result<ReceiveOutcome>>, FakePeer) {
+        let TransferMessage::Request { transfer_id, .. } = offer else {
+            panic!("not a request");
+        };
+        let transfer = service.track_receive(*transfer_id);
+        let context = context(service, dir);
+        let request_line = serde_json::to_string(offer).unwrap();
+        let (mut reader, mut writer, peer) = connection();
+        let receiving = tokio::spawn(async move {
+            let mut progress = None;
+            TransferService::receive_file(
+                &mut reader,
+                &mut writer,
+                peer_address(),
+                &context,
+                &request_line,
+                Some(&transfer),
+                &mut progress,
+            )
+            .await
+        });
+        (receiving, peer)
+    }
+
+    #[tokio::test]
+    async fn requested_download_still_answers_to_rules() {
+        let rule = |action| AcceptRule {
+            name: "no images".to_string(),
+            peer: None,
+            mime_types: Vec::new(),
+            extensions: vec!["iso".to_string()],
+            max_size: None,
+            action,
+        };
+        // (rules, default action, accepted)
+        let cases = [
+            (vec![rule(AcceptAction::Reject)], AcceptAction::Accept, false),
+            (vec![rule(AcceptAction::Prompt)], AcceptAction::Accept, true),
+            // Asking for it is the answer a prompt or the default would want
+            (Vec::new(), AcceptAction::Prompt, true),
+            (Vec::new(), AcceptAction::Reject, true),
+        ];
+        for (rules, default_action, accepted) in cases {
+            let dir = scratch_dir();
+            let mut config = AppConfig::default();
+            config.transfer.accept_rules = rules;
+            config.transfer.default_action = default_action;
+            let service = service(config, &dir);
+
+            let offer = request(Uuid::new_v4(), "disk.iso", b"image");
+            let (receiving, mut sender) = receive_requested(&service, &dir, &offer);
+            match (sender.recv().await, accepted) {
+                (TransferMessage::Accept { .. }, true) => {}
+                (TransferMessage::Reject { reason, .. }, false) => {
+                    assert_eq!(reason.as_deref(), Some("Declined by rule \"no images\""));
+                    assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Refused(_))));
+                }
+                (other, _) => panic!("default {:?}: unexpected {:?}", default_action, other),
+            }
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn requested_download_still_answers_to_the_blocklist() {
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        config.transfer.blocked_extensions = vec!["exe".to_string()];
+        let service = service(config, &dir);
+
+        // Trailing dots would otherwise hide the extension on some systems
+        let offer = request(Uuid::new_v4(), "tool.exe.", b"MZ");
+        let (receiving, mut sender) = receive_requested(&service, &dir, &offer);
+        match sender.recv().await {
+            TransferMessage::Reject { reason, .. } => {
+                assert_eq!(reason.as_deref(), Some("Files of type .exe are not accepted"))
+            }
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Refused(_))));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_file_the_disk_has_no_room_for_is_refused_before_anything_is_written() {
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        // More than any disk has, so nothing fits
+        config.transfer.free_space_margin = u64::MAX / 2;
+        let service = service(config, &dir);
+
+        let (receiving, mut sender) = receive(context(&service, &dir), &request(Uuid::new_v4(), "vm.img", &sample_data()));
+        match sender.recv().await {
+            TransferMessage::Reject { reason: Some(reason), .. } => {
+                assert_eq!(reason, "Insufficient disk space: 3.91 KB needed, 0 B free")
+            }
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Refused(_))));
+        assert!(downloaded_files(&dir).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_receiver_out_of_space_fails_the_send_with_its_reason() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let path = dir.join("vm.img");
+        std::fs::write(&path, sample_data()).unwrap();
+
+        let (sending, mut receiver) = send(context(&service, &dir), service.track_send(Uuid::new_v4()), path);
+        let TransferMessage::Request { transfer_id, .. } = receiver.recv().await else {
+            panic!("expected a request");
+        };
+        let reason = "Insufficient disk space: 40 GB needed, 2 GB free";
+        receiver
+            .send(&TransferMessage::Reject {
+                transfer_id,
+                reason: Some(reason.to_string()),
+                protocol_version: None,
+                max_file_size: None,
+            })
+            .await;
+        let Err(error) = sending.await.unwrap() else { panic!("sent to a full disk") };
+        assert_eq!(error.to_string(), format!("Transfer rejected by peer: {}", reason));
+        assert_eq!(TransferError::code_of(&error).as_deref(), Some("insufficient_space"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn used_up_daily_budget_refuses_new_receives() {
+        let dir = scratch_dir();
+        let data = sample_data();
+        let mut config = AppConfig::default();
+        config.transfer.daily_budget_bytes = Some(data.len() as u64);
+        let service = service(config, &dir);
+
+        let transfer_id = Uuid::new_v4();
+        let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, "first.bin", &data));
+        assert!(matches!(sender.recv().await, TransferMessage::Accept { .. }));
+        sender.send_chunks(transfer_id, &data).await;
+        sender.complete(transfer_id, None).await;
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Complete)));
+        assert_eq!(service.usage().used_today(), data.len() as u64);
+
+        let (receiving, mut sender) = receive(context(&service, &dir), &request(Uuid::new_v4(), "second.bin", &data));
+        match sender.recv().await {
+            TransferMessage::Reject { reason, .. } => assert!(reason.unwrap().starts_with("Daily transfer budget")),
+            other => panic!("expected a rejection, got {:?}", other),
+        }
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Refused(_))));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn received_files_get_the_configured_mode_and_group() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let dir = scratch_dir();
+        let mut config: AppConfig = toml::from_str(&toml::to_string(&AppConfig::default()).unwrap().replace(
+            "[transfer]",
+            "[transfer]\nreceived_file_mode = \"0600\"\nreceived_dir_mode = \"0750\"",
+        ))
+        .unwrap();
+        config.transfer.chunk_size = CHUNK;
+        let gid = unsafe { libc::getgid() };
+        config.transfer.received_group = Some(gid.to_string());
+        let service = service(config, &dir);
+
+        let data = sample_data();
+        let transfer_id = Uuid::new_v4();
+        let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, "private.bin", &data));
+        assert!(matches!(sender.recv().await, TransferMessage::Accept { .. }));
+        sender.send_chunks(transfer_id, &data).await;
+        sender
+            .send(&TransferMessage::Complete {
+                transfer_id,
+                file_checksum: None,
+                seal: None,
+            })
+            .await;
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Complete)));
+
+        let saved = std::fs::metadata(dir.join("downloads").join("private.bin")).unwrap();
+        assert_eq!(saved.permissions().mode() & 0o7777, 0o600);
+        assert_eq!(saved.gid(), gid);
+        let folder = std::fs::metadata(dir.join("downloads")).unwrap();
+        assert_eq!(folder.permissions().mode() & 0o7777, 0o750);
+
+        // A mode that isn't octal doesn't make it past loading the config
+        let invalid = toml::to_string(&AppConfig::default())
+            .unwrap()
+            .replace("[transfer]", "[transfer]\nreceived_file_mode = \"rw-------\"");
+        assert!(toml::from_str::<AppConfig>(&invalid).is_err());
+        assert!("0800".parse::<FileMode>().is_err());
+        assert_eq!("640".parse::<FileMode>().unwrap().to_string(), "0640");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn same_content_received_twice_is_stored_once() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        config.transfer.chunk_size = CHUNK;
+        config.transfer.content_addressed = true;
+        let service = service(config, &dir);
+
+        let data = sample_data();
+        for filename in ["first.bin", "again.bin"] {
+            let transfer_id = Uuid::new_v4();
+            let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, filename, &data));
+            assert!(matches!(sender.recv().await, TransferMessage::Accept { .. }));
+            sender.send_chunks(transfer_id, &data).await;
+            sender
+                .send(&TransferMessage::Complete {
+                    transfer_id,
+                    file_checksum: None,
+                    seal: None,
+                })
+                .await;
+            assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Complete)));
+        }
+
+        let downloads = dir.join("downloads");
+        let stored: Vec<_> = std::fs::read_dir(downloads.join(objects::OBJECTS_DIR)).unwrap().collect();
+        assert_eq!(stored.len(), 1);
+        assert_eq!(std::fs::metadata(stored[0].as_ref().unwrap().path()).unwrap().nlink(), 3);
+        assert_eq!(std::fs::read(downloads.join("again.bin")).unwrap(), data);
+        assert!(!downloaded_files(&dir).iter().any(|name| name.ends_with(".part")));
+
+        // Once no name is left, the next sweep reclaims the content
+        std::fs::remove_file(downloads.join("first.bin")).unwrap();
+        std::fs::remove_file(downloads.join("again.bin")).unwrap();
+        let report = service.run_maintenance(false).await.unwrap();
+        assert_eq!(report.removed.len(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn files_the_receiver_has_already_are_not_sent_again() {
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        config.transfer.chunk_size = CHUNK;
+        config.transfer.on_existing_file = ExistingFilePolicy::Reject;
+        let sending = service(config.clone(), &dir);
+        config.transfer.resend_identical = true;
+        let resending = service(config.clone(), &dir);
+        let service = service(config, &dir);
+        let data = sample_data();
+        std::fs::create_dir_all(dir.join("downloads")).unwrap();
+        let path = dir.join("downloads").join("build.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        // Offered under keys, the receiver vouches for the file it has
+        let sender_identity = Identity::generate();
+        let offer = |transfer_id, data: &[u8], exchange: Option<&KeyExchange>| {
+            let mut offer = request(transfer_id, "build.bin", data);
+            if let TransferMessage::Request {
+                skip_identical,
+                public_key,
+                identity_key,
+                ..
+            } = &mut offer
+            {
+                *skip_identical = true;
+                *public_key = exchange.map(KeyExchange::public_key_hex);
+                *identity_key = exchange.map(|_| sender_identity.public_key_hex());
+            }
+            serde_json::to_string(&offer).unwrap()
+        };
+        let transfer_id = Uuid::new_v4();
+        let exchange = KeyExchange::new();
+        let request_line = offer(transfer_id, &data, Some(&exchange));
+        let (receiving, mut sender) = receive_line(context(&service, &dir), request_line.clone());
+        let TransferMessage::AlreadyExists {
+            public_key,
+            identity_key,
+            proof,
+            ..
+        } = sender.recv().await
+        else {
+            panic!("expected AlreadyExists");
+        };
+        let cipher = exchange
+            .finish(Role::Sender, &public_key, Some((sender_identity.secret(), &identity_key)), transfer_id, &[&request_line])
+            .unwrap();
+        cipher.verify_held(&proof, data.len() as u64, &Sha256::digest(&data)).unwrap();
+        assert!(cipher.verify_held(&proof, data.len() as u64 + 1, &Sha256::digest(&data)).is_err());
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Identical)));
+
+        // Without keys there's nothing to vouch under, so the name is taken;
+        // so it is for different content under it
+        let mut changed = data.clone();
+        changed[0] ^= 1;
+        let unkeyed = offer(Uuid::new_v4(), &data, None);
+        let changed = offer(Uuid::new_v4(), &changed, Some(&KeyExchange::new()));
+        for request_line in [unkeyed, changed] {
+            let (receiving, mut sender) = receive_line(context(&service, &dir), request_line);
+            assert!(matches!(sender.recv().await, TransferMessage::Reject { .. }));
+            assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Refused(_))));
+        }
+        assert_eq!(downloaded_files(&dir), ["build.bin"]);
+
+        // The sender takes the word of the receiver it pinned as done,
+        // having sent nothing
+        let receiver_identity = Identity::generate();
+        pin_peer(&sending, &receiver_identity).await;
+        let answer = |request_line: &str, identity: &Identity| {
+            let TransferMessage::Request {
+                transfer_id,
+                skip_identical,
+                public_key,
+                identity_key,
+                ..
+            } = serde_json::from_str(request_line).unwrap()
+            else {
+                panic!("expected a request");
+            };
+            assert!(skip_identical);
+            let checksum = hex::encode(Sha256::digest(&data));
+            let (key, sender_identity) = (public_key.unwrap(), identity_key.unwrap());
+            TransferService::already_exists(transfer_id, request_line, data.len() as u64, &checksum, &key, &sender_identity, identity)
+                .unwrap()
+        };
+        let (sent, mut receiver) = send(context(&sending, &dir), sending.track_send(Uuid::new_v4()), path.clone());
+        let request_line = TransferService::read_raw_message(&mut receiver.reader, MAX_CONTROL_MESSAGE_LEN).await.unwrap();
+        receiver.send(&answer(&request_line, &receiver_identity)).await;
+        let sent = sent.await.unwrap().unwrap();
+        assert!(sent.deduplicated && sent.peer_ack);
+        assert_eq!(sent.bytes_sent, 0);
+        assert_eq!(sent.checksum, Some(hex::encode(Sha256::digest(&data))));
+
+        // Nobody else's
+        let (sent, mut receiver) = send(context(&sending, &dir), sending.track_send(Uuid::new_v4()), path.clone());
+        let request_line = TransferService::read_raw_message(&mut receiver.reader, MAX_CONTROL_MESSAGE_LEN).await.unwrap();
+        receiver.send(&answer(&request_line, &Identity::generate())).await;
+        assert!(matches!(receiver.recv().await, TransferMessage::Cancel { .. }));
+        assert!(sent.await.unwrap().is_err());
+
+        // Nor is it asked for when told to send regardless
+        pin_peer(&resending, &receiver_identity).await;
+        let (sent, mut receiver) = send(context(&resending, &dir), resending.track_send(Uuid::new_v4()), path);
+        assert!(matches!(receiver.recv().await, TransferMessage::Request { skip_identical: false, .. }));
+        drop(receiver);
+        let _ = sent.await;
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn received_files_are_filed_by_what_they_turn_out_to_be() {
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        config.transfer.chunk_size = CHUNK;
+        config.transfer.organize_by_type = true;
+        let service = service(config, &dir);
+
+        // A PNG under a name that doesn't say so, and text that only its name tells
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        png.extend(sample_data());
+        for (filename, data) in [("holiday.bin", png), ("notes.txt", sample_data())] {
+            let transfer_id = Uuid::new_v4();
+            let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, filename, &data));
+            assert!(matches!(sender.recv().await, TransferMessage::Accept { .. }));
+            sender.send_chunks(transfer_id, &data).await;
+            sender
+                .send(&TransferMessage::Complete {
+                    transfer_id,
+                    file_checksum: None,
+                    seal: None,
+                })
+                .await;
+            assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Complete)));
+        }
+
+        let downloads = dir.join("downloads");
+        assert!(downloads.join("Images").join("holiday.bin").is_file());
+        assert!(downloads.join("Documents").join("notes.txt").is_file());
+        assert!(!downloads.join("holiday.bin").exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Receives `data` as `filename` and returns how that came out, with
+    /// where it was saved under downloads.
+    async fn receive_named(context: ConnectionContext, filename: &str, data: &[u8]) -> (Result<ReceiveOutcome>, Option<String>, FakePeer) {
+        let transfer_id = Uuid::new_v4();
+        let request_line = serde_json::to_string(&request(transfer_id, filename, data)).unwrap();
+        let (mut reader, mut writer, mut sender) = connection();
+        let receiving = tokio::spawn(async move {
+            let mut progress = None;
+            let outcome =
+                TransferService::receive_file(&mut reader, &mut writer, peer_address(), &context, &request_line, None, &mut progress)
+                    .await;
+            (outcome, progress.and_then(|progress| progress.relative_path))
+        });
+        if let TransferMessage::Accept { .. } = sender.recv().await {
+            sender.send_chunks(transfer_id, data).await;
+            sender
+                .send(&TransferMessage::Complete {
+                    transfer_id,
+                    file_checksum: None,
+                    seal: None,
+                })
+                .await;
+        }
+        let (outcome, relative_path) = receiving.await.unwrap();
+        (outcome, relative_path, sender)
+    }
+
+    #[tokio::test]
+    async fn a_second_file_of_the_same_name_is_kept_beside_the_first() {
+        let dir = scratch_dir();
+        let service = service(limited_config(5), &dir);
+        let downloads = dir.join("downloads");
+        let first = sample_data();
+        let second: Vec<u8> = first.iter().rev().copied().collect();
+
+        let (outcome, saved_as, _) = receive_named(context(&service, &dir), "report.pdf", &first).await;
+        assert!(matches!(outcome, Ok(ReceiveOutcome::Complete)));
+        assert_eq!(saved_as.as_deref(), Some("report.pdf"));
+        let (outcome, saved_as, _) = receive_named(context(&service, &dir), "report.pdf", &second).await;
+        assert!(matches!(outcome, Ok(ReceiveOutcome::Complete)));
+        assert_eq!(saved_as.as_deref(), Some("report (1).pdf"));
+        assert_eq!(std::fs::read(downloads.join("report.pdf")).unwrap(), first);
+        assert_eq!(std::fs::read(downloads.join("report (1).pdf")).unwrap(), second);
+
+        // Overwriting replaces the first; rejecting refuses before anything is sent
+        let overwrite = ConnectionContext {
+            on_existing_file: ExistingFilePolicy::Overwrite,
+            ..context(&service, &dir)
+        };
+        let (_, saved_as, _) = receive_named(overwrite, "report.pdf", &second).await;
+        assert_eq!(saved_as.as_deref(), Some("report.pdf"));
+        assert_eq!(std::fs::read(downloads.join("report.pdf")).unwrap(), second);
+        let reject = ConnectionContext {
+            on_existing_file: ExistingFilePolicy::Reject,
+            ..context(&service, &dir)
+        };
+        let (outcome, _, _) = receive_named(reject, "report.pdf", &first).await;
+        assert!(matches!(outcome, Ok(ReceiveOutcome::Refused(reason)) if reason == "report.pdf already exists"));
+        assert_eq!(downloaded_files(&dir).len(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn files_finishing_together_never_share_a_name() {
+        let dir = scratch_dir();
+        std::fs::create_dir_all(&dir).unwrap();
+        let claims = (0..8).map(|_| TransferService::claim_path(&dir, "same.bin", ExistingFilePolicy::Rename));
+        let mut claimed: Vec<PathBuf> = join_all(claims).await.into_iter().map(|path| path.unwrap().unwrap()).collect();
+        claimed.sort();
+        claimed.dedup();
+        assert_eq!(claimed.len(), 8);
+
+        // Only one of those rejecting a taken name gets it
+        let claims = (0..8).map(|_| TransferService::claim_path(&dir, "once.bin", ExistingFilePolicy::Reject));
+        let claimed = join_all(claims).await.into_iter().filter(|path| path.as_ref().unwrap().is_some()).count();
+        assert_eq!(claimed, 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_taken_name_found_only_once_filed_is_rejected_then() {
+        let dir = scratch_dir();
+        let service = service(limited_config(5), &dir);
+        let context = || ConnectionContext {
+            organize_by_type: true,
+            on_existing_file: ExistingFilePolicy::Reject,
+            ..context(&service, &dir)
+        };
+
+        let (outcome, saved_as, _) = receive_named(context(), "notes.txt", &sample_data()).await;
+        assert!(matches!(outcome, Ok(ReceiveOutcome::Complete)));
+        assert_eq!(saved_as, Some(Path::new("Documents").join("notes.txt").to_string_lossy().to_string()));
+
+        // Where it goes isn't known until it's all here, so it's sent and discarded
+        let (outcome, _, mut sender) = receive_named(context(), "notes.txt", &sample_data()).await;
+        assert!(matches!(outcome, Err(e) if e.to_string() == "notes.txt already exists"));
+        assert!(matches!(
+            sender.recv().await,
+            TransferMessage::Error { code: Some(code), .. } if code == CODE_FILE_EXISTS
+        ));
+        assert!(!dir.join("downloads").join("Documents").join("notes (1).txt").exists());
+        assert!(!downloaded_files(&dir).iter().any(|name| name.ends_with(".part")));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Sends `path` from `sender` to `receiver` through a pipe, as over a
+    /// connection between them. Returns how each side came out, everything
+    /// that went to the receiver, and how long it took.
+    async fn pipe_between(
+        (sender, sender_dir): (&TransferService, &Path),
+        (receiver, receiver_dir): (&TransferService, &Path),
+        path: PathBuf,
+    ) -> (Result<TransferOutcome>, Result<ReceiveOutcome>, std::io::Result<Vec<u8>>, Duration) {
+        let started = Instant::now();
+        let (sending, mut receiver_end) = send(context(sender, sender_dir), sender.track_send(Uuid::new_v4()), path);
+        let request_line = TransferService::read_raw_message(&mut receiver_end.reader, MAX_CONTROL_MESSAGE_LEN).await.unwrap();
+        let mut forwarded = format!("{}\n", request_line).into_bytes();
+        let (receiving, mut sender_end) = receive_line(context(receiver, receiver_dir), request_line);
+        let forward = async {
+            let mut buffer = vec![0u8; 64 * 1024];
+            loop {
+                let n = receiver_end.reader.read(&mut buffer).await?;
+                if n == 0 {
+                    return Ok(forwarded);
+                }
+                forwarded.extend_from_slice(&buffer[..n]);
+                sender_end.writer.write_all(&buffer[..n]).await?;
+            }
+        };
+        // Closed on once the receiver is done, as its socket would be
+        let backward = async {
+            tokio::io::copy(&mut sender_end.reader, &mut receiver_end.writer).await?;
+            receiver_end.writer.shutdown().await
+        };
+        let (forwarded, _) = timeout(Duration::from_secs(10), async { tokio::join!(forward, backward) }).await.unwrap();
+        (sending.await.unwrap(), receiving.await.unwrap(), forwarded, started.elapsed())
+    }
+
+    /// Sends `path` from `service` to itself through a pipe, and returns
+    /// how many bytes went to the receiver and how long it all took.
+    async fn send_through_pipe(service: &TransferService, dir: &Path, path: PathBuf) -> (usize, Duration) {
+        let (sent, received, forwarded, took) = pipe_between((service, dir), (service, dir), path).await;
+        sent.unwrap();
+        assert!(matches!(received, Ok(ReceiveOutcome::Complete)));
+        (forwarded.unwrap().len(), took)
+    }
+
+    #[tokio::test]
+    async fn a_chunk_damaged_on_the_way_goes_again_and_the_file_arrives_whole() {
+        let dir = scratch_dir();
+        let service = service(limited_config(1), &dir);
+        let data = sample_data();
+        let path = dir.join("notes.bin");
+        std::fs::write(&path, &data).unwrap();
+        let transfer_id = Uuid::new_v4();
+        // What a frame for chunk 2 starts with: a CRC and the data follow
+        // its length
+        let mut header = vec![CHECKED_FRAME_MARKER];
+        header.extend_from_slice(transfer_id.as_bytes());
+        header.extend_from_slice(&2u64.to_be_bytes());
+
+        let (sending, mut receiver_end) = send(context(&service, &dir), service.track_send(transfer_id), path);
+        let request_line = TransferService::read_raw_message(&mut receiver_end.reader, MAX_CONTROL_MESSAGE_LEN).await.unwrap();
+        let (receiving, mut sender_end) = receive_line(context(&service, &dir), request_line);
+        let forward = async {
+            let mut damaged = false;
+            let mut buffer = vec![0u8; 64 * 1024];
+            loop {
+                let n = receiver_end.reader.read(&mut buffer).await?;
+                if n == 0 {
+                    return Ok::<_, std::io::Error>(damaged);
+                }
+                // Frames are written whole, so one read holds all of it
+                let at = buffer[..n].windows(header.len()).position(|window| window == header);
+                if let (false, Some(at)) = (damaged, at) {
+                    buffer[at + header.len() + 4 + 4 + 10] ^= 0x08;
+                    damaged = true;
+                }
+                sender_end.writer.write_all(&buffer[..n]).await?;
+            }
+        };
+        let backward = async {
+            let mut replies = Vec::new();
+            let mut buffer = vec![0u8; 64 * 1024];
+            loop {
+                let n = sender_end.reader.read(&mut buffer).await?;
+                if n == 0 {
+                    receiver_end.writer.shutdown().await?;
+                    return Ok::<_, std::io::Error>(replies);
+                }
+                replies.extend_from_slice(&buffer[..n]);
+                receiver_end.writer.write_all(&buffer[..n]).await?;
+            }
+        };
+        let (damaged, replies) = timeout(Duration::from_secs(10), async { tokio::join!(forward, backward) }).await.unwrap();
+        assert!(damaged.unwrap());
+        assert!(String::from_utf8_lossy(&replies.unwrap()).contains(r#"{"ChunkNack":"#));
+
+        let sent = sending.await.unwrap().unwrap();
+        assert!(sent.peer_ack);
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Complete)));
+        assert_eq!(std::fs::read(dir.join("downloads").join("notes.bin")).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn chunks_between_current_peers_carry_no_json_overhead() {
+        let dir = scratch_dir();
+        let mut config = limited_config(2);
+        config.transfer.compress_chunks = false;
+        let service = service(config, &dir);
+        let data: Vec<u8> = (0..256 * CHUNK).map(|i| (i % 251) as u8).collect();
+        let path = dir.join("large.bin");
+        std::fs::write(&path, &data).unwrap();
+
+        let (forwarded, _) = send_through_pipe(&service, &dir, path).await;
+        assert_eq!(std::fs::read(dir.join("downloads").join("large.bin")).unwrap(), data);
+        // A frame header and an encryption tag on each of these small chunks,
+        // where JSON would take several bytes for every byte sent
+        assert!(forwarded < data.len() * 11 / 10, "{} bytes for {}", forwarded, data.len());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn compressible_files_go_compressed_and_sooner_under_a_speed_cap() {
+        let data: Vec<u8> = (0..40_000)
+            .flat_map(|i| format!("2026-03-02T09:{:02}:00Z,node-{},INFO,served in {}ms\n", i % 60, i % 17, i % 250).into_bytes())
+            .collect();
+        let mut results = Vec::new();
+        for compress_chunks in [false, true] {
+            let dir = scratch_dir();
+            let mut config = AppConfig::default();
+            config.transfer.compress_chunks = compress_chunks;
+            config.bandwidth.max_up = Some(2 * 1024 * 1024);
+            let service = service(config, &dir);
+            let path = dir.join("requests.csv");
+            std::fs::write(&path, &data).unwrap();
+
+            let (forwarded, took) = send_through_pipe(&service, &dir, path).await;
+            // Checked against the checksum of the file as it was
+            assert_eq!(std::fs::read(dir.join("downloads").join("requests.csv")).unwrap(), data);
+            results.push((forwarded, took));
+            let _ = std::fs::remove_dir_all(&dir);
+        }
+        let [(plain_bytes, plain_took), (compressed_bytes, compressed_took)] = results[..] else { unreachable!() };
+        assert!(plain_bytes >= data.len());
+        assert!(compressed_bytes < data.len() / 5, "{} bytes for {}", compressed_bytes, data.len());
+        assert!(compressed_took * 2 < plain_took, "{:?} compressed, {:?} not", compressed_took, plain_took);
+    }
+
+    #[tokio::test]
+    async fn file_contents_never_cross_the_wire_in_the_clear() {
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        config.transfer.compress_chunks = false;
+        let service = service(config, &dir);
+        let data = b"quarterly payroll: alice 4200, bob 3900\n".repeat(2000);
+        let path = dir.join("payroll.csv");
+        std::fs::write(&path, &data).unwrap();
+
+        let (sent, received, forwarded, _) = pipe_between((&service, &dir), (&service, &dir), path).await;
+        assert!(sent.unwrap().encrypted);
+        assert!(matches!(received, Ok(ReceiveOutcome::Complete)));
+        assert_eq!(std::fs::read(dir.join("downloads").join("payroll.csv")).unwrap(), data);
+        let forwarded = forwarded.unwrap();
+        assert!(forwarded.len() >= data.len());
+        assert!(!forwarded.windows(17).any(|window| window == b"quarterly payroll"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_receiver_whose_identity_isnt_the_pinned_one_gets_nothing() {
+        let (ours, theirs) = (scratch_dir(), scratch_dir());
+        let us = service(AppConfig::default(), &ours);
+        let them = service(AppConfig::default(), &theirs);
+        // What we pinned for their address is someone else's identity
+        pin_peer(&us, &Identity::generate()).await;
+        let path = ours.join("secret.txt");
+        std::fs::write(&path, b"for the pinned peer only").unwrap();
+
+        let (sent, received, forwarded, _) = pipe_between((&us, &ours), (&them, &theirs), path).await;
+        let error = sent.err().expect("the send is refused").to_string();
+        assert!(error.starts_with("Refusing to send: identity of pinned changed to"), "{}", error);
+        assert!(matches!(received, Ok(ReceiveOutcome::CancelledBySender)));
+        assert!(!forwarded.unwrap_or_default().windows(11).any(|window| window == b"pinned peer"));
+        assert!(downloaded_files(&theirs).is_empty());
+        let _ = std::fs::remove_dir_all(&ours);
+        let _ = std::fs::remove_dir_all(&theirs);
+    }
+
+    /// Lists a peer at the test address holding `identity` and announcing
+    /// all this build can do, so what we offer it goes sealed.
+    pub(crate) async fn sealing_peer(service: &TransferService, identity: &Identity) {
+        let mut peer = Peer::from_discovery(Uuid::new_v4(), peer_address(), "sealing".to_string(), Some(identity.fingerprint()));
+        peer.identity_key = Some(identity.public_key_hex());
+        peer.capabilities = capability::local(&AppConfig::default());
+        service.peers.write().await.add_or_update_peer(peer);
+    }
+
+    #[tokio::test]
+    async fn what_a_request_offers_is_sealed_to_a_peer_that_can_open_it() {
+        let dir = scratch_dir();
+        let service = service(AppConfig::default(), &dir);
+        let data = b"quarterly payroll: alice 4200, bob 3900\n".repeat(200);
+        let checksum = hex::encode(Sha256::digest(&data));
+        let shows = |wire: &[u8], what: &str| wire.windows(what.len()).any(|window| window == what.as_bytes());
+
+        // A peer we hold no key for is offered the file as ever
+        let path = dir.join("payroll-february.csv");
+        std::fs::write(&path, &data).unwrap();
+        let (sent, _, forwarded, _) = pipe_between((&service, &dir), (&service, &dir), path).await;
+        sent.unwrap();
+        assert!(shows(&forwarded.unwrap(), "payroll-february"));
+
+        let identity = service.peers.read().await.identity();
+        sealing_peer(&service, &identity).await;
+        let path = dir.join("payroll-march.csv");
+        std::fs::write(&path, &data).unwrap();
+        let (sent, received, forwarded, _) = pipe_between((&service, &dir), (&service, &dir), path).await;
+        // Checked against the checksum it was sent under
+        assert!(sent.unwrap().peer_ack);
+        assert!(matches!(received, Ok(ReceiveOutcome::Complete)));
+        assert_eq!(std::fs::read(dir.join("downloads").join("payroll-march.csv")).unwrap(), data);
+        let forwarded = forwarded.unwrap();
+        assert!(shows(&forwarded, r#""sealed":["#));
+        assert!(!shows(&forwarded, "payroll-march"));
+        assert!(!shows(&forwarded, &checksum));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_request_sealed_to_someone_else_is_refused_unread() {
+        let (ours, theirs) = (scratch_dir(), scratch_dir());
+        let us = service(AppConfig::default(), &ours);
+        let them = service(AppConfig::default(), &theirs);
+        // The key we hold for their address is someone else's
+        sealing_peer(&us, &Identity::generate()).await;
+        let path = ours.join("secret.txt");
+        std::fs::write(&path, b"for the pinned peer only").unwrap();
+
+        let (sent, received, _, _) = pipe_between((&us, &ours), (&them, &theirs), path).await;
+        let error = sent.err().expect("the send is refused").to_string();
+        assert!(error.starts_with("Transfer rejected by peer: Could not open the sealed request"), "{}", error);
+        assert!(matches!(received, Ok(ReceiveOutcome::Refused(_))));
+        assert!(downloaded_files(&theirs).is_empty());
+        let _ = std::fs::remove_dir_all(&ours);
+        let _ = std::fs::remove_dir_all(&theirs);
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_mac_under_the_shared_secret_are_refused() {
+        let dir = scratch_dir();
+        let mut config = limited_config(1);
+        config.transfer.shared_secret = Some("correct horse".to_string());
+        let service = service(config, &dir);
+        let data = sample_data();
+        let checksum = hex::encode(Sha256::digest(&data));
+        let mac_under = |secret: &str, transfer_id: Uuid, file_size: u64| {
+            crypto::request_mac(secret, transfer_id, "notes.txt", file_size, Some(&checksum), None)
+        };
+
+        let size = data.len() as u64;
+        for case in ["none", "not hex", "another secret", "another offer"] {
+            let transfer_id = Uuid::new_v4();
+            let mac = match case {
+                "none" => None,
+                "not hex" => Some(case.to_string()),
+                "another secret" => Some(mac_under("battery staple", transfer_id, size)),
+                _ => Some(mac_under("correct horse", transfer_id, size + 1)),
+            };
+            let mut offer = request(transfer_id, "notes.txt", &data);
+            if let TransferMessage::Request { mac: offered, .. } = &mut offer {
+                *offered = mac;
+            }
+            let (receiving, mut sender) = receive(context(&service, &dir), &offer);
+            match sender.recv().await {
+                TransferMessage::Reject { reason, .. } => assert_eq!(reason.as_deref(), Some(BAD_SHARED_SECRET)),
+                other => panic!("expected a rejection, got {:?}", other),
+            }
+            assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Refused(_))));
+        }
+        assert!(downloaded_files(&dir).is_empty());
+
+        let transfer_id = Uuid::new_v4();
+        let mut offer = request(transfer_id, "notes.txt", &data);
+        if let TransferMessage::Request { mac, .. } = &mut offer {
+            *mac = Some(mac_under("correct horse", transfer_id, size));
+        }
+        let (receiving, mut sender) = receive(context(&service, &dir), &offer);
+        assert!(matches!(sender.recv().await, TransferMessage::Accept { .. }));
+        sender.send_chunks(transfer_id, &data).await;
+        sender.complete(transfer_id, None).await;
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Complete)));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn only_peers_sharing_the_secret_or_both_without_one_get_through() {
+        let with_secret = |secret: Option<&str>| {
+            let mut config = AppConfig::default();
+            config.transfer.shared_secret = secret.map(str::to_string);
+            config
+        };
+        let pairings = [
+            (Some("correct horse"), Some("correct horse"), true),
+            (None, None, true),
+            // Only the receiver checks
+            (Some("correct horse"), None, true),
+            (None, Some("correct horse"), false),
+            (Some("battery staple"), Some("correct horse"), false),
+        ];
+        for (sender_secret, receiver_secret, delivered) in pairings {
+            let (ours, theirs) = (scratch_dir(), scratch_dir());
+            let us = service(with_secret(sender_secret), &ours);
+            let them = service(with_secret(receiver_secret), &theirs);
+            let path = ours.join("notes.txt");
+            std::fs::write(&path, b"for peers that know the secret").unwrap();
+
+            let (sent, received, _, _) = pipe_between((&us, &ours), (&them, &theirs), path).await;
+            if delivered {
+                sent.unwrap();
+                assert!(matches!(received, Ok(ReceiveOutcome::Complete)));
+            } else {
+                let error = sent.err().expect("the send is refused").to_string();
+                assert_eq!(error, format!("Transfer rejected by peer: {}", BAD_SHARED_SECRET));
+                assert!(matches!(received, Ok(ReceiveOutcome::Refused(_))));
+                assert!(downloaded_files(&theirs).is_empty());
+            }
+            let _ = std::fs::remove_dir_all(&ours);
+            let _ = std::fs::remove_dir_all(&theirs);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_sender_cancelling_part_way_leaves_no_partial_file() {
+        let dir = scratch_dir();
+        let service = service(limited_config(1), &dir);
+        let data = sample_data();
+        let transfer_id = Uuid::new_v4();
+        let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, "half.bin", &data));
+        assert!(matches!(sender.recv().await, TransferMessage::Accept { .. }));
+        sender.send_chunks(transfer_id, &data[..2 * CHUNK]).await;
+        sender.send(&TransferMessage::Cancel { transfer_id }).await;
+
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::CancelledBySender)));
+        assert!(downloaded_files(&dir).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_receive_whose_data_stops_moving_fails_as_stalled() {
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        config.transfer.chunk_size = CHUNK;
+        config.transfer.stall_timeout_secs = Some(1);
+        let service = service(config, &dir);
+        let data = sample_data();
+        let transfer_id = Uuid::new_v4();
+        let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, "wedged.bin", &data));
+        assert!(matches!(sender.recv().await, TransferMessage::Accept { .. }));
+        // Half the file, then nothing, with the connection left open
+        sender.send_chunks(transfer_id, &data[..2 * CHUNK]).await;
+
+        let outcome = tokio::select! {
+            outcome = timeout(Duration::from_secs(10), receiving) => outcome.unwrap().unwrap(),
+            _ = service.watch_for_stalls() => unreachable!("the watchdog runs until stopped"),
+        };
+        let error = outcome.err().expect("the receive fails");
+        assert_eq!(TransferError::code_of(&error).as_deref(), Some("stalled"));
+        let TransferMessage::Error { code, .. } = sender.recv().await else {
+            panic!("expected the sender to be told");
+        };
+        assert_eq!(code.as_deref(), Some("stalled"));
+        assert!(downloaded_files(&dir).is_empty());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn a_paused_send_outlasts_every_timeout_and_finishes_once_resumed() {
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        config.transfer.chunk_size = CHUNK;
+        config.transfer.send_stall_timeout = 1;
+        config.transfer.stall_timeout_secs = Some(1);
+        let service = service(config, &dir);
+        let data = sample_data();
+        let path = dir.join("outgoing.bin");
+        std::fs::write(&path, &data).unwrap();
+        let transfer_id = Uuid::new_v4();
+        let (sending, mut receiver) = send(context(&service, &dir), service.track_send(transfer_id), path);
+
+        assert!(matches!(receiver.recv().await, TransferMessage::Request { .. }));
+        assert!(service.active_transfers().set_paused(transfer_id, Direction::Send, true));
+        receiver
+            .send(&TransferMessage::Accept {
+                transfer_id,
+                public_key: None,
+                identity_key: None,
+                protocol_version: Some(PROTOCOL_VERSION),
+                window: None,
+                framed: false,
+                compression: None,
+                chunk_checksums: false,
+                parallel_streams: 0,
+            })
+            .await;
+        assert!(matches!(receiver.recv().await, TransferMessage::Pause { .. }));
+
+        // Silent for longer than the send may stall or the watchdog allows
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(2500)) => {}
+            _ = service.watch_for_stalls() => unreachable!("the watchdog runs until stopped"),
+        }
+        assert!(!sending.is_finished());
+
+        assert!(service.active_transfers().set_paused(transfer_id, Direction::Send, false));
+        assert!(matches!(receiver.recv().await, TransferMessage::Resume { .. }));
+        let mut received = Vec::new();
+        loop {
+            match receiver.recv().await {
+                TransferMessage::Chunk { data, .. } => received.extend(data),
+                TransferMessage::Complete { .. } => break,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        assert_eq!(received, data);
+        drop(receiver);
+        assert!(sending.await.unwrap().is_ok());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn pausing_a_receive_holds_the_sender_until_resumed() {
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        config.transfer.chunk_size = CHUNK;
+        config.transfer.stall_timeout_secs = Some(1);
+        let service = service(config, &dir);
+        let data = sample_data();
+        let transfer_id = Uuid::new_v4();
+        let (receiving, mut sender) = receive(context(&service, &dir), &request(transfer_id, "held.bin", &data));
+        assert!(matches!(sender.recv().await, TransferMessage::Accept { .. }));
+        sender.send_chunks(transfer_id, &data[..2 * CHUNK]).await;
+
+        assert!(service.active_transfers().set_paused(transfer_id, Direction::Receive, true));
+        assert!(matches!(sender.recv().await, TransferMessage::Pause { .. }));
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(2500)) => {}
+            _ = service.watch_for_stalls() => unreachable!("the watchdog runs until stopped"),
+        }
+        assert!(!receiving.is_finished());
+
+        assert!(service.active_transfers().set_paused(transfer_id, Direction::Receive, false));
+        assert!(matches!(sender.recv().await, TransferMessage::Resume { .. }));
+        for (index, chunk) in data.chunks(CHUNK).enumerate().skip(2) {
+            let chunk = TransferMessage::Chunk {
+                transfer_id,
+                chunk_index: index as u64,
+                data: chunk.to_vec(),
+                crc: None,
+            };
+            sender.send(&chunk).await;
+        }
+        sender.complete(transfer_id, None).await;
+        assert!(matches!(receiving.await.unwrap(), Ok(ReceiveOutcome::Complete)));
+        assert_eq!(std::fs::read(dir.join("downloads").join("held.bin")).unwrap(), data);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    /// Serves one connection to `service` on a loopback port, as its listener
+    /// would, and returns the address to reach it at.
+    pub(crate) async fn serve_once(service: &TransferService, dir: &Path, known: bool) -> (SocketAddr, JoinHandle<()>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let context = context(service, dir);
+        let refusals = service.refusals.clone();
+        let serving = tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            if known {
+                let mut progress = None;
+                TransferService::handle_connection(stream.into(), addr, context, &mut progress).await.unwrap();
+            } else {
+                TransferService::greet_stranger(stream, addr, context, refusals).await;
+            }
+        });
+        (address, serving)
+    }
+
+    #[tokio::test]
+    async fn peer_addresses_take_the_transfer_port_by_default() {
+        let resolve = |address: &'static str| TransferService::resolve(address, 7879);
+        assert_eq!(resolve("127.0.0.1").await.unwrap(), SocketAddr::from(([127, 0, 0, 1], 7879)));
+        assert_eq!(resolve("127.0.0.1:9000").await.unwrap(), SocketAddr::from(([127, 0, 0, 1], 9000)));
+        assert_eq!(resolve("::1").await.unwrap(), "[::1]:7879".parse().unwrap());
+        assert_eq!(resolve("[::1]:9000").await.unwrap(), "[::1]:9000".parse().unwrap());
+        assert!(resolve("127.0.0.1:notaport").await.is_err());
+    }
+
+    /// Measures the tuned transfer sockets against plain ones over loopback:
+    /// control message round trips, then a bulk stream. Left out of normal
+    /// runs; use `cargo test --release socket_tuning -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn socket_tuning_against_plain_sockets() {
+        const ROUND_TRIPS: usize = 200;
+        const BULK: usize = 512 * 1024 * 1024;
+        let dir = scratch_dir();
+        let mut config = AppConfig::default();
+        config.transfer.socket_send_buffer = Some(4 * 1024 * 1024);
+        config.transfer.socket_recv_buffer = Some(4 * 1024 * 1024);
+        let service = service(config, &dir);
+
+        for tuned in [false, true] {
+            let loopback = SocketAddr::from(([127, 0, 0, 1], 0));
+            let listener = match tuned {
+                true => service.bind_listener(loopback).unwrap(),
+                false => TcpListener::bind(loopback).await.unwrap(),
+            };
+            let address = listener.local_addr().unwrap();
+            let transfer = service.config.transfer.clone();
+            // Answers each message as a peer would, then takes data until closed
+            let serving = tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                if tuned {
+                    TransferService::tune_socket(&transfer, SockRef::from(&stream)).unwrap();
+                }
+                let (reader, mut writer) = stream.into_split();
+                let mut reader = BufReader::new(reader);
+                for _ in 0..ROUND_TRIPS {
+                    let message = TransferService::read_message(&mut reader, MAX_CONTROL_MESSAGE_LEN).await.unwrap();
+                    TransferService::write_message(&mut writer, &message).await.unwrap();
+                }
+                tokio::io::copy(&mut reader, &mut tokio::io::sink()).await.unwrap()
+            });
+            let stream = match tuned {
+                true => TransferService::connect(&service.config.transfer, address).await.unwrap(),
+                false => TcpStream::connect(address).await.unwrap(),
+            };
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+
+            let mut round_trips = Vec::with_capacity(ROUND_TRIPS);
+            for _ in 0..ROUND_TRIPS {
+                let started = Instant::now();
+                TransferService::write_message(&mut writer, &TransferMessage::SpaceQuery).await.unwrap();
+                TransferService::read_message(&mut reader, MAX_CONTROL_MESSAGE_LEN).await.unwrap();
+                round_trips.push(started.elapsed());
+            }
+            round_trips.sort();
+
+            let block = vec![7u8; 64 * 1024];
+            let started = Instant::now();
+            for _ in 0..BULK / block.len() {
+                writer.write_all(&block).await.unwrap();
+            }
+            writer.shutdown().await.unwrap();
+            assert_eq!(serving.await.unwrap(), BULK as u64);
+            let throughput = BULK as f64 / started.elapsed().as_secs_f64() / (1024.0 * 1024.0);
+            println!(
+                "{}: round trip median {:?}, p99 {:?}, mean {:?}; bulk {:.0} MiB/s",
+                if tuned { "tuned" } else { "plain" },
+                round_trips[ROUND_TRIPS / 2],
+                round_trips[ROUND_TRIPS * 99 / 100],
+                round_trips.iter().sum::<Duration>() / ROUND_TRIPS as u32,
+                throughput
+            );
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
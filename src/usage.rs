@@ -0,0 +1,220 @@
+use crate::utils;
+use chrono::{Local, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Kept in the data directory next to the transfer history.
+pub const USAGE_FILE: &str = "usage.json";
+/// Longest counters go unsaved while transfers run.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// Days of counters kept.
+const KEPT_DAYS: u64 = 400;
+
+/// Bytes moved on one day with one peer, or a total of those.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageRow {
+    /// The local calendar day; unset in totals per peer.
+    pub day: Option<NaiveDate>,
+    /// The peer; unset in totals per day, and for senders we couldn't tell.
+    pub peer_id: Option<Uuid>,
+    /// Chunk bytes we sent.
+    pub sent_bytes: u64,
+    /// Chunk bytes we received.
+    pub received_bytes: u64,
+}
+
+/// How GetBandwidthUsage adds up its rows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UsageGrouping {
+    #[default]
+    Day,
+    Peer,
+}
+
+/// Bytes moved per local day and peer, counted chunk by chunk as transfers
+/// go so a cancelled transfer counts what it really moved. Also enforces
+/// `transfer.daily_budget_bytes`.
+pub struct UsageLog {
+    path: PathBuf,
+    daily_budget: Option<u64>,
+    state: Mutex<UsageState>,
+}
+
+struct UsageState {
+    counts: BTreeMap<(NaiveDate, Option<Uuid>), (u64, u64)>,
+    unsaved: bool,
+    saved: Instant,
+}
+
+impl UsageLog {
+    /// Counters saved in `path`, starting from what it holds.
+    pub fn load(path: PathBuf, daily_budget: Option<u64>) -> Self {
+        let rows: Vec<UsageRow> = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring unreadable bandwidth usage in {}: {}", path.display(), e);
+                Vec::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => {
+                tracing::warn!("Couldn't read bandwidth usage from {}: {}", path.display(), e);
+                Vec::new()
+            }
+        };
+        let counts = rows
+            .into_iter()
+            .filter_map(|row| Some(((row.day?, row.peer_id), (row.sent_bytes, row.received_bytes))))
+            .collect();
+        Self {
+            path,
+            daily_budget: daily_budget.filter(|budget| *budget > 0),
+            state: Mutex::new(UsageState {
+                counts,
+                unsaved: false,
+                saved: Instant::now(),
+            }),
+        }
+    }
+
+    /// Counts `bytes` sent to `peer_id` today.
+    pub fn record_sent(&self, peer_id: Option<Uuid>, bytes: usize) {
+        self.record(peer_id, bytes as u64, 0);
+    }
+
+    /// Counts `bytes` received from `peer_id` today.
+    pub fn record_received(&self, peer_id: Option<Uuid>, bytes: usize) {
+        self.record(peer_id, 0, bytes as u64);
+    }
+
+    fn record(&self, peer_id: Option<Uuid>, sent: u64, received: u64) {
+        let mut state = self.state.lock().unwrap();
+        let counts = state.counts.entry((today(), peer_id)).or_default();
+        counts.0 += sent;
+        counts.1 += received;
+        state.unsaved = true;
+        if state.saved.elapsed() >= SAVE_INTERVAL {
+            if let Err(e) = self.save(&mut state) {
+                tracing::warn!("Couldn't save bandwidth usage: {}", e);
+            }
+        }
+    }
+
+    /// Bytes moved today, both ways and with everyone.
+    pub fn used_today(&self) -> u64 {
+        let today = today();
+        let state = self.state.lock().unwrap();
+        state
+            .counts
+            .range((today, None)..)
+            .map(|(_, (sent, received))| sent + received)
+            .sum()
+    }
+
+    /// The daily budget, if one is set.
+    pub fn daily_budget(&self) -> Option<u64> {
+        self.daily_budget
+    }
+
+    /// The daily budget, if today's is used up; no new transfer starts
+    /// until midnight, local time.
+    pub fn exhausted_budget(&self) -> Option<u64> {
+        self.daily_budget.filter(|budget| self.used_today() >= *budget)
+    }
+
+    /// Totals per day or per peer, from `since` on, oldest day or lowest
+    /// peer id first.
+    pub fn report(&self, since: Option<NaiveDate>, group_by: UsageGrouping) -> Vec<UsageRow> {
+        let state = self.state.lock().unwrap();
+        let mut totals: BTreeMap<(Option<NaiveDate>, Option<Uuid>), UsageRow> = BTreeMap::new();
+        for (&(day, peer_id), &(sent, received)) in &state.counts {
+            if since.is_some_and(|since| day < since) {
+                continue;
+            }
+            let key = match group_by {
+                UsageGrouping::Day => (Some(day), None),
+                UsageGrouping::Peer => (None, peer_id),
+            };
+            let row = totals.entry(key).or_insert(UsageRow {
+                day: key.0,
+                peer_id: key.1,
+                ..UsageRow::default()
+            });
+            row.sent_bytes += sent;
+            row.received_bytes += received;
+        }
+        totals.into_values().collect()
+    }
+
+    /// Writes counters not yet saved.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.unsaved {
+            self.save(&mut state)?;
+        }
+        Ok(())
+    }
+
+    fn save(&self, state: &mut UsageState) -> std::io::Result<()> {
+        state.saved = Instant::now();
+        if let Some(oldest) = today().checked_sub_days(chrono::Days::new(KEPT_DAYS)) {
+            state.counts.retain(|(day, _), _| *day > oldest);
+        }
+        let rows: Vec<UsageRow> = state
+            .counts
+            .iter()
+            .map(|(&(day, peer_id), &(sent_bytes, received_bytes))| UsageRow {
+                day: Some(day),
+                peer_id,
+                sent_bytes,
+                received_bytes,
+            })
+            .collect();
+        utils::write_atomic(&self.path, &serde_json::to_vec_pretty(&rows)?)?;
+        state.unsaved = false;
+        Ok(())
+    }
+}
+
+fn today() -> NaiveDate {
+    Local::now().date_naive()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_add_up_by_day_and_peer_and_survive_a_restart() {
+        let path = std::env::temp_dir().join(format!("p2p-sharing-usage-{}.json", Uuid::new_v4()));
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let usage = UsageLog::load(path.clone(), Some(1000));
+        usage.record_sent(Some(alice), 300);
+        usage.record_received(Some(bob), 200);
+        usage.record_received(None, 100);
+        assert_eq!(usage.used_today(), 600);
+        assert_eq!(usage.exhausted_budget(), None);
+        usage.flush().unwrap();
+
+        let usage = UsageLog::load(path.clone(), Some(1000));
+        let days = usage.report(None, UsageGrouping::Day);
+        assert_eq!(days.len(), 1);
+        assert_eq!((days[0].day, days[0].sent_bytes, days[0].received_bytes), (Some(today()), 300, 300));
+        let peers = usage.report(None, UsageGrouping::Peer);
+        assert_eq!(peers.len(), 3);
+        assert!(peers.contains(&UsageRow {
+            day: None,
+            peer_id: Some(alice),
+            sent_bytes: 300,
+            received_bytes: 0,
+        }));
+        assert!(usage.report(today().succ_opt(), UsageGrouping::Day).is_empty());
+
+        usage.record_sent(Some(alice), 400);
+        assert_eq!(usage.exhausted_budget(), Some(1000));
+        let _ = std::fs::remove_file(&path);
+    }
+}
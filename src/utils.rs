@@ -1,11 +1,117 @@
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 use sha2::{Digest, Sha256};
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
-use mime_guess;
 
-pub fn get_local_ip() -> Option<Ipv4Addr> {
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalInterface {
+    pub name: String,
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+}
+
+/// Lists the usable IPv4 addresses of this machine, skipping loopback and
+/// link-local (169.254.x.x) addresses.
+pub fn list_local_ips() -> Vec<LocalInterface> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            tracing::warn!("Failed to enumerate network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+
+    interfaces
+        .into_iter()
+        .filter_map(|iface| match iface.addr {
+            if_addrs::IfAddr::V4(addr) if is_usable_address(addr.ip) => {
+                Some(LocalInterface {
+                    name: iface.name,
+                    ip: addr.ip,
+                    netmask: addr.netmask,
+                })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Loopback and link-local addresses can't be reached by other machines.
+fn is_usable_address(ip: Ipv4Addr) -> bool {
+    !ip.is_loopback() && !ip.is_link_local()
+}
+
+/// Picks the address this machine should advertise to peers.
+///
+/// Interfaces are enumerated locally, so this works on air-gapped networks.
+/// The outbound-route lookup is only used to break ties between several
+/// candidate interfaces.
+pub fn get_local_ip(preferred_interface: Option<&str>) -> Option<Ipv4Addr> {
+    get_local_interface(preferred_interface).map(|iface| iface.ip)
+}
+
+pub fn get_local_interface(preferred_interface: Option<&str>) -> Option<LocalInterface> {
+    let interfaces = list_local_ips();
+    select_local_interface(&interfaces, preferred_interface, route_local_ip()).cloned()
+}
+
+/// Every address to advertise to peers, the one `get_local_ip` picks first.
+/// With a configured interface, only that one's.
+pub fn get_local_ips(preferred_interface: Option<&str>) -> Vec<Ipv4Addr> {
+    advertised_ips(&list_local_ips(), preferred_interface, route_local_ip())
+}
+
+/// Our transfer addresses on `port` as announced to peers, the main one
+/// first; just localhost when there's no usable interface.
+pub fn advertised_addresses(preferred_interface: Option<&str>, port: u16) -> Vec<SocketAddr> {
+    let ips = get_local_ips(preferred_interface);
+    if ips.is_empty() {
+        return vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)];
+    }
+    ips.into_iter().map(|ip| SocketAddr::new(IpAddr::V4(ip), port)).collect()
+}
+
+fn advertised_ips(interfaces: &[LocalInterface], preferred_interface: Option<&str>, route_hint: Option<Ipv4Addr>) -> Vec<Ipv4Addr> {
+    let Some(primary) = select_local_interface(interfaces, preferred_interface, route_hint) else {
+        return Vec::new();
+    };
+    if preferred_interface == Some(primary.name.as_str()) {
+        return vec![primary.ip];
+    }
+    let others = interfaces.iter().map(|iface| iface.ip).filter(|ip| *ip != primary.ip);
+    std::iter::once(primary.ip).chain(others).collect()
+}
+
+fn select_local_interface<'a>(
+    interfaces: &'a [LocalInterface],
+    preferred_interface: Option<&str>,
+    route_hint: Option<Ipv4Addr>,
+) -> Option<&'a LocalInterface> {
+    if let Some(name) = preferred_interface {
+        if let Some(iface) = interfaces.iter().find(|i| i.name == name) {
+            return Some(iface);
+        }
+        tracing::warn!("Configured interface {} has no usable IPv4 address", name);
+    }
+
+    if let Some(hint) = route_hint {
+        if let Some(iface) = interfaces.iter().find(|i| i.ip == hint) {
+            return Some(iface);
+        }
+    }
+
+    interfaces
+        .iter()
+        .find(|i| i.ip.is_private())
+        .or_else(|| interfaces.first())
+}
+
+/// Asks the OS which local address it would use to reach the internet.
+/// No packet is sent; this fails when there is no default route.
+fn route_local_ip() -> Option<Ipv4Addr> {
     let socket = std::net::UdpSocket::bind("0.0.0.0:0").ok()?;
     socket.connect("8.8.8.8:80").ok()?;
     let addr = socket.local_addr().ok()?;
@@ -16,19 +122,82 @@ pub fn get_local_ip() -> Option<Ipv4Addr> {
     }
 }
 
-pub fn get_broadcast_address() -> String {
-    if let Some(ip) = get_local_ip() {
-        let octets = ip.octets();
-        format!("{}.{}.{}.255", octets[0], octets[1], octets[2])
-    } else {
-        "255.255.255.255".to_string()
+/// Returns the directed broadcast address of the active interface's subnet,
+/// or the limited broadcast address when no interface is available.
+pub fn get_broadcast_address(preferred_interface: Option<&str>) -> Ipv4Addr {
+    match get_local_interface(preferred_interface) {
+        Some(iface) => subnet_broadcast(iface.ip, iface.netmask),
+        None => Ipv4Addr::BROADCAST,
+    }
+}
+
+pub fn subnet_broadcast(ip: Ipv4Addr, netmask: Ipv4Addr) -> Ipv4Addr {
+    if netmask.is_unspecified() {
+        return Ipv4Addr::BROADCAST;
+    }
+    Ipv4Addr::from(u32::from(ip) | !u32::from(netmask))
+}
+
+/// An address range in CIDR notation, e.g. "192.168.1.0/24". A bare address
+/// matches only itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = match s.trim().split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (s.trim().parse::<IpAddr>()?, None),
+        };
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max_prefix);
+        if prefix > max_prefix {
+            anyhow::bail!("prefix /{} is too long for {}", prefix, addr);
+        }
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
     }
 }
 
+/// Minimum time between progress callbacks while hashing a file.
+const CHECKSUM_PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
 pub async fn calculate_file_checksum(file_path: &Path) -> anyhow::Result<String> {
+    calculate_file_checksum_with_progress(file_path, |_, _| {}).await
+}
+
+/// Hashes a file like `calculate_file_checksum`, calling `on_progress` with
+/// (bytes hashed, total bytes) at most every 200ms and once when finished.
+pub async fn calculate_file_checksum_with_progress<F>(
+    file_path: &Path,
+    mut on_progress: F,
+) -> anyhow::Result<String>
+where
+    F: FnMut(u64, u64),
+{
     let mut file = File::open(file_path).await?;
+    let total = file.metadata().await?.len();
     let mut hasher = Sha256::new();
     let mut buffer = vec![0u8; 65536]; // 64KB buffer
+    let mut hashed = 0u64;
+    let mut last_report = Instant::now();
     
     loop {
         let n = file.read(&mut buffer).await?;
@@ -36,18 +205,314 @@ pub async fn calculate_file_checksum(file_path: &Path) -> anyhow::Result<String>
             break;
         }
         hasher.update(&buffer[..n]);
+        hashed += n as u64;
+
+        if last_report.elapsed() >= CHECKSUM_PROGRESS_INTERVAL {
+            on_progress(hashed, total);
+            last_report = Instant::now();
+            // Give other tasks a turn on single-threaded runtimes
+            tokio::task::yield_now().await;
+        }
     }
+    on_progress(hashed, total);
     
     let hash = hasher.finalize();
     Ok(hex::encode(hash))
 }
 
+/// Hashes a file on the calling thread, for `spawn_blocking`, reporting
+/// progress like `calculate_file_checksum_with_progress`.
+pub fn checksum_file_blocking<F>(file_path: &Path, mut on_progress: F) -> std::io::Result<String>
+where
+    F: FnMut(u64, u64),
+{
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(file_path)?;
+    let total = file.metadata()?.len();
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 65536];
+    let mut hashed = 0u64;
+    let mut last_report = Instant::now();
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        hashed += n as u64;
+        if last_report.elapsed() >= CHECKSUM_PROGRESS_INTERVAL {
+            on_progress(hashed, total);
+            last_report = Instant::now();
+        }
+    }
+    on_progress(hashed, total);
+    Ok(hex::encode(hasher.finalize()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiskSpace {
+    pub available: u64,
+    pub total: u64,
+}
+
+/// Returns the free and total space of the filesystem holding `path`.
+/// If `path` doesn't exist yet, its nearest existing ancestor is used.
+pub fn available_space(path: &Path) -> std::io::Result<DiskSpace> {
+    let existing = path
+        .ancestors()
+        .find(|p| p.exists())
+        .unwrap_or_else(|| Path::new("."));
+    disk_space(existing)
+}
+
+#[cfg(unix)]
+fn disk_space(path: &Path) -> std::io::Result<DiskSpace> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let block_size = stat.f_frsize as u64;
+    Ok(DiskSpace {
+        available: stat.f_bavail as u64 * block_size,
+        total: stat.f_blocks as u64 * block_size,
+    })
+}
+
+#[cfg(windows)]
+fn disk_space(path: &Path) -> std::io::Result<DiskSpace> {
+    use std::os::windows::ffi::OsStrExt;
+    use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(std::iter::once(0)).collect();
+    let mut available = 0u64;
+    let mut total = 0u64;
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(wide.as_ptr(), &mut available, &mut total, std::ptr::null_mut())
+    };
+    if ok == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(DiskSpace { available, total })
+}
+
+/// Sets the permission bits of `path`. Returns false where files have none,
+/// on Windows.
+#[cfg(unix)]
+pub fn set_mode(path: &Path, mode: u32) -> std::io::Result<bool> {
+    use std::os::unix::fs::PermissionsExt;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(true)
+}
+
+#[cfg(windows)]
+pub fn set_mode(_path: &Path, _mode: u32) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+/// Gives `path` to `group`, a name or a numeric id. Returns false where
+/// files have no group, on Windows.
+#[cfg(unix)]
+pub fn set_group(path: &Path, group: &str) -> std::io::Result<bool> {
+    let gid = match group.parse() {
+        Ok(gid) => gid,
+        Err(_) => group_id(group)?,
+    };
+    std::os::unix::fs::chown(path, None, Some(gid))?;
+    Ok(true)
+}
+
+#[cfg(windows)]
+pub fn set_group(_path: &Path, _group: &str) -> std::io::Result<bool> {
+    Ok(false)
+}
+
+#[cfg(unix)]
+fn group_id(name: &str) -> std::io::Result<u32> {
+    let c_name = std::ffi::CString::new(name)?;
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    let mut found: *mut libc::group = std::ptr::null_mut();
+    let rc = unsafe { libc::getgrnam_r(c_name.as_ptr(), &mut group, buffer.as_mut_ptr(), buffer.len(), &mut found) };
+    if rc != 0 {
+        return Err(std::io::Error::from_raw_os_error(rc));
+    }
+    if found.is_null() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("no group named {:?}", name)));
+    }
+    Ok(group.gr_gid)
+}
+
+/// Creates `dir` if it isn't there and checks a file can be written in it.
+pub fn ensure_writable_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let probe = dir.join(format!(".write-test-{}", std::process::id()));
+    std::fs::File::create(&probe)?;
+    std::fs::remove_file(&probe)
+}
+
+/// Replaces `path` with `contents` in one step: they're written beside it
+/// and renamed over it, so a crash mid-write leaves the old file intact.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = std::path::PathBuf::from(temp);
+    let mut file = std::fs::File::create(&temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    drop(file);
+    std::fs::rename(&temp, path)
+}
+
+/// Returns the MIME type claimed by the file's extension.
 pub fn get_mime_type(file_path: &Path) -> Option<String> {
     mime_guess::from_path(file_path)
         .first()
         .map(|m| m.to_string())
 }
 
+/// Number of leading bytes inspected when sniffing a file's content type.
+pub const MIME_SNIFF_LEN: u64 = 8192;
+
+/// Detects the MIME type from the file's leading bytes, falling back to the
+/// extension when the content isn't recognised. Only the first 8KB are read.
+pub async fn detect_mime_type(file_path: &Path) -> Option<String> {
+    let mut header = Vec::with_capacity(MIME_SNIFF_LEN as usize);
+    if let Ok(file) = File::open(file_path).await {
+        let _ = file.take(MIME_SNIFF_LEN).read_to_end(&mut header).await;
+    }
+
+    sniff_mime_type(&header).or_else(|| get_mime_type(file_path))
+}
+
+/// Content type recognised from a file's leading bytes, if any.
+pub fn sniff_mime_type(header: &[u8]) -> Option<String> {
+    infer::get(header).map(|kind| kind.mime_type().to_string())
+}
+
+/// True when both types are known and the content contradicts the extension.
+pub fn is_mime_mismatch(claimed: Option<&str>, detected: Option<&str>) -> bool {
+    matches!((claimed, detected), (Some(claimed), Some(detected)) if claimed != detected)
+}
+
+/// Longest filename most filesystems will store, in bytes.
+const MAX_FILENAME_LEN: usize = 255;
+/// Names Windows keeps for devices, whatever the extension.
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// What separates the parts of a path here. A backslash only does on
+/// Windows; elsewhere it's an ordinary character, if one Windows can't hold.
+const PATH_SEPARATORS: &[char] = if cfg!(windows) { &['/', '\\'] } else { &['/'] };
+
+/// Turns a filename a peer gave us into one that is safe to create in
+/// downloads: only the last path component, without characters some
+/// filesystems reject, and without leading dots so it can't hide or land on
+/// our own dot-directories. Device names like "CON" get a leading "_".
+/// Falls back to "download" when nothing is left.
+pub fn safe_filename(name: &str) -> String {
+    let base = name.rsplit(PATH_SEPARATORS).next().unwrap_or_default();
+    let replaced: String = base
+        .chars()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '|' | '?' | '*' | '\\' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+    let mut safe = replaced.trim().trim_start_matches('.').trim_end_matches(['.', ' ']).to_string();
+    let stem = safe.split('.').next().unwrap_or_default().trim_end();
+    if RESERVED_NAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved)) {
+        safe.insert(0, '_');
+    }
+    if safe.len() > MAX_FILENAME_LEN {
+        // Cut from the stem, so the extension stays, and trim again what the
+        // cut leaves at its end
+        let (stem, extension) = match safe.rsplit_once('.') {
+            Some((stem, extension)) if !stem.is_empty() && extension.len() < MAX_FILENAME_LEN / 2 => (stem, Some(extension)),
+            _ => (safe.as_str(), None),
+        };
+        let room = MAX_FILENAME_LEN - extension.map_or(0, |extension| extension.len() + 1);
+        let mut cut = stem.to_string();
+        while cut.len() > room {
+            cut.pop();
+        }
+        let mut shortened = cut.trim_end_matches(['.', ' ']).to_string();
+        if let Some(extension) = extension {
+            shortened.push('.');
+            shortened.push_str(extension);
+        }
+        safe = shortened;
+    }
+    if safe.is_empty() {
+        "download".to_string()
+    } else {
+        safe
+    }
+}
+
+/// Why a filename a peer offered can't be saved as it stands, if it can't.
+/// Our senders only ever offer a bare name, so one naming a path, or with
+/// nothing in it worth keeping, is refused rather than guessed at; anything
+/// else `safe_filename` can tidy.
+pub fn filename_refusal(name: &str) -> Option<&'static str> {
+    if name.contains(PATH_SEPARATORS) || matches!(name.trim(), "." | "..") {
+        Some("it names a path")
+    } else if !name.chars().any(|c| c.is_alphanumeric()) {
+        Some("nothing of it can be used")
+    } else {
+        None
+    }
+}
+
+/// `filename` with " (n)" before its extension, the way a second copy is
+/// saved next to the first; `filename` itself for 0.
+pub fn numbered_filename(filename: &str, n: usize) -> String {
+    if n == 0 {
+        return filename.to_string();
+    }
+    match filename.rsplit_once('.').filter(|(stem, _)| !stem.is_empty()) {
+        Some((stem, extension)) => format!("{} ({}).{}", stem, n, extension),
+        None => format!("{} ({})", filename, n),
+    }
+}
+
+/// Returns the first entry of `extensions` that `filename` has. Every
+/// extension of the name counts, not just the last, and a leading dot
+/// (hidden files) doesn't start one.
+pub fn matching_extension(filename: &str, extensions: &[String]) -> Option<String> {
+    let name = filename.trim_start_matches('.').to_lowercase();
+    let own: Vec<&str> = name.split('.').skip(1).collect();
+    extensions
+        .iter()
+        .map(|entry| entry.trim_start_matches('.').to_lowercase())
+        .find(|entry| own.contains(&entry.as_str()) || (entry.contains('.') && name.ends_with(&format!(".{}", entry))))
+}
+
+/// True when `mime_type` matches one of `patterns`, exactly or as a
+/// "type/*" family.
+pub fn mime_matches(mime_type: &str, patterns: &[String]) -> bool {
+    let mime_type = mime_type.to_lowercase();
+    patterns.iter().any(|entry| {
+        let entry = entry.to_lowercase();
+        match entry.strip_suffix("/*") {
+            Some(family) => mime_type.split('/').next() == Some(family),
+            None => mime_type == entry,
+        }
+    })
+}
+
+/// A byte count for people, e.g. "1.50 MB".
 pub fn format_bytes(bytes: u64) -> String {
     const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
     let mut size = bytes as f64;
@@ -65,6 +530,7 @@ pub fn format_bytes(bytes: u64) -> String {
     }
 }
 
+/// A speed for people, e.g. "1.50 MB/s".
 pub fn format_speed(bytes_per_sec: u64) -> String {
     format!("{}/s", format_bytes(bytes_per_sec))
 }
@@ -76,3 +542,393 @@ pub fn calculate_eta(remaining_bytes: u64, speed_bytes_per_sec: u64) -> Option<u
     Some(remaining_bytes / speed_bytes_per_sec)
 }
 
+
+pub fn average_speed(bytes: u64, elapsed: Duration) -> u64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        (bytes as f64 / secs) as u64
+    } else {
+        0
+    }
+}
+
+/// Window over which the instantaneous speed is measured.
+const SPEED_WINDOW: Duration = Duration::from_secs(1);
+/// Minimum spacing between updates of the smoothed speed.
+const SMOOTHING_INTERVAL: Duration = Duration::from_millis(250);
+/// Weight of the newest measurement in the exponential moving average.
+const SMOOTHING_FACTOR: f64 = 0.3;
+
+/// Tracks progress of a single transfer and derives speed and ETA from it.
+///
+/// Call `update` with the running byte count as data moves. The smoothed speed
+/// reacts within a second or two but doesn't jitter on every chunk, which makes
+/// it the right figure to show to users and to base the ETA on.
+#[derive(Debug, Clone)]
+pub struct ProgressTracker {
+    total: u64,
+    transferred: u64,
+    started: Instant,
+    samples: VecDeque<(Instant, u64)>,
+    smoothed: Option<f64>,
+    last_smoothed: (Instant, u64),
+}
+
+impl ProgressTracker {
+    pub fn new(total: u64) -> Self {
+        Self::starting_at(total, Instant::now())
+    }
+
+    pub fn starting_at(total: u64, now: Instant) -> Self {
+        let mut samples = VecDeque::new();
+        samples.push_back((now, 0));
+        Self {
+            total,
+            transferred: 0,
+            started: now,
+            samples,
+            smoothed: None,
+            last_smoothed: (now, 0),
+        }
+    }
+
+    pub fn update(&mut self, transferred: u64) {
+        self.update_at(transferred, Instant::now());
+    }
+
+    pub fn update_at(&mut self, transferred: u64, now: Instant) {
+        self.transferred = transferred;
+        self.samples.push_back((now, transferred));
+        while self.samples.len() > 2 {
+            match self.samples.get(1) {
+                Some((t, _)) if now.duration_since(*t) >= SPEED_WINDOW => {
+                    self.samples.pop_front();
+                }
+                _ => break,
+            }
+        }
+
+        let (last_time, last_bytes) = self.last_smoothed;
+        let interval = now.duration_since(last_time);
+        if interval >= SMOOTHING_INTERVAL {
+            let rate = transferred.saturating_sub(last_bytes) as f64 / interval.as_secs_f64();
+            self.smoothed = Some(match self.smoothed {
+                Some(prev) => SMOOTHING_FACTOR * rate + (1.0 - SMOOTHING_FACTOR) * prev,
+                None => rate,
+            });
+            self.last_smoothed = (now, transferred);
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn percentage(&self) -> f64 {
+        if self.total == 0 {
+            return 100.0;
+        }
+        (self.transferred as f64 / self.total as f64) * 100.0
+    }
+
+    /// Speed over roughly the last second.
+    pub fn instantaneous_speed(&self) -> u64 {
+        match (self.samples.front(), self.samples.back()) {
+            (Some((t0, b0)), Some((t1, b1))) => average_speed(b1.saturating_sub(*b0), t1.duration_since(*t0)),
+            _ => 0,
+        }
+    }
+
+    /// Exponentially smoothed speed; falls back to the instantaneous speed
+    /// until enough time has passed for a first measurement.
+    pub fn smoothed_speed(&self) -> u64 {
+        self.smoothed
+            .map(|s| s as u64)
+            .unwrap_or_else(|| self.instantaneous_speed())
+    }
+
+    /// Lifetime average speed.
+    pub fn average_speed(&self) -> u64 {
+        average_speed(self.transferred, self.elapsed())
+    }
+
+    pub fn eta_seconds(&self) -> Option<u64> {
+        calculate_eta(self.total.saturating_sub(self.transferred), self.smoothed_speed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: u64 = 1_000_000;
+    const STEP: Duration = Duration::from_millis(100);
+
+    /// Feeds `steps` updates of `rate` bytes per second, 100ms apart,
+    /// continuing from `bytes` at `now`. Returns the new (now, bytes).
+    fn feed(tracker: &mut ProgressTracker, now: Instant, bytes: u64, rate: u64, steps: u32) -> (Instant, u64) {
+        let (mut now, mut bytes) = (now, bytes);
+        for _ in 0..steps {
+            now += STEP;
+            bytes += rate / 10;
+            tracker.update_at(bytes, now);
+        }
+        (now, bytes)
+    }
+
+    fn iface(name: &str, ip: [u8; 4]) -> LocalInterface {
+        LocalInterface {
+            name: name.to_string(),
+            ip: Ipv4Addr::from(ip),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+        }
+    }
+
+    #[test]
+    fn unreachable_addresses_are_not_usable() {
+        let cases = [
+            ([192, 168, 1, 20], true),
+            ([10, 0, 0, 5], true),
+            ([203, 0, 113, 7], true),
+            ([127, 0, 0, 1], false),
+            ([127, 1, 2, 3], false),
+            ([169, 254, 10, 1], false),
+        ];
+        for (ip, usable) in cases {
+            assert_eq!(is_usable_address(Ipv4Addr::from(ip)), usable, "{:?}", ip);
+        }
+    }
+
+    #[test]
+    fn local_interface_selection() {
+        let wifi = iface("wlan0", [192, 168, 1, 20]);
+        let docker = iface("docker0", [172, 17, 0, 1]);
+        let public = iface("eth1", [203, 0, 113, 7]);
+        let vpn = iface("tun0", [10, 8, 0, 2]);
+
+        // (interfaces, configured interface, route hint, expected name)
+        type Case<'a> = (&'a [&'a LocalInterface], Option<&'a str>, Option<[u8; 4]>, Option<&'a str>);
+        let cases: &[Case] = &[
+            (&[], None, None, None),
+            (&[], Some("wlan0"), Some([192, 168, 1, 20]), None),
+            (&[&wifi], None, None, Some("wlan0")),
+            // The configured interface wins over everything else
+            (&[&wifi, &vpn], Some("tun0"), Some([192, 168, 1, 20]), Some("tun0")),
+            // A configured interface that's missing falls back to auto-detection
+            (&[&wifi, &vpn], Some("eth9"), None, Some("wlan0")),
+            // The route lookup breaks ties between private addresses
+            (&[&docker, &wifi], None, Some([192, 168, 1, 20]), Some("wlan0")),
+            (&[&docker, &wifi], None, None, Some("docker0")),
+            // A hint that matches no interface is ignored
+            (&[&public, &wifi], None, Some([8, 8, 4, 4]), Some("wlan0")),
+            // Private addresses are preferred, public ones are a last resort
+            (&[&public, &wifi], None, None, Some("wlan0")),
+            (&[&public], None, None, Some("eth1")),
+        ];
+
+        for (interfaces, preferred, hint, expected) in cases {
+            let interfaces: Vec<LocalInterface> = interfaces.iter().map(|i| (*i).clone()).collect();
+            let selected = select_local_interface(&interfaces, *preferred, hint.map(Ipv4Addr::from));
+            assert_eq!(
+                selected.map(|i| i.name.as_str()),
+                *expected,
+                "interfaces {:?}, preferred {:?}, hint {:?}",
+                interfaces.iter().map(|i| &i.name).collect::<Vec<_>>(),
+                preferred,
+                hint
+            );
+        }
+    }
+
+    #[test]
+    fn every_interface_is_advertised_the_selected_one_first() {
+        let interfaces = [iface("docker0", [172, 17, 0, 1]), iface("wlan0", [192, 168, 1, 20]), iface("tun0", [10, 8, 0, 2])];
+        let ips = |preferred, hint: Option<[u8; 4]>| advertised_ips(&interfaces, preferred, hint.map(Ipv4Addr::from));
+
+        let all = ips(None, Some([192, 168, 1, 20]));
+        assert_eq!(all, [[192, 168, 1, 20], [172, 17, 0, 1], [10, 8, 0, 2]].map(Ipv4Addr::from));
+        assert_eq!(ips(Some("tun0"), None), [Ipv4Addr::new(10, 8, 0, 2)]);
+        assert!(advertised_ips(&[], None, None).is_empty());
+    }
+
+    #[test]
+    fn subnet_broadcast_from_netmask() {
+        // (ip, netmask, broadcast)
+        let cases = [
+            ([10, 1, 2, 3], [255, 0, 0, 0], [10, 255, 255, 255]),
+            ([172, 16, 5, 4], [255, 255, 0, 0], [172, 16, 255, 255]),
+            ([192, 168, 5, 40], [255, 255, 252, 0], [192, 168, 7, 255]),
+            ([192, 168, 1, 20], [255, 255, 255, 0], [192, 168, 1, 255]),
+            ([192, 168, 1, 20], [255, 255, 255, 128], [192, 168, 1, 127]),
+            ([192, 168, 1, 200], [255, 255, 255, 128], [192, 168, 1, 255]),
+            ([192, 168, 1, 70], [255, 255, 255, 192], [192, 168, 1, 127]),
+            ([192, 168, 1, 9], [255, 255, 255, 252], [192, 168, 1, 11]),
+            ([192, 168, 1, 9], [255, 255, 255, 255], [192, 168, 1, 9]),
+            // An unknown mask falls back to the limited broadcast address
+            ([192, 168, 1, 20], [0, 0, 0, 0], [255, 255, 255, 255]),
+        ];
+        for (ip, netmask, broadcast) in cases {
+            assert_eq!(
+                subnet_broadcast(Ipv4Addr::from(ip), Ipv4Addr::from(netmask)),
+                Ipv4Addr::from(broadcast),
+                "{:?}/{:?}",
+                ip,
+                netmask
+            );
+        }
+    }
+
+    fn assert_close(actual: u64, expected: u64) {
+        let tolerance = expected / 100;
+        assert!(actual.abs_diff(expected) <= tolerance, "expected about {}, got {}", expected, actual);
+    }
+
+    #[test]
+    fn steady_rate_is_reported_by_every_speed() {
+        let start = Instant::now();
+        let mut tracker = ProgressTracker::starting_at(10 * MB, start);
+        feed(&mut tracker, start, 0, MB, 30);
+
+        assert_close(tracker.instantaneous_speed(), MB);
+        assert_close(tracker.smoothed_speed(), MB);
+        assert_eq!(tracker.percentage(), 30.0);
+        assert!(matches!(tracker.eta_seconds(), Some(6..=7)));
+    }
+
+    #[test]
+    fn smoothed_speed_follows_a_change_gradually() {
+        let start = Instant::now();
+        let mut tracker = ProgressTracker::starting_at(100 * MB, start);
+        let (now, bytes) = feed(&mut tracker, start, 0, MB, 20);
+
+        // One smoothing step covering 0.3s, two thirds of it at the new rate:
+        // 0.3 * 1.33 MB/s + 0.7 * 1 MB/s
+        let (now, bytes) = feed(&mut tracker, now, bytes, 2 * MB, 3);
+        assert_close(tracker.smoothed_speed(), 1_100_000);
+
+        feed(&mut tracker, now, bytes, 2 * MB, 30);
+        assert_close(tracker.instantaneous_speed(), 2 * MB);
+        let settled = tracker.smoothed_speed();
+        assert!(settled > 1_950_000 && settled <= 2 * MB, "converges on the new rate: {}", settled);
+    }
+
+    #[test]
+    fn instantaneous_speed_only_covers_the_last_second() {
+        let start = Instant::now();
+        let mut tracker = ProgressTracker::starting_at(100 * MB, start);
+        let (now, bytes) = feed(&mut tracker, start, 0, 10 * MB, 20);
+        feed(&mut tracker, now, bytes, MB, 20);
+
+        assert_close(tracker.instantaneous_speed(), MB);
+    }
+
+    #[test]
+    fn byte_count_going_backwards_does_not_panic() {
+        let start = Instant::now();
+        let mut tracker = ProgressTracker::starting_at(10 * MB, start);
+        let (now, _) = feed(&mut tracker, start, 0, MB, 10);
+
+        // A transfer that starts over from the beginning
+        tracker.update_at(0, now + Duration::from_millis(300));
+        assert_eq!(tracker.instantaneous_speed(), 0);
+        assert_eq!(tracker.percentage(), 0.0);
+        assert!(tracker.smoothed_speed() < MB);
+    }
+
+    #[test]
+    fn speed_is_zero_before_any_time_passes() {
+        let start = Instant::now();
+        let mut tracker = ProgressTracker::starting_at(MB, start);
+        tracker.update_at(1000, start);
+
+        assert_eq!(tracker.instantaneous_speed(), 0);
+        assert_eq!(tracker.smoothed_speed(), 0);
+        assert_eq!(tracker.eta_seconds(), None);
+    }
+
+    #[test]
+    fn empty_transfer_is_complete() {
+        let tracker = ProgressTracker::new(0);
+        assert_eq!(tracker.percentage(), 100.0);
+    }
+
+    #[test]
+    fn peer_filenames_are_made_safe() {
+        let cases = [
+            ("report.pdf", "report.pdf"),
+            ("../../etc/passwd", "passwd"),
+            ("/absolute/path.txt", "path.txt"),
+            ("dir/", "download"),
+            ("..", "download"),
+            ("", "download"),
+            (".bashrc", "bashrc"),
+            (".quarantine", "quarantine"),
+            ("what?.txt", "what_.txt"),
+            ("a:b|c*d", "a_b_c_d"),
+            ("tab\there", "tab_here"),
+            ("setup.exe. . ", "setup.exe"),
+            ("  padded  ", "padded"),
+            ("naïve café.txt", "naïve café.txt"),
+            ("CON", "_CON"),
+            ("nul.txt", "_nul.txt"),
+            ("Com1 .tar.gz", "_Com1 .tar.gz"),
+            ("console.log", "console.log"),
+            ("LPT10", "LPT10"),
+            ("bell\u{7}\r\n.txt", "bell___.txt"),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(safe_filename(name), expected, "{:?}", name);
+        }
+        // Only Windows takes a backslash for a separator; elsewhere it goes
+        // the way of the other characters Windows can't hold
+        let backslashed = if cfg!(windows) { ["win.ini", "slash.txt"] } else { ["_.._Windows_win.ini", "back_slash.txt"] };
+        for (name, expected) in ["..\\..\\Windows\\win.ini", "back\\slash.txt"].into_iter().zip(backslashed) {
+            assert_eq!(safe_filename(name), expected, "{:?}", name);
+        }
+
+        let long = safe_filename(&"é".repeat(200));
+        assert!(long.len() <= MAX_FILENAME_LEN);
+        assert_eq!(long, "é".repeat(MAX_FILENAME_LEN / 2));
+
+        // Shortened before its extension, and never to end in a dot or space
+        let long = safe_filename(&format!("{}.pdf", "x".repeat(300)));
+        assert_eq!(long, format!("{}.pdf", "x".repeat(MAX_FILENAME_LEN - 4)));
+        let long = safe_filename(&format!("{} .{}.pdf", "a".repeat(249), "b".repeat(20)));
+        assert_eq!(long, format!("{}.pdf", "a".repeat(249)));
+    }
+
+    #[test]
+    fn filenames_naming_a_path_or_nothing_are_refused() {
+        let refused = [
+            "../../.bashrc",
+            "../outside.bin",
+            "/etc/passwd",
+            "docs/report.pdf",
+            "..",
+            ".",
+            " .. ",
+            "",
+            "...",
+            "???",
+            "\u{0}\u{1}",
+            "   ",
+        ];
+        for name in refused {
+            assert!(filename_refusal(name).is_some(), "{:?}", name);
+        }
+        for name in ["report.pdf", ".bashrc", "CON", "what?.txt", "setup.exe. . ", "naïve café.txt", "日本語.txt"] {
+            assert_eq!(filename_refusal(name), None, "{:?}", name);
+        }
+        for name in ["C:\\Windows\\System32\\drivers\\etc\\hosts", "\\\\server\\share\\file.txt", "back\\slash.txt"] {
+            assert_eq!(filename_refusal(name).is_some(), cfg!(windows), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn copies_are_numbered_before_the_extension() {
+        assert_eq!(numbered_filename("report.pdf", 0), "report.pdf");
+        assert_eq!(numbered_filename("report.pdf", 1), "report (1).pdf");
+        assert_eq!(numbered_filename("backup.tar.gz", 2), "backup.tar (2).gz");
+        assert_eq!(numbered_filename("README", 3), "README (3)");
+    }
+}
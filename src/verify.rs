@@ -0,0 +1,213 @@
+use crate::history::{TransferHistory, TransferRecord};
+use std::path::{Component, Path, PathBuf};
+use uuid::Uuid;
+
+/// Entries looked at when searching the downloads folder for a moved file.
+const MAX_SEARCH_ENTRIES: usize = 10_000;
+
+/// Why a download can't be checked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    /// No finished download matches what was asked for.
+    UnknownDownload(String),
+    /// The download's record has no checksum to compare against.
+    NoChecksum,
+    /// Nothing is where the download was saved, and it wasn't found elsewhere.
+    Deleted,
+    /// Not where it was saved, but a file of the same name and size is at
+    /// this path, relative to the downloads folder.
+    Moved(String),
+    /// The path given leads outside the downloads folder.
+    InvalidPath,
+}
+
+impl VerifyError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            VerifyError::UnknownDownload(_) => "unknown_download",
+            VerifyError::NoChecksum => "no_checksum",
+            VerifyError::Deleted => "file_deleted",
+            VerifyError::Moved(_) => "file_moved",
+            VerifyError::InvalidPath => "invalid_path",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            VerifyError::UnknownDownload(what) => format!("No finished download {}", what),
+            VerifyError::NoChecksum => {
+                "No checksum was stored for this download, so it can't be verified".to_string()
+            }
+            VerifyError::Deleted => "The file is no longer in the downloads folder".to_string(),
+            VerifyError::Moved(found) => format!("The file has moved to {}; verify it by that path", found),
+            VerifyError::InvalidPath => "Path is outside the downloads folder".to_string(),
+        }
+    }
+}
+
+/// A download located on disk, with the checksum it arrived with.
+#[derive(Debug)]
+pub struct Target {
+    pub transfer_id: Uuid,
+    /// Relative to the downloads folder.
+    pub relative_path: String,
+    pub path: PathBuf,
+    pub expected: String,
+}
+
+/// Finds the download to check: by `transfer_id`, where it was saved, or
+/// by `relative_path`, the newest download of that file name.
+pub async fn locate(
+    history: &TransferHistory,
+    downloads_dir: &Path,
+    transfer_id: Option<Uuid>,
+    relative_path: Option<String>,
+) -> Result<Target, VerifyError> {
+    let (record, relative_path) = match (transfer_id, relative_path) {
+        (_, Some(relative_path)) => {
+            let name = file_name(&relative_path).ok_or(VerifyError::InvalidPath)?;
+            let record = history
+                .latest_received(|record| transfer_id.is_none_or(|id| record.transfer_id == id) && record.filename == name)
+                .await
+                .ok_or_else(|| VerifyError::UnknownDownload(format!("named {:?}", name)))?;
+            (record, relative_path)
+        }
+        (Some(id), None) => {
+            let record = history
+                .latest_received(|record| record.transfer_id == id)
+                .await
+                .ok_or_else(|| VerifyError::UnknownDownload(format!("with transfer id {}", id)))?;
+            let relative_path = record.relative_path.clone().unwrap_or_else(|| record.filename.clone());
+            (record, relative_path)
+        }
+        (None, None) => return Err(VerifyError::UnknownDownload("was given".to_string())),
+    };
+    let expected = record.file_checksum.clone().ok_or(VerifyError::NoChecksum)?;
+
+    let path = downloads_dir.join(&relative_path);
+    if !tokio::fs::metadata(&path).await.is_ok_and(|m| m.is_file()) {
+        return Err(search(downloads_dir, &record).await);
+    }
+    Ok(Target {
+        transfer_id: record.transfer_id,
+        relative_path,
+        path,
+        expected,
+    })
+}
+
+/// The file name of a path inside the downloads folder, if that's all it is.
+fn file_name(relative_path: &str) -> Option<String> {
+    let path = Path::new(relative_path);
+    if !path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir)) {
+        return None;
+    }
+    path.file_name().map(|name| name.to_string_lossy().to_string())
+}
+
+/// Looks through the downloads folder for a missing download.
+async fn search(downloads_dir: &Path, record: &TransferRecord) -> VerifyError {
+    let root = downloads_dir.to_path_buf();
+    let name = record.filename.clone();
+    let size = record.file_size;
+    let found = tokio::task::spawn_blocking(move || find_moved(&root, &name, size))
+        .await
+        .ok()
+        .flatten();
+    match found {
+        Some(found) => VerifyError::Moved(found),
+        None => VerifyError::Deleted,
+    }
+}
+
+/// A file named `name` of `size` bytes under `root`, as a relative path.
+/// Symlinks aren't followed.
+fn find_moved(root: &Path, name: &str, size: u64) -> Option<String> {
+    let mut pending = vec![root.to_path_buf()];
+    let mut seen = 0;
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(&dir).ok()?.flatten() {
+            seen += 1;
+            if seen > MAX_SEARCH_ENTRIES {
+                return None;
+            }
+            let Ok(file_type) = entry.file_type() else { continue };
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file()
+                && entry.file_name().to_string_lossy() == name
+                && entry.metadata().is_ok_and(|m| m.len() == size)
+            {
+                let path = entry.path();
+                return path.strip_prefix(root).ok().map(|p| p.to_string_lossy().to_string());
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("p2p-sharing-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn received(history: &TransferHistory, name: &str, size: u64, checksum: Option<&str>) -> Uuid {
+        let record = TransferRecord::new(
+            Uuid::new_v4(),
+            None,
+            "office-nas".to_string(),
+            name.to_string(),
+            String::new(),
+            size,
+            "received".to_string(),
+        );
+        let id = record.transfer_id;
+        history.start_transfer(record).await;
+        history.complete_transfer(&id, checksum.map(str::to_string), true).await;
+        id
+    }
+
+    #[tokio::test]
+    async fn downloads_are_found_by_id_or_path() {
+        let dir = scratch_dir();
+        std::fs::write(dir.join("report.pdf"), b"12345").unwrap();
+        let history = TransferHistory::new(10);
+        received(&history, "report.pdf", 5, Some("old")).await;
+        let id = received(&history, "report.pdf", 5, Some("abc")).await;
+
+        let by_id = locate(&history, &dir, Some(id), None).await.unwrap();
+        assert_eq!((by_id.relative_path.as_str(), by_id.expected.as_str()), ("report.pdf", "abc"));
+        let by_path = locate(&history, &dir, None, Some("report.pdf".to_string())).await.unwrap();
+        assert_eq!(by_path.transfer_id, id);
+        assert_eq!(
+            locate(&history, &dir, None, Some("../report.pdf".to_string())).await.unwrap_err(),
+            VerifyError::InvalidPath
+        );
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn missing_checksums_and_files_are_told_apart() {
+        let dir = scratch_dir();
+        let history = TransferHistory::new(10);
+        let unchecked = received(&history, "notes.txt", 3, None).await;
+        let deleted = received(&history, "gone.txt", 3, Some("abc")).await;
+        let moved = received(&history, "photo.jpg", 4, Some("abc")).await;
+        std::fs::create_dir_all(dir.join("sorted")).unwrap();
+        std::fs::write(dir.join("sorted").join("photo.jpg"), b"1234").unwrap();
+
+        let error = |id| locate(&history, &dir, Some(id), None);
+        assert_eq!(error(unchecked).await.unwrap_err(), VerifyError::NoChecksum);
+        assert_eq!(error(deleted).await.unwrap_err(), VerifyError::Deleted);
+        let found = Path::new("sorted").join("photo.jpg").to_string_lossy().to_string();
+        assert_eq!(error(moved).await.unwrap_err(), VerifyError::Moved(found.clone()));
+        let by_new_path = locate(&history, &dir, None, Some(found)).await.unwrap();
+        assert_eq!(by_new_path.transfer_id, moved);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
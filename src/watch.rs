@@ -0,0 +1,140 @@
+use crate::client_queue::ClientQueue;
+use axum::extract::ws::Message;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Which clients follow each running send, so a send outlives the
+/// connection that started it: the client that starts one watches it, and
+/// any client may join with WatchTransfer. Progress goes to every watcher
+/// and the latest is kept for whoever joins next.
+#[derive(Default)]
+pub struct TransferWatchers {
+    transfers: Mutex<HashMap<Uuid, Watched>>,
+}
+
+struct Watched {
+    clients: HashMap<Uuid, Arc<ClientQueue>>,
+    /// Running transfers to cancel once it has gone unwatched too long;
+    /// empty for sends no client started, e.g. scheduled ones.
+    cancels: Vec<Uuid>,
+    latest: Option<Message>,
+    unwatched_since: Option<Instant>,
+}
+
+impl TransferWatchers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Follows `transfer_id`, watched by `starter` if the client that
+    /// started it is still here. `cancels` are the transfers to stop if
+    /// nobody watches it for long enough.
+    pub fn start(&self, transfer_id: Uuid, starter: Option<Arc<ClientQueue>>, cancels: Vec<Uuid>) {
+        let unwatched_since = (starter.is_none() && !cancels.is_empty()).then(Instant::now);
+        let clients = starter.into_iter().map(|queue| (queue.client_id(), queue)).collect();
+        self.transfers.lock().unwrap().insert(
+            transfer_id,
+            Watched {
+                clients,
+                cancels,
+                latest: None,
+                unwatched_since,
+            },
+        );
+    }
+
+    /// Adds a watcher, returning the latest progress to catch it up with;
+    /// `None` if `transfer_id` isn't followed.
+    pub fn watch(&self, transfer_id: Uuid, queue: Arc<ClientQueue>) -> Option<Option<Message>> {
+        let mut transfers = self.transfers.lock().unwrap();
+        let watched = transfers.get_mut(&transfer_id)?;
+        watched.clients.insert(queue.client_id(), queue);
+        watched.unwatched_since = None;
+        Some(watched.latest.clone())
+    }
+
+    /// Keeps `message` as the latest progress and passes it to every
+    /// watcher. Never blocks.
+    pub fn progress(&self, transfer_id: Uuid, message: Message) {
+        let mut transfers = self.transfers.lock().unwrap();
+        let Some(watched) = transfers.get_mut(&transfer_id) else { return };
+        for queue in watched.clients.values() {
+            queue.push_progress(transfer_id, message.clone());
+        }
+        watched.latest = Some(message);
+    }
+
+    /// Who watches `transfer_id` now.
+    pub fn watchers(&self, transfer_id: &Uuid) -> Vec<Arc<ClientQueue>> {
+        self.transfers
+            .lock()
+            .unwrap()
+            .get(transfer_id)
+            .map(|watched| watched.clients.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Stops following `transfer_id`, returning who watched it.
+    pub fn finish(&self, transfer_id: &Uuid) -> Vec<Arc<ClientQueue>> {
+        self.transfers
+            .lock()
+            .unwrap()
+            .remove(transfer_id)
+            .map(|watched| watched.clients.into_values().collect())
+            .unwrap_or_default()
+    }
+
+    /// Drops a disconnected client from everything it watched.
+    pub fn detach(&self, client_id: &Uuid) {
+        let now = Instant::now();
+        for watched in self.transfers.lock().unwrap().values_mut() {
+            if watched.clients.remove(client_id).is_some() && watched.clients.is_empty() {
+                watched.unwatched_since = Some(now);
+            }
+        }
+    }
+
+    /// Transfers to cancel for sends nobody has watched for `after`, each
+    /// given once.
+    pub fn orphaned(&self, after: Duration) -> Vec<Uuid> {
+        let mut transfers = self.transfers.lock().unwrap();
+        transfers
+            .values_mut()
+            .filter(|watched| watched.unwatched_since.is_some_and(|since| since.elapsed() >= after))
+            .flat_map(|watched| std::mem::take(&mut watched.cancels))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sends_left_unwatched_are_given_up_once() {
+        let watchers = TransferWatchers::new();
+        let (send, scheduled) = (Uuid::new_v4(), Uuid::new_v4());
+        let starter = Arc::new(ClientQueue::new(Uuid::new_v4()));
+        watchers.start(send, Some(starter.clone()), vec![send]);
+        watchers.start(scheduled, None, Vec::new());
+        assert!(watchers.orphaned(Duration::ZERO).is_empty());
+
+        watchers.progress(send, Message::Text("first".to_string()));
+        watchers.detach(&starter.client_id());
+        assert!(watchers.watchers(&send).is_empty());
+
+        // Rejoining catches up with the latest progress and keeps it going
+        let later = Arc::new(ClientQueue::new(Uuid::new_v4()));
+        assert_eq!(watchers.watch(send, later.clone()), Some(Some(Message::Text("first".to_string()))));
+        assert!(watchers.orphaned(Duration::ZERO).is_empty());
+        assert_eq!(watchers.watch(Uuid::new_v4(), later.clone()), None);
+
+        watchers.detach(&later.client_id());
+        assert!(watchers.orphaned(Duration::from_secs(60)).is_empty());
+        assert_eq!(watchers.orphaned(Duration::ZERO), vec![send]);
+        assert!(watchers.orphaned(Duration::ZERO).is_empty());
+        assert!(watchers.finish(&send).is_empty());
+    }
+}
@@ -1,99 +1,806 @@
+use crate::active::{CancelOutcome, Direction, SpeedSamples};
+use crate::archive;
+use crate::broadcast::BroadcastTarget;
+use crate::chat::{ChatLimiter, ChatRejection};
+use crate::client_queue::ClientQueue;
 use crate::config::AppConfig;
-use crate::history::TransferHistory;
+use crate::history::{TransferHistory, HISTORY_FILE};
+use crate::notify::{Notification, NotificationStatus, Notifier};
 use crate::peer::PeerManager;
-use crate::protocol::{ClientMessage, ServerMessage, PeerInfo};
-use crate::transfer::TransferService;
+use crate::portmap::PortMapper;
+use crate::privacy;
+use crate::protocol::{
+    CancelResult, ClientInfo, ClientMessage, ConfigSummary, ManifestEntry, PeerInfo, PeerSort, ServerMessage, ServiceHealth,
+    SystemInfo,
+};
+use crate::schedule::{ScheduledTransfer, Scheduler};
+use crate::session::{ReceiveSession, SessionFile};
+use crate::sync::SyncService;
+use crate::transfer::{ReceiveProgress, RelayedPeer, Route, TransferError, TransferService};
 use crate::utils;
-use anyhow::Result;
+use crate::verify;
+use crate::watch::TransferWatchers;
+use crate::webhook::WebhookService;
+use anyhow::{bail, Result};
 use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
 use axum::extract::State;
 use axum::response::Response;
 use axum::routing::get;
-use axum::Router;
+use axum::{Json, Router};
 use futures_util::{SinkExt, StreamExt};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, watch, RwLock};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
+/// Longest name a client may register under, in characters.
+const MAX_CLIENT_NAME_LEN: usize = 64;
+/// How often sends are checked for having gone unwatched too long.
+const ORPHAN_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+/// Least time between progress updates on a send, unless another percent
+/// of it has gone by sooner.
+const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+/// What a send started by `start_send` sends.
+enum SendSource {
+    File(PathBuf),
+    /// Files packed into one zip as it goes, offered as `name`.
+    Archive { entries: Vec<archive::Entry>, name: String },
+}
+
+/// Serves the client API over WebSocket and tells clients what happens.
 pub struct WebSocketService {
     config: Arc<AppConfig>,
     peers: Arc<RwLock<PeerManager>>,
-    connections: Arc<RwLock<HashMap<Uuid, mpsc::UnboundedSender<Message>>>>,
+    connections: Arc<RwLock<HashMap<Uuid, Arc<ClientQueue>>>>,
     client_to_peer: Arc<RwLock<HashMap<Uuid, Uuid>>>,
+    /// What each connected client is called.
+    clients: Arc<RwLock<HashMap<Uuid, ClientInfo>>>,
+    /// Who follows each running send, whether or not its client is still here.
+    watchers: TransferWatchers,
     transfer_service: Arc<TransferService>,
+    port_mapper: Arc<PortMapper>,
     history: Arc<TransferHistory>,
+    scheduler: Arc<Scheduler>,
+    sync: Arc<SyncService>,
+    webhooks: Arc<WebhookService>,
+    notifier: Arc<Notifier>,
+    chat_limiter: ChatLimiter,
+    /// Each supervised service's last reported state and error.
+    service_status: std::sync::RwLock<BTreeMap<String, ServiceHealth>>,
+    started: std::time::Instant,
+    shutdown: CancellationToken,
+    /// Where the server is bound, once it is.
+    local_addr: watch::Sender<Option<SocketAddr>>,
+    /// Where discovery listens, for LocalInfo.
+    discovery_addr: watch::Sender<Option<SocketAddr>>,
 }
 
 impl WebSocketService {
+    /// Loads the transfer history and sets up webhooks and notifications;
+    /// nothing is served until `start_server`.
     pub fn new(
         config: Arc<AppConfig>,
         peers: Arc<RwLock<PeerManager>>,
         transfer_service: Arc<TransferService>,
+        port_mapper: Arc<PortMapper>,
+        scheduler: Arc<Scheduler>,
+        sync: Arc<SyncService>,
+        shutdown: CancellationToken,
     ) -> Self {
+        let webhooks = Arc::new(WebhookService::new(&config));
+        let notifier = Arc::new(Notifier::new(&config.ui));
+        let chat_limiter = ChatLimiter::new(&config.chat);
+        let history = TransferHistory::load(transfer_service.data_dir().join(HISTORY_FILE), config.history.max_entries)
+            .keeping_speed_samples(config.transfer.keep_speed_samples);
         Self {
             config,
             peers,
             connections: Arc::new(RwLock::new(HashMap::new())),
             client_to_peer: Arc::new(RwLock::new(HashMap::new())),
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            watchers: TransferWatchers::new(),
             transfer_service,
-            history: Arc::new(TransferHistory::new(1000)), // Keep last 1000 transfers
+            port_mapper,
+            history: Arc::new(history),
+            scheduler,
+            sync,
+            webhooks,
+            notifier,
+            chat_limiter,
+            service_status: std::sync::RwLock::new(BTreeMap::new()),
+            started: std::time::Instant::now(),
+            shutdown,
+            local_addr: watch::Sender::new(None),
+            discovery_addr: watch::Sender::new(None),
         }
     }
 
+    /// The address the server is bound to, once it is. With
+    /// `network.web_port` 0 this is where to find the port picked.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *self.local_addr.borrow()
+    }
+
+    /// Resolves with the server's address once it is bound.
+    pub async fn wait_for_local_addr(&self) -> SocketAddr {
+        let mut bound = self.local_addr.subscribe();
+        loop {
+            if let Some(addr) = *bound.borrow_and_update() {
+                return addr;
+            }
+            // Never closed, since we hold the sender
+            let _ = bound.changed().await;
+        }
+    }
+
+    /// Records where discovery listens, so clients can be told.
+    pub fn set_discovery_addr(&self, addr: SocketAddr) {
+        self.discovery_addr.send_replace(Some(addr));
+    }
+
+    /// Where discovery listens, once it has bound.
+    pub fn discovery_addr(&self) -> Option<SocketAddr> {
+        *self.discovery_addr.borrow()
+    }
+
+    /// The webhooks that client-bound events are posted to.
+    pub fn webhooks(&self) -> Arc<WebhookService> {
+        self.webhooks.clone()
+    }
+
+    /// Desktop notifications for received files and failed transfers.
+    pub fn notifier(&self) -> Arc<Notifier> {
+        self.notifier.clone()
+    }
+
+    /// Saves the transfer history, for shutdown.
+    pub async fn flush_history(&self) -> Result<()> {
+        self.history.save().await
+    }
+
+    /// The API routes, for serving them yourself.
     pub fn create_router(self: Arc<Self>) -> Router {
         Router::new()
             .route("/ws", get(websocket_handler))
+            .route("/health", get(health_handler))
             .with_state(self)
     }
 
+    /// Serves the API on `network.web_port` until shutdown. A port picked
+    /// by the OS is kept across restarts, so clients can reconnect.
     pub async fn start_server(self: Arc<Self>) -> Result<()> {
-        let addr = SocketAddr::from(([0, 0, 0, 0], self.config.network.web_port));
-        let listener = tokio::net::TcpListener::bind(addr).await?;
+        let port = self.local_addr().map_or(self.config.network.web_port, |addr| addr.port());
+        let listener = tokio::net::TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+        let addr = listener.local_addr()?;
+        self.local_addr.send_replace(Some(addr));
         tracing::info!("WebSocket server started on http://{}", addr);
 
+        let shutdown = self.shutdown.clone();
+        let service = self.clone();
         let app = self.create_router();
-        let server = axum::serve(listener, app);
+        let server = axum::serve(listener, app).with_graceful_shutdown(async move {
+            shutdown.cancelled().await;
+            // Upgraded sockets outlive the HTTP server, so close them explicitly
+            service.broadcast_to_all(Message::Close(None)).await;
+        });
 
         server.await?;
         Ok(())
     }
 
-    pub async fn add_connection(&self, client_id: Uuid, peer_id: Uuid, tx: mpsc::UnboundedSender<Message>) {
+    /// Starts sending events to a client.
+    pub async fn add_connection(&self, client_id: Uuid, peer_id: Uuid, queue: Arc<ClientQueue>) {
         let mut connections = self.connections.write().await;
-        connections.insert(client_id, tx);
+        connections.insert(client_id, queue);
         let mut client_to_peer = self.client_to_peer.write().await;
         client_to_peer.insert(client_id, peer_id);
-        tracing::info!("WebSocket client connected: {} (peer: {})", client_id, peer_id);
+        let client = ClientInfo {
+            client_id,
+            name: format!("client-{}", &client_id.simple().to_string()[..8]),
+            kind: None,
+            registered: false,
+            connected_since: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        tracing::info!("WebSocket client connected: {} (peer: {})", client.name, peer_id);
+        self.clients.write().await.insert(client_id, client);
     }
 
+    /// Stops sending events to a client.
     pub async fn remove_connection(&self, client_id: &Uuid) {
-        let mut connections = self.connections.write().await;
-        connections.remove(client_id);
-        let mut client_to_peer = self.client_to_peer.write().await;
-        client_to_peer.remove(client_id);
-        tracing::info!("WebSocket client disconnected: {}", client_id);
+        if let Some(queue) = self.connections.write().await.remove(client_id) {
+            queue.close();
+        }
+        self.client_to_peer.write().await.remove(client_id);
+        self.chat_limiter.forget(client_id);
+        self.watchers.detach(client_id);
+
+        // Both halves of a connection remove it; only the first tells anyone
+        let Some(client) = self.clients.write().await.remove(client_id) else { return };
+        tracing::info!("WebSocket client disconnected: {}", client.name);
+        self.broadcast(ServerMessage::ClientDisconnected {
+            client_id: *client_id,
+            name: client.name,
+        })
+        .await;
+    }
+
+    /// What a client is called in logs and events: its registered name, or
+    /// the label it was given on connecting.
+    pub async fn client_name(&self, client_id: &Uuid) -> String {
+        match self.clients.read().await.get(client_id) {
+            Some(client) => client.name.clone(),
+            None => client_id.to_string(),
+        }
     }
 
+    /// Sends an encoded message to every client.
     pub async fn broadcast_to_all(&self, message: Message) {
-        let connections = self.connections.read().await;
-        for (client_id, tx) in connections.iter() {
-            if let Err(e) = tx.send(message.clone()) {
-                tracing::warn!("Failed to send message to client {}: {}", client_id, e);
+        // Release the lock before waiting on any slow client
+        let queues: Vec<(Uuid, Arc<ClientQueue>)> = self
+            .connections
+            .read()
+            .await
+            .iter()
+            .map(|(id, queue)| (*id, queue.clone()))
+            .collect();
+
+        let sends = queues.iter().map(|(client_id, queue)| {
+            let message = message.clone();
+            async move {
+                if let Err(e) = queue.push(message).await {
+                    tracing::warn!("Failed to send message to client {}: {}", client_id, e);
+                }
             }
-        }
+        });
+        futures_util::future::join_all(sends).await;
+    }
+
+    /// Whether any client is connected.
+    pub async fn has_clients(&self) -> bool {
+        !self.connections.read().await.is_empty()
+    }
+
+    /// Sends a message to every client.
+    pub async fn broadcast(&self, message: ServerMessage) {
+        self.broadcast_to_all(self.encode(message)).await;
     }
 
+    /// Serializes a message for clients, applying the configured redaction.
+    /// Webhooks see the same redacted message.
+    pub fn encode(&self, message: ServerMessage) -> Message {
+        let message = privacy::redact(message, &self.config.privacy);
+        self.webhooks.notify(&message);
+        Message::Text(serde_json::to_string(&message).unwrap_or_default())
+    }
+
+    async fn client_queue(&self, client_id: &Uuid) -> Option<Arc<ClientQueue>> {
+        self.connections.read().await.get(client_id).cloned()
+    }
+
+    /// Sends an encoded message to one client.
     pub async fn send_to_client(&self, client_id: &Uuid, message: Message) -> Result<()> {
-        let connections = self.connections.read().await;
-        if let Some(tx) = connections.get(client_id) {
-            tx.send(message)?;
-            Ok(())
+        match self.client_queue(client_id).await {
+            Some(queue) => queue.push(message).await,
+            None => Err(anyhow::anyhow!("Client not found")),
+        }
+    }
+
+    /// Sends a progress update that may be coalesced with older updates for
+    /// the same transfer if the client is falling behind.
+    pub async fn send_progress_to_client(&self, client_id: &Uuid, transfer_id: Uuid, message: Message) {
+        if let Some(queue) = self.client_queue(client_id).await {
+            queue.push_progress(transfer_id, message);
+        }
+    }
+
+    /// Re-hashes a located download off the runtime, reporting progress and
+    /// then the result to `client_id`.
+    async fn spawn_verification(self: &Arc<Self>, client_id: Uuid, target: verify::Target) {
+        let client_queue = self.client_queue(&client_id).await;
+        let websocket_service = self.clone();
+        tokio::spawn(async move {
+            let transfer_id = target.transfer_id;
+            let path = target.path.clone();
+            let hashed = tokio::task::spawn_blocking(move || {
+                utils::checksum_file_blocking(&path, |progress, total| {
+                    let Some(queue) = &client_queue else { return };
+                    let msg = ServerMessage::ChecksumProgress {
+                        transfer_id,
+                        progress,
+                        total,
+                    };
+                    if let Ok(json) = serde_json::to_string(&msg) {
+                        queue.push_progress(transfer_id, Message::Text(json));
+                    }
+                })
+            })
+            .await;
+            let message = match hashed {
+                Ok(Ok(actual)) => ServerMessage::VerificationResult {
+                    transfer_id,
+                    relative_path: target.relative_path,
+                    matches: actual == target.expected,
+                    expected: target.expected,
+                    actual,
+                },
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::NotFound => ServerMessage::VerificationError {
+                    transfer_id: Some(transfer_id),
+                    relative_path: Some(target.relative_path),
+                    code: verify::VerifyError::Deleted.code().to_string(),
+                    message: verify::VerifyError::Deleted.message(),
+                },
+                Ok(Err(e)) => ServerMessage::Error {
+                    message: format!("Failed to read {}: {}", target.relative_path, e),
+                },
+                Err(e) => ServerMessage::Error { message: e.to_string() },
+            };
+            let _ = websocket_service
+                .send_to_client(&client_id, websocket_service.encode(message))
+                .await;
+        });
+    }
+
+    /// Sends a message about `transfer_id` to every client watching it, or
+    /// to every client when nobody is. `finished` stops following it.
+    async fn deliver_watched(&self, transfer_id: Uuid, message: ServerMessage, finished: bool) {
+        let watchers = if finished {
+            self.watchers.finish(&transfer_id)
         } else {
-            Err(anyhow::anyhow!("Client not found"))
+            self.watchers.watchers(&transfer_id)
+        };
+        if watchers.is_empty() {
+            return self.broadcast(message).await;
         }
+        let message = self.encode(message);
+        let sends = watchers.iter().map(|queue| {
+            let message = message.clone();
+            async move {
+                if let Err(e) = queue.push(message).await {
+                    tracing::warn!("Failed to send message to client {}: {}", queue.client_id(), e);
+                }
+            }
+        });
+        futures_util::future::join_all(sends).await;
+    }
+
+    /// Pauses or resumes `transfer_id` every way it runs. False when it
+    /// isn't running.
+    fn set_paused(&self, transfer_id: Uuid, paused: bool) -> bool {
+        let active = self.transfer_service.active_transfers();
+        let directions = active.directions_of(&transfer_id);
+        // Not all: one may have finished since
+        directions
+            .into_iter()
+            .filter(|direction| active.set_paused(transfer_id, *direction, paused))
+            .count()
+            > 0
+    }
+
+    /// Why `transfer_id` can't be acted on: it has finished, or never was.
+    async fn not_running(&self, transfer_id: Uuid) -> anyhow::Error {
+        match self.history.get_transfer(&transfer_id).await {
+            Some(record) => anyhow::anyhow!("Transfer {} has already finished ({})", transfer_id, record.status),
+            None => anyhow::anyhow!("No transfer {}", transfer_id),
+        }
+    }
+
+    /// Cancels sends nobody has watched for `transfer.orphan_cancel_minutes`
+    /// since their client left, until shutdown.
+    pub async fn cancel_orphaned_transfers(&self) -> Result<()> {
+        let Some(minutes) = self.config.transfer.orphan_cancel_minutes else { return Ok(()) };
+        let after = std::time::Duration::from_secs(minutes * 60);
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(ORPHAN_CHECK_INTERVAL) => {}
+                _ = self.shutdown.cancelled() => return Ok(()),
+            }
+            let active = self.transfer_service.active_transfers();
+            for transfer_id in self.watchers.orphaned(after) {
+                tracing::info!("Cancelling transfer {}: no client has watched it for {} minutes", transfer_id, minutes);
+                for direction in active.directions_of(&transfer_id) {
+                    active.cancel(transfer_id, direction).await;
+                }
+            }
+        }
+    }
+
+    /// Starts sending a file, recording it in history and reporting its
+    /// progress and outcome to whoever watches it: `client_id` to begin
+    /// with, and every client when nobody does. Returns the request to show
+    /// and the running send.
+    async fn start_send(
+        self: &Arc<Self>,
+        client_id: Option<Uuid>,
+        peer_id: Uuid,
+        source: SendSource,
+        relay_peer_id: Option<Uuid>,
+        debug: bool,
+    ) -> Result<(ServerMessage, JoinHandle<Result<()>>)> {
+        let (peer, relay) = {
+            let peers = self.peers.read().await;
+            (
+                peers.get_peer(&peer_id).cloned(),
+                relay_peer_id.map(|relay_id| peers.get_peer(&relay_id).cloned()),
+            )
+        };
+        if peer.as_ref().is_some_and(|p| p.identity_changed) {
+            bail!("Peer identity changed; accept its new fingerprint before sending");
+        }
+        // Through a relay the destination needn't be one we can see
+        let (route, hostname) = match relay {
+            None => match &peer {
+                Some(peer) => (Route::Peer(peer.id), peer.hostname.clone()),
+                None => bail!("Peer not found"),
+            },
+            Some(Some(relay)) => (
+                Route::Relay {
+                    relay: relay.address,
+                    destination: peer_id,
+                },
+                peer.as_ref().map_or_else(|| peer_id.to_string(), |peer| peer.hostname.clone()),
+            ),
+            Some(None) => bail!("Relay peer not found"),
+        };
+        let (filename, shown_path, file_size, mime_type, detected_mime_type) = match &source {
+            SendSource::File(file_path) => {
+                let Some(metadata) = tokio::fs::metadata(file_path).await.ok().filter(|m| m.is_file()) else {
+                    bail!("File not found or is not a file");
+                };
+                let filename = file_path
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or("unknown")
+                    .to_string();
+                (
+                    filename,
+                    file_path.to_string_lossy().to_string(),
+                    metadata.len(),
+                    utils::get_mime_type(file_path),
+                    utils::detect_mime_type(file_path).await,
+                )
+            }
+            // Sized once it has all been sent
+            SendSource::Archive { name, .. } => {
+                let mime_type = Some(archive::MIME_TYPE.to_string());
+                (name.clone(), name.clone(), 0, mime_type.clone(), mime_type)
+            }
+        };
+
+        // Create history record
+        let transfer_id = Uuid::new_v4();
+        let mut history_record = crate::history::TransferRecord::new(
+            transfer_id,
+            Some(peer_id),
+            hostname.clone(),
+            filename.clone(),
+            shown_path.clone(),
+            file_size,
+            "sent".to_string(),
+        );
+        history_record.mime_type = mime_type.clone();
+        history_record.detected_mime_type = detected_mime_type.clone();
+        self.history.start_transfer(history_record).await;
+        let transfer = self.transfer_service.track_send(transfer_id);
+        if debug || self.config.transfer.trace_transfers {
+            transfer.trace().enable();
+        }
+
+        // A send no client started never counts as left behind
+        let starter = match client_id {
+            Some(client_id) => self.client_queue(&client_id).await,
+            None => None,
+        };
+        let cancels = if client_id.is_some() { vec![transfer_id] } else { Vec::new() };
+        self.watchers.start(transfer_id, starter, cancels);
+
+        let transfer_service = self.transfer_service.clone();
+        let history = self.history.clone();
+        let websocket_service = self.clone();
+        let failure = Notification {
+            hostname,
+            filename: filename.clone(),
+            size: file_size,
+            status: NotificationStatus::Failed,
+        };
+
+        let task = tokio::spawn(async move {
+            let watched = websocket_service.clone();
+            let on_checksum_progress = move |progress, total| {
+                let msg = ServerMessage::ChecksumProgress {
+                    transfer_id,
+                    progress,
+                    total,
+                };
+                if let Ok(json) = serde_json::to_string(&msg) {
+                    watched.watchers.progress(transfer_id, Message::Text(json));
+                }
+            };
+
+            let sending = async {
+                match source {
+                    SendSource::File(file_path) => {
+                        transfer_service.send_file(&transfer, route, file_path, on_checksum_progress).await
+                    }
+                    SendSource::Archive { entries, name } => {
+                        transfer_service.send_archive(&transfer, route, entries, name).await
+                    }
+                }
+            };
+            let result = websocket_service
+                .reporting_progress(transfer_id, transfer.samples(), file_size, sending)
+                .await;
+            history.keep_samples(&transfer_id, transfer.samples()).await;
+            match result {
+                Ok(outcome) => {
+                    transfer.trace().record("completed", "");
+                    history.complete_send(&outcome).await;
+                    let complete_msg = outcome.complete_message(transfer_id, Some(peer_id));
+                    websocket_service.deliver_watched(transfer_id, complete_msg, true).await;
+                    Ok(())
+                }
+                Err(e) => {
+                    if let Some(sample) = transfer.samples().snapshot().last() {
+                        history.note_progress(&transfer_id, sample.bytes_transferred).await;
+                    }
+                    if transfer.is_cancelled() {
+                        transfer.trace().record("cancelled", "");
+                        history.cancel_transfer(&transfer_id).await;
+                    } else {
+                        transfer.trace().record("failed", e.to_string());
+                        history.keep_trace(&transfer_id, transfer.trace()).await;
+                        history.fail_transfer(&transfer_id, e.to_string(), TransferError::code_of(&e)).await;
+                        websocket_service.notifier.notify(failure);
+                    }
+                    let error_msg = ServerMessage::FileTransferError {
+                        transfer_id,
+                        peer_id: Some(peer_id),
+                        message: e.to_string(),
+                        code: TransferError::code_of(&e),
+                        peer_space: TransferError::peer_space_of(&e),
+                    };
+                    websocket_service.deliver_watched(transfer_id, error_msg, true).await;
+                    Err(e)
+                }
+            }
+        });
+
+        let request = ServerMessage::FileTransferRequest {
+            transfer_id,
+            peer_id,
+            filename,
+            file_path: shown_path,
+            file_size,
+            file_checksum: None, // Will be calculated during transfer
+            mime_type,
+            detected_mime_type,
+            origin: match client_id {
+                Some(client_id) => Some(self.client_name(&client_id).await),
+                None => None,
+            },
+        };
+        Ok((request, task))
+    }
+
+    /// Runs send `work`, telling whoever watches `transfer_id` how far it
+    /// has got as `samples` hear of it: every PROGRESS_INTERVAL, or sooner
+    /// for each percent of `total` and for the end. The speed goes to
+    /// history too, for GetTransferStats.
+    async fn reporting_progress<F>(&self, transfer_id: Uuid, samples: &SpeedSamples, total: u64, work: F) -> F::Output
+    where
+        F: std::future::Future,
+    {
+        tokio::pin!(work);
+        let mut moved = samples.progress();
+        let step = (total / 100).max(1);
+        let mut reported: Option<(tokio::time::Instant, u64)> = None;
+        let mut speed = None;
+        let mut waiting = false;
+        loop {
+            let due = reported.map(|(at, _)| at + PROGRESS_INTERVAL);
+            let finished = tokio::select! {
+                biased;
+                output = &mut work => {
+                    // Whatever came in since the last update, however soon
+                    if !moved.has_changed().unwrap_or(false) {
+                        return output;
+                    }
+                    Some(output)
+                }
+                _ = moved.changed() => {
+                    waiting = true;
+                    None
+                }
+                _ = tokio::time::sleep_until(due.unwrap_or_else(tokio::time::Instant::now)), if waiting => None,
+            };
+            let bytes = *moved.borrow_and_update();
+            let now = tokio::time::Instant::now();
+            if let Some((at, before)) = reported {
+                let ending = finished.is_some() || (total > 0 && bytes >= total);
+                if bytes <= before || (due.is_some_and(|due| now < due) && bytes - before < step && !ending) {
+                    waiting &= bytes > before;
+                    match finished {
+                        Some(output) => return output,
+                        None => continue,
+                    }
+                }
+                speed = Some(utils::average_speed(bytes - before, now - at));
+            }
+            reported = Some((now, bytes));
+            waiting = false;
+
+            if let Some(speed) = speed {
+                self.history.update_speed(&transfer_id, speed).await;
+            }
+            let message = ServerMessage::FileTransferProgress {
+                transfer_id,
+                progress: bytes,
+                total,
+                speed_bytes_per_sec: speed,
+                eta_seconds: speed
+                    .filter(|_| total > 0)
+                    .and_then(|speed| utils::calculate_eta(total.saturating_sub(bytes), speed)),
+            };
+            self.watchers.progress(transfer_id, self.encode(message));
+            if let Some(output) = finished {
+                return output;
+            }
+        }
+    }
+
+    /// Starts sending `paths` to `peer_id` as one session, recording it and
+    /// each of its files in history. Each file reports to whoever watches
+    /// the session as a send of its own would. Returns the session to show.
+    async fn start_session(
+        self: &Arc<Self>,
+        client_id: Uuid,
+        peer_id: Uuid,
+        paths: Vec<PathBuf>,
+    ) -> Result<ServerMessage> {
+        let Some(peer) = self.peers.read().await.get_peer(&peer_id).cloned() else {
+            bail!("Peer not found");
+        };
+        if peer.identity_changed {
+            bail!("Peer identity changed; accept its new fingerprint before sending");
+        }
+        if paths.is_empty() {
+            bail!("No files to send");
+        }
+        let mut files = Vec::with_capacity(paths.len());
+        let mut listed = Vec::with_capacity(paths.len());
+        for path in paths {
+            let Some(metadata) = tokio::fs::metadata(&path).await.ok().filter(|m| m.is_file()) else {
+                bail!("{} not found or is not a file", path.display());
+            };
+            let transfer = self.transfer_service.track_send(Uuid::new_v4());
+            listed.push(ManifestEntry {
+                transfer_id: transfer.id(),
+                filename: path.file_name().and_then(|n| n.to_str()).unwrap_or("unknown").to_string(),
+                file_size: metadata.len(),
+                file_checksum: None,
+            });
+            files.push(SessionFile { transfer, path });
+        }
+
+        let session_id = Uuid::new_v4();
+        let parent = Self::session_record(session_id, Some(peer_id), peer.hostname.clone(), &listed, "sent");
+        let total_size = parent.file_size;
+        self.history.start_transfer(parent).await;
+        for (file, entry) in files.iter().zip(&listed) {
+            let mut record = crate::history::TransferRecord::new(
+                entry.transfer_id,
+                Some(peer_id),
+                peer.hostname.clone(),
+                entry.filename.clone(),
+                file.path.to_string_lossy().to_string(),
+                entry.file_size,
+                "sent".to_string(),
+            );
+            record.mime_type = utils::get_mime_type(&file.path);
+            record.detected_mime_type = utils::detect_mime_type(&file.path).await;
+            record.session_id = Some(session_id);
+            self.history.start_transfer(record).await;
+            if self.config.transfer.trace_transfers {
+                file.transfer.trace().enable();
+            }
+        }
+        let starter = self.client_queue(&client_id).await;
+        let cancels = listed.iter().map(|entry| entry.transfer_id).collect();
+        self.watchers.start(session_id, starter, cancels);
+
+        let sizes: Vec<u64> = listed.iter().map(|entry| entry.file_size).collect();
+        let transfer_service = self.transfer_service.clone();
+        let history = self.history.clone();
+        let websocket_service = self.clone();
+        tokio::spawn(async move {
+            let (results_tx, mut results) = mpsc::unbounded_channel();
+            let session = transfer_service.send_files(session_id, Route::Peer(peer_id), &files, results_tx);
+            let report = async {
+                let (mut successful, mut failed, mut bytes_transferred) = (0, 0, 0);
+                while let Some((index, result)) = results.recv().await {
+                    let transfer = &files[index].transfer;
+                    let transfer_id = transfer.id();
+                    history.keep_samples(&transfer_id, transfer.samples()).await;
+                    let message = match result {
+                        Ok(outcome) => {
+                            successful += 1;
+                            // A file the peer had already didn't go
+                            if !outcome.deduplicated {
+                                bytes_transferred += sizes[index];
+                            }
+                            history.complete_send(&outcome).await;
+                            outcome.complete_message(transfer_id, Some(peer_id))
+                        }
+                        Err(e) => {
+                            failed += 1;
+                            if let Some(sample) = transfer.samples().snapshot().last() {
+                                bytes_transferred += sample.bytes_transferred;
+                                history.note_progress(&transfer_id, sample.bytes_transferred).await;
+                            }
+                            if transfer.is_cancelled() {
+                                history.cancel_transfer(&transfer_id).await;
+                            } else {
+                                history.fail_transfer(&transfer_id, e.to_string(), TransferError::code_of(&e)).await;
+                            }
+                            ServerMessage::FileTransferError {
+                                transfer_id,
+                                peer_id: Some(peer_id),
+                                message: e.to_string(),
+                                code: TransferError::code_of(&e),
+                                peer_space: TransferError::peer_space_of(&e),
+                            }
+                        }
+                    };
+                    websocket_service.deliver_watched(session_id, message, false).await;
+
+                    let progress_msg = ServerMessage::SessionTransferProgress {
+                        session_id,
+                        completed_files: successful,
+                        failed_files: failed,
+                        total_files: sizes.len(),
+                        bytes_transferred,
+                        total_size,
+                    };
+                    websocket_service
+                        .watchers
+                        .progress(session_id, websocket_service.encode(progress_msg));
+                }
+            };
+            tokio::join!(session, report);
+
+            let (successful_files, failed_files) = history.finish_session(&session_id).await;
+            let complete_msg = ServerMessage::SessionTransferComplete {
+                session_id,
+                successful_files,
+                failed_files,
+            };
+            websocket_service.deliver_watched(session_id, complete_msg, true).await;
+        });
+
+        Ok(ServerMessage::SessionTransferStart {
+            session_id,
+            peer_id: Some(peer_id),
+            direction: "sent".to_string(),
+            files: listed,
+            total_size,
+        })
+    }
+
+    /// Runs a scheduled transfer through the normal send pipeline, showing it
+    /// to every client, and resolves once it has finished.
+    pub async fn send_scheduled(self: Arc<Self>, job: ScheduledTransfer) -> Result<()> {
+        let (request, task) = self
+            .start_send(None, job.peer_id, SendSource::File(PathBuf::from(&job.file_path)), None, false)
+            .await?;
+        self.broadcast(request).await;
+        task.await?
     }
 
     async fn handle_client_message(
@@ -102,112 +809,153 @@ impl WebSocketService {
         message: ClientMessage,
     ) -> Result<Option<ServerMessage>> {
         match message {
-            ClientMessage::GetPeers => {
-                let peers = self.peers.read().await;
-                let peer_list: Vec<PeerInfo> = peers
+            ClientMessage::GetPeers { sort_by, descending } => {
+                let approvals = self.transfer_service.approvals();
+                let mut peer_list: Vec<PeerInfo> = self
+                    .peers
+                    .read()
+                    .await
                     .list_peers()
                     .into_iter()
-                    .map(PeerInfo::from)
+                    .map(|peer| {
+                        let blocked = approvals.rejects_everything_from(&peer);
+                        PeerInfo { blocked, ..PeerInfo::from(peer) }
+                    })
                     .collect();
+                PeerSort::sort(sort_by, descending, &mut peer_list);
                 Ok(Some(ServerMessage::PeersList { peers: peer_list }))
             }
+            ClientMessage::AddPeer { address } => {
+                let websocket_service = self.clone();
+                tokio::spawn(async move {
+                    let message = match websocket_service.transfer_service.add_peer(address.trim()).await {
+                        Ok(peer) => ServerMessage::PeerAdded { peer },
+                        Err(e) => ServerMessage::Error {
+                            message: format!("Couldn't add peer {}: {}", address, e),
+                        },
+                    };
+                    let _ = websocket_service.send_to_client(&client_id, websocket_service.encode(message)).await;
+                });
+                Ok(None)
+            }
             ClientMessage::GetLocalInfo => {
+                let downloads_dir = self.transfer_service.downloads_dir();
+                let space = utils::available_space(&downloads_dir).ok();
+                let transfer_port = self.transfer_service.local_addr().map(|addr| addr.port());
+                let interface = self.config.network.interface.as_deref();
+                let advertised_addresses = transfer_port
+                    .map(|port| utils::advertised_addresses(interface, port))
+                    .unwrap_or_default()
+                    .into_iter()
+                    .chain(self.port_mapper.external_address())
+                    .collect();
                 let peers = self.peers.read().await;
                 Ok(Some(ServerMessage::LocalInfo {
                     peer_id: peers.local_id(),
                     hostname: peers.local_hostname().to_string(),
+                    fingerprint: peers.identity().fingerprint(),
+                    downloads_dir: downloads_dir.to_string_lossy().to_string(),
+                    downloads_free_bytes: space.map(|s| s.available),
+                    downloads_total_bytes: space.map(|s| s.total),
+                    port_mapping: self.port_mapper.status(),
+                    discovery_port: self.discovery_addr().map(|addr| addr.port()),
+                    transfer_port,
+                    web_port: self.local_addr().map(|addr| addr.port()),
+                    advertised_addresses,
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                    uptime_secs: self.started.elapsed().as_secs(),
+                    services: self
+                        .service_status
+                        .read()
+                        .unwrap()
+                        .iter()
+                        .map(|(name, health)| (name.clone(), health.status.clone()))
+                        .collect(),
+                    bandwidth: self.transfer_service.bandwidth().current(),
                 }))
             }
-            ClientMessage::SendFile { peer_id, file_path } => {
-                let peers = self.peers.read().await;
-                if let Some(peer) = peers.get_peer(&peer_id) {
-                    let file_path = PathBuf::from(&file_path);
-                    if file_path.exists() && file_path.is_file() {
-                        let filename = file_path
-                            .file_name()
-                            .and_then(|n| n.to_str())
-                            .unwrap_or("unknown")
-                            .to_string();
-                        let file_size = std::fs::metadata(&file_path)?.len();
-                        
-                        // Create history record
-                        let transfer_id = Uuid::new_v4();
-                        let history_record = crate::history::TransferRecord::new(
-                            transfer_id,
-                            Some(peer_id),
-                            peer.hostname.clone(),
-                            filename.clone(),
-                            file_path.to_string_lossy().to_string(),
-                            file_size,
-                            "sent".to_string(),
-                        );
-                        self.history.start_transfer(history_record).await;
-                        
-                        let transfer_service = self.transfer_service.clone();
-                        let history = self.history.clone();
-                        let websocket_service = self.clone();
-                        let client_id_clone = client_id;
-                        
-                        tokio::spawn(async move {
-                            match transfer_service.send_file(peer.address, file_path).await {
-                                Ok(_) => {
-                                    // Note: checksum verification would be done in transfer service
-                                    history.complete_transfer(&transfer_id, None, true).await;
-                                }
-                                Err(e) => {
-                                    history.fail_transfer(&transfer_id).await;
-                                    let error_msg = ServerMessage::FileTransferError {
-                                        transfer_id,
-                                        peer_id: Some(peer_id),
-                                        message: e.to_string(),
-                                    };
-                                    let json = serde_json::to_string(&error_msg).unwrap_or_default();
-                                    let _ = websocket_service.send_to_client(
-                                        &client_id_clone,
-                                        axum::extract::ws::Message::Text(json),
-                                    ).await;
-                                }
-                            }
-                        });
-                        
-                        Ok(Some(ServerMessage::FileTransferRequest {
-                            transfer_id,
-                            peer_id,
-                            filename,
-                            file_path: file_path.to_string_lossy().to_string(),
-                            file_size,
-                            file_checksum: None, // Will be calculated during transfer
-                            mime_type: utils::get_mime_type(&file_path),
-                        }))
-                    } else {
-                        Ok(Some(ServerMessage::Error {
-                            message: "File not found or is not a file".to_string(),
-                        }))
-                    }
-                } else {
-                    Ok(Some(ServerMessage::Error {
-                        message: "Peer not found".to_string(),
-                    }))
+            ClientMessage::GetSystemInfo => Ok(Some(ServerMessage::SystemInfo {
+                info: self.system_info().await,
+            })),
+            ClientMessage::SendFile {
+                peer_id,
+                file_path,
+                relay_peer_id,
+                debug,
+            } => match self
+                .start_send(Some(client_id), peer_id, SendSource::File(PathBuf::from(file_path)), relay_peer_id, debug)
+                .await
+            {
+                Ok((request, _)) => Ok(Some(request)),
+                Err(e) => Ok(Some(ServerMessage::Error { message: e.to_string() })),
+            },
+            ClientMessage::SendArchive {
+                peer_id,
+                file_paths,
+                archive_name,
+            } => {
+                let paths: Vec<PathBuf> = file_paths.into_iter().map(PathBuf::from).collect();
+                let source = match archive::entries(&paths).await {
+                    Ok(entries) => SendSource::Archive {
+                        entries,
+                        name: archive::archive_name(&archive_name),
+                    },
+                    Err(e) => return Ok(Some(ServerMessage::Error { message: e.to_string() })),
+                };
+                match self.start_send(Some(client_id), peer_id, source, None, false).await {
+                    Ok((request, _)) => Ok(Some(request)),
+                    Err(e) => Ok(Some(ServerMessage::Error { message: e.to_string() })),
                 }
             }
-            ClientMessage::BroadcastFile { file_path } => {
-                let peers = self.peers.read().await;
-                let peer_list = peers.list_peers();
-                let file_path = PathBuf::from(file_path);
-                
-                if !file_path.exists() {
+            ClientMessage::SendFiles { peer_id, file_paths } => {
+                let paths = file_paths.into_iter().map(PathBuf::from).collect();
+                match self.start_session(client_id, peer_id, paths).await {
+                    Ok(start) => Ok(Some(start)),
+                    Err(e) => Ok(Some(ServerMessage::Error { message: e.to_string() })),
+                }
+            }
+            ClientMessage::ScheduleTransfer {
+                peer_id,
+                file_path,
+                at_unix,
+                recurring,
+                missed,
+            } => {
+                let is_file = tokio::fs::metadata(&file_path).await.is_ok_and(|m| m.is_file());
+                if !is_file {
                     return Ok(Some(ServerMessage::Error {
-                        message: "File not found".to_string(),
+                        message: "File not found or is not a file".to_string(),
                     }));
                 }
-
-                let file_metadata = std::fs::metadata(&file_path)?;
-                let filename = file_path
-                    .file_name()
-                    .and_then(|n| n.to_str())
-                    .unwrap_or("unknown")
-                    .to_string();
-                let file_size = file_metadata.len();
+                match self.scheduler.add(peer_id, file_path, at_unix, recurring, missed) {
+                    Ok(schedule) => Ok(Some(ServerMessage::TransferScheduled { schedule })),
+                    Err(e) => Ok(Some(ServerMessage::Error { message: e.to_string() })),
+                }
+            }
+            ClientMessage::GetScheduledTransfers => Ok(Some(ServerMessage::ScheduledTransfers {
+                schedules: self.scheduler.list(),
+            })),
+            ClientMessage::CancelScheduledTransfer { schedule_id } => match self.scheduler.cancel(&schedule_id) {
+                Ok(()) => Ok(Some(ServerMessage::ScheduledTransferCancelled { schedule_id })),
+                Err(e) => Ok(Some(ServerMessage::Error { message: e.to_string() })),
+            },
+            ClientMessage::BroadcastFile {
+                file_path,
+                exclude_peer_ids,
+                dry_run,
+            } => {
+                // Copy the list out so the lock isn't held across any I/O
+                // Peers with an unresolved identity change are left out
+                let (peer_list, excluded): (Vec<_>, Vec<_>) = self
+                    .peers
+                    .read()
+                    .await
+                    .list_peers()
+                    .into_iter()
+                    .filter(|p| !p.identity_changed)
+                    .partition(|p| !exclude_peer_ids.as_ref().is_some_and(|ids| ids.contains(&p.id)));
+                let excluded: Vec<Uuid> = excluded.into_iter().map(|peer| peer.id).collect();
+                let file_path = PathBuf::from(file_path);
 
                 let broadcast_id = Uuid::new_v4();
                 let total_peers = peer_list.len();
@@ -217,91 +965,210 @@ impl WebSocketService {
                         message: "No peers available for broadcast".to_string(),
                     }));
                 }
+                if dry_run {
+                    let websocket_service = self.clone();
+                    tokio::spawn(async move {
+                        let message = match tokio::fs::metadata(&file_path).await {
+                            Ok(metadata) if metadata.is_file() => {
+                                let checks = peer_list
+                                    .iter()
+                                    .map(|peer| websocket_service.transfer_service.check_peer(peer.id));
+                                ServerMessage::BroadcastDryRunResult {
+                                    file_path: file_path.to_string_lossy().to_string(),
+                                    file_size: metadata.len(),
+                                    peers: futures_util::future::join_all(checks).await,
+                                    excluded_peers: excluded,
+                                }
+                            }
+                            _ => ServerMessage::Error {
+                                message: "File not found".to_string(),
+                            },
+                        };
+                        let _ = websocket_service.send_to_client(&client_id, websocket_service.encode(message)).await;
+                    });
+                    return Ok(None);
+                }
 
-                let file_checksum = utils::calculate_file_checksum(&file_path).await.ok();
-                let mime_type = utils::get_mime_type(&file_path);
-                
-                let start_msg = ServerMessage::BroadcastTransferStart {
-                    transfer_id: broadcast_id,
-                    filename: filename.clone(),
-                    file_path: file_path.to_string_lossy().to_string(),
-                    file_size,
-                    total_peers,
-                    file_checksum,
-                    mime_type,
-                };
-                let json = serde_json::to_string(&start_msg).unwrap_or_default();
-                let ws_msg = axum::extract::ws::Message::Text(json);
-                self.send_to_client(&client_id, ws_msg).await?;
+                // Registered up front so peers still waiting their turn can be cancelled
+                let peer_ids: Vec<_> = peer_list.iter().map(|peer| peer.id).collect();
+                let hostnames: Vec<_> = peer_list.iter().map(|peer| peer.hostname.clone()).collect();
+                let targets: Vec<_> = peer_list
+                    .into_iter()
+                    .map(|peer| BroadcastTarget {
+                        transfer: self.transfer_service.track_send(Uuid::new_v4()),
+                        peer_id: peer.id,
+                    })
+                    .collect();
 
                 let transfer_service = self.transfer_service.clone();
+                let history = self.history.clone();
                 let websocket_service = self.clone();
                 let client_id_clone = client_id;
 
+                // Stat and hash in the background so this client's socket
+                // (and everyone else's) keeps being serviced meanwhile.
                 tokio::spawn(async move {
-                    let mut successful = 0;
-                    let mut failed = 0;
-                    let mut completed = 0;
+                    let file_metadata = match tokio::fs::metadata(&file_path).await {
+                        Ok(metadata) if metadata.is_file() => metadata,
+                        _ => {
+                            let error_msg = ServerMessage::Error {
+                                message: "File not found".to_string(),
+                            };
+                            let _ = websocket_service.send_to_client(
+                                &client_id_clone,
+                                websocket_service.encode(error_msg),
+                            ).await;
+                            return;
+                        }
+                    };
+                    let filename = file_path
+                        .file_name()
+                        .and_then(|n| n.to_str())
+                        .unwrap_or("unknown")
+                        .to_string();
+                    let file_size = file_metadata.len();
 
-                    for peer in peer_list {
-                        let result = transfer_service
-                            .send_file(peer.address, file_path.clone())
-                            .await;
+                    let file_checksum = utils::calculate_file_checksum(&file_path).await.ok();
+                    let mime_type = utils::get_mime_type(&file_path);
+                    let detected_mime_type = utils::detect_mime_type(&file_path).await;
 
-                        completed += 1;
+                    // Each peer's send is a transfer of its own in history
+                    for ((target, peer_id), hostname) in targets.iter().zip(&peer_ids).zip(&hostnames) {
+                        let mut record = crate::history::TransferRecord::new(
+                            target.transfer.id(),
+                            Some(*peer_id),
+                            hostname.clone(),
+                            filename.clone(),
+                            file_path.to_string_lossy().to_string(),
+                            file_size,
+                            "sent".to_string(),
+                        );
+                        record.mime_type = mime_type.clone();
+                        record.detected_mime_type = detected_mime_type.clone();
+                        record.broadcast_id = Some(broadcast_id);
+                        record.excluded_peers = excluded.clone();
+                        history.start_transfer(record).await;
+                    }
+                    let starter = websocket_service.client_queue(&client_id_clone).await;
+                    let cancels = targets.iter().map(|target| target.transfer.id()).collect();
+                    websocket_service.watchers.start(broadcast_id, starter, cancels);
 
-                        match result {
-                            Ok(_) => {
-                                successful += 1;
-                            }
-                            Err(e) => {
-                                failed += 1;
-                                let error_msg = ServerMessage::FileTransferError {
-                                    transfer_id: broadcast_id,
-                                    peer_id: Some(peer.id),
-                                    message: e.to_string(),
-                                };
-                                let json = serde_json::to_string(&error_msg).unwrap_or_default();
-                                let _ = websocket_service.send_to_client(
-                                    &client_id_clone,
-                                    axum::extract::ws::Message::Text(json),
-                                ).await;
-                            }
-                        }
+                    let start_msg = ServerMessage::BroadcastTransferStart {
+                        transfer_id: broadcast_id,
+                        filename: filename.clone(),
+                        file_path: file_path.to_string_lossy().to_string(),
+                        file_size,
+                        total_peers,
+                        file_checksum: file_checksum.clone(),
+                        mime_type,
+                        detected_mime_type,
+                        excluded_peers: excluded,
+                        origin: Some(websocket_service.client_name(&client_id_clone).await),
+                    };
+                    websocket_service.deliver_watched(broadcast_id, start_msg, false).await;
 
-                        let progress_msg = ServerMessage::BroadcastTransferProgress {
-                            transfer_id: broadcast_id,
-                            completed_peers: completed,
-                            total_peers,
-                        };
-                        let json = serde_json::to_string(&progress_msg).unwrap_or_default();
-                        let _ = websocket_service.send_to_client(
-                            &client_id_clone,
-                            axum::extract::ws::Message::Text(json),
-                        ).await;
-                    }
+                    // Peers finish in any order; each is reported as it does
+                    let (results_tx, mut results) = mpsc::unbounded_channel();
+                    let broadcast = transfer_service.broadcast_file(&targets, &file_path, file_checksum, results_tx);
+                    let report = async {
+                        let mut successful = 0;
+                        let mut failed = 0;
+                        while let Some((index, result)) = results.recv().await {
+                            let transfer = &targets[index].transfer;
+                            let transfer_id = transfer.id();
+                            history.keep_samples(&transfer_id, transfer.samples()).await;
+                            let peer_id = Some(peer_ids[index]);
+                            let message = match result {
+                                Ok(outcome) => {
+                                    successful += 1;
+                                    history.complete_send(&outcome).await;
+                                    outcome.complete_message(broadcast_id, peer_id)
+                                }
+                                Err(e) => {
+                                    failed += 1;
+                                    if let Some(sample) = transfer.samples().snapshot().last() {
+                                        history.note_progress(&transfer_id, sample.bytes_transferred).await;
+                                    }
+                                    if transfer.is_cancelled() {
+                                        history.cancel_transfer(&transfer_id).await;
+                                    } else {
+                                        history.fail_transfer(&transfer_id, e.to_string(), TransferError::code_of(&e)).await;
+                                    }
+                                    ServerMessage::FileTransferError {
+                                        transfer_id: broadcast_id,
+                                        peer_id,
+                                        message: e.to_string(),
+                                        code: TransferError::code_of(&e),
+                                        peer_space: TransferError::peer_space_of(&e),
+                                    }
+                                }
+                            };
+                            websocket_service.deliver_watched(broadcast_id, message, false).await;
+
+                            let progress_msg = ServerMessage::BroadcastTransferProgress {
+                                transfer_id: broadcast_id,
+                                completed_peers: successful + failed,
+                                total_peers,
+                            };
+                            websocket_service
+                                .watchers
+                                .progress(broadcast_id, websocket_service.encode(progress_msg));
+                        }
+                        (successful, failed)
+                    };
+                    let (_, (successful, failed)) = tokio::join!(broadcast, report);
 
                     let complete_msg = ServerMessage::BroadcastTransferComplete {
                         transfer_id: broadcast_id,
                         successful_peers: successful,
                         failed_peers: failed,
                     };
-                    let json = serde_json::to_string(&complete_msg).unwrap_or_default();
-                    let _ = websocket_service.send_to_client(
-                        &client_id_clone,
-                        axum::extract::ws::Message::Text(json),
-                    ).await;
+                    websocket_service.deliver_watched(broadcast_id, complete_msg, true).await;
                 });
 
                 Ok(None)
             }
             ClientMessage::SendChat { peer_id, message } => {
-                let peers = self.peers.read().await;
-                let client_to_peer = self.client_to_peer.read().await;
-                let from_peer_id = client_to_peer.get(&client_id)
-                    .copied()
-                    .unwrap_or_else(|| peers.local_id());
-                let from_hostname = peers.local_hostname().to_string();
+                if let Err(rejection) = self.chat_limiter.check(client_id, &message) {
+                    let (message, retry_after_ms) = match rejection {
+                        ChatRejection::TooLong => (
+                            format!(
+                                "Chat message is {} bytes; the limit is {}",
+                                message.len(),
+                                self.config.chat.max_message_len
+                            ),
+                            None,
+                        ),
+                        ChatRejection::RateLimited(wait) => (
+                            "Too many chat messages; try again shortly".to_string(),
+                            Some(wait.as_millis() as u64),
+                        ),
+                    };
+                    return Ok(Some(ServerMessage::ChatRejected {
+                        code: rejection.code().to_string(),
+                        message,
+                        retry_after_ms,
+                    }));
+                }
+                let (local_id, from_hostname) = {
+                    let peers = self.peers.read().await;
+                    (peers.local_id(), peers.local_hostname().to_string())
+                };
+                // Snapshot who it's from and who gets it, so no lock is held
+                // while pushing to a slow client
+                let (from_peer_id, recipients) = {
+                    let connections = self.connections.read().await;
+                    let client_to_peer = self.client_to_peer.read().await;
+                    let from_peer_id = client_to_peer.get(&client_id).copied().unwrap_or(local_id);
+                    let recipients: Option<Vec<Arc<ClientQueue>>> = peer_id.map(|target_peer_id| {
+                        client_to_peer
+                            .iter()
+                            .filter(|(cid, peer_id_map)| **peer_id_map == target_peer_id || **cid == client_id)
+                            .filter_map(|(cid, _)| connections.get(cid).cloned())
+                            .collect()
+                    });
+                    (from_peer_id, recipients)
+                };
 
                 let timestamp = std::time::SystemTime::now()
                     .duration_since(std::time::UNIX_EPOCH)
@@ -316,22 +1183,15 @@ impl WebSocketService {
                     timestamp,
                 };
 
-                let json = serde_json::to_string(&chat_msg).unwrap_or_default();
-                let ws_msg = axum::extract::ws::Message::Text(json.clone());
+                let ws_msg = self.encode(chat_msg);
 
-                if let Some(target_peer_id) = peer_id {
-                    let connections = self.connections.read().await;
-                    let client_to_peer = self.client_to_peer.read().await;
-                    
-                    for (cid, peer_id_map) in client_to_peer.iter() {
-                        if *peer_id_map == target_peer_id || *cid == client_id {
-                            if let Some(tx) = connections.get(cid) {
-                                let _ = tx.send(ws_msg.clone());
-                            }
+                match recipients {
+                    Some(recipients) => {
+                        for queue in recipients {
+                            let _ = queue.push(ws_msg.clone()).await;
                         }
                     }
-                } else {
-                    self.broadcast_to_all(ws_msg).await;
+                    None => self.broadcast_to_all(ws_msg).await,
                 }
 
                 Ok(None)
@@ -342,63 +1202,876 @@ impl WebSocketService {
                     transfers: history_entries,
                 }))
             }
-            ClientMessage::GetTransferStats { transfer_id } => {
-                if let Some(record) = self.history.get_transfer(&transfer_id).await {
-                    Ok(Some(ServerMessage::TransferStats {
+            ClientMessage::GetTransferSamples { transfer_id } => {
+                if let Some(samples) = self.transfer_service.active_transfers().samples_of(&transfer_id) {
+                    return Ok(Some(ServerMessage::TransferSamples {
+                        transfer_id,
+                        active: true,
+                        samples,
+                    }));
+                }
+                match self.history.samples_of(&transfer_id).await {
+                    Some(samples) => Ok(Some(ServerMessage::TransferSamples {
+                        transfer_id,
+                        active: false,
+                        samples,
+                    })),
+                    None => bail!("No speed samples for transfer {}", transfer_id),
+                }
+            }
+            ClientMessage::GetTransferTrace { transfer_id } => {
+                if let Some(events) = self.transfer_service.active_transfers().trace_of(&transfer_id) {
+                    return Ok(Some(ServerMessage::TransferTrace {
                         transfer_id,
-                        status: record.status,
-                        progress: 0, // Would need to track this separately
-                        total: record.file_size,
-                        speed_bytes_per_sec: record.speed_bytes_per_sec,
-                        eta_seconds: None, // Would need to calculate
-                        start_time: record.start_time,
-                    }))
-                } else {
-                    Ok(Some(ServerMessage::Error {
-                        message: "Transfer not found".to_string(),
-                    }))
+                        active: true,
+                        events,
+                    }));
+                }
+                match self.history.trace_of(&transfer_id).await {
+                    Some(events) => Ok(Some(ServerMessage::TransferTrace {
+                        transfer_id,
+                        active: false,
+                        events,
+                    })),
+                    None => bail!(
+                        "No trace for transfer {}; send with debug, or set transfer.trace_transfers",
+                        transfer_id
+                    ),
+                }
+            }
+            ClientMessage::RunMaintenance { dry_run } => {
+                let report = self.transfer_service.run_maintenance(dry_run).await?;
+                Ok(Some(ServerMessage::MaintenanceReport { report }))
+            }
+            ClientMessage::VerifyDownload {
+                transfer_id,
+                relative_path,
+            } => {
+                if transfer_id.is_none() && relative_path.is_none() {
+                    bail!("Give the transfer_id or relative_path of the download to verify");
+                }
+                let downloads_dir = self.transfer_service.downloads_dir();
+                let target = match verify::locate(&self.history, &downloads_dir, transfer_id, relative_path.clone()).await
+                {
+                    Ok(target) => target,
+                    Err(e) => {
+                        return Ok(Some(ServerMessage::VerificationError {
+                            transfer_id,
+                            relative_path,
+                            code: e.code().to_string(),
+                            message: e.message(),
+                        }))
+                    }
+                };
+                self.spawn_verification(client_id, target).await;
+                Ok(None)
+            }
+            ClientMessage::ExportHistory { redact } => {
+                let redaction = privacy::Redaction::for_export(&self.config.privacy, redact);
+                let hostnames: Vec<String> = {
+                    let peers = self.peers.read().await;
+                    std::iter::once(peers.local_hostname().to_string())
+                        .chain(peers.list_peers().into_iter().map(|peer| peer.hostname))
+                        .collect()
+                };
+                let transfers = self
+                    .history
+                    .export()
+                    .await
+                    .into_iter()
+                    .map(|record| {
+                        let mut hostnames = hostnames.clone();
+                        hostnames.push(record.peer_hostname.clone());
+                        privacy::redact_record(record, redaction, &hostnames)
+                    })
+                    .collect();
+                Ok(Some(ServerMessage::HistoryExport { transfers }))
+            }
+            ClientMessage::PruneHistory { keep_last, older_than_days } => {
+                let older_than = older_than_days.map(|days| chrono::Duration::days(days.into()));
+                let removed = self.history.prune(keep_last, older_than).await;
+                self.history.save().await?;
+                tracing::info!("Pruned {} entries from transfer history", removed);
+                Ok(Some(ServerMessage::HistoryPruned { removed }))
+            }
+            ClientMessage::SetHistoryMaxEntries { max_entries } => {
+                let removed = self.history.set_max_entries(max_entries).await;
+                self.history.save().await?;
+                // Starts from the file so changes saved since startup are kept
+                let mut config = AppConfig::load()?;
+                config.history.max_entries = max_entries;
+                config
+                    .save()
+                    .map_err(|e| anyhow::anyhow!("History cap is in effect but couldn't be saved: {}", e))?;
+                tracing::info!("History cap set to {} ({} entries pruned)", max_entries, removed);
+                Ok(Some(ServerMessage::HistoryPruned { removed }))
+            }
+            ClientMessage::GetConfig => Ok(Some(ServerMessage::Config {
+                config: privacy::masked_config(&self.config),
+            })),
+            ClientMessage::GetActiveTransfers => {
+                let transfers = self
+                    .history
+                    .get_active_transfers()
+                    .await
+                    .iter()
+                    .map(|record| record.to_history_entry())
+                    .collect();
+                Ok(Some(ServerMessage::ActiveTransfers {
+                    transfers,
+                    sends: self.transfer_service.send_slot_usage(),
+                    receives: self.transfer_service.receive_slot_usage(),
+                }))
+            }
+            ClientMessage::GetBandwidthUsage { since, group_by } => {
+                let usage = self.transfer_service.usage();
+                Ok(Some(ServerMessage::BandwidthUsage {
+                    rows: usage.report(since, group_by),
+                    today_bytes: usage.used_today(),
+                    daily_budget_bytes: usage.daily_budget(),
+                }))
+            }
+            ClientMessage::GetHistoryAnalytics { since_unix } => {
+                let since = match since_unix {
+                    Some(secs) => match chrono::DateTime::from_timestamp(secs, 0) {
+                        Some(since) => Some(since),
+                        None => {
+                            return Ok(Some(ServerMessage::Error {
+                                message: "since_unix is out of range".to_string(),
+                            }))
+                        }
+                    },
+                    None => None,
+                };
+                Ok(Some(ServerMessage::HistoryAnalytics {
+                    analytics: self.history.analytics(since).await,
+                }))
+            }
+            ClientMessage::SetTransferLimits {
+                max_concurrent_sends,
+                max_concurrent_receives,
+                persist,
+            } => {
+                let (sends, receives) = self
+                    .transfer_service
+                    .set_slot_limits(max_concurrent_sends, max_concurrent_receives)?;
+                tracing::info!("Concurrency limits set to {} sends, {} receives", sends.limit, receives.limit);
+                if persist {
+                    // Starts from the file so changes saved since startup are kept
+                    let mut config = AppConfig::load()?;
+                    if max_concurrent_sends.is_some() {
+                        config.transfer.max_concurrent_sends = max_concurrent_sends;
+                    }
+                    if max_concurrent_receives.is_some() {
+                        config.transfer.max_concurrent_receives = max_concurrent_receives;
+                    }
+                    config
+                        .save()
+                        .map_err(|e| anyhow::anyhow!("Limits are in effect but couldn't be saved: {}", e))?;
+                }
+                Ok(Some(ServerMessage::TransferLimits { sends, receives }))
+            }
+            ClientMessage::SetBandwidthLimit { max_up, persist } => {
+                let bandwidth = self.transfer_service.bandwidth().set_max_up(max_up);
+                if persist {
+                    let mut config = AppConfig::load()?;
+                    config.bandwidth.max_up = max_up;
+                    config
+                        .save()
+                        .map_err(|e| anyhow::anyhow!("Limit is in effect but couldn't be saved: {}", e))?;
                 }
+                Ok(Some(ServerMessage::BandwidthLimit { bandwidth }))
+            }
+            ClientMessage::GetTransferStats { transfer_id } => Ok(Some(self.transfer_stats(transfer_id).await)),
+            ClientMessage::WatchTransfer { transfer_id } => {
+                let Some(queue) = self.client_queue(&client_id).await else { return Ok(None) };
+                if let Some(Some(latest)) = self.watchers.watch(transfer_id, queue.clone()) {
+                    queue.push_progress(transfer_id, latest);
+                }
+                Ok(Some(self.transfer_stats(transfer_id).await))
             }
             ClientMessage::CancelTransfer { transfer_id } => {
-                self.history.cancel_transfer(&transfer_id).await;
+                let active = self.transfer_service.active_transfers();
+                let mut outcomes = Vec::new();
+                for direction in active.directions_of(&transfer_id) {
+                    outcomes.push(active.cancel(transfer_id, direction).await);
+                }
+                // A record that isn't running, e.g. a paused one
+                let recorded = self.history.cancel_transfer(&transfer_id).await;
+                if outcomes.contains(&CancelOutcome::Stopping) {
+                    bail!("Transfer {} was asked to stop but is still winding down", transfer_id);
+                }
+                if !recorded && !outcomes.contains(&CancelOutcome::Cancelled) {
+                    return Err(self.not_running(transfer_id).await);
+                }
                 Ok(Some(ServerMessage::TransferCancelled { transfer_id }))
             }
+            ClientMessage::CancelAllTransfers { direction } => {
+                let direction = direction.as_deref().map(str::parse::<Direction>).transpose()?;
+                let results: Vec<CancelResult> = self
+                    .transfer_service
+                    .active_transfers()
+                    .cancel_all(direction)
+                    .await
+                    .into_iter()
+                    .map(|(transfer_id, direction, outcome)| CancelResult {
+                        transfer_id,
+                        direction: direction.as_str().to_string(),
+                        status: outcome.as_str().to_string(),
+                    })
+                    .collect();
+                tracing::info!("Cancelled {} transfers", results.len());
+                Ok(Some(ServerMessage::TransfersCancelled { results }))
+            }
             ClientMessage::PauseTransfer { transfer_id } => {
+                if !self.set_paused(transfer_id, true) {
+                    return Err(self.not_running(transfer_id).await);
+                }
                 self.history.pause_transfer(&transfer_id).await;
                 Ok(Some(ServerMessage::TransferPaused { transfer_id }))
             }
             ClientMessage::ResumeTransfer { transfer_id } => {
+                if !self.set_paused(transfer_id, false) {
+                    return Err(self.not_running(transfer_id).await);
+                }
                 self.history.resume_transfer(&transfer_id).await;
                 Ok(Some(ServerMessage::TransferResumed { transfer_id }))
             }
-            ClientMessage::SendDirectory { peer_id, dir_path } => {
+            ClientMessage::SendDirectory { peer_id: _, dir_path: _ } => {
                 // Directory transfer would require archiving - for now return error
                 Ok(Some(ServerMessage::Error {
                     message: "Directory transfer not yet implemented. Please archive the directory first.".to_string(),
                 }))
             }
-            ClientMessage::BroadcastDirectory { dir_path: _ } => {
+            ClientMessage::BroadcastDirectory { .. } => {
                 Ok(Some(ServerMessage::Error {
                     message: "Directory broadcast not yet implemented. Please archive the directory first.".to_string(),
                 }))
             }
+            ClientMessage::AcceptPeerIdentity { peer_id } => {
+                let fingerprint = self.peers.write().await.accept_identity(&peer_id)?;
+                tracing::info!("Accepted new fingerprint {} for peer {}", fingerprint, peer_id);
+                Ok(Some(ServerMessage::PeerIdentityAccepted { peer_id, fingerprint }))
+            }
+            ClientMessage::GetAcceptRules => {
+                let approvals = self.transfer_service.approvals();
+                Ok(Some(ServerMessage::AcceptRules {
+                    rules: approvals.rules(),
+                    default_action: approvals.default_action(),
+                }))
+            }
+            ClientMessage::SetAcceptRules { rules } => {
+                let approvals = self.transfer_service.approvals();
+                approvals.set_rules(rules)?;
+                tracing::info!("Accept rules updated ({} rules)", approvals.rules().len());
+                Ok(Some(ServerMessage::AcceptRules {
+                    rules: approvals.rules(),
+                    default_action: approvals.default_action(),
+                }))
+            }
+            ClientMessage::GetSyncStatus => Ok(Some(ServerMessage::SyncStatus {
+                pairs: self.sync.status(),
+            })),
+            ClientMessage::AddSyncPair { pair } => {
+                let name = pair.name.clone();
+                self.sync.add_pair(pair)?;
+                tracing::info!("Synced folder {:?} added", name);
+                Ok(Some(ServerMessage::SyncStatus {
+                    pairs: self.sync.status(),
+                }))
+            }
+            ClientMessage::RemoveSyncPair { name } => {
+                self.sync.remove_pair(&name)?;
+                tracing::info!("Synced folder {:?} removed", name);
+                Ok(Some(ServerMessage::SyncStatus {
+                    pairs: self.sync.status(),
+                }))
+            }
+            ClientMessage::GetWebhookStatus => Ok(Some(ServerMessage::WebhookStatus {
+                webhooks: self.webhooks.status(),
+            })),
+            ClientMessage::BrowsePeerShare { peer_id, path } => {
+                let peer = self.peers.read().await.get_peer(&peer_id).cloned();
+                let Some(peer) = peer else {
+                    return Ok(Some(ServerMessage::Error {
+                        message: "Peer not found".to_string(),
+                    }));
+                };
+                // The peer may take a while; answer when it does without
+                // holding up this client's other requests
+                let websocket_service = self.clone();
+                tokio::spawn(async move {
+                    let message = match websocket_service.transfer_service.browse_share(peer.address, &path).await {
+                        Ok(entries) => ServerMessage::PeerShareListing { peer_id, path, entries },
+                        Err(e) => ServerMessage::Error { message: e.to_string() },
+                    };
+                    let _ = websocket_service.send_to_client(&client_id, websocket_service.encode(message)).await;
+                });
+                Ok(None)
+            }
+            ClientMessage::CheckPeer { peer_id } => {
+                let websocket_service = self.clone();
+                tokio::spawn(async move {
+                    let result = websocket_service.transfer_service.check_peer(peer_id).await;
+                    let message = ServerMessage::PeerReachability { result };
+                    let _ = websocket_service.send_to_client(&client_id, websocket_service.encode(message)).await;
+                });
+                Ok(None)
+            }
+            ClientMessage::QueryPeerSpace { peer_id } => {
+                let peer = self.peers.read().await.get_peer(&peer_id).cloned();
+                let Some(peer) = peer else {
+                    return Ok(Some(ServerMessage::Error {
+                        message: "Peer not found".to_string(),
+                    }));
+                };
+                let websocket_service = self.clone();
+                tokio::spawn(async move {
+                    let message = match websocket_service.transfer_service.query_space(Route::Direct(peer.address)).await {
+                        Ok(space) => ServerMessage::PeerSpace { peer_id, space },
+                        Err(e) => ServerMessage::Error { message: e.to_string() },
+                    };
+                    let _ = websocket_service.send_to_client(&client_id, websocket_service.encode(message)).await;
+                });
+                Ok(None)
+            }
+            ClientMessage::BenchmarkPeer {
+                peer_id,
+                duration_secs,
+                payload_size,
+            } => {
+                let peer = self.peers.read().await.get_peer(&peer_id).cloned();
+                let Some(peer) = peer else {
+                    return Ok(Some(ServerMessage::Error {
+                        message: "Peer not found".to_string(),
+                    }));
+                };
+                let duration = std::time::Duration::from_secs(duration_secs.unwrap_or(5).max(1));
+                let payload_size = payload_size.unwrap_or(1024 * 1024 * 1024);
+                let websocket_service = self.clone();
+                tokio::spawn(async move {
+                    let benchmark = websocket_service.transfer_service.benchmark_peer(peer_id, duration, payload_size).await;
+                    let message = match benchmark {
+                        Ok(result) => {
+                            let benchmark_id = Uuid::new_v4();
+                            websocket_service.history.record_benchmark(benchmark_id, peer.hostname, result).await;
+                            ServerMessage::PeerBenchmark { benchmark_id, result }
+                        }
+                        Err(e) => ServerMessage::Error { message: e.to_string() },
+                    };
+                    let _ = websocket_service.send_to_client(&client_id, websocket_service.encode(message)).await;
+                });
+                Ok(None)
+            }
+            ClientMessage::DownloadFromPeer { peer_id, relative_path } => {
+                let peer = self.peers.read().await.get_peer(&peer_id).cloned();
+                let Some(peer) = peer else {
+                    return Ok(Some(ServerMessage::Error {
+                        message: "Peer not found".to_string(),
+                    }));
+                };
+                if peer.identity_changed {
+                    return Ok(Some(ServerMessage::Error {
+                        message: "Peer identity changed; accept its new fingerprint before downloading".to_string(),
+                    }));
+                }
+
+                let transfer_id = Uuid::new_v4();
+                let filename = utils::safe_filename(&relative_path);
+                let history_record = crate::history::TransferRecord::new(
+                    transfer_id,
+                    Some(peer_id),
+                    peer.hostname.clone(),
+                    filename,
+                    relative_path.clone(),
+                    0,
+                    "received".to_string(),
+                );
+                self.history.start_transfer(history_record).await;
+                let transfer = self.transfer_service.track_receive(transfer_id);
+                if self.config.transfer.trace_transfers {
+                    transfer.trace().enable();
+                }
+
+                let transfer_service = self.transfer_service.clone();
+                let history = self.history.clone();
+                let websocket_service = self.clone();
+                let fetch_path = relative_path.clone();
+
+                tokio::spawn(async move {
+                    let result = transfer_service.fetch_shared(&transfer, peer.address, &fetch_path).await;
+                    history.keep_samples(&transfer_id, transfer.samples()).await;
+                    let message = match result {
+                        Ok(received) => {
+                            history.set_offered_file(&transfer_id, received.filename, received.file_size).await;
+                            if received.encrypted {
+                                history.mark_encrypted(&transfer_id).await;
+                            }
+                            history
+                                .complete_transfer(&transfer_id, received.checksum.clone(), received.verified)
+                                .await;
+                            ServerMessage::FileTransferComplete {
+                                transfer_id,
+                                peer_id: Some(peer_id),
+                                file_checksum: received.checksum,
+                                verified: received.verified,
+                                bytes_transferred: Some(received.received),
+                                duration_ms: None,
+                                speed_bytes_per_sec: None,
+                            }
+                        }
+                        Err(e) => {
+                            if let Some(sample) = transfer.samples().snapshot().last() {
+                                history.note_progress(&transfer_id, sample.bytes_transferred).await;
+                            }
+                            if transfer.is_cancelled() {
+                                history.cancel_transfer(&transfer_id).await;
+                            } else {
+                                transfer.trace().record("failed", e.to_string());
+                                history.keep_trace(&transfer_id, transfer.trace()).await;
+                                history.fail_transfer(&transfer_id, e.to_string(), TransferError::code_of(&e)).await;
+                            }
+                            ServerMessage::FileTransferError {
+                                transfer_id,
+                                peer_id: Some(peer_id),
+                                message: e.to_string(),
+                                code: TransferError::code_of(&e),
+                                peer_space: TransferError::peer_space_of(&e),
+                            }
+                        }
+                    };
+                    let _ = websocket_service.send_to_client(&client_id, websocket_service.encode(message)).await;
+                });
+
+                Ok(Some(ServerMessage::ShareDownloadStarted {
+                    transfer_id,
+                    peer_id,
+                    relative_path,
+                }))
+            }
+            ClientMessage::RespondToTransfer { transfer_id, accept } => {
+                self.transfer_service.approvals().respond(&transfer_id, accept)?;
+                Ok(None)
+            }
+            ClientMessage::RegisterClient { name, kind } => {
+                let name = name.trim();
+                if name.is_empty() || name.chars().count() > MAX_CLIENT_NAME_LEN {
+                    bail!("Client name must be 1 to {} characters", MAX_CLIENT_NAME_LEN);
+                }
+                let client = {
+                    let mut clients = self.clients.write().await;
+                    let Some(client) = clients.get_mut(&client_id) else {
+                        bail!("Client is not connected");
+                    };
+                    tracing::info!("WebSocket client {} registered as {}", client.name, name);
+                    client.name = name.to_string();
+                    client.kind = kind;
+                    client.registered = true;
+                    client.clone()
+                };
+                Ok(Some(ServerMessage::ClientRegistered { client }))
+            }
+            ClientMessage::GetClients => {
+                let mut clients: Vec<ClientInfo> = self.clients.read().await.values().cloned().collect();
+                clients.sort_by_key(|client| client.connected_since);
+                Ok(Some(ServerMessage::Clients { clients }))
+            }
             ClientMessage::Ping => Ok(Some(ServerMessage::Pong)),
         }
     }
 
+    /// Tells clients about a new peer.
     pub async fn notify_peer_discovered(&self, peer: PeerInfo) {
         let message = ServerMessage::PeerDiscovered { peer };
-        let json = serde_json::to_string(&message).unwrap_or_default();
-        self.broadcast_to_all(Message::Text(json)).await;
+        self.broadcast_to_all(self.encode(message)).await;
     }
 
+    /// Tells clients a known peer moved or was renamed.
+    pub async fn notify_peer_updated(&self, peer: PeerInfo) {
+        let message = ServerMessage::PeerUpdated { peer };
+        self.broadcast_to_all(self.encode(message)).await;
+    }
+
+    /// A snapshot of this node for diagnostics.
+    pub async fn system_info(&self) -> SystemInfo {
+        let network = &self.config.network;
+        let config = ConfigSummary {
+            discovery_port: self.discovery_addr().map_or(network.discovery_port, |addr| addr.port()),
+            transfer_port: self
+                .transfer_service
+                .local_addr()
+                .map_or(network.transfer_port, |addr| addr.port()),
+            web_port: self.local_addr().map_or(network.web_port, |addr| addr.port()),
+            chunk_size: self.config.transfer.chunk_size,
+            sends: self.transfer_service.send_slot_usage(),
+            receives: self.transfer_service.receive_slot_usage(),
+            bandwidth: self.transfer_service.bandwidth().current(),
+        };
+        SystemInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_hash: option_env!("P2P_SHARING_GIT_HASH").map(str::to_string),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            config,
+            peers: self.peers.read().await.list_peers().len(),
+            active_transfers: self.transfer_service.active_transfers().snapshot(None).len(),
+            websocket_clients: self.connections.read().await.len(),
+            history_entries: self.history.entry_count().await,
+            downloads_free_bytes: utils::available_space(&self.transfer_service.downloads_dir())
+                .ok()
+                .map(|space| space.available),
+            services: self.service_status.read().unwrap().clone(),
+        }
+    }
+
+    /// Tells clients a service started, stopped or gave up.
+    pub async fn notify_service_status(&self, service: &str, status: &str, message: Option<String>) {
+        {
+            let mut statuses = self.service_status.write().unwrap();
+            let health = statuses.entry(service.to_string()).or_default();
+            health.status = status.to_string();
+            if message.is_some() {
+                health.last_error = message.clone();
+            }
+        }
+        let message = ServerMessage::ServiceStatusChanged {
+            service: service.to_string(),
+            status: status.to_string(),
+            message,
+        };
+        self.broadcast_to_all(self.encode(message)).await;
+    }
+
+    /// Tells clients a peer went away.
     pub async fn notify_peer_removed(&self, peer_id: Uuid) {
         let message = ServerMessage::PeerRemoved { peer_id };
-        let json = serde_json::to_string(&message).unwrap_or_default();
-        self.broadcast_to_all(Message::Text(json)).await;
+        self.broadcast_to_all(self.encode(message)).await;
+    }
+
+    /// Tells clients a peer presented an unexpected identity key.
+    pub async fn notify_peer_identity_changed(
+        &self,
+        peer_id: Uuid,
+        hostname: String,
+        previous_fingerprint: String,
+        fingerprint: String,
+    ) {
+        let message = ServerMessage::PeerIdentityChanged {
+            peer_id,
+            hostname,
+            previous_fingerprint,
+            fingerprint,
+        };
+        self.broadcast_to_all(self.encode(message)).await;
+    }
+
+    /// How `transfer_id` is going or went: TransferStats, or BroadcastStats
+    /// for a broadcast.
+    async fn transfer_stats(&self, transfer_id: Uuid) -> ServerMessage {
+        let Some(record) = self.history.get_transfer(&transfer_id).await else {
+            let children = self.history.broadcast_children(&transfer_id).await;
+            if !children.is_empty() {
+                return self.broadcast_stats(transfer_id, children);
+            }
+            return ServerMessage::Error {
+                message: "Transfer not found".to_string(),
+            };
+        };
+        let (progress, speed_bytes_per_sec, eta_seconds) = self.live_stats(&record);
+        ServerMessage::TransferStats {
+            transfer_id,
+            progress,
+            total: record.file_size,
+            speed_bytes_per_sec,
+            eta_seconds,
+            start_time: record.start_time,
+            end_time: record.end_time,
+            duration_seconds: record.duration_seconds,
+            average_speed_bytes_per_sec: record.average_speed(),
+            verified: (record.status == "completed").then_some(record.verified),
+            error: record.error,
+            status: record.status,
+        }
+    }
+
+    /// Bytes moved, speed and seconds left for `record`: as far as a running
+    /// transfer has got by its latest speed sample, or as far as a finished
+    /// one got.
+    fn live_stats(&self, record: &crate::history::TransferRecord) -> (u64, Option<u64>, Option<u64>) {
+        if record.end_time.is_some() {
+            return (record.bytes_transferred.unwrap_or(0), record.speed_bytes_per_sec, None);
+        }
+        let samples = self
+            .transfer_service
+            .active_transfers()
+            .samples_of(&record.transfer_id)
+            .unwrap_or_default();
+        let progress = samples
+            .last()
+            .map(|sample| sample.bytes_transferred)
+            .or(record.bytes_transferred)
+            .unwrap_or(0);
+        let speed = record.speed_bytes_per_sec.or_else(|| match samples.as_slice() {
+            [.., before, latest] => {
+                let elapsed = (latest.timestamp - before.timestamp).num_milliseconds();
+                let moved = latest.bytes_transferred.saturating_sub(before.bytes_transferred);
+                (elapsed > 0).then(|| moved * 1000 / elapsed as u64)
+            }
+            _ => None,
+        });
+        let eta_seconds = speed
+            .filter(|speed| *speed > 0)
+            .map(|speed| record.file_size.saturating_sub(progress) / speed);
+        (progress, speed, eta_seconds)
+    }
+
+    /// Progress of broadcast `broadcast_id` over its per-peer `children`.
+    fn broadcast_stats(&self, broadcast_id: Uuid, children: Vec<crate::history::TransferRecord>) -> ServerMessage {
+        let (mut completed_peers, mut failed_peers, mut in_progress_peers) = (0, 0, 0);
+        let (mut progress, mut total) = (0, 0);
+        let (mut speed_bytes_per_sec, mut eta_seconds) = (None, None);
+        for child in &children {
+            let (moved, speed, eta) = self.live_stats(child);
+            progress += moved;
+            total += child.file_size;
+            match child.status.as_str() {
+                "completed" => completed_peers += 1,
+                "in_progress" | "paused" => {
+                    in_progress_peers += 1;
+                    if let Some(speed) = speed {
+                        speed_bytes_per_sec = Some(speed_bytes_per_sec.unwrap_or(0) + speed);
+                    }
+                    // The broadcast is done when its slowest peer is
+                    eta_seconds = eta_seconds.max(eta);
+                }
+                _ => failed_peers += 1,
+            }
+        }
+        ServerMessage::BroadcastStats {
+            transfer_id: broadcast_id,
+            total_peers: children.len(),
+            completed_peers,
+            failed_peers,
+            in_progress_peers,
+            progress,
+            total,
+            speed_bytes_per_sec,
+            eta_seconds,
+        }
+    }
+
+    /// Who sent an incoming transfer: the origin of a relayed one, or else
+    /// the peer at the address it connected from.
+    async fn sender_of(&self, sender: SocketAddr, relayed_from: Option<&RelayedPeer>) -> (Option<Uuid>, String) {
+        match relayed_from {
+            Some(origin) => (Some(origin.peer_id), origin.hostname.clone()),
+            None => {
+                let peers = self.peers.read().await;
+                match peers.find_by_ip(sender.ip()) {
+                    Some(peer) => (Some(peer.id), peer.hostname.clone()),
+                    None => (None, sender.ip().to_string()),
+                }
+            }
+        }
+    }
+
+    /// Builds the history record for an incoming transfer, timed from when
+    /// it was let in if it has been recorded since.
+    async fn received_record(&self, sender: SocketAddr, progress: ReceiveProgress) -> crate::history::TransferRecord {
+        let (peer_id, peer_hostname) = self.sender_of(sender, progress.relayed_from.as_ref()).await;
+
+        let mut record = crate::history::TransferRecord::new(
+            progress.transfer_id,
+            peer_id,
+            peer_hostname,
+            progress.filename,
+            progress
+                .saved_path
+                .as_ref()
+                .map(|path| path.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            progress.file_size,
+            "received".to_string(),
+        );
+        if let Some(started) = self.history.get_transfer(&progress.transfer_id).await {
+            record.timestamp = started.timestamp;
+            record.start_time = started.start_time;
+        }
+        record.bytes_transferred = Some(progress.received);
+        record.encrypted = progress.encrypted;
+        record.accept_rule = progress.accept_rule;
+        record.file_mode = progress.file_mode.map(|mode| mode.to_string());
+        record.relative_path = progress.relative_path;
+        record.dedup_saved_bytes = progress.deduplicated.then_some(progress.file_size);
+        record.connectivity = Some(progress.connectivity);
+        record.session_id = progress.session_id;
+        record
+    }
+
+    /// The record a session keeps of itself, beside those of its files.
+    fn session_record(
+        session_id: Uuid,
+        peer_id: Option<Uuid>,
+        peer_hostname: String,
+        files: &[ManifestEntry],
+        direction: &str,
+    ) -> crate::history::TransferRecord {
+        let mut record = crate::history::TransferRecord::new(
+            session_id,
+            peer_id,
+            peer_hostname,
+            format!("{} files", files.len()),
+            String::new(),
+            files.iter().map(|file| file.file_size).sum(),
+            direction.to_string(),
+        );
+        record.session_files = Some(files.len());
+        record
+    }
+
+    /// Records a session a peer started sending us and tells every client.
+    pub async fn notify_session_started(&self, sender: SocketAddr, session: &ReceiveSession) {
+        let (peer_id, peer_hostname) = self.sender_of(sender, session.relayed_from.as_ref()).await;
+        let record = Self::session_record(session.session_id, peer_id, peer_hostname, &session.files, "received");
+        let total_size = record.file_size;
+        self.history.start_transfer(record).await;
+        let message = ServerMessage::SessionTransferStart {
+            session_id: session.session_id,
+            peer_id,
+            direction: "received".to_string(),
+            files: session.files.clone(),
+            total_size,
+        };
+        self.broadcast_to_all(self.encode(message)).await;
+    }
+
+    /// Tells every client another file of an incoming session is done.
+    pub async fn notify_session_progress(&self, session: &ReceiveSession) {
+        let message = ServerMessage::SessionTransferProgress {
+            session_id: session.session_id,
+            completed_files: session.completed,
+            failed_files: session.failed,
+            total_files: session.files.len(),
+            bytes_transferred: session.bytes_received,
+            total_size: session.files.iter().map(|file| file.file_size).sum(),
+        };
+        self.broadcast_to_all(self.encode(message)).await;
+    }
+
+    /// Finishes an incoming session's record and tells every client.
+    pub async fn notify_session_finished(&self, sender: SocketAddr, session: &ReceiveSession) {
+        tracing::debug!("Session {} from {} finished", session.session_id, sender);
+        self.history.finish_session(&session.session_id).await;
+        let message = ServerMessage::SessionTransferComplete {
+            session_id: session.session_id,
+            successful_files: session.completed,
+            failed_files: session.files.len() - session.completed,
+        };
+        self.broadcast_to_all(self.encode(message)).await;
+    }
+
+    /// Tells whoever watches send `transfer_id` its place in line for a slot.
+    pub fn notify_send_queued(&self, transfer_id: Uuid, position: usize) {
+        let message = ServerMessage::TransferQueued { transfer_id, position };
+        self.watchers.progress(transfer_id, self.encode(message));
+    }
+
+    /// Records an incoming transfer we let in, as running until it ends.
+    pub async fn notify_receive_started(&self, sender: SocketAddr, progress: &ReceiveProgress) {
+        let record = self.received_record(sender, progress.clone()).await;
+        self.history.start_transfer(record).await;
+    }
+
+    /// Records a file a peer sent us, with the checksum it arrived with so
+    /// it can be verified later, shows a desktop notification for it and
+    /// tells every client.
+    pub async fn notify_received(&self, sender: SocketAddr, progress: &ReceiveProgress) {
+        let transfer_id = progress.transfer_id;
+        let checksum = progress.checksum.clone();
+        let verified = progress.verified;
+        let record = self.received_record(sender, progress.clone()).await;
+        self.notifier.notify(Notification {
+            hostname: record.peer_hostname.clone(),
+            filename: record.filename.clone(),
+            size: record.file_size,
+            status: NotificationStatus::Received,
+        });
+        let message = ServerMessage::FileReceived {
+            transfer_id,
+            from_peer_id: record.peer_id,
+            from_hostname: record.peer_hostname.clone(),
+            filename: record.filename.clone(),
+            saved_path: progress
+                .saved_path
+                .as_ref()
+                .map(|path| path.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            relative_path: progress.relative_path.clone(),
+            file_size: record.file_size,
+            mime_type: progress.mime_type.clone(),
+            verified,
+        };
+        self.history.start_transfer(record).await;
+        self.history.keep_samples(&transfer_id, &progress.samples).await;
+        self.history.complete_transfer(&transfer_id, checksum, verified).await;
+        self.broadcast_to_all(self.encode(message)).await;
+    }
+
+    /// Records an incoming transfer that stopped part way and tells every client.
+    pub async fn notify_receive_failed(&self, sender: SocketAddr, progress: ReceiveProgress, error: &anyhow::Error) {
+        let (code, error) = (TransferError::code_of(error), error.to_string());
+        let transfer_id = progress.transfer_id;
+        let samples = progress.samples.clone();
+        let trace = progress.trace.clone();
+        trace.record("failed", error.clone());
+        let record = self.received_record(sender, progress).await;
+        let peer_id = record.peer_id;
+        self.notifier.notify(Notification {
+            hostname: record.peer_hostname.clone(),
+            filename: record.filename.clone(),
+            size: record.file_size,
+            status: NotificationStatus::Failed,
+        });
+        self.history.start_transfer(record).await;
+        self.history.keep_samples(&transfer_id, &samples).await;
+        self.history.keep_trace(&transfer_id, &trace).await;
+        self.history.fail_transfer(&transfer_id, error.clone(), code.clone()).await;
+
+        let message = ServerMessage::FileTransferError {
+            transfer_id,
+            peer_id,
+            message: error,
+            code,
+            peer_space: None,
+        };
+        self.broadcast_to_all(self.encode(message)).await;
+    }
+
+    /// Records an incoming transfer we cancelled and tells every client.
+    pub async fn notify_receive_cancelled(&self, sender: SocketAddr, progress: ReceiveProgress) {
+        let transfer_id = progress.transfer_id;
+        let samples = progress.samples.clone();
+        let record = self.received_record(sender, progress).await;
+        self.history.start_transfer(record).await;
+        self.history.keep_samples(&transfer_id, &samples).await;
+        self.history.cancel_transfer(&transfer_id).await;
+        self.broadcast_to_all(self.encode(ServerMessage::TransferCancelled { transfer_id })).await;
+    }
+
+    /// Records an incoming transfer we declined, with the rule that decided it.
+    pub async fn notify_receive_declined(&self, sender: SocketAddr, progress: ReceiveProgress) {
+        let transfer_id = progress.transfer_id;
+        let record = self.received_record(sender, progress).await;
+        self.history.start_transfer(record).await;
+        self.history.reject_transfer(&transfer_id).await;
     }
 }
 
+async fn health_handler(State(service): State<Arc<WebSocketService>>) -> Json<SystemInfo> {
+    Json(service.system_info().await)
+}
+
 async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(service): State<Arc<WebSocketService>>,
@@ -408,22 +2081,23 @@ async fn websocket_handler(
 
 async fn handle_socket(socket: WebSocket, service: Arc<WebSocketService>) {
     let client_id = Uuid::new_v4();
-    let (tx, mut rx) = mpsc::unbounded_channel();
+    let queue = Arc::new(ClientQueue::new(client_id));
 
     let peer_id = {
         let peers = service.peers.read().await;
         peers.local_id()
     };
 
-    service.add_connection(client_id, peer_id, tx.clone()).await;
+    service.add_connection(client_id, peer_id, queue.clone()).await;
 
     let (mut sender, mut receiver) = socket.split();
 
     let service_send = service.clone();
     let client_id_send = client_id;
+    let send_queue = queue.clone();
 
-    let send_task = tokio::spawn(async move {
-        while let Some(msg) = rx.recv().await {
+    let mut send_task = tokio::spawn(async move {
+        while let Some(msg) = send_queue.pop().await {
             if sender.send(msg).await.is_err() {
                 break;
             }
@@ -433,19 +2107,17 @@ async fn handle_socket(socket: WebSocket, service: Arc<WebSocketService>) {
 
     let service_recv = service.clone();
     let client_id_recv = client_id;
-    let pong_tx = tx.clone();
+    let reply_queue = queue.clone();
 
-    let recv_task = tokio::spawn(async move {
+    let mut recv_task = tokio::spawn(async move {
         while let Some(Ok(msg)) = receiver.next().await {
             match msg {
                 Message::Text(text) => {
                     if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(&text) {
                         match service_recv.clone().handle_client_message(client_id_recv, client_msg).await {
                             Ok(Some(response)) => {
-                                if let Ok(json) = serde_json::to_string(&response) {
-                                    if let Err(e) = pong_tx.send(Message::Text(json)) {
-                                        tracing::error!("Failed to send response: {}", e);
-                                    }
+                                if let Err(e) = reply_queue.push(service_recv.encode(response)).await {
+                                    tracing::error!("Failed to send response: {}", e);
                                 }
                             }
                             Ok(None) => {}
@@ -453,13 +2125,12 @@ async fn handle_socket(socket: WebSocket, service: Arc<WebSocketService>) {
                                 let error_msg = ServerMessage::Error {
                                     message: e.to_string(),
                                 };
-                                if let Ok(json) = serde_json::to_string(&error_msg) {
-                                    let _ = pong_tx.send(Message::Text(json));
-                                }
+                                let _ = reply_queue.push(service_recv.encode(error_msg)).await;
                             }
                         }
                     } else {
-                        tracing::warn!("Invalid message format from {}: {}", client_id_recv, text);
+                        let name = service_recv.client_name(&client_id_recv).await;
+                        tracing::warn!("Invalid message format from {}: {}", name, text);
                     }
                 }
                 Message::Binary(data) => {
@@ -469,7 +2140,7 @@ async fn handle_socket(socket: WebSocket, service: Arc<WebSocketService>) {
                     break;
                 }
                 Message::Ping(data) => {
-                    if let Err(e) = pong_tx.send(Message::Pong(data)) {
+                    if let Err(e) = reply_queue.push(Message::Pong(data)).await {
                         tracing::error!("Failed to send pong: {}", e);
                         break;
                     }
@@ -481,10 +2152,17 @@ async fn handle_socket(socket: WebSocket, service: Arc<WebSocketService>) {
     });
 
     tokio::select! {
-        _ = send_task => {
+        _ = &mut send_task => {
             recv_task.abort();
         }
-        _ = recv_task => {
+        _ = &mut recv_task => {
+            send_task.abort();
+        }
+        _ = queue.closed() => {
+            // Dropped for not keeping up; the send task may be stuck on the socket
+            send_task.abort();
+            recv_task.abort();
+            service.remove_connection(&client_id).await;
         }
     }
 }